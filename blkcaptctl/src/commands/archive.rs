@@ -0,0 +1,979 @@
+use anyhow::{bail, Context, Result};
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::core::{
+    archive::{plan_collection, plan_restore_chain, ArchiveContainer, ArchiveManifest},
+    parse_snapshot_timestamp,
+    restore::DatasetRestore,
+    BtrfsPool,
+};
+use libblkcapt::model::entities::{
+    ArchiveBackend, ArchiveContainerEntity, ArchiveEncryption, ArchivePricing, RcloneBackend, S3Backend,
+};
+use libblkcapt::model::{entity_by_id_mut, entity_by_name, history::JobKind, storage, Entities, Entity, EntityType};
+use libblkcapt::sys::{net::ServiceClient, tape::TapeDrive};
+use slog_scope::debug;
+use std::{fmt::Debug, num::NonZeroUsize, path::PathBuf, sync::Arc};
+
+use super::{
+    archive_search, audit::audited, dataset_search, entity_by_type_lookup, pool_search, RetentionCreateUpdateOptions,
+    RetentionUpdateOptions,
+};
+use crate::ui::{
+    comfy_feature_state_cell, comfy_id_header, comfy_id_value_full, comfy_name_value, comfy_value_or, format_bytes,
+    format_datetime, print_comfy_info, print_comfy_table, ByteSizeArg, CellOrCells,
+};
+
+const MIN_PART_SIZE: u64 = 1024 * 1024;
+
+fn check_part_size(part_size: Option<u64>) -> Result<()> {
+    if matches!(part_size, Some(size) if size < MIN_PART_SIZE) {
+        bail!("The part size must be at least {} bytes.", MIN_PART_SIZE);
+    }
+    Ok(())
+}
+
+fn check_upload_retries(upload_retries: Option<u32>) -> Result<()> {
+    if matches!(upload_retries, Some(retries) if retries > ArchiveContainerEntity::MAX_UPLOAD_RETRIES) {
+        bail!(
+            "Upload retries must be at most {}.",
+            ArchiveContainerEntity::MAX_UPLOAD_RETRIES
+        );
+    }
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ArchivePricingOptions {
+    /// Price of storing a GB for a month, for the cost estimates of `archive show`
+    #[clap(long, value_name("price"))]
+    storage_price: Option<f64>,
+
+    /// Price of downloading a GB from the service, for the cost estimates of `archive show`
+    #[clap(long, value_name("price"))]
+    egress_price: Option<f64>,
+}
+
+impl ArchivePricingOptions {
+    fn update_pricing(&self, pricing: &mut Option<ArchivePricing>) -> Result<()> {
+        if self
+            .storage_price
+            .iter()
+            .chain(self.egress_price.iter())
+            .any(|p| !p.is_finite() || *p < 0.0)
+        {
+            bail!("Prices can't be negative.");
+        }
+        match (pricing.as_mut(), self.storage_price) {
+            (Some(pricing), storage_price) => {
+                if let Some(storage_price) = storage_price {
+                    pricing.storage_per_gb_month = storage_price;
+                }
+                if let Some(egress_price) = self.egress_price {
+                    pricing.egress_per_gb = egress_price;
+                }
+            }
+            (None, Some(storage_price)) => {
+                *pricing = Some(ArchivePricing {
+                    storage_per_gb_month: storage_price,
+                    egress_per_gb: self.egress_price.unwrap_or_default(),
+                })
+            }
+            (None, None) if self.egress_price.is_some() => bail!("Cost estimates need --storage-price."),
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap)]
+pub struct ArchiveAttachOptions {
+    /// Name of the archive container
+    #[clap(short, long)]
+    name: String,
+
+    /// Base URL of the S3 compatible service, e.g. https://s3.us-west-002.backblazeb2.com
+    #[clap(long, value_name("url"))]
+    endpoint: Option<String>,
+
+    /// Region of the bucket
+    #[clap(long, default_value("us-east-1"))]
+    region: String,
+
+    /// Bucket to store snapshot streams in
+    #[clap(long)]
+    bucket: Option<String>,
+
+    /// Key prefix for everything stored, so a bucket can be shared
+    #[clap(long, default_value(""))]
+    prefix: String,
+
+    /// Access key id of the credentials
+    #[clap(long, value_name("id"))]
+    access_key_id: Option<String>,
+
+    /// Secret access key of the credentials
+    #[clap(long, value_name("key"))]
+    secret_access_key: Option<String>,
+
+    /// Store snapshot streams through rclone at a configured remote and path, e.g. gdrive:backups/nas, instead of S3
+    #[clap(
+        long,
+        value_name("remote:path"),
+        conflicts_with_all(&["endpoint", "bucket", "access-key-id", "secret-access-key", "storage-class"])
+    )]
+    rclone_remote: Option<String>,
+
+    /// rclone config file the remote is defined in [default: rclone's default]
+    #[clap(long, value_name("path"), requires("rclone-remote"))]
+    rclone_config: Option<PathBuf>,
+
+    /// Size in bytes of the parts a stream is split into [default: 64 MiB]
+    #[clap(long, value_name("bytes"))]
+    part_size: Option<u64>,
+
+    /// Storage class to upload parts in, e.g. STANDARD_IA or GLACIER [default: the bucket's default]
+    #[clap(long, value_name("class"))]
+    storage_class: Option<String>,
+
+    /// Parts to upload at once, each held in memory while it uploads [default: 4]
+    #[clap(long, value_name("count"))]
+    upload_parallelism: Option<NonZeroUsize>,
+
+    /// Times to retry a failed part upload before the upload fails [default: 3, at most 10]
+    #[clap(long, value_name("count"))]
+    upload_retries: Option<u32>,
+
+    #[clap(flatten)]
+    pricing: ArchivePricingOptions,
+
+    /// Encrypt streams with age before uploading them. Requires at least one --recipient
+    #[clap(long)]
+    encrypt: bool,
+
+    /// Age recipient (public key or ssh public key) to encrypt streams for. May be repeated.
+    #[clap(short, long = "recipient", value_name = "recipient", requires("encrypt"))]
+    recipients: Vec<String>,
+
+    /// Age identity file to decrypt streams with when restoring
+    #[clap(long, value_name("path"), requires("encrypt"))]
+    identity_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    retention: RetentionCreateUpdateOptions,
+}
+
+// Written by hand so the secret key stays out of logs and the audit trail.
+impl Debug for ArchiveAttachOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveAttachOptions")
+            .field("name", &self.name)
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("access_key_id", &self.access_key_id)
+            .field("rclone_remote", &self.rclone_remote)
+            .field("rclone_config", &self.rclone_config)
+            .field("part_size", &self.part_size)
+            .field("storage_class", &self.storage_class)
+            .field("upload_parallelism", &self.upload_parallelism)
+            .field("upload_retries", &self.upload_retries)
+            .field("pricing", &self.pricing)
+            .field("encrypt", &self.encrypt)
+            .field("recipients", &self.recipients)
+            .field("identity_file", &self.identity_file)
+            .field("retention", &self.retention)
+            .finish()
+    }
+}
+
+pub fn attach_archive(options: ArchiveAttachOptions) -> Result<()> {
+    debug!("Command 'attach_archive': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    if entity_by_name(&entities.archive_containers, &options.name).is_some() {
+        bail!("Archive container name '{}' already exists.", options.name);
+    }
+    check_part_size(options.part_size)?;
+    check_upload_retries(options.upload_retries)?;
+    if options.encrypt && options.recipients.is_empty() {
+        bail!("At least one --recipient is required to encrypt the archive container.");
+    }
+
+    let backend = match (
+        options.rclone_remote,
+        options.endpoint,
+        options.bucket,
+        options.access_key_id,
+        options.secret_access_key,
+    ) {
+        (Some(remote), ..) => ArchiveBackend::Rclone(RcloneBackend {
+            remote,
+            config: options.rclone_config,
+        }),
+        (None, Some(endpoint), Some(bucket), Some(access_key_id), Some(secret_access_key)) => {
+            ArchiveBackend::S3(S3Backend {
+                endpoint,
+                region: options.region,
+                bucket,
+                prefix: options.prefix,
+                access_key_id,
+                secret_access_key,
+            })
+        }
+        _ => bail!(
+            "Either --rclone-remote or all of --endpoint, --bucket, --access-key-id and --secret-access-key are required."
+        ),
+    };
+    let mut archive = ArchiveContainerEntity::new(options.name, backend);
+    archive.part_size = options.part_size;
+    archive.storage_class = options.storage_class;
+    archive.upload_parallelism = options.upload_parallelism.map(NonZeroUsize::get);
+    archive.upload_retries = options.upload_retries;
+    options.pricing.update_pricing(&mut archive.pricing)?;
+    options.retention.update_retention(&mut archive.snapshot_retention);
+    if options.encrypt {
+        archive.encryption = Some(ArchiveEncryption {
+            recipients: options.recipients,
+            identity_file: options.identity_file,
+        });
+    }
+    entities.archive_containers.push(archive);
+
+    storage::store_entity_config(entities);
+    Ok(())
+}
+
+#[derive(Clap)]
+pub struct ArchiveUpdateOptions {
+    /// The name or id of the archive container
+    #[clap(value_name("archive|id"))]
+    archive: String,
+
+    /// Base URL of the S3 compatible service
+    #[clap(long, value_name("url"))]
+    endpoint: Option<String>,
+
+    /// Region of the bucket
+    #[clap(long)]
+    region: Option<String>,
+
+    /// Access key id of the credentials
+    #[clap(long, value_name("id"), requires("secret-access-key"))]
+    access_key_id: Option<String>,
+
+    /// Secret access key of the credentials
+    #[clap(long, value_name("key"), requires("access-key-id"))]
+    secret_access_key: Option<String>,
+
+    /// rclone remote and path of an rclone container. Streams already stored have to be moved there first
+    #[clap(long, value_name("remote:path"))]
+    rclone_remote: Option<String>,
+
+    /// rclone config file the remote is defined in
+    #[clap(long, value_name("path"))]
+    rclone_config: Option<PathBuf>,
+
+    /// Size in bytes of the parts a stream is split into. Streams already stored keep their parts
+    #[clap(long, value_name("bytes"))]
+    part_size: Option<u64>,
+
+    /// Storage class to upload new parts in. Parts already stored keep theirs
+    #[clap(long, value_name("class"), conflicts_with("default-storage-class"))]
+    storage_class: Option<String>,
+
+    /// Upload new parts in the bucket's default storage class
+    #[clap(long)]
+    default_storage_class: bool,
+
+    /// Parts to upload at once, each held in memory while it uploads
+    #[clap(long, value_name("count"))]
+    upload_parallelism: Option<NonZeroUsize>,
+
+    /// Times to retry a failed part upload before the upload fails
+    #[clap(long, value_name("count"))]
+    upload_retries: Option<u32>,
+
+    #[clap(flatten)]
+    pricing: ArchivePricingOptions,
+
+    /// Remove the prices, hiding the cost estimates
+    #[clap(long, conflicts_with_all(&["storage-price", "egress-price"]))]
+    clear_pricing: bool,
+
+    /// Replace the age recipients of an encrypted container. Streams already stored stay encrypted for the old ones
+    #[clap(short, long = "recipient", value_name = "recipient")]
+    recipients: Vec<String>,
+
+    /// Age identity file to decrypt streams with when restoring
+    #[clap(long, value_name("path"))]
+    identity_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    retention_update: RetentionUpdateOptions,
+
+    #[clap(flatten)]
+    retention: RetentionCreateUpdateOptions,
+}
+
+impl Debug for ArchiveUpdateOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveUpdateOptions")
+            .field("archive", &self.archive)
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("access_key_id", &self.access_key_id)
+            .field("rclone_remote", &self.rclone_remote)
+            .field("rclone_config", &self.rclone_config)
+            .field("part_size", &self.part_size)
+            .field("storage_class", &self.storage_class)
+            .field("default_storage_class", &self.default_storage_class)
+            .field("upload_parallelism", &self.upload_parallelism)
+            .field("upload_retries", &self.upload_retries)
+            .field("pricing", &self.pricing)
+            .field("clear_pricing", &self.clear_pricing)
+            .field("recipients", &self.recipients)
+            .field("identity_file", &self.identity_file)
+            .field("retention_update", &self.retention_update)
+            .field("retention", &self.retention)
+            .finish()
+    }
+}
+
+pub fn update_archive(options: ArchiveUpdateOptions) -> Result<()> {
+    debug!("Command 'update_archive': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let archive_id = archive_search(&entities, &options.archive)?.id();
+    let archive = entity_by_id_mut(&mut entities.archive_containers, archive_id).expect("always exists if path found");
+
+    check_part_size(options.part_size)?;
+    check_upload_retries(options.upload_retries)?;
+    if options.part_size.is_some() {
+        archive.part_size = options.part_size;
+    }
+    if options.storage_class.is_some() || options.default_storage_class {
+        archive.storage_class = options.storage_class;
+    }
+    if let Some(parallelism) = options.upload_parallelism {
+        archive.upload_parallelism = Some(parallelism.get());
+    }
+    if options.upload_retries.is_some() {
+        archive.upload_retries = options.upload_retries;
+    }
+    if options.clear_pricing {
+        archive.pricing = None;
+    }
+    options.pricing.update_pricing(&mut archive.pricing)?;
+    options.retention_update.update_pruning(&mut archive.pause_pruning);
+    options.retention.update_retention(&mut archive.snapshot_retention);
+    if !options.recipients.is_empty() || options.identity_file.is_some() {
+        let encryption = archive
+            .encryption
+            .as_mut()
+            .context("The archive container isn't encrypted. Encryption is chosen when attaching it.")?;
+        if !options.recipients.is_empty() {
+            encryption.recipients = options.recipients;
+        }
+        if options.identity_file.is_some() {
+            encryption.identity_file = options.identity_file;
+        }
+    }
+    match &mut archive.backend {
+        ArchiveBackend::S3(s3) => {
+            if options.rclone_remote.is_some() || options.rclone_config.is_some() {
+                bail!("The archive container stores streams in S3, not through rclone.");
+            }
+            if let Some(endpoint) = options.endpoint {
+                s3.endpoint = endpoint;
+            }
+            if let Some(region) = options.region {
+                s3.region = region;
+            }
+            if let (Some(access_key_id), Some(secret_access_key)) = (options.access_key_id, options.secret_access_key) {
+                s3.access_key_id = access_key_id;
+                s3.secret_access_key = secret_access_key;
+            }
+        }
+        ArchiveBackend::Rclone(rclone) => {
+            if options.endpoint.is_some() || options.region.is_some() || options.access_key_id.is_some() {
+                bail!("The archive container stores streams through rclone, not in S3.");
+            }
+            if archive.storage_class.is_some() {
+                bail!("Storage classes are set in the rclone remote's config for rclone archive containers.");
+            }
+            if let Some(remote) = options.rclone_remote {
+                rclone.remote = remote;
+            }
+            if options.rclone_config.is_some() {
+                rclone.config = options.rclone_config;
+            }
+        }
+    }
+
+    storage::store_entity_config(entities);
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ArchiveRestoreOptions {
+    /// The name or id of the archive container
+    #[clap(value_name("archive|id"))]
+    archive: String,
+
+    /// The name or id of the dataset to restore
+    #[clap(value_name("dataset|id"))]
+    dataset: String,
+
+    /// Restore the newest snapshot at or before this time, a snapshot label or RFC 3339 datetime [default: newest]
+    #[clap(long, value_name("time"))]
+    at: Option<String>,
+
+    /// Pool to recreate the dataset on [default: the dataset's pool]
+    #[clap(short, long)]
+    target_pool: Option<String>,
+
+    /// Age identity file to decrypt encrypted streams with [default: the archive container's identity file]
+    #[clap(short, long)]
+    identity: Option<PathBuf>,
+
+    /// Show the streams that would be received without checking or restoring them
+    #[clap(long)]
+    dry_run: bool,
+}
+
+pub async fn restore_archive(options: ArchiveRestoreOptions) -> Result<()> {
+    debug!("Command 'restore_archive': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let archive = ArchiveContainer::validate(archive_search(&entities, &options.archive)?.clone())?;
+    let dataset_path = dataset_search(&entities, &options.dataset)?;
+    let dataset = dataset_path.entity.clone();
+    let target_pool_model = match &options.target_pool {
+        Some(query) => pool_search(&entities, query)?,
+        None => dataset_path.parent,
+    };
+    let target_pool_id = target_pool_model.id();
+    let target_pool = Arc::new(BtrfsPool::validate(target_pool_model.clone())?);
+    let point_in_time = match &options.at {
+        Some(at) => parse_snapshot_timestamp(at)?,
+        None => chrono::Utc::now(),
+    };
+
+    let manifests = archive
+        .manifests()
+        .await?
+        .remove(&dataset.id())
+        .with_context(|| format!("The archive has no streams for dataset {}.", dataset.name()))?;
+    let chain = plan_restore_chain(&manifests, point_in_time)?;
+
+    print_comfy_table(
+        vec![
+            Cell::new("Snapshot"),
+            Cell::new("Stream"),
+            Cell::new("Parts"),
+            Cell::new("Size"),
+        ],
+        chain.iter().map(|m| {
+            vec![
+                Cell::new(format_datetime(m.datetime)),
+                Cell::new(if m.is_full() { "full" } else { "incremental" }),
+                Cell::new(m.parts.len()),
+                Cell::new(format_bytes(m.size())),
+            ]
+        }),
+    );
+    println!(
+        "{} stream(s), {} to download.",
+        chain.len(),
+        format_bytes(chain.iter().map(|m| m.size()).sum())
+    );
+
+    if options.dry_run {
+        return Ok(());
+    }
+
+    let identity = options.identity.clone().or_else(|| {
+        archive
+            .model()
+            .encryption
+            .as_ref()
+            .and_then(|e| e.identity_file.clone())
+    });
+    if identity.is_none() && chain.iter().any(|m| m.encrypted) {
+        bail!("The archived streams are encrypted. Give the age identity to decrypt them with --identity.");
+    }
+
+    let restore = DatasetRestore::new(&target_pool, dataset)?;
+    println!("Checking the archived streams, and requesting parts in cold storage be restored for reading...");
+    archive.verify_chain(&chain).await?;
+
+    println!("Restoring {}...", restore.model().name());
+    let restored = restore
+        .run_archive(&archive, &chain, identity.as_deref())
+        .await
+        .context("Failed to restore the dataset from the archive.")?;
+    entities.relocate_dataset(restored.take_model(), target_pool_id)?;
+    storage::store_entity_config(entities);
+    Ok(())
+}
+
+fn stream_kind(manifest: &ArchiveManifest) -> &'static str {
+    if manifest.is_full() {
+        "full"
+    } else {
+        "incremental"
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct ArchiveListOptions {
+    /// The name or id of the archive container
+    #[clap(value_name("archive|id"))]
+    archive: String,
+
+    /// Only list the restore points of this dataset
+    #[clap(short, long, value_name("dataset|id"))]
+    dataset: Option<String>,
+}
+
+pub async fn list_archive(options: ArchiveListOptions) -> Result<()> {
+    debug!("Command 'list_archive': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let archive = ArchiveContainer::validate(archive_search(&entities, &options.archive)?.clone())?;
+    let dataset_id = match &options.dataset {
+        Some(query) => Some(dataset_search(&entities, query)?.entity.id()),
+        None => None,
+    };
+
+    let mut manifests = archive
+        .manifests()
+        .await?
+        .into_iter()
+        .filter(|(id, _)| dataset_id.map_or(true, |d| d == *id))
+        .collect::<Vec<_>>();
+    manifests.sort_unstable_by_key(|(id, _)| entity_by_type_lookup(&entities, EntityType::Dataset, *id));
+
+    print_comfy_table(
+        vec![
+            Cell::new("Dataset"),
+            Cell::new("Snapshot"),
+            Cell::new("Stream"),
+            Cell::new("Depends On"),
+            Cell::new("Size"),
+            Cell::new("Restore Size"),
+            Cell::new("Encrypted"),
+        ],
+        manifests.iter().flat_map(|(id, dataset_manifests)| {
+            let dataset_name =
+                entity_by_type_lookup(&entities, EntityType::Dataset, *id).unwrap_or_else(|| id.to_string());
+            dataset_manifests.iter().map(move |m| {
+                let parent = m
+                    .parent_uuid
+                    .map(|uuid| dataset_manifests.iter().find(|p| p.uuid == uuid).map(|p| p.datetime));
+                let restore_size = plan_restore_chain(dataset_manifests, m.datetime)
+                    .ok()
+                    .map(|chain| format_bytes(chain.iter().map(|c| c.size()).sum()));
+                vec![
+                    Cell::new(&dataset_name),
+                    Cell::new(format_datetime(m.datetime)),
+                    Cell::new(stream_kind(m)),
+                    match parent {
+                        None => Cell::new("-"),
+                        Some(parent) => comfy_value_or(parent, "Missing"),
+                    },
+                    Cell::new(format_bytes(m.size())),
+                    comfy_value_or(restore_size, "Broken chain"),
+                    Cell::new(if m.encrypted { "yes" } else { "no" }),
+                ]
+            })
+        }),
+    );
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ArchiveShowOptions {
+    /// The name or id of the archive container
+    #[clap(value_name("archive|id"))]
+    archive: String,
+
+    /// The name or id of the dataset the snapshot was taken of. Without it the container itself is shown
+    #[clap(value_name("dataset|id"), requires("snapshot"))]
+    dataset: Option<String>,
+
+    /// Snapshot timestamp, either a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime
+    snapshot: Option<String>,
+}
+
+/// Transfers to the container within this long are summed up as its recent uploads.
+const UPLOAD_WINDOW_DAYS: i64 = 30;
+
+pub async fn show_archive(options: ArchiveShowOptions) -> Result<()> {
+    debug!("Command 'show_archive': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let archive = ArchiveContainer::validate(archive_search(&entities, &options.archive)?.clone())?;
+    let (dataset, snapshot) = match (&options.dataset, &options.snapshot) {
+        (Some(dataset), Some(snapshot)) => (dataset, snapshot),
+        _ => return show_archive_container(&entities, &archive).await,
+    };
+    let dataset = dataset_search(&entities, dataset)?.entity;
+    let datetime = parse_snapshot_timestamp(snapshot)?;
+
+    let manifests = archive.manifests().await?.remove(&dataset.id()).unwrap_or_default();
+    let manifest = manifests
+        .iter()
+        .find(|m| m.datetime == datetime)
+        .context("Snapshot not found in the archive.")?;
+
+    let chain_rows = match plan_restore_chain(&manifests, datetime) {
+        Ok(chain) => vec![
+            (
+                Cell::new("Restore Chain"),
+                chain
+                    .iter()
+                    .map(|c| Cell::new(format!("{} ({})", format_datetime(c.datetime), stream_kind(c))))
+                    .collect::<Vec<_>>()
+                    .into(),
+            ),
+            (
+                Cell::new("Restore Size"),
+                Cell::new(format_bytes(chain.iter().map(|c| c.size()).sum())).into(),
+            ),
+        ],
+        Err(e) => vec![(Cell::new("Restore Chain"), Cell::new(e).into())],
+    };
+
+    let mut rows: Vec<(Cell, CellOrCells)> = vec![
+        (
+            Cell::new("Snapshot"),
+            Cell::new(format_datetime(manifest.datetime)).into(),
+        ),
+        (Cell::new("Location"), Cell::new(&archive).into()),
+        (Cell::new("Source UUID"), comfy_id_value_full(manifest.uuid).into()),
+        (Cell::new("Stream"), Cell::new(stream_kind(manifest)).into()),
+        (
+            Cell::new("Parent UUID"),
+            comfy_value_or(manifest.parent_uuid, "none").into(),
+        ),
+        (
+            Cell::new("Compression"),
+            comfy_value_or(manifest.compression, "none").into(),
+        ),
+        (
+            Cell::new("Encrypted"),
+            Cell::new(if manifest.encrypted { "yes" } else { "no" }).into(),
+        ),
+        (Cell::new("Parts"), Cell::new(manifest.parts.len()).into()),
+        (Cell::new("Size"), Cell::new(format_bytes(manifest.size())).into()),
+        (
+            Cell::new("Uploaded"),
+            Cell::new(format_datetime(manifest.created)).into(),
+        ),
+    ];
+    rows.extend(chain_rows);
+    print_comfy_info(rows);
+
+    Ok(())
+}
+
+async fn show_archive_container(entities: &Entities, archive: &ArchiveContainer) -> Result<()> {
+    let model = archive.model();
+    let manifests = archive.manifests().await?;
+    let streams = manifests.values().map(Vec::len).sum::<usize>();
+    let stored = manifests.values().flatten().map(|m| m.size()).sum::<u64>();
+    let restore_size = manifests
+        .values()
+        .filter_map(|m| plan_restore_chain(m, chrono::Utc::now()).ok())
+        .flat_map(|chain| chain.into_iter().map(|m| m.size()))
+        .sum::<u64>();
+
+    let since = chrono::Utc::now() - chrono::Duration::days(UPLOAD_WINDOW_DAYS);
+    let sync_ids = entities
+        .snapshot_syncs
+        .iter()
+        .filter(|s| s.container_id == model.id())
+        .map(|s| s.id())
+        .collect::<Vec<_>>();
+    let uploaded = storage::load_history()?
+        .iter()
+        .filter(|r| r.kind == JobKind::Transfer && r.succeeded() && r.started >= since)
+        .filter(|r| sync_ids.contains(&r.entity_id))
+        .filter_map(|r| r.bytes)
+        .sum::<u64>();
+
+    let mut rows: Vec<(Cell, CellOrCells)> = vec![
+        (comfy_id_header(), comfy_id_value_full(model.id()).into()),
+        (Cell::new("Name"), comfy_name_value(model.name()).into()),
+        (Cell::new("Location"), Cell::new(archive).into()),
+        (
+            Cell::new("Part Size"),
+            Cell::new(format_bytes(model.part_size())).into(),
+        ),
+        (
+            Cell::new("Storage Class"),
+            comfy_value_or(model.storage_class.as_ref(), "Default").into(),
+        ),
+        (
+            Cell::new("Encrypted"),
+            Cell::new(if model.encryption.is_some() { "yes" } else { "no" }).into(),
+        ),
+        (
+            Cell::new("Pruning"),
+            comfy_feature_state_cell(model.pruning_state()).into(),
+        ),
+        (Cell::new("Streams"), Cell::new(streams).into()),
+        (Cell::new("Stored"), Cell::new(format_bytes(stored)).into()),
+        (
+            Cell::new(format!("Uploaded ({} days)", UPLOAD_WINDOW_DAYS)),
+            Cell::new(format_bytes(uploaded)).into(),
+        ),
+    ];
+    if let Some(pricing) = &model.pricing {
+        rows.extend(vec![
+            (
+                Cell::new("Est. Monthly Storage Cost"),
+                Cell::new(format!("{:.2}", pricing.storage_cost(stored))).into(),
+            ),
+            (
+                Cell::new("Est. Monthly Growth"),
+                Cell::new(format!("+{:.2} per month", pricing.storage_cost(uploaded))).into(),
+            ),
+            (
+                Cell::new("Est. Full Restore Egress"),
+                Cell::new(format!(
+                    "{:.2} for {}",
+                    pricing.egress_cost(restore_size),
+                    format_bytes(restore_size)
+                ))
+                .into(),
+            ),
+        ]);
+    }
+    print_comfy_info(rows);
+    if model.pricing.is_some() {
+        println!("Estimates are rough. They leave out request fees, minimum storage durations and free tiers.");
+    }
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ArchiveGcOptions {
+    /// The name or id of the archive container
+    #[clap(value_name("archive|id"))]
+    archive: String,
+
+    /// Report the streams that would be deleted without deleting them
+    #[clap(long)]
+    dry_run: bool,
+}
+
+pub async fn gc_archive(options: ArchiveGcOptions) -> Result<()> {
+    debug!("Command 'gc_archive': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let archive = ArchiveContainer::validate(archive_search(&entities, &options.archive)?.clone())?;
+    let rules = archive
+        .model()
+        .snapshot_retention
+        .clone()
+        .context("The archive container has no retention rules to collect streams by.")?;
+
+    let manifests = archive.manifests().await?;
+    let mut datasets = manifests.iter().collect::<Vec<_>>();
+    datasets.sort_unstable_by_key(|(id, _)| entity_by_type_lookup(&entities, EntityType::Dataset, **id));
+    let collections = datasets
+        .into_iter()
+        .map(|(id, dataset_manifests)| (*id, plan_collection(dataset_manifests, &rules)))
+        .collect::<Vec<_>>();
+
+    print_comfy_table(
+        vec![
+            Cell::new("Dataset"),
+            Cell::new("Snapshot"),
+            Cell::new("Stream"),
+            Cell::new("Size"),
+            Cell::new("Action"),
+        ],
+        collections.iter().flat_map(|(id, collection)| {
+            let dataset_name =
+                entity_by_type_lookup(&entities, EntityType::Dataset, *id).unwrap_or_else(|| id.to_string());
+            collection
+                .pinned
+                .iter()
+                .map(|m| (m, "keep, needed by a retained stream"))
+                .chain(collection.obsolete.iter().map(|m| (m, "delete")))
+                .map(move |(m, action)| {
+                    vec![
+                        Cell::new(&dataset_name),
+                        Cell::new(format_datetime(m.datetime)),
+                        Cell::new(stream_kind(m)),
+                        Cell::new(format_bytes(m.size())),
+                        Cell::new(action),
+                    ]
+                })
+        }),
+    );
+
+    let obsolete = collections
+        .iter()
+        .flat_map(|(_, c)| c.obsolete.iter())
+        .collect::<Vec<_>>();
+    println!(
+        "{} stream(s) to delete, {} to free.",
+        obsolete.len(),
+        format_bytes(obsolete.iter().map(|m| m.size()).sum())
+    );
+    if options.dry_run {
+        return Ok(());
+    }
+
+    // a running worker uploads into the archive and keeps its own list of streams, so it has to do the deleting, and
+    // records it in the audit log
+    let path = format!("/archives/{}/prune", archive.model().id());
+    match ServiceClient::default().post(&path).await {
+        Ok(response) if response.status().is_success() => {
+            println!("The worker is deleting the streams.");
+            return Ok(());
+        }
+        Ok(response) => {
+            let status = response.status();
+            let body = hyper::body::to_bytes(response).await?;
+            bail!(
+                "worker refused to delete the streams: {} {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+        Err(e) if e.is_connect() => debug!("Worker is not running, deleting the streams directly: {}", e),
+        Err(e) => return Err(e.into()),
+    }
+
+    let deleted = async {
+        for manifest in obsolete {
+            archive.delete_stream(manifest).await.with_context(|| {
+                format!(
+                    "Failed to delete the archived stream for {}.",
+                    format_datetime(manifest.datetime)
+                )
+            })?;
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    audited("archive gc", &options).record(deleted)
+}
+
+const DEFAULT_TAPE_BLOCK_SIZE: u64 = 256 * 1024;
+
+#[derive(Clap, Debug)]
+pub struct ArchiveExportTapeOptions {
+    /// The name or id of the archive container
+    #[clap(value_name("archive|id"))]
+    archive: String,
+
+    /// Non-rewinding tape device to write to, e.g. /dev/nst0
+    #[clap(short('f'), long, value_name("device"))]
+    device: PathBuf,
+
+    /// Only write the restore chain of this dataset [default: every dataset in the archive]
+    #[clap(short, long, value_name("dataset|id"))]
+    dataset: Option<String>,
+
+    /// Write the chains to the newest snapshot at or before this time, a snapshot label or RFC 3339 datetime
+    /// [default: newest]
+    #[clap(long, value_name("time"))]
+    at: Option<String>,
+
+    /// Size of the blocks written to tape, a multiple of 512 bytes [default: 256 KiB]
+    #[clap(long, value_name("size"))]
+    block_size: Option<ByteSizeArg>,
+
+    /// Also save the tape's index to this file, to look up what the tape holds without loading it
+    #[clap(long, value_name("path"))]
+    index: Option<PathBuf>,
+
+    /// Write after the tape's current position instead of rewinding it first
+    #[clap(long)]
+    append: bool,
+}
+
+pub async fn export_tape_archive(options: ArchiveExportTapeOptions) -> Result<()> {
+    debug!("Command 'export_tape_archive': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let archive = ArchiveContainer::validate(archive_search(&entities, &options.archive)?.clone())?;
+    let dataset_id = match &options.dataset {
+        Some(query) => Some(dataset_search(&entities, query)?.entity.id()),
+        None => None,
+    };
+    let point_in_time = match &options.at {
+        Some(at) => parse_snapshot_timestamp(at)?,
+        None => chrono::Utc::now(),
+    };
+    let block_size = options.block_size.as_ref().map_or(DEFAULT_TAPE_BLOCK_SIZE, |s| s.0);
+    let drive = TapeDrive::new(&options.device, block_size as usize)?;
+
+    let mut manifests = archive
+        .manifests()
+        .await?
+        .into_iter()
+        .filter(|(id, _)| dataset_id.map_or(true, |d| d == *id))
+        .collect::<Vec<_>>();
+    if manifests.is_empty() {
+        bail!("The archive has no streams to write.");
+    }
+    manifests.sort_unstable_by_key(|(id, _)| entity_by_type_lookup(&entities, EntityType::Dataset, *id));
+    let mut chains = Vec::new();
+    for (id, dataset_manifests) in manifests.iter() {
+        let dataset_name = entity_by_type_lookup(&entities, EntityType::Dataset, *id).unwrap_or_else(|| id.to_string());
+        let chain = plan_restore_chain(dataset_manifests, point_in_time)
+            .with_context(|| format!("Failed to plan the restore chain of dataset {}.", dataset_name))?;
+        chains.extend(chain.into_iter().map(|m| (dataset_name.clone(), m)));
+    }
+
+    print_comfy_table(
+        vec![
+            Cell::new("Tape File"),
+            Cell::new("Dataset"),
+            Cell::new("Snapshot"),
+            Cell::new("Stream"),
+            Cell::new("Size"),
+        ],
+        chains.iter().enumerate().map(|(i, (dataset_name, m))| {
+            vec![
+                Cell::new(i + 1),
+                Cell::new(dataset_name),
+                Cell::new(format_datetime(m.datetime)),
+                Cell::new(stream_kind(m)),
+                Cell::new(format_bytes(m.size())),
+            ]
+        }),
+    );
+
+    let chain = chains.into_iter().map(|(_, m)| m).collect::<Vec<_>>();
+    println!("Checking the archived streams, and requesting parts in cold storage be restored for reading...");
+    archive.verify_chain(&chain).await?;
+
+    if !options.append {
+        drive.rewind()?;
+    }
+    println!(
+        "Writing {} stream(s), {} to {}...",
+        chain.len(),
+        format_bytes(chain.iter().map(|m| m.size()).sum()),
+        options.device.display()
+    );
+    let index = archive.write_to_tape(&chain, &drive).await?;
+    if let Some(path) = &options.index {
+        std::fs::write(path, serde_json::to_vec_pretty(&index)?)
+            .with_context(|| format!("Failed to save the tape index to {}.", path.display()))?;
+    }
+    println!("Wrote the tape index and {} stream file(s).", index.files.len());
+    Ok(())
+}
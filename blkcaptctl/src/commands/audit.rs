@@ -0,0 +1,85 @@
+use anyhow::Result;
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::model::{
+    audit::{AuditInterface, AuditRecord},
+    storage,
+};
+use slog_scope::*;
+use std::fmt::Debug;
+
+use crate::ui::{format_datetime, print_comfy_table};
+
+/// Records a mutating command in the audit log once it finished, with the error if it failed. The detail is captured
+/// from the options before the command consumes them. Actions the worker carries out are recorded by the worker.
+pub struct AuditedCommand {
+    action: &'static str,
+    detail: String,
+}
+
+pub fn audited<T: Debug>(action: &'static str, options: &T) -> AuditedCommand {
+    AuditedCommand {
+        action,
+        detail: format!("{:?}", options),
+    }
+}
+
+impl AuditedCommand {
+    pub fn with_detail(action: &'static str, detail: String) -> Self {
+        Self { action, detail }
+    }
+
+    pub fn record<T>(self, result: Result<T>) -> Result<T> {
+        let mut record = AuditRecord::for_current_user(AuditInterface::Cli, self.action, self.detail);
+        record.error = result.as_ref().err().map(|e| format!("{:#}", e));
+        if let Err(e) = storage::append_audit(&record) {
+            warn!("Failed to record '{}' in the audit log: {:#}", self.action, e);
+        }
+        result
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct AuditOptions {
+    /// Number of most recent records to show
+    #[clap(short, long, default_value("50"))]
+    limit: usize,
+}
+
+pub fn audit(options: AuditOptions) -> Result<()> {
+    debug!("Command 'audit': {:?}", options);
+
+    let records = storage::load_audit()?;
+    if records.is_empty() {
+        println!("No administrative actions recorded.");
+        return Ok(());
+    }
+
+    let skip = records.len().saturating_sub(options.limit);
+    print_comfy_table(
+        vec![
+            Cell::new("Time"),
+            Cell::new("User"),
+            Cell::new("Interface"),
+            Cell::new("Action"),
+            Cell::new("Detail"),
+            Cell::new("Outcome"),
+        ],
+        records.into_iter().skip(skip).map(|r| {
+            vec![
+                Cell::new(format_datetime(r.timestamp)),
+                Cell::new(r.actor()),
+                Cell::new(r.interface),
+                Cell::new(&r.action),
+                Cell::new(&r.detail),
+                Cell::new(
+                    r.error
+                        .as_deref()
+                        .map_or(String::from("done"), |e| format!("failed: {}", e)),
+                ),
+            ]
+        }),
+    );
+
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Clap;
+use libblkcapt::model::{migrate::EntityConfig, storage, Entities};
+use slog_scope::*;
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use super::service::reload_running_worker;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            _ => Err(anyhow!("unsupported config format, use toml, yaml or json")),
+        }
+    }
+}
+
+impl ConfigFormat {
+    /// The explicit format, else the one matching the file extension. Stdin and stdout default to toml.
+    fn resolve(format: Option<Self>, path: Option<&Path>) -> Result<Self> {
+        match (format, path.and_then(Path::extension)) {
+            (Some(format), _) => Ok(format),
+            (None, Some(extension)) => extension
+                .to_string_lossy()
+                .parse()
+                .context("Can't tell the format from the file extension, use --format."),
+            (None, None) => Ok(Self::Toml),
+        }
+    }
+
+    fn serialize(self, config: &EntityConfig) -> Result<String> {
+        match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config).map_err(|e| anyhow!(e)),
+            // values have to come before tables in toml, which serializing through a toml value takes care of
+            ConfigFormat::Toml => toml::Value::try_from(config)
+                .and_then(|value| toml::to_string_pretty(&value))
+                .map_err(|e| anyhow!(e)),
+            ConfigFormat::Yaml => serde_yaml::to_string(config).map_err(|e| anyhow!(e)),
+        }
+        .context("Failed to serialize the configuration.")
+    }
+
+    fn deserialize(self, data: &str) -> Result<EntityConfig> {
+        match self {
+            ConfigFormat::Json => serde_json::from_str(data).map_err(|e| anyhow!(e)),
+            ConfigFormat::Toml => toml::from_str(data).map_err(|e| anyhow!(e)),
+            ConfigFormat::Yaml => serde_yaml::from_str(data).map_err(|e| anyhow!(e)),
+        }
+        .context("Failed to parse the configuration.")
+    }
+}
+
+/// Write the entity configuration in a reviewable format, for diffing or provisioning with configuration management
+#[derive(Clap, Debug)]
+pub struct ConfigExportOptions {
+    /// File to write to [default: stdout]
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    /// Format to write [toml|yaml|json] [default: from the output extension, or toml]
+    #[clap(short, long, value_name("format"))]
+    format: Option<ConfigFormat>,
+}
+
+pub fn config_export(options: ConfigExportOptions) -> Result<()> {
+    debug!("Command 'config_export': {:?}", options);
+
+    let format = ConfigFormat::resolve(options.format, options.output.as_deref())?;
+    let data = format.serialize(&EntityConfig(storage::load_entity_config()))?;
+    match &options.output {
+        Some(path) => fs::write(path, data).with_context(|| format!("Failed to write {:?}.", path))?,
+        None => print!("{}", data),
+    }
+    Ok(())
+}
+
+/// Replace the entity configuration with one written by config export, or by hand in the same shape
+#[derive(Clap, Debug)]
+pub struct ConfigImportOptions {
+    /// File to read, - for stdin
+    input: PathBuf,
+
+    /// Format to read [toml|yaml|json] [default: from the input extension, or toml]
+    #[clap(short, long, value_name("format"))]
+    format: Option<ConfigFormat>,
+
+    /// Only check that the configuration parses and is consistent
+    #[clap(long)]
+    check: bool,
+
+    /// Replace an existing, non-empty configuration.
+    #[clap(long)]
+    force: bool,
+}
+
+pub async fn config_import(options: ConfigImportOptions) -> Result<()> {
+    debug!("Command 'config_import': {:?}", options);
+
+    let from_stdin = options.input == Path::new("-");
+    let format = ConfigFormat::resolve(options.format, Some(options.input.as_path()).filter(|_| !from_stdin))?;
+    let data = if from_stdin {
+        let mut data = String::new();
+        io::stdin()
+            .read_to_string(&mut data)
+            .context("Failed to read the configuration from stdin.")?;
+        data
+    } else {
+        fs::read_to_string(&options.input).with_context(|| format!("Failed to read {:?}.", options.input))?
+    };
+    let entities = parse_config(format, &data)?;
+
+    let described = describe(&entities);
+    if options.check {
+        println!("Configuration is valid: {}.", described);
+        return Ok(());
+    }
+    if !options.force && !storage::load_entity_config().is_empty() {
+        bail!("A configuration already exists on this system. Use --force to replace it.");
+    }
+
+    storage::store_entity_config(entities);
+    println!("Imported {}.", described);
+    let reloaded = reload_running_worker()
+        .await
+        .context("The running worker failed to apply the configuration, check it and run 'service reload'.")?;
+    match reloaded {
+        true => println!("The running worker applied the configuration."),
+        false => println!("The worker is not running, it applies the configuration when it starts."),
+    }
+    Ok(())
+}
+
+fn parse_config(format: ConfigFormat, data: &str) -> Result<Entities> {
+    let EntityConfig(entities) = format.deserialize(data)?;
+    entities.validate().context("The configuration is invalid.")?;
+    Ok(entities)
+}
+
+fn describe(entities: &Entities) -> String {
+    format!(
+        "{} pool(s), {} sync(s), {} restic, {} remote and {} archive container(s), {} observer(s)",
+        entities.btrfs_pools.len(),
+        entities.snapshot_syncs.len(),
+        entities.restic_containers.len(),
+        entities.remote_containers.len(),
+        entities.archive_containers.len(),
+        entities.observers.len() + entities.notifiers.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libblkcapt::{
+        model::entities::{
+            ArchiveBackend, ArchiveContainerEntity, BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity,
+            HealthchecksObservation, HealthchecksObserverEntity, ObservableEvent, Observation, RcloneBackend,
+            RemoteContainerEntity, ResticContainerEntity, ResticRepository, ScheduleModel, SnapshotSyncEntity,
+            SshTarget,
+        },
+        model::Entity,
+        sys::fs::FsPathBuf,
+    };
+    use std::{convert::TryFrom, time::Duration};
+    use uuid::Uuid;
+
+    fn populated() -> Entities {
+        let mut pool =
+            BtrfsPoolEntity::new("tank".to_owned(), PathBuf::from("/mnt/tank"), Uuid::new_v4(), vec![]).unwrap();
+        let mut dataset = BtrfsDatasetEntity::new("home".to_owned(), FsPathBuf::from("/home"), Uuid::new_v4()).unwrap();
+        dataset.snapshot_schedule = Some(ScheduleModel::try_from(&Duration::from_secs(3600)).unwrap());
+        let container =
+            BtrfsContainerEntity::new("backups".to_owned(), FsPathBuf::from("/backups"), Uuid::new_v4()).unwrap();
+        let sync = SnapshotSyncEntity::new("home-backups".to_owned(), dataset.id(), container.id());
+        let observer = HealthchecksObserverEntity::new(
+            "healthchecks".to_owned(),
+            vec![HealthchecksObservation {
+                observation: Observation {
+                    entity_id: sync.id(),
+                    event: ObservableEvent::SnapshotSync,
+                },
+                healthcheck_id: Uuid::new_v4(),
+            }],
+        );
+        pool.attach_dataset(dataset).unwrap();
+        pool.attach_container(container).unwrap();
+
+        let mut entities = Entities::default();
+        entities.attach_pool(pool).unwrap();
+        entities.snapshot_syncs.push(sync);
+        entities.attach_observer(observer).unwrap();
+        entities.restic_containers.push(ResticContainerEntity::new(
+            "offsite".to_owned(),
+            ResticRepository::Custom("/srv/restic".to_owned()),
+        ));
+        entities.remote_containers.push(RemoteContainerEntity::new(
+            "nas2".to_owned(),
+            SshTarget::new("nas2.lan".to_owned()),
+            PathBuf::from("/mnt/backups"),
+        ));
+        entities.archive_containers.push(ArchiveContainerEntity::new(
+            "cold".to_owned(),
+            ArchiveBackend::Rclone(RcloneBackend {
+                remote: "gdrive:backups".to_owned(),
+                config: None,
+            }),
+        ));
+        entities
+    }
+
+    fn round_trip(format: ConfigFormat) {
+        let entities = populated();
+        let exported = format.serialize(&EntityConfig(entities.clone())).unwrap();
+        let imported = parse_config(format, &exported).unwrap();
+        assert_eq!(
+            serde_json::to_value(&imported).unwrap(),
+            serde_json::to_value(&entities).unwrap()
+        );
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        round_trip(ConfigFormat::Toml);
+    }
+
+    #[test]
+    fn yaml_round_trip() {
+        round_trip(ConfigFormat::Yaml);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(ConfigFormat::Json);
+    }
+
+    #[test]
+    fn import_rejects_dangling_references() {
+        let mut entities = populated();
+        entities.btrfs_pools[0].containers.clear();
+        let exported = ConfigFormat::Json.serialize(&EntityConfig(entities)).unwrap();
+        let error = parse_config(ConfigFormat::Json, &exported).unwrap_err();
+        assert!(format!("{:#}", error).contains("destination container does not exist"));
+
+        let mut entities = populated();
+        entities.snapshot_syncs.clear();
+        let exported = ConfigFormat::Json.serialize(&EntityConfig(entities)).unwrap();
+        assert!(parse_config(ConfigFormat::Json, &exported).is_err());
+    }
+
+    #[test]
+    fn import_rejects_duplicates() {
+        let mut entities = populated();
+        let pool = entities.btrfs_pools[0].clone();
+        entities.btrfs_pools.push(pool);
+        let exported = ConfigFormat::Yaml.serialize(&EntityConfig(entities)).unwrap();
+        assert!(parse_config(ConfigFormat::Yaml, &exported).is_err());
+
+        let mut entities = populated();
+        let dataset = entities.btrfs_pools[0].datasets[0].clone();
+        entities.btrfs_pools[0].datasets.push(dataset);
+        let exported = ConfigFormat::Toml.serialize(&EntityConfig(entities)).unwrap();
+        assert!(parse_config(ConfigFormat::Toml, &exported).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+use anyhow::Result;
+use clap::Clap;
+use comfy_table::{Cell, Color};
+use libblkcapt::{
+    core::BtrfsPool,
+    model::{
+        history::{JobKind, JobRecord},
+        storage, AnyContainer, Entities, Entity, EntityId, EntityPath,
+    },
+};
+use slog_scope::*;
+
+use crate::ui::{comfy_feature_state_cell, format_datetime, print_comfy_table};
+
+#[derive(Clap, Debug)]
+pub struct CoverageOptions {}
+
+pub fn coverage(options: CoverageOptions) -> Result<()> {
+    debug!("Command 'coverage': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let history = storage::load_history()?;
+
+    let mut gaps = 0;
+    let mut unknown = 0;
+    let mut subvolume_rows = Vec::new();
+    for pool_model in entities.btrfs_pools.iter() {
+        let pool = match BtrfsPool::validate(pool_model.clone()) {
+            Ok(pool) => pool,
+            Err(e) => {
+                gaps += 1;
+                subvolume_rows.push(vec![
+                    Cell::new(pool_model.name()),
+                    Cell::new(pool_model.mountpoint_path.display()),
+                    Cell::new(format!("pool unavailable: {:#}", e)).fg(Color::Red),
+                ]);
+                continue;
+            }
+        };
+
+        let subvolumes = match pool.user_subvolumes() {
+            Ok(subvolumes) => subvolumes,
+            Err(e) => {
+                unknown += 1;
+                subvolume_rows.push(vec![
+                    Cell::new(pool_model.name()),
+                    Cell::new(pool_model.mountpoint_path.display()),
+                    Cell::new(format!("subvolumes unavailable: {:#}", e)).fg(Color::Yellow),
+                ]);
+                continue;
+            }
+        };
+        for subvolume in subvolumes {
+            let inside_container = pool_model
+                .containers
+                .iter()
+                .any(|c| subvolume.path != c.path && subvolume.path.starts_with(&c.path));
+            if inside_container {
+                continue;
+            }
+
+            let coverage = if let Some(dataset) = pool_model.datasets.iter().find(|d| d.uuid == subvolume.uuid) {
+                Cell::new(format!("dataset {}", dataset.name())).fg(Color::Green)
+            } else if let Some(container) = pool_model.containers.iter().find(|c| c.uuid == subvolume.uuid) {
+                Cell::new(format!("container {}", container.name()))
+            } else {
+                gaps += 1;
+                Cell::new("not covered").fg(Color::Red)
+            };
+            subvolume_rows.push(vec![
+                Cell::new(pool_model.name()),
+                Cell::new(subvolume.path.as_pathbuf(&pool_model.mountpoint_path).display()),
+                coverage,
+            ]);
+        }
+    }
+
+    print_comfy_table(
+        vec![Cell::new("Pool"), Cell::new("Subvolume"), Cell::new("Coverage")],
+        subvolume_rows.into_iter(),
+    );
+
+    let mut dataset_rows = Vec::new();
+    for dataset in entities.datasets() {
+        let (health, targets) = dataset_coverage(&entities, &history, dataset.id());
+        match health {
+            TargetHealth::Working => {}
+            TargetHealth::Unknown => unknown += 1,
+            TargetHealth::Failing => gaps += 1,
+        }
+
+        let targets_cell = match targets.is_empty() {
+            true => Cell::new("no sync targets"),
+            false => Cell::new(targets.join("\n")),
+        };
+        dataset_rows.push(vec![
+            Cell::new(dataset.path()),
+            comfy_feature_state_cell(dataset.entity.snapshotting_state()),
+            match health {
+                TargetHealth::Working => targets_cell,
+                TargetHealth::Unknown => targets_cell.fg(Color::Yellow),
+                TargetHealth::Failing => targets_cell.fg(Color::Red),
+            },
+        ]);
+    }
+
+    print_comfy_table(
+        vec![
+            Cell::new("Dataset"),
+            Cell::new("Snapshotting"),
+            Cell::new("Sync Targets"),
+        ],
+        dataset_rows.into_iter(),
+    );
+
+    match gaps {
+        0 => println!("No coverage gaps found."),
+        n => println!("{} coverage gap(s) found.", n),
+    }
+    if unknown > 0 {
+        println!("{} item(s) could not be checked, their coverage is unknown.", unknown);
+    }
+    Ok(())
+}
+
+/// How well a sync target protects its dataset, judged by its latest transfer. Ordered from worst to best.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TargetHealth {
+    Failing,
+    /// Nothing was transferred yet, so it's not known whether the target works.
+    Unknown,
+    Working,
+}
+
+/// A dataset is as well protected as its best sync target, and not at all without one. Returns the status line of
+/// each target along with it.
+fn dataset_coverage(entities: &Entities, history: &[JobRecord], dataset_id: EntityId) -> (TargetHealth, Vec<String>) {
+    let targets = entities
+        .snapshot_syncs
+        .iter()
+        .filter(|s| s.dataset_id == dataset_id)
+        .map(|s| sync_target_status(entities, history, s.id(), s.container_id, s.name()))
+        .collect::<Vec<_>>();
+    let health = targets
+        .iter()
+        .map(|(health, _)| *health)
+        .max()
+        .unwrap_or(TargetHealth::Failing);
+    (health, targets.into_iter().map(|(_, status)| status).collect())
+}
+
+fn sync_target_status(
+    entities: &Entities, history: &[JobRecord], sync_id: EntityId, container_id: EntityId, sync_name: &str,
+) -> (TargetHealth, String) {
+    let container_name = match entities.any_container(container_id) {
+        Some(AnyContainer::Btrfs(c)) => c.name().to_owned(),
+        Some(AnyContainer::Restic(c)) => c.name().to_owned(),
+        Some(AnyContainer::Remote(c)) => c.name().to_owned(),
+        Some(AnyContainer::Archive(c)) => c.name().to_owned(),
+        None => return (TargetHealth::Failing, format!("{}: container missing", sync_name)),
+    };
+
+    let last_transfer = history
+        .iter()
+        .filter(|r| r.kind == JobKind::Transfer && r.entity_id == sync_id)
+        .max_by_key(|r| r.started);
+    match last_transfer {
+        Some(record) if !record.succeeded() => (
+            TargetHealth::Failing,
+            format!("{} -> {}: last transfer failed", sync_name, container_name),
+        ),
+        Some(record) => (
+            TargetHealth::Working,
+            format!(
+                "{} -> {}: last transfer {}",
+                sync_name,
+                container_name,
+                format_datetime(record.started)
+            ),
+        ),
+        None => (
+            TargetHealth::Unknown,
+            format!("{} -> {}: no transfers recorded", sync_name, container_name),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use libblkcapt::model::entities::{ResticContainerEntity, ResticRepository, SnapshotSyncEntity};
+    use std::time::Duration;
+
+    fn transfer(sync_id: EntityId, hours_ago: i64, error: Option<&str>) -> JobRecord {
+        JobRecord {
+            kind: JobKind::Transfer,
+            entity_id: sync_id,
+            started: Utc::now() - chrono::Duration::hours(hours_ago),
+            duration: Duration::from_secs(60),
+            bytes: None,
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn targets_are_judged_by_their_latest_transfer() {
+        let mut entities = Entities::default();
+        let container = ResticContainerEntity::new("offsite".to_owned(), ResticRepository::Custom("/srv".to_owned()));
+        let container_id = container.id();
+        entities.restic_containers.push(container);
+        let sync_id = EntityId::new();
+        let status = |history: &[JobRecord]| sync_target_status(&entities, history, sync_id, container_id, "nightly").0;
+
+        assert_eq!(status(&[]), TargetHealth::Unknown);
+        assert_eq!(
+            status(&[transfer(sync_id, 2, Some("receive failed")), transfer(sync_id, 1, None)]),
+            TargetHealth::Working
+        );
+        assert_eq!(
+            status(&[transfer(sync_id, 2, None), transfer(sync_id, 1, Some("receive failed"))]),
+            TargetHealth::Failing
+        );
+        assert_eq!(
+            sync_target_status(&entities, &[], sync_id, EntityId::new(), "nightly").0,
+            TargetHealth::Failing
+        );
+    }
+
+    #[test]
+    fn one_working_target_covers_a_dataset() {
+        let mut entities = Entities::default();
+        let container = ResticContainerEntity::new("offsite".to_owned(), ResticRepository::Custom("/srv".to_owned()));
+        let dataset_id = EntityId::new();
+        let syncs = ["failing", "working", "untried"]
+            .iter()
+            .map(|name| SnapshotSyncEntity::new((*name).to_owned(), dataset_id, container.id()))
+            .collect::<Vec<_>>();
+        let (failing, working) = (syncs[0].id(), syncs[1].id());
+        entities.restic_containers.push(container);
+        entities.snapshot_syncs.extend(syncs);
+        let history = [transfer(failing, 1, Some("receive failed")), transfer(working, 1, None)];
+
+        let (health, targets) = dataset_coverage(&entities, &history, dataset_id);
+        assert_eq!(health, TargetHealth::Working);
+        assert_eq!(targets.len(), 3);
+
+        let (health, _) = dataset_coverage(&entities, &history[..1], dataset_id);
+        assert_eq!(health, TargetHealth::Unknown);
+
+        entities.snapshot_syncs.retain(|s| s.id() == failing);
+        let (health, _) = dataset_coverage(&entities, &history, dataset_id);
+        assert_eq!(health, TargetHealth::Failing);
+
+        let (health, targets) = dataset_coverage(&entities, &history, EntityId::new());
+        assert_eq!(health, TargetHealth::Failing);
+        assert!(targets.is_empty());
+    }
+}
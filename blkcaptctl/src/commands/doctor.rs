@@ -0,0 +1,101 @@
+use anyhow::Result;
+use clap::Clap;
+use comfy_table::{Cell, Color};
+use libblkcapt::{
+    core::{BtrfsDataset, BtrfsPool},
+    model::{storage, Entity},
+    sys::{btrfs::SubvolumeProperties, privilege::running_as_root},
+};
+use slog_scope::*;
+use std::sync::Arc;
+
+use crate::ui::print_comfy_table;
+
+#[derive(Clap, Debug)]
+pub struct DoctorOptions {}
+
+pub fn doctor(options: DoctorOptions) -> Result<()> {
+    debug!("Command 'doctor': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let mut findings = Vec::<(String, Finding, String)>::new();
+
+    for pool_model in entities.btrfs_pools.iter() {
+        let pool = match BtrfsPool::validate(pool_model.clone()) {
+            Ok(pool) => Arc::new(pool),
+            Err(e) => {
+                findings.push((pool_model.name().to_owned(), Finding::Error, format!("{:#}", e)));
+                continue;
+            }
+        };
+
+        for dataset_model in pool_model.datasets.iter() {
+            let path = format!("{}/{}", pool_model.name(), dataset_model.name());
+            let dataset = BtrfsDataset::validate(&pool, dataset_model.clone());
+            if let (Ok(dataset), false) = (&dataset, running_as_root()) {
+                match dataset.unprivileged_access() {
+                    Ok(access) => findings.push((
+                        path.clone(),
+                        Finding::Info,
+                        format!(
+                            "running without root. unavailable: {}.",
+                            access.unavailable_features().join(", ")
+                        ),
+                    )),
+                    Err(e) => findings.push((path.clone(), Finding::Error, format!("{:#}", e))),
+                }
+            }
+
+            let properties = dataset.and_then(|d| d.properties());
+            match properties {
+                Ok(properties) => {
+                    if properties.checksums_disabled() {
+                        findings.push((
+                            path.clone(),
+                            Finding::Warning,
+                            String::from(SubvolumeProperties::CHECKSUMS_DISABLED_WARNING),
+                        ));
+                    }
+                    if properties != dataset_model.properties {
+                        findings.push((
+                            path,
+                            Finding::Info,
+                            String::from("subvolume properties changed since the dataset was attached."),
+                        ));
+                    }
+                }
+                Err(e) => findings.push((path, Finding::Error, format!("{:#}", e))),
+            }
+        }
+    }
+
+    if findings.is_empty() {
+        println!("No problems found.");
+        return Ok(());
+    }
+
+    print_comfy_table(
+        vec![Cell::new("Entity"), Cell::new("Severity"), Cell::new("Finding")],
+        findings
+            .into_iter()
+            .map(|(entity, severity, message)| vec![Cell::new(entity), severity.cell(), Cell::new(message)]),
+    );
+
+    Ok(())
+}
+
+enum Finding {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Finding {
+    fn cell(&self) -> Cell {
+        match self {
+            Finding::Info => Cell::new("Info").fg(Color::Blue),
+            Finding::Warning => Cell::new("Warning").fg(Color::Yellow),
+            Finding::Error => Cell::new("Error").fg(Color::Red),
+        }
+    }
+}
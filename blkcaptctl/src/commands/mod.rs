@@ -6,20 +6,37 @@ use libblkcapt::model::{
     entities::BtrfsDatasetEntity,
     entities::BtrfsPoolEntity,
     entities::{
-        BtrfsContainerEntity, IntervalSpec, KeepSpec, ResticContainerEntity, RetentionRuleset, SnapshotSyncEntity,
+        ArchiveContainerEntity, BtrfsContainerEntity, IntervalSpec, KeepSpec, RemoteContainerEntity,
+        ResticContainerEntity, RetentionRuleset, SnapshotSyncEntity,
     },
     entity_by_name, EntityId, EntityPath, EntityPath1, EntityPath2, EntityStatic, EntityType,
 };
 use libblkcapt::{
-    model::{entities::HealthchecksObserverEntity, Entities},
+    model::{
+        entities::{HealthchecksObserverEntity, NotificationObserverEntity},
+        Entities,
+    },
     model::{entity_by_name_or_id, Entity},
 };
 
 use crate::ui::ScheduleArg;
+pub mod archive;
+pub mod audit;
+pub mod config;
+pub mod coverage;
+pub mod doctor;
+pub mod net;
 pub mod observer;
 pub mod pool;
+pub mod power;
+pub mod recovery;
+pub mod remote;
 pub mod restic;
+pub mod snapshot;
+pub mod stats;
 pub mod sync;
+pub mod tui;
+pub mod upgrade;
 
 pub fn dataset_search<'a>(
     entities: &'a Entities, query: &str,
@@ -47,6 +64,14 @@ pub fn restic_search<'a>(entities: &'a Entities, query: &str) -> Result<&'a Rest
     entity_search1(entities.restic_containers.iter(), query)
 }
 
+pub fn remote_search<'a>(entities: &'a Entities, query: &str) -> Result<&'a RemoteContainerEntity> {
+    entity_search1(entities.remote_containers.iter(), query)
+}
+
+pub fn archive_search<'a>(entities: &'a Entities, query: &str) -> Result<&'a ArchiveContainerEntity> {
+    entity_search1(entities.archive_containers.iter(), query)
+}
+
 pub fn pool_search<'a>(entities: &'a Entities, query: &str) -> Result<&'a BtrfsPoolEntity> {
     entity_search1(entities.btrfs_pools.iter(), query)
 }
@@ -59,14 +84,12 @@ pub fn observer_search<'a>(entities: &'a Entities, query: &str) -> Result<&'a He
     entity_search1(entities.observers.iter(), query)
 }
 
+pub fn notifier_search<'a>(entities: &'a Entities, query: &str) -> Result<&'a NotificationObserverEntity> {
+    entity_search1(entities.notifiers.iter(), query)
+}
+
 pub fn entity_by_type_lookup(entities: &Entities, etype: EntityType, id: EntityId) -> Option<String> {
-    match etype {
-        EntityType::Pool => entities.pool(id).map(|p| p.name().to_owned()),
-        EntityType::Dataset => entities.dataset(id).map(|d| d.path()),
-        EntityType::Container => entities.container(id).map(|d| d.path()),
-        EntityType::SnapshotSync => entities.snapshot_sync(id).map(|s| s.name().to_owned()),
-        EntityType::Observer => entities.observer(id).map(|o| o.name().to_owned()),
-    }
+    entities.entity_path(etype, id)
 }
 
 pub fn entity_by_type_search<'a>(
@@ -81,9 +104,11 @@ pub fn entity_by_type_search<'a>(
         EntityType::SnapshotSync => {
             snapshot_sync_search(entities, query).map(|entity| Box::new(EntityPath1 { entity }) as Box<dyn EntityPath>)
         }
-        EntityType::Observer => {
-            observer_search(entities, query).map(|entity| Box::new(EntityPath1 { entity }) as Box<dyn EntityPath>)
-        }
+        EntityType::Observer => observer_search(entities, query)
+            .map(|entity| Box::new(EntityPath1 { entity }) as Box<dyn EntityPath>)
+            .or_else(|_| {
+                notifier_search(entities, query).map(|entity| Box::new(EntityPath1 { entity }) as Box<dyn EntityPath>)
+            }),
     }
 }
 
@@ -136,6 +161,14 @@ pub struct RetentionCreateUpdateOptions {
     /// Set the schedule for pruning snapshots
     #[clap(long, value_name("cron"))]
     prune_schedule: Option<ScheduleArg>,
+
+    /// Move pruned snapshots to the pool's trash and keep them there this long before deleting them
+    #[clap(long, value_name("duration"), conflicts_with("no-trash"))]
+    trash_period: Option<humantime::Duration>,
+
+    /// Delete pruned snapshots immediately
+    #[clap(long)]
+    no_trash: bool,
 }
 
 impl RetentionCreateUpdateOptions {
@@ -154,6 +187,15 @@ impl RetentionCreateUpdateOptions {
                 retention.evaluation_schedule = schedule.into();
             }
         }
+
+        if let Some(retention) = retention.as_mut() {
+            if let Some(trash_period) = self.trash_period {
+                retention.trash_period = Some(*trash_period);
+            }
+            if self.no_trash {
+                retention.trash_period = None;
+            }
+        }
     }
 }
 
@@ -208,46 +250,229 @@ impl FromStr for IntervalSpecArg {
 }
 
 pub mod service {
-    use anyhow::Result;
+    use anyhow::{bail, Result};
     use bytes::buf::Buf;
     use clap::Clap;
     use comfy_table::Cell;
     use libblkcapt::{
-        core::system::{ActiveState, ActorState, SystemState, TerminalState},
-        model::{storage, BcLogLevel},
-        sys::net::ServiceClient,
+        core::system::{ActiveState, ActorActivity, ActorState, SystemActor, SystemState, TerminalState},
+        model::{
+            access::{ApiRole, ApiToken},
+            storage, BcLogLevel, Entities, Entity, EntityId,
+        },
+        sys::{
+            net::{ServiceClient, API_TOKEN_ENV},
+            polkit::ActionClass,
+        },
     };
+    use slog_scope::*;
+    use std::{collections::HashMap, time::Duration};
 
-    use crate::ui::{comfy_id_header, comfy_name_value, print_comfy_table};
+    use crate::ui::{comfy_id_header, comfy_name_value, comfy_value_or, format_datetime, print_comfy_table};
 
+    /// Show the worker's actors as a tree under the pools, datasets, containers, syncs and observers they work for
     #[derive(Clap, Debug)]
-    pub struct ServiceStatusOptions {}
+    pub struct ServiceStatusOptions {
+        /// List the actors in a flat table ordered by id instead
+        #[clap(long)]
+        flat: bool,
+
+        /// Only show actors of this type
+        #[clap(short('t'), long, value_name("type"))]
+        actor_type: Option<String>,
+
+        /// Skip this many actors, ordered by id
+        #[clap(long, value_name("count"))]
+        offset: Option<usize>,
+
+        /// Show at most this many actors
+        #[clap(short('n'), long, value_name("count"))]
+        limit: Option<usize>,
+    }
+
+    pub async fn service_status(options: ServiceStatusOptions) -> Result<()> {
+        let mut query = Vec::new();
+        if let Some(actor_type) = &options.actor_type {
+            query.push(format!("actor_type={}", actor_type));
+        }
+        if let Some(offset) = options.offset {
+            query.push(format!("offset={}", offset));
+        }
+        if let Some(limit) = options.limit {
+            query.push(format!("limit={}", limit));
+        }
+        let path = if query.is_empty() {
+            String::from("/")
+        } else {
+            format!("/?{}", query.join("&"))
+        };
 
-    pub async fn service_status(_: ServiceStatusOptions) -> Result<()> {
         let client = ServiceClient::default();
-        let result = client.get("/").await?;
+        let result = client.get(&path).await?;
+        if result.status() == hyper::StatusCode::FORBIDDEN {
+            bail!(
+                "not authorized to read the service status. polkit action: {}",
+                ActionClass::ReadStatus.action_id()
+            );
+        }
         let body = hyper::body::aggregate(result).await?;
         let mut system: SystemState = serde_json::from_reader(body.reader())?;
         system.actors.sort_by_key(|a| a.actor_id);
+        let shown = system.actors.len();
 
+        if options.flat {
+            print_actor_table(system.actors);
+        } else {
+            print_actor_tree(&storage::load_entity_config(), system.actors);
+        }
+
+        if system.total > shown {
+            println!("Showing {} of {} actors.", shown, system.total);
+        }
+
+        Ok(())
+    }
+
+    fn print_actor_table(actors: Vec<SystemActor>) {
         print_comfy_table(
             vec![
                 comfy_id_header(),
                 Cell::new("Actor Type"),
                 Cell::new("State"),
                 Cell::new("Substate"),
+                Cell::new("Job"),
+                Cell::new("Queue"),
+                Cell::new("Last Error"),
             ],
-            system.actors.into_iter().map(|a| {
+            actors.into_iter().map(|a| {
+                let status = match &a.actor_state {
+                    ActorState::Started(ActiveState::Running(status)) => Some(status.clone()),
+                    _ => None,
+                };
                 vec![
                     comfy_name_value(a.actor_id),
                     Cell::new(&a.actor_type),
                     actor_state_cell(&a.actor_state),
                     actor_substate_cell(a.actor_state),
+                    comfy_value_or(
+                        status.as_ref().and_then(|s| {
+                            s.current_job.as_ref().map(|job| match s.since {
+                                Some(since) => format!("{} (since {})", job, format_datetime(since)),
+                                None => job.clone(),
+                            })
+                        }),
+                        "",
+                    ),
+                    comfy_value_or(status.as_ref().map(|s| s.queue_depth).filter(|&d| d > 0), ""),
+                    comfy_value_or(status.and_then(|s| s.last_error), "").fg(comfy_table::Color::Red),
                 ]
             }),
         );
+    }
 
-        Ok(())
+    fn print_actor_tree(entities: &Entities, actors: Vec<SystemActor>) {
+        let mut tree = ActorTree::default();
+        for actor in actors {
+            tree.actors.entry(actor.entity_id).or_default().push(actor);
+        }
+
+        for pool in &entities.btrfs_pools {
+            tree.group(0, format!("pool {}", pool.name()), pool.id(), |tree| {
+                for dataset in &pool.datasets {
+                    tree.group(1, format!("dataset {}", dataset.name()), dataset.id(), |_| {});
+                }
+                for container in &pool.containers {
+                    tree.group(1, format!("container {}", container.name()), container.id(), |_| {});
+                }
+            });
+        }
+        let other_containers = entities
+            .restic_containers
+            .iter()
+            .map(|c| (c.name(), c.id()))
+            .chain(entities.remote_containers.iter().map(|c| (c.name(), c.id())))
+            .chain(entities.archive_containers.iter().map(|c| (c.name(), c.id())));
+        for (name, id) in other_containers {
+            tree.group(0, format!("container {}", name), id, |_| {});
+        }
+        for sync in &entities.snapshot_syncs {
+            tree.group(0, format!("sync {}", sync.name()), sync.id(), |_| {});
+        }
+        let observers = entities
+            .observers
+            .iter()
+            .map(|o| (o.name(), o.id()))
+            .chain(entities.notifiers.iter().map(|o| (o.name(), o.id())));
+        for (name, id) in observers {
+            tree.group(0, format!("observer {}", name), id, |_| {});
+        }
+        // the captain and its helpers, along with anything working for an entity not in the local config
+        let mut rest = tree.actors.drain().flat_map(|(_, actors)| actors).collect::<Vec<_>>();
+        rest.sort_by_key(|a| a.actor_id);
+        tree.rows.push((0, String::from("worker"), None));
+        tree.rows
+            .extend(rest.into_iter().map(|a| (1, a.actor_type.clone(), Some(a))));
+
+        let now = chrono::Utc::now();
+        print_comfy_table(
+            vec![
+                Cell::new("Actor"),
+                comfy_id_header(),
+                Cell::new("State"),
+                Cell::new("Substate"),
+                Cell::new("Job"),
+                Cell::new("Last Activity"),
+                Cell::new("Last Error"),
+            ],
+            tree.rows.into_iter().map(|(depth, label, actor)| {
+                let label = format!("{}{}", "  ".repeat(depth), label);
+                let a = match actor {
+                    Some(a) => a,
+                    None => return vec![Cell::new(label).add_attribute(comfy_table::Attribute::Bold)],
+                };
+                let status = match &a.actor_state {
+                    ActorState::Started(ActiveState::Running(status)) => Some(status.clone()),
+                    _ => None,
+                };
+                vec![
+                    Cell::new(label),
+                    comfy_name_value(a.actor_id),
+                    actor_state_cell(&a.actor_state),
+                    actor_substate_cell(a.actor_state),
+                    comfy_value_or(status.as_ref().and_then(|s| s.current_job.clone()), ""),
+                    comfy_value_or(
+                        a.last_activity.map(|t| {
+                            let age = Duration::from_secs((now - t).num_seconds().max(0) as u64);
+                            format!("{} ago", humantime::format_duration(age))
+                        }),
+                        "",
+                    ),
+                    comfy_value_or(status.and_then(|s| s.last_error), "").fg(comfy_table::Color::Red),
+                ]
+            }),
+        );
+    }
+
+    /// Rows of the actor tree: depth, label and, for actor rows, the actor.
+    #[derive(Default)]
+    struct ActorTree {
+        actors: HashMap<Option<EntityId>, Vec<SystemActor>>,
+        rows: Vec<(usize, String, Option<SystemActor>)>,
+    }
+
+    impl ActorTree {
+        /// Add an entity with the actors working for it and its children. Entities without any are left out.
+        fn group(&mut self, depth: usize, label: String, id: EntityId, children: impl FnOnce(&mut Self)) {
+            let start = self.rows.len();
+            self.rows.push((depth, label, None));
+            let actors = self.actors.remove(&Some(id)).unwrap_or_default();
+            self.rows
+                .extend(actors.into_iter().map(|a| (depth + 1, a.actor_type.clone(), Some(a))));
+            children(self);
+            if self.rows.len() == start + 1 {
+                self.rows.pop();
+            }
+        }
     }
 
     pub fn actor_state_cell(state: &ActorState) -> Cell {
@@ -262,7 +487,15 @@ pub mod service {
     pub fn actor_substate_cell(state: ActorState) -> Cell {
         let (message, color) = match state {
             ActorState::Started(active_state) => match active_state {
-                ActiveState::Custom(state) => (state, comfy_table::Color::Green),
+                ActiveState::Running(status) => (
+                    status.activity.to_string(),
+                    match status.activity {
+                        ActorActivity::Idle => comfy_table::Color::Green,
+                        ActorActivity::Active => comfy_table::Color::Cyan,
+                        ActorActivity::Faulted => comfy_table::Color::Red,
+                        ActorActivity::Unreachable => comfy_table::Color::Yellow,
+                    },
+                ),
                 ActiveState::Unresponsive => (ActiveState::Unresponsive.to_string(), comfy_table::Color::Red),
                 ActiveState::Stopping => (ActiveState::Stopping.to_string(), comfy_table::Color::Yellow),
             },
@@ -282,6 +515,48 @@ pub mod service {
         Cell::new(message).fg(color)
     }
 
+    /// Make the running worker pick up entity changes. Only the actors of changed entities restart, schedule and
+    /// retention changes apply without cancelling transfers. The worker config still needs a restart
+    #[derive(Clap, Debug)]
+    pub struct ServiceReloadOptions {}
+
+    pub async fn service_reload(options: ServiceReloadOptions) -> Result<()> {
+        debug!("Command 'service_reload': {:?}", options);
+
+        let response = ServiceClient::default().post("/reload").await?;
+        check_reload_response(response).await?;
+        println!("Worker reloaded the entity config.");
+        Ok(())
+    }
+
+    /// Hand an entity change made with blkcaptctl to the worker, if it runs. The change is recorded in the audit log
+    /// by the command that made it. Returns whether a worker was running.
+    pub async fn reload_running_worker() -> Result<bool> {
+        match ServiceClient::default().post("/reload?recorded=true").await {
+            Ok(response) => check_reload_response(response).await.map(|_| true),
+            Err(e) if e.is_connect() => {
+                debug!("Worker is not running, nothing to reload: {}", e);
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn check_reload_response(response: hyper::Response<hyper::Body>) -> Result<()> {
+        if response.status() == hyper::StatusCode::FORBIDDEN {
+            bail!(
+                "not authorized to reload the worker. polkit action: {}",
+                ActionClass::ManageJobs.action_id()
+            );
+        }
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = hyper::body::to_bytes(response).await?;
+            bail!("worker failed to reload: {} {}", status, String::from_utf8_lossy(&body));
+        }
+        Ok(())
+    }
+
     #[derive(Clap, Debug)]
     pub struct ServiceConfigOptions {
         #[clap(short, long, value_name("level"))]
@@ -298,4 +573,63 @@ pub mod service {
         storage::store_server_config(config)?;
         Ok(())
     }
+
+    #[derive(Clap, Debug)]
+    pub struct ServiceTokenCreateOptions {
+        /// Name used to identify the token when listing or deleting it
+        name: String,
+        /// What the token may do: viewer, operator, or admin
+        #[clap(short, long, value_name("role"), default_value("viewer"))]
+        role: ApiRole,
+    }
+
+    pub fn service_token_create(options: ServiceTokenCreateOptions) -> Result<()> {
+        debug!("Command 'service_token_create': {:?}", options);
+
+        let mut tokens = storage::load_api_tokens()?;
+        let (token, secret) = ApiToken::generate(options.name, options.role);
+        tokens.add(token)?;
+        storage::store_api_tokens(&tokens)?;
+
+        println!("{}", secret);
+        eprintln!(
+            "Store this token now, it can not be shown again. Clients present it with {}.",
+            API_TOKEN_ENV
+        );
+        Ok(())
+    }
+
+    #[derive(Clap, Debug)]
+    pub struct ServiceTokenListOptions {}
+
+    pub fn service_token_list(options: ServiceTokenListOptions) -> Result<()> {
+        debug!("Command 'service_token_list': {:?}", options);
+
+        let tokens = storage::load_api_tokens()?;
+        print_comfy_table(
+            vec![Cell::new("Name"), Cell::new("Role"), Cell::new("Created")],
+            tokens.tokens.into_iter().map(|t| {
+                vec![
+                    comfy_name_value(t.name),
+                    Cell::new(t.role),
+                    Cell::new(format_datetime(t.created)),
+                ]
+            }),
+        );
+        Ok(())
+    }
+
+    #[derive(Clap, Debug)]
+    pub struct ServiceTokenDeleteOptions {
+        /// Name of the token to delete
+        name: String,
+    }
+
+    pub fn service_token_delete(options: ServiceTokenDeleteOptions) -> Result<()> {
+        debug!("Command 'service_token_delete': {:?}", options);
+
+        let mut tokens = storage::load_api_tokens()?;
+        tokens.remove(&options.name)?;
+        storage::store_api_tokens(&tokens)
+    }
 }
@@ -0,0 +1,50 @@
+use anyhow::{bail, Result};
+use bytes::buf::Buf;
+use clap::Clap;
+use libblkcapt::{core::system::NetworkPauseResponse, sys::net::ServiceClient};
+use slog_scope::*;
+
+/// Hold back remote transfers, cancelling any running, until resumed or the worker restarts
+#[derive(Clap, Debug)]
+pub struct NetPauseOptions {}
+
+pub async fn net_pause(options: NetPauseOptions) -> Result<()> {
+    debug!("Command 'net_pause': {:?}", options);
+
+    let response = set_network_paused("/network/pause").await?;
+    println!(
+        "Remote transfers are {}.",
+        if response.paused { "paused" } else { "running" }
+    );
+    Ok(())
+}
+
+/// Let remote transfers run again
+#[derive(Clap, Debug)]
+pub struct NetResumeOptions {}
+
+pub async fn net_resume(options: NetResumeOptions) -> Result<()> {
+    debug!("Command 'net_resume': {:?}", options);
+
+    let response = set_network_paused("/network/resume").await?;
+    println!(
+        "Remote transfers are {}.",
+        if response.paused { "paused" } else { "running" }
+    );
+    Ok(())
+}
+
+async fn set_network_paused(path: &str) -> Result<NetworkPauseResponse> {
+    let response = ServiceClient::default().post(path).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = hyper::body::to_bytes(response).await?;
+        bail!(
+            "worker refused the request: {} {}",
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    let body = hyper::body::aggregate(response).await?;
+    Ok(serde_json::from_reader(body.reader())?)
+}
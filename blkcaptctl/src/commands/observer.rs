@@ -1,11 +1,18 @@
-use super::{entity_by_type_lookup, entity_by_type_search, observer_search};
+use super::{entity_by_type_lookup, entity_by_type_search, notifier_search, observer_search};
 use crate::ui::*;
 use anyhow::{bail, Context, Result};
 use clap::Clap;
 use comfy_table::Cell;
 use hyper::Uri;
-use libblkcapt::core::ObservationRouter;
-use libblkcapt::model::{entity_by_id_mut, entity_by_name_or_id, storage, Entity};
+use libblkcapt::core::{
+    notify::{NotificationContext, NotificationEmitter, NTFY_DEFAULT_SERVER},
+    ObservationRouter,
+};
+use libblkcapt::model::entities::{
+    FeatureState, NotificationObserverEntity, NotificationService, NotifyOn, ScheduleModel,
+};
+use libblkcapt::model::{entity_by_id_mut, entity_by_name_or_id, storage, Entity, EntityId};
+use libblkcapt::sys::net::hostname;
 use libblkcapt::{core::ObservableEventStage, model::entities::HealthchecksHeartbeat};
 use libblkcapt::{
     core::ObservationEmitter,
@@ -16,7 +23,7 @@ use libblkcapt::{
     },
 };
 use slog_scope::*;
-use std::{str::FromStr, time::Duration};
+use std::{fmt::Debug, str::FromStr, time::Duration};
 use uuid::Uuid;
 
 #[derive(Clap, Debug)]
@@ -57,6 +64,13 @@ impl ObserverCreateUpdateOptions {
         self.heartbeat.as_ref().map(|h| h.uuid())
     }
 
+    fn reject_for_notifier(&self) -> Result<()> {
+        if self.custom_url.is_some() || self.heartbeat.is_some() || self.heartbeat_frequency.is_some() {
+            bail!("custom-url and heartbeat options only apply to healthchecks observers.");
+        }
+        Ok(())
+    }
+
     fn maybe_heartbeat_model(&self) -> Result<Option<HealthchecksHeartbeat>> {
         self.validate_frequency()?;
         self.maybe_heartbeat().map_or::<Result<_>, _>(Ok(None), |id| {
@@ -75,13 +89,16 @@ pub struct ObserverCreateOptions {
     #[clap(short, long, default_value = "default")]
     name: String,
 
-    /// Type of observer (must be "healthchecks")
+    /// Type of observer: healthchecks, telegram, ntfy, gotify, webhook, nrdp, snmp_trap, uptime_kuma or zabbix
     #[clap(short('t'), long("type"), value_name("type"), required(true))]
     observer_type: String,
 
     #[clap(flatten)]
     shared: ObserverCreateUpdateOptions,
 
+    #[clap(flatten)]
+    notifier: NotifierOptions,
+
     /// Observations specifications
     #[clap()]
     observations: Vec<ObservationArg>,
@@ -91,8 +108,9 @@ pub fn create_observer(options: ObserverCreateOptions) -> Result<()> {
     let mut entities = storage::load_entity_config();
 
     if options.observer_type != "healthchecks" {
-        bail!("only healthchecks is supported");
+        return create_notifier(entities, options);
     }
+    options.notifier.reject_unused("healthchecks")?;
 
     let observations = build_observation_models(&entities, &options.observations)?;
 
@@ -107,6 +125,319 @@ pub fn create_observer(options: ObserverCreateOptions) -> Result<()> {
     Ok(())
 }
 
+fn create_notifier(mut entities: Entities, options: ObserverCreateOptions) -> Result<()> {
+    options.shared.reject_for_notifier()?;
+    options.notifier.reject_unused(&options.observer_type)?;
+
+    let service = options.notifier.service_model(&options.observer_type)?;
+    let observations = build_notifier_observations(&entities, &options.observations)?;
+
+    let mut notifier = NotificationObserverEntity::new(options.name.clone(), service, observations);
+    options.notifier.update_messages(&mut notifier);
+
+    entities.attach_notifier(notifier)?;
+
+    storage::store_entity_config(entities);
+
+    Ok(())
+}
+
+const OBSERVER_TYPES: &[&str] = &[
+    "healthchecks",
+    "telegram",
+    "ntfy",
+    "gotify",
+    "webhook",
+    "nrdp",
+    "snmp_trap",
+    "uptime_kuma",
+    "zabbix",
+];
+
+/// Options of the push notification, webhook and passive check observer types.
+#[derive(Clap)]
+pub struct NotifierOptions {
+    /// Telegram bot token
+    #[clap(long, value_name("token"))]
+    bot_token: Option<String>,
+
+    /// Telegram chat the bot sends messages to
+    #[clap(long, value_name("chat_id"))]
+    chat_id: Option<String>,
+
+    /// ntfy or Gotify server URL (ntfy default: https://ntfy.sh)
+    #[clap(long, value_name("url"))]
+    server: Option<Uri>,
+
+    /// ntfy topic
+    #[clap(long)]
+    topic: Option<String>,
+
+    /// ntfy access token, Gotify application token, webhook bearer token or NRDP token
+    #[clap(long, value_name("token"))]
+    token: Option<String>,
+
+    /// Webhook URL the event is POSTed to as JSON, NRDP URL or Uptime Kuma push URL
+    #[clap(long, value_name("url"))]
+    url: Option<Uri>,
+
+    /// Zabbix server or proxy
+    #[clap(long, value_name("host[:port]"))]
+    zabbix_server: Option<String>,
+
+    /// Host the NRDP or Zabbix checks belong to (default: this machine's hostname)
+    #[clap(long, value_name("name"))]
+    check_host: Option<String>,
+
+    /// SNMP manager that receives the traps
+    #[clap(long, value_name("host[:port]"))]
+    snmp_target: Option<String>,
+
+    /// SNMP community (default: public)
+    #[clap(long)]
+    community: Option<String>,
+
+    /// Event stages that send a notification: failure, completion or all
+    #[clap(long, value_name("stages"))]
+    notify_on: Option<NotifyOn>,
+
+    /// Message title template. Placeholders: {entity}, {event}, {result}, {error} and {host}. Empty for the default
+    #[clap(long, value_name("template"))]
+    title_template: Option<String>,
+
+    /// Message body template, with the same placeholders and \n for line breaks. Empty for the default
+    #[clap(long, value_name("template"))]
+    message_template: Option<String>,
+
+    /// Collect successes and send them as one digest on this schedule. Failures are still sent right away
+    #[clap(long, value_name("cron"), conflicts_with("no-digest"))]
+    digest_schedule: Option<ScheduleArg>,
+
+    /// Send every notification right away
+    #[clap(long)]
+    no_digest: bool,
+}
+
+impl Debug for NotifierOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NotifierOptions")
+            .field("chat_id", &self.chat_id)
+            .field("server", &self.server)
+            .field("topic", &self.topic)
+            .field("url", &self.url)
+            .field("zabbix_server", &self.zabbix_server)
+            .field("check_host", &self.check_host)
+            .field("snmp_target", &self.snmp_target)
+            .field("notify_on", &self.notify_on)
+            .field("title_template", &self.title_template)
+            .field("message_template", &self.message_template)
+            .field("digest_schedule", &self.digest_schedule)
+            .field("no_digest", &self.no_digest)
+            .finish()
+    }
+}
+
+impl NotifierOptions {
+    fn reject_unused(&self, observer_type: &str) -> Result<()> {
+        const NOTIFIERS: &[&str] = &["telegram", "ntfy", "gotify", "webhook", "snmp_trap"];
+        const MESSAGES: &[&str] = &[
+            "telegram",
+            "ntfy",
+            "gotify",
+            "webhook",
+            "nrdp",
+            "snmp_trap",
+            "uptime_kuma",
+        ];
+        const DIGESTS: &[&str] = &["telegram", "ntfy", "gotify", "webhook"];
+        let args: [(&str, bool, &[&str]); 15] = [
+            ("--bot-token", self.bot_token.is_some(), &["telegram"]),
+            ("--chat-id", self.chat_id.is_some(), &["telegram"]),
+            ("--server", self.server.is_some(), &["ntfy", "gotify"]),
+            ("--topic", self.topic.is_some(), &["ntfy"]),
+            ("--token", self.token.is_some(), &["ntfy", "gotify", "webhook", "nrdp"]),
+            ("--url", self.url.is_some(), &["webhook", "nrdp", "uptime_kuma"]),
+            ("--zabbix-server", self.zabbix_server.is_some(), &["zabbix"]),
+            ("--check-host", self.check_host.is_some(), &["nrdp", "zabbix"]),
+            ("--snmp-target", self.snmp_target.is_some(), &["snmp_trap"]),
+            ("--community", self.community.is_some(), &["snmp_trap"]),
+            ("--notify-on", self.notify_on.is_some(), NOTIFIERS),
+            ("--title-template", self.title_template.is_some(), MESSAGES),
+            ("--message-template", self.message_template.is_some(), MESSAGES),
+            ("--digest-schedule", self.digest_schedule.is_some(), DIGESTS),
+            ("--no-digest", self.no_digest, DIGESTS),
+        ];
+
+        if !OBSERVER_TYPES.contains(&observer_type) {
+            bail!("Observer type must be one of: {}.", OBSERVER_TYPES.join(", "));
+        }
+        if let Some((arg, ..)) = args
+            .iter()
+            .find(|(_, set, types)| *set && !types.contains(&observer_type))
+        {
+            bail!("{} does not apply to {} observers.", arg, observer_type);
+        }
+        Ok(())
+    }
+
+    fn server_url(&self) -> Option<String> {
+        self.server.as_ref().map(|u| u.to_string())
+    }
+
+    fn service_model(&self, observer_type: &str) -> Result<NotificationService> {
+        Ok(match observer_type {
+            "telegram" => NotificationService::Telegram {
+                bot_token: self
+                    .bot_token
+                    .clone()
+                    .context("telegram observers require --bot-token")?,
+                chat_id: self.chat_id.clone().context("telegram observers require --chat-id")?,
+            },
+            "ntfy" => NotificationService::Ntfy {
+                server: self
+                    .server_url()
+                    .filter(|s| s.trim_end_matches('/') != NTFY_DEFAULT_SERVER),
+                topic: self.topic.clone().context("ntfy observers require --topic")?,
+                access_token: self.token.clone(),
+            },
+            "gotify" => NotificationService::Gotify {
+                server: self.server_url().context("gotify observers require --server")?,
+                app_token: self.token.clone().context("gotify observers require --token")?,
+            },
+            "webhook" => NotificationService::Webhook {
+                url: self
+                    .url
+                    .as_ref()
+                    .context("webhook observers require --url")?
+                    .to_string(),
+                token: self.token.clone(),
+            },
+            "nrdp" => NotificationService::Nrdp {
+                url: self.url.as_ref().context("nrdp observers require --url")?.to_string(),
+                token: self.token.clone().context("nrdp observers require --token")?,
+                hostname: self.check_host.clone(),
+            },
+            "snmp_trap" => NotificationService::SnmpTrap {
+                target: self
+                    .snmp_target
+                    .clone()
+                    .context("snmp_trap observers require --snmp-target")?,
+                community: self.community.clone().unwrap_or_else(|| String::from("public")),
+            },
+            "uptime_kuma" => NotificationService::UptimeKuma {
+                push_url: self
+                    .url
+                    .as_ref()
+                    .context("uptime_kuma observers require --url")?
+                    .to_string(),
+            },
+            "zabbix" => NotificationService::Zabbix {
+                server: self
+                    .zabbix_server
+                    .clone()
+                    .context("zabbix observers require --zabbix-server")?,
+                hostname: self.check_host.clone(),
+            },
+            _ => bail!("Observer type must be one of: {}.", OBSERVER_TYPES.join(", ")),
+        })
+    }
+
+    fn update_service(&self, service: &mut NotificationService) -> Result<()> {
+        self.reject_unused(service.kind())?;
+        match service {
+            NotificationService::Telegram { bot_token, chat_id } => {
+                if let Some(token) = &self.bot_token {
+                    *bot_token = token.clone();
+                }
+                if let Some(id) = &self.chat_id {
+                    *chat_id = id.clone();
+                }
+            }
+            NotificationService::Ntfy {
+                server,
+                topic,
+                access_token,
+            } => {
+                if self.server.is_some() {
+                    *server = self
+                        .server_url()
+                        .filter(|s| s.trim_end_matches('/') != NTFY_DEFAULT_SERVER);
+                }
+                if let Some(t) = &self.topic {
+                    *topic = t.clone();
+                }
+                if let Some(token) = &self.token {
+                    *access_token = Some(token.clone()).filter(|t| !t.is_empty());
+                }
+            }
+            NotificationService::Gotify { server, app_token } => {
+                if let Some(url) = self.server_url() {
+                    *server = url;
+                }
+                if let Some(token) = &self.token {
+                    *app_token = token.clone();
+                }
+            }
+            NotificationService::Webhook { url, token } => {
+                if let Some(u) = &self.url {
+                    *url = u.to_string();
+                }
+                if let Some(t) = &self.token {
+                    *token = Some(t.clone()).filter(|t| !t.is_empty());
+                }
+            }
+            NotificationService::Nrdp { url, token, hostname } => {
+                if let Some(u) = &self.url {
+                    *url = u.to_string();
+                }
+                if let Some(t) = &self.token {
+                    *token = t.clone();
+                }
+                if let Some(host) = &self.check_host {
+                    *hostname = Some(host.clone()).filter(|h| !h.is_empty());
+                }
+            }
+            NotificationService::SnmpTrap { target, community } => {
+                if let Some(t) = &self.snmp_target {
+                    *target = t.clone();
+                }
+                if let Some(c) = &self.community {
+                    *community = c.clone();
+                }
+            }
+            NotificationService::UptimeKuma { push_url } => {
+                if let Some(u) = &self.url {
+                    *push_url = u.to_string();
+                }
+            }
+            NotificationService::Zabbix { server, hostname } => {
+                if let Some(s) = &self.zabbix_server {
+                    *server = s.clone();
+                }
+                if let Some(host) = &self.check_host {
+                    *hostname = Some(host.clone()).filter(|h| !h.is_empty());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn update_messages(&self, notifier: &mut NotificationObserverEntity) {
+        if let Some(notify_on) = self.notify_on {
+            notifier.notify_on = notify_on;
+        }
+        if let Some(template) = &self.title_template {
+            notifier.title_template = Some(template.clone()).filter(|t| !t.is_empty());
+        }
+        if let Some(template) = &self.message_template {
+            notifier.message_template = Some(template.clone()).filter(|t| !t.is_empty());
+        }
+        if self.digest_schedule.is_some() || self.no_digest {
+            notifier.digest_schedule = self.digest_schedule.clone().map(ScheduleModel::from);
+        }
+    }
+}
+
 #[derive(Clap, Debug)]
 pub struct ObserverUpdateOptions {
     /// The name or id of the observer
@@ -116,6 +447,9 @@ pub struct ObserverUpdateOptions {
     #[clap(flatten)]
     shared: ObserverCreateUpdateOptions,
 
+    #[clap(flatten)]
+    notifier: NotifierOptions,
+
     /// Observation to add
     #[clap(
         long,
@@ -143,6 +477,11 @@ pub struct ObserverUpdateOptions {
 pub fn update_observer(options: ObserverUpdateOptions) -> Result<()> {
     let mut entities = storage::load_entity_config();
 
+    if let Ok(notifier) = notifier_search(&entities, &options.observer).map(|n| n.id()) {
+        return update_notifier(entities, notifier, options);
+    }
+    options.notifier.reject_unused("healthchecks")?;
+
     let observations = build_observation_models(&entities, &options.add)?;
 
     let observer = observer_search(&entities, &options.observer).map(|o| o.id())?;
@@ -181,6 +520,33 @@ pub fn update_observer(options: ObserverUpdateOptions) -> Result<()> {
     Ok(())
 }
 
+fn update_notifier(mut entities: Entities, notifier: EntityId, options: ObserverUpdateOptions) -> Result<()> {
+    options.shared.reject_for_notifier()?;
+    if options.remove_heartbeat {
+        bail!("--remove-heartbeat does not apply to notification observers.");
+    }
+
+    let observations = build_notifier_observations(&entities, &options.add)?;
+
+    let notifier =
+        entity_by_id_mut(entities.notifiers.as_mut_slice(), notifier).expect("entity exists, found in search");
+
+    let mut removes = options.remove.clone();
+    removes.sort_unstable();
+    for index in removes.iter().rev() {
+        notifier.observations.remove(*index);
+    }
+
+    notifier.observations.extend(observations);
+
+    options.notifier.update_service(&mut notifier.service)?;
+    options.notifier.update_messages(notifier);
+
+    storage::store_entity_config(entities);
+
+    Ok(())
+}
+
 #[derive(Clap, Debug)]
 pub struct ObserverTestOptions {
     /// Send a failure instead of success
@@ -209,6 +575,9 @@ pub async fn test_observer(options: ObserverTestOptions) -> Result<()> {
 
     let entities = storage::load_entity_config();
 
+    if let Ok(notifier) = notifier_search(&entities, &options.observer) {
+        return test_notifier(&entities, notifier, &options).await;
+    }
     let observer = observer_search(&entities, &options.observer)?;
 
     let entity = entity_by_type_search(&entities, options.event.entity_type(), &options.entity)?;
@@ -254,6 +623,50 @@ pub async fn test_observer(options: ObserverTestOptions) -> Result<()> {
     Ok(())
 }
 
+async fn test_notifier(
+    entities: &Entities, notifier: &NotificationObserverEntity, options: &ObserverTestOptions,
+) -> Result<()> {
+    if options.heartbeat {
+        bail!("Notification observers have no heartbeat");
+    }
+
+    let entity = entity_by_type_search(entities, options.event.entity_type(), &options.entity)?;
+    info!("Found {}.", entity.path());
+
+    if !notifier
+        .observations
+        .iter()
+        .any(|o| o.entity_id == entity.id() && o.event == options.event)
+    {
+        bail!("No matching observations found");
+    }
+
+    let stage = match options.fail {
+        true => ObservableEventStage::Failed(String::from("This is a test failure.")),
+        false => ObservableEventStage::Succeeded,
+    };
+    let path = entity.path();
+    let host = hostname();
+    let context = NotificationContext {
+        entity_id: entity.id(),
+        entity: &path,
+        event: options.event,
+        stage: &stage,
+        host: &host,
+        timestamp: chrono::Utc::now(),
+    };
+    NotificationEmitter::new(notifier.service.clone())
+        .send(
+            &context,
+            &context.render(notifier.title_template()),
+            &context.render(notifier.message_template()),
+        )
+        .await?;
+    info!("Test succeeded.");
+
+    Ok(())
+}
+
 #[derive(Clap, Debug)]
 pub struct ObserverListOptions {}
 
@@ -262,24 +675,38 @@ pub fn list_observer(options: ObserverListOptions) -> Result<()> {
 
     let entities = storage::load_entity_config();
 
-    if entities.observers.is_empty() {
+    if entities.observers.is_empty() && entities.notifiers.is_empty() {
         info!("No observers configured")
     } else {
         print_comfy_table(
             vec![
                 comfy_id_header(),
                 Cell::new("Observer Name"),
+                Cell::new("Type"),
                 Cell::new("Observations"),
                 Cell::new("Heartbeat"),
             ],
-            entities.observers.iter().map(|p| {
-                vec![
-                    comfy_id_value(p.id()),
-                    comfy_name_value(p.name()),
-                    Cell::new(p.observations.len()),
-                    comfy_feature_state_cell(p.heartbeat_state()),
-                ]
-            }),
+            entities
+                .observers
+                .iter()
+                .map(|p| {
+                    vec![
+                        comfy_id_value(p.id()),
+                        comfy_name_value(p.name()),
+                        Cell::new("healthchecks"),
+                        Cell::new(p.observations.len()),
+                        comfy_feature_state_cell(p.heartbeat_state()),
+                    ]
+                })
+                .chain(entities.notifiers.iter().map(|n| {
+                    vec![
+                        comfy_id_value(n.id()),
+                        comfy_name_value(n.name()),
+                        Cell::new(n.service.kind()),
+                        Cell::new(n.observations.len()),
+                        comfy_feature_state_cell(FeatureState::Unconfigured),
+                    ]
+                })),
         );
     }
 
@@ -296,6 +723,18 @@ pub struct ObserverDeleteOptions {
 pub fn delete_observer(options: ObserverDeleteOptions) -> Result<()> {
     let mut entities = storage::load_entity_config();
 
+    if let Ok(notifier) = notifier_search(&entities, &options.observer).map(|n| n.id()) {
+        let position = entities
+            .notifiers
+            .iter()
+            .position(|n| n.id() == notifier)
+            .expect("id always exists");
+        let name = entities.notifiers.remove(position).name().to_owned();
+        storage::store_entity_config(entities);
+        info!("Deleted observer '{}'", name);
+        return Ok(());
+    }
+
     let (id, name) = {
         let observer = entity_by_name_or_id(entities.observers.iter(), &options.observer)?;
         (observer.id(), observer.name().to_owned())
@@ -325,6 +764,10 @@ pub struct ObserverShowOptions {
 pub fn show_observer(options: ObserverShowOptions) -> Result<()> {
     let entities = storage::load_entity_config();
 
+    if let Ok(notifier) = notifier_search(&entities, &options.observer) {
+        show_notifier(&entities, notifier);
+        return Ok(());
+    }
     let observer = observer_search(&entities, &options.observer)?;
 
     print_comfy_info(vec![
@@ -385,9 +828,67 @@ pub fn show_observer(options: ObserverShowOptions) -> Result<()> {
     Ok(())
 }
 
+fn show_notifier(entities: &Entities, notifier: &NotificationObserverEntity) {
+    let destination = match &notifier.service {
+        NotificationService::Telegram { chat_id, .. } => format!("Chat {}", chat_id),
+        NotificationService::Ntfy { server, topic, .. } => format!(
+            "{}/{}",
+            server.as_deref().unwrap_or(NTFY_DEFAULT_SERVER).trim_end_matches('/'),
+            topic
+        ),
+        NotificationService::Gotify { server, .. } => server.clone(),
+        NotificationService::Webhook { url, .. } => url.clone(),
+        NotificationService::Nrdp { url, hostname, .. } => {
+            format!("{} (host {})", url, hostname.as_deref().unwrap_or("<this machine>"))
+        }
+        NotificationService::SnmpTrap { target, .. } => target.clone(),
+        NotificationService::UptimeKuma { push_url } => push_url.clone(),
+        NotificationService::Zabbix { server, hostname } => {
+            format!("{} (host {})", server, hostname.as_deref().unwrap_or("<this machine>"))
+        }
+    };
+
+    print_comfy_info(vec![
+        (comfy_id_header(), comfy_id_value_full(notifier.id()).into()),
+        (Cell::new("Name"), comfy_name_value(notifier.name()).into()),
+        (Cell::new("Type"), Cell::new(notifier.service.kind()).into()),
+        (Cell::new("Destination"), Cell::new(destination).into()),
+        (Cell::new("Notify On"), Cell::new(notifier.notify_on).into()),
+        (Cell::new("Title Template"), Cell::new(notifier.title_template()).into()),
+        (
+            Cell::new("Message Template"),
+            Cell::new(notifier.message_template().replace('\n', "\\n")).into(),
+        ),
+        (
+            Cell::new("Digest"),
+            Cell::new(match notifier.digest_schedule {
+                Some(_) => "Successes on schedule",
+                None => "Off",
+            })
+            .into(),
+        ),
+    ]);
+
+    println!();
+
+    print_comfy_table(
+        vec![comfy_index_header(), Cell::new("Entity"), Cell::new("Event")],
+        notifier.observations.iter().enumerate().map(|(i, observation)| {
+            vec![
+                comfy_name_value(i),
+                Cell::new(
+                    find_observed_entity(entities, observation)
+                        .unwrap_or_else(|| format!("{} <MISSING>", observation.entity_id)),
+                ),
+                Cell::new(observation.event),
+            ]
+        }),
+    );
+}
+
 #[derive(Debug)]
 pub struct ObservationArg {
-    healthcheck_id: Uuid,
+    healthcheck_id: Option<Uuid>,
     entity: String,
     event: ObservableEvent,
 }
@@ -398,12 +899,15 @@ impl FromStr for ObservationArg {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let outter = s.split('=').collect::<Vec<_>>();
         let inner = outter[0].split(':').collect::<Vec<_>>();
-        if inner.len() != 2 || outter.len() != 2 {
-            bail!("Observation format is <[path/]entity|id>:<event>=<healthchecks_id>");
+        if inner.len() != 2 || outter.len() > 2 {
+            bail!("Observation format is <[path/]entity|id>:<event>[=<healthchecks_id>]");
         };
         Ok(Self {
             entity: inner[0].to_owned(),
-            healthcheck_id: UuidArg::parse(outter[1]).context("Healthcheck ID is invalid")?,
+            healthcheck_id: outter
+                .get(1)
+                .map(|id| UuidArg::parse(id).context("Healthcheck ID is invalid"))
+                .transpose()?,
             event: ObservableEvent::from_str(inner[1]).context(format!("Event name '{}' is invalid", inner[1]))?,
         })
     }
@@ -412,8 +916,11 @@ impl FromStr for ObservationArg {
 fn build_observation_models(entities: &Entities, args: &[ObservationArg]) -> Result<Vec<HealthchecksObservation>> {
     args.iter()
         .map(|o| {
+            let healthcheck_id = o
+                .healthcheck_id
+                .context("Healthchecks observations require a healthchecks ID")?;
             entity_by_type_search(&entities, o.event.entity_type(), &o.entity).map(|e| HealthchecksObservation {
-                healthcheck_id: o.healthcheck_id,
+                healthcheck_id,
                 observation: Observation {
                     entity_id: e.id(),
                     event: o.event,
@@ -423,6 +930,20 @@ fn build_observation_models(entities: &Entities, args: &[ObservationArg]) -> Res
         .collect::<Result<Vec<_>>>()
 }
 
+fn build_notifier_observations(entities: &Entities, args: &[ObservationArg]) -> Result<Vec<Observation>> {
+    args.iter()
+        .map(|o| {
+            if o.healthcheck_id.is_some() {
+                bail!("Notification observations take no healthchecks ID");
+            }
+            entity_by_type_search(&entities, o.event.entity_type(), &o.entity).map(|e| Observation {
+                entity_id: e.id(),
+                event: o.event,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+}
+
 fn find_observed_entity(entities: &Entities, observation: &Observation) -> Option<String> {
     entity_by_type_lookup(&entities, observation.event.entity_type(), observation.entity_id)
 }
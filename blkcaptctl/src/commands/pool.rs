@@ -1,25 +1,46 @@
 use anyhow::{bail, Context, Result};
+use bytes::buf::Buf;
+use chrono::Utc;
 use clap::Clap;
-use comfy_table::Cell;
+use comfy_table::{Cell, Color};
 use dialoguer::Confirm;
+use hyper::{Body, Response, StatusCode};
 use libblkcapt::{
-    core::{BtrfsContainer, BtrfsDataset, BtrfsPool},
+    core::system::{ConfirmationChallenge, DeletedSnapshotsResponse, RefreshedSnapshotsResponse},
+    core::{
+        parse_snapshot_timestamp,
+        restore::DatasetRestore,
+        retention::{evaluate_pre_upgrade_retention, evaluate_retention},
+        BtrfsContainer, BtrfsDataset, BtrfsPool, Snapshot,
+    },
     model::{entity_by_id_mut, entity_by_name_mut, entity_by_name_or_id, storage, Entity},
 };
 use libblkcapt::{
-    model::entities::ScheduleModel,
+    model::entities::{
+        BootLoader, BootMenuConfig, BtrfsDatasetEntity, BtrfsPoolEntity, DefragConfig, HookFailurePolicy, IntervalSpec,
+        KeepSpec, ScheduleModel, SnapshotHooks, SnapshotQuota, SnapshotQuotaAction, SnapshotSyncEntity,
+        SyncBacklogAction, SyncBacklogLimit,
+    },
     sys::{
-        btrfs::{add_to_fstab, AllocationMode, Filesystem},
+        btrfs::{add_to_fstab, AllocationMode, CompressionAlgorithm, Filesystem},
         fs::{find_mountentry, BlockDeviceIds, BlockDeviceInfo, DevicePathBuf},
+        net::ServiceClient,
     },
 };
 use slog_scope::*;
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    num::{NonZeroU32, NonZeroUsize},
+    path::{Component, PathBuf},
+    sync::Arc,
+};
+use uuid::Uuid;
 
-use super::{dataset_search, pool_search, RetentionCreateUpdateOptions, RetentionUpdateOptions};
+use super::{container_search, dataset_search, pool_search, RetentionCreateUpdateOptions, RetentionUpdateOptions};
 use crate::ui::{
-    comfy_feature_state_cell, comfy_id_header, comfy_id_value, comfy_id_value_full, comfy_name_value, comfy_value_or,
-    print_comfy_info, print_comfy_table, ScheduleArg,
+    comfy_checksums_cell, comfy_feature_state_cell, comfy_id_header, comfy_id_value, comfy_id_value_full,
+    comfy_name_value, comfy_value_or, format_bytes, format_datetime, print_comfy_info, print_comfy_table, ByteSizeArg,
+    CellOrCells, ScheduleArg,
 };
 
 #[derive(Clap, Debug)]
@@ -171,6 +192,126 @@ pub fn attach_pool(options: PoolAttachOptions) -> Result<()> {
     Ok(())
 }
 
+/// Update an existing pool. Snapshot options set the defaults inherited by datasets attached or created later.
+#[derive(Clap, Debug)]
+#[clap(after_help(AFTER_HELP))]
+pub struct PoolUpdateOptions {
+    /// Clear all dataset defaults before applying any other options
+    #[clap(long)]
+    clear_dataset_defaults: bool,
+
+    #[clap(flatten)]
+    defaults: DatasetCreateUpdateOptions,
+
+    /// Limit the snapshot transfers running on this pool at once, within the worker's global limit
+    #[clap(long, value_name("count"), conflicts_with("unlimited-transfers"))]
+    max_concurrent_transfers: Option<NonZeroUsize>,
+
+    /// Remove the pool's transfer limit
+    #[clap(long)]
+    unlimited_transfers: bool,
+
+    /// Hold the pool's snapshots, prunes and syncs until windows on this schedule, so its disks can sleep
+    #[clap(long, value_name("cron"), conflicts_with("no-wake-schedule"))]
+    wake_schedule: Option<ScheduleArg>,
+
+    /// Run the pool's jobs whenever they come due
+    #[clap(long)]
+    no_wake_schedule: bool,
+
+    /// Keep a btrfs quota group for each dataset and container to account their space usage
+    #[clap(long, conflicts_with("no-qgroup-hierarchy"))]
+    qgroup_hierarchy: bool,
+
+    /// Stop maintaining quota groups. Existing groups are left on the filesystem
+    #[clap(long)]
+    no_qgroup_hierarchy: bool,
+
+    /// Set the schedule for discarding unused blocks with fstrim, for pools on SSDs
+    #[clap(long, value_name("cron"), conflicts_with("no-trim-schedule"))]
+    trim_schedule: Option<ScheduleArg>,
+
+    /// Remove the trim schedule
+    #[clap(long)]
+    no_trim_schedule: bool,
+
+    /// Prevent starting new trim jobs on this pool
+    #[clap(long, conflicts_with("resume-trimming"))]
+    pause_trimming: bool,
+
+    #[clap(long)]
+    resume_trimming: bool,
+
+    /// Refuse to snapshot while the pool has less free space than this, e.g. 10G [default: 1G]
+    #[clap(long, value_name("size"))]
+    min_free_space: Option<ByteSizeArg>,
+
+    /// Refuse to snapshot while less than this is left for metadata, only checked as root [default: 256M]
+    #[clap(long, value_name("size"))]
+    min_metadata_headroom: Option<ByteSizeArg>,
+
+    /// The pool to update
+    #[clap(value_name("pool|id"))]
+    pool: String,
+}
+
+pub fn update_pool(options: PoolUpdateOptions) -> Result<()> {
+    debug!("Command 'update_pool': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let pool_id = pool_search(&entities, &options.pool)?.id();
+    let pool = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("always exists if path found");
+
+    let defaults = &mut pool.dataset_defaults;
+    if options.clear_dataset_defaults {
+        *defaults = Default::default();
+    }
+    options.defaults.update_snapshots(&mut defaults.snapshot_schedule);
+    options.defaults.update_rpo(&mut defaults.target_rpo);
+    options
+        .defaults
+        .retention
+        .update_retention(&mut defaults.snapshot_retention);
+
+    if options.max_concurrent_transfers.is_some() || options.unlimited_transfers {
+        pool.max_concurrent_transfers = options.max_concurrent_transfers;
+    }
+
+    if options.wake_schedule.is_some() || options.no_wake_schedule {
+        pool.wake_schedule = options.wake_schedule.map(ScheduleModel::from);
+    }
+
+    if options.qgroup_hierarchy {
+        pool.qgroup_hierarchy = true;
+        let in_use = BtrfsPool::validate(pool.clone())?.qgroups_in_use()?;
+        pool.assign_qgroups(&in_use);
+    } else if options.no_qgroup_hierarchy {
+        pool.qgroup_hierarchy = false;
+        pool.datasets.iter_mut().for_each(|d| d.qgroup = None);
+        pool.containers.iter_mut().for_each(|c| c.qgroup = None);
+    }
+
+    if options.trim_schedule.is_some() || options.no_trim_schedule {
+        pool.trim_schedule = options.trim_schedule.map(ScheduleModel::from);
+    }
+
+    if options.pause_trimming || options.resume_trimming {
+        pool.pause_trimming = options.pause_trimming;
+    }
+
+    if let Some(size) = options.min_free_space {
+        pool.min_free_bytes = Some(size.0);
+    }
+
+    if let Some(size) = options.min_metadata_headroom {
+        pool.min_metadata_headroom_bytes = Some(size.0);
+    }
+
+    storage::store_entity_config(entities);
+
+    Ok(())
+}
+
 #[derive(Clap, Debug)]
 pub struct DatasetAttachOptions {
     /// Existing path to subvolume to attach to.
@@ -178,6 +319,9 @@ pub struct DatasetAttachOptions {
 
     /// Name of the dataset. [default: path basename]
     name: Option<String>,
+
+    #[clap(flatten)]
+    shared: DatasetCreateUpdateOptions,
 }
 
 pub fn attach_dataset(options: DatasetAttachOptions) -> Result<()> {
@@ -200,9 +344,94 @@ pub fn attach_dataset(options: DatasetAttachOptions) -> Result<()> {
     });
 
     let pool = Arc::new(BtrfsPool::validate(pool_model.clone())?);
-    let dataset = BtrfsDataset::new(&pool, name, options.path)?;
+    let mut dataset = BtrfsDataset::new(&pool, name, options.path)?.take_model();
+    pool_model.dataset_defaults.apply(&mut dataset);
+    options.shared.apply(&mut dataset);
+
+    pool_model.attach_dataset(dataset)?;
+    assign_qgroups(pool_model, &pool)?;
+    storage::store_entity_config(entities);
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetCloneConfigOptions {
+    /// The dataset to copy the configuration from
+    #[clap(value_name("[pool/]dataset|id"))]
+    source: String,
+
+    /// Existing path to the subvolume to attach as the new dataset
+    path: PathBuf,
+
+    /// Name of the new dataset. [default: path basename]
+    #[clap(short, long)]
+    name: Option<String>,
+}
+
+pub fn clone_config_dataset(options: DatasetCloneConfigOptions) -> Result<()> {
+    debug!("Command 'clone_config_dataset': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let source = dataset_search(&entities, &options.source)?.entity.clone();
+    let source_syncs = entities
+        .snapshot_syncs
+        .iter()
+        .filter(|s| s.dataset_id == source.id())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mountentry =
+        find_mountentry(&options.path).context(format!("Failed to detect mountpoint for {:?}.", options.path))?;
+    let pool_model = entities
+        .pool_by_mountpoint_mut(mountentry.file.as_path())
+        .context(format!("No pool found for mountpoint {:?}.", mountentry.file))?;
+
+    let path = &options.path;
+    let name = options.name.unwrap_or_else(|| {
+        path.file_name()
+            .expect("Path should end with a directory name.")
+            .to_string_lossy()
+            .to_string()
+    });
+
+    let pool = Arc::new(BtrfsPool::validate(pool_model.clone())?);
+    let mut dataset = BtrfsDataset::new(&pool, name, options.path)?.take_model();
+    dataset.snapshot_schedule = source.snapshot_schedule.clone();
+    dataset.pause_snapshotting = source.pause_snapshotting;
+    dataset.snapshot_retention = source.snapshot_retention.clone();
+    dataset.pause_pruning = source.pause_pruning;
+    dataset.target_rpo = source.target_rpo;
+    dataset.skip_unchanged = source.skip_unchanged;
+    dataset.snapshot_after_wake = source.snapshot_after_wake;
+    dataset.emergency_prune = source.emergency_prune;
+    dataset.critical = source.critical;
+    dataset.pre_upgrade_retention = source.pre_upgrade_retention.clone();
+    dataset.sync_backlog = source.sync_backlog.clone();
+    dataset.snapshot_quota = source.snapshot_quota.clone();
+    dataset.defrag = source.defrag.clone();
+    dataset.snapshot_hooks = source.snapshot_hooks.clone();
+    dataset.boot_menu = source.boot_menu.clone();
+    let dataset_id = dataset.id();
+    let dataset_name = dataset.name().to_owned();
+    pool_model.attach_dataset(dataset)?;
+    assign_qgroups(pool_model, &pool)?;
+
+    for source_sync in source_syncs {
+        let mut sync = SnapshotSyncEntity::new(
+            format!("{}-{}", source_sync.name(), dataset_name),
+            dataset_id,
+            source_sync.container_id,
+        );
+        sync.sync_mode = source_sync.sync_mode;
+        sync.progress_interval = source_sync.progress_interval;
+        sync.compression = source_sync.compression;
+        sync.bandwidth_limit = source_sync.bandwidth_limit;
+        sync.restore_test_schedule = source_sync.restore_test_schedule;
+        println!("Created sync '{}'.", sync.name());
+        entities.snapshot_syncs.push(sync);
+    }
 
-    pool_model.attach_dataset(dataset.take_model())?;
     storage::store_entity_config(entities);
 
     Ok(())
@@ -231,13 +460,11 @@ pub fn create_dataset(options: DatasetCreateOptions) -> Result<()> {
     let dataset = pool.create_dataset(options.name)?;
 
     let mut dataset = dataset.take_model();
-    options.shared.update_snapshots(&mut dataset.snapshot_schedule);
-    options
-        .shared
-        .retention
-        .update_retention(&mut dataset.snapshot_retention);
+    pool_model.dataset_defaults.apply(&mut dataset);
+    options.shared.apply(&mut dataset);
 
     pool_model.attach_dataset(dataset)?;
+    assign_qgroups(pool_model, &pool)?;
     storage::store_entity_config(entities);
 
     Ok(())
@@ -250,21 +477,175 @@ pub struct DatasetShowOptions {
     dataset: String,
 }
 
+/// Give datasets and containers without a quota group one that is free on the filesystem, when the pool keeps a
+/// quota group hierarchy.
+fn assign_qgroups(pool_model: &mut BtrfsPoolEntity, pool: &BtrfsPool) -> Result<()> {
+    if pool_model.qgroup_hierarchy {
+        pool_model.assign_qgroups(&pool.qgroups_in_use()?);
+    }
+    Ok(())
+}
+
+fn format_backlog_limit(limit: &SyncBacklogLimit) -> String {
+    let mut limits = Vec::new();
+    if let Some(max_snapshots) = limit.max_snapshots {
+        limits.push(format!("{} snapshots", max_snapshots));
+    }
+    if let Some(max_bytes) = limit.max_bytes {
+        limits.push(format_bytes(max_bytes));
+    }
+    format!("{} ({})", limits.join(" or "), limit.action)
+}
+
+fn format_snapshot_quota(quota: &SnapshotQuota) -> String {
+    format!("{} ({})", format_bytes(quota.max_referenced), quota.action)
+}
+
 pub fn show_dataset(options: DatasetShowOptions) -> Result<()> {
     debug!("Command 'show_dataset': {:?}", options);
 
     let entities = storage::load_entity_config();
     let dataset = dataset_search(&entities, &options.dataset)?;
 
-    print_comfy_info(vec![
+    let validated = BtrfsPool::validate(dataset.parent.clone())
+        .and_then(|pool| BtrfsDataset::validate(&Arc::new(pool), dataset.entity.clone()))
+        .map(Arc::new)
+        .map_err(|e| warn!("Unable to inspect the dataset subvolume: {:#}", e))
+        .ok();
+    let changed_bytes = validated.as_ref().and_then(|d| {
+        d.latest_snapshot()
+            .and_then(|latest| latest.map(|s| d.changed_bytes_since(&s)).transpose())
+            .map_err(|e| warn!("Unable to determine changes since the latest snapshot: {:#}", e))
+            .ok()
+            .flatten()
+    });
+    // validating detects a restore the stored config doesn't know of yet, the worker does the same when it starts
+    let divergence = validated
+        .as_ref()
+        .map_or(dataset.entity.restore_divergence.as_ref(), |d| {
+            d.model().restore_divergence.as_ref()
+        });
+
+    let properties = &dataset.entity.properties;
+    let mut rows: Vec<(Cell, CellOrCells)> = vec![
         (comfy_id_header(), comfy_id_value_full(dataset.id()).into()),
-        (Cell::new("Pool Name"), comfy_name_value(dataset.name()).into()),
+        (Cell::new("Pool Name"), comfy_name_value(dataset.parent.name()).into()),
         (Cell::new("Dataset Name"), comfy_name_value(dataset.name()).into()),
         (
-            Cell::new("Snaps"),
-            vec![Cell::new("Test1"), Cell::new("Test2"), Cell::new("Test5")].into(),
+            Cell::new("Compression"),
+            comfy_value_or(properties.compression.as_ref(), "None").into(),
+        ),
+        (Cell::new("Data Checksums"), comfy_checksums_cell(properties).into()),
+        (
+            Cell::new("Target RPO"),
+            comfy_value_or(dataset.entity.target_rpo.map(humantime::Duration::from), "None").into(),
+        ),
+        (
+            Cell::new("Unchanged Snapshots"),
+            Cell::new(if dataset.entity.skip_unchanged {
+                "Skipped"
+            } else {
+                "Taken"
+            })
+            .into(),
+        ),
+        (
+            Cell::new("Snapshot After Wake"),
+            comfy_value_or(
+                dataset.entity.snapshot_after_wake.map(humantime::Duration::from),
+                "None",
+            )
+            .into(),
+        ),
+        (
+            Cell::new("Changed Since Latest"),
+            comfy_value_or(changed_bytes.map(format_bytes), "Unknown").into(),
+        ),
+        (
+            Cell::new("Emergency Prune"),
+            Cell::new(if dataset.entity.emergency_prune {
+                "Enabled"
+            } else {
+                "Disabled"
+            })
+            .into(),
+        ),
+        (
+            Cell::new("Pre-upgrade Snapshots"),
+            Cell::new(format!(
+                "{}, keeping newest {} up to {}",
+                dataset.entity.pre_upgrade_snapshots.len(),
+                dataset.entity.pre_upgrade_retention.newest_count,
+                humantime::format_duration(dataset.entity.pre_upgrade_retention.max_age)
+            ))
+            .into(),
+        ),
+        (
+            Cell::new("Critical"),
+            Cell::new(if dataset.entity.critical { "Yes" } else { "No" }).into(),
+        ),
+        (
+            Cell::new("Sync Backlog Limit"),
+            comfy_value_or(dataset.entity.sync_backlog.as_ref().map(format_backlog_limit), "None").into(),
+        ),
+        (
+            Cell::new("Quota Group"),
+            comfy_value_or(dataset.entity.qgroup, "None").into(),
+        ),
+        (
+            Cell::new("Snapshot Quota"),
+            comfy_value_or(
+                dataset.entity.snapshot_quota.as_ref().map(format_snapshot_quota),
+                "None",
+            )
+            .into(),
         ),
-    ]);
+        (
+            Cell::new("Defrag"),
+            comfy_feature_state_cell(dataset.entity.defrag_state()).into(),
+        ),
+        (
+            Cell::new("Snapshot Hooks"),
+            comfy_value_or(
+                dataset.entity.snapshot_hooks.as_ref().map(format_snapshot_hooks),
+                "None",
+            )
+            .into(),
+        ),
+        (
+            Cell::new("Boot Menu"),
+            comfy_value_or(dataset.entity.boot_menu.as_ref().map(format_boot_menu), "None").into(),
+        ),
+    ];
+
+    match divergence {
+        Some(divergence) => rows.extend(vec![
+            (Cell::new("State"), Cell::new("Restored").fg(Color::Yellow).into()),
+            (
+                Cell::new("Restored From"),
+                comfy_id_value_full(divergence.source_snapshot).into(),
+            ),
+            (
+                Cell::new("Diverged At"),
+                comfy_value_or(divergence.diverged_at.map(format_datetime), "Unknown").into(),
+            ),
+            (
+                Cell::new(""),
+                Cell::new(
+                    "Snapshots taken before the restore belong to the replaced subvolume. \
+                    They will not be used as parents for incremental sends.",
+                )
+                .into(),
+            ),
+        ]),
+        None => rows.push((Cell::new("State"), Cell::new("Original").into())),
+    }
+
+    rows.push((
+        Cell::new("Snaps"),
+        vec![Cell::new("Test1"), Cell::new("Test2"), Cell::new("Test5")].into(),
+    ));
+    print_comfy_info(rows);
 
     Ok(())
 }
@@ -305,6 +686,10 @@ pub struct DatasetCreateUpdateOptions {
     #[clap(short('s'), long, value_name("cron"))]
     snapshot_schedule: Option<ScheduleArg>,
 
+    /// Set the maximum acceptable age of the newest snapshot on each sync target
+    #[clap(long, value_name("duration"))]
+    target_rpo: Option<humantime::Duration>,
+
     #[clap(flatten)]
     retention: RetentionCreateUpdateOptions,
 }
@@ -315,6 +700,354 @@ impl DatasetCreateUpdateOptions {
             *schedule = self.snapshot_schedule.clone().map(|s| s.into());
         }
     }
+
+    fn update_rpo(&self, target_rpo: &mut Option<std::time::Duration>) {
+        if let Some(rpo) = self.target_rpo {
+            *target_rpo = Some(*rpo);
+        }
+    }
+
+    fn apply(&self, dataset: &mut BtrfsDatasetEntity) {
+        self.update_snapshots(&mut dataset.snapshot_schedule);
+        self.update_rpo(&mut dataset.target_rpo);
+        self.retention.update_retention(&mut dataset.snapshot_retention);
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct SyncBacklogOptions {
+    /// Limit the snapshots that haven't reached every sync target yet
+    #[clap(long, value_name("count"))]
+    backlog_max_snapshots: Option<usize>,
+
+    /// Limit the data written since the newest snapshot every sync target has, e.g. 50G
+    #[clap(long, value_name("size"))]
+    backlog_max_size: Option<ByteSizeArg>,
+
+    /// What to do when the sync backlog is over its limit: alert, pause or slow:<interval> [default: alert]
+    #[clap(long, value_name("action"))]
+    backlog_action: Option<SyncBacklogAction>,
+
+    /// Remove the sync backlog limit
+    #[clap(long, conflicts_with_all(&["backlog-max-snapshots", "backlog-max-size", "backlog-action"]))]
+    clear_backlog_limit: bool,
+}
+
+impl SyncBacklogOptions {
+    fn update_limit(&self, limit: &mut Option<SyncBacklogLimit>) -> Result<()> {
+        if self.clear_backlog_limit {
+            *limit = None;
+            return Ok(());
+        }
+        if self.backlog_max_snapshots.is_none() && self.backlog_max_size.is_none() && self.backlog_action.is_none() {
+            return Ok(());
+        }
+
+        let limit = limit.get_or_insert(SyncBacklogLimit {
+            max_snapshots: None,
+            max_bytes: None,
+            action: SyncBacklogAction::Alert,
+        });
+        if self.backlog_max_snapshots.is_some() {
+            limit.max_snapshots = self.backlog_max_snapshots;
+        }
+        if let Some(size) = self.backlog_max_size {
+            limit.max_bytes = Some(size.0);
+        }
+        if let Some(action) = &self.backlog_action {
+            limit.action = action.clone();
+        }
+        if limit.max_snapshots.is_none() && limit.max_bytes.is_none() {
+            bail!("A sync backlog limit needs --backlog-max-snapshots or --backlog-max-size.");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct SnapshotQuotaOptions {
+    /// Limit the space referenced by the dataset and its snapshots, e.g. 500G. Requires the pool's quota group
+    /// hierarchy
+    #[clap(long, value_name("size"))]
+    quota_max_size: Option<ByteSizeArg>,
+
+    /// What to do when the dataset is over its quota: alert or prune [default: alert]
+    #[clap(long, value_name("action"))]
+    quota_action: Option<SnapshotQuotaAction>,
+
+    /// Remove the snapshot quota
+    #[clap(long, conflicts_with_all(&["quota-max-size", "quota-action"]))]
+    clear_quota: bool,
+}
+
+impl SnapshotQuotaOptions {
+    fn update_quota(&self, dataset: &mut BtrfsDatasetEntity) -> Result<()> {
+        if self.clear_quota {
+            dataset.snapshot_quota = None;
+            return Ok(());
+        }
+        if self.quota_max_size.is_none() && self.quota_action.is_none() {
+            return Ok(());
+        }
+        if dataset.qgroup.is_none() {
+            bail!("The dataset has no quota group. Enable it with `pool update --qgroup-hierarchy` first.");
+        }
+
+        match (&mut dataset.snapshot_quota, self.quota_max_size) {
+            (Some(quota), size) => {
+                if let Some(size) = size {
+                    quota.max_referenced = size.0;
+                }
+                if let Some(action) = self.quota_action {
+                    quota.action = action;
+                }
+            }
+            (None, Some(size)) => {
+                dataset.snapshot_quota = Some(SnapshotQuota {
+                    max_referenced: size.0,
+                    action: self.quota_action.unwrap_or(SnapshotQuotaAction::Alert),
+                });
+            }
+            (None, None) => bail!("A snapshot quota needs --quota-max-size."),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct DefragOptions {
+    /// Set the schedule for defragmenting this dataset
+    #[clap(long, value_name("cron"))]
+    defrag_schedule: Option<ScheduleArg>,
+
+    /// Defragment only this file or directory, relative to the dataset root. May be repeated
+    #[clap(long, value_name("path"), multiple_occurrences(true), multiple_values(false))]
+    defrag_path: Vec<PathBuf>,
+
+    /// Recompress defragmented data: zlib, lzo or zstd
+    #[clap(long, value_name("algorithm"))]
+    defrag_compress: Option<CompressionAlgorithm>,
+
+    /// Prevent starting new defrag jobs on this dataset
+    #[clap(long, conflicts_with("resume-defrag"))]
+    pause_defrag: bool,
+
+    #[clap(long)]
+    resume_defrag: bool,
+
+    /// Remove the defrag job
+    #[clap(long, conflicts_with_all(&["defrag-schedule", "defrag-path", "defrag-compress"]))]
+    clear_defrag: bool,
+}
+
+impl DefragOptions {
+    fn update_defrag(&self, defrag: &mut Option<DefragConfig>) -> Result<()> {
+        if self.clear_defrag {
+            *defrag = None;
+            return Ok(());
+        }
+        if let Some(path) = self
+            .defrag_path
+            .iter()
+            .find(|p| !p.components().all(|c| matches!(c, Component::Normal(_))))
+        {
+            bail!("Defrag path {:?} must be relative to the dataset root.", path);
+        }
+
+        if defrag.is_none() {
+            match &self.defrag_schedule {
+                Some(schedule) => {
+                    *defrag = Some(DefragConfig {
+                        schedule: schedule.clone().into(),
+                        paths: Vec::new(),
+                        compress: None,
+                        paused: false,
+                    })
+                }
+                None if self.defrag_path.is_empty() && self.defrag_compress.is_none() => return Ok(()),
+                None => bail!("A defrag job needs --defrag-schedule."),
+            }
+        }
+
+        let defrag = defrag.as_mut().expect("created above");
+        if let Some(schedule) = &self.defrag_schedule {
+            defrag.schedule = schedule.clone().into();
+        }
+        if !self.defrag_path.is_empty() {
+            defrag.paths = self.defrag_path.clone();
+        }
+        if self.defrag_compress.is_some() {
+            defrag.compress = self.defrag_compress;
+        }
+        if self.pause_defrag || self.resume_defrag {
+            defrag.paused = self.pause_defrag;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct BootMenuOptions {
+    /// Add boot menu entries for this dataset's newest snapshots: grub or systemd_boot. Only for the root dataset
+    #[clap(long, value_name("loader"))]
+    boot_menu: Option<BootLoader>,
+
+    /// Number of the newest snapshots that get a boot menu entry
+    #[clap(long, value_name("count"))]
+    boot_menu_entries: Option<NonZeroU32>,
+
+    /// Kernel image as the bootloader finds it, e.g. /vmlinuz-linux
+    #[clap(long, value_name("path"))]
+    boot_kernel: Option<String>,
+
+    /// Initramfs image as the bootloader finds it, microcode first. May be repeated
+    #[clap(long, value_name("path"), multiple_occurrences(true), multiple_values(false))]
+    boot_initrd: Vec<String>,
+
+    /// Mountpoint of the ESP or XBOOTLDR partition holding systemd-boot's loader directory
+    #[clap(long, value_name("path"))]
+    boot_path: Option<PathBuf>,
+
+    /// Stop generating boot menu entries
+    #[clap(long, conflicts_with_all(&["boot-menu", "boot-menu-entries", "boot-kernel", "boot-initrd", "boot-path"]))]
+    no_boot_menu: bool,
+}
+
+impl BootMenuOptions {
+    fn update_boot_menu(&self, boot_menu: &mut Option<BootMenuConfig>) -> Result<()> {
+        if self.no_boot_menu {
+            *boot_menu = None;
+            return Ok(());
+        }
+
+        if boot_menu.is_none() {
+            match (self.boot_menu, &self.boot_kernel) {
+                (Some(loader), Some(kernel)) => *boot_menu = Some(BootMenuConfig::new(loader, kernel.clone())),
+                (Some(_), None) => bail!("A boot menu needs --boot-kernel."),
+                (None, _)
+                    if self.boot_menu_entries.is_none()
+                        && self.boot_kernel.is_none()
+                        && self.boot_initrd.is_empty()
+                        && self.boot_path.is_none() =>
+                {
+                    return Ok(())
+                }
+                (None, _) => bail!("A boot menu needs --boot-menu."),
+            }
+        }
+
+        let boot_menu = boot_menu.as_mut().expect("created above");
+        if let Some(loader) = self.boot_menu {
+            boot_menu.loader = loader;
+        }
+        if let Some(entries) = self.boot_menu_entries {
+            boot_menu.entries = entries;
+        }
+        if let Some(kernel) = &self.boot_kernel {
+            boot_menu.kernel = kernel.clone();
+        }
+        if !self.boot_initrd.is_empty() {
+            boot_menu.initrds = self.boot_initrd.clone();
+        }
+        if let Some(path) = &self.boot_path {
+            if !path.is_absolute() {
+                bail!("Boot path {:?} must be absolute.", path);
+            }
+            boot_menu.boot_path = path.clone();
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct SnapshotHookOptions {
+    /// Shell command to run before each snapshot, e.g. to flush a database to disk
+    #[clap(long, value_name("command"))]
+    pre_snapshot_hook: Option<String>,
+
+    /// Shell command to run after each snapshot, whether or not it was taken
+    #[clap(long, value_name("command"))]
+    post_snapshot_hook: Option<String>,
+
+    /// How long a hook command may run before it is killed [default: 60s]
+    #[clap(long, value_name("duration"))]
+    hook_timeout: Option<humantime::Duration>,
+
+    /// What to do when the pre-snapshot hook fails: abort or continue [default: abort]
+    #[clap(long, value_name("policy"))]
+    hook_failure: Option<HookFailurePolicy>,
+
+    /// Remove the snapshot hooks
+    #[clap(
+        long,
+        conflicts_with_all(&["pre-snapshot-hook", "post-snapshot-hook", "hook-timeout", "hook-failure"])
+    )]
+    clear_hooks: bool,
+}
+
+impl SnapshotHookOptions {
+    fn update_hooks(&self, hooks: &mut Option<SnapshotHooks>) -> Result<()> {
+        if self.clear_hooks {
+            *hooks = None;
+            return Ok(());
+        }
+        if hooks.is_none() {
+            if self.pre_snapshot_hook.is_none() && self.post_snapshot_hook.is_none() {
+                if self.hook_timeout.is_some() || self.hook_failure.is_some() {
+                    bail!("Snapshot hooks need --pre-snapshot-hook or --post-snapshot-hook.");
+                }
+                return Ok(());
+            }
+            *hooks = Some(SnapshotHooks {
+                pre: None,
+                post: None,
+                timeout: None,
+                on_failure: Default::default(),
+            });
+        }
+
+        let hooks = hooks.as_mut().expect("created above");
+        if self.pre_snapshot_hook.is_some() {
+            hooks.pre = self.pre_snapshot_hook.clone();
+        }
+        if self.post_snapshot_hook.is_some() {
+            hooks.post = self.post_snapshot_hook.clone();
+        }
+        if let Some(timeout) = self.hook_timeout {
+            hooks.timeout = Some(*timeout);
+        }
+        if let Some(policy) = self.hook_failure {
+            hooks.on_failure = policy;
+        }
+        Ok(())
+    }
+}
+
+fn format_snapshot_hooks(hooks: &SnapshotHooks) -> String {
+    let mut parts = Vec::new();
+    if let Some(pre) = &hooks.pre {
+        parts.push(format!("pre: {}", pre));
+    }
+    if let Some(post) = &hooks.post {
+        parts.push(format!("post: {}", post));
+    }
+    parts.push(format!(
+        "timeout {}, on failure {}",
+        humantime::format_duration(hooks.timeout()),
+        hooks.on_failure
+    ));
+    parts.join("\n")
+}
+
+fn format_boot_menu(boot_menu: &BootMenuConfig) -> String {
+    let mut parts = vec![
+        format!("{}, newest {}", boot_menu.loader, boot_menu.entries),
+        format!("kernel: {}", boot_menu.kernel),
+    ];
+    if !boot_menu.initrds.is_empty() {
+        parts.push(format!("initrd: {}", boot_menu.initrds.join(" ")));
+    }
+    parts.join("\n")
 }
 
 const AFTER_HELP: &str = r"RETENTION
@@ -333,6 +1066,59 @@ pub struct DatasetUpdateOptions {
     #[clap(long)]
     resume_snapshotting: bool,
 
+    /// Skip scheduled snapshots when nothing was written since the latest snapshot
+    #[clap(long, conflicts_with("always-snapshot"))]
+    skip_unchanged: bool,
+
+    /// Take every scheduled snapshot, even when nothing changed
+    #[clap(long)]
+    always_snapshot: bool,
+
+    /// Also snapshot this long after the system boots or resumes from suspend, for machines often off on schedule
+    #[clap(long, value_name("duration"), conflicts_with("no-snapshot-after-wake"))]
+    snapshot_after_wake: Option<humantime::Duration>,
+
+    /// Only take scheduled snapshots
+    #[clap(long)]
+    no_snapshot_after_wake: bool,
+
+    /// Prune down to the newest retained snapshots when the pool is too full to take a snapshot
+    #[clap(long, conflicts_with("no-emergency-prune"))]
+    emergency_prune: bool,
+
+    #[clap(long)]
+    no_emergency_prune: bool,
+
+    /// Snapshot and sync the dataset right away when the UPS battery runs low
+    #[clap(long, conflicts_with("no-critical"))]
+    critical: bool,
+
+    #[clap(long)]
+    no_critical: bool,
+
+    /// Number of pre-upgrade snapshots to keep
+    #[clap(long, value_name("count"))]
+    pre_upgrade_keep: Option<NonZeroU32>,
+
+    /// Prune pre-upgrade snapshots older than this, even the newest
+    #[clap(long, value_name("duration"))]
+    pre_upgrade_max_age: Option<humantime::Duration>,
+
+    #[clap(flatten)]
+    sync_backlog: SyncBacklogOptions,
+
+    #[clap(flatten)]
+    snapshot_quota: SnapshotQuotaOptions,
+
+    #[clap(flatten)]
+    defrag: DefragOptions,
+
+    #[clap(flatten)]
+    snapshot_hooks: SnapshotHookOptions,
+
+    #[clap(flatten)]
+    boot_menu: BootMenuOptions,
+
     #[clap(flatten)]
     shared: DatasetCreateUpdateOptions,
 
@@ -363,11 +1149,42 @@ pub fn update_dataset(options: DatasetUpdateOptions) -> Result<()> {
     };
 
     options.shared.update_snapshots(&mut dataset.snapshot_schedule);
+    options.shared.update_rpo(&mut dataset.target_rpo);
 
     if options.pause_snapshotting || options.resume_snapshotting {
         dataset.pause_snapshotting = options.pause_snapshotting
     }
 
+    if options.skip_unchanged || options.always_snapshot {
+        dataset.skip_unchanged = options.skip_unchanged
+    }
+
+    if options.snapshot_after_wake.is_some() || options.no_snapshot_after_wake {
+        dataset.snapshot_after_wake = options.snapshot_after_wake.map(Into::into);
+    }
+
+    if options.emergency_prune || options.no_emergency_prune {
+        dataset.emergency_prune = options.emergency_prune
+    }
+
+    if options.critical || options.no_critical {
+        dataset.critical = options.critical
+    }
+
+    if let Some(keep) = options.pre_upgrade_keep {
+        dataset.pre_upgrade_retention.newest_count = keep;
+    }
+
+    if let Some(max_age) = options.pre_upgrade_max_age {
+        dataset.pre_upgrade_retention.max_age = max_age.into();
+    }
+
+    options.sync_backlog.update_limit(&mut dataset.sync_backlog)?;
+    options.snapshot_quota.update_quota(dataset)?;
+    options.defrag.update_defrag(&mut dataset.defrag)?;
+    options.snapshot_hooks.update_hooks(&mut dataset.snapshot_hooks)?;
+    options.boot_menu.update_boot_menu(&mut dataset.boot_menu)?;
+
     options.retention_update.update_pruning(&mut dataset.pause_pruning);
     options
         .shared
@@ -416,11 +1233,12 @@ pub fn attach_container(options: ContainerAttachOptions) -> Result<()> {
     let pool = Arc::new(BtrfsPool::validate(pool_model.clone())?);
     let container = BtrfsContainer::new(&pool, name, options.path)?;
 
-    let pool = entities
+    let pool_model = entities
         .pool_by_mountpoint_mut(mountentry.file.as_path())
         .context(format!("No pool found for mountpoint {:?}.", mountentry.file))?;
 
-    pool.attach_container(container.take_model())?;
+    pool_model.attach_container(container.take_model())?;
+    assign_qgroups(pool_model, &pool)?;
     storage::store_entity_config(entities);
 
     Ok(())
@@ -454,6 +1272,39 @@ pub fn create_container(options: ContainerCreateOptions) -> Result<()> {
         .update_retention(&mut container.snapshot_retention);
 
     pool_model.attach_container(container)?;
+    assign_qgroups(pool_model, &pool)?;
+    storage::store_entity_config(entities);
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ContainerUpdateOptions {
+    /// The container to update
+    #[clap(value_name("[pool/]container|id"))]
+    container: String,
+
+    /// Keep a checksum manifest of every received snapshot and verify the snapshots against them on this schedule
+    #[clap(long, value_name("cron"), conflicts_with("no-verify-schedule"))]
+    verify_schedule: Option<ScheduleArg>,
+
+    /// Stop verifying snapshots. Existing manifests are left in the container
+    #[clap(long)]
+    no_verify_schedule: bool,
+}
+
+pub fn update_container(options: ContainerUpdateOptions) -> Result<()> {
+    debug!("Command 'update_container': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let container_path = container_search(&entities, &options.container)?.into_id_path();
+    let pool = entity_by_id_mut(&mut entities.btrfs_pools, container_path.parent).expect("always exists if path found");
+    let container = entity_by_id_mut(&mut pool.containers, container_path.entity).expect("always exists if path found");
+
+    if options.verify_schedule.is_some() || options.no_verify_schedule {
+        container.verify_schedule = options.verify_schedule.map(ScheduleModel::from);
+    }
+
     storage::store_entity_config(entities);
 
     Ok(())
@@ -486,3 +1337,455 @@ pub fn list_container(options: ContainerListOptions) -> Result<()> {
 
     Ok(())
 }
+
+#[derive(Clap, Debug)]
+pub struct PoolTrashOptions {
+    /// The pool [pool|id]
+    pool: String,
+}
+
+pub fn trash_pool(options: PoolTrashOptions) -> Result<()> {
+    debug!("Command 'trash_pool': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let pool = BtrfsPool::validate(pool_search(&entities, &options.pool)?.clone())?;
+    let trashed = pool.trashed_snapshots()?;
+
+    if trashed.is_empty() {
+        println!("Trash is empty.");
+        return Ok(());
+    }
+
+    print_comfy_table(
+        vec![Cell::new("UUID"), Cell::new("Pruned From"), Cell::new("Deleted After")],
+        trashed.into_iter().map(|t| {
+            vec![
+                comfy_id_value_full(t.subvolume.uuid),
+                Cell::new(t.original_path.as_ref().display()),
+                Cell::new(format_datetime(t.expires)),
+            ]
+        }),
+    );
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct PoolUntrashOptions {
+    /// The pool [pool|id]
+    pool: String,
+
+    /// UUID of the trashed snapshot to restore
+    uuid: Uuid,
+}
+
+pub fn untrash_pool(options: PoolUntrashOptions) -> Result<()> {
+    debug!("Command 'untrash_pool': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let pool = BtrfsPool::validate(pool_search(&entities, &options.pool)?.clone())?;
+    let restored_path = pool.restore_trashed(options.uuid)?;
+    println!(
+        "Restored snapshot to {}. It will be pruned again unless the retention rules change.",
+        restored_path.as_ref().display()
+    );
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ContainerDeleteDataOptions {
+    /// The container to delete from
+    #[clap(value_name("[pool/]container|id"))]
+    container: String,
+
+    /// The source dataset whose snapshots are deleted
+    #[clap(value_name("[pool/]dataset|id"))]
+    dataset: String,
+}
+
+pub async fn delete_container_data(options: ContainerDeleteDataOptions) -> Result<()> {
+    debug!("Command 'delete_container_data': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let container = container_search(&entities, &options.container)?;
+    let dataset = dataset_search(&entities, &options.dataset)?;
+    let path = format!("/containers/{}/datasets/{}", container.entity.id(), dataset.entity.id());
+
+    let client = ServiceClient::default();
+    let response = client.delete(&path, None).await?;
+    if response.status() != StatusCode::PRECONDITION_REQUIRED {
+        bail!("worker refused the request: {}", response_message(response).await?);
+    }
+    let body = hyper::body::aggregate(response).await?;
+    let challenge: ConfirmationChallenge = serde_json::from_reader(body.reader())?;
+
+    if !Confirm::new()
+        .with_prompt(format!(
+            "Are you sure you want to delete all snapshots of dataset '{}' from container '{}'?",
+            dataset.entity.name(),
+            container.entity.name()
+        ))
+        .interact()?
+    {
+        println!();
+        bail!("user aborted");
+    }
+
+    let response = client.delete(&path, Some(&challenge.token)).await?;
+    if !response.status().is_success() {
+        bail!("worker refused the request: {}", response_message(response).await?);
+    }
+    let body = hyper::body::aggregate(response).await?;
+    let result: DeletedSnapshotsResponse = serde_json::from_reader(body.reader())?;
+    println!("Deleted {} snapshots.", result.deleted);
+
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetRefreshOptions {
+    /// The dataset to re-scan
+    #[clap(value_name("[pool/]dataset|id"))]
+    dataset: String,
+}
+
+pub async fn refresh_dataset(options: DatasetRefreshOptions) -> Result<()> {
+    debug!("Command 'refresh_dataset': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let dataset = dataset_search(&entities, &options.dataset)?;
+    refresh_snapshots(&format!("/datasets/{}/refresh", dataset.entity.id())).await
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetPruneOptions {
+    /// The dataset to prune by its retention rules
+    #[clap(value_name("[pool/]dataset|id"))]
+    dataset: String,
+
+    /// Only show which snapshots the rules keep or delete, and why, without deleting anything
+    #[clap(long)]
+    dry_run: bool,
+}
+
+fn format_interval(interval: &IntervalSpec) -> String {
+    let keep = match interval.keep {
+        KeepSpec::Newest(count) => count.to_string(),
+        KeepSpec::All => String::from("all"),
+    };
+    format!(
+        "{}x{}:{}",
+        interval.repeat,
+        humantime::format_duration(interval.duration),
+        keep
+    )
+}
+
+pub async fn prune_dataset(options: DatasetPruneOptions) -> Result<()> {
+    debug!("Command 'prune_dataset': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let dataset = dataset_search(&entities, &options.dataset)?;
+    let rules = dataset
+        .entity
+        .snapshot_retention
+        .as_ref()
+        .context("The dataset has no retention rules.")?;
+    let pool = Arc::new(BtrfsPool::validate(dataset.parent.clone())?);
+    let snapshots = Arc::new(BtrfsDataset::validate(&pool, dataset.entity.clone())?).snapshots()?;
+
+    let pre_upgrade = &dataset.entity.pre_upgrade_snapshots;
+    let (pre_upgrade_snapshots, regular_snapshots): (Vec<_>, Vec<_>) = snapshots
+        .iter()
+        .cloned()
+        .partition(|s| pre_upgrade.contains(&s.datetime()));
+    let mut evaluation = evaluate_retention(&regular_snapshots, rules);
+    let held = &dataset.entity.held_snapshots;
+    let (held_drops, drops): (Vec<_>, Vec<_>) = evaluation
+        .drop_snapshots
+        .into_iter()
+        .partition(|s| held.contains(&s.datetime()));
+    evaluation.drop_snapshots = drops;
+    let mut reasons = HashMap::new();
+    for bucket in evaluation.keep_interval_buckets.iter() {
+        for snapshot in bucket.snapshots.iter() {
+            let reason = format!(
+                "interval {} ({})",
+                bucket.interval + 1,
+                format_interval(&rules.interval[bucket.interval])
+            );
+            reasons.insert(snapshot.datetime(), (true, reason));
+        }
+    }
+    for snapshot in evaluation.keep_minimum_snapshots.iter() {
+        let reason = format!("newest {}", rules.newest_count);
+        reasons.insert(snapshot.datetime(), (true, reason));
+    }
+    for snapshot in held_drops.iter() {
+        reasons.insert(snapshot.datetime(), (true, String::from("held")));
+    }
+    for snapshot in evaluation.drop_snapshots.iter() {
+        reasons.insert(snapshot.datetime(), (false, String::from("no rule")));
+    }
+    let mut pruned = evaluation.drop_snapshots.len();
+    for snapshot in pre_upgrade_snapshots.iter() {
+        reasons.insert(snapshot.datetime(), (true, String::from("pre-upgrade")));
+    }
+    for snapshot in evaluate_pre_upgrade_retention(
+        &pre_upgrade_snapshots,
+        &dataset.entity.pre_upgrade_retention,
+        Utc::now(),
+    ) {
+        if held.contains(&snapshot.datetime()) {
+            reasons.insert(snapshot.datetime(), (true, String::from("held")));
+        } else {
+            reasons.insert(snapshot.datetime(), (false, String::from("pre-upgrade expired")));
+            pruned += 1;
+        }
+    }
+
+    let drop_action = match rules.trash_period {
+        Some(period) => format!("trash for {}", humantime::format_duration(period)),
+        None => String::from("delete"),
+    };
+    print_comfy_table(
+        vec![Cell::new("Snapshot"), Cell::new("Action"), Cell::new("Rule")],
+        snapshots.iter().rev().map(|s| {
+            let (keep, reason) = &reasons[&s.datetime()];
+            vec![
+                Cell::new(format_datetime(s.datetime())),
+                match keep {
+                    true => Cell::new("keep"),
+                    false => Cell::new(&drop_action).fg(Color::Red),
+                },
+                Cell::new(reason),
+            ]
+        }),
+    );
+    println!(
+        "{} snapshots kept, {} pruned. Snapshots a running sync is sending are kept until it finishes.",
+        snapshots.len() - pruned,
+        pruned
+    );
+    if dataset.entity.pause_pruning {
+        println!("Pruning is paused for this dataset.");
+    }
+    if options.dry_run || pruned == 0 {
+        return Ok(());
+    }
+
+    // the dataset's actor holds the snapshots syncs are sending, so only the worker can prune safely
+    let path = format!("/datasets/{}/prune", dataset.entity.id());
+    let response = ServiceClient::default()
+        .post(&path)
+        .await
+        .context("The worker has to be running to prune a dataset.")?;
+    if !response.status().is_success() {
+        bail!("worker refused the request: {}", response_message(response).await?);
+    }
+    println!("The worker is pruning the dataset.");
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct DatasetRestoreOptions {
+    /// The dataset to restore
+    #[clap(value_name("[pool/]dataset|id"))]
+    dataset: String,
+
+    /// Snapshot timestamp, either a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime.
+    snapshot: String,
+
+    /// Restore from the copy of the snapshot held by this container instead of the local snapshot.
+    #[clap(short, long)]
+    container: Option<String>,
+
+    /// Do not prompt for confirmation.
+    #[clap(long)]
+    force: bool,
+}
+
+pub async fn restore_dataset(options: DatasetRestoreOptions) -> Result<()> {
+    debug!("Command 'restore_dataset': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let datetime = parse_snapshot_timestamp(&options.snapshot)?;
+    let dataset_path = dataset_search(&entities, &options.dataset)?;
+    let pool_id = dataset_path.parent.id();
+    let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+    let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?);
+    let local_snapshot = dataset.snapshots()?.into_iter().find(|s| s.datetime() == datetime);
+    let container_snapshot = match &options.container {
+        Some(container_query) => {
+            if local_snapshot.is_some() {
+                bail!("The dataset still has this snapshot locally. Restore it without --container.");
+            }
+            let container_path = container_search(&entities, container_query)?;
+            let container_pool = Arc::new(BtrfsPool::validate(container_path.parent.clone())?);
+            let container = Arc::new(BtrfsContainer::validate(
+                &container_pool,
+                container_path.entity.clone(),
+            )?);
+            Some(
+                container
+                    .snapshot_by_datetime(dataset.model().id(), datetime)
+                    .context("Snapshot not found in container.")?,
+            )
+        }
+        None => {
+            local_snapshot
+                .as_ref()
+                .context("Snapshot not found in dataset. Use --container to restore from a container's copy.")?;
+            None
+        }
+    };
+
+    let restore = DatasetRestore::replacing(&pool, dataset.model().clone())?;
+    let replaced_path = restore
+        .replaced_path()
+        .map(|p| p.as_pathbuf(&pool.model().mountpoint_path));
+    if !options.force
+        && !Confirm::new()
+            .with_prompt(format!(
+                "Replace the contents of {} with snapshot {}? The current contents are kept at {:?}.",
+                dataset,
+                format_datetime(datetime),
+                replaced_path.clone().unwrap_or_default()
+            ))
+            .interact()?
+    {
+        println!();
+        bail!("user aborted");
+    }
+
+    println!("Restoring {}...", dataset);
+    let restored = match (&container_snapshot, &local_snapshot) {
+        (Some(snapshot), _) => restore.run(snapshot).await,
+        (None, Some(snapshot)) => restore.run_local(snapshot),
+        (None, None) => unreachable!("snapshot presence checked above"),
+    }
+    .context("Failed to restore the dataset.")?;
+    entities.relocate_dataset(restored.take_model(), pool_id)?;
+    storage::store_entity_config(entities);
+
+    if let Some(path) = replaced_path {
+        println!(
+            "The previous contents are at {}. Delete it with 'btrfs subvolume delete' once the restore is verified.",
+            path.display()
+        );
+    }
+    println!(
+        "Remount the dataset if it is mounted by subvolume id, and restart the worker so it picks up the new subvolume."
+    );
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct ContainerRefreshOptions {
+    /// The container to re-scan
+    #[clap(value_name("[pool/]container|id"))]
+    container: String,
+}
+
+pub async fn refresh_container(options: ContainerRefreshOptions) -> Result<()> {
+    debug!("Command 'refresh_container': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let container = container_search(&entities, &options.container)?;
+    refresh_snapshots(&format!("/containers/{}/refresh", container.entity.id())).await
+}
+
+async fn refresh_snapshots(path: &str) -> Result<()> {
+    let response = ServiceClient::default().post(path).await?;
+    if !response.status().is_success() {
+        bail!("worker refused the request: {}", response_message(response).await?);
+    }
+    let body = hyper::body::aggregate(response).await?;
+    let changes: RefreshedSnapshotsResponse = serde_json::from_reader(body.reader())?;
+    println!("Found {} new and {} missing snapshots.", changes.added, changes.removed);
+
+    Ok(())
+}
+
+pub async fn response_message(response: Response<Body>) -> Result<String> {
+    let status = response.status();
+    let body = hyper::body::to_bytes(response).await?;
+    Ok(format!("{} {}", status, String::from_utf8_lossy(&body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libblkcapt::sys::fs::FsPathBuf;
+
+    fn dataset_with_qgroup() -> BtrfsDatasetEntity {
+        let mut dataset = BtrfsDatasetEntity::new("home".to_owned(), FsPathBuf::from("/home"), Uuid::new_v4()).unwrap();
+        dataset.qgroup = Some("1/100".parse().unwrap());
+        dataset
+    }
+
+    fn quota_options(max_size: Option<u64>, action: Option<SnapshotQuotaAction>, clear: bool) -> SnapshotQuotaOptions {
+        SnapshotQuotaOptions {
+            quota_max_size: max_size.map(ByteSizeArg),
+            quota_action: action,
+            clear_quota: clear,
+        }
+    }
+
+    #[test]
+    fn snapshot_quota_is_set_updated_and_cleared() {
+        let mut dataset = dataset_with_qgroup();
+
+        assert!(quota_options(None, Some(SnapshotQuotaAction::Prune), false)
+            .update_quota(&mut dataset)
+            .is_err());
+        quota_options(Some(1000), None, false)
+            .update_quota(&mut dataset)
+            .unwrap();
+        assert_eq!(
+            dataset.snapshot_quota,
+            Some(SnapshotQuota {
+                max_referenced: 1000,
+                action: SnapshotQuotaAction::Alert
+            })
+        );
+
+        quota_options(None, Some(SnapshotQuotaAction::Prune), false)
+            .update_quota(&mut dataset)
+            .unwrap();
+        assert_eq!(
+            dataset.snapshot_quota,
+            Some(SnapshotQuota {
+                max_referenced: 1000,
+                action: SnapshotQuotaAction::Prune
+            })
+        );
+
+        quota_options(None, None, true).update_quota(&mut dataset).unwrap();
+        assert_eq!(dataset.snapshot_quota, None);
+    }
+
+    #[test]
+    fn snapshot_quota_requires_a_quota_group() {
+        let mut dataset = dataset_with_qgroup();
+        dataset.qgroup = None;
+        assert!(quota_options(Some(1000), None, false)
+            .update_quota(&mut dataset)
+            .is_err());
+        assert_eq!(dataset.snapshot_quota, None);
+    }
+
+    #[test]
+    fn snapshot_quota_is_exceeded_above_the_limit_only() {
+        let quota = SnapshotQuota {
+            max_referenced: 1000,
+            action: SnapshotQuotaAction::Alert,
+        };
+        assert_eq!(quota.exceeded(1000), None);
+        assert_eq!(
+            quota.exceeded(1001).as_deref(),
+            Some("1001 bytes referenced (quota 1000)")
+        );
+    }
+}
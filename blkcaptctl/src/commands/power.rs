@@ -0,0 +1,72 @@
+use anyhow::{bail, Result};
+use bytes::buf::Buf;
+use clap::Clap;
+use libblkcapt::{core::system::EmergencyResponse, sys::net::ServiceClient};
+use slog_scope::*;
+
+/// UPS events meaning the power is about to go. NUT passes its notify type, apcupsd the name of the event script.
+const LOW_POWER_EVENTS: [&str; 7] = [
+    "lowbatt",
+    "fsd",
+    "runlimit",
+    "loadlimit",
+    "timeout",
+    "failing",
+    "doshutdown",
+];
+
+/// Environment variable NUT's upsmon sets for its NOTIFYCMD.
+const NUT_NOTIFY_TYPE_ENV: &str = "NOTIFYTYPE";
+
+/// Snapshot the critical datasets and start syncing them right away, ahead of a power loss
+#[derive(Clap, Debug)]
+pub struct PowerEmergencyOptions {}
+
+pub async fn power_emergency(options: PowerEmergencyOptions) -> Result<()> {
+    debug!("Command 'power_emergency': {:?}", options);
+
+    start_emergency().await
+}
+
+/// Handle a UPS event, starting emergency snapshots when the battery runs low. Use as NUT's NOTIFYCMD, or call it
+/// from the apcupsd event scripts with the event name. Other events are ignored. Set BLKCAPT_TOKEN to a token with
+/// the manage-jobs role when the UPS daemon doesn't run as root
+#[derive(Clap, Debug)]
+pub struct PowerUpsEventOptions {
+    /// The event, e.g. runlimit or lowbatt [default: $NOTIFYTYPE]
+    event: Option<String>,
+}
+
+pub async fn power_ups_event(options: PowerUpsEventOptions) -> Result<()> {
+    debug!("Command 'power_ups_event': {:?}", options);
+
+    let event = match options.event.or_else(|| std::env::var(NUT_NOTIFY_TYPE_ENV).ok()) {
+        Some(event) => event,
+        None => bail!("No UPS event given and {} is not set.", NUT_NOTIFY_TYPE_ENV),
+    };
+    if !LOW_POWER_EVENTS.contains(&event.to_lowercase().as_str()) {
+        info!("Ignoring UPS event {}.", event);
+        return Ok(());
+    }
+    start_emergency().await
+}
+
+async fn start_emergency() -> Result<()> {
+    let response = ServiceClient::default().post("/power/emergency").await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = hyper::body::to_bytes(response).await?;
+        bail!(
+            "worker refused the request: {} {}",
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    let body = hyper::body::aggregate(response).await?;
+    let response: EmergencyResponse = serde_json::from_reader(body.reader())?;
+    println!(
+        "Snapshotted {} critical datasets and started {} syncs.",
+        response.datasets, response.syncs
+    );
+    Ok(())
+}
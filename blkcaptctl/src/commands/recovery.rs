@@ -0,0 +1,762 @@
+use anyhow::{bail, Context, Result};
+use clap::Clap;
+use comfy_table::{Cell, Color};
+use dialoguer::Confirm;
+use libblkcapt::{
+    core::{
+        metadata::{has_recoverable_state, recover_mappings},
+        restore::DatasetRestore,
+        verify::ChecksumManifest,
+        BtrfsContainer, BtrfsPool, BtrfsSnapshot,
+    },
+    model::{
+        entities::{BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity, SnapshotSyncEntity},
+        entity_by_id_mut,
+        history::{estimate_transfer_duration, JobKind},
+        recovery::{latest_verifications, BundledKey, BundledManifest, RecoveryBundle},
+        storage, Entities, Entity, EntityId, EntityPath, EntityType,
+    },
+    sys::{
+        age,
+        btrfs::{add_to_fstab, subvolume_fstab_line, AllocationMode, Filesystem},
+        fs::DevicePathBuf,
+    },
+};
+use slog_scope::*;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::{container_search, pool_search};
+use crate::ui::{comfy_estimate_cell, format_bytes, format_datetime, print_comfy_table};
+
+#[derive(Clap, Debug)]
+pub struct DrExportOptions {
+    /// Path to write the recovery bundle to.
+    output: PathBuf,
+
+    /// Age recipient (public key or ssh public key) to encrypt the bundle for. May be repeated.
+    #[clap(short, long = "recipient", value_name = "recipient")]
+    recipients: Vec<String>,
+
+    /// Write the bundle without encryption. Credentials in the configuration are left out.
+    #[clap(long, conflicts_with = "recipients")]
+    unencrypted: bool,
+
+    /// Include the age identity files archive containers decrypt streams with.
+    #[clap(long, conflicts_with = "unencrypted")]
+    include_keys: bool,
+}
+
+pub fn dr_export(options: DrExportOptions) -> Result<()> {
+    debug!("Command 'dr_export': {:?}", options);
+
+    if options.recipients.is_empty() && !options.unencrypted {
+        bail!("At least one --recipient is required to encrypt the bundle. Use --unencrypted to skip encryption.");
+    }
+
+    let entities = storage::load_entity_config();
+    let mut bundle = RecoveryBundle::new(
+        match options.unencrypted {
+            true => entities.redacted(),
+            false => entities.clone(),
+        },
+        storage::load_server_config()?,
+    );
+    if options.include_keys {
+        bundle.keys = bundled_keys(&entities);
+    }
+    bundle.manifests = bundled_manifests(&entities)?;
+    bundle.verifications = latest_verifications(&storage::load_history()?);
+    let data = bundle.to_bytes()?;
+
+    if options.unencrypted {
+        fs::write(&options.output, data).context(format!("Failed to write bundle to {:?}.", options.output))?;
+    } else {
+        age::encrypt_to_file(&data, &options.recipients, &options.output)?;
+    }
+
+    println!(
+        "Exported {} pool(s), {} sync(s), {} restic container(s), {} observer(s), {} key(s), {} manifest(s) and {} \
+         verification result(s) to {:?}.",
+        bundle.entities.btrfs_pools.len(),
+        bundle.entities.snapshot_syncs.len(),
+        bundle.entities.restic_containers.len(),
+        bundle.entities.observers.len() + bundle.entities.notifiers.len(),
+        bundle.keys.len(),
+        bundle.manifests.len(),
+        bundle.verifications.len(),
+        options.output
+    );
+    if options.unencrypted {
+        println!("The bundle isn't encrypted, so credentials were left out. Enter them again after importing it.");
+    }
+    Ok(())
+}
+
+fn bundled_keys(entities: &Entities) -> Vec<BundledKey> {
+    entities
+        .archive_containers
+        .iter()
+        .filter_map(|a| {
+            a.encryption
+                .as_ref()
+                .and_then(|e| e.identity_file.as_ref())
+                .map(|path| (a, path))
+        })
+        .filter_map(|(archive, path)| match fs::read_to_string(path) {
+            Ok(contents) => Some(BundledKey {
+                container_id: archive.id(),
+                path: path.clone(),
+                contents,
+            }),
+            Err(e) => {
+                warn!(
+                    "Leaving out the identity of archive {}, {:?} can't be read: {}",
+                    archive.name(),
+                    path,
+                    e
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+fn bundled_manifests(entities: &Entities) -> Result<Vec<BundledManifest>> {
+    let mut manifests = Vec::new();
+    for container_path in entities.containers() {
+        let stored = BtrfsPool::validate(container_path.parent.clone())
+            .map(Arc::new)
+            .and_then(|p| BtrfsContainer::validate(&p, container_path.entity.clone()))
+            .and_then(|c| ChecksumManifest::stored(&c));
+        match stored {
+            Ok(stored) => {
+                for (dataset_id, copy_uuid, manifest) in stored {
+                    manifests.push(BundledManifest {
+                        container_id: container_path.entity.id(),
+                        dataset_id,
+                        copy_uuid,
+                        manifest: serde_json::to_value(manifest)?,
+                    });
+                }
+            }
+            Err(e) => warn!(
+                "Leaving out the manifests of container {}: {:#}",
+                container_path.path(),
+                e
+            ),
+        }
+    }
+    Ok(manifests)
+}
+
+#[derive(Clap, Debug)]
+pub struct DrImportOptions {
+    /// Path to the recovery bundle.
+    input: PathBuf,
+
+    /// Age identity file to decrypt the bundle with. Omit for an unencrypted bundle.
+    #[clap(short, long)]
+    identity: Option<PathBuf>,
+
+    /// Replace an existing, non-empty configuration.
+    #[clap(long)]
+    force: bool,
+}
+
+pub fn dr_import(options: DrImportOptions) -> Result<()> {
+    debug!("Command 'dr_import': {:?}", options);
+
+    if !options.force && !storage::load_entity_config().is_empty() {
+        bail!("A configuration already exists on this system. Use --force to replace it.");
+    }
+
+    let data = match &options.identity {
+        Some(identity) => age::decrypt_file(&options.input, identity)?,
+        None => fs::read(&options.input).context(format!("Failed to read bundle from {:?}.", options.input))?,
+    };
+    let bundle = RecoveryBundle::from_bytes(&data)?;
+
+    println!(
+        "Recovery bundle from host '{}' created {}.",
+        bundle.hostname,
+        format_datetime(bundle.created)
+    );
+
+    print_comfy_table(
+        vec![
+            Cell::new("Pool Name"),
+            Cell::new("Filesystem UUID"),
+            Cell::new("Expected Mountpoint"),
+            Cell::new("Filesystem"),
+        ],
+        bundle.entities.btrfs_pools.iter().map(|p| {
+            vec![
+                Cell::new(p.name()),
+                Cell::new(p.uuid),
+                Cell::new(p.mountpoint_path.display()),
+                match Filesystem::query_uuid(&p.uuid) {
+                    Ok(_) => Cell::new("present").fg(Color::Green),
+                    Err(_) => Cell::new("missing").fg(Color::Red),
+                },
+            ]
+        }),
+    );
+
+    if !bundle.verifications.is_empty() {
+        print_comfy_table(
+            vec![
+                Cell::new("Entity"),
+                Cell::new("Check"),
+                Cell::new("Started"),
+                Cell::new("Result"),
+            ],
+            bundle.verifications.iter().map(|v| {
+                vec![
+                    Cell::new(
+                        bundle
+                            .entities
+                            .entity_path(
+                                match v.kind {
+                                    JobKind::RestoreTest => EntityType::SnapshotSync,
+                                    _ => EntityType::Container,
+                                },
+                                v.entity_id,
+                            )
+                            .unwrap_or_else(|| v.entity_id.to_string()),
+                    ),
+                    Cell::new(v.kind),
+                    Cell::new(format_datetime(v.started)),
+                    match &v.error {
+                        None => Cell::new("passed").fg(Color::Green),
+                        Some(error) => Cell::new(error.lines().next().unwrap_or_default()).fg(Color::Red),
+                    },
+                ]
+            }),
+        );
+    }
+
+    for key in bundle.keys.iter() {
+        if let Err(e) = restore_key(key) {
+            warn!("Failed to restore identity {:?}: {:#}", key.path, e);
+        }
+    }
+    let restored_manifests = restore_manifests(&bundle.entities, &bundle.manifests);
+    if restored_manifests < bundle.manifests.len() {
+        println!(
+            "Restored {} of {} manifest(s). The rest belong to containers that aren't available yet.",
+            restored_manifests,
+            bundle.manifests.len()
+        );
+    }
+
+    let redacted = bundle.entities.has_redacted_secrets();
+    storage::store_server_config(bundle.server_config)?;
+    storage::store_entity_config(bundle.entities);
+    println!(
+        "Configuration imported. Mount any missing pools at their expected mountpoints before starting the service."
+    );
+    if redacted {
+        println!("The bundle was exported without credentials. Enter them again before starting the service.");
+    }
+    Ok(())
+}
+
+/// Identities already present are left alone, a new one is only readable by its owner.
+fn restore_key(key: &BundledKey) -> Result<()> {
+    if key.path.exists() {
+        return Ok(());
+    }
+    if let Some(dir) = key.path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&key.path)
+        .and_then(|mut file| file.write_all(key.contents.as_bytes()))
+        .with_context(|| format!("Failed to write {:?}.", key.path))
+}
+
+/// Manifests are written into the containers that can be reached already. Returns how many were.
+fn restore_manifests(entities: &Entities, manifests: &[BundledManifest]) -> usize {
+    let mut restored = 0;
+    for container_path in entities.containers() {
+        let container_manifests = manifests
+            .iter()
+            .filter(|m| m.container_id == container_path.entity.id())
+            .collect::<Vec<_>>();
+        if container_manifests.is_empty() {
+            continue;
+        }
+        let container = match BtrfsPool::validate(container_path.parent.clone())
+            .map(Arc::new)
+            .and_then(|p| BtrfsContainer::validate(&p, container_path.entity.clone()))
+        {
+            Ok(container) => container,
+            Err(e) => {
+                debug!("Skipping manifests of container {}: {:#}", container_path.path(), e);
+                continue;
+            }
+        };
+        for bundled in container_manifests {
+            let path = ChecksumManifest::path(&container, bundled.dataset_id, bundled.copy_uuid);
+            let result = serde_json::from_value::<ChecksumManifest>(bundled.manifest.clone())
+                .map_err(anyhow::Error::from)
+                .and_then(|manifest| manifest.store(&path));
+            match result {
+                Ok(()) => restored += 1,
+                Err(e) => warn!("Failed to restore manifest {:?}: {:#}", path, e),
+            }
+        }
+    }
+    restored
+}
+
+#[derive(Clap, Debug)]
+pub struct RestoreMachineOptions {
+    /// Container holding the snapshots to restore from.
+    container: String,
+
+    /// Pool to recreate the datasets on.
+    #[clap(short, long)]
+    target_pool: String,
+
+    /// Dataset to restore. May be repeated. [default: all datasets in the container]
+    #[clap(short, long = "dataset", value_name = "dataset")]
+    datasets: Vec<String>,
+
+    /// Device to format for the target pool, when its disks were lost. May be repeated. The new filesystem is mounted
+    /// where the pool was and the pool keeps its settings.
+    #[clap(long = "device", value_name = "device")]
+    devices: Vec<DevicePathBuf>,
+
+    /// Data allocation mode for the formatted devices.
+    #[clap(long)]
+    data: Option<AllocationMode>,
+
+    /// Metadata allocation mode for the formatted devices.
+    #[clap(long)]
+    metadata: Option<AllocationMode>,
+
+    /// Do not prompt for confirmation before formatting devices.
+    #[clap(long)]
+    force: bool,
+
+    /// Show the restore plan without making any changes.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+pub async fn restore_machine(options: RestoreMachineOptions) -> Result<()> {
+    debug!("Command 'restore_machine': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+
+    let container_path = container_search(&entities, &options.container)?;
+    let source_pool = Arc::new(BtrfsPool::validate(container_path.parent.clone())?);
+    let container = Arc::new(BtrfsContainer::validate(&source_pool, container_path.entity.clone())?);
+    let target_pool_id = pool_search(&entities, &options.target_pool)?.id();
+    let format_devices = !options.devices.is_empty();
+    if format_devices {
+        recreate_pool(&mut entities, target_pool_id, &options)?;
+    }
+    // a dry run doesn't format the devices, so its plan is made against the pool as it would be mounted
+    let (target_pool, fstree_mountpoint) = if format_devices && options.dry_run {
+        let model = entities.pool(target_pool_id).expect("pool found above");
+        (None, model.mountpoint_path.clone())
+    } else {
+        let pool = Arc::new(BtrfsPool::validate(
+            entities.pool(target_pool_id).expect("pool found above").clone(),
+        )?);
+        let fstree_mountpoint = pool.fstree_mountpoint().to_owned();
+        (Some(pool), fstree_mountpoint)
+    };
+    if let (Some(pool), false) = (&target_pool, options.dry_run) {
+        recreate_containers(&mut entities, pool)?;
+    }
+
+    let mut plan = Vec::new();
+    for dataset_id in container.source_dataset_ids()? {
+        let dataset = match entities.dataset(dataset_id) {
+            Some(dataset) => dataset.entity.clone(),
+            None => {
+                warn!("Container has snapshots for unknown dataset {}. Skipping.", dataset_id);
+                continue;
+            }
+        };
+        let selected = options.datasets.is_empty()
+            || options
+                .datasets
+                .iter()
+                .any(|q| q == dataset.name() || *q == dataset_id.to_string());
+        if !selected {
+            continue;
+        }
+        match container.snapshots(dataset_id)?.pop() {
+            Some(snapshot) => plan.push((dataset, snapshot)),
+            None => warn!("Container has no snapshots for dataset {}. Skipping.", dataset.name()),
+        }
+    }
+
+    if plan.is_empty() {
+        bail!("Nothing to restore.");
+    }
+    // nothing can be in the way on a pool that isn't formatted yet
+    let restores = match &target_pool {
+        Some(pool) => plan
+            .iter()
+            .map(|(dataset, _)| DatasetRestore::new(pool, dataset.clone()))
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+
+    let history = storage::load_history()?;
+    let mut total_size = 0;
+    let mut rows = Vec::new();
+    for (dataset, snapshot) in plan.iter() {
+        let size = snapshot.size()?;
+        total_size += size;
+        rows.push(vec![
+            Cell::new(dataset.name()),
+            Cell::new(snapshot),
+            Cell::new(dataset.path.as_pathbuf(&fstree_mountpoint).display()),
+            Cell::new(format_bytes(size)),
+            comfy_estimate_cell(estimate_transfer_duration(size, &history)),
+        ]);
+    }
+    rows.push(vec![
+        Cell::new("Total"),
+        Cell::new(""),
+        Cell::new(""),
+        Cell::new(format_bytes(total_size)),
+        comfy_estimate_cell(estimate_transfer_duration(total_size, &history)),
+    ]);
+
+    print_comfy_table(
+        vec![
+            Cell::new("Dataset"),
+            Cell::new("Snapshot"),
+            Cell::new("Restore Path"),
+            Cell::new("Size"),
+            Cell::new("Est. Duration"),
+        ],
+        rows.into_iter(),
+    );
+
+    if options.dry_run {
+        return Ok(());
+    }
+    let target_pool = target_pool.expect("only a dry run goes without the pool");
+
+    let planned = plan.len();
+    let mut restored = Vec::new();
+    let mut result = Ok(());
+    for (restore, (_, snapshot)) in restores.into_iter().zip(plan) {
+        let name = restore.model().name().to_owned();
+        println!("Restoring {} from {}...", name, snapshot);
+        match restore.run(&snapshot).await {
+            Ok(dataset) => restored.push(dataset.take_model()),
+            Err(e) => {
+                result = Err(e.context(format!("Failed to restore dataset {}.", name)));
+                break;
+            }
+        }
+    }
+
+    for dataset in restored.iter().cloned() {
+        entities.relocate_dataset(dataset, target_pool_id)?;
+    }
+    storage::store_entity_config(entities);
+
+    if !restored.is_empty() {
+        println!();
+        println!("# fstab entries for the restored datasets. Adjust mountpoints as needed.");
+        for dataset in restored.iter() {
+            println!(
+                "{}",
+                subvolume_fstab_line(
+                    &target_pool.model().uuid,
+                    &dataset.path,
+                    &Path::new("/").join(dataset.name())
+                )
+            );
+        }
+    }
+
+    // the config already names the restored datasets, so a failure has to say how far the restore came
+    result.with_context(|| format!("Restored {} of {} datasets.", restored.len(), planned))
+}
+
+/// Format the devices for a pool whose filesystem is gone and mount the new one where the pool was. The pool keeps
+/// its identity and settings, only the filesystem it refers to changes.
+fn recreate_pool(entities: &mut Entities, pool_id: EntityId, options: &RestoreMachineOptions) -> Result<()> {
+    let model = entities.pool(pool_id).expect("pool found by search");
+    if Filesystem::query_uuid(&model.uuid).is_ok() {
+        bail!(
+            "The filesystem of pool {} still exists, restore to it without formatting devices.",
+            model.name()
+        );
+    }
+
+    let devices = options
+        .devices
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if options.dry_run {
+        println!(
+            "Would format {} for pool {} and mount it at {}.",
+            devices,
+            model.name(),
+            model.mountpoint_path.display()
+        );
+        return Ok(());
+    }
+    println!("Formatting {} for pool {}.", devices, model.name());
+    if !options.force
+        && !Confirm::new()
+            .with_prompt("Are you sure you want to destroy all data on these devices?")
+            .interact()?
+    {
+        bail!("user aborted");
+    }
+
+    let name = model.name().to_owned();
+    let mountpoint = model.mountpoint_path.clone();
+    let filesystem = Filesystem::make(&options.devices, &name, options.data, options.metadata)?;
+    fs::create_dir_all(&mountpoint).context("Failed to create the pool's mountpoint.")?;
+    let filesystem = filesystem.mount(&mountpoint)?;
+    add_to_fstab(&filesystem)?;
+
+    let created = BtrfsPool::new(name, mountpoint)?.take_model();
+    let model = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("pool found by search");
+    model.uuid = created.uuid;
+    model.uuid_subs = created.uuid_subs;
+    storage::store_entity_config(entities.clone());
+    Ok(())
+}
+
+/// Create empty subvolumes for the pool's containers that are missing, so they can receive snapshots again.
+fn recreate_containers(entities: &mut Entities, pool: &BtrfsPool) -> Result<()> {
+    let pool_model = entity_by_id_mut(&mut entities.btrfs_pools, pool.model().id()).expect("pool is configured");
+    let mut recreated = 0;
+    for container in pool_model.containers.iter_mut() {
+        let created = pool
+            .recreate_container(container)
+            .with_context(|| format!("Failed to recreate container {}.", container.name()))?;
+        if created {
+            println!("Recreated container {}.", container.name());
+            recreated += 1;
+        }
+    }
+    if recreated > 0 {
+        storage::store_entity_config(entities.clone());
+    }
+    Ok(())
+}
+
+/// Rebuild a best-effort configuration for a pool from the snapshots blkcapt left on it, after the entity store was
+/// lost. Schedules, retention and observers aren't recorded on disk and have to be set up again.
+#[derive(Clap, Debug)]
+pub struct RecoverConfigOptions {
+    /// Top-level (fstree) mountpoint of the pool.
+    mountpoint: PathBuf,
+
+    /// Name for the recovered pool. [default: the name recorded in its snapshots]
+    #[clap(short, long)]
+    name: Option<String>,
+
+    /// Show what would be recovered without changing the configuration.
+    #[clap(long)]
+    dry_run: bool,
+}
+
+pub fn recover_config(options: RecoverConfigOptions) -> Result<()> {
+    debug!("Command 'recover_config': {:?}", options);
+
+    if !has_recoverable_state(&options.mountpoint) {
+        bail!(
+            "Found nothing to recover at {:?}. It must be the pool's top-level mountpoint.",
+            options.mountpoint
+        );
+    }
+    let mut entities = storage::load_entity_config();
+
+    let scanned_pool = BtrfsPool::new(options.name.clone().unwrap_or_default(), options.mountpoint.clone())?;
+    if let Some(existing) = entities.pool_by_uuid(scanned_pool.model().uuid) {
+        bail!("The filesystem is already configured as pool {}.", existing.name());
+    }
+    let mappings = recover_mappings(&scanned_pool)?;
+    if mappings.datasets.is_empty() && mappings.containers.is_empty() {
+        bail!("Found no snapshots to recover datasets or containers from.");
+    }
+
+    let recorded_pools = mappings
+        .datasets
+        .iter()
+        .filter_map(|d| d.metadata.as_ref())
+        .map(|m| (m.pool_id, m.pool_name.clone()))
+        .collect::<Vec<_>>();
+    let scanned_pool = scanned_pool.take_model();
+    let name = options
+        .name
+        .or_else(|| recorded_pools.last().map(|(_, name)| name.clone()))
+        .or_else(|| options.mountpoint.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "recovered".to_owned());
+    let mut pool = BtrfsPoolEntity::new(
+        name,
+        scanned_pool.mountpoint_path,
+        scanned_pool.uuid,
+        scanned_pool.uuid_subs,
+    )?;
+    if let Some((id, _)) = recorded_pools.last() {
+        pool = pool.with_id(*id);
+    }
+    let pool_id = pool.id();
+
+    let mut rows = Vec::new();
+    let mut recovered_datasets = Vec::new();
+    for recovered in mappings.datasets {
+        let subvolume = match recovered.subvolume {
+            Some(subvolume) => subvolume,
+            None => {
+                warn!(
+                    "Dataset {} has {} snapshot(s) but its subvolume is gone. Skipping.",
+                    recovered.id, recovered.snapshots
+                );
+                continue;
+            }
+        };
+        if let Some(existing) = entities.dataset(recovered.id) {
+            warn!(
+                "Dataset {} is already configured as {}. Skipping.",
+                recovered.id,
+                existing.path()
+            );
+            continue;
+        }
+        let id = recovered.id;
+        let name = recovered
+            .metadata
+            .map(|m| m.dataset_name)
+            .filter(|n| !n.is_empty())
+            .or_else(|| subvolume.path.file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_else(|| id.to_string());
+        let dataset = BtrfsDatasetEntity::new(name, subvolume.path.clone(), subvolume.uuid)?.with_id(id);
+        rows.push(vec![
+            Cell::new("Dataset"),
+            Cell::new(dataset.name()),
+            Cell::new(subvolume.path.as_pathbuf(&options.mountpoint).display()),
+            Cell::new(recovered.snapshots),
+        ]);
+        recovered_datasets.push(dataset.id());
+        pool.attach_dataset(dataset)?;
+    }
+
+    let mut recovered_containers = Vec::new();
+    for recovered in mappings.containers {
+        let name = recovered.subvolume.path.file_name().map_or_else(
+            || recovered.subvolume.uuid.to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+        let container = BtrfsContainerEntity::new(name, recovered.subvolume.path.clone(), recovered.subvolume.uuid)?;
+        rows.push(vec![
+            Cell::new("Container"),
+            Cell::new(container.name()),
+            Cell::new(recovered.subvolume.path.as_pathbuf(&options.mountpoint).display()),
+            Cell::new(recovered.sources.iter().map(|s| s.snapshots).sum::<usize>()),
+        ]);
+        recovered_containers.push((container.id(), recovered.sources));
+        pool.attach_container(container)?;
+    }
+
+    let pool_name = pool.name().to_owned();
+    entities.attach_pool(pool)?;
+
+    // pair the recovered containers with every known dataset they hold snapshots of, and the recovered datasets
+    // with containers on pools recovered before this one
+    let mut links = Vec::new();
+    for (container_id, sources) in recovered_containers.iter() {
+        for source in sources {
+            if entities.dataset(source.dataset_id).is_some() {
+                links.push((source.dataset_id, *container_id));
+            } else {
+                warn!(
+                    "Container {} holds snapshots of dataset {} ({}), which isn't configured. Recover the pool it \
+                     lives on to link them.",
+                    container_id,
+                    source.metadata.as_ref().map_or("unknown", |m| m.dataset_name.as_str()),
+                    source.dataset_id
+                );
+            }
+        }
+    }
+    for container_path in entities.containers().filter(|c| c.parent.id() != pool_id) {
+        let held = BtrfsPool::validate(container_path.parent.clone())
+            .map(Arc::new)
+            .and_then(|p| BtrfsContainer::validate(&p, container_path.entity.clone()))
+            .and_then(|c| c.source_dataset_ids());
+        match held {
+            Ok(held) => links.extend(
+                recovered_datasets
+                    .iter()
+                    .filter(|id| held.contains(id))
+                    .map(|id| (*id, container_path.entity.id())),
+            ),
+            Err(e) => debug!("Skipping container {}: {:#}", container_path.path(), e),
+        }
+    }
+    links.retain(|(dataset_id, container_id)| {
+        !entities
+            .snapshot_syncs
+            .iter()
+            .any(|s| s.dataset_id == *dataset_id && s.container_id == *container_id)
+    });
+
+    let mut syncs = Vec::new();
+    for (dataset_id, container_id) in links {
+        let (dataset, container) = match (entities.dataset(dataset_id), entities.container(container_id)) {
+            (Some(dataset), Some(container)) => (dataset, container),
+            _ => continue,
+        };
+        let name = format!("{}-{}", dataset.entity.name(), container.entity.name());
+        rows.push(vec![
+            Cell::new("Sync"),
+            Cell::new(&name),
+            Cell::new(format!("{} -> {}", dataset.path(), container.path())),
+            Cell::new(""),
+        ]);
+        syncs.push(SnapshotSyncEntity::new(name, dataset_id, container_id));
+    }
+
+    print_comfy_table(
+        vec![
+            Cell::new("Type"),
+            Cell::new("Name"),
+            Cell::new("Path"),
+            Cell::new("Snapshots"),
+        ],
+        rows.into_iter(),
+    );
+
+    if options.dry_run {
+        return Ok(());
+    }
+
+    entities.snapshot_syncs.extend(syncs);
+    storage::store_entity_config(entities);
+    println!(
+        "Recovered pool {}. Review it with `blkcaptctl pool list`, then set up schedules, retention and observers \
+         again.",
+        pool_name
+    );
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use anyhow::{bail, Result};
+use clap::Clap;
+use libblkcapt::core::remote::RemoteContainer;
+use libblkcapt::model::entities::{RemoteContainerEntity, SshTarget};
+use libblkcapt::model::{entity_by_id_mut, entity_by_name, storage, Entity};
+use slog_scope::debug;
+use std::path::{Path, PathBuf};
+
+use super::{remote_search, RetentionCreateUpdateOptions, RetentionUpdateOptions};
+
+#[derive(Clap, Debug)]
+pub struct SshTargetOptions {
+    /// User to log in to the remote host as [default: as configured for ssh]
+    #[clap(long)]
+    user: Option<String>,
+
+    /// Port of the remote ssh server [default: as configured for ssh]
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Private key file used to log in to the remote host
+    #[clap(long, value_name("path"))]
+    identity_file: Option<PathBuf>,
+}
+
+impl SshTargetOptions {
+    fn update_ssh(&self, ssh: &mut SshTarget) {
+        if self.user.is_some() {
+            ssh.user = self.user.clone();
+        }
+        if self.port.is_some() {
+            ssh.port = self.port;
+        }
+        if self.identity_file.is_some() {
+            ssh.identity_file = self.identity_file.clone();
+        }
+    }
+}
+
+fn check_remote_path(path: &Path) -> Result<()> {
+    if !path.is_absolute() {
+        bail!("The remote path must be absolute.");
+    }
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct RemoteAttachOptions {
+    /// Name of the remote container
+    #[clap(short, long)]
+    name: String,
+
+    /// Host name or address of the remote machine
+    host: String,
+
+    /// Directory on the remote btrfs filesystem to receive snapshots into
+    #[clap(value_name("remote path"))]
+    path: PathBuf,
+
+    #[clap(flatten)]
+    ssh: SshTargetOptions,
+
+    #[clap(flatten)]
+    retention: RetentionCreateUpdateOptions,
+}
+
+pub fn attach_remote(options: RemoteAttachOptions) -> Result<()> {
+    debug!("Command 'attach_remote': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    if entity_by_name(&entities.remote_containers, &options.name).is_some() {
+        bail!("Remote container name '{}' already exists.", options.name);
+    }
+    check_remote_path(&options.path)?;
+
+    let mut ssh = SshTarget::new(options.host);
+    options.ssh.update_ssh(&mut ssh);
+    let mut remote = RemoteContainerEntity::new(options.name, ssh, options.path);
+    options.retention.update_retention(&mut remote.snapshot_retention);
+    RemoteContainer::validate(remote.clone())?;
+    entities.remote_containers.push(remote);
+
+    storage::store_entity_config(entities);
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct RemoteUpdateOptions {
+    /// The name or id of the remote container
+    #[clap(value_name("remote|id"))]
+    remote: String,
+
+    /// Host name or address of the remote machine
+    #[clap(long)]
+    host: Option<String>,
+
+    /// Directory on the remote btrfs filesystem to receive snapshots into
+    #[clap(long, value_name("remote path"))]
+    path: Option<PathBuf>,
+
+    #[clap(flatten)]
+    ssh: SshTargetOptions,
+
+    /// Log in as the user ssh is configured to use
+    #[clap(long, conflicts_with("user"))]
+    default_user: bool,
+
+    /// Connect to the port ssh is configured to use
+    #[clap(long, conflicts_with("port"))]
+    default_port: bool,
+
+    /// Log in with the keys ssh is configured to use
+    #[clap(long, conflicts_with("identity-file"))]
+    default_identity: bool,
+
+    #[clap(flatten)]
+    retention: RetentionCreateUpdateOptions,
+
+    #[clap(flatten)]
+    retention_update: RetentionUpdateOptions,
+}
+
+pub fn update_remote(options: RemoteUpdateOptions) -> Result<()> {
+    debug!("Command 'update_remote': {:?}", options);
+
+    let mut entities = storage::load_entity_config();
+    let remote_id = remote_search(&entities, &options.remote)?.id();
+    let remote = entity_by_id_mut(&mut entities.remote_containers, remote_id).expect("always exists if path found");
+
+    if let Some(host) = options.host {
+        remote.ssh.host = host;
+    }
+    if let Some(path) = options.path {
+        check_remote_path(&path)?;
+        remote.path = path;
+    }
+    options.ssh.update_ssh(&mut remote.ssh);
+    if options.default_user {
+        remote.ssh.user = None;
+    }
+    if options.default_port {
+        remote.ssh.port = None;
+    }
+    if options.default_identity {
+        remote.ssh.identity_file = None;
+    }
+    options.retention_update.update_pruning(&mut remote.pause_pruning);
+    options.retention.update_retention(&mut remote.snapshot_retention);
+    RemoteContainer::validate(remote.clone())?;
+
+    storage::store_entity_config(entities);
+    Ok(())
+}
@@ -1,11 +1,14 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::Clap;
-use libblkcapt::model::entities::{ResticContainerEntity, ResticRepository};
-use libblkcapt::model::storage;
+use libblkcapt::model::entities::{RemoteHost, ResticContainerEntity, ResticRepository, WakeOnLan};
+use libblkcapt::model::{entity_by_id_mut, storage, Entity};
+use libblkcapt::sys::net::MacAddress;
+use slog_scope::debug;
+use std::{collections::HashMap, fmt::Debug, net::SocketAddr};
 
-use super::{RetentionCreateUpdateOptions, RetentionUpdateOptions};
+use super::{restic_search, RetentionCreateUpdateOptions, RetentionUpdateOptions};
 
-#[derive(Clap, Debug)]
+#[derive(Clap)]
 pub struct ResticCreateUpdateOptions {
     #[clap(flatten)]
     retention: RetentionCreateUpdateOptions,
@@ -20,6 +23,114 @@ pub struct ResticCreateUpdateOptions {
         value_name("name=value")
     )]
     environment_variable: Vec<String>,
+
+    #[clap(flatten)]
+    host: RemoteHostOptions,
+}
+
+// Written by hand so the variable values, usually repository passwords and cloud keys, stay out of logs and the
+// audit trail. Only the names are shown.
+impl Debug for ResticCreateUpdateOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self
+            .environment_variable
+            .iter()
+            .map(|v| v.split('=').next().unwrap_or_default())
+            .collect::<Vec<_>>();
+        f.debug_struct("ResticCreateUpdateOptions")
+            .field("retention", &self.retention)
+            .field("environment_variable", &names)
+            .field("host", &self.host)
+            .finish()
+    }
+}
+
+#[derive(Clap, Debug)]
+pub struct RemoteHostOptions {
+    /// Address of the repository host, checked before the repository is used
+    #[clap(long, value_name("host:port"))]
+    host: Option<String>,
+
+    /// Forget the repository host and its wake-on-lan settings
+    #[clap(long, conflicts_with_all(&["host", "wake-mac", "wake-broadcast", "wake-timeout"]))]
+    clear_host: bool,
+
+    /// Send a wake-on-lan packet to this MAC address when the host doesn't answer
+    #[clap(long, value_name("mac"))]
+    wake_mac: Option<MacAddress>,
+
+    /// Address the wake-on-lan packet is sent to [default: 255.255.255.255:9]
+    #[clap(long, value_name("address:port"))]
+    wake_broadcast: Option<SocketAddr>,
+
+    /// How long to wait for the host to answer after waking it [default: 2m]
+    #[clap(long, value_name("duration"))]
+    wake_timeout: Option<humantime::Duration>,
+
+    /// Stop waking the repository host
+    #[clap(long, conflicts_with_all(&["wake-mac", "wake-broadcast", "wake-timeout"]))]
+    no_wake: bool,
+}
+
+impl RemoteHostOptions {
+    fn update_host(&self, host: &mut Option<RemoteHost>) -> Result<()> {
+        if self.clear_host {
+            *host = None;
+            return Ok(());
+        }
+        if let Some(address) = &self.host {
+            match host {
+                Some(host) => host.address = address.clone(),
+                None => *host = Some(RemoteHost::new(address.clone())),
+            }
+        }
+
+        let configures_wake = self.wake_mac.is_some() || self.wake_broadcast.is_some() || self.wake_timeout.is_some();
+        if !configures_wake && !self.no_wake {
+            return Ok(());
+        }
+        let host = match host {
+            Some(host) => host,
+            None => bail!("Wake-on-lan needs the repository --host to check when it is awake."),
+        };
+        if self.no_wake {
+            host.wake_on_lan = None;
+            return Ok(());
+        }
+
+        if host.wake_on_lan.is_none() {
+            let mac = self
+                .wake_mac
+                .ok_or_else(|| anyhow!("Wake-on-lan needs the --wake-mac of the repository host."))?;
+            host.wake_on_lan = Some(WakeOnLan::new(mac));
+        }
+        let wake_on_lan = host.wake_on_lan.as_mut().expect("set above");
+        if let Some(mac) = self.wake_mac {
+            wake_on_lan.mac_address = mac;
+        }
+        if self.wake_broadcast.is_some() {
+            wake_on_lan.broadcast = self.wake_broadcast;
+        }
+        if let Some(timeout) = self.wake_timeout {
+            wake_on_lan.timeout = Some(*timeout);
+        }
+        Ok(())
+    }
+}
+
+fn parse_environment(environment_variables: &[String]) -> Result<HashMap<String, String>> {
+    environment_variables
+        .iter()
+        .map(|p| {
+            // Simplify with nightly split_once
+            let parts: Vec<_> = p.splitn(2, '=').collect();
+            if parts.len() == 2 {
+                Ok((parts[0].to_owned(), parts[1].to_owned()))
+            } else {
+                Err(anyhow!("environment variable definitions must contain '='"))
+            }
+        })
+        .collect()
 }
 
 #[derive(Clap, Debug)]
@@ -44,20 +155,8 @@ pub fn attach_restic(options: ResticAttachOptions) -> Result<()> {
         .map(ResticRepository::Custom)?;
     let mut restic = ResticContainerEntity::new(options.name, repository);
 
-    restic.custom_environment = options
-        .shared
-        .environment_variable
-        .iter()
-        .map(|p| {
-            // Simplify with nightly split_once
-            let parts: Vec<_> = p.splitn(2, '=').collect();
-            if parts.len() == 2 {
-                Ok((parts[0].to_owned(), parts[1].to_owned()))
-            } else {
-                Err(anyhow!("environment variable definitions must contain '='"))
-            }
-        })
-        .collect::<Result<_>>()?;
+    restic.custom_environment = parse_environment(&options.shared.environment_variable)?;
+    options.shared.host.update_host(&mut restic.host)?;
 
     options
         .shared
@@ -83,9 +182,46 @@ pub struct ResticUpdateOptions {
     shared: ResticCreateUpdateOptions,
 }
 
-pub fn update_restic(_options: ResticUpdateOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+pub fn update_restic(options: ResticUpdateOptions) -> Result<()> {
+    debug!("Command 'update_restic': {:?}", options);
 
-    //storage::store_entity_state(entities);
+    let mut entities = storage::load_entity_config();
+    let restic_id = restic_search(&entities, &options.sync)?.id();
+    let restic = entity_by_id_mut(&mut entities.restic_containers, restic_id).expect("always exists if path found");
+
+    if !options.shared.environment_variable.is_empty() {
+        restic.custom_environment = parse_environment(&options.shared.environment_variable)?;
+    }
+    options.shared.host.update_host(&mut restic.host)?;
+
+    options.retention_update.update_pruning(&mut restic.pause_pruning);
+    options
+        .shared
+        .retention
+        .update_retention(&mut restic.snapshot_retention);
+
+    storage::store_entity_config(entities);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_leaves_out_environment_values() {
+        let options = ResticAttachOptions::try_parse_from(&[
+            "attach",
+            "-e",
+            "RESTIC_PASSWORD=hunter2",
+            "-e",
+            "AWS_SECRET_ACCESS_KEY=abc123",
+        ])
+        .unwrap();
+        let detail = format!("{:?}", options);
+        assert!(detail.contains("RESTIC_PASSWORD"));
+        assert!(detail.contains("AWS_SECRET_ACCESS_KEY"));
+        assert!(!detail.contains("hunter2"));
+        assert!(!detail.contains("abc123"));
+    }
+}
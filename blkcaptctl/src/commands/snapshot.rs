@@ -0,0 +1,516 @@
+use anyhow::{bail, Context, Result};
+use bytes::buf::Buf;
+use chrono::{DateTime, Utc};
+use clap::Clap;
+use comfy_table::Cell;
+use libblkcapt::{
+    core::system::{FileKind, SnapshotCreatedResponse, SnapshotFilesResponse},
+    core::{
+        browse::{extract_from_snapshot, find_in_snapshot, find_snapshot_by_uuid, PathGlob},
+        parse_snapshot_timestamp, BtrfsContainer, BtrfsDataset, BtrfsPool, BtrfsSnapshot, Snapshot,
+    },
+    model::{entity_by_id_mut, history::estimate_transfer_duration, storage, Entities, Entity, EntityId},
+    sys::{
+        btrfs::{self, PropertyName, Subvolume},
+        net::ServiceClient,
+    },
+};
+use slog_scope::*;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use uuid::Uuid;
+
+use super::{audit::AuditedCommand, container_search, dataset_search, service::reload_running_worker};
+use crate::ui::{
+    comfy_estimate_cell, comfy_id_value_full, comfy_value_or, format_bytes, format_datetime, print_comfy_info,
+    print_comfy_table,
+};
+
+#[derive(Clap, Debug)]
+pub struct SnapshotShowOptions {
+    /// Dataset the snapshot was taken of.
+    dataset: String,
+
+    /// Snapshot timestamp, either a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime.
+    snapshot: String,
+
+    /// Show the copy of the snapshot held by this container instead of the local snapshot.
+    #[clap(short, long)]
+    container: Option<String>,
+}
+
+pub fn show_snapshot(options: SnapshotShowOptions) -> Result<()> {
+    debug!("Command 'show_snapshot': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let datetime = parse_snapshot_timestamp(&options.snapshot)?;
+    let dataset_path = dataset_search(&entities, &options.dataset)?;
+
+    let (location, snapshot): (String, Box<dyn BtrfsSnapshot>) = match &options.container {
+        Some(container_query) => {
+            let container_path = container_search(&entities, container_query)?;
+            let pool = Arc::new(BtrfsPool::validate(container_path.parent.clone())?);
+            let container = Arc::new(BtrfsContainer::validate(&pool, container_path.entity.clone())?);
+            let snapshot = container
+                .snapshot_by_datetime(dataset_path.entity.id(), datetime)
+                .context("Snapshot not found in container.")?;
+            (container.to_string(), Box::new(snapshot))
+        }
+        None => {
+            let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+            let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?);
+            let snapshot = dataset
+                .snapshots()?
+                .into_iter()
+                .find(|s| s.datetime() == datetime)
+                .context("Snapshot not found in dataset.")?;
+            (dataset.to_string(), Box::new(snapshot))
+        }
+    };
+
+    let held = options.container.is_none() && dataset_path.entity.held_snapshots.contains(&datetime);
+    let size = snapshot.size()?;
+    let history = storage::load_history()?;
+
+    print_comfy_info(vec![
+        (
+            Cell::new("Snapshot"),
+            Cell::new(format_datetime(snapshot.datetime())).into(),
+        ),
+        (Cell::new("Location"), Cell::new(location).into()),
+        (Cell::new("UUID"), comfy_id_value_full(snapshot.uuid()).into()),
+        (
+            Cell::new("Received UUID"),
+            comfy_value_or(snapshot.received_uuid(), "none").into(),
+        ),
+        (Cell::new("Held"), Cell::new(if held { "yes" } else { "no" }).into()),
+        (Cell::new("Restore Size"), Cell::new(format_bytes(size)).into()),
+        (
+            Cell::new("Est. Restore Time"),
+            comfy_estimate_cell(estimate_transfer_duration(size, &history)).into(),
+        ),
+    ]);
+
+    Ok(())
+}
+
+/// Take a snapshot of a dataset now
+#[derive(Clap, Debug, Default)]
+pub struct SnapshotTakeOptions {
+    /// Dataset to snapshot. Default: the dataset containing the current directory
+    dataset: Option<String>,
+}
+
+pub async fn take_dataset_snapshot(options: SnapshotTakeOptions) -> Result<()> {
+    debug!("Command 'take_dataset_snapshot': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let dataset_id = match &options.dataset {
+        Some(query) => dataset_search(&entities, query)?.entity.id(),
+        None => current_dataset(&entities)?,
+    };
+    let dataset_path = entities.dataset(dataset_id).expect("found above");
+    let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+    let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?);
+    let datetime = take_snapshot(&dataset, false).await?;
+    println!(
+        "Took snapshot {} of dataset '{}'.",
+        format_datetime(datetime),
+        dataset_path.entity.name()
+    );
+    Ok(())
+}
+
+/// The dataset of the subvolume containing the current directory.
+fn current_dataset(entities: &Entities) -> Result<EntityId> {
+    let cwd = std::env::current_dir().context("Failed to read the current directory.")?;
+    // btrfs only shows subvolume roots, so the nearest one that resolves is the containing subvolume
+    let subvolume = cwd
+        .ancestors()
+        .find_map(|path| Subvolume::from_path(path).ok())
+        .context("The current directory is not on a btrfs filesystem. Name the dataset to snapshot.")?;
+    entities
+        .datasets()
+        .find(|d| d.entity.uuid == subvolume.uuid)
+        .map(|d| d.entity.id())
+        .with_context(|| {
+            format!(
+                "No dataset is attached for the subvolume {:?}. Name the dataset to snapshot.",
+                subvolume.path
+            )
+        })
+}
+
+/// Through the worker when it runs, so the snapshot isn't reported as an outside change. A pre-upgrade snapshot is
+/// kept from the regular retention by the worker until the tag is stored. Whoever takes the snapshot records it in
+/// the audit log.
+pub async fn take_snapshot(dataset: &Arc<BtrfsDataset>, pre_upgrade: bool) -> Result<DateTime<Utc>> {
+    let path = format!(
+        "/datasets/{}/snapshot?pre_upgrade={}",
+        dataset.model().id(),
+        pre_upgrade
+    );
+    let response = match ServiceClient::default().post(&path).await {
+        Ok(response) => response,
+        Err(e) if e.is_connect() => {
+            debug!("Worker is not running, taking the snapshot directly: {}", e);
+            let snapshot = dataset.create_local_snapshot().map(|s| s.datetime());
+            return AuditedCommand::with_detail("dataset snapshot", format!("dataset: {}", dataset.model().id()))
+                .record(snapshot);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = hyper::body::to_bytes(response).await?;
+        bail!(
+            "worker refused the request: {} {}",
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    let body = hyper::body::aggregate(response).await?;
+    let created: SnapshotCreatedResponse = serde_json::from_reader(body.reader())?;
+    Ok(created.datetime)
+}
+
+/// Pin a dataset snapshot so it is never pruned
+#[derive(Clap, Debug)]
+pub struct SnapshotHoldOptions {
+    /// Dataset the snapshot was taken of.
+    dataset: String,
+
+    /// Snapshot timestamp, either a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime.
+    snapshot: String,
+}
+
+pub async fn hold_snapshot(options: SnapshotHoldOptions) -> Result<()> {
+    debug!("Command 'hold_snapshot': {:?}", options);
+
+    set_snapshot_hold(&options.dataset, &options.snapshot, true).await
+}
+
+/// Release a held dataset snapshot, leaving it to the retention rules again
+#[derive(Clap, Debug)]
+pub struct SnapshotUnholdOptions {
+    /// Dataset the snapshot was taken of.
+    dataset: String,
+
+    /// Snapshot timestamp, either a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime.
+    snapshot: String,
+}
+
+pub async fn unhold_snapshot(options: SnapshotUnholdOptions) -> Result<()> {
+    debug!("Command 'unhold_snapshot': {:?}", options);
+
+    set_snapshot_hold(&options.dataset, &options.snapshot, false).await
+}
+
+/// Also drops the holds of snapshots that no longer exist, then reloads a running worker so its next prune sees the
+/// change.
+async fn set_snapshot_hold(dataset_query: &str, snapshot: &str, hold: bool) -> Result<()> {
+    let mut entities = storage::load_entity_config();
+    let datetime = parse_snapshot_timestamp(snapshot)?;
+    let dataset_path = dataset_search(&entities, dataset_query)?;
+    let (pool_id, dataset_id) = (dataset_path.parent.id(), dataset_path.entity.id());
+    let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+    let existing = BtrfsDataset::validate(&pool, dataset_path.entity.clone())?
+        .snapshots()?
+        .iter()
+        .map(|s| s.datetime())
+        .collect::<Vec<_>>();
+    if hold && !existing.contains(&datetime) {
+        bail!("Snapshot not found in dataset.");
+    }
+
+    let pool = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("always exists if path found");
+    let dataset = entity_by_id_mut(&mut pool.datasets, dataset_id).expect("always exists if path found");
+    for stale in dataset.held_snapshots.iter().filter(|d| !existing.contains(d)) {
+        println!("Dropping the hold of deleted snapshot {}.", stale);
+    }
+    let held = dataset.held_snapshots.contains(&datetime);
+    dataset.held_snapshots.retain(|d| existing.contains(d));
+    match (hold, held) {
+        (true, false) => dataset.held_snapshots.push(datetime),
+        (false, true) => dataset.held_snapshots.retain(|d| d != &datetime),
+        (true, true) => bail!("Snapshot is already held."),
+        (false, false) => bail!("Snapshot is not held."),
+    }
+    dataset.held_snapshots.sort_unstable();
+
+    storage::store_entity_config(entities);
+    reload_running_worker().await?;
+    Ok(())
+}
+
+/// Search the snapshots of a dataset for paths matching a pattern
+#[derive(Clap, Debug)]
+pub struct FindOptions {
+    /// Dataset whose snapshots are searched.
+    dataset: String,
+
+    /// Path relative to the dataset root. `*` and `?` match within a directory, `**` matches any depth.
+    #[clap(value_name("path-glob"))]
+    pattern: PathGlob,
+
+    /// Search the copies of the snapshots held by this container instead of the local snapshots.
+    #[clap(short, long)]
+    container: Option<String>,
+}
+
+pub fn find_in_snapshots(options: FindOptions) -> Result<()> {
+    debug!("Command 'find_in_snapshots': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let dataset_path = dataset_search(&entities, &options.dataset)?;
+
+    let snapshots: Vec<Box<dyn BtrfsSnapshot>> = match &options.container {
+        Some(container_query) => {
+            let container_path = container_search(&entities, container_query)?;
+            let pool = Arc::new(BtrfsPool::validate(container_path.parent.clone())?);
+            let container = Arc::new(BtrfsContainer::validate(&pool, container_path.entity.clone())?);
+            container
+                .snapshots(dataset_path.entity.id())?
+                .into_iter()
+                .map(|s| Box::new(s) as Box<dyn BtrfsSnapshot>)
+                .collect()
+        }
+        None => {
+            let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+            let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?);
+            dataset
+                .snapshots()?
+                .into_iter()
+                .map(|s| Box::new(s) as Box<dyn BtrfsSnapshot>)
+                .collect()
+        }
+    };
+
+    let mut rows = Vec::new();
+    let mut containing = 0;
+    for snapshot in snapshots.iter() {
+        let found = find_in_snapshot(snapshot.as_ref(), &options.pattern)?;
+        if !found.is_empty() {
+            containing += 1;
+        }
+        rows.extend(found.into_iter().map(|e| {
+            vec![
+                Cell::new(format_datetime(snapshot.datetime())),
+                Cell::new(e.name),
+                Cell::new(if e.kind == FileKind::File {
+                    format_bytes(e.size)
+                } else {
+                    e.kind.to_string()
+                }),
+                comfy_value_or(e.modified.map(format_datetime), ""),
+            ]
+        }));
+    }
+
+    if rows.is_empty() {
+        println!("No matching paths in {} snapshot(s).", snapshots.len());
+        return Ok(());
+    }
+    print_comfy_table(
+        vec![
+            Cell::new("Snapshot"),
+            Cell::new("Path"),
+            Cell::new("Size"),
+            Cell::new("Modified"),
+        ],
+        rows.into_iter(),
+    );
+    println!("Found in {} of {} snapshot(s).", containing, snapshots.len());
+    Ok(())
+}
+
+#[derive(Clap, Debug)]
+pub struct SnapshotCloneOptions {
+    /// UUID of the snapshot to clone. Either a local dataset snapshot or a copy held by a container.
+    uuid: Uuid,
+
+    /// Path for the writable clone. Must be on the same pool as the snapshot. blkcapt does not manage the clone.
+    path: PathBuf,
+}
+
+pub fn clone_snapshot(options: SnapshotCloneOptions) -> Result<()> {
+    debug!("Command 'clone_snapshot': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let (location, snapshot) = find_snapshot_by_uuid(&entities.btrfs_pools, options.uuid)?
+        .with_context(|| format!("No snapshot found with UUID {}.", options.uuid))?;
+    snapshot.clone_writable(&options.path)?;
+    println!(
+        "Cloned snapshot {} of {} to {:?}.",
+        format_datetime(snapshot.datetime()),
+        location,
+        options.path
+    );
+    Ok(())
+}
+
+/// List a directory inside a snapshot through the worker
+#[derive(Clap, Debug)]
+pub struct SnapshotLsOptions {
+    /// UUID of the snapshot. Either a local dataset snapshot or a copy held by a container.
+    uuid: Uuid,
+
+    /// Directory relative to the snapshot root. Default: the root
+    path: Option<PathBuf>,
+}
+
+pub async fn snapshot_ls(options: SnapshotLsOptions) -> Result<()> {
+    debug!("Command 'snapshot_ls': {:?}", options);
+
+    let path = match &options.path {
+        Some(path) => format!(
+            "/snapshots/{}/files?path={}",
+            options.uuid,
+            query_escape(&path.to_string_lossy())
+        ),
+        None => format!("/snapshots/{}/files", options.uuid),
+    };
+    let response = ServiceClient::default().get(&path).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = hyper::body::to_bytes(response).await?;
+        bail!(
+            "worker refused the request: {} {}",
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    let body = hyper::body::aggregate(response).await?;
+    let listing: SnapshotFilesResponse = serde_json::from_reader(body.reader())?;
+
+    println!("{}:{}", listing.snapshot, Path::new("/").join(&listing.path).display());
+    print_comfy_table(
+        vec![
+            Cell::new("Name"),
+            Cell::new("Kind"),
+            Cell::new("Size"),
+            Cell::new("Modified"),
+        ],
+        listing.entries.into_iter().map(|e| {
+            vec![
+                Cell::new(e.name),
+                Cell::new(e.kind),
+                Cell::new(if e.kind == FileKind::File {
+                    format_bytes(e.size)
+                } else {
+                    String::new()
+                }),
+                comfy_value_or(e.modified.map(format_datetime), ""),
+            ]
+        }),
+    );
+
+    Ok(())
+}
+
+/// Copy a file or directory out of a snapshot
+#[derive(Clap, Debug)]
+pub struct SnapshotExtractOptions {
+    /// UUID of the snapshot. Either a local dataset snapshot or a copy held by a container.
+    uuid: Uuid,
+
+    /// File or directory relative to the snapshot root.
+    path: PathBuf,
+
+    /// Where to put the copy. An existing directory receives it under its original name.
+    dest: PathBuf,
+}
+
+pub fn snapshot_extract(options: SnapshotExtractOptions) -> Result<()> {
+    debug!("Command 'snapshot_extract': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let (location, snapshot) = find_snapshot_by_uuid(&entities.btrfs_pools, options.uuid)?
+        .with_context(|| format!("No snapshot found with UUID {}.", options.uuid))?;
+    let target = extract_from_snapshot(snapshot.as_ref(), &options.path, &options.dest)?;
+    println!(
+        "Extracted {} from snapshot {} of {} to {:?}.",
+        Path::new("/").join(&options.path).display(),
+        format_datetime(snapshot.datetime()),
+        location,
+        target
+    );
+    Ok(())
+}
+
+/// Percent-encode everything but unreserved characters for use in a query string.
+fn query_escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Show or set btrfs properties of a snapshot
+#[derive(Clap, Debug)]
+pub struct SnapshotPropOptions {
+    /// UUID of the snapshot. Either a local dataset snapshot or a copy held by a container.
+    uuid: Uuid,
+
+    /// Property to show or set: ro or compression. Shows all of them when omitted.
+    name: Option<PropertyName>,
+
+    /// New value for the property. An empty value resets compression.
+    value: Option<String>,
+
+    /// Allow making a snapshot writable
+    #[clap(long)]
+    force: bool,
+}
+
+impl SnapshotPropOptions {
+    pub fn is_set(&self) -> bool {
+        self.value.is_some()
+    }
+}
+
+pub fn snapshot_prop(options: SnapshotPropOptions) -> Result<()> {
+    debug!("Command 'snapshot_prop': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let (location, snapshot) = find_snapshot_by_uuid(&entities.btrfs_pools, options.uuid)?
+        .with_context(|| format!("No snapshot found with UUID {}.", options.uuid))?;
+    let path = snapshot.local_path();
+
+    match (options.name, &options.value) {
+        (None, _) => print_comfy_info(vec![
+            (Cell::new("Snapshot"), Cell::new(snapshot.to_string()).into()),
+            (Cell::new("Location"), Cell::new(location).into()),
+            (Cell::new("ro"), Cell::new(btrfs::is_read_only(&path)?).into()),
+            (
+                Cell::new("compression"),
+                comfy_value_or(btrfs::compression(&path)?, "none").into(),
+            ),
+        ]),
+        (Some(name), None) => println!("{}", btrfs::get_property(&path, name)?.unwrap_or_default()),
+        (Some(PropertyName::Ro), Some(value)) => {
+            let read_only = value
+                .parse::<bool>()
+                .context("The ro property is either true or false.")?;
+            if !read_only && !options.force {
+                bail!(
+                    "blkcapt snapshots must stay read-only to be sent or used as incremental parents, and a \
+                    writable received snapshot loses its received UUID. Use --force to make it writable anyway, or \
+                    `snapshot clone` for a writable copy."
+                );
+            }
+            btrfs::set_read_only(&path, read_only)?;
+        }
+        (Some(PropertyName::Compression), Some(value)) => {
+            let compression = if value.is_empty() { None } else { Some(value.parse()?) };
+            btrfs::set_compression(&path, compression)?;
+        }
+    }
+
+    Ok(())
+}
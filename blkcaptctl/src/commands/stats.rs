@@ -0,0 +1,68 @@
+use anyhow::Result;
+use chrono::Utc;
+use clap::Clap;
+use comfy_table::{Cell, Color};
+use libblkcapt::model::{history::summarize, storage};
+use slog_scope::*;
+use std::time::Duration;
+
+use crate::ui::print_comfy_table;
+
+#[derive(Clap, Debug)]
+pub struct StatsOptions {
+    /// Only include jobs started within this window
+    #[clap(short, long, value_name("duration"), default_value("30days"))]
+    window: humantime::Duration,
+
+    /// Number of distinct failure causes to show per job type
+    #[clap(long, default_value("3"))]
+    causes: usize,
+}
+
+pub fn stats(options: StatsOptions) -> Result<()> {
+    debug!("Command 'stats': {:?}", options);
+
+    let history = storage::load_history()?;
+    let since = Utc::now() - chrono::Duration::from_std(*options.window)?;
+    let summaries = summarize(&history, since);
+
+    if summaries.is_empty() {
+        println!("No jobs recorded in the last {}.", options.window);
+        return Ok(());
+    }
+
+    print_comfy_table(
+        vec![
+            Cell::new("Job Type"),
+            Cell::new("Runs"),
+            Cell::new("Success Rate"),
+            Cell::new("Avg Duration"),
+            Cell::new("Failure Causes"),
+        ],
+        summaries.into_iter().map(|s| {
+            let rate = s.success_rate();
+            vec![
+                Cell::new(s.kind),
+                Cell::new(s.runs),
+                Cell::new(format!("{:.1}%", rate * 100.0)).fg(match rate {
+                    r if r >= 0.99 => Color::Green,
+                    r if r >= 0.9 => Color::Yellow,
+                    _ => Color::Red,
+                }),
+                Cell::new(humantime::format_duration(Duration::from_secs(
+                    s.average_duration.as_secs(),
+                ))),
+                Cell::new(
+                    s.failure_causes
+                        .iter()
+                        .take(options.causes)
+                        .map(|(cause, count)| format!("{}x {}", count, cause))
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                ),
+            ]
+        }),
+    );
+
+    Ok(())
+}
@@ -1,12 +1,23 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Clap;
+use comfy_table::Cell;
 use humantime::Duration;
+use libblkcapt::core::{verify::verify_sample, BtrfsContainer, BtrfsDataset, BtrfsPool, Snapshot};
 use libblkcapt::model::entities::{SnapshotSyncEntity, SnapshotSyncMode};
-use libblkcapt::model::{storage, Entity};
+use libblkcapt::model::{storage, Entity, EntityType};
+use libblkcapt::sys::btrfs::StreamCompression;
+use slog_scope::*;
+use std::sync::Arc;
 
-use crate::ui::ScheduleArg;
+use crate::ui::{
+    comfy_id_header, comfy_id_value, comfy_name_value, comfy_value_or, format_datetime, print_comfy_table, ByteSizeArg,
+    ScheduleArg,
+};
 
-use super::{container_search, dataset_search, restic_search};
+use super::{
+    archive_search, container_search, dataset_search, entity_by_type_lookup, remote_search, restic_search,
+    snapshot_sync_search,
+};
 
 #[derive(Clap, Debug)]
 pub struct SyncCreateUpdateOptions {
@@ -21,6 +32,23 @@ pub struct SyncCreateUpdateOptions {
     /// Interval for interval_immediate mode
     #[clap(short, long, value_name("interval"))]
     interval: Option<Duration>,
+
+    /// How often to log and observe progress while a transfer is running [default: 10m]
+    #[clap(long, value_name("interval"))]
+    progress_interval: Option<Duration>,
+
+    /// Compress the send stream to a remote or archive container with zstd, falling back to gzip where zstd isn't
+    /// installed, or with gzip
+    #[clap(long, value_name("zstd|gzip"))]
+    compression: Option<StreamCompression>,
+
+    /// Most bytes per second the send stream may use, e.g. 50M
+    #[clap(long, value_name("rate"))]
+    bandwidth_limit: Option<ByteSizeArg>,
+
+    /// Schedule for receiving the latest snapshot in a btrfs container into scratch space and checking it restores
+    #[clap(long, value_name("schedule"))]
+    restore_test_schedule: Option<ScheduleArg>,
 }
 
 impl SyncCreateUpdateOptions {
@@ -38,8 +66,20 @@ impl SyncCreateUpdateOptions {
             _ => Err(anyhow!("invalid schedule or interval option for sync mode")),
         }
     }
+
+    fn progress_interval(&self) -> Result<Option<std::time::Duration>> {
+        match self.progress_interval.map(std::time::Duration::from) {
+            Some(interval) if interval < MIN_PROGRESS_INTERVAL => bail!(
+                "The progress interval must be at least {}.",
+                humantime::format_duration(MIN_PROGRESS_INTERVAL)
+            ),
+            interval => Ok(interval),
+        }
+    }
 }
 
+const MIN_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 #[derive(Clap, Debug)]
 pub struct SyncCreateOptions {
     /// Name of the sync
@@ -66,7 +106,9 @@ pub fn create_sync(options: SyncCreateOptions) -> Result<()> {
     // the same name so user may accidentally select wrong target.
     let container_id = container_search(&entities, &options.container)
         .map(|c| c.id())
-        .or_else(|_| restic_search(&entities, &options.container).map(|c| c.id()))?;
+        .or_else(|_| restic_search(&entities, &options.container).map(|c| c.id()))
+        .or_else(|_| remote_search(&entities, &options.container).map(|c| c.id()))
+        .or_else(|_| archive_search(&entities, &options.container).map(|c| c.id()))?;
     let maybe_mode = options
         .shared
         .mode
@@ -78,6 +120,10 @@ pub fn create_sync(options: SyncCreateOptions) -> Result<()> {
     if let Some(mode) = maybe_mode {
         sync.sync_mode = mode;
     }
+    sync.progress_interval = options.shared.progress_interval()?;
+    sync.compression = options.shared.compression;
+    sync.bandwidth_limit = options.shared.bandwidth_limit.map(|l| l.0);
+    sync.restore_test_schedule = options.shared.restore_test_schedule.map(|s| s.into());
 
     entities.snapshot_syncs.push(sync);
 
@@ -105,10 +151,37 @@ pub fn update_sync(_options: SyncUpdateOptions) -> Result<()> {
 #[derive(Clap, Debug)]
 pub struct SyncListOptions {}
 
-pub fn list_sync(_options: SyncListOptions) -> Result<()> {
-    //let mut entities = storage::load_entity_state();
+pub fn list_sync(options: SyncListOptions) -> Result<()> {
+    debug!("Command 'list_sync': {:?}", options);
+
+    let entities = storage::load_entity_config();
+
+    print_comfy_table(
+        vec![
+            comfy_id_header(),
+            Cell::new("Sync Name"),
+            Cell::new("Dataset"),
+            Cell::new("Container"),
+            Cell::new("Topology"),
+        ],
+        entities.snapshot_syncs.iter().map(|s| {
+            vec![
+                comfy_id_value(s.id()),
+                comfy_name_value(s.name()),
+                comfy_value_or(
+                    entity_by_type_lookup(&entities, EntityType::Dataset, s.dataset_id),
+                    "Missing",
+                ),
+                comfy_value_or(
+                    entity_by_type_lookup(&entities, EntityType::Container, s.container_id)
+                        .or_else(|| entities.restic_container(s.container_id).map(|r| r.name().to_owned())),
+                    "Missing",
+                ),
+                comfy_value_or(entities.sync_topology(s).ok(), "Invalid"),
+            ]
+        }),
+    );
 
-    //storage::store_entity_state(entities);
     Ok(())
 }
 
@@ -139,3 +212,72 @@ pub fn delete_sync(_options: SyncDeleteOptions) -> Result<()> {
     //storage::store_entity_state(entities);
     Ok(())
 }
+
+/// Compare the contents of sampled files between the latest synced snapshot and its copy in the container
+#[derive(Clap, Debug)]
+pub struct SyncVerifyOptions {
+    /// The name or id of the sync
+    #[clap(value_name("sync|id"))]
+    sync: String,
+
+    /// Number of randomly chosen files to compare
+    #[clap(short, long, default_value = "100")]
+    sample: usize,
+}
+
+pub fn verify_sync(options: SyncVerifyOptions) -> Result<()> {
+    debug!("Command 'verify_sync': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let sync = snapshot_sync_search(&entities, &options.sync)?;
+    let dataset_path = entities
+        .dataset(sync.dataset_id)
+        .context("The sync's source dataset does not exist.")?;
+    let container_path = entities
+        .container(sync.container_id)
+        .context("Only syncs to btrfs containers can be verified.")?;
+
+    let source_pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+    let dataset = Arc::new(BtrfsDataset::validate(&source_pool, dataset_path.entity.clone())?);
+    let container_pool = Arc::new(BtrfsPool::validate(container_path.parent.clone())?);
+    let container = Arc::new(BtrfsContainer::validate(
+        &container_pool,
+        container_path.entity.clone(),
+    )?);
+
+    let copies = container.snapshots(dataset.model().id())?;
+    let (source, copy) = dataset
+        .snapshots()?
+        .into_iter()
+        .rev()
+        .find_map(|s| {
+            copies
+                .iter()
+                .find(|c| c.datetime() == s.datetime())
+                .cloned()
+                .map(|c| (s, c))
+        })
+        .context("No snapshot of the dataset has been synced to the container yet.")?;
+
+    let verification = verify_sample(&source, &copy, options.sample)?;
+    println!(
+        "Compared {} file(s) of snapshot {} between {} and {}.",
+        verification.checked.len(),
+        format_datetime(source.datetime()),
+        dataset,
+        container
+    );
+    for path in verification.missing.iter() {
+        println!("Missing in container: {}", path.display());
+    }
+    for path in verification.mismatched.iter() {
+        println!("Content differs: {}", path.display());
+    }
+    if !verification.is_intact() {
+        bail!(
+            "{} of the sampled files are missing or differ in the container.",
+            verification.missing.len() + verification.mismatched.len()
+        );
+    }
+    Ok(())
+}
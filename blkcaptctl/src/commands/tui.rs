@@ -0,0 +1,471 @@
+use anyhow::{bail, Result};
+use bytes::buf::Buf;
+use chrono::{DateTime, Utc};
+use clap::Clap;
+use crossterm::{
+    event::{Event, EventStream, KeyCode},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures_util::StreamExt;
+use libblkcapt::{
+    core::system::{
+        ActiveState, ActorActivity, ActorState, DashboardState, DatasetOverview, PoolOverview, SyncOverview,
+        SystemActor, SystemState,
+    },
+    sys::net::ServiceClient,
+};
+use serde::de::DeserializeOwned;
+use slog_scope::*;
+use std::{io, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::ui::{color_enabled, format_datetime_with};
+use tui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Modifier, Style},
+    widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState},
+    Frame, Terminal,
+};
+
+type Backend = CrosstermBackend<io::Stdout>;
+
+/// Live dashboard of the pools, datasets, syncs and worker actors. Keys: tab switches between datasets and syncs,
+/// s snapshots and p prunes the selected dataset, y runs the selected sync, r refreshes, q quits
+#[derive(Clap, Debug)]
+pub struct TuiOptions {
+    /// Seconds between refreshes
+    #[clap(long, value_name("seconds"), default_value("2"))]
+    interval: u64,
+}
+
+pub async fn tui(options: TuiOptions) -> Result<()> {
+    debug!("Command 'tui': {:?}", options);
+
+    if options.interval == 0 {
+        bail!("The refresh interval must be at least a second.");
+    }
+
+    let mut terminal = TerminalGuard::enter()?;
+    let mut dashboard = Dashboard::default();
+    let mut events = EventStream::new();
+    let mut refresh = tokio::time::interval(Duration::from_secs(options.interval));
+    let (job_sender, mut job_results) = mpsc::unbounded_channel::<String>();
+
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => dashboard.refresh().await,
+            Some(message) = job_results.recv() => dashboard.message = message,
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) => match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => break,
+                    KeyCode::Char('r') => dashboard.refresh().await,
+                    KeyCode::Tab | KeyCode::BackTab => dashboard.switch_focus(),
+                    KeyCode::Down | KeyCode::Char('j') => dashboard.select(1),
+                    KeyCode::Up | KeyCode::Char('k') => dashboard.select(-1),
+                    KeyCode::Char(c) if "spy".contains(c) => {
+                        if let Some((description, path)) = dashboard.job_request(c) {
+                            dashboard.message = format!("{}...", description);
+                            let sender = job_sender.clone();
+                            tokio::spawn(async move {
+                                let _ = sender.send(start_job(&description, &path).await);
+                            });
+                        }
+                    }
+                    _ => {}
+                },
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            },
+        }
+        terminal.0.draw(|f| dashboard.draw(f))?;
+    }
+    Ok(())
+}
+
+/// Restores the terminal when the dashboard exits, also on errors.
+struct TerminalGuard(Terminal<Backend>);
+
+impl TerminalGuard {
+    fn enter() -> Result<Self> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        if let Err(e) = execute!(stdout, EnterAlternateScreen) {
+            let _ = disable_raw_mode();
+            return Err(e.into());
+        }
+        let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        terminal.hide_cursor()?;
+        Ok(Self(terminal))
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0.backend_mut(), LeaveAlternateScreen);
+        let _ = self.0.show_cursor();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Datasets,
+    Syncs,
+}
+
+impl Default for Focus {
+    fn default() -> Self {
+        Focus::Datasets
+    }
+}
+
+#[derive(Default)]
+struct Dashboard {
+    pools: Vec<PoolOverview>,
+    datasets: Vec<DatasetOverview>,
+    syncs: Vec<SyncOverview>,
+    actors: Vec<SystemActor>,
+    /// Why the worker's status couldn't be read.
+    worker_error: Option<String>,
+    focus: Focus,
+    dataset_state: TableState,
+    sync_state: TableState,
+    message: String,
+    refreshed: Option<DateTime<Utc>>,
+}
+
+impl Dashboard {
+    async fn refresh(&mut self) {
+        match fetch_status().await {
+            Ok((dashboard, actors)) => {
+                self.pools = dashboard.pools;
+                self.datasets = dashboard.datasets;
+                self.syncs = dashboard.syncs;
+                self.actors = actors;
+                self.worker_error = None;
+            }
+            Err(e) => self.worker_error = Some(format!("{:#}", e)),
+        }
+        clamp_selection(&mut self.dataset_state, self.datasets.len());
+        clamp_selection(&mut self.sync_state, self.syncs.len());
+        self.refreshed = Some(Utc::now());
+    }
+
+    /// How much newer the dataset's latest snapshot is than the newest snapshot the sync delivered.
+    fn sync_lag(&self, sync: &SyncOverview) -> Option<chrono::Duration> {
+        let latest_snapshot = self
+            .datasets
+            .iter()
+            .find(|d| d.id == sync.dataset_id)
+            .and_then(|d| d.activity.as_ref())
+            .and_then(|a| a.latest_snapshot)?;
+        match sync.activity.as_ref()?.newest_synced {
+            Some(synced) => Some(latest_snapshot - synced).filter(|l| *l > chrono::Duration::zero()),
+            None => Some(Utc::now() - latest_snapshot),
+        }
+    }
+
+    fn switch_focus(&mut self) {
+        self.focus = match self.focus {
+            Focus::Datasets => Focus::Syncs,
+            Focus::Syncs => Focus::Datasets,
+        };
+    }
+
+    fn select(&mut self, step: isize) {
+        let (state, len) = match self.focus {
+            Focus::Datasets => (&mut self.dataset_state, self.datasets.len()),
+            Focus::Syncs => (&mut self.sync_state, self.syncs.len()),
+        };
+        if len == 0 {
+            return;
+        }
+        let current = state.selected().unwrap_or_default() as isize;
+        state.select(Some((current + step).rem_euclid(len as isize) as usize));
+    }
+
+    /// The request for a job key on the selected row, with a description for the status line.
+    fn job_request(&mut self, key: char) -> Option<(String, String)> {
+        let (datasets, syncs, focus) = (&self.datasets, &self.syncs, self.focus);
+        let dataset = self
+            .dataset_state
+            .selected()
+            .and_then(|i| datasets.get(i))
+            .filter(|_| focus == Focus::Datasets);
+        let sync = self
+            .sync_state
+            .selected()
+            .and_then(|i| syncs.get(i))
+            .filter(|_| focus == Focus::Syncs);
+        let request = match (key, dataset, sync) {
+            ('s', Some(d), _) => (
+                format!("Snapshotting {}", d.path),
+                format!("/datasets/{}/snapshot", d.id),
+            ),
+            ('p', Some(d), _) => (format!("Pruning {}", d.path), format!("/datasets/{}/prune", d.id)),
+            ('y', _, Some(s)) => (format!("Syncing {}", s.name), format!("/syncs/{}/run", s.id)),
+            ('s', None, _) | ('p', None, _) => {
+                self.message = String::from("Select a dataset to snapshot or prune.");
+                return None;
+            }
+            _ => {
+                self.message = String::from("Select a sync to run.");
+                return None;
+            }
+        };
+        Some(request)
+    }
+
+    fn draw(&mut self, f: &mut Frame<Backend>) {
+        let actor_count = if self.worker_error.is_some() {
+            1
+        } else {
+            self.actors.len()
+        };
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Length(self.pools.len() as u16 + 3),
+                    Constraint::Min(self.datasets.len().min(8) as u16 + 3),
+                    Constraint::Min(self.syncs.len().min(8) as u16 + 3),
+                    Constraint::Length(actor_count.min(12) as u16 + 3),
+                    Constraint::Length(1),
+                ]
+                .as_ref(),
+            )
+            .split(f.size());
+
+        self.draw_pools(f, chunks[0]);
+        self.draw_datasets(f, chunks[1]);
+        self.draw_syncs(f, chunks[2]);
+        self.draw_actors(f, chunks[3]);
+
+        let refreshed = self
+            .refreshed
+            .map_or_else(String::new, |r| format!("updated {}", format_datetime_with(r, "%T")));
+        let status = if self.message.is_empty() {
+            format!(
+                "{}  tab: switch  s: snapshot  p: prune  y: sync  r: refresh  q: quit",
+                refreshed
+            )
+        } else {
+            format!("{}  {}", refreshed, self.message)
+        };
+        f.render_widget(Paragraph::new(status), chunks[4]);
+    }
+
+    fn draw_pools(&self, f: &mut Frame<Backend>, area: Rect) {
+        let rows = self.pools.iter().map(|p| {
+            Row::new(vec![
+                Cell::from(p.name.clone()),
+                Cell::from(p.mountpoint.display().to_string()),
+                Cell::from(p.datasets.to_string()),
+                Cell::from(p.containers.to_string()),
+            ])
+        });
+        let table = Table::new(rows)
+            .header(header(&["Pool", "Mountpoint", "Datasets", "Containers"]))
+            .block(Block::default().borders(Borders::ALL).title("Pools"))
+            .widths(&[
+                Constraint::Percentage(25),
+                Constraint::Percentage(45),
+                Constraint::Percentage(15),
+                Constraint::Percentage(15),
+            ]);
+        f.render_widget(table, area);
+    }
+
+    fn draw_datasets(&mut self, f: &mut Frame<Backend>, area: Rect) {
+        let now = Utc::now();
+        let rows = self.datasets.iter().map(|d| {
+            let (latest, prune) = match &d.activity {
+                Some(activity) => (
+                    Cell::from(
+                        activity
+                            .latest_snapshot
+                            .map_or_else(|| "none".to_owned(), |s| format_age(now - s)),
+                    ),
+                    match activity.last_prune {
+                        Some(outcome) if outcome.succeeded => Cell::from(format_age(now - outcome.finished)),
+                        Some(_) => Cell::from("failed").style(fg(Color::Red)),
+                        None => Cell::from("not yet"),
+                    },
+                ),
+                None => (Cell::from("not running").style(fg(Color::Yellow)), Cell::from("")),
+            };
+            Row::new(vec![Cell::from(d.path.clone()), latest, prune])
+        });
+        let table = Table::new(rows)
+            .header(header(&["Dataset", "Latest Snapshot", "Last Prune"]))
+            .block(focus_block("Datasets", self.focus == Focus::Datasets))
+            .widths(&[
+                Constraint::Percentage(50),
+                Constraint::Percentage(25),
+                Constraint::Percentage(25),
+            ])
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(table, area, &mut self.dataset_state);
+    }
+
+    fn draw_syncs(&mut self, f: &mut Frame<Backend>, area: Rect) {
+        let now = Utc::now();
+        let rows = self.syncs.iter().map(|s| {
+            let activity = match &s.activity {
+                Some(activity) => activity,
+                None => {
+                    return Row::new(vec![
+                        Cell::from(s.name.clone()),
+                        Cell::from(s.route.clone()),
+                        Cell::from("not running").style(fg(Color::Yellow)),
+                        Cell::from(""),
+                    ])
+                }
+            };
+            let lag = match self.sync_lag(s) {
+                Some(lag) => Cell::from(format_age(lag)).style(fg(Color::Yellow)),
+                None if activity.newest_synced.is_some() => Cell::from("up to date").style(fg(Color::Green)),
+                None => Cell::from("unknown"),
+            };
+            let last_synced = Cell::from(
+                activity
+                    .newest_synced
+                    .map_or_else(|| "never".to_owned(), |t| format_age(now - t)),
+            );
+            let failing = activity.last_transfer.map_or(false, |t| !t.succeeded);
+            Row::new(vec![
+                Cell::from(s.name.clone()),
+                Cell::from(s.route.clone()),
+                if failing {
+                    last_synced.style(fg(Color::Red))
+                } else {
+                    last_synced
+                },
+                lag,
+            ])
+        });
+        let table = Table::new(rows)
+            .header(header(&["Sync", "Route", "Last Synced", "Lag"]))
+            .block(focus_block("Syncs", self.focus == Focus::Syncs))
+            .widths(&[
+                Constraint::Percentage(20),
+                Constraint::Percentage(45),
+                Constraint::Percentage(20),
+                Constraint::Percentage(15),
+            ])
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(table, area, &mut self.sync_state);
+    }
+
+    fn draw_actors(&self, f: &mut Frame<Backend>, area: Rect) {
+        let block = Block::default().borders(Borders::ALL).title("Worker");
+        if let Some(e) = &self.worker_error {
+            let error = Paragraph::new(format!("worker unavailable: {}", e))
+                .style(fg(Color::Red))
+                .block(block);
+            f.render_widget(error, area);
+            return;
+        }
+        let rows = self.actors.iter().map(|a| {
+            let (state, color, job, error) = match &a.actor_state {
+                ActorState::Started(ActiveState::Running(status)) => (
+                    status.activity.to_string(),
+                    match status.activity {
+                        ActorActivity::Idle => Color::Green,
+                        ActorActivity::Active => Color::Cyan,
+                        ActorActivity::Faulted => Color::Red,
+                        ActorActivity::Unreachable => Color::Yellow,
+                    },
+                    status.current_job.clone().unwrap_or_default(),
+                    status.last_error.clone().unwrap_or_default(),
+                ),
+                state => (state.to_string(), Color::Yellow, String::new(), String::new()),
+            };
+            Row::new(vec![
+                Cell::from(a.actor_type.clone()),
+                Cell::from(state).style(fg(color)),
+                Cell::from(job),
+                Cell::from(error).style(fg(Color::Red)),
+            ])
+        });
+        let table = Table::new(rows)
+            .header(header(&["Actor", "State", "Job", "Last Error"]))
+            .block(block)
+            .widths(&[
+                Constraint::Percentage(20),
+                Constraint::Percentage(12),
+                Constraint::Percentage(38),
+                Constraint::Percentage(30),
+            ]);
+        f.render_widget(table, area);
+    }
+}
+
+fn header(titles: &[&'static str]) -> Row<'static> {
+    Row::new(titles.iter().copied()).style(Style::default().add_modifier(Modifier::BOLD))
+}
+
+fn focus_block(title: &'static str, focused: bool) -> Block<'static> {
+    let block = Block::default().borders(Borders::ALL).title(title);
+    if focused {
+        block.border_style(fg(Color::Cyan))
+    } else {
+        block
+    }
+}
+
+fn clamp_selection(state: &mut TableState, len: usize) {
+    state.select(match (state.selected(), len) {
+        (_, 0) => None,
+        (Some(selected), len) => Some(selected.min(len - 1)),
+        (None, _) => Some(0),
+    });
+}
+
+/// A foreground color, unless colors are turned off.
+fn fg(color: Color) -> Style {
+    match color_enabled() {
+        true => Style::default().fg(color),
+        false => Style::default(),
+    }
+}
+
+/// Coarse age for a dashboard cell, in the largest unit that fits.
+fn format_age(age: chrono::Duration) -> String {
+    let seconds = age.num_seconds().max(0);
+    match seconds {
+        s if s < 60 => format!("{}s", s),
+        s if s < 3600 => format!("{}m", s / 60),
+        s if s < 86400 => format!("{}h {}m", s / 3600, s % 3600 / 60),
+        s => format!("{}d {}h", s / 86400, s % 86400 / 3600),
+    }
+}
+
+/// The entity overview and the actors, both from the worker.
+async fn fetch_status() -> Result<(DashboardState, Vec<SystemActor>)> {
+    let dashboard: DashboardState = fetch_json("/dashboard").await?;
+    let mut system: SystemState = fetch_json("/").await?;
+    system.actors.sort_by_key(|a| a.actor_id);
+    Ok((dashboard, system.actors))
+}
+
+async fn fetch_json<T: DeserializeOwned>(path: &str) -> Result<T> {
+    let response = ServiceClient::default().get(path).await?;
+    if !response.status().is_success() {
+        bail!("{}", response.status());
+    }
+    let body = hyper::body::aggregate(response).await?;
+    Ok(serde_json::from_reader(body.reader())?)
+}
+
+async fn start_job(description: &str, path: &str) -> String {
+    match ServiceClient::default().post(path).await {
+        Ok(response) if response.status() == hyper::StatusCode::ACCEPTED => format!("{} started.", description),
+        Ok(response) if response.status().is_success() => format!("{} done.", description),
+        Ok(response) => format!("{} refused: {}", description, response.status()),
+        Err(e) => format!("{} failed: {}", description, e),
+    }
+}
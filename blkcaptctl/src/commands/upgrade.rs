@@ -0,0 +1,344 @@
+use anyhow::{anyhow, bail, Context, Result};
+use bytes::buf::Buf;
+use chrono::{DateTime, Utc};
+use clap::Clap;
+use dialoguer::Confirm;
+use hyper::StatusCode;
+use libblkcapt::{
+    core::{
+        parse_snapshot_timestamp,
+        restore::DatasetRollback,
+        snapshot_label,
+        system::{ConfirmationChallenge, RollbackResponse},
+        BtrfsDataset, BtrfsPool, Snapshot,
+    },
+    model::{entity_by_id_mut, storage, Entities, Entity, EntityId},
+    sys::{btrfs::Subvolume, fs::lookup_mountentry, net::ServiceClient},
+};
+use slog_scope::*;
+use std::{fs, path::Path, str::FromStr, sync::Arc};
+
+use super::{
+    audit::audited, dataset_search, pool::response_message, service::reload_running_worker, snapshot::take_snapshot,
+};
+use crate::ui::format_datetime;
+
+#[derive(Debug, Clone, Copy)]
+pub enum PackageManager {
+    Pacman,
+    Apt,
+}
+
+impl FromStr for PackageManager {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pacman" => Ok(Self::Pacman),
+            "apt" => Ok(Self::Apt),
+            _ => Err(anyhow!("unsupported package manager, use pacman or apt")),
+        }
+    }
+}
+
+impl PackageManager {
+    fn hook_path(self) -> &'static str {
+        match self {
+            PackageManager::Pacman => "/etc/pacman.d/hooks/00-blockcaptain-pre-upgrade.hook",
+            PackageManager::Apt => "/etc/apt/apt.conf.d/80blockcaptain-pre-upgrade",
+        }
+    }
+
+    /// A failed snapshot is reported, but doesn't hold up the upgrade.
+    fn hook(self, command: &str) -> String {
+        match self {
+            PackageManager::Pacman => format!(
+                "[Trigger]\nOperation = Install\nOperation = Upgrade\nOperation = Remove\nType = Package\nTarget = *\n\n\
+                 [Action]\nDescription = Taking pre-upgrade snapshots...\nWhen = PreTransaction\nExec = {}\n",
+                command
+            ),
+            PackageManager::Apt => format!("DPkg::Pre-Invoke {{ \"{} || true\"; }};\n", command),
+        }
+    }
+}
+
+/// Snapshot datasets ahead of a system package upgrade, tagged so they are pruned by the dataset's pre-upgrade
+/// retention instead of its regular rules. This is what the installed package manager hooks run
+#[derive(Clap, Debug)]
+pub struct UpgradeHookRunOptions {
+    /// The datasets to snapshot [default: the dataset mounted at /]
+    #[clap(value_name("[pool/]dataset|id"))]
+    datasets: Vec<String>,
+}
+
+pub async fn upgrade_hook_run(options: UpgradeHookRunOptions) -> Result<()> {
+    debug!("Command 'upgrade_hook_run': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let dataset_ids = if options.datasets.is_empty() {
+        vec![root_dataset(&entities)?]
+    } else {
+        options
+            .datasets
+            .iter()
+            .map(|query| dataset_search(&entities, query).map(|d| d.entity.id()))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut taken = Vec::new();
+    for dataset_id in dataset_ids {
+        let dataset_path = entities.dataset(dataset_id).expect("found above");
+        let name = dataset_path.entity.name().to_owned();
+        let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+        let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?);
+        let datetime = take_snapshot(&dataset, true).await?;
+        let existing = dataset.snapshots()?.iter().map(|s| s.datetime()).collect::<Vec<_>>();
+        taken.push((dataset_id, datetime, existing));
+        println!(
+            "Took pre-upgrade snapshot {} of dataset '{}'.",
+            format_datetime(datetime),
+            name
+        );
+    }
+
+    // the config read above is stale once the worker has taken the snapshots, tag them in the current one
+    let mut entities = storage::load_entity_config();
+    for (dataset_id, datetime, existing) in taken {
+        if let Some(dataset) = entities
+            .btrfs_pools
+            .iter_mut()
+            .find_map(|p| entity_by_id_mut(&mut p.datasets, dataset_id))
+        {
+            tag_pre_upgrade(&mut dataset.pre_upgrade_snapshots, datetime, &existing);
+        }
+    }
+    storage::store_entity_config(entities);
+    reload_running_worker().await?;
+    Ok(())
+}
+
+/// Add the new pre-upgrade snapshot and forget the ones that no longer exist.
+fn tag_pre_upgrade(tagged: &mut Vec<DateTime<Utc>>, datetime: DateTime<Utc>, existing: &[DateTime<Utc>]) {
+    tagged.retain(|d| existing.contains(d) && *d != datetime);
+    tagged.push(datetime);
+    tagged.sort_unstable();
+}
+
+/// The dataset of the subvolume mounted at /.
+fn root_dataset(entities: &Entities) -> Result<EntityId> {
+    let root = Subvolume::from_path(Path::new("/")).context("The root filesystem is not a btrfs subvolume.")?;
+    entities
+        .datasets()
+        .find(|d| d.entity.uuid == root.uuid)
+        .map(|d| d.entity.id())
+        .context("No dataset is attached for the subvolume mounted at /. Name the datasets to snapshot.")
+}
+
+/// Install a package manager hook that takes pre-upgrade snapshots before every package transaction
+#[derive(Clap, Debug)]
+pub struct UpgradeHookInstallOptions {
+    /// The package manager to hook into [pacman|apt]
+    manager: PackageManager,
+
+    /// The datasets to snapshot [default: the dataset mounted at /]
+    #[clap(value_name("[pool/]dataset|id"))]
+    datasets: Vec<String>,
+
+    /// Print the hook instead of installing it
+    #[clap(long)]
+    print: bool,
+}
+
+pub fn upgrade_hook_install(options: UpgradeHookInstallOptions) -> Result<()> {
+    debug!("Command 'upgrade_hook_install': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    for query in &options.datasets {
+        dataset_search(&entities, query)?;
+    }
+
+    let executable = std::env::current_exe().context("Failed to locate the blkcapt executable.")?;
+    let command = Some(format!("{} upgrade-hook run", executable.display()))
+        .into_iter()
+        .chain(options.datasets.iter().cloned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let hook = options.manager.hook(&command);
+    if options.print {
+        print!("{}", hook);
+        return Ok(());
+    }
+
+    let path = Path::new(options.manager.hook_path());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    }
+    fs::write(path, hook).with_context(|| format!("Failed to write {:?}.", path))?;
+    println!("Installed {}.", path.display());
+    Ok(())
+}
+
+/// Roll the root filesystem back to one of its snapshots, effective on the next boot. The snapshot becomes a new
+/// subvolume at the dataset's path, and the default subvolume when the replaced one was. The replaced subvolume is
+/// kept next to it and snapshotted first, with a hold on that snapshot
+#[derive(Clap, Debug)]
+pub struct RollbackOptions {
+    /// Snapshot timestamp, either a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime.
+    snapshot: String,
+
+    /// The dataset to roll back [default: the dataset mounted at /]
+    #[clap(long, value_name("[pool/]dataset|id"))]
+    dataset: Option<String>,
+
+    /// Do not prompt for confirmation.
+    #[clap(long)]
+    force: bool,
+}
+
+/// A running worker rolls back on a confirmed request, so its actors follow the dataset to the new subvolume, and
+/// records it in the audit log. Otherwise the rollback runs here.
+pub async fn rollback(options: RollbackOptions) -> Result<()> {
+    debug!("Command 'rollback': {:?}", options);
+
+    let entities = storage::load_entity_config();
+    let datetime = parse_snapshot_timestamp(&options.snapshot)?;
+    let dataset_id = match &options.dataset {
+        Some(query) => dataset_search(&entities, query)?.entity.id(),
+        None => root_dataset(&entities)?,
+    };
+    let dataset_path = entities.dataset(dataset_id).expect("found above");
+    let pool_id = dataset_path.parent.id();
+    let pool = Arc::new(BtrfsPool::validate(dataset_path.parent.clone())?);
+    let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_path.entity.clone())?);
+    let rollback = DatasetRollback::new(&dataset, datetime)?;
+
+    let path = format!("/datasets/{}/rollback/{}", dataset_id, snapshot_label(datetime));
+    let client = ServiceClient::default();
+    let challenge = match client.post_confirmed(&path, None).await {
+        Ok(response) if response.status() == StatusCode::PRECONDITION_REQUIRED => {
+            let body = hyper::body::aggregate(response).await?;
+            Some(serde_json::from_reader::<_, ConfirmationChallenge>(body.reader())?)
+        }
+        Ok(response) => bail!("worker refused the request: {}", response_message(response).await?),
+        Err(e) if e.is_connect() => {
+            debug!("Worker is not running, rolling back directly: {}", e);
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    if !options.force
+        && !Confirm::new()
+            .with_prompt(format!(
+                "Roll {} back to snapshot {} on the next boot? The current subvolume is snapshotted and kept.",
+                dataset,
+                format_datetime(datetime)
+            ))
+            .interact()?
+    {
+        println!();
+        bail!("user aborted");
+    }
+
+    let rolled_back = match challenge {
+        Some(challenge) => {
+            let response = client.post_confirmed(&path, Some(&challenge.token)).await?;
+            if !response.status().is_success() {
+                bail!("worker refused the request: {}", response_message(response).await?);
+            }
+            let body = hyper::body::aggregate(response).await?;
+            serde_json::from_reader(body.reader())?
+        }
+        None => audited("rollback", &options).record(rollback_local(entities, pool_id, &dataset, rollback))?,
+    };
+    println!(
+        "Took safety snapshot {} of the previous state and put a hold on it.",
+        format_datetime(rolled_back.safety_snapshot)
+    );
+
+    println!("Rolled {} back to {}.", dataset, format_datetime(datetime));
+    print_boot_guidance(rolled_back.made_default);
+    println!(
+        "Reboot to start the rolled back system. Until then, changes go to the replaced subvolume at {}, \
+         delete it with 'btrfs subvolume delete' once the rolled back system runs fine.",
+        rolled_back.replaced_path.display()
+    );
+    Ok(())
+}
+
+fn rollback_local(
+    mut entities: Entities, pool_id: EntityId, dataset: &Arc<BtrfsDataset>, rollback: DatasetRollback,
+) -> Result<RollbackResponse> {
+    let safety = dataset
+        .create_local_snapshot()
+        .context("Failed to take the safety snapshot.")?
+        .datetime();
+    let dataset_id = dataset.model().id();
+    let pool_model = entity_by_id_mut(&mut entities.btrfs_pools, pool_id).expect("always exists if path found");
+    let dataset_model = entity_by_id_mut(&mut pool_model.datasets, dataset_id).expect("always exists if path found");
+    dataset_model.held_snapshots.push(safety);
+    dataset_model.held_snapshots.sort_unstable();
+    // the hold is kept even if the rollback fails below
+    storage::store_entity_config(entities);
+
+    let response = RollbackResponse {
+        safety_snapshot: safety,
+        replaced_path: rollback.replaced_path().to_owned(),
+        made_default: rollback.was_default(),
+    };
+    let restored = rollback.run(safety)?;
+    let mut entities = storage::load_entity_config();
+    entities.relocate_dataset(restored.take_model(), pool_id)?;
+    storage::store_entity_config(entities);
+    Ok(response)
+}
+
+/// How the kernel finds the root subvolume decides whether the rollback is picked up without bootloader changes.
+fn print_boot_guidance(made_default: bool) {
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let root_flags = cmdline
+        .split_whitespace()
+        .find_map(|arg| arg.strip_prefix("rootflags="))
+        .unwrap_or_default();
+    let flags = root_flags.split(',').collect::<Vec<_>>();
+    if flags.iter().any(|f| f.starts_with("subvolid=")) {
+        println!(
+            "The kernel command line selects the root subvolume by id (rootflags={}), which still is the replaced \
+             one. Change it to subvol= with the dataset's path in the bootloader configuration.",
+            root_flags
+        );
+    } else if let Some(subvol) = flags.iter().find(|f| f.starts_with("subvol=")) {
+        println!(
+            "The kernel command line mounts {}, which is the rolled back subvolume now.",
+            subvol
+        );
+    } else if made_default {
+        println!("The kernel command line mounts the default subvolume, which is the rolled back subvolume now.");
+    } else {
+        println!(
+            "The kernel command line doesn't name a subvolume and the dataset wasn't the default subvolume. Add \
+             rootflags=subvol= with the dataset's path to the bootloader configuration."
+        );
+    }
+
+    let fstab = fs::read_to_string("/etc/fstab").unwrap_or_default();
+    let fstab_by_id = fstab.lines().any(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        !line.trim_start().starts_with('#')
+            && fields.get(1) == Some(&"/")
+            && fields
+                .get(3)
+                .map_or(false, |o| o.split(',').any(|o| o.starts_with("subvolid=")))
+    });
+    if fstab_by_id {
+        println!("The / entry in /etc/fstab mounts by subvolid, change it to subvol= with the dataset's path.");
+    }
+
+    if lookup_mountentry(Path::new("/boot")).is_some() {
+        println!(
+            "/boot is a separate filesystem and is not rolled back. If the snapshot predates a kernel upgrade, \
+             reinstall the kernel package after booting so its modules match."
+        );
+    }
+    println!("Regenerate the bootloader menu if it lists snapshots, e.g. grub-mkconfig -o /boot/grub/grub.cfg.");
+}
@@ -1,6 +1,8 @@
 use std::{
+    env,
     error::Error,
     fmt::{Debug, Display},
+    path::PathBuf,
     process::exit,
 };
 
@@ -10,21 +12,52 @@ use blkcaptapp::{
     slogext::{CustomFullFormat, SyncDrain},
 };
 use clap::{crate_version, Clap};
+use libblkcapt::{data_dir, PROFILE_ENV, STORE_ENV};
 mod commands;
 mod ui;
+use commands::archive::*;
+use commands::audit::*;
+use commands::config::*;
+use commands::coverage::*;
+use commands::doctor::*;
+use commands::net::*;
 use commands::observer::*;
 use commands::pool::*;
+use commands::power::*;
+use commands::recovery::*;
+use commands::remote::*;
 use commands::restic::*;
 use commands::service::*;
+use commands::snapshot::*;
+use commands::stats::*;
 use commands::sync::*;
+use commands::tui::*;
+use commands::upgrade::*;
 use slog::Drain;
 
 fn main() {
     let maybe_options = CliOptions::try_parse();
     let vcount = maybe_options.as_ref().map(|o| o.verbose as usize).unwrap_or_default();
 
+    // The store location is resolved from the environment on first use, so the flags must be applied before then.
+    if let Ok(options) = &maybe_options {
+        if let Some(store) = &options.store {
+            env::set_var(STORE_ENV, store);
+        }
+        if let Some(profile) = &options.profile {
+            env::set_var(PROFILE_ENV, profile);
+        }
+        ui::set_utc_times(options.utc);
+    }
+    let no_color = maybe_options.as_ref().map(|o| o.no_color).unwrap_or_default();
+    let ascii = maybe_options.as_ref().map(|o| o.ascii).unwrap_or_default();
+    ui::set_output_style(no_color, ascii);
+
     let slog_drain = {
-        let decorator = slog_term::TermDecorator::new().build();
+        let decorator = match ui::color_enabled() {
+            true => slog_term::TermDecorator::new().build(),
+            false => slog_term::TermDecorator::new().force_plain().build(),
+        };
         let drain = CustomFullFormat::new(decorator, false).fuse();
         let drain = SyncDrain::new(drain);
         slog_atomic::AtomicSwitch::new(drain)
@@ -48,56 +81,191 @@ async fn async_main(options: clap::Result<CliOptions>) -> Result<()> {
 }
 
 async fn command_dispath(options: CliOptions) -> Result<()> {
+    slog_scope::debug!("Using entity store {:?}", data_dir());
     match options.subcmd {
         TopCommands::Pool(top_options) => match top_options.subcmd {
-            PoolSubCommands::Attach(options) => attach_pool(options),
-            PoolSubCommands::Create(options) => create_pool(options),
+            PoolSubCommands::Attach(options) => audited("pool attach", &options).record(attach_pool(options)),
+            PoolSubCommands::Create(options) => audited("pool create", &options).record(create_pool(options)),
             PoolSubCommands::List(options) => list_pool(options),
+            PoolSubCommands::Update(options) => audited("pool update", &options).record(update_pool(options)),
+            PoolSubCommands::Trash(options) => trash_pool(options),
+            PoolSubCommands::Untrash(options) => audited("pool untrash", &options).record(untrash_pool(options)),
         },
         TopCommands::Dataset(top_options) => match top_options.subcmd {
-            DatasetSubCommands::Attach(options) => attach_dataset(options),
-            DatasetSubCommands::Create(options) => create_dataset(options),
+            DatasetSubCommands::Attach(options) => audited("dataset attach", &options).record(attach_dataset(options)),
+            DatasetSubCommands::Create(options) => audited("dataset create", &options).record(create_dataset(options)),
             DatasetSubCommands::List(options) => list_dataset(options),
-            DatasetSubCommands::Update(options) => update_dataset(options),
+            DatasetSubCommands::Update(options) => audited("dataset update", &options).record(update_dataset(options)),
             DatasetSubCommands::Show(options) => show_dataset(options),
+            DatasetSubCommands::Refresh(options) => refresh_dataset(options).await,
+            DatasetSubCommands::Prune(options) => prune_dataset(options).await,
+            DatasetSubCommands::Restore(options) => {
+                audited("dataset restore", &options).record(restore_dataset(options).await)
+            }
+            DatasetSubCommands::CloneConfig(options) => {
+                audited("dataset clone-config", &options).record(clone_config_dataset(options))
+            }
         },
         TopCommands::Container(top_options) => match top_options.subcmd {
-            ContainerSubCommands::Attach(options) => attach_container(options),
-            ContainerSubCommands::Create(options) => create_container(options),
+            ContainerSubCommands::Attach(options) => {
+                audited("container attach", &options).record(attach_container(options))
+            }
+            ContainerSubCommands::Create(options) => {
+                audited("container create", &options).record(create_container(options))
+            }
+            ContainerSubCommands::Update(options) => {
+                audited("container update", &options).record(update_container(options))
+            }
             ContainerSubCommands::List(options) => list_container(options),
+            ContainerSubCommands::DeleteData(options) => delete_container_data(options).await,
+            ContainerSubCommands::Refresh(options) => refresh_container(options).await,
         },
         TopCommands::Observer(top_options) => match top_options.subcmd {
-            ObserverSubCommands::Create(options) => create_observer(options),
-            ObserverSubCommands::Update(options) => update_observer(options),
-            ObserverSubCommands::Delete(options) => delete_observer(options),
+            ObserverSubCommands::Create(options) => {
+                audited("observer create", &options).record(create_observer(options))
+            }
+            ObserverSubCommands::Update(options) => {
+                audited("observer update", &options).record(update_observer(options))
+            }
+            ObserverSubCommands::Delete(options) => {
+                audited("observer delete", &options).record(delete_observer(options))
+            }
             ObserverSubCommands::Show(options) => show_observer(options),
             ObserverSubCommands::Test(options) => test_observer(options).await,
             ObserverSubCommands::List(options) => list_observer(options),
         },
         TopCommands::Sync(top_options) => match top_options.subcmd {
-            SyncSubCommands::Create(options) => create_sync(options),
-            SyncSubCommands::Update(options) => update_sync(options),
-            SyncSubCommands::Delete(options) => delete_sync(options),
+            SyncSubCommands::Create(options) => audited("sync create", &options).record(create_sync(options)),
+            SyncSubCommands::Update(options) => audited("sync update", &options).record(update_sync(options)),
+            SyncSubCommands::Delete(options) => audited("sync delete", &options).record(delete_sync(options)),
             SyncSubCommands::Show(options) => show_sync(options),
             SyncSubCommands::List(options) => list_sync(options),
+            SyncSubCommands::Verify(options) => verify_sync(options),
         },
         TopCommands::Restic(top_options) => match top_options.subcmd {
-            ResticSubCommands::Attach(options) => attach_restic(options),
-            ResticSubCommands::Update(options) => update_restic(options),
+            ResticSubCommands::Attach(options) => audited("restic attach", &options).record(attach_restic(options)),
+            ResticSubCommands::Update(options) => audited("restic update", &options).record(update_restic(options)),
+        },
+        TopCommands::Remote(top_options) => match top_options.subcmd {
+            RemoteSubCommands::Attach(options) => audited("remote attach", &options).record(attach_remote(options)),
+            RemoteSubCommands::Update(options) => audited("remote update", &options).record(update_remote(options)),
+        },
+        TopCommands::Archive(top_options) => match top_options.subcmd {
+            ArchiveSubCommands::Attach(options) => audited("archive attach", &options).record(attach_archive(options)),
+            ArchiveSubCommands::Update(options) => audited("archive update", &options).record(update_archive(options)),
+            ArchiveSubCommands::Restore(options) => {
+                audited("archive restore", &options).record(restore_archive(options).await)
+            }
+            ArchiveSubCommands::List(options) => list_archive(options).await,
+            ArchiveSubCommands::Show(options) => show_archive(options).await,
+            ArchiveSubCommands::Gc(options) => gc_archive(options).await,
+            ArchiveSubCommands::ExportTape(options) => {
+                audited("archive export-tape", &options).record(export_tape_archive(options).await)
+            }
+        },
+        TopCommands::Snapshot(top_options) => match top_options.subcmd.unwrap_or_else(SnapshotSubCommands::default) {
+            SnapshotSubCommands::Take(options) => take_dataset_snapshot(options).await,
+            SnapshotSubCommands::Show(options) => show_snapshot(options),
+            SnapshotSubCommands::Clone(options) => audited("snapshot clone", &options).record(clone_snapshot(options)),
+            SnapshotSubCommands::Prop(options) if options.is_set() => {
+                audited("snapshot prop", &options).record(snapshot_prop(options))
+            }
+            SnapshotSubCommands::Prop(options) => snapshot_prop(options),
+            SnapshotSubCommands::Ls(options) => snapshot_ls(options).await,
+            SnapshotSubCommands::Extract(options) => {
+                audited("snapshot extract", &options).record(snapshot_extract(options))
+            }
+            SnapshotSubCommands::Hold(options) => {
+                audited("snapshot hold", &options).record(hold_snapshot(options).await)
+            }
+            SnapshotSubCommands::Unhold(options) => {
+                audited("snapshot unhold", &options).record(unhold_snapshot(options).await)
+            }
+        },
+        TopCommands::Find(options) => find_in_snapshots(options),
+        TopCommands::Net(top_options) => match top_options.subcmd {
+            NetSubCommands::Pause(options) => net_pause(options).await,
+            NetSubCommands::Resume(options) => net_resume(options).await,
+        },
+        TopCommands::UpgradeHook(top_options) => match top_options.subcmd {
+            UpgradeHookSubCommands::Run(options) => {
+                audited("upgrade-hook run", &options).record(upgrade_hook_run(options).await)
+            }
+            UpgradeHookSubCommands::Install(options) => {
+                audited("upgrade-hook install", &options).record(upgrade_hook_install(options))
+            }
+        },
+        TopCommands::Power(top_options) => match top_options.subcmd {
+            PowerSubCommands::Emergency(options) => power_emergency(options).await,
+            PowerSubCommands::UpsEvent(options) => power_ups_event(options).await,
         },
         TopCommands::Service(top_options) => match top_options.subcmd {
             ServiceSubCommands::Status(options) => service_status(options).await,
-            ServiceSubCommands::Config(options) => service_config(options).await,
+            ServiceSubCommands::Reload(options) => service_reload(options).await,
+            ServiceSubCommands::Config(options) => {
+                audited("service config", &options).record(service_config(options).await)
+            }
+            ServiceSubCommands::Token(token_options) => match token_options.subcmd {
+                ServiceTokenSubCommands::Create(options) => {
+                    audited("service token create", &options).record(service_token_create(options))
+                }
+                ServiceTokenSubCommands::List(options) => service_token_list(options),
+                ServiceTokenSubCommands::Delete(options) => {
+                    audited("service token delete", &options).record(service_token_delete(options))
+                }
+            },
+        },
+        TopCommands::Doctor(options) => doctor(options),
+        TopCommands::Coverage(options) => coverage(options),
+        TopCommands::Stats(options) => stats(options),
+        TopCommands::Tui(options) => tui(options).await,
+        TopCommands::DrExport(options) => dr_export(options),
+        TopCommands::DrImport(options) => audited("dr-import", &options).record(dr_import(options)),
+        TopCommands::Audit(options) => audit(options),
+        TopCommands::RestoreMachine(options) => {
+            audited("restore-machine", &options).record(restore_machine(options).await)
+        }
+        TopCommands::Rollback(options) => rollback(options).await,
+        TopCommands::RecoverConfig(options) => audited("recover-config", &options).record(recover_config(options)),
+        TopCommands::Config(top_options) => match top_options.subcmd {
+            ConfigSubCommands::Export(options) => config_export(options),
+            ConfigSubCommands::Import(options) => {
+                audited("config import", &options).record(config_import(options).await)
+            }
         },
     }
 }
 
+fn validate_profile(value: &str) -> Result<(), String> {
+    if value.is_empty() || value.starts_with('.') || value.contains('/') {
+        Err(String::from("profile must be a plain name"))
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Clap)]
 #[clap(version = crate_version!(), author = "rebeagle")]
 struct CliOptions {
     /// Enable debug logs. Use twice to enable trace logs.
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
+    /// Use the entity store in this directory. Also set by BLKCAPT_STORE.
+    #[clap(long, value_name("dir"), global(true), conflicts_with("profile"))]
+    store: Option<PathBuf>,
+    /// Use a per-user entity store under the XDG data directory. `system` selects the system store. Also set by
+    /// BLKCAPT_PROFILE.
+    #[clap(long, value_name("name"), global(true), validator(validate_profile))]
+    profile: Option<String>,
+    /// Show times in UTC instead of the local timezone.
+    #[clap(long, global(true))]
+    utc: bool,
+    /// Don't color the output. Also set by NO_COLOR.
+    #[clap(long, global(true))]
+    no_color: bool,
+    /// Draw tables with ASCII characters only.
+    #[clap(long, global(true))]
+    ascii: bool,
     #[clap(subcommand)]
     subcmd: TopCommands,
 }
@@ -105,12 +273,32 @@ struct CliOptions {
 #[derive(Clap)]
 enum TopCommands {
     Pool(PoolCommands),
+    #[clap(visible_alias = "ds")]
     Dataset(DatasetCommands),
     Container(ContainerCommands),
     Observer(ObserverCommands),
     Sync(SyncCommands),
     Restic(ResticCommands),
+    Remote(RemoteCommands),
+    Archive(ArchiveCommands),
+    #[clap(visible_alias = "ss")]
+    Snapshot(SnapshotCommands),
+    Find(FindOptions),
+    Net(NetCommands),
+    Power(PowerCommands),
+    UpgradeHook(UpgradeHookCommands),
     Service(ServiceCommands),
+    Doctor(DoctorOptions),
+    Coverage(CoverageOptions),
+    Stats(StatsOptions),
+    Tui(TuiOptions),
+    DrExport(DrExportOptions),
+    DrImport(DrImportOptions),
+    RestoreMachine(RestoreMachineOptions),
+    Rollback(RollbackOptions),
+    RecoverConfig(RecoverConfigOptions),
+    Config(ConfigCommands),
+    Audit(AuditOptions),
 }
 
 #[derive(Clap)]
@@ -123,7 +311,11 @@ struct PoolCommands {
 enum PoolSubCommands {
     Create(PoolCreateOptions),
     Attach(PoolAttachOptions),
+    #[clap(visible_alias = "ls")]
     List(PoolListOptions),
+    Update(PoolUpdateOptions),
+    Trash(PoolTrashOptions),
+    Untrash(PoolUntrashOptions),
 }
 
 #[derive(Clap)]
@@ -137,9 +329,14 @@ struct DatasetCommands {
 enum DatasetSubCommands {
     Attach(DatasetAttachOptions),
     Create(DatasetCreateOptions),
+    #[clap(visible_alias = "ls")]
     List(DatasetListOptions),
     Update(DatasetUpdateOptions),
     Show(DatasetShowOptions),
+    CloneConfig(DatasetCloneConfigOptions),
+    Refresh(DatasetRefreshOptions),
+    Prune(DatasetPruneOptions),
+    Restore(DatasetRestoreOptions),
 }
 
 #[derive(Clap)]
@@ -152,7 +349,11 @@ struct ContainerCommands {
 enum ContainerSubCommands {
     Attach(ContainerAttachOptions),
     Create(ContainerCreateOptions),
+    Update(ContainerUpdateOptions),
+    #[clap(visible_alias = "ls")]
     List(ContainerListOptions),
+    DeleteData(ContainerDeleteDataOptions),
+    Refresh(ContainerRefreshOptions),
 }
 
 #[derive(Clap)]
@@ -168,6 +369,7 @@ enum ObserverSubCommands {
     Delete(ObserverDeleteOptions),
     Show(ObserverShowOptions),
     Test(ObserverTestOptions),
+    #[clap(visible_alias = "ls")]
     List(ObserverListOptions),
 }
 
@@ -183,7 +385,9 @@ enum SyncSubCommands {
     Update(SyncUpdateOptions),
     Delete(SyncDeleteOptions),
     Show(SyncShowOptions),
+    #[clap(visible_alias = "ls")]
     List(SyncListOptions),
+    Verify(SyncVerifyOptions),
 }
 
 #[derive(Clap)]
@@ -198,6 +402,110 @@ enum ResticSubCommands {
     Update(ResticUpdateOptions),
 }
 
+#[derive(Clap)]
+struct RemoteCommands {
+    #[clap(subcommand)]
+    subcmd: RemoteSubCommands,
+}
+
+#[derive(Clap)]
+enum RemoteSubCommands {
+    Attach(RemoteAttachOptions),
+    Update(RemoteUpdateOptions),
+}
+
+#[derive(Clap)]
+struct ArchiveCommands {
+    #[clap(subcommand)]
+    subcmd: ArchiveSubCommands,
+}
+
+#[derive(Clap)]
+enum ArchiveSubCommands {
+    Attach(ArchiveAttachOptions),
+    Update(ArchiveUpdateOptions),
+    Restore(ArchiveRestoreOptions),
+    #[clap(visible_alias = "ls")]
+    List(ArchiveListOptions),
+    Show(ArchiveShowOptions),
+    Gc(ArchiveGcOptions),
+    ExportTape(ArchiveExportTapeOptions),
+}
+
+/// Without a subcommand, take a snapshot of the dataset containing the current directory
+#[derive(Clap)]
+struct SnapshotCommands {
+    #[clap(subcommand)]
+    subcmd: Option<SnapshotSubCommands>,
+}
+
+#[derive(Clap)]
+enum SnapshotSubCommands {
+    Take(SnapshotTakeOptions),
+    Show(SnapshotShowOptions),
+    Clone(SnapshotCloneOptions),
+    Prop(SnapshotPropOptions),
+    #[clap(visible_alias = "browse")]
+    Ls(SnapshotLsOptions),
+    Extract(SnapshotExtractOptions),
+    Hold(SnapshotHoldOptions),
+    Unhold(SnapshotUnholdOptions),
+}
+
+impl Default for SnapshotSubCommands {
+    fn default() -> Self {
+        Self::Take(SnapshotTakeOptions::default())
+    }
+}
+
+#[derive(Clap)]
+struct NetCommands {
+    #[clap(subcommand)]
+    subcmd: NetSubCommands,
+}
+
+#[derive(Clap)]
+enum NetSubCommands {
+    Pause(NetPauseOptions),
+    Resume(NetResumeOptions),
+}
+
+#[derive(Clap)]
+struct UpgradeHookCommands {
+    #[clap(subcommand)]
+    subcmd: UpgradeHookSubCommands,
+}
+
+#[derive(Clap)]
+enum UpgradeHookSubCommands {
+    Run(UpgradeHookRunOptions),
+    Install(UpgradeHookInstallOptions),
+}
+
+#[derive(Clap)]
+struct PowerCommands {
+    #[clap(subcommand)]
+    subcmd: PowerSubCommands,
+}
+
+#[derive(Clap)]
+enum PowerSubCommands {
+    Emergency(PowerEmergencyOptions),
+    UpsEvent(PowerUpsEventOptions),
+}
+
+#[derive(Clap)]
+struct ConfigCommands {
+    #[clap(subcommand)]
+    subcmd: ConfigSubCommands,
+}
+
+#[derive(Clap)]
+enum ConfigSubCommands {
+    Export(ConfigExportOptions),
+    Import(ConfigImportOptions),
+}
+
 #[derive(Clap)]
 struct ServiceCommands {
     #[clap(subcommand)]
@@ -207,7 +515,22 @@ struct ServiceCommands {
 #[derive(Clap)]
 enum ServiceSubCommands {
     Status(ServiceStatusOptions),
+    Reload(ServiceReloadOptions),
     Config(ServiceConfigOptions),
+    Token(ServiceTokenCommands),
+}
+
+#[derive(Clap)]
+struct ServiceTokenCommands {
+    #[clap(subcommand)]
+    subcmd: ServiceTokenSubCommands,
+}
+
+#[derive(Clap)]
+enum ServiceTokenSubCommands {
+    Create(ServiceTokenCreateOptions),
+    List(ServiceTokenListOptions),
+    Delete(ServiceTokenDeleteOptions),
 }
 
 struct ClapErrorWrapper(clap::Error);
@@ -1,18 +1,78 @@
 use anyhow::{Context, Result};
-use comfy_table::presets::UTF8_FULL;
+use chrono::{DateTime, Local, Utc};
+use comfy_table::presets::{ASCII_FULL, UTF8_FULL};
 use comfy_table::*;
 use libblkcapt::{
     model::entities::{FeatureState, ScheduleModel},
-    parsing::parse_uuid,
+    parsing::{parse_byte_size, parse_uuid},
+    sys::btrfs::SubvolumeProperties,
 };
 use presets::ASCII_NO_BORDERS;
-use std::{convert::TryInto, str::FromStr};
+use std::{
+    convert::TryInto,
+    env,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 use uuid::Uuid;
 
-pub fn print_comfy_table(header: Vec<Cell>, rows: impl Iterator<Item = Vec<Cell>>) {
+static UTC_TIMES: AtomicBool = AtomicBool::new(false);
+static NO_COLOR: AtomicBool = AtomicBool::new(false);
+static ASCII_TABLES: AtomicBool = AtomicBool::new(false);
+
+/// Turn off colors and draw tables with ASCII instead of Unicode box characters. Colors are also off when the
+/// `NO_COLOR` environment variable is set to anything but an empty string.
+pub fn set_output_style(no_color: bool, ascii: bool) {
+    let no_color_env = env::var_os("NO_COLOR").map_or(false, |v| !v.is_empty());
+    NO_COLOR.store(no_color || no_color_env, Ordering::Relaxed);
+    ASCII_TABLES.store(ascii, Ordering::Relaxed);
+}
+
+pub fn color_enabled() -> bool {
+    !NO_COLOR.load(Ordering::Relaxed)
+}
+
+fn new_table() -> Table {
     let mut table = Table::new();
+    if !color_enabled() {
+        // Without a tty comfy-table drops all styling, but also stops fitting the table to the terminal width.
+        table.force_no_tty();
+        if let Ok((width, _)) = crossterm::terminal::size() {
+            table.set_table_width(width);
+        }
+    }
     table
-        .load_preset(UTF8_FULL)
+}
+
+/// Show times in UTC instead of the local timezone. Stored times and snapshot names are UTC either way.
+pub fn set_utc_times(utc: bool) {
+    UTC_TIMES.store(utc, Ordering::Relaxed);
+}
+
+/// Converts a time to the timezone it is shown in and formats it with `format`.
+pub fn format_datetime_with(datetime: DateTime<Utc>, format: &str) -> String {
+    match UTC_TIMES.load(Ordering::Relaxed) {
+        true => datetime.format(format).to_string(),
+        false => datetime.with_timezone(&Local).format(format).to_string(),
+    }
+}
+
+/// Formats a time as RFC 3339 in the timezone it is shown in.
+pub fn format_datetime(datetime: DateTime<Utc>) -> String {
+    match UTC_TIMES.load(Ordering::Relaxed) {
+        true => datetime.to_rfc3339(),
+        false => datetime.with_timezone(&Local).to_rfc3339(),
+    }
+}
+
+pub fn print_comfy_table(header: Vec<Cell>, rows: impl Iterator<Item = Vec<Cell>>) {
+    let mut table = new_table();
+    table
+        .load_preset(match ASCII_TABLES.load(Ordering::Relaxed) {
+            true => ASCII_FULL,
+            false => UTF8_FULL,
+        })
         .set_content_arrangement(ContentArrangement::Dynamic)
         .set_header(header);
 
@@ -31,6 +91,37 @@ pub fn comfy_feature_state_cell(state: FeatureState) -> Cell {
     })
 }
 
+pub fn comfy_checksums_cell(properties: &SubvolumeProperties) -> Cell {
+    if properties.checksums_disabled() {
+        Cell::new("Disabled (nodatacow)").fg(comfy_table::Color::Red)
+    } else {
+        Cell::new("Enabled").fg(comfy_table::Color::Green)
+    }
+}
+
+pub fn comfy_estimate_cell(estimate: Option<Duration>) -> Cell {
+    match estimate {
+        Some(estimate) => Cell::new(humantime::format_duration(Duration::from_secs(
+            estimate.as_secs().max(1),
+        ))),
+        None => Cell::new("unknown (no transfer history)"),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
 pub fn comfy_id_header() -> Cell {
     comfy_identifier_header("ID")
 }
@@ -83,7 +174,7 @@ impl From<Vec<Cell>> for CellOrCells {
 }
 
 pub fn print_comfy_info(rows: Vec<(Cell, CellOrCells)>) {
-    let mut table = Table::new();
+    let mut table = new_table();
     table
         .load_preset(ASCII_NO_BORDERS)
         .remove_style(TableComponent::HorizontalLines)
@@ -161,3 +252,14 @@ impl FromStr for ScheduleArg {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy)]
+pub struct ByteSizeArg(pub u64);
+
+impl FromStr for ByteSizeArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_byte_size(s).map(Self)
+    }
+}
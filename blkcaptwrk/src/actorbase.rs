@@ -8,13 +8,83 @@ use futures_util::{
 };
 use libblkcapt::{
     error_cause,
-    model::{Entity, EntityId, EntityStatic},
+    model::{storage, worker::BatteryJob, Entity, EntityId, EntityStatic},
+    sys::{
+        clock::{self, ClockMonitor, ClockProblem},
+        power::PowerSupply,
+    },
 };
-use slog::{debug, error, Logger};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use slog::{debug, error, info, warn, Logger};
 use std::future::Future;
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use tokio::task::JoinHandle;
 use xactor::{Actor, Addr, Message};
 
+/// How often a job held back on battery checks the power supply again.
+pub const BATTERY_RECHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Whether the worker config holds `job` back on the current power supply. An unknown power supply holds nothing
+/// back.
+pub fn battery_defers(job: BatteryJob, log: &Logger) -> bool {
+    let config = storage::worker_config();
+    if config.battery.is_empty() {
+        return false;
+    }
+    match PowerSupply::read() {
+        Ok(supply) => config.battery_defers(job, &supply),
+        Err(e) => {
+            debug!(log, "power supply unknown"; "error" => %e);
+            false
+        }
+    }
+}
+
+/// Shared by all actors, so a jump noticed by one job is still reported to the jobs that follow it.
+static CLOCK_MONITOR: Lazy<Mutex<Option<ClockMonitor>>> = Lazy::new(|| Mutex::new(ClockMonitor::new().ok()));
+
+/// Why the wall clock can't be trusted right now, if it can't. `newest` is the newest snapshot of the job's dataset
+/// or container, which must not be dated after now.
+pub fn clock_problem(newest: Option<DateTime<Utc>>, log: &Logger) -> Option<ClockProblem> {
+    let jump = match CLOCK_MONITOR.lock().expect("clock monitor lock poisoned").as_mut() {
+        Some(monitor) => monitor.check().unwrap_or_else(|e| {
+            debug!(log, "clock jump check failed"; "error" => %e);
+            None
+        }),
+        None => None,
+    };
+    if let Some(offset) = jump {
+        return Some(ClockProblem::Jumped(offset));
+    }
+    if let Some(newest) = newest.filter(|newest| *newest > Utc::now()) {
+        return Some(ClockProblem::BehindSnapshot(newest));
+    }
+    match clock::synchronized() {
+        Ok(false) => Some(ClockProblem::Unsynchronized),
+        Ok(true) => None,
+        Err(e) => {
+            debug!(log, "clock synchronization unknown"; "error" => %e);
+            None
+        }
+    }
+}
+
+/// Whether a prune has to wait for the clock. Problems the worker config tolerates are only warned about.
+pub fn clock_defers_pruning(newest: Option<DateTime<Utc>>, log: &Logger) -> bool {
+    match clock_problem(newest, log) {
+        Some(problem) if storage::worker_config().clock_defers_pruning(problem) => {
+            warn!(log, "prune deferred until the clock is sane"; "reason" => %problem);
+            true
+        }
+        Some(problem) => {
+            warn!(log, "pruning with an untrusted clock"; "reason" => %problem);
+            false
+        }
+        None => false,
+    }
+}
+
 pub fn unhandled_error(log: &Logger, error: Error) {
     log_error(log, &error);
 }
@@ -50,7 +120,29 @@ fn schedule_next_delay(schedule: &Schedule, after: DateTime<Utc>) -> Option<(Dat
     })
 }
 
-pub struct ScheduledMessage {}
+/// Longest a scheduled message sleeps before looking at the wall clock again. Tokio's timers run on the monotonic
+/// clock, which stands still while the machine is suspended and doesn't follow changes to the wall clock.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Sleep until the wall clock reaches `deadline`, `delay` away when the sleep started. Returns false when the clock
+/// was set back far enough that the deadline has to be computed again.
+async fn sleep_until_wall_clock(deadline: DateTime<Utc>, delay: Duration) -> bool {
+    loop {
+        let remaining = match (deadline - Utc::now()).to_std() {
+            Ok(remaining) if remaining > Duration::from_secs(0) => remaining,
+            _ => return true,
+        };
+        if remaining > delay + SCHEDULE_CHECK_INTERVAL {
+            return false;
+        }
+        tokio::time::sleep(remaining.min(SCHEDULE_CHECK_INTERVAL)).await;
+    }
+}
+
+/// Sends a message to the actor on a schedule. Dropping it cancels the schedule.
+pub struct ScheduledMessage {
+    task: JoinHandle<()>,
+}
 
 impl ScheduledMessage {
     pub fn new<M: Message<Result = ()> + Clone, A: BcHandler<M> + BcActorCtrl, S: Into<String>>(
@@ -59,27 +151,50 @@ impl ScheduledMessage {
         let sender = ctx.address().sender();
         let what = what.into();
         let log = ctx.log().clone();
-        tokio::spawn(async move {
+        let task = tokio::spawn(async move {
             loop {
-                if let Some((next_datetime, interval)) = schedule_next_delay(&schedule, Utc::now()) {
-                    let display_delay = Duration::from_secs(interval.as_secs());
-                    debug!(
-                        log,
-                        "next {} scheduled at {} (in {})",
-                        what,
-                        next_datetime,
-                        humantime::Duration::from(display_delay)
-                    );
-                    tokio::time::sleep(interval).await;
-                    if sender.send(message.clone()).is_err() {
+                let (next_datetime, interval) = match schedule_next_delay(&schedule, Utc::now()) {
+                    Some(next) => next,
+                    None => {
+                        debug!(log, "no next {} in schedule", what);
                         break;
                     }
-                } else {
-                    debug!(log, "no next {} in schedule", what);
+                };
+                let display_delay = Duration::from_secs(interval.as_secs());
+                debug!(
+                    log,
+                    "next {} scheduled at {} (in {})",
+                    what,
+                    next_datetime,
+                    humantime::Duration::from(display_delay)
+                );
+                if !sleep_until_wall_clock(next_datetime, interval).await {
+                    info!(log, "clock set back, rescheduling {}", what);
+                    continue;
+                }
+                // Only a suspend makes it this late, missed runs in between are not made up.
+                if let Ok(late) = (Utc::now() - next_datetime).to_std() {
+                    if late > SCHEDULE_CHECK_INTERVAL {
+                        info!(
+                            log,
+                            "running {} late after a suspend (by {})",
+                            what,
+                            humantime::Duration::from(Duration::from_secs(late.as_secs()))
+                        );
+                    }
+                }
+                if sender.send(message.clone()).is_err() {
+                    break;
                 }
             }
         });
-        Self {}
+        Self { task }
+    }
+}
+
+impl Drop for ScheduledMessage {
+    fn drop(&mut self) {
+        self.task.abort();
     }
 }
 
@@ -159,3 +274,105 @@ where
         .collect::<HashMap<_, _>>()
         .await
 }
+
+/// How the entities of one kind differ between the running actors and a reloaded entity config.
+pub struct EntityDiff<'a, M> {
+    pub added: Vec<&'a M>,
+    pub removed: Vec<EntityId>,
+    pub changed: Vec<&'a M>,
+}
+
+pub fn diff_entities<'a, M: Entity + Serialize>(running: &[M], reloaded: &'a [M]) -> EntityDiff<'a, M> {
+    let removed = running
+        .iter()
+        .map(|m| m.id())
+        .filter(|&id| reloaded.iter().all(|m| m.id() != id))
+        .collect();
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+    for model in reloaded {
+        match running.iter().find(|m| m.id() == model.id()) {
+            None => added.push(model),
+            Some(current) if !same_model(current, model, &[]) => changed.push(model),
+            Some(_) => {}
+        }
+    }
+    EntityDiff {
+        added,
+        removed,
+        changed,
+    }
+}
+
+/// Whether two models are the same apart from the `ignored` fields. Models that fail to serialize are never the
+/// same.
+pub fn same_model<M: Serialize>(a: &M, b: &M, ignored: &[&str]) -> bool {
+    let fields = |model: &M| {
+        let mut value = serde_json::to_value(model).ok()?;
+        if let Some(fields) = value.as_object_mut() {
+            for field in ignored {
+                fields.remove(*field);
+            }
+        }
+        Some(value)
+    };
+    match (fields(a), fields(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libblkcapt::model::entities::{ResticContainerEntity, ResticRepository};
+
+    fn restic(name: &str) -> ResticContainerEntity {
+        ResticContainerEntity::new(name.to_owned(), ResticRepository::Custom("/srv/restic".to_owned()))
+    }
+
+    #[test]
+    fn diff_finds_added_removed_and_changed() {
+        let kept = restic("kept");
+        let changed = restic("changed");
+        let removed = restic("removed");
+        let running = vec![kept.clone(), changed.clone(), removed.clone()];
+
+        let mut changed_now = changed.clone();
+        changed_now.pause_pruning = true;
+        let added = restic("added");
+        let reloaded = vec![kept, changed_now, added.clone()];
+
+        let diff = diff_entities(&running, &reloaded);
+        assert_eq!(diff.added.iter().map(|m| m.id()).collect::<Vec<_>>(), vec![added.id()]);
+        assert_eq!(diff.removed, vec![removed.id()]);
+        assert_eq!(
+            diff.changed.iter().map(|m| m.id()).collect::<Vec<_>>(),
+            vec![changed.id()]
+        );
+    }
+
+    #[test]
+    fn diff_of_identical_configs_is_empty() {
+        let running = vec![restic("one"), restic("two")];
+        let diff = diff_entities(&running, &running);
+        assert!(diff.added.is_empty() && diff.removed.is_empty() && diff.changed.is_empty());
+    }
+
+    #[test]
+    fn same_model_ignores_only_the_given_fields() {
+        let current = restic("one");
+        let mut paused = current.clone();
+        paused.pause_pruning = true;
+        assert!(same_model(&current, &current.clone(), &[]));
+        assert!(!same_model(&current, &paused, &[]));
+        assert!(same_model(&current, &paused, &["pause_pruning"]));
+        assert!(!same_model(&current, &paused, &["snapshot_retention"]));
+    }
+
+    #[test]
+    fn same_model_compares_ids() {
+        // two entities created alike still differ by id
+        assert!(!same_model(&restic("one"), &restic("one"), &[]));
+    }
+}
@@ -0,0 +1,445 @@
+use super::{
+    dataset::SenderReadyMessage,
+    localsender::{LocalSenderActor, LocalSenderFinishedMessage, TakeReaderMessage},
+    observation::{observable_func, StartedObservation},
+    transfer::TransferComplete,
+};
+use crate::{
+    actorbase::{clock_defers_pruning, state_result, state_result_from_result, unhandled_result, ScheduledMessage},
+    snapshots::{
+        failed_snapshot_deletes_as_result, ContainerSnapshotsResponse, GetContainerSnapshotsMessage, PruneMessage,
+    },
+    tasks::{WorkerCompleteMessage, WorkerTask},
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use libblkcapt::{
+    core::{
+        archive::{plan_collection, ArchiveContainer, ArchiveManifest, ArchiveUpload},
+        SnapshotHandle,
+    },
+    model::{
+        entities::{ArchiveContainerEntity, FeatureState, ObservableEvent},
+        history::{JobKind, JobRecord},
+        storage, Entity, EntityId,
+    },
+    sys::btrfs::StreamCompression,
+};
+use slog::{debug, error, o, trace, warn, Logger};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use xactor::{message, Addr, Sender};
+
+pub struct ArchiveContainerActor {
+    container: Arc<ArchiveContainer>,
+    manifests: HashMap<EntityId, Vec<ArchiveManifest>>,
+    active_uploads: usize,
+    prune_schedule: Option<ScheduledMessage>,
+}
+
+/// Check the object store accepts the credentials, so a sync can fail fast instead of after reading a stream.
+#[message(result = "Result<()>")]
+pub struct CheckArchiveMessage;
+
+#[message(result = "Result<ArchiveUpload>")]
+pub struct GetArchiveUploadMessage {
+    pub source_dataset_id: EntityId,
+    pub snapshot: SnapshotHandle,
+    pub parent: Option<SnapshotHandle>,
+    pub compression: Option<StreamCompression>,
+}
+
+#[message()]
+struct ArchiveUploadedMessage(Option<ArchiveManifest>);
+
+impl ArchiveContainerActor {
+    pub fn new(model: ArchiveContainerEntity, log: &Logger) -> Result<BcActor<Self>> {
+        let id = model.id();
+        ArchiveContainer::validate(model).map(|container| {
+            BcActor::new(
+                Self {
+                    container: Arc::new(container),
+                    manifests: Default::default(),
+                    active_uploads: 0,
+                    prune_schedule: None,
+                },
+                &log.new(o!("container_id" => id.to_string())),
+            )
+            .observe_lifecycle(id, ObservableEvent::ContainerWorker)
+        })
+    }
+
+    async fn prune(&mut self, log: &Logger) -> Result<()> {
+        let rules = self
+            .container
+            .model()
+            .snapshot_retention
+            .clone()
+            .expect("retention exist based on message scheduling in started");
+
+        let mut failed_deletes = 0;
+        for (dataset_id, manifests) in self.manifests.iter_mut() {
+            trace!(log, "prune archive container"; "dataset_id" => %dataset_id);
+            let obsolete = plan_collection(manifests, &rules)
+                .obsolete
+                .into_iter()
+                .cloned()
+                .collect::<Vec<_>>();
+            for manifest in obsolete {
+                match self.container.delete_stream(&manifest).await {
+                    Ok(()) => {
+                        debug!(log, "deleted archived stream {}", manifest; "bytes" => manifest.size());
+                        manifests.retain(|m| m.uuid != manifest.uuid);
+                    }
+                    Err(e) => {
+                        warn!(log, "failed to delete archived stream {}: {:#}", manifest, e);
+                        failed_deletes += 1;
+                    }
+                }
+            }
+        }
+        failed_snapshot_deletes_as_result(failed_deletes)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for ArchiveContainerActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        self.manifests = self.container.manifests().await?;
+        trace!(
+            ctx.log(),
+            "Starting archive container {} with {} streams from {} datasets.",
+            self.container,
+            self.manifests.values().fold(0, |acc, v| acc + v.len()),
+            self.manifests.len()
+        );
+
+        if self.container.model().pruning_state() == FeatureState::Enabled {
+            self.prune_schedule = self
+                .container
+                .model()
+                .snapshot_retention
+                .as_ref()
+                .map(|r| &r.evaluation_schedule)
+                .map_or(Ok(None), |s| {
+                    s.try_into()
+                        .map(|schedule| Some(ScheduledMessage::new(schedule, "prune", PruneMessage, &ctx)))
+                })?;
+        }
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
+        TerminalState::Succeeded
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetContainerSnapshotsMessage> for ArchiveContainerActor {
+    async fn handle(
+        &mut self, _ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
+    ) -> ContainerSnapshotsResponse {
+        let (snapshots, total) = msg
+            .query
+            .select(self.manifests.get(&msg.source_dataset_id).into_iter().flatten());
+        ContainerSnapshotsResponse { snapshots, total }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PruneMessage> for ArchiveContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
+        let container_id = self.container.model().id();
+        let log = ctx.log();
+        if clock_defers_pruning(None, log) {
+            return;
+        }
+        let result = observable_func(container_id, ObservableEvent::ContainerPrune, || self.prune(log)).await;
+        unhandled_result(log, result);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<CheckArchiveMessage> for ArchiveContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: CheckArchiveMessage) -> Result<()> {
+        self.container.check().await
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetArchiveUploadMessage> for ArchiveContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetArchiveUploadMessage) -> Result<ArchiveUpload> {
+        if self
+            .manifests
+            .get(&msg.source_dataset_id)
+            .into_iter()
+            .flatten()
+            .any(|m| m.datetime == msg.snapshot.datetime)
+        {
+            return Err(anyhow!(
+                "upload requested for existing stream dataset_id: {} snapshot_datetime: {}",
+                msg.source_dataset_id,
+                msg.snapshot.datetime
+            ));
+        }
+
+        self.active_uploads += 1;
+        Ok(self.container.upload(
+            msg.source_dataset_id,
+            &msg.snapshot,
+            msg.parent.as_ref(),
+            msg.compression,
+        ))
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ArchiveUploadedMessage> for ArchiveContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ArchiveUploadedMessage) {
+        self.active_uploads = self.active_uploads.saturating_sub(1);
+        if let Some(manifest) = msg.0 {
+            debug!(ctx.log(), "archive container stored stream {}", manifest.datetime; "parts" => manifest.parts.len(), "bytes" => manifest.size());
+            self.manifests.entry(manifest.dataset_id).or_default().push(manifest);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for ArchiveContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        if self.active_uploads == 0 {
+            ActorStatus::idle()
+        } else {
+            ActorStatus::active(format!("uploading {} streams", self.active_uploads))
+        }
+    }
+}
+
+/// Reads a send stream from a local sender and uploads it to an archive container in parts.
+pub struct ArchiveTransferActor {
+    parent: Addr<BcActor<ArchiveContainerActor>>,
+    requestor: Sender<TransferComplete>,
+    container: Arc<ArchiveContainer>,
+    sync_id: EntityId,
+    started: Option<DateTime<Utc>>,
+    progress: Arc<AtomicU64>,
+    state: State,
+}
+
+enum State {
+    WaitingForSender(ArchiveUpload, StartedObservation),
+    Uploading(
+        Completions,
+        WorkerTask,
+        Addr<BcActor<LocalSenderActor>>,
+        StartedObservation,
+    ),
+    Uploaded(Result<ArchiveManifest>),
+    Faulted,
+}
+
+impl State {
+    fn take(&mut self) -> Self {
+        mem::replace(self, State::Faulted)
+    }
+}
+
+#[derive(Default)]
+struct Completions {
+    sender: Option<Result<()>>,
+    upload: Option<Result<ArchiveManifest>>,
+}
+
+type UploadWorkerCompleteMessage = WorkerCompleteMessage<Result<ArchiveManifest>>;
+
+impl ArchiveTransferActor {
+    pub fn new(
+        requestor: Sender<TransferComplete>, container: Addr<BcActor<ArchiveContainerActor>>, upload: ArchiveUpload,
+        sync_id: EntityId, observation: StartedObservation, log: &Logger,
+    ) -> BcActor<Self> {
+        BcActor::new(
+            Self {
+                parent: container,
+                requestor,
+                container: Arc::clone(upload.container()),
+                sync_id,
+                started: None,
+                progress: Default::default(),
+                state: State::WaitingForSender(upload, observation),
+            },
+            log,
+        )
+    }
+
+    async fn run_upload(
+        sender_actor: Addr<BcActor<LocalSenderActor>>, upload: ArchiveUpload, progress: Arc<AtomicU64>,
+    ) -> Result<ArchiveManifest> {
+        let reader = sender_actor.call(TakeReaderMessage).await??;
+        upload.run(reader, &progress).await
+    }
+
+    /// The manifest is only written once the sender confirms the stream it produced is complete.
+    async fn maybe_finish(&mut self, incoming: State, ctx: &BcContext<'_, Self>) -> State {
+        if let State::Uploading(
+            Completions {
+                sender: Some(sender),
+                upload: Some(upload),
+            },
+            _,
+            _,
+            observation,
+        ) = incoming
+        {
+            let result = match sender.and(upload) {
+                Ok(manifest) => self.container.put_manifest(&manifest).await.map(|_| manifest),
+                Err(e) => Err(e),
+            };
+            self.record_history(&result, ctx);
+            observation.result(&result);
+            ctx.stop(None);
+            State::Uploaded(result)
+        } else {
+            incoming
+        }
+    }
+
+    fn record_history(&self, result: &Result<ArchiveManifest>, ctx: &BcContext<'_, Self>) {
+        if !storage::worker_config().history_enabled {
+            return;
+        }
+
+        if let Some(started) = self.started {
+            let record = JobRecord {
+                kind: JobKind::Transfer,
+                entity_id: self.sync_id,
+                started,
+                duration: (Utc::now() - started).to_std().unwrap_or_default(),
+                bytes: Some(self.progress.load(Ordering::Relaxed)),
+                error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            };
+            unhandled_result(ctx.log(), storage::append_history(&record));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for ArchiveTransferActor {
+    async fn started(&mut self, _ctx: BcContext<'_, Self>) -> Result<()> {
+        Ok(())
+    }
+
+    async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+        let (terminal_state, result) = match self.state.take() {
+            State::Uploading(_, worker_task, sender, observation) => {
+                warn!(ctx.log(), "cancelled during upload");
+                worker_task.abort();
+                debug!(ctx.log(), "waiting for worker");
+                worker_task.wait().await;
+                observation.cancelled();
+                let _ = sender.stop(None);
+                state_result(TerminalState::Cancelled)
+            }
+            State::WaitingForSender(_, observation) => {
+                warn!(ctx.log(), "cancelled prior to upload");
+                observation.cancelled();
+                state_result(TerminalState::Cancelled)
+            }
+            State::Uploaded(result) => state_result_from_result(result),
+            State::Faulted => {
+                error!(ctx.log(), "actor faulted");
+                state_result(TerminalState::Faulted)
+            }
+        };
+
+        let container_notify_result = self.parent.send(ArchiveUploadedMessage(result.ok()));
+        let requestor_notify_result = self.requestor.send(TransferComplete(terminal_state));
+        if !matches!(terminal_state, TerminalState::Cancelled) {
+            unhandled_result(ctx.log(), container_notify_result);
+            unhandled_result(ctx.log(), requestor_notify_result);
+        }
+        terminal_state
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SenderReadyMessage> for ArchiveTransferActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: SenderReadyMessage) {
+        self.state = match (self.state.take(), msg.0) {
+            (State::WaitingForSender(upload, observation), Ok(sender)) => {
+                self.started = Some(Utc::now());
+                let mv_sender = sender.clone();
+                let mv_progress = self.progress.clone();
+                let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+                    Self::run_upload(mv_sender, upload, mv_progress).await.into()
+                });
+                State::Uploading(Default::default(), task, sender, observation)
+            }
+            (State::WaitingForSender(_, observation), Err(e)) => {
+                ctx.stop(None);
+                observation.error::<anyhow::Error, _>(&e);
+                State::Uploaded(Err(e))
+            }
+            _ => {
+                ctx.stop(None);
+                State::Faulted
+            }
+        };
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<LocalSenderFinishedMessage> for ArchiveTransferActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: LocalSenderFinishedMessage) {
+        self.state = match self.state.take() {
+            State::Uploading(mut completions, task, sender, observation) if completions.sender.is_none() => {
+                completions.sender = Some(msg.0);
+                self.maybe_finish(State::Uploading(completions, task, sender, observation), &ctx)
+                    .await
+            }
+            _ => {
+                ctx.stop(None);
+                State::Faulted
+            }
+        };
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<UploadWorkerCompleteMessage> for ArchiveTransferActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: UploadWorkerCompleteMessage) {
+        self.state = match self.state.take() {
+            State::Uploading(mut completions, task, sender, observation) if completions.upload.is_none() => {
+                completions.upload = Some(msg.0);
+                self.maybe_finish(State::Uploading(completions, task, sender, observation), &ctx)
+                    .await
+            }
+            _ => {
+                ctx.stop(None);
+                State::Faulted
+            }
+        };
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for ArchiveTransferActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        match &self.state {
+            State::WaitingForSender(..) => ActorStatus::active("waiting for sender"),
+            State::Uploading(..) => {
+                ActorStatus::active(format!("uploaded {} bytes", self.progress.load(Ordering::Relaxed)))
+            }
+            State::Uploaded(Err(e)) => ActorStatus::idle().with_last_error(format!("{:#}", e)),
+            State::Faulted => ActorStatus::faulted("upload faulted"),
+            State::Uploaded(Ok(_)) => ActorStatus::idle(),
+        }
+    }
+}
@@ -1,55 +1,183 @@
-use super::{observation::HealthchecksActor, server::ServerActor, sync::SyncActor};
-use super::{pool::PoolActor, restic::ResticContainerActor, sync::SyncToContainer};
-use crate::{
-    actorbase::build_child_actors,
-    xactorext::{BcActor, BcActorCtrl, BcContext},
+use super::{
+    archive::ArchiveContainerActor,
+    container::{ContainerActor, DeleteDatasetSnapshotsMessage},
+    dataset::{DatasetActor, EmergencySnapshotMessage, GetDatasetActivityMessage, TakeSnapshotMessage},
+    pool::{stopped_by_update, PoolActor, UpdatePoolMessage},
+    remote::RemoteContainerActor,
+    restic::ResticContainerActor,
+    sync::{
+        EmergencySyncMessage, GetSyncActivityMessage, StartSnapshotSyncCycleMessage, SyncToContainer, UpdateSyncMessage,
+    },
+};
+use super::{
+    history::HistoryActor,
+    lifecycle::LifecycleActor,
+    observation::{HealthchecksActor, NotificationActor},
+    server::ServerActor,
+    sync::SyncActor,
 };
 use crate::{
     actorbase::logged_result,
+    snapshots::{PruneMessage, RefreshSnapshotsMessage},
     xactorext::{
-        join_all_actors, stop_all_actors, BcHandler, GetActorStatusMessage, GetChildActorMessage, TerminalState,
+        join_all_actors, stop_all_actors, ActorStatus, BcHandler, GetActorStatusMessage, GetChildActorMessage,
+        TerminalState,
     },
 };
-use anyhow::{Context as AnyhowContext, Result};
+use crate::{
+    actorbase::{build_child_actors, diff_entities, log_result, EntityDiff},
+    xactorext::{BcActor, BcActorCtrl, BcContext},
+};
+use anyhow::{bail, Context as AnyhowContext, Result};
+use chrono::{DateTime, Utc};
 use futures_util::future;
 use libblkcapt::{
+    core::{
+        restore::DatasetRollback,
+        system::{
+            DashboardState, DatasetOverview, EmergencyResponse, PoolOverview, RefreshedSnapshotsResponse,
+            RollbackResponse, SyncOverview,
+        },
+        BtrfsDataset, BtrfsPool,
+    },
     create_data_dir,
-    model::{entities::SnapshotSyncEntity, storage, AnyContainer, Entities, Entity, EntityId},
+    model::{
+        entities::{BtrfsPoolEntity, SnapshotSyncEntity},
+        entity_by_id_mut, storage, AnyContainer, Entities, Entity, EntityId, EntityPath,
+    },
+    sys::privilege::{running_as_root, ROOT_ONLY_FEATURES},
+};
+use slog::{debug, info, trace, warn, Logger};
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    sync::Arc,
 };
-use slog::{trace, Logger};
-use std::collections::HashMap;
-use xactor::{Actor, Addr};
+use xactor::{message, Actor, Addr};
 
 pub struct CaptainActor {
     healthcheck_actors: HashMap<EntityId, Addr<BcActor<HealthchecksActor>>>,
+    notification_actors: HashMap<EntityId, Addr<BcActor<NotificationActor>>>,
     sync_actors: HashMap<EntityId, Addr<BcActor<SyncActor>>>,
     pool_actors: HashMap<EntityId, Addr<BcActor<PoolActor>>>,
     restic_actors: HashMap<EntityId, Addr<BcActor<ResticContainerActor>>>,
+    remote_actors: HashMap<EntityId, Addr<BcActor<RemoteContainerActor>>>,
+    archive_actors: HashMap<EntityId, Addr<BcActor<ArchiveContainerActor>>>,
     server_actor: Option<Addr<BcActor<ServerActor>>>,
+    history_actor: Option<Addr<BcActor<HistoryActor>>>,
+    lifecycle_actor: Option<Addr<BcActor<LifecycleActor>>>,
+    /// The entity config the running actors were started from.
+    entities: Entities,
+}
+
+/// Delete everything a container holds from one dataset. Returns the number of snapshots deleted.
+#[message(result = "Result<usize>")]
+pub struct DeleteContainerDataMessage {
+    pub container_id: EntityId,
+    pub dataset_id: EntityId,
+}
+
+/// Re-scan the snapshots of a dataset or container on disk.
+#[message(result = "Result<RefreshedSnapshotsResponse>")]
+pub struct RefreshEntitySnapshotsMessage(pub EntityId);
+
+/// Snapshot a dataset right away. Returns when the snapshot was taken.
+#[message(result = "Result<DateTime<Utc>>")]
+pub struct SnapshotDatasetMessage(pub EntityId);
+
+/// Snapshot a dataset right away, tagged for its pre-upgrade retention. Returns when the snapshot was taken.
+#[message(result = "Result<DateTime<Utc>>")]
+pub struct PreUpgradeSnapshotMessage(pub EntityId);
+
+/// Roll a dataset back to one of its snapshots, after taking a held snapshot of its current state. The dataset and its
+/// syncs stop for the swap, and a reload of the stored entity config starts them again.
+#[message(result = "Result<RollbackResponse>")]
+pub struct RollbackDatasetMessage {
+    pub dataset_id: EntityId,
+    pub snapshot: DateTime<Utc>,
 }
 
+/// Prune a dataset's snapshots now. Held for the pool's wake window like scheduled prunes.
+#[message(result = "Result<()>")]
+pub struct PruneDatasetMessage(pub EntityId);
+
+/// Delete the streams of an archive container its retention no longer keeps, now.
+#[message(result = "Result<()>")]
+pub struct PruneArchiveMessage(pub EntityId);
+
+/// Start a sync cycle now. Held for the pools' wake windows like scheduled cycles.
+#[message(result = "Result<()>")]
+pub struct RunSyncMessage(pub EntityId);
+
+/// The configured pools, datasets and syncs with what their actors know, for the dashboard.
+#[message(result = "DashboardState")]
+pub struct GetDashboardMessage;
+
+/// Snapshot the critical datasets and start syncing them, the UPS is about to run out.
+#[message(result = "Result<EmergencyResponse>")]
+pub struct EmergencyMessage;
+
+/// Apply the entity store to the running actors, so changes made with blkcaptctl take effect without restarting the
+/// worker. Actors of added entities start and those of removed entities stop. Changed pools, datasets, containers
+/// and syncs are updated in place where they can be, so transfers in flight carry on. Other changed entities, and
+/// the syncs that depend on them, restart.
+#[message(result = "Result<()>")]
+pub struct ReloadMessage;
+
 impl CaptainActor {
     pub fn new(log: &Logger) -> BcActor<Self> {
         BcActor::new(
             Self {
                 healthcheck_actors: Default::default(),
+                notification_actors: Default::default(),
                 sync_actors: Default::default(),
                 pool_actors: Default::default(),
                 restic_actors: Default::default(),
+                remote_actors: Default::default(),
+                archive_actors: Default::default(),
                 server_actor: None,
+                history_actor: None,
+                lifecycle_actor: None,
+                entities: Default::default(),
             },
             log,
         )
     }
 
+    async fn dataset_actor(&self, dataset_id: EntityId) -> Result<Addr<BcActor<DatasetActor>>> {
+        let dataset = self
+            .entities
+            .dataset(dataset_id)
+            .with_context(|| format!("no dataset with id {}", dataset_id))?;
+        let pool = self
+            .pool_actors
+            .get(&dataset.parent.id())
+            .context("dataset's pool did not start")?;
+        pool.call(GetChildActorMessage::new(dataset_id))
+            .await?
+            .context("dataset did not start")
+    }
+
     async fn new_sync_actor(
         &self, entities: &Entities, model: SnapshotSyncEntity, log: &Logger,
     ) -> Result<BcActor<SyncActor>> {
+        let topology = entities.sync_topology(&model)?;
         let dataset_pool_id = entities
             .dataset(model.dataset_id)
             .map(|p| p.parent.id())
             .context("source dataset does not exist")?;
 
+        let pools = topology
+            .pools()
+            .into_iter()
+            .map(|id| {
+                self.pool_actors
+                    .get(&id)
+                    .cloned()
+                    .context("pool for sync did not start")
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         let dataset_pool = self
             .pool_actors
             .get(&dataset_pool_id)
@@ -85,54 +213,230 @@ impl CaptainActor {
 
                 SyncToContainer::Restic(container_actor.clone())
             }
+            AnyContainer::Remote(container_model) => {
+                let container_actor = self
+                    .remote_actors
+                    .get(&container_model.id())
+                    .context("destination remote container did not start")?;
+
+                SyncToContainer::Remote(container_actor.clone())
+            }
+            AnyContainer::Archive(container_model) => {
+                let container_actor = self
+                    .archive_actors
+                    .get(&container_model.id())
+                    .context("destination archive container did not start")?;
+
+                SyncToContainer::Archive(container_actor.clone())
+            }
         };
 
-        Ok(SyncActor::new(dataset_actor, to_container_actor, model, log))
-    }
-}
+        let target_rpo = entities.dataset(model.dataset_id).and_then(|d| d.entity.target_rpo);
+        let wake_pools = topology
+            .pools()
+            .into_iter()
+            .filter(|&id| entities.pool(id).map_or(false, |p| p.wake_schedule.is_some()))
+            .collect();
 
-#[async_trait::async_trait]
-impl BcActorCtrl for CaptainActor {
-    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
-        create_data_dir()?;
+        Ok(SyncActor::new(
+            dataset_actor,
+            to_container_actor,
+            pools,
+            topology,
+            target_rpo,
+            wake_pools,
+            model,
+            log,
+        ))
+    }
 
-        let entities = storage::load_entity_config();
+    /// Starts the actors of the selected entities.
+    async fn start_entity_actors(
+        &mut self, ctx: &BcContext<'_, Self>, entities: &Entities, selected: &(dyn Fn(EntityId) -> bool + Sync),
+    ) {
+        let worker_config = storage::worker_config();
 
-        if !entities.observers.is_empty() {
+        if !worker_config.observers_enabled {
+            debug!(ctx.log(), "observers are disabled in the worker config");
+        } else if !entities.observers.is_empty() {
             trace!(ctx.log(), "building observer actors");
-            self.healthcheck_actors = build_child_actors(&ctx, entities.observers.iter(), |m| {
+            let actors = build_child_actors(ctx, entities.observers.iter().filter(|m| selected(m.id())), |m| {
                 future::ok(HealthchecksActor::new(m.clone(), ctx.log()))
             })
             .await;
+            self.healthcheck_actors.extend(actors);
         };
 
+        if worker_config.observers_enabled && !entities.notifiers.is_empty() {
+            trace!(ctx.log(), "building notification observer actors");
+            let actors = build_child_actors(ctx, entities.notifiers.iter().filter(|m| selected(m.id())), |m| {
+                future::ok(NotificationActor::new(m.clone(), entities, ctx.log()))
+            })
+            .await;
+            self.notification_actors.extend(actors);
+        }
+
         if !entities.btrfs_pools.is_empty() {
             trace!(ctx.log(), "building pool actors");
-            self.pool_actors = build_child_actors(&ctx, entities.btrfs_pools.iter(), |m| {
+            let actors = build_child_actors(ctx, entities.btrfs_pools.iter().filter(|m| selected(m.id())), |m| {
                 future::ok(PoolActor::new(m.clone(), ctx.log()))
             })
             .await;
+            self.pool_actors.extend(actors);
         }
 
         if !entities.restic_containers.is_empty() {
             trace!(ctx.log(), "building restic actors");
-            self.restic_actors = build_child_actors(&ctx, entities.restic_containers.iter(), |m| {
-                future::ok(ResticContainerActor::new(m.clone(), ctx.log()))
-            })
+            let actors = build_child_actors(
+                ctx,
+                entities.restic_containers.iter().filter(|m| selected(m.id())),
+                |m| future::ok(ResticContainerActor::new(m.clone(), ctx.log())),
+            )
+            .await;
+            self.restic_actors.extend(actors);
+        };
+
+        if !entities.remote_containers.is_empty() {
+            trace!(ctx.log(), "building remote container actors");
+            let actors = build_child_actors(
+                ctx,
+                entities.remote_containers.iter().filter(|m| selected(m.id())),
+                |m| future::ready(RemoteContainerActor::new(m.clone(), ctx.log())),
+            )
             .await;
+            self.remote_actors.extend(actors);
+        };
+
+        if !entities.archive_containers.is_empty() {
+            trace!(ctx.log(), "building archive container actors");
+            let actors = build_child_actors(
+                ctx,
+                entities.archive_containers.iter().filter(|m| selected(m.id())),
+                |m| future::ready(ArchiveContainerActor::new(m.clone(), ctx.log())),
+            )
+            .await;
+            self.archive_actors.extend(actors);
         };
 
         if !entities.snapshot_syncs.is_empty() {
             trace!(ctx.log(), "building sync actors");
-            self.sync_actors = build_child_actors(&ctx, entities.snapshot_syncs.iter(), |m| {
-                self.new_sync_actor(&entities, m.clone(), ctx.log())
+            let actors = build_child_actors(ctx, entities.snapshot_syncs.iter().filter(|m| selected(m.id())), |m| {
+                self.new_sync_actor(entities, m.clone(), ctx.log())
             })
             .await;
+            self.sync_actors.extend(actors);
+        }
+    }
+
+    async fn stop_entity_actors(&mut self) {
+        stop_all_actors(self.healthcheck_actors.values_mut());
+        stop_all_actors(self.notification_actors.values_mut());
+        stop_all_actors(self.sync_actors.values_mut());
+        stop_all_actors(self.pool_actors.values_mut());
+        stop_all_actors(self.restic_actors.values_mut());
+        stop_all_actors(self.remote_actors.values_mut());
+        stop_all_actors(self.archive_actors.values_mut());
+
+        join_all_actors(self.healthcheck_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.notification_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.sync_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.pool_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.restic_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.remote_actors.drain().map(|(_k, v)| v)).await;
+        join_all_actors(self.archive_actors.drain().map(|(_k, v)| v)).await;
+    }
+
+    /// Stop a dataset's syncs, then its actor, by updating its pool to `quiesced_pool`, the pool without it.
+    async fn quiesce_dataset(&mut self, dataset_id: EntityId, quiesced_pool: BtrfsPoolEntity) -> Result<()> {
+        let syncs = self
+            .entities
+            .snapshot_syncs
+            .iter()
+            .filter(|s| s.dataset_id == dataset_id)
+            .map(|s| s.id())
+            .collect::<HashSet<_>>();
+        stop_actors(&mut self.sync_actors, &syncs).await;
+        let pool_actor = self
+            .pool_actors
+            .get(&quiesced_pool.id())
+            .context("the dataset's pool is not running")?;
+        match pool_actor.call(UpdatePoolMessage(quiesced_pool)).await?? {
+            Some(_) => Ok(()),
+            None => bail!("the dataset's pool can't be updated in place"),
+        }
+    }
+}
+
+/// Plans for the actors of removed and changed entities to stop, and for those of added and changed entities to
+/// start.
+fn plan_restarts<M: Entity>(diff: EntityDiff<'_, M>, start: &mut HashSet<EntityId>, stop: &mut HashSet<EntityId>) {
+    start.extend(diff.added.iter().chain(diff.changed.iter()).map(|m| m.id()));
+    stop.extend(diff.removed.into_iter().chain(diff.changed.iter().map(|m| m.id())));
+}
+
+/// The entities without a running actor that aren't already planned to stop.
+fn not_running<'a, A, M: Entity>(
+    actors: &'a HashMap<EntityId, Addr<A>>, models: &'a [M], stop: &'a HashSet<EntityId>,
+) -> impl Iterator<Item = EntityId> + 'a {
+    models
+        .iter()
+        .map(|m| m.id())
+        .filter(move |id| !actors.contains_key(id) && !stop.contains(id))
+}
+
+/// Stops the actors of the entities in `stop` and waits for them. Returns the number stopped.
+async fn stop_actors<A: BcActorCtrl>(
+    actors: &mut HashMap<EntityId, Addr<BcActor<A>>>, stop: &HashSet<EntityId>,
+) -> usize {
+    let mut stopping = stop.iter().filter_map(|id| actors.remove(id)).collect::<Vec<_>>();
+    let count = stopping.len();
+    stop_all_actors(&mut stopping);
+    join_all_actors(stopping).await;
+    count
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for CaptainActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        create_data_dir()?;
+
+        let entities = storage::load_entity_config();
+        let worker_config = storage::worker_config();
+
+        if !running_as_root() {
+            warn!(
+                ctx.log(),
+                "running without root, some features are unavailable";
+                "unavailable" => ROOT_ONLY_FEATURES.join(", ")
+            );
+        }
+
+        if worker_config.history_enabled {
+            self.history_actor = logged_result(
+                ctx.log(),
+                HistoryActor::new(ctx.log())
+                    .start()
+                    .await
+                    .context("failed to start history actor"),
+            )
+            .ok();
         }
 
+        self.lifecycle_actor = logged_result(
+            ctx.log(),
+            LifecycleActor::new(ctx.log())
+                .start()
+                .await
+                .context("failed to start lifecycle actor"),
+        )
+        .ok();
+
+        self.start_entity_actors(&ctx, &entities, &|_| true).await;
+        self.entities = entities;
+
         self.server_actor = logged_result(
             ctx.log(),
-            ServerActor::new(ctx.log())
+            ServerActor::new(ctx.address().downgrade(), ctx.log())
                 .start()
                 .await
                 .context("failed to start server actor"),
@@ -143,28 +447,507 @@ impl BcActorCtrl for CaptainActor {
     }
 
     async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
-        stop_all_actors(self.healthcheck_actors.values_mut());
-        stop_all_actors(self.sync_actors.values_mut());
-        stop_all_actors(self.pool_actors.values_mut());
-        stop_all_actors(self.restic_actors.values_mut());
-
-        join_all_actors(self.healthcheck_actors.drain().map(|(_k, v)| v)).await;
-        join_all_actors(self.sync_actors.drain().map(|(_k, v)| v)).await;
-        join_all_actors(self.pool_actors.drain().map(|(_k, v)| v)).await;
-        join_all_actors(self.restic_actors.drain().map(|(_k, v)| v)).await;
+        self.stop_entity_actors().await;
 
         if let Some(mut actor) = self.server_actor.take() {
             let _ = actor.stop(None);
             let _ = actor.wait_for_stop();
         }
 
+        if let Some(mut actor) = self.history_actor.take() {
+            let _ = actor.stop(None);
+            actor.wait_for_stop().await;
+        }
+
+        if let Some(mut actor) = self.lifecycle_actor.take() {
+            let _ = actor.stop(None);
+            actor.wait_for_stop().await;
+        }
+
         TerminalState::Succeeded
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<DeleteContainerDataMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: DeleteContainerDataMessage) -> Result<usize> {
+        let entities = storage::load_entity_config();
+        let pool_id = entities
+            .container(msg.container_id)
+            .map(|c| c.parent.id())
+            .context("container does not exist")?;
+        let container_pool = self
+            .pool_actors
+            .get(&pool_id)
+            .context("container's pool did not start")?;
+        let container_actor: Addr<BcActor<ContainerActor>> = container_pool
+            .call(GetChildActorMessage::new(msg.container_id))
+            .await?
+            .context("container did not start")?;
+
+        container_actor
+            .call(DeleteDatasetSnapshotsMessage(msg.dataset_id))
+            .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<RefreshEntitySnapshotsMessage> for CaptainActor {
+    async fn handle(
+        &mut self, _ctx: BcContext<'_, Self>, msg: RefreshEntitySnapshotsMessage,
+    ) -> Result<RefreshedSnapshotsResponse> {
+        let entities = storage::load_entity_config();
+        let entity_id = msg.0;
+        if let Some(dataset) = entities.dataset(entity_id) {
+            let pool = self
+                .pool_actors
+                .get(&dataset.parent.id())
+                .context("dataset's pool did not start")?;
+            let dataset_actor: Addr<BcActor<DatasetActor>> = pool
+                .call(GetChildActorMessage::new(entity_id))
+                .await?
+                .context("dataset did not start")?;
+            dataset_actor.call(RefreshSnapshotsMessage).await?
+        } else if let Some(container) = entities.container(entity_id) {
+            let pool = self
+                .pool_actors
+                .get(&container.parent.id())
+                .context("container's pool did not start")?;
+            let container_actor: Addr<BcActor<ContainerActor>> = pool
+                .call(GetChildActorMessage::new(entity_id))
+                .await?
+                .context("container did not start")?;
+            container_actor.call(RefreshSnapshotsMessage).await?
+        } else {
+            bail!("no dataset or container with id {}", entity_id)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SnapshotDatasetMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: SnapshotDatasetMessage) -> Result<DateTime<Utc>> {
+        self.dataset_actor(msg.0)
+            .await?
+            .call(TakeSnapshotMessage {
+                pre_upgrade: false,
+                hold: false,
+            })
+            .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PreUpgradeSnapshotMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: PreUpgradeSnapshotMessage) -> Result<DateTime<Utc>> {
+        self.dataset_actor(msg.0)
+            .await?
+            .call(TakeSnapshotMessage {
+                pre_upgrade: true,
+                hold: false,
+            })
+            .await?
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<RollbackDatasetMessage> for CaptainActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: RollbackDatasetMessage) -> Result<RollbackResponse> {
+        let dataset = self
+            .entities
+            .dataset(msg.dataset_id)
+            .with_context(|| format!("no dataset with id {}", msg.dataset_id))?;
+        let (pool_id, dataset_id) = (dataset.parent.id(), msg.dataset_id);
+        let (pool_model, dataset_model) = (dataset.parent.clone(), dataset.entity.clone());
+        let quiesced_pool = {
+            let mut pool = pool_model.clone();
+            pool.datasets.retain(|d| d.id() != dataset_id);
+            pool
+        };
+        let snapshot = msg.snapshot;
+        // the snapshot must exist before the safety snapshot is taken
+        let rollback = tokio::task::spawn_blocking(move || {
+            let pool = Arc::new(BtrfsPool::validate(pool_model)?);
+            let dataset = Arc::new(BtrfsDataset::validate(&pool, dataset_model)?);
+            DatasetRollback::new(&dataset, snapshot)
+        })
+        .await
+        .context("preparing the rollback panicked")??;
+
+        let safety = self
+            .dataset_actor(dataset_id)
+            .await?
+            .call(TakeSnapshotMessage {
+                pre_upgrade: false,
+                hold: true,
+            })
+            .await?
+            .context("failed to take the safety snapshot")?;
+        let response = RollbackResponse {
+            safety_snapshot: safety,
+            replaced_path: rollback.replaced_path().to_owned(),
+            made_default: rollback.was_default(),
+        };
+        // the hold is kept even if the rollback fails below
+        tokio::task::spawn_blocking(move || {
+            let mut entities = storage::load_entity_config();
+            if let Some(dataset) = entity_by_id_mut(&mut entities.btrfs_pools, pool_id)
+                .and_then(|pool| entity_by_id_mut(&mut pool.datasets, dataset_id))
+            {
+                dataset.held_snapshots.push(safety);
+                dataset.held_snapshots.sort_unstable();
+            }
+            storage::store_entity_config(entities);
+        })
+        .await
+        .context("storing the safety snapshot hold panicked")?;
+
+        // Nothing may snapshot, send or prune the dataset while its subvolume is swapped. Its syncs and the dataset
+        // actor stop here, the reload below starts them again whether or not the rollback worked.
+        let result = self
+            .quiesce_dataset(dataset_id, quiesced_pool)
+            .await
+            .context("failed to stop the dataset's actors for the rollback");
+        let result = match result {
+            Ok(()) => tokio::task::spawn_blocking(move || {
+                let restored = rollback.run(safety)?;
+                let mut entities = storage::load_entity_config();
+                entities.relocate_dataset(restored.take_model(), pool_id)?;
+                storage::store_entity_config(entities);
+                Ok::<_, anyhow::Error>(())
+            })
+            .await
+            .context("rolling back panicked")
+            .and_then(|r| r),
+            Err(e) => Err(e),
+        };
+        log_result(ctx.log(), &ctx.address().send(ReloadMessage));
+        result?;
+
+        info!(
+            ctx.log(), "dataset rolled back";
+            "dataset_id" => %dataset_id, "snapshot" => %snapshot, "safety_snapshot" => %safety
+        );
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PruneDatasetMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: PruneDatasetMessage) -> Result<()> {
+        let dataset = self
+            .entities
+            .dataset(msg.0)
+            .with_context(|| format!("no dataset with id {}", msg.0))?;
+        if dataset.entity.snapshot_retention.is_none() {
+            bail!(
+                "dataset {} has no snapshot retention rules to prune by",
+                dataset.entity.name()
+            );
+        }
+        self.dataset_actor(msg.0).await?.send(PruneMessage)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PruneArchiveMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: PruneArchiveMessage) -> Result<()> {
+        let archive = self
+            .entities
+            .archive_containers
+            .iter()
+            .find(|a| a.id() == msg.0)
+            .with_context(|| format!("no archive container with id {}", msg.0))?;
+        if archive.snapshot_retention.is_none() {
+            bail!(
+                "archive container {} has no retention rules to collect streams by",
+                archive.name()
+            );
+        }
+        self.archive_actors
+            .get(&msg.0)
+            .with_context(|| format!("no running archive container with id {}", msg.0))?
+            .send(PruneMessage)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<RunSyncMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: RunSyncMessage) -> Result<()> {
+        self.sync_actors
+            .get(&msg.0)
+            .with_context(|| format!("no running sync with id {}", msg.0))?
+            .send(StartSnapshotSyncCycleMessage)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetDashboardMessage> for CaptainActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetDashboardMessage) -> DashboardState {
+        let entities = &self.entities;
+        let mut dashboard = DashboardState {
+            pools: entities
+                .btrfs_pools
+                .iter()
+                .map(|p| PoolOverview {
+                    id: p.id(),
+                    name: p.name().to_owned(),
+                    mountpoint: p.mountpoint_path.clone(),
+                    datasets: p.datasets.len(),
+                    containers: p.containers.len(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        for dataset in entities.datasets() {
+            let activity = match self.dataset_actor(dataset.id()).await {
+                Ok(actor) => actor.call(GetDatasetActivityMessage).await.ok(),
+                Err(_) => None,
+            };
+            dashboard.datasets.push(DatasetOverview {
+                id: dataset.id(),
+                path: dataset.path(),
+                activity,
+            });
+        }
+        for sync in entities.snapshot_syncs.iter() {
+            let activity = match self.sync_actors.get(&sync.id()) {
+                Some(actor) => actor.call(GetSyncActivityMessage).await.ok(),
+                None => None,
+            };
+            dashboard.syncs.push(SyncOverview {
+                id: sync.id(),
+                name: sync.name().to_owned(),
+                dataset_id: sync.dataset_id,
+                route: format!(
+                    "{} -> {}",
+                    entities
+                        .dataset(sync.dataset_id)
+                        .map_or_else(|| "missing".to_owned(), |d| d.path()),
+                    entities
+                        .any_container(sync.container_id)
+                        .map_or("missing", |c| c.name())
+                ),
+                activity,
+            });
+        }
+        dashboard
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<EmergencyMessage> for CaptainActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: EmergencyMessage) -> Result<EmergencyResponse> {
+        let entities = &self.entities;
+        let mut response = EmergencyResponse::default();
+        for dataset in entities.datasets().filter(|d| d.entity.critical) {
+            let dataset_id = dataset.entity.id();
+            let pool = match self.pool_actors.get(&dataset.parent.id()) {
+                Some(pool) => pool,
+                None => {
+                    warn!(ctx.log(), "critical dataset's pool did not start"; "dataset_id" => %dataset_id);
+                    continue;
+                }
+            };
+            let dataset_actor: Option<Addr<BcActor<DatasetActor>>> =
+                match pool.call(GetChildActorMessage::new(dataset_id)).await {
+                    Ok(actor) => actor,
+                    Err(e) => {
+                        warn!(ctx.log(), "dataset lookup failed"; "dataset_id" => %dataset_id, "error" => %e);
+                        continue;
+                    }
+                };
+            let dataset_actor = match dataset_actor {
+                Some(actor) => actor,
+                None => {
+                    warn!(ctx.log(), "critical dataset did not start"; "dataset_id" => %dataset_id);
+                    continue;
+                }
+            };
+            // Snapshot first, so the syncs started below pick it up. They still run when it fails, to get the
+            // latest snapshot there is off the machine.
+            let snapshot = dataset_actor
+                .call(EmergencySnapshotMessage)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result);
+            match snapshot {
+                Ok(Some(_)) => response.datasets += 1,
+                Ok(None) => {}
+                Err(e) => warn!(ctx.log(), "emergency snapshot failed"; "dataset_id" => %dataset_id, "error" => %e),
+            }
+
+            for sync in entities.snapshot_syncs.iter().filter(|s| s.dataset_id == dataset_id) {
+                match self.sync_actors.get(&sync.id()) {
+                    Some(sync_actor) if sync_actor.send(EmergencySyncMessage).is_ok() => response.syncs += 1,
+                    _ => warn!(ctx.log(), "emergency sync not started"; "sync_id" => %sync.id()),
+                }
+            }
+        }
+        info!(ctx.log(), "emergency snapshots taken"; "datasets" => response.datasets, "syncs" => response.syncs);
+        Ok(response)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ReloadMessage> for CaptainActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ReloadMessage) -> Result<()> {
+        // the running actors are left alone when the stored config can't be read
+        let entities = tokio::task::spawn_blocking(storage::try_load_entity_config)
+            .await
+            .context("loading the entity config panicked")?
+            .context("failed to read the entity config, keeping the running one")?;
+        let running = mem::take(&mut self.entities);
+        info!(ctx.log(), "reloading entity config");
+
+        // Entities whose actors start, and those whose actors stop or restart. Syncs depending on the latter restart.
+        let mut start = HashSet::new();
+        let mut stop = HashSet::new();
+        plan_restarts(
+            diff_entities(&running.observers, &entities.observers),
+            &mut start,
+            &mut stop,
+        );
+        plan_restarts(
+            diff_entities(&running.notifiers, &entities.notifiers),
+            &mut start,
+            &mut stop,
+        );
+        plan_restarts(
+            diff_entities(&running.restic_containers, &entities.restic_containers),
+            &mut start,
+            &mut stop,
+        );
+        plan_restarts(
+            diff_entities(&running.remote_containers, &entities.remote_containers),
+            &mut start,
+            &mut stop,
+        );
+        plan_restarts(
+            diff_entities(&running.archive_containers, &entities.archive_containers),
+            &mut start,
+            &mut stop,
+        );
+
+        let pools = diff_entities(&running.btrfs_pools, &entities.btrfs_pools);
+        start.extend(pools.added.iter().map(|m| m.id()));
+        stop.extend(pools.removed.iter().copied());
+
+        // Syncs hold on to the datasets and containers they use, so those that a pool update stops go first.
+        let pool_stopped = pools
+            .changed
+            .iter()
+            .filter_map(|&model| {
+                running
+                    .pool(model.id())
+                    .map(|current| stopped_by_update(current, model))
+            })
+            .flatten()
+            .collect::<HashSet<_>>();
+        let early_syncs = running
+            .snapshot_syncs
+            .iter()
+            .filter(|s| pool_stopped.contains(&s.dataset_id) || pool_stopped.contains(&s.container_id))
+            .map(|s| s.id())
+            .collect::<HashSet<_>>();
+        let stopped_early = stop_actors(&mut self.sync_actors, &early_syncs).await;
+        start.extend(early_syncs.iter().copied());
+        stop.extend(early_syncs);
+
+        for model in pools.changed {
+            let updated = match self.pool_actors.get(&model.id()) {
+                Some(actor) => actor.call(UpdatePoolMessage(model.clone())).await.and_then(|r| r),
+                None => Ok(None),
+            };
+            match updated {
+                Ok(Some(restarted)) => stop.extend(restarted),
+                Ok(None) => {
+                    start.insert(model.id());
+                    stop.insert(model.id());
+                }
+                Err(e) => {
+                    warn!(ctx.log(), "updating pool failed, restarting it"; "pool_id" => %model.id(), "error" => %e);
+                    start.insert(model.id());
+                    stop.insert(model.id());
+                }
+            }
+        }
+
+        let syncs = diff_entities(&running.snapshot_syncs, &entities.snapshot_syncs);
+        let added_syncs = syncs.added.iter().map(|m| m.id()).collect::<HashSet<_>>();
+        start.extend(added_syncs.iter().copied());
+        stop.extend(syncs.removed.iter().copied());
+        for sync in entities
+            .snapshot_syncs
+            .iter()
+            .filter(|s| !added_syncs.contains(&s.id()))
+        {
+            let depends_on_restarted = stop.contains(&sync.dataset_id)
+                || stop.contains(&sync.container_id)
+                || entities
+                    .sync_topology(sync)
+                    .map_or(true, |t| t.pools().iter().any(|p| stop.contains(p)));
+            let target_rpo = entities.dataset(sync.dataset_id).and_then(|d| d.entity.target_rpo);
+            let running_target_rpo = running.dataset(sync.dataset_id).and_then(|d| d.entity.target_rpo);
+            let changed = syncs.changed.iter().any(|s| s.id() == sync.id()) || target_rpo != running_target_rpo;
+
+            let updated = match (depends_on_restarted, changed, self.sync_actors.get(&sync.id())) {
+                (false, false, _) => true,
+                (false, true, Some(actor)) => {
+                    let update = UpdateSyncMessage {
+                        model: sync.clone(),
+                        target_rpo,
+                    };
+                    actor.call(update).await.and_then(|r| r).unwrap_or_else(|e| {
+                        warn!(ctx.log(), "updating sync failed, restarting it"; "sync_id" => %sync.id(), "error" => %e);
+                        false
+                    })
+                }
+                _ => false,
+            };
+            if !updated {
+                start.insert(sync.id());
+                stop.insert(sync.id());
+            }
+        }
+
+        // Entities that failed to start before are tried again.
+        start.extend(not_running(&self.healthcheck_actors, &entities.observers, &stop));
+        start.extend(not_running(&self.notification_actors, &entities.notifiers, &stop));
+        start.extend(not_running(&self.pool_actors, &entities.btrfs_pools, &stop));
+        start.extend(not_running(&self.restic_actors, &entities.restic_containers, &stop));
+        start.extend(not_running(&self.remote_actors, &entities.remote_containers, &stop));
+        start.extend(not_running(&self.archive_actors, &entities.archive_containers, &stop));
+        start.extend(not_running(&self.sync_actors, &entities.snapshot_syncs, &stop));
+
+        // Syncs hold on to the pools and containers they use, so they stop first.
+        let stopped = stopped_early
+            + stop_actors(&mut self.sync_actors, &stop).await
+            + stop_actors(&mut self.healthcheck_actors, &stop).await
+            + stop_actors(&mut self.notification_actors, &stop).await
+            + stop_actors(&mut self.restic_actors, &stop).await
+            + stop_actors(&mut self.remote_actors, &stop).await
+            + stop_actors(&mut self.archive_actors, &stop).await
+            + stop_actors(&mut self.pool_actors, &stop).await;
+        self.start_entity_actors(&ctx, &entities, &|id| start.contains(&id))
+            .await;
+        self.entities = entities;
+
+        info!(
+            ctx.log(),
+            "entity config reloaded";
+            "stopped" => stopped,
+            "started" => start.len(),
+            "pools" => self.pool_actors.len(),
+            "syncs" => self.sync_actors.len()
+        );
+        Ok(())
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for CaptainActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("idle")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::idle()
     }
 }
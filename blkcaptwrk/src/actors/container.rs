@@ -1,33 +1,49 @@
 use super::{
     localreceiver::{LocalReceiverActor, LocalReceiverStoppedMessage, LocalReceiverStoppedParentMessage},
-    observation::observable_func,
+    observation::{observable_func, start_observation, StartedObservation},
     pool::PoolActor,
 };
 use crate::{
-    actorbase::{log_result, unhandled_result, ScheduledMessage},
+    actorbase::{clock_defers_pruning, log_result, same_model, unhandled_error, unhandled_result, ScheduledMessage},
     snapshots::{
-        failed_snapshot_deletes_as_result, prune_btrfs_snapshots, ContainerSnapshotsResponse,
-        GetContainerSnapshotsMessage, PruneMessage,
+        clear_deleted, delete_snapshots, failed_snapshot_deletes_as_result, prune_btrfs_snapshots, reconcile_snapshots,
+        report_external_changes, ContainerSnapshotsResponse, DeferredJobs, GetContainerSnapshotsMessage, PruneMessage,
+        RefreshSnapshotsMessage, RunDeferredJobsMessage, ScheduledRefreshMessage, SNAPSHOT_REFRESH_INTERVAL,
     },
+    tasks::{WorkerCompleteMessage, WorkerTask},
+    watch::{SnapshotWatcher, SnapshotsChangedMessage},
     xactorext::{
-        join_all_actors, stop_all_actors, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage,
-        TerminalState,
+        join_all_actors, stop_all_actors, ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler,
+        GetActorStatusMessage, TerminalState,
     },
 };
-use anyhow::{Context as _, Result};
+use anyhow::{bail, Context as _, Result};
 use futures_util::future::ready;
 use libblkcapt::{
-    core::{BtrfsContainer, BtrfsContainerSnapshot, BtrfsPool},
-    core::{Snapshot, SnapshotHandle},
+    core::{
+        restore::RestoreTest,
+        verify::{verify_container_snapshots, ChecksumManifest, ContainerVerification},
+        BtrfsContainer, BtrfsContainerSnapshot, BtrfsPool,
+    },
+    core::{system::RefreshedSnapshotsResponse, BtrfsSnapshot, Snapshot, SnapshotHandle},
     model::entities::FeatureState,
     model::Entity,
     model::{
         entities::{BtrfsContainerEntity, ObservableEvent},
         EntityId,
     },
+    sys::{btrfs::StreamCompression, privilege::running_as_root},
+};
+use slog::{debug, info, o, trace, warn, Logger};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    iter::once,
+    mem,
+    path::PathBuf,
+    sync::Arc,
 };
-use slog::{debug, o, trace, Logger};
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use uuid::Uuid;
 use xactor::{message, Actor, Addr, Handler, Sender, WeakAddr};
 
 pub struct ContainerActor {
@@ -37,19 +53,61 @@ pub struct ContainerActor {
     prune_schedule: Option<ScheduledMessage>,
     active_receivers: HashMap<u64, ActiveReceiver>,
     faulted: bool,
+    watcher: Option<SnapshotWatcher>,
+    deferred: Option<DeferredJobs>,
+    verify_schedule: Option<ScheduledMessage>,
+    integrity: Option<(IntegrityJob, WorkerTask)>,
+    /// The snapshots the running integrity job reads, held from pruning and deletion until it completes.
+    integrity_reads: Vec<Uuid>,
+    manifest_queue: VecDeque<PendingManifest>,
+    verify_queued: bool,
+    restore_test_queue: VecDeque<RestoreTestMessage>,
 }
 
 pub struct ActiveReceiver {
     actor: WeakAddr<BcActor<LocalReceiverActor>>,
     dataset_id: EntityId,
+    source_snapshot: SnapshotHandle,
+    source_path: Option<PathBuf>,
+}
+
+/// Manifests are written, snapshots verified and restores tested one at a time, all read whole snapshots.
+enum IntegrityJob {
+    Manifest,
+    Verify(StartedObservation),
+    RestoreTest(EntityId, StartedObservation),
+}
+
+/// Checksums are taken from the source snapshot, so a copy that was corrupted on receive doesn't pass as intact.
+struct PendingManifest {
+    dataset_id: EntityId,
+    source: SnapshotHandle,
+    source_path: PathBuf,
+    snapshot: BtrfsContainerSnapshot,
+}
+
+#[message()]
+#[derive(Clone)]
+struct VerifyMessage;
+
+type IntegrityCompleteMessage = WorkerCompleteMessage<Result<()>>;
+
+/// Receive the latest snapshot from the dataset into scratch space, check it against its manifest and delete it
+/// again. The outcome is observed for the sync that asked.
+#[message()]
+pub struct RestoreTestMessage {
+    pub sync_id: EntityId,
+    pub dataset_id: EntityId,
 }
 
 #[message(result = "Result<()>")]
 pub struct GetSnapshotReceiverMessage {
-    source_dataset_id: EntityId,
-    source_snapshot_handle: SnapshotHandle,
-    target_ready: Sender<ReceiverReadyMessage>,
-    target_finished: Sender<LocalReceiverStoppedMessage>,
+    pub(super) source_dataset_id: EntityId,
+    pub(super) source_snapshot_handle: SnapshotHandle,
+    pub(super) target_ready: Sender<ReceiverReadyMessage>,
+    pub(super) target_finished: Sender<LocalReceiverStoppedMessage>,
+    pub(super) compression: Option<StreamCompression>,
+    pub(super) source_path: Option<PathBuf>,
 }
 
 impl GetSnapshotReceiverMessage {
@@ -64,13 +122,46 @@ impl GetSnapshotReceiverMessage {
             source_snapshot_handle,
             target_ready: requestor_addr.sender(),
             target_finished: requestor_addr.sender(),
+            compression: None,
+            source_path: None,
         }
     }
+
+    pub fn compressed(mut self, compression: Option<StreamCompression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Where the source snapshot is reachable locally, to take the checksum manifest from.
+    pub fn from_source(mut self, source_path: Option<PathBuf>) -> Self {
+        self.source_path = source_path;
+        self
+    }
 }
 
+/// Delete every snapshot received from a dataset. Returns the number deleted.
+#[message(result = "Result<usize>")]
+pub struct DeleteDatasetSnapshotsMessage(pub EntityId);
+
 #[message()]
 pub struct ReceiverReadyMessage(pub Result<Addr<BcActor<LocalReceiverActor>>>);
 
+/// Apply a reloaded model without restarting the actor, so receives in flight carry on. Returns false when the
+/// change needs a restart.
+#[message(result = "Result<bool>")]
+pub struct UpdateContainerMessage {
+    pub pool: Arc<BtrfsPool>,
+    pub model: BtrfsContainerEntity,
+}
+
+/// Fields read when needed rather than only when the actor starts.
+const LIVE_FIELDS: &[&str] = &["name", "snapshot_retention", "pause_pruning", "verify_schedule"];
+
+/// Whether a running container actor takes the change without restarting.
+pub fn updates_in_place(current: &BtrfsContainerEntity, new: &BtrfsContainerEntity) -> bool {
+    same_model(current, new, LIVE_FIELDS)
+}
+
 impl ContainerActor {
     pub fn new(
         pool_actor: Addr<BcActor<PoolActor>>, pool: &Arc<BtrfsPool>, model: BtrfsContainerEntity, log: &Logger,
@@ -91,11 +182,251 @@ impl ContainerActor {
                         prune_schedule: None,
                         active_receivers: Default::default(),
                         faulted: false,
+                        watcher: None,
+                        deferred: pool.model().wake_schedule.as_ref().map(|_| DeferredJobs::default()),
+                        verify_schedule: None,
+                        integrity: None,
+                        integrity_reads: Vec::new(),
+                        manifest_queue: Default::default(),
+                        verify_queued: false,
+                        restore_test_queue: Default::default(),
                     },
                     &log.new(o!("container_id" => id.to_string())),
-                ))
+                )
+                .observe_lifecycle(id, ObservableEvent::ContainerWorker))
             })
     }
+
+    /// Schedules the prune and verify jobs of the model, replacing any scheduled before.
+    fn schedule_jobs(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
+        self.prune_schedule = None;
+        if self.container.model().pruning_state() == FeatureState::Enabled {
+            self.prune_schedule = self
+                .container
+                .model()
+                .snapshot_retention
+                .as_ref()
+                .map(|r| &r.evaluation_schedule)
+                .map_or(Ok(None), |s| {
+                    s.try_into()
+                        .map(|schedule| Some(ScheduledMessage::new(schedule, "prune", PruneMessage, ctx)))
+                })?;
+        }
+
+        self.verify_schedule = self.container.model().verify_schedule.as_ref().map_or(Ok(None), |s| {
+            s.try_into()
+                .map(|schedule| Some(ScheduledMessage::new(schedule, "verify", VerifyMessage, ctx)))
+        })?;
+        Ok(())
+    }
+
+    async fn prune(&mut self, log: &Logger) {
+        let newest = self
+            .snapshots
+            .values()
+            .filter_map(|s| s.last())
+            .map(|s| s.datetime())
+            .max();
+        if clock_defers_pruning(newest, log) {
+            return;
+        }
+        let result = observable_func(self.container.model().id(), ObservableEvent::ContainerPrune, || {
+            let rules = self
+                .container
+                .model()
+                .snapshot_retention
+                .as_ref()
+                .expect("retention exist based on message scheduling in started");
+
+            let holds = self.integrity_holds();
+            let failed_deletes = self.snapshots.iter_mut().fold(0, |acc, (dataset_id, snapshots)| {
+                trace!(log, "prune container"; "dataset_id" => %dataset_id);
+                acc + prune_btrfs_snapshots(snapshots, &holds, rules, log)
+            });
+            ready(failed_snapshot_deletes_as_result(failed_deletes))
+        })
+        .await;
+
+        unhandled_result(log, result);
+    }
+
+    /// Snapshots the running integrity job reads, and those of queued manifests. Queued verifications and restore
+    /// tests pick their snapshots when they start.
+    fn integrity_holds(&self) -> Vec<Uuid> {
+        self.integrity_reads
+            .iter()
+            .copied()
+            .chain(self.manifest_queue.iter().map(|m| m.snapshot.uuid()))
+            .collect()
+    }
+
+    /// Start the next queued manifest, or a queued verification or restore test once all manifests are written.
+    async fn run_integrity_jobs(&mut self, ctx: &BcContext<'_, Self>) {
+        if self.integrity.is_some() {
+            return;
+        }
+        let container = Arc::clone(&self.container);
+        if let Some(pending) = self.manifest_queue.pop_front() {
+            self.integrity_reads = vec![pending.snapshot.uuid()];
+            let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+                run_blocking(move || {
+                    let path = ChecksumManifest::path(&container, pending.dataset_id, pending.snapshot.uuid());
+                    ChecksumManifest::generate(&pending.source, &pending.source_path)?.store(&path)
+                })
+                .await
+                .into()
+            });
+            self.integrity = Some((IntegrityJob::Manifest, task));
+        } else if mem::take(&mut self.verify_queued) {
+            let observation = start_observation(self.container.model().id(), ObservableEvent::ContainerVerify).await;
+            let snapshots = self
+                .snapshots
+                .iter()
+                .map(|(&dataset_id, snapshots)| (dataset_id, snapshots.clone()))
+                .collect::<Vec<_>>();
+            self.integrity_reads = snapshots.iter().flat_map(|(_, s)| s.iter().map(|s| s.uuid())).collect();
+            let log = ctx.log().clone();
+            let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+                run_blocking(move || verify_container(&container, &snapshots, &log))
+                    .await
+                    .into()
+            });
+            self.integrity = Some((IntegrityJob::Verify(observation), task));
+        } else {
+            while let Some(request) = self.restore_test_queue.pop_front() {
+                let observation = start_observation(request.sync_id, ObservableEvent::SnapshotSyncRestoreTest).await;
+                let snapshot = match self.snapshots.get(&request.dataset_id).and_then(|s| s.last()) {
+                    Some(snapshot) => snapshot.clone(),
+                    None => {
+                        observation.failed("container has no snapshots from the dataset");
+                        continue;
+                    }
+                };
+                let manifest_path = ChecksumManifest::path(&container, request.dataset_id, snapshot.uuid());
+                self.integrity_reads = vec![snapshot.uuid()];
+                let log = ctx.log().clone();
+                let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+                    test_restore(&snapshot, manifest_path, &log).await.into()
+                });
+                self.integrity = Some((IntegrityJob::RestoreTest(request.sync_id, observation), task));
+                break;
+            }
+        }
+    }
+
+    /// Clean up after receives into a dataset that never got sealed. Must not run while receiving for the dataset.
+    fn recover_receives(&mut self, dataset_id: EntityId, log: &Logger) {
+        match self.container.recover_receives(dataset_id) {
+            Ok(recovered) => {
+                if !recovered.sealed.is_empty() || recovered.discarded > 0 {
+                    info!(log, "recovered interrupted receives"; "dataset_id" => %dataset_id, "sealed" => recovered.sealed.len(), "discarded" => recovered.discarded);
+                }
+                let snapshots = self.snapshots.entry(dataset_id).or_default();
+                snapshots.extend(recovered.sealed);
+                snapshots.sort_unstable_by_key(|s| s.datetime());
+            }
+            Err(e) => warn!(log, "failed to recover interrupted receives: {:#}", e; "dataset_id" => %dataset_id),
+        }
+    }
+
+    /// The container directory may not exist yet, so the scheduled refresh retries until the watch is in place.
+    fn start_watcher(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
+        let watched_paths = once(self.container.local_path())
+            .chain(
+                self.snapshots
+                    .keys()
+                    .map(|&id| self.container.local_path().join(id.to_string())),
+            )
+            .collect();
+        self.watcher = Some(SnapshotWatcher::start(&ctx.address(), watched_paths, true, ctx.log())?);
+        Ok(())
+    }
+
+    /// Snapshots from datasets that are currently being received into are left alone, a partial receive is not
+    /// a snapshot yet.
+    fn refresh_snapshots(&mut self, log: &Logger) -> Result<RefreshedSnapshotsResponse> {
+        let receiving = self
+            .active_receivers
+            .values()
+            .map(|r| r.dataset_id)
+            .collect::<HashSet<_>>();
+        let source_ids = self.container.source_dataset_ids()?;
+
+        let mut changes = RefreshedSnapshotsResponse::default();
+        for &source_id in source_ids.iter().filter(|id| !receiving.contains(id)) {
+            let found = self.container.snapshots(source_id)?;
+            let source_changes = reconcile_snapshots(self.snapshots.entry(source_id).or_default(), found);
+            changes.added += source_changes.added;
+            changes.removed += source_changes.removed;
+        }
+        let vanished = self
+            .snapshots
+            .keys()
+            .filter(|id| !source_ids.contains(id) && !receiving.contains(id))
+            .copied()
+            .collect::<Vec<_>>();
+        for source_id in vanished {
+            if let Some(snapshots) = self.snapshots.remove(&source_id) {
+                changes.removed += snapshots.len();
+            }
+        }
+
+        if !changes.is_empty() {
+            info!(log, "snapshots changed outside the worker"; "added" => changes.added, "removed" => changes.removed);
+        }
+        Ok(changes)
+    }
+}
+
+async fn run_blocking(func: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+    tokio::task::spawn_blocking(func)
+        .await
+        .context("integrity task panicked")?
+}
+
+async fn test_restore(snapshot: &BtrfsContainerSnapshot, manifest_path: PathBuf, log: &Logger) -> Result<()> {
+    let restored = RestoreTest::receive(snapshot).await?;
+    let log = log.clone();
+    run_blocking(move || {
+        let manifest = ChecksumManifest::load(&manifest_path);
+        let verified = manifest.and_then(|m| restored.verify(m.as_ref()).map(|v| (m.is_some(), v)));
+        restored.discard()?;
+        let (had_manifest, verification) = verified?;
+        if !verification.is_intact() {
+            bail!(
+                "restored copy has {} changed and {} missing files",
+                verification.mismatched.len(),
+                verification.missing.len()
+            );
+        }
+        info!(log, "restore test passed"; "files" => verification.checked.len(), "manifest" => had_manifest);
+        Ok(())
+    })
+    .await
+}
+
+fn verify_container(
+    container: &BtrfsContainer, snapshots: &[(EntityId, Vec<BtrfsContainerSnapshot>)], log: &Logger,
+) -> Result<()> {
+    let mut verification = ContainerVerification::default();
+    for (dataset_id, snapshots) in snapshots {
+        verify_container_snapshots(container, *dataset_id, snapshots, &mut verification)?;
+    }
+    info!(log, "verified container snapshots"; "verified" => verification.verified, "unverified" => verification.unverified, "failed" => verification.failures.len(), "removed_manifests" => verification.removed_manifests);
+
+    if !verification.is_intact() {
+        bail!(
+            "{} snapshots failed verification: {}",
+            verification.failures.len(),
+            verification
+                .failures
+                .iter()
+                .map(|(uuid, reason)| format!("{}: {}", uuid, reason))
+                .collect::<Vec<_>>()
+                .join("; ")
+        );
+    }
+    Ok(())
 }
 
 #[async_trait::async_trait]
@@ -108,23 +439,40 @@ impl BcActorCtrl for ContainerActor {
             self.snapshots.len()
         );
 
-        if self.container.model().pruning_state() == FeatureState::Enabled {
-            self.prune_schedule = self
-                .container
-                .model()
-                .snapshot_retention
-                .as_ref()
-                .map(|r| &r.evaluation_schedule)
-                .map_or(Ok(None), |s| {
-                    s.try_into()
-                        .map(|schedule| Some(ScheduledMessage::new(schedule, "prune", PruneMessage, &ctx)))
-                })?;
+        let dataset_ids = self.snapshots.keys().copied().collect::<Vec<_>>();
+        for dataset_id in dataset_ids {
+            self.recover_receives(dataset_id, ctx.log());
+        }
+
+        if running_as_root() {
+            match self.container.sync_qgroup() {
+                Ok(assigned) if assigned > 0 => info!(ctx.log(), "assigned {} subvolumes to the quota group", assigned),
+                Ok(_) => {}
+                Err(e) => warn!(ctx.log(), "failed to maintain the container quota group: {:#}", e),
+            }
+        }
+
+        self.schedule_jobs(&ctx)?;
+
+        ctx.send_interval(ScheduledRefreshMessage, SNAPSHOT_REFRESH_INTERVAL);
+        if let Err(e) = self.start_watcher(&ctx) {
+            warn!(ctx.log(), "not watching for external snapshot changes yet"; "error" => %e);
         }
 
         Ok(())
     }
 
     async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
+        self.watcher = None;
+        if let Some((job, task)) = self.integrity.take() {
+            task.abort();
+            match job {
+                IntegrityJob::Verify(observation) | IntegrityJob::RestoreTest(_, observation) => {
+                    observation.cancelled()
+                }
+                IntegrityJob::Manifest => {}
+            }
+        }
         if self.faulted {
             return TerminalState::Faulted;
         }
@@ -149,15 +497,10 @@ impl BcHandler<GetContainerSnapshotsMessage> for ContainerActor {
     async fn handle(
         &mut self, _ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
     ) -> ContainerSnapshotsResponse {
-        ContainerSnapshotsResponse {
-            snapshots: self
-                .snapshots
-                .entry(msg.source_dataset_id)
-                .or_default()
-                .iter()
-                .map(|s| s.into())
-                .collect(),
-        }
+        let (snapshots, total) = msg
+            .query
+            .select(self.snapshots.entry(msg.source_dataset_id).or_default().iter());
+        ContainerSnapshotsResponse { snapshots, total }
     }
 }
 
@@ -176,7 +519,10 @@ impl BcHandler<GetSnapshotReceiverMessage> for ContainerActor {
             )
         }
 
-        let snapshot_receiver = self.container.receive(msg.source_dataset_id)?;
+        let snapshot_receiver = self
+            .container
+            .receive(msg.source_dataset_id, msg.source_snapshot_handle.datetime)?
+            .compressed(msg.compression);
         let started_receiver_actor = LocalReceiverActor::new(
             ctx.address().sender(),
             msg.target_finished,
@@ -192,6 +538,8 @@ impl BcHandler<GetSnapshotReceiverMessage> for ContainerActor {
                 ActiveReceiver {
                     actor: addr.downgrade(),
                     dataset_id: msg.source_dataset_id,
+                    source_snapshot: msg.source_snapshot_handle,
+                    source_path: msg.source_path,
                 },
             );
         } else {
@@ -224,11 +572,32 @@ impl BcHandler<LocalReceiverStoppedParentMessage> for ContainerActor {
             if let Ok(new_snapshot) = sealed_snapshot {
                 debug!(ctx.log(), "container received snapshot {}", new_snapshot.datetime(); "received_uuid" => %new_snapshot.received_uuid());
 
+                match (&self.verify_schedule, active_receiver.source_path) {
+                    (Some(_), Some(source_path)) => {
+                        self.manifest_queue.push_back(PendingManifest {
+                            dataset_id: active_receiver.dataset_id,
+                            source: active_receiver.source_snapshot,
+                            source_path,
+                            snapshot: new_snapshot.clone(),
+                        });
+                        self.run_integrity_jobs(&ctx).await;
+                    }
+                    (Some(_), None) => {
+                        debug!(ctx.log(), "source snapshot is gone, no manifest kept"; "uuid" => %new_snapshot.uuid())
+                    }
+                    (None, _) => {}
+                }
                 self.snapshots
                     .entry(active_receiver.dataset_id)
                     .or_default()
                     .push(new_snapshot);
             }
+        } else if !self
+            .active_receivers
+            .values()
+            .any(|r| r.dataset_id == active_receiver.dataset_id)
+        {
+            self.recover_receives(active_receiver.dataset_id, ctx.log());
         }
     }
 }
@@ -236,33 +605,179 @@ impl BcHandler<LocalReceiverStoppedParentMessage> for ContainerActor {
 #[async_trait::async_trait]
 impl BcHandler<PruneMessage> for ContainerActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
-        let result = observable_func(self.container.model().id(), ObservableEvent::ContainerPrune, || {
-            let rules = self
-                .container
-                .model()
-                .snapshot_retention
-                .as_ref()
-                .expect("retention exist based on message scheduling in started");
+        if let Some(deferred) = &mut self.deferred {
+            debug!(ctx.log(), "prune deferred to the pool's wake window");
+            deferred.prune = true;
+            return;
+        }
+        self.prune(ctx.log()).await;
+    }
+}
 
-            let failed_deletes = self.snapshots.iter_mut().fold(0, |acc, (dataset_id, snapshots)| {
-                trace!(ctx.log(), "prune container"; "dataset_id" => %dataset_id);
-                acc + prune_btrfs_snapshots(snapshots, &[], rules, ctx.log())
-            });
-            ready(failed_snapshot_deletes_as_result(failed_deletes))
-        })
-        .await;
+#[async_trait::async_trait]
+impl BcHandler<VerifyMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: VerifyMessage) {
+        if matches!(self.integrity, Some((IntegrityJob::Verify(_), _))) || self.verify_queued {
+            debug!(ctx.log(), "verify already running");
+            return;
+        }
+        self.verify_queued = true;
+        self.run_integrity_jobs(&ctx).await;
+    }
+}
 
+#[async_trait::async_trait]
+impl BcHandler<RestoreTestMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: RestoreTestMessage) {
+        let running =
+            matches!(self.integrity, Some((IntegrityJob::RestoreTest(sync_id, _), _)) if sync_id == msg.sync_id);
+        if running || self.restore_test_queue.iter().any(|r| r.sync_id == msg.sync_id) {
+            debug!(ctx.log(), "restore test already pending"; "sync_id" => %msg.sync_id);
+            return;
+        }
+        self.restore_test_queue.push_back(msg);
+        self.run_integrity_jobs(&ctx).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<IntegrityCompleteMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: IntegrityCompleteMessage) {
+        self.integrity_reads.clear();
+        match self.integrity.take() {
+            Some((IntegrityJob::Manifest, _)) => {
+                log_result(ctx.log(), &msg.0.context("failed to write checksum manifest"));
+            }
+            Some((IntegrityJob::Verify(observation), _)) | Some((IntegrityJob::RestoreTest(_, observation), _)) => {
+                observation.result(&msg.0)
+            }
+            None => {
+                self.faulted = true;
+                ctx.stop(None);
+                return;
+            }
+        }
+        self.run_integrity_jobs(&ctx).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<DeleteDatasetSnapshotsMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: DeleteDatasetSnapshotsMessage) -> Result<usize> {
+        let dataset_id = msg.0;
+        if self.active_receivers.values().any(|r| r.dataset_id == dataset_id) {
+            bail!("a snapshot from dataset {} is being received", dataset_id);
+        }
+        if self.restore_test_queue.iter().any(|r| r.dataset_id == dataset_id) {
+            bail!("a restore test of dataset {} is queued", dataset_id);
+        }
+
+        let holds = self.integrity_holds();
+        let snapshots = self
+            .snapshots
+            .get_mut(&dataset_id)
+            .with_context(|| format!("container has no snapshots from dataset {}", dataset_id))?;
+        if snapshots.iter().any(|s| holds.contains(&s.uuid())) {
+            bail!(
+                "a snapshot from dataset {} is being verified or restore tested",
+                dataset_id
+            );
+        }
+        info!(ctx.log(), "deleting all snapshots from dataset"; "dataset_id" => %dataset_id, "count" => snapshots.len());
+        let deleted = delete_snapshots(&snapshots.iter().collect::<Vec<_>>(), None, ctx.log());
+        let deleted_count = deleted.len();
+        clear_deleted(snapshots, deleted);
+        failed_snapshot_deletes_as_result(snapshots.len())?;
+        Ok(deleted_count)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<RefreshSnapshotsMessage> for ContainerActor {
+    async fn handle(
+        &mut self, ctx: BcContext<'_, Self>, _msg: RefreshSnapshotsMessage,
+    ) -> Result<RefreshedSnapshotsResponse> {
+        self.refresh_snapshots(ctx.log())
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ScheduledRefreshMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ScheduledRefreshMessage) {
+        if self.watcher.is_none() && self.start_watcher(&ctx).is_ok() {
+            info!(ctx.log(), "watching for external snapshot changes");
+        }
+        if let Some(deferred) = &mut self.deferred {
+            deferred.refresh = true;
+            return;
+        }
+        let result = self.refresh_snapshots(ctx.log());
         unhandled_result(ctx.log(), result);
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<RunDeferredJobsMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: RunDeferredJobsMessage) {
+        let due = match &mut self.deferred {
+            Some(deferred) => deferred.take(),
+            None => return,
+        };
+        if due.refresh {
+            unhandled_result(ctx.log(), self.refresh_snapshots(ctx.log()).map(|_| ()));
+        }
+        if due.prune {
+            self.prune(ctx.log()).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SnapshotsChangedMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SnapshotsChangedMessage) {
+        match self.refresh_snapshots(ctx.log()) {
+            Ok(changes) => {
+                report_external_changes(
+                    self.container.model().id(),
+                    ObservableEvent::ContainerExternalChange,
+                    changes,
+                    ctx.log(),
+                )
+                .await
+            }
+            Err(e) => unhandled_error(ctx.log(), e),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<UpdateContainerMessage> for ContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: UpdateContainerMessage) -> Result<bool> {
+        if !updates_in_place(self.container.model(), &msg.model) {
+            return Ok(false);
+        }
+        self.container = BtrfsContainer::validate(&msg.pool, msg.model).map(Arc::new)?;
+        self.schedule_jobs(&ctx)?;
+        info!(ctx.log(), "container updated in place");
+        Ok(true)
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for ContainerActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        if self.active_receivers.is_empty() {
-            String::from("idle")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        let status = if self.faulted {
+            ActorStatus::faulted("container faulted")
+        } else if self.active_receivers.is_empty() {
+            match &self.integrity {
+                Some((IntegrityJob::Verify(_), _)) => ActorStatus::active("verifying snapshots"),
+                Some((IntegrityJob::Manifest, _)) => ActorStatus::active("writing checksum manifests"),
+                Some((IntegrityJob::RestoreTest(..), _)) => ActorStatus::active("testing a restore"),
+                None => ActorStatus::idle(),
+            }
         } else {
-            String::from("active")
-        }
+            ActorStatus::active(format!("receiving {} snapshots", self.active_receivers.len()))
+        };
+        status.with_queue_depth(self.deferred.as_ref().map_or(0, DeferredJobs::count))
     }
 }
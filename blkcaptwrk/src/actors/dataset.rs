@@ -1,30 +1,49 @@
 use super::{
     localsender::{LocalSenderActor, LocalSenderFinishedMessage, LocalSenderParentFinishedMessage},
-    observation::observable_func,
-    pool::PoolActor,
+    observation::{observable_func, start_observation, StartedObservation},
+    pool::{DefragJob, PoolActor, PoolDefragMessage},
 };
 use crate::{
-    actorbase::unhandled_result,
+    actorbase::{clock_defers_pruning, clock_problem, same_model, unhandled_result},
     xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler},
 };
 use crate::{
     actorbase::{unhandled_error, ScheduledMessage},
     snapshots::PruneMessage,
-    snapshots::{failed_snapshot_deletes_as_result, prune_btrfs_snapshots},
-    xactorext::{join_all_actors, stop_all_actors, BoxBcWeakAddr, GetActorStatusMessage, TerminalState},
+    snapshots::{
+        emergency_prune_btrfs_snapshots, failed_snapshot_deletes_as_result, prune_btrfs_snapshots,
+        prune_pre_upgrade_snapshots, reconcile_snapshots, report_external_changes, DeferredJobs,
+        RefreshSnapshotsMessage, RunDeferredJobsMessage, ScheduledRefreshMessage, SnapshotQuery,
+        SNAPSHOT_REFRESH_INTERVAL,
+    },
+    tasks::{WorkerCompleteMessage, WorkerTask},
+    watch::{SnapshotWatcher, SnapshotsChangedMessage},
+    xactorext::{join_all_actors, stop_all_actors, ActorStatus, BoxBcWeakAddr, GetActorStatusMessage, TerminalState},
 };
-use anyhow::{Context as AnyhowContext, Result};
+use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
+use chrono::{DateTime, Local, Utc};
 use futures_util::future::ready;
 use libblkcapt::{
-    core::{BtrfsDataset, BtrfsDatasetSnapshot, BtrfsPool, BtrfsSnapshot},
-    core::{Snapshot, SnapshotHandle},
+    core::{
+        bootmenu::update_boot_menu,
+        system::{DatasetActivity, JobOutcome, RefreshedSnapshotsResponse},
+        Snapshot, SnapshotHandle,
+    },
+    core::{BtrfsDataset, BtrfsDatasetSnapshot, BtrfsPool, BtrfsSnapshot, PoolNearlyFullError},
     model::entities::BtrfsDatasetEntity,
-    model::entities::FeatureState,
     model::entities::ObservableEvent,
-    model::Entity,
+    model::entities::{FeatureState, HookFailurePolicy},
+    model::entities::{SnapshotQuota, SnapshotQuotaAction, SyncBacklogAction, SyncBacklogLimit},
+    model::{storage, Entity, EntityId},
+    sys::{
+        btrfs::{StreamCompression, SubvolumeProperties},
+        power::{boot_id, uptime, ResumeDetector},
+        privilege::running_as_root,
+        process::run_shell_hook,
+    },
 };
-use slog::{info, o, Logger};
-use std::{convert::TryInto, iter::once, path::PathBuf, sync::Arc};
+use slog::{debug, info, o, warn, Logger};
+use std::{collections::HashMap, convert::TryInto, iter::once, path::PathBuf, sync::Arc, time::Duration};
 use uuid::Uuid;
 use xactor::{message, Actor, Addr, Handler, Sender};
 
@@ -34,26 +53,137 @@ pub struct DatasetActor {
     snapshots: Vec<BtrfsDatasetSnapshot>,
     snapshot_schedule: Option<ScheduledMessage>,
     prune_schedule: Option<ScheduledMessage>,
+    defrag_schedule: Option<ScheduledMessage>,
     active_sends_holds: Vec<(BoxBcWeakAddr, Uuid, Option<Uuid>)>,
+    watcher: Option<SnapshotWatcher>,
+    sync_positions: HashMap<EntityId, Option<DateTime<Utc>>>,
+    deferred: Option<DeferredJobs>,
+    resume_detector: Option<ResumeDetector>,
+    last_prune: Option<JobOutcome>,
+    /// Pre-upgrade snapshots taken on request that the model doesn't list yet. blkcaptctl stores the tag and reloads
+    /// the worker after the snapshot is taken, a prune in between must already treat it as pre-upgrade.
+    pending_pre_upgrade: Vec<DateTime<Utc>>,
+    /// Snapshots held on request that the model doesn't list yet.
+    pending_holds: Vec<DateTime<Utc>>,
+    quota_recheck: Option<QuotaRecheck>,
 }
 
+/// A quota check waiting for the space of the snapshots an early prune deleted to be freed.
+struct QuotaRecheck {
+    _task: WorkerTask,
+    observation: StartedObservation,
+    quota: SnapshotQuota,
+    exceeded: String,
+}
+
+type QuotaWorkerCompleteMessage = WorkerCompleteMessage<Result<Option<u64>>>;
+
+const RESUME_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 #[message()]
 #[derive(Clone)]
 struct SnapshotMessage;
 
+/// The snapshot after boot, for the boot with this id.
+#[message()]
+struct BootSnapshotMessage(String);
+
+#[message()]
+#[derive(Clone)]
+struct ResumeCheckMessage;
+
+/// Take a snapshot now, even if nothing changed since the latest one. Returns when it was taken.
+#[message(result = "Result<DateTime<Utc>>")]
+pub struct TakeSnapshotMessage {
+    pub pre_upgrade: bool,
+    /// Hold the snapshot from pruning right away, before the hold reaches the model with a reload.
+    pub hold: bool,
+}
+
+/// Take a snapshot now, even while jobs are held for the pool's wake window, the sync backlog is over its limit or
+/// nothing changed. Sent when the power is about to go. Returns the snapshot taken.
+#[message(result = "Result<Option<BtrfsDatasetSnapshot>>")]
+pub struct EmergencySnapshotMessage;
+
+#[message()]
+#[derive(Clone)]
+struct DefragMessage;
+
+/// Apply a reloaded model without restarting the actor, so sends in flight carry on. Returns false when the change
+/// needs a restart.
+#[message(result = "Result<bool>")]
+pub struct UpdateDatasetMessage {
+    pub pool: Arc<BtrfsPool>,
+    pub model: BtrfsDatasetEntity,
+}
+
+/// Fields read when needed rather than only when the actor starts.
+const LIVE_FIELDS: &[&str] = &[
+    "name",
+    "snapshot_schedule",
+    "pause_snapshotting",
+    "snapshot_retention",
+    "pause_pruning",
+    "restore_divergence",
+    "target_rpo",
+    "skip_unchanged",
+    "emergency_prune",
+    "critical",
+    "sync_backlog",
+    "snapshot_quota",
+    "defrag",
+    "held_snapshots",
+    "snapshot_hooks",
+    "pre_upgrade_snapshots",
+    "pre_upgrade_retention",
+    "boot_menu",
+];
+
+/// Whether a running dataset actor takes the change without restarting.
+pub fn updates_in_place(current: &BtrfsDatasetEntity, new: &BtrfsDatasetEntity) -> bool {
+    same_model(current, new, LIVE_FIELDS)
+}
+
+/// The newest snapshot and the latest prune, for the dashboard.
+#[message(result = "DatasetActivity")]
+pub struct GetDatasetActivityMessage;
+
+/// Sent by a sync with the time of the newest snapshot its target holds.
+#[message()]
+pub struct SyncPositionMessage {
+    pub sync_id: EntityId,
+    pub newest_synced: Option<DateTime<Utc>>,
+}
+
+/// Sent by a sync when it stops, so a deleted sync no longer holds back the backlog. It reports its position again
+/// if it starts back up.
+#[message()]
+pub struct SyncStoppedMessage {
+    pub sync_id: EntityId,
+}
+
 #[message(result = "DatasetSnapshotsResponse")]
-pub struct GetDatasetSnapshotsMessage;
+pub struct GetDatasetSnapshotsMessage(pub SnapshotQuery);
 
 pub struct DatasetSnapshotsResponse {
     pub snapshots: Vec<SnapshotHandle>,
+    /// Number of snapshots matching the query, before paging.
+    pub total: usize,
+    pub pre_restore: Vec<Uuid>,
 }
 
+/// Where a snapshot of the dataset is reachable in the local filesystem. None once it was pruned.
+#[message(result = "Option<PathBuf>")]
+pub struct GetSnapshotPathMessage(pub Uuid);
+
 #[message(result = "Result<()>")]
 pub struct GetSnapshotSenderMessage {
     pub send_snapshot_handle: SnapshotHandle,
     pub parent_snapshot_handle: Option<SnapshotHandle>,
     pub target_ready: Sender<SenderReadyMessage>,
     pub target_finished: Sender<LocalSenderFinishedMessage>,
+    pub compression: Option<StreamCompression>,
+    pub bandwidth_limit: Option<u64>,
 }
 
 impl GetSnapshotSenderMessage {
@@ -68,8 +198,20 @@ impl GetSnapshotSenderMessage {
             parent_snapshot_handle,
             target_ready: requestor_addr.sender(),
             target_finished: requestor_addr.sender(),
+            compression: None,
+            bandwidth_limit: None,
         }
     }
+
+    pub fn compressed(mut self, compression: Option<StreamCompression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn throttled(mut self, bandwidth_limit: Option<u64>) -> Self {
+        self.bandwidth_limit = bandwidth_limit;
+        self
+    }
 }
 
 #[message()]
@@ -117,25 +259,57 @@ impl DatasetActor {
                     dataset,
                     snapshot_schedule: None,
                     prune_schedule: None,
+                    defrag_schedule: None,
                     active_sends_holds: Default::default(),
+                    watcher: None,
+                    sync_positions: Default::default(),
+                    deferred: pool.model().wake_schedule.as_ref().map(|_| DeferredJobs::default()),
+                    resume_detector: None,
+                    last_prune: None,
+                    pending_pre_upgrade: Vec::new(),
+                    pending_holds: Vec::new(),
+                    quota_recheck: None,
                 },
                 &log.new(o!("dataset_id" => id.to_string())),
-            ))
+            )
+            .observe_lifecycle(id, ObservableEvent::DatasetWorker))
         })
     }
-}
 
-#[async_trait::async_trait]
-impl BcActorCtrl for DatasetActor {
-    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
-        if self.dataset.model().snapshotting_state() == FeatureState::Enabled {
+    /// Schedules the snapshot, prune and defrag jobs of the model, replacing any scheduled before. Returns whether
+    /// the dataset can be snapshotted.
+    fn schedule_jobs(&mut self, ctx: &BcContext<'_, Self>) -> Result<bool> {
+        let (can_snapshot, can_prune) = if running_as_root() {
+            (true, true)
+        } else {
+            let access = self.dataset.unprivileged_access()?;
+            if !access.create_snapshots && self.dataset.model().snapshotting_state() == FeatureState::Enabled {
+                warn!(
+                    ctx.log(),
+                    "snapshotting disabled. without root the dataset and its snapshot directory must be owned by \
+                    the worker user"
+                );
+            }
+            if !access.delete_snapshots && self.dataset.model().pruning_state() == FeatureState::Enabled {
+                warn!(
+                    ctx.log(),
+                    "pruning disabled. without root the pool must be mounted with user_subvol_rm_allowed"
+                );
+            }
+            (access.create_snapshots, access.delete_snapshots)
+        };
+        let can_snapshot = self.dataset.model().snapshotting_state() == FeatureState::Enabled && can_snapshot;
+
+        self.snapshot_schedule = None;
+        if can_snapshot {
             self.snapshot_schedule = self.dataset.model().snapshot_schedule.as_ref().map_or(Ok(None), |s| {
                 s.try_into()
-                    .map(|schedule| Some(ScheduledMessage::new(schedule, "snapshot", SnapshotMessage, &ctx)))
+                    .map(|schedule| Some(ScheduledMessage::new(schedule, "snapshot", SnapshotMessage, ctx)))
             })?;
         }
 
-        if self.dataset.model().pruning_state() == FeatureState::Enabled {
+        self.prune_schedule = None;
+        if self.dataset.model().pruning_state() == FeatureState::Enabled && can_prune {
             self.prune_schedule = self
                 .dataset
                 .model()
@@ -144,14 +318,424 @@ impl BcActorCtrl for DatasetActor {
                 .map(|r| &r.evaluation_schedule)
                 .map_or(Ok(None), |s| {
                     s.try_into()
-                        .map(|schedule| Some(ScheduledMessage::new(schedule, "prune", PruneMessage, &ctx)))
+                        .map(|schedule| Some(ScheduledMessage::new(schedule, "prune", PruneMessage, ctx)))
                 })?;
         }
 
+        self.defrag_schedule = None;
+        if self.dataset.model().defrag_state() == FeatureState::Enabled && !running_as_root() {
+            warn!(ctx.log(), "defrag disabled. defrag requires root");
+        } else if self.dataset.model().defrag_state() == FeatureState::Enabled {
+            self.defrag_schedule = self.dataset.model().defrag.as_ref().map_or(Ok(None), |d| {
+                (&d.schedule)
+                    .try_into()
+                    .map(|schedule| Some(ScheduledMessage::new(schedule, "defrag", DefragMessage, ctx)))
+            })?;
+        }
+
+        Ok(can_snapshot)
+    }
+
+    /// The id of the current boot, when the dataset wasn't snapshot after it yet.
+    fn unsnapshot_boot(&self) -> Result<Option<String>> {
+        let boot_id = boot_id()?;
+        let snapshot_boot = storage::load_boot_snapshot(self.dataset.model().id())?;
+        Ok(Some(boot_id).filter(|id| *id != snapshot_boot))
+    }
+
+    async fn maybe_create_snapshot(&mut self, log: &Logger) -> Result<Option<BtrfsDatasetSnapshot>> {
+        if self.dataset.model().skip_unchanged {
+            if let Some(latest) = self.snapshots.last() {
+                if !self.dataset.changed_since(latest)? {
+                    return Ok(None);
+                }
+            }
+        }
+        self.create_snapshot_with_hooks(log).await.map(Some)
+    }
+
+    async fn create_snapshot_with_hooks(&mut self, log: &Logger) -> Result<BtrfsDatasetSnapshot> {
+        if let Some(problem) = clock_problem(self.snapshots.last().map(|s| s.datetime()), log) {
+            warn!(log, "snapshot taken with an untrusted clock"; "reason" => %problem);
+        }
+        let hooks = match self.dataset.model().snapshot_hooks.clone() {
+            Some(hooks) if hooks.pre.is_some() || hooks.post.is_some() => hooks,
+            _ => return self.create_snapshot(log).await,
+        };
+        let mut failures = Vec::new();
+        let pre_result = match &hooks.pre {
+            Some(command) => self.run_snapshot_hook("pre", command, hooks.timeout(), None).await,
+            None => Ok(()),
+        };
+        let result = match pre_result {
+            Err(e) if hooks.on_failure == HookFailurePolicy::Abort => {
+                Err(e.context("snapshot skipped, pre-snapshot hook failed"))
+            }
+            pre_result => {
+                if let Err(e) = pre_result {
+                    warn!(log, "pre-snapshot hook failed, taking the snapshot anyway"; "error" => %e);
+                    failures.push(format!("pre-snapshot hook: {:#}", e));
+                }
+                self.create_snapshot(log).await
+            }
+        };
+        if let Some(command) = &hooks.post {
+            let snapshot = result.as_ref().ok().map(|s| s.datetime().to_rfc3339());
+            if let Err(e) = self
+                .run_snapshot_hook("post", command, hooks.timeout(), snapshot.as_deref())
+                .await
+            {
+                warn!(log, "post-snapshot hook failed"; "error" => %e);
+                failures.push(format!("post-snapshot hook: {:#}", e));
+            }
+        }
+
+        let observation = start_observation(self.dataset.model().id(), ObservableEvent::DatasetSnapshotHook).await;
+        match failures.is_empty() {
+            true => observation.succeeded(),
+            false => observation.failed(failures.join("; ")),
+        }
+        result
+    }
+
+    /// Run a snapshot hook command. It gets the dataset and, after a snapshot was taken, the snapshot in its
+    /// environment.
+    async fn run_snapshot_hook(
+        &self, stage: &str, command: &str, timeout: Duration, snapshot: Option<&str>,
+    ) -> Result<()> {
+        let model = self.dataset.model();
+        let mut env = vec![("BLKCAPT_HOOK", stage), ("BLKCAPT_DATASET", model.name())];
+        if let Some(snapshot) = snapshot {
+            env.push(("BLKCAPT_SNAPSHOT", snapshot));
+        }
+        run_shell_hook(command, &env, timeout)
+            .await
+            .with_context(|| format!("{}-snapshot hook '{}' failed", stage, command))
+    }
+
+    async fn create_snapshot(&mut self, log: &Logger) -> Result<BtrfsDatasetSnapshot> {
+        let mut result = self.dataset.create_local_snapshot();
+        if matches!(&result, Err(e) if e.is::<PoolNearlyFullError>()) && self.reclaim_trash(log).await? {
+            result = self.dataset.create_local_snapshot();
+        }
+
+        match result {
+            Err(e) if e.is::<PoolNearlyFullError>() && self.dataset.model().emergency_prune => {
+                let rules = match self.dataset.model().snapshot_retention.as_ref() {
+                    Some(rules) => rules,
+                    None => return Err(e.context("emergency prune requires a retention ruleset")),
+                };
+                warn!(log, "pool nearly full, running emergency prune"; "error" => %e);
+                let holds = self.holds();
+                let failed_deletes = emergency_prune_btrfs_snapshots(&mut self.snapshots, &holds, rules, log);
+                failed_snapshot_deletes_as_result(failed_deletes)?;
+                self.sync_snapshot_deletes().await?;
+                self.dataset.create_local_snapshot()
+            }
+            result => result,
+        }
+    }
+
+    /// Empty the pool's trash to make room. Returns whether anything was deleted.
+    async fn reclaim_trash(&self, log: &Logger) -> Result<bool> {
+        let deleted = self.dataset.pool().empty_trash(None)?;
+        if deleted == 0 {
+            return Ok(false);
+        }
+        warn!(log, "pool nearly full, emptied trash"; "deleted" => deleted);
+        self.sync_snapshot_deletes().await?;
+        Ok(true)
+    }
+
+    /// Wait until the filesystem freed the space of deleted snapshots. That can take minutes, so it waits on a
+    /// blocking thread rather than one of the runtime's.
+    async fn sync_snapshot_deletes(&self) -> Result<()> {
+        let dataset = self.dataset.clone();
+        tokio::task::spawn_blocking(move || dataset.sync_snapshot_deletes()).await?
+    }
+
+    async fn snapshot(&mut self, ctx: &BcContext<'_, Self>) {
+        let log = ctx.log();
+        let limit = self.dataset.model().sync_backlog.clone();
+        if let Some((limit, (snapshots, bytes))) = limit.zip(self.sync_backlog(log)) {
+            let observation = start_observation(self.dataset.model().id(), ObservableEvent::DatasetSyncBacklog).await;
+            match limit.exceeded(snapshots, bytes) {
+                Some(reason) => {
+                    let allowed = self.sync_backlog_allows_snapshot(&limit, &reason, log);
+                    observation.failed(reason);
+                    if !allowed {
+                        info!(log, "snapshot skipped, sync backlog over limit");
+                        return;
+                    }
+                }
+                None => observation.succeeded(),
+            }
+        }
+
+        let result = observable_func(self.dataset.model().id(), ObservableEvent::DatasetSnapshot, || {
+            self.maybe_create_snapshot(log)
+        })
+        .await;
+        match result {
+            Ok(Some(snapshot)) => {
+                info!(log, "snapshot created"; "time" => %snapshot.datetime());
+                self.snapshots.push(snapshot);
+                self.update_boot_menu(log);
+                self.check_quota(ctx).await;
+            }
+            Ok(None) => {
+                info!(log, "snapshot skipped, dataset unchanged since latest snapshot");
+            }
+            Err(e) => {
+                unhandled_error(log, e);
+            }
+        }
+    }
+
+    async fn prune(&mut self, log: &Logger) {
+        if clock_defers_pruning(self.snapshots.last().map(|s| s.datetime()), log) {
+            return;
+        }
+        let result = observable_func(self.dataset.model().id(), ObservableEvent::DatasetPrune, || {
+            let rules = match self.dataset.model().snapshot_retention.as_ref() {
+                Some(rules) => rules,
+                None => return ready(Err(anyhow!("dataset has no snapshot retention rules"))),
+            };
+
+            let holds = self.holds();
+            let model = self.dataset.model();
+            let pending = &self.pending_pre_upgrade;
+            let (mut pre_upgrade, mut regular): (Vec<_>, Vec<_>) = self
+                .snapshots
+                .drain(..)
+                .partition(|s| model.pre_upgrade_snapshots.contains(&s.datetime()) || pending.contains(&s.datetime()));
+            let failed_deletes = prune_btrfs_snapshots(&mut regular, &holds, rules, log)
+                + prune_pre_upgrade_snapshots(
+                    &mut pre_upgrade,
+                    &holds,
+                    &model.pre_upgrade_retention,
+                    rules.trash_period,
+                    log,
+                );
+            regular.append(&mut pre_upgrade);
+            regular.sort_unstable_by_key(|s| s.datetime());
+            self.snapshots = regular;
+            ready(failed_snapshot_deletes_as_result(failed_deletes))
+        })
+        .await;
+
+        self.last_prune = Some(JobOutcome::now(result.is_ok()));
+        self.update_boot_menu(log);
+        unhandled_result(log, result);
+    }
+
+    fn update_boot_menu(&self, log: &Logger) {
+        if !running_as_root() {
+            return;
+        }
+        if let Err(e) = update_boot_menu(&self.dataset, &self.snapshots) {
+            warn!(log, "failed to update the boot menu: {:#}", e);
+        }
+    }
+
+    /// Compare the space referenced by the dataset's quota group against its snapshot quota.
+    async fn check_quota(&mut self, ctx: &BcContext<'_, Self>) {
+        let log = ctx.log();
+        let quota = match self.dataset.model().snapshot_quota.clone() {
+            Some(quota) => quota,
+            None => return,
+        };
+        if self.quota_recheck.is_some() {
+            debug!(
+                log,
+                "quota check skipped, the previous one is still waiting for deletes"
+            );
+            return;
+        }
+        let referenced = match self.dataset.qgroup_referenced() {
+            Ok(Some(referenced)) => referenced,
+            Ok(None) => {
+                warn!(log, "snapshot quota ignored, the dataset has no quota group");
+                return;
+            }
+            Err(e) => {
+                warn!(log, "failed to read quota group usage"; "error" => %e);
+                return;
+            }
+        };
+
+        let observation = start_observation(self.dataset.model().id(), ObservableEvent::DatasetQuota).await;
+        let exceeded = match quota.exceeded(referenced) {
+            Some(exceeded) => exceeded,
+            None => return observation.succeeded(),
+        };
+        warn!(log, "dataset over snapshot quota"; "usage" => &exceeded);
+        if quota.action != SnapshotQuotaAction::Prune || self.prune_schedule.is_none() {
+            return observation.failed(exceeded);
+        }
+
+        info!(log, "running retention early to get back under quota");
+        self.prune(log).await;
+        // the space of deleted snapshots is only freed once the filesystem cleaned them up, which can take a while
+        let dataset = self.dataset.clone();
+        let task = WorkerTask::run(ctx.address(), log, |_| async move {
+            tokio::task::spawn_blocking(move || {
+                dataset.sync_snapshot_deletes()?;
+                dataset.qgroup_referenced()
+            })
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r)
+            .into()
+        });
+        self.quota_recheck = Some(QuotaRecheck {
+            _task: task,
+            observation,
+            quota,
+            exceeded,
+        });
+    }
+
+    /// Hand the defrag job to the pool, which runs it once no scrub or transfer is using the disks.
+    fn defrag(&self, log: &Logger) {
+        let defrag = match &self.dataset.model().defrag {
+            Some(defrag) => defrag,
+            None => return,
+        };
+        let job = DefragJob {
+            dataset_id: self.dataset.model().id(),
+            paths: self.dataset.defrag_paths(),
+            compress: defrag.compress,
+        };
+        unhandled_result(log, self.pool.send(PoolDefragMessage(job)));
+    }
+
+    /// The snapshot directory may not exist yet, so the scheduled refresh retries until the watch is in place.
+    fn start_watcher(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
+        self.watcher = Some(SnapshotWatcher::start(
+            &ctx.address(),
+            vec![self.dataset.snapshot_container_local_path()],
+            false,
+            ctx.log(),
+        )?);
+        Ok(())
+    }
+
+    fn refresh_snapshots(&mut self, log: &Logger) -> Result<RefreshedSnapshotsResponse> {
+        let changes = reconcile_snapshots(&mut self.snapshots, self.dataset.snapshots()?);
+        if !changes.is_empty() {
+            info!(log, "snapshots changed outside the worker"; "added" => changes.added, "removed" => changes.removed);
+        }
+        Ok(changes)
+    }
+
+    /// Count and estimated size of the snapshots some sync target doesn't have yet. None without syncs.
+    fn sync_backlog(&self, log: &Logger) -> Option<(usize, Option<u64>)> {
+        let synced_through = self.sync_positions.values().min()?;
+        let (synced, unsynced): (Vec<_>, Vec<_>) = self
+            .snapshots
+            .iter()
+            .partition(|s| Some(s.datetime()) <= *synced_through);
+
+        let bytes = match synced.last() {
+            Some(base) => self.dataset.changed_bytes_since(base),
+            None => unsynced.last().map_or(Ok(0), |s| s.size()),
+        };
+        let bytes = bytes
+            .map_err(|e| warn!(log, "failed to estimate sync backlog size"; "error" => %e))
+            .ok();
+        Some((unsynced.len(), bytes))
+    }
+
+    /// Check the sync backlog against its limit and decide whether the next scheduled snapshot is taken.
+    fn sync_backlog_allows_snapshot(&self, limit: &SyncBacklogLimit, reason: &str, log: &Logger) -> bool {
+        warn!(log, "sync backlog over limit"; "backlog" => reason);
+        match limit.action {
+            SyncBacklogAction::Alert => true,
+            SyncBacklogAction::Pause => false,
+            SyncBacklogAction::Slow(interval) => self.snapshots.last().map_or(true, |latest| {
+                (Utc::now() - latest.datetime())
+                    .to_std()
+                    .map_or(false, |age| age >= interval)
+            }),
+        }
+    }
+
+    fn holds(&self) -> Vec<Uuid> {
+        let user_holds = self.user_holds();
+        self.active_sends_holds
+            .iter()
+            .flat_map(|a| once(a.1).chain(a.2.into_iter()))
+            .chain(
+                self.snapshots
+                    .iter()
+                    .filter(|s| user_holds.contains(&s.datetime()) || self.pending_holds.contains(&s.datetime()))
+                    .map(|s| s.uuid()),
+            )
+            .collect()
+    }
+
+    /// Snapshots held with `blkcaptctl snapshot hold`, which reloads the worker so the model has them.
+    fn user_holds(&self) -> &[DateTime<Utc>] {
+        &self.dataset.model().held_snapshots
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for DatasetActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        if self.dataset.model().properties.checksums_disabled() {
+            warn!(ctx.log(), "{}", SubvolumeProperties::CHECKSUMS_DISABLED_WARNING);
+        }
+
+        if running_as_root() {
+            match self.dataset.sync_qgroup() {
+                Ok(assigned) if assigned > 0 => info!(ctx.log(), "assigned {} subvolumes to the quota group", assigned),
+                Ok(_) => {}
+                Err(e) => warn!(ctx.log(), "failed to maintain the dataset quota group: {:#}", e),
+            }
+        }
+
+        let can_snapshot = self.schedule_jobs(&ctx)?;
+        if can_snapshot {
+            if let Some(delay) = self.dataset.model().snapshot_after_wake {
+                // The worker also starts when it's restarted or reloaded, only the first start after a boot counts.
+                match self.unsnapshot_boot() {
+                    Ok(Some(boot_id)) => {
+                        let uptime = uptime().unwrap_or_default();
+                        let remaining = Duration::from_secs(delay.saturating_sub(uptime).as_secs());
+                        info!(
+                            ctx.log(),
+                            "snapshot after boot in {}",
+                            humantime::format_duration(remaining)
+                        );
+                        ctx.send_later(BootSnapshotMessage(boot_id), remaining);
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!(ctx.log(), "boot unknown, no snapshot after boot"; "error" => %e),
+                }
+                self.resume_detector = ResumeDetector::new()
+                    .map_err(|e| warn!(ctx.log(), "resume from suspend won't be detected"; "error" => %e))
+                    .ok();
+                ctx.send_interval(ResumeCheckMessage, RESUME_CHECK_INTERVAL);
+            }
+        }
+
+        if self.dataset.model().boot_menu.is_some() && !running_as_root() {
+            warn!(ctx.log(), "boot menu disabled. writing boot entries requires root");
+        }
+        self.update_boot_menu(ctx.log());
+
+        ctx.send_interval(ScheduledRefreshMessage, SNAPSHOT_REFRESH_INTERVAL);
+        if let Err(e) = self.start_watcher(&ctx) {
+            warn!(ctx.log(), "not watching for external snapshot changes yet"; "error" => %e);
+        }
+
         Ok(())
     }
 
     async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
+        self.watcher = None;
         let mut active_actors = self
             .active_sends_holds
             .drain(..)
@@ -170,18 +754,131 @@ impl BcActorCtrl for DatasetActor {
 #[async_trait::async_trait]
 impl BcHandler<SnapshotMessage> for DatasetActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SnapshotMessage) {
-        let result = observable_func(self.dataset.model().id(), ObservableEvent::DatasetSnapshot, || {
-            ready(self.dataset.create_local_snapshot())
-        })
-        .await;
-        match result {
-            Ok(snapshot) => {
-                info!(ctx.log(), "snapshot created"; "time" => %snapshot.datetime());
-                self.snapshots.push(snapshot);
-            }
+        if let Some(deferred) = &mut self.deferred {
+            debug!(ctx.log(), "snapshot deferred to the pool's wake window");
+            deferred.snapshot = true;
+            return;
+        }
+        self.snapshot(&ctx).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<TakeSnapshotMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: TakeSnapshotMessage) -> Result<DateTime<Utc>> {
+        let log = ctx.log();
+        let id = self.dataset.model().id();
+        let observation = start_observation(id, ObservableEvent::DatasetSnapshot).await;
+        let result = self.create_snapshot_with_hooks(log).await;
+        observation.result(&result);
+        let snapshot = result?;
+        let datetime = snapshot.datetime();
+        info!(log, "snapshot created on request"; "time" => %datetime, "pre_upgrade" => msg.pre_upgrade);
+        if msg.pre_upgrade {
+            self.pending_pre_upgrade.push(datetime);
+        }
+        if msg.hold {
+            self.pending_holds.push(datetime);
+        }
+        self.snapshots.push(snapshot);
+        self.update_boot_menu(log);
+        self.check_quota(&ctx).await;
+        Ok(datetime)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<UpdateDatasetMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: UpdateDatasetMessage) -> Result<bool> {
+        if !updates_in_place(self.dataset.model(), &msg.model) {
+            return Ok(false);
+        }
+        self.dataset = BtrfsDataset::validate(&msg.pool, msg.model).map(Arc::new)?;
+        let tagged = &self.dataset.model().pre_upgrade_snapshots;
+        self.pending_pre_upgrade.retain(|d| !tagged.contains(d));
+        let held = &self.dataset.model().held_snapshots;
+        self.pending_holds.retain(|d| !held.contains(d));
+        self.schedule_jobs(&ctx)?;
+        self.update_boot_menu(ctx.log());
+        info!(ctx.log(), "dataset updated in place");
+        Ok(true)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<EmergencySnapshotMessage> for DatasetActor {
+    async fn handle(
+        &mut self, ctx: BcContext<'_, Self>, _msg: EmergencySnapshotMessage,
+    ) -> Result<Option<BtrfsDatasetSnapshot>> {
+        let log = ctx.log();
+        let observation = start_observation(self.dataset.model().id(), ObservableEvent::DatasetSnapshot).await;
+        let result = self.create_snapshot_with_hooks(log).await;
+        observation.result(&result);
+        let snapshot = result?;
+        info!(log, "emergency snapshot created"; "time" => %snapshot.datetime());
+        self.snapshots.push(snapshot.clone());
+        self.update_boot_menu(log);
+        self.check_quota(&ctx).await;
+        Ok(Some(snapshot))
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<QuotaWorkerCompleteMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: QuotaWorkerCompleteMessage) {
+        let recheck = match self.quota_recheck.take() {
+            Some(recheck) => recheck,
+            None => return,
+        };
+        let exceeded = match msg.0 {
+            Ok(referenced) => referenced.and_then(|r| recheck.quota.exceeded(r)),
             Err(e) => {
-                unhandled_error(ctx.log(), e);
+                warn!(ctx.log(), "failed to read quota group usage after pruning"; "error" => %e);
+                Some(recheck.exceeded)
             }
+        };
+        match exceeded {
+            Some(reason) => recheck.observation.failed(reason),
+            None => recheck.observation.succeeded(),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<BootSnapshotMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: BootSnapshotMessage) {
+        match &mut self.deferred {
+            Some(deferred) => {
+                debug!(ctx.log(), "snapshot after boot deferred to the pool's wake window");
+                deferred.snapshot = true;
+            }
+            None => self.snapshot(&ctx).await,
+        }
+        if let Err(e) = storage::store_boot_snapshot(self.dataset.model().id(), &msg.0) {
+            warn!(ctx.log(), "failed to record the snapshot after boot"; "error" => %e);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ResumeCheckMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ResumeCheckMessage) {
+        let (detector, delay) = match (&mut self.resume_detector, self.dataset.model().snapshot_after_wake) {
+            (Some(detector), Some(delay)) => (detector, delay),
+            _ => return,
+        };
+        match detector.check() {
+            Ok(Some(suspended)) => {
+                info!(
+                    ctx.log(),
+                    "resumed after {} suspended. snapshot in {}",
+                    humantime::format_duration(Duration::from_secs(suspended.as_secs())),
+                    humantime::format_duration(delay)
+                );
+                ctx.send_later(SnapshotMessage, delay);
+            }
+            Ok(None) => {}
+            Err(e) => debug!(ctx.log(), "resume check failed"; "error" => %e),
         }
     }
 }
@@ -189,39 +886,132 @@ impl BcHandler<SnapshotMessage> for DatasetActor {
 #[async_trait::async_trait]
 impl BcHandler<PruneMessage> for DatasetActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
-        let result = observable_func(self.dataset.model().id(), ObservableEvent::DatasetPrune, || {
-            let rules = self
-                .dataset
-                .model()
-                .snapshot_retention
-                .as_ref()
-                .expect("retention exist based on message scheduling in started");
+        if let Some(deferred) = &mut self.deferred {
+            debug!(ctx.log(), "prune deferred to the pool's wake window");
+            deferred.prune = true;
+            return;
+        }
+        self.prune(ctx.log()).await;
+    }
+}
 
-            let holds: Vec<_> = self
-                .active_sends_holds
-                .iter()
-                .flat_map(|a| once(a.1).chain(a.2.into_iter()))
-                .collect();
-            let failed_deletes = prune_btrfs_snapshots(&mut self.snapshots, &holds, rules, ctx.log());
-            ready(failed_snapshot_deletes_as_result(failed_deletes))
-        })
-        .await;
+#[async_trait::async_trait]
+impl BcHandler<DefragMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: DefragMessage) {
+        if let Some(deferred) = &mut self.deferred {
+            debug!(ctx.log(), "defrag deferred to the pool's wake window");
+            deferred.defrag = true;
+            return;
+        }
+        self.defrag(ctx.log());
+    }
+}
 
+#[async_trait::async_trait]
+impl BcHandler<RefreshSnapshotsMessage> for DatasetActor {
+    async fn handle(
+        &mut self, ctx: BcContext<'_, Self>, _msg: RefreshSnapshotsMessage,
+    ) -> Result<RefreshedSnapshotsResponse> {
+        self.refresh_snapshots(ctx.log())
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ScheduledRefreshMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ScheduledRefreshMessage) {
+        if self.watcher.is_none() && self.start_watcher(&ctx).is_ok() {
+            info!(ctx.log(), "watching for external snapshot changes");
+        }
+        if let Some(deferred) = &mut self.deferred {
+            deferred.refresh = true;
+            return;
+        }
+        let result = self.refresh_snapshots(ctx.log());
         unhandled_result(ctx.log(), result);
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<SnapshotsChangedMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: SnapshotsChangedMessage) {
+        match self.refresh_snapshots(ctx.log()) {
+            Ok(changes) => {
+                report_external_changes(
+                    self.dataset.model().id(),
+                    ObservableEvent::DatasetExternalChange,
+                    changes,
+                    ctx.log(),
+                )
+                .await
+            }
+            Err(e) => unhandled_error(ctx.log(), e),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<RunDeferredJobsMessage> for DatasetActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: RunDeferredJobsMessage) {
+        let due = match &mut self.deferred {
+            Some(deferred) => deferred.take(),
+            None => return,
+        };
+        if due.refresh {
+            unhandled_result(ctx.log(), self.refresh_snapshots(ctx.log()).map(|_| ()));
+        }
+        if due.snapshot {
+            self.snapshot(&ctx).await;
+        }
+        if due.prune {
+            self.prune(ctx.log()).await;
+        }
+        if due.defrag {
+            self.defrag(ctx.log());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SyncPositionMessage> for DatasetActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: SyncPositionMessage) {
+        self.sync_positions.insert(msg.sync_id, msg.newest_synced);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<SyncStoppedMessage> for DatasetActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: SyncStoppedMessage) {
+        self.sync_positions.remove(&msg.sync_id);
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetDatasetSnapshotsMessage> for DatasetActor {
-    async fn handle(
-        &mut self, _ctx: BcContext<'_, Self>, _msg: GetDatasetSnapshotsMessage,
-    ) -> DatasetSnapshotsResponse {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetDatasetSnapshotsMessage) -> DatasetSnapshotsResponse {
+        let (snapshots, total) = msg.0.select(self.snapshots.iter());
         DatasetSnapshotsResponse {
-            snapshots: self.snapshots.iter().map(|s| s.into()).collect(),
+            snapshots,
+            total,
+            pre_restore: self
+                .snapshots
+                .iter()
+                .filter(|s| s.is_pre_restore())
+                .map(|s| s.uuid())
+                .collect(),
         }
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotPathMessage> for DatasetActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetSnapshotPathMessage) -> Option<PathBuf> {
+        self.snapshots
+            .iter()
+            .find(|s| s.uuid() == msg.0)
+            .map(|s| s.local_path())
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetSnapshotSenderMessage> for DatasetActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotSenderMessage) -> Result<()> {
@@ -240,11 +1030,37 @@ impl BcHandler<GetSnapshotSenderMessage> for DatasetActor {
             None => None,
         };
 
-        let snapshot_sender = send_snapshot.send(parent_snapshot);
+        if let Some(parent) = parent_snapshot {
+            if parent.is_pre_restore() {
+                bail!(
+                    "parent snapshot {} was taken before the dataset was restored and can't be used for an incremental send",
+                    parent
+                );
+            }
+        }
+
+        // only a full, uncompressed send has a size that can be known up front
+        let expected_size = match (parent_snapshot, msg.compression) {
+            (Some(_), _) | (_, Some(_)) => None,
+            (None, None) => send_snapshot
+                .size()
+                .map_err(|e| warn!(ctx.log(), "unable to determine size of snapshot"; "error" => %e))
+                .ok(),
+        };
+
+        // the sync's own limit and the worker's bandwidth schedule are applied by the one throttle on the stream
+        let stream_limit = msg.bandwidth_limit;
+        let snapshot_sender = send_snapshot
+            .send(parent_snapshot)
+            .compressed(msg.compression)
+            .throttled(Box::new(move || {
+                storage::worker_config().stream_bandwidth_limit(stream_limit, Local::now().time())
+            }));
         let started_sender_actor = LocalSenderActor::new(
             ctx.address().sender(),
             msg.target_finished,
             snapshot_sender,
+            expected_size,
             &ctx.log().new(o!("message" => ())),
         )
         .start()
@@ -307,14 +1123,25 @@ impl BcHandler<LocalSenderParentFinishedMessage> for DatasetActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<GetDatasetActivityMessage> for DatasetActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetDatasetActivityMessage) -> DatasetActivity {
+        DatasetActivity {
+            latest_snapshot: self.snapshots.last().map(|s| s.datetime()),
+            last_prune: self.last_prune,
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for DatasetActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        if self.active_sends_holds.is_empty() {
-            String::from("idle")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        let status = if self.active_sends_holds.is_empty() {
+            ActorStatus::idle()
         } else {
-            String::from("active")
-        }
+            ActorStatus::active(format!("sending {} snapshots", self.active_sends_holds.len()))
+        };
+        status.with_queue_depth(self.deferred.as_ref().map_or(0, DeferredJobs::count))
     }
 }
 
@@ -348,7 +1175,7 @@ impl BcActorCtrl for DatasetHolderActor {
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for DatasetHolderActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("holding")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::active("holding snapshot")
     }
 }
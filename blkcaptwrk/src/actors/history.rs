@@ -0,0 +1,91 @@
+use super::observation::ObservableEventMessage;
+use crate::{
+    actorbase::unhandled_result,
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use libblkcapt::{
+    core::ObservableEventStage,
+    model::{
+        entities::ObservableEvent,
+        history::{JobKind, JobRecord},
+        storage, EntityId,
+    },
+};
+use slog::{warn, Logger};
+use std::collections::HashMap;
+
+/// Records the outcome of every observed job in the history store.
+pub struct HistoryActor {
+    started: HashMap<(EntityId, ObservableEvent), DateTime<Utc>>,
+}
+
+impl HistoryActor {
+    pub fn new(log: &Logger) -> BcActor<Self> {
+        BcActor::new(
+            Self {
+                started: Default::default(),
+            },
+            log,
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for HistoryActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        ctx.subscribe::<ObservableEventMessage>().await
+    }
+
+    async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+        let _ = ctx.unsubscribe::<ObservableEventMessage>().await;
+        TerminalState::Succeeded
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ObservableEventMessage> for HistoryActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ObservableEventMessage) {
+        let kind = match JobKind::from_event(msg.event) {
+            Some(kind) => kind,
+            None => return,
+        };
+
+        let key = (msg.source, msg.event);
+        let error = match msg.stage {
+            ObservableEventStage::Starting => {
+                self.started.insert(key, Utc::now());
+                return;
+            }
+            ObservableEventStage::Succeeded => None,
+            ObservableEventStage::Failed(message) => Some(message),
+            ObservableEventStage::Warning(_) => None,
+        };
+
+        let started = match self.started.remove(&key) {
+            Some(started) => started,
+            None => {
+                warn!(ctx.log(), "job finished without a recorded start"; "entity_id" => %msg.source, "observable_event" => %msg.event);
+                return;
+            }
+        };
+
+        let record = JobRecord {
+            kind,
+            entity_id: msg.source,
+            started,
+            duration: (Utc::now() - started).to_std().unwrap_or_default(),
+            bytes: None,
+            error,
+        };
+        unhandled_result(ctx.log(), storage::append_history(&record));
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for HistoryActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::idle()
+    }
+}
@@ -1,11 +1,12 @@
-use crate::xactorext::{BcActor, BcActorCtrl, BoxBcWeakAddr, TerminalState};
+use crate::xactorext::{BcActor, BcActorCtrl, BoxBcWeakAddr, LastActivity, TerminalState};
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use futures_util::{
     future::BoxFuture,
     future::FutureExt,
     stream::{FuturesUnordered, StreamExt},
 };
-use libblkcapt::core::system;
+use libblkcapt::{core::system, model::EntityId};
 use once_cell::sync::OnceCell;
 use slog::{error, trace, warn, Logger};
 use std::{
@@ -20,7 +21,7 @@ pub struct IntelActor {
 }
 
 #[message]
-pub struct ActorStartMessage(u64, BoxBcWeakAddr);
+pub struct ActorStartMessage(u64, BoxBcWeakAddr, Option<EntityId>, LastActivity);
 
 #[derive(Clone)]
 enum ActorState {
@@ -31,8 +32,10 @@ enum ActorState {
 }
 
 impl ActorStartMessage {
-    pub fn new<T: BcActorCtrl>(actor_id: u64, actor_address: Addr<BcActor<T>>) -> Self {
-        Self(actor_id, actor_address.into())
+    pub fn new<T: BcActorCtrl>(
+        actor_id: u64, actor_address: Addr<BcActor<T>>, entity_id: Option<EntityId>, last_activity: LastActivity,
+    ) -> Self {
+        Self(actor_id, actor_address.into(), entity_id, last_activity)
     }
 }
 
@@ -88,6 +91,9 @@ struct Tractor {
     state: ActorState,
     terminal_state: Option<TerminalState>,
     changed: Instant,
+    started: DateTime<Utc>,
+    entity_id: Option<EntityId>,
+    last_activity: LastActivity,
 }
 
 impl Tractor {
@@ -101,7 +107,7 @@ impl Tractor {
 struct Update;
 
 #[message(result = "BoxFuture<'static, system::SystemState>")]
-pub struct GetStateMessage;
+pub struct GetStateMessage(pub system::StatusQuery);
 
 #[async_trait::async_trait]
 impl Actor for IntelActor {
@@ -134,6 +140,9 @@ impl Handler<ActorStartMessage> for IntelActor {
                 state: ActorState::Started,
                 terminal_state: None,
                 changed: Instant::now(),
+                started: Utc::now(),
+                entity_id: msg.2,
+                last_activity: msg.3,
             },
         );
     }
@@ -188,10 +197,25 @@ impl Handler<Update> for IntelActor {
 #[async_trait::async_trait]
 impl Handler<GetStateMessage> for IntelActor {
     async fn handle(
-        &mut self, _ctx: &mut Context<Self>, _msg: GetStateMessage,
+        &mut self, _ctx: &mut Context<Self>, msg: GetStateMessage,
     ) -> BoxFuture<'static, system::SystemState> {
-        self.actors
-            .clone()
+        let query = msg.0;
+        let mut selected = self
+            .actors
+            .iter()
+            .filter(|(_, tractor)| {
+                query
+                    .actor_type
+                    .as_ref()
+                    .map_or(true, |t| tractor.actor.actor_type().eq_ignore_ascii_case(t))
+            })
+            .map(|(id, tractor)| (*id, tractor.clone()))
+            .collect::<Vec<_>>();
+        selected.sort_by_key(|(id, _)| *id);
+        // Only the actors in the requested page are asked for their status.
+        let (selected, total) = query.page().apply(selected);
+
+        selected
             .into_iter()
             .map(|(id, tractor)| async move {
                 system::SystemActor {
@@ -202,7 +226,7 @@ impl Handler<GetStateMessage> for IntelActor {
                                 Some(actor) => match tokio::time::timeout(Duration::from_secs(3), actor.status()).await
                                 {
                                     Ok(status_result) => match status_result {
-                                        Ok(data) => system::ActiveState::Custom(data),
+                                        Ok(status) => system::ActiveState::Running(status),
                                         Err(_) => system::ActiveState::Stopping,
                                     },
                                     Err(_) => system::ActiveState::Unresponsive,
@@ -216,11 +240,14 @@ impl Handler<GetStateMessage> for IntelActor {
                         ActorState::Zombie => system::ActorState::Zombie(tractor.system_terminal_state()),
                     },
                     actor_type: tractor.actor.actor_type(),
+                    started: Some(tractor.started),
+                    entity_id: tractor.entity_id,
+                    last_activity: tractor.last_activity.get(),
                 }
             })
             .collect::<FuturesUnordered<_>>()
             .collect::<Vec<_>>()
-            .map(|actors| system::SystemState { actors })
+            .map(move |actors| system::SystemState { actors, total })
             .boxed()
     }
 }
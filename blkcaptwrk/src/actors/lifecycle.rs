@@ -0,0 +1,246 @@
+use super::observation::start_observation;
+use crate::xactorext::{
+    ActorLifecycleEvent, ActorLifecycleMessage, ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler,
+    GetActorStatusMessage, TerminalState,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use libblkcapt::model::{entities::ObservableEvent, EntityId};
+use slog::{warn, Logger};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+/// Failures of one entity's worker within [`RESTART_LOOP_WINDOW`] that make it a restart loop.
+const RESTART_LOOP_FAILURES: usize = 3;
+const RESTART_LOOP_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Turns the lifecycle events of the actors working for entities into observations. Only changes are reported: the
+/// first failure, a restart loop, and the recovery after either.
+pub struct LifecycleActor {
+    tracker: LifecycleTracker,
+}
+
+impl LifecycleActor {
+    pub fn new(log: &Logger) -> BcActor<Self> {
+        BcActor::new(
+            Self {
+                tracker: Default::default(),
+            },
+            log,
+        )
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for LifecycleActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        ctx.subscribe::<ActorLifecycleMessage>().await
+    }
+
+    async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+        let _ = ctx.unsubscribe::<ActorLifecycleMessage>().await;
+        TerminalState::Succeeded
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ActorLifecycleMessage> for LifecycleActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ActorLifecycleMessage) {
+        let (entity_id, event) = match msg.observed {
+            Some(observed) => observed,
+            None => return,
+        };
+        match self.tracker.record(entity_id, event, &msg.event, Utc::now()) {
+            Some(LifecycleReport::Recovered) => start_observation(entity_id, event).await.succeeded(),
+            Some(LifecycleReport::Failed(reason)) => start_observation(entity_id, event).await.failed(reason),
+            Some(LifecycleReport::RestartLoop(reason)) => {
+                warn!(
+                    ctx.log(), "worker restarting in a loop";
+                    "actor_type" => &msg.actor_type, "entity_id" => %entity_id
+                );
+                start_observation(entity_id, event).await.failed(reason)
+            }
+            None => {}
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for LifecycleActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::idle()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum LifecycleReport {
+    Recovered,
+    Failed(String),
+    RestartLoop(String),
+}
+
+#[derive(Default)]
+struct WorkerHealth {
+    failing: bool,
+    looping: bool,
+    recent_failures: VecDeque<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct LifecycleTracker(HashMap<(EntityId, ObservableEvent), WorkerHealth>);
+
+impl LifecycleTracker {
+    fn record(
+        &mut self, entity_id: EntityId, event: ObservableEvent, lifecycle: &ActorLifecycleEvent, now: DateTime<Utc>,
+    ) -> Option<LifecycleReport> {
+        let health = self.0.entry((entity_id, event)).or_default();
+        let window = chrono::Duration::from_std(RESTART_LOOP_WINDOW).expect("window fits");
+        while health.recent_failures.front().map_or(false, |f| *f < now - window) {
+            health.recent_failures.pop_front();
+        }
+
+        let reason = match lifecycle {
+            ActorLifecycleEvent::Started => {
+                // starting again between the failures of a loop isn't a recovery
+                if !health.failing || (health.looping && !health.recent_failures.is_empty()) {
+                    return None;
+                }
+                health.failing = false;
+                health.looping = false;
+                return Some(LifecycleReport::Recovered);
+            }
+            ActorLifecycleEvent::StartFailed(error) => format!("worker failed to start: {}", error),
+            ActorLifecycleEvent::Stopped(terminal_state) if terminal_state.faulted() => {
+                format!("worker stopped: {}", terminal_state)
+            }
+            ActorLifecycleEvent::Stopped(_) => return None,
+        };
+
+        health.recent_failures.push_back(now);
+        let was_failing = std::mem::replace(&mut health.failing, true);
+        if health.recent_failures.len() >= RESTART_LOOP_FAILURES {
+            if std::mem::replace(&mut health.looping, true) {
+                return None;
+            }
+            return Some(LifecycleReport::RestartLoop(format!(
+                "worker is restarting in a loop, {} failures within {}: {}",
+                health.recent_failures.len(),
+                humantime::format_duration(RESTART_LOOP_WINDOW),
+                reason
+            )));
+        }
+        match was_failing {
+            true => None,
+            false => Some(LifecycleReport::Failed(reason)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT: ObservableEvent = ObservableEvent::DatasetWorker;
+
+    fn at(minutes: i64) -> DateTime<Utc> {
+        "2020-11-29T12:00:00Z".parse::<DateTime<Utc>>().unwrap() + chrono::Duration::minutes(minutes)
+    }
+
+    fn faulted() -> ActorLifecycleEvent {
+        ActorLifecycleEvent::Stopped(TerminalState::Faulted)
+    }
+
+    #[test]
+    fn starts_are_only_reported_after_a_failure() {
+        let mut tracker = LifecycleTracker::default();
+        let entity_id = EntityId::default();
+
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(0)),
+            None
+        );
+        assert_eq!(
+            tracker.record(
+                entity_id,
+                EVENT,
+                &ActorLifecycleEvent::Stopped(TerminalState::Succeeded),
+                at(1)
+            ),
+            None
+        );
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &faulted(), at(2)),
+            Some(LifecycleReport::Failed("worker stopped: faulted".to_owned()))
+        );
+        assert_eq!(
+            tracker.record(
+                entity_id,
+                EVENT,
+                &ActorLifecycleEvent::StartFailed("busy".to_owned()),
+                at(3)
+            ),
+            None
+        );
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(30)),
+            Some(LifecycleReport::Recovered)
+        );
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(31)),
+            None
+        );
+    }
+
+    #[test]
+    fn repeated_failures_are_reported_once_as_a_loop() {
+        let mut tracker = LifecycleTracker::default();
+        let entity_id = EntityId::default();
+
+        assert!(matches!(
+            tracker.record(entity_id, EVENT, &faulted(), at(0)),
+            Some(LifecycleReport::Failed(_))
+        ));
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(1)),
+            Some(LifecycleReport::Recovered)
+        );
+        assert!(matches!(
+            tracker.record(entity_id, EVENT, &faulted(), at(2)),
+            Some(LifecycleReport::Failed(_))
+        ));
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(3)),
+            Some(LifecycleReport::Recovered)
+        );
+        assert!(matches!(
+            tracker.record(entity_id, EVENT, &faulted(), at(4)),
+            Some(LifecycleReport::RestartLoop(_))
+        ));
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(5)),
+            None
+        );
+        assert_eq!(tracker.record(entity_id, EVENT, &faulted(), at(6)), None);
+
+        // once the failures age out of the window, the next start is a recovery again
+        assert_eq!(
+            tracker.record(entity_id, EVENT, &ActorLifecycleEvent::Started, at(30)),
+            Some(LifecycleReport::Recovered)
+        );
+    }
+
+    #[test]
+    fn entities_are_tracked_separately() {
+        let mut tracker = LifecycleTracker::default();
+        let failing = EntityId::default();
+        let healthy = "f0fd6e0e-3e61-4b3c-a3ea-8fdbeb4b1c6c".parse::<EntityId>().unwrap();
+
+        assert!(tracker.record(failing, EVENT, &faulted(), at(0)).is_some());
+        assert_eq!(
+            tracker.record(healthy, EVENT, &ActorLifecycleEvent::Started, at(1)),
+            None
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{
     actorbase::{log_result, state_result, state_result_from_result, unhandled_result},
     tasks::{WorkerCompleteMessage, WorkerTask},
-    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
 use anyhow::{anyhow, Result};
 use libblkcapt::sys::btrfs::SnapshotReceiver;
@@ -165,8 +165,14 @@ impl BcHandler<WriterDropped> for LocalReceiverActor {
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for LocalReceiverActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        self.state.to_string()
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        match &self.state {
+            State::Faulted => ActorStatus::faulted("receiver faulted"),
+            State::Waiting(Err(e)) | State::Finished(Err(e)) => {
+                ActorStatus::active(self.state.to_string()).with_last_error(format!("{:#}", e))
+            }
+            state => ActorStatus::active(state.to_string()),
+        }
     }
 }
 
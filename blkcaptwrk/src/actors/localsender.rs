@@ -1,7 +1,7 @@
 use crate::{
     actorbase::{state_result, state_result_from_result, unhandled_result},
     tasks::{WorkerCompleteMessage, WorkerTask},
-    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
 use anyhow::{anyhow, Result};
 use libblkcapt::sys::btrfs::SnapshotSender;
@@ -21,9 +21,13 @@ pub struct LocalSenderParentFinishedMessage(pub u64);
 #[message(result = "Result<Box<dyn AsyncRead + Send + Unpin>>")]
 pub struct TakeReaderMessage;
 
+#[message(result = "Option<u64>")]
+pub struct GetExpectedSizeMessage;
+
 pub struct LocalSenderActor {
     parent: Sender<LocalSenderParentFinishedMessage>,
     requestor: Sender<LocalSenderFinishedMessage>,
+    expected_size: Option<u64>,
     state: State,
 }
 
@@ -56,12 +60,13 @@ struct ReaderDropped;
 impl LocalSenderActor {
     pub fn new(
         parent: Sender<LocalSenderParentFinishedMessage>, requestor: Sender<LocalSenderFinishedMessage>,
-        sender: SnapshotSender, log: &Logger,
+        sender: SnapshotSender, expected_size: Option<u64>, log: &Logger,
     ) -> BcActor<Self> {
         BcActor::new(
             Self {
                 parent,
                 requestor,
+                expected_size,
                 state: State::Holding(sender),
             },
             log,
@@ -160,10 +165,23 @@ impl BcHandler<ReaderDropped> for LocalSenderActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<GetExpectedSizeMessage> for LocalSenderActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetExpectedSizeMessage) -> Option<u64> {
+        self.expected_size
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for LocalSenderActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        self.state.to_string()
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        match &self.state {
+            State::Faulted => ActorStatus::faulted("sender faulted"),
+            State::Draining(Err(e)) | State::Finished(Err(e)) => {
+                ActorStatus::active(self.state.to_string()).with_last_error(format!("{:#}", e))
+            }
+            state => ActorStatus::active(state.to_string()),
+        }
     }
 }
 
@@ -1,21 +1,37 @@
 use crate::{
     actorbase::{unhandled_result, ScheduledMessage},
-    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+    slogext::recent_log_lines,
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
 use anyhow::Result;
+use chrono::Utc;
 use libblkcapt::{
+    core::notify::{NotificationContext, NotificationEmitter},
     core::ObservableEventStage,
     core::ObservationEmitter,
     core::ObservationRouter,
     model::entities::HealthchecksHeartbeat,
     model::Entity,
+    model::{digest::NotificationDigest, storage},
     model::{
-        entities::{HealthchecksObserverEntity, ObservableEvent, ScheduleModel},
-        EntityId,
+        entities::{
+            HealthchecksObserverEntity, NotificationObserverEntity, NotifyOn, ObservableEvent, Observation,
+            ScheduleModel,
+        },
+        Entities, EntityId,
     },
+    sys::net::hostname,
 };
 use slog::{error, o, Logger};
-use std::{borrow::Borrow, convert::TryFrom, convert::TryInto, fmt::Debug, future::Future};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    convert::TryFrom,
+    convert::TryInto,
+    fmt::Debug,
+    future::Future,
+    time::{Duration, Instant},
+};
 use xactor::{message, Addr, Broker, Service};
 
 #[message()]
@@ -78,6 +94,11 @@ impl StartedObservation {
         self.stop(ObservableEventStage::Failed(message.as_ref().to_owned()));
     }
 
+    pub fn warned<S: AsRef<str>>(self, message: S) {
+        slog_scope::trace!("observation warned"; "entity_id" => %self.source, "observable_event" => %self.event, "warning" => message.as_ref());
+        self.stop(ObservableEventStage::Warning(message.as_ref().to_owned()));
+    }
+
     pub fn cancelled(self) {
         slog_scope::trace!("observation cancelled"; "entity_id" => %self.source, "observable_event" => %self.event);
         self.failed("cancelled");
@@ -120,16 +141,21 @@ impl Drop for StartedObservation {
     }
 }
 
+/// Number of the entity's recent log lines sent with a failure ping.
+const FAILURE_LOG_LINES: usize = 20;
+
 pub struct HealthchecksActor {
     router: ObservationRouter,
     emitter: ObservationEmitter,
     heartbeat_config: Option<HealthchecksHeartbeat>,
     heartbeat_schedule: Option<ScheduledMessage>,
+    started: HashMap<(EntityId, ObservableEvent), Instant>,
 }
 
 impl HealthchecksActor {
     pub fn new(model: HealthchecksObserverEntity, log: &Logger) -> BcActor<Self> {
-        let observer_id = model.id().to_string();
+        let id = model.id();
+        let observer_id = id.to_string();
         BcActor::new(
             Self {
                 router: ObservationRouter::new(model.observations),
@@ -138,9 +164,11 @@ impl HealthchecksActor {
                     .map_or_else(ObservationEmitter::default, ObservationEmitter::new),
                 heartbeat_config: model.heartbeat,
                 heartbeat_schedule: None,
+                started: HashMap::new(),
             },
             &log.new(o!("observer_id" => observer_id)),
         )
+        .for_entity(id)
     }
 }
 
@@ -171,8 +199,40 @@ impl BcActorCtrl for HealthchecksActor {
 impl BcHandler<ObservableEventMessage> for HealthchecksActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ObservableEventMessage) {
         let observers = self.router.route(msg.source, msg.event);
+        if observers.is_empty() {
+            return;
+        }
+
+        let key = (msg.source, msg.event);
+        let body = match &msg.stage {
+            ObservableEventStage::Starting => {
+                self.started.insert(key, Instant::now());
+                None
+            }
+            ObservableEventStage::Succeeded => self
+                .started
+                .remove(&key)
+                .map(|started| format!("Completed in {}.", format_elapsed(started))),
+            ObservableEventStage::Failed(error) => {
+                let mut report = error.clone();
+                if let Some(started) = self.started.remove(&key) {
+                    report.push_str(&format!("\n\nFailed after {}.", format_elapsed(started)));
+                }
+                let lines = recent_log_lines(&msg.source.to_string(), FAILURE_LOG_LINES);
+                if !lines.is_empty() {
+                    report.push_str("\n\nRecent log lines:\n");
+                    report.push_str(&lines.join("\n"));
+                }
+                Some(report)
+            }
+            ObservableEventStage::Warning(warning) => Some(warning.clone()),
+        };
+
         for observer in observers {
-            let result = self.emitter.emit(observer.healthcheck_id, msg.stage.clone()).await;
+            let result = self
+                .emitter
+                .emit_with_body(observer.healthcheck_id, msg.stage.clone(), body.clone())
+                .await;
             unhandled_result(ctx.log(), result);
         }
     }
@@ -196,7 +256,162 @@ impl BcHandler<HeartbeatMessage> for HealthchecksActor {
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for HealthchecksActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("idle")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::idle()
+    }
+}
+
+fn format_elapsed(started: Instant) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_secs(started.elapsed().as_secs()))
+}
+
+pub struct NotificationActor {
+    id: EntityId,
+    observations: Vec<Observation>,
+    emitter: NotificationEmitter,
+    notify_on: NotifyOn,
+    title_template: String,
+    message_template: String,
+    entity_paths: HashMap<EntityId, String>,
+    host: String,
+    digest_schedule: Option<ScheduleModel>,
+    digest_message: Option<ScheduledMessage>,
+    /// Successes since the last digest, stored after every change so a restart keeps them.
+    digest: NotificationDigest,
+}
+
+#[message()]
+#[derive(Clone)]
+struct DigestMessage;
+
+impl NotificationActor {
+    pub fn new(model: NotificationObserverEntity, entities: &Entities, log: &Logger) -> BcActor<Self> {
+        let id = model.id();
+        let observer_id = id.to_string();
+        let entity_paths = model
+            .observations
+            .iter()
+            .filter_map(|o| {
+                entities
+                    .entity_path(o.event.entity_type(), o.entity_id)
+                    .map(|path| (o.entity_id, path))
+            })
+            .collect();
+        let digest_schedule = model.digest_schedule.filter(|_| model.service.takes_digest());
+        let digest = match digest_schedule {
+            Some(_) => storage::load_notification_digest(id).unwrap_or_else(|e| {
+                error!(log, "failed to load the pending digest: {:#}", e; "observer_id" => %id);
+                NotificationDigest::default()
+            }),
+            None => NotificationDigest::default(),
+        };
+        BcActor::new(
+            Self {
+                id,
+                title_template: model.title_template().to_owned(),
+                message_template: model.message_template().to_owned(),
+                observations: model.observations,
+                emitter: NotificationEmitter::new(model.service),
+                notify_on: model.notify_on,
+                entity_paths,
+                host: hostname(),
+                digest_schedule,
+                digest_message: None,
+                digest,
+            },
+            &log.new(o!("observer_id" => observer_id)),
+        )
+        .for_entity(id)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for NotificationActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        ctx.subscribe::<ObservableEventMessage>().await?;
+        self.digest_message = self.digest_schedule.as_ref().map_or(Ok(None), |s| {
+            s.try_into()
+                .map(|schedule| Some(ScheduledMessage::new(schedule, "digest", DigestMessage, &ctx)))
+        })?;
+        Ok(())
+    }
+
+    async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
+        let _ = ctx.unsubscribe::<ObservableEventMessage>().await;
+
+        TerminalState::Succeeded
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<ObservableEventMessage> for NotificationActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ObservableEventMessage) {
+        let observed = self
+            .observations
+            .iter()
+            .any(|o| o.entity_id == msg.source && o.event == msg.event);
+        if !observed {
+            return;
+        }
+
+        let entity = self
+            .entity_paths
+            .get(&msg.source)
+            .cloned()
+            .unwrap_or_else(|| msg.source.to_string());
+        // successes go into the digest whatever notify_on says, it only decides what is sent on its own
+        if self.digest_message.is_some() && matches!(msg.stage, ObservableEventStage::Succeeded) {
+            self.digest.record(&entity, msg.event);
+            unhandled_result(ctx.log(), storage::store_notification_digest(self.id, &self.digest));
+            return;
+        }
+        if !self.emitter.should_send(self.notify_on, &msg.stage) {
+            return;
+        }
+
+        let context = NotificationContext {
+            entity_id: msg.source,
+            entity: &entity,
+            event: msg.event,
+            stage: &msg.stage,
+            host: &self.host,
+            timestamp: Utc::now(),
+        };
+        let result = self
+            .emitter
+            .send(
+                &context,
+                &context.render(&self.title_template),
+                &context.render(&self.message_template),
+            )
+            .await;
+        unhandled_result(ctx.log(), result);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<DigestMessage> for NotificationActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: DigestMessage) {
+        if self.digest.is_empty() {
+            return;
+        }
+
+        let title = format!("{} successful events on {}", self.digest.total(), self.host);
+        let result = self
+            .emitter
+            .send_digest(&self.host, &title, &self.digest.summary())
+            .await;
+        if result.is_ok() {
+            self.digest.clear();
+            unhandled_result(ctx.log(), storage::store_notification_digest(self.id, &self.digest));
+        }
+        unhandled_result(ctx.log(), result);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for NotificationActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::idle()
     }
 }
@@ -1,32 +1,75 @@
-use super::{container::ContainerActor, dataset::DatasetActor, observation::start_observation};
+use super::{
+    container::{self, ContainerActor, UpdateContainerMessage},
+    dataset::{self, DatasetActor, UpdateDatasetMessage},
+    observation::{start_observation, StartedObservation},
+};
 use crate::{
-    actorbase::unhandled_error,
+    actorbase::{battery_defers, log_result, unhandled_error, BATTERY_RECHECK_INTERVAL},
+    snapshots::RunDeferredJobsMessage,
+    tasks::{WorkerCompleteMessage, WorkerTask},
     xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler},
 };
 use crate::{
-    actorbase::{build_child_actors, ScheduledMessage},
-    xactorext::{GetActorStatusMessage, GetChildActorMessage},
+    actorbase::{build_child_actors, diff_entities, same_model, ScheduledMessage},
+    xactorext::{
+        join_all_actors, stop_all_actors, ActorStatus, BoxBcWeakAddr, GetActorStatusMessage, GetChildActorMessage,
+    },
 };
 use anyhow::{Context as _, Result};
+use chrono::Utc;
 use futures_util::future;
 use libblkcapt::{
     core::BtrfsPool,
     model::Entity,
     model::{
         entities::{BtrfsPoolEntity, FeatureState, ObservableEvent},
+        worker::BatteryJob,
         EntityId,
     },
+    sys::{
+        btrfs::{defragment, CompressionAlgorithm},
+        privilege::running_as_root,
+    },
 };
 use scrub::{PoolScrubActor, ScrubCompleteMessage};
-use slog::{info, o, Logger};
-use std::{collections::HashMap, convert::TryInto, mem, sync::Arc};
-use xactor::{message, Actor, Addr};
+use slog::{debug, info, o, warn, Logger};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Semaphore;
+use xactor::{message, Actor, Addr, Broker, Service};
 
 pub struct PoolActor {
     pool: PoolState,
     scrub_schedule: Option<ScheduledMessage>,
+    wake_schedule: Option<ScheduledMessage>,
+    trash_purge_due: bool,
     datasets: HashMap<EntityId, Addr<BcActor<DatasetActor>>>,
     containers: HashMap<EntityId, Addr<BcActor<ContainerActor>>>,
+    transfer_holds: Vec<BoxBcWeakAddr>,
+    transfer_permits: Option<PoolTransferPermits>,
+    scrub_deferred: bool,
+    trim_schedule: Option<ScheduledMessage>,
+    trim_deferred: bool,
+    defrag_queue: VecDeque<DefragJob>,
+    battery_recheck_pending: bool,
+}
+
+/// Limits the snapshot transfers running on one pool at a time.
+#[derive(Clone)]
+pub struct PoolTransferPermits {
+    pub pool_id: EntityId,
+    pub permits: Arc<Semaphore>,
+    /// Transfers waiting for one of the permits.
+    pub queued: Arc<AtomicUsize>,
 }
 
 enum PoolState {
@@ -37,6 +80,8 @@ enum PoolState {
 
 enum State {
     Scrubbing(Addr<BcActor<PoolScrubActor>>),
+    /// A defrag or trim, named by the job.
+    Maintaining(&'static str, WorkerTask, StartedObservation),
     Idle,
 }
 
@@ -50,18 +95,228 @@ impl PoolState {
 #[derive(Clone)]
 struct ScrubMessage;
 
+#[message()]
+#[derive(Clone)]
+struct TrimMessage;
+
+#[message()]
+struct BatteryRecheckMessage;
+
+#[message()]
+#[derive(Clone)]
+struct PurgeTrashMessage;
+
+#[message()]
+#[derive(Clone)]
+struct WakeWindowMessage;
+
+/// Published once a pool with a wake schedule has run its deferred jobs, while its disks are still spun up.
+#[message()]
+#[derive(Clone, Debug)]
+pub struct PoolWakeWindowMessage(pub EntityId);
+
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Apply a reloaded model without restarting the pool, so transfers in flight carry on. Datasets and containers
+/// are started, stopped or updated in place as needed. Returns the datasets and containers that had to restart, or
+/// `None` when the whole pool has to.
+#[message(result = "Result<Option<Vec<EntityId>>>")]
+pub struct UpdatePoolMessage(pub BtrfsPoolEntity);
+
+/// Fields read when needed rather than only when the actor starts.
+const LIVE_FIELDS: &[&str] = &[
+    "name",
+    "scrub_schedule",
+    "pause_scrubbing",
+    "trim_schedule",
+    "pause_trimming",
+    "datasets",
+    "containers",
+];
+
+/// Whether a running pool actor takes the change without restarting. Its datasets and containers may still restart.
+pub fn updates_in_place(current: &BtrfsPoolEntity, new: &BtrfsPoolEntity) -> bool {
+    same_model(current, new, LIVE_FIELDS)
+}
+
+/// The datasets and containers of a pool that stop when the pool is updated to `new`: removed ones and those whose
+/// change needs a restart. All of them when the pool itself restarts.
+pub fn stopped_by_update(current: &BtrfsPoolEntity, new: &BtrfsPoolEntity) -> Vec<EntityId> {
+    if !updates_in_place(current, new) {
+        return current
+            .datasets
+            .iter()
+            .map(|d| d.id())
+            .chain(current.containers.iter().map(|c| c.id()))
+            .collect();
+    }
+    let datasets = diff_entities(&current.datasets, &new.datasets);
+    let containers = diff_entities(&current.containers, &new.containers);
+    datasets
+        .removed
+        .into_iter()
+        .chain(
+            datasets
+                .changed
+                .into_iter()
+                .filter(|d| {
+                    current
+                        .datasets
+                        .iter()
+                        .any(|c| c.id() == d.id() && !dataset::updates_in_place(c, d))
+                })
+                .map(|d| d.id()),
+        )
+        .chain(containers.removed)
+        .chain(
+            containers
+                .changed
+                .into_iter()
+                .filter(|d| {
+                    current
+                        .containers
+                        .iter()
+                        .any(|c| c.id() == d.id() && !container::updates_in_place(c, d))
+                })
+                .map(|d| d.id()),
+        )
+        .collect()
+}
+
+/// The permits limiting the transfers on this pool, if the pool has a limit.
+#[message(result = "Option<PoolTransferPermits>")]
+pub struct GetTransferPermitsMessage;
+
+/// Registers an active transfer that reads from or writes to this pool. A sync between two pools holds both.
+#[message()]
+pub struct PoolTransferHoldMessage(pub BoxBcWeakAddr);
+
+#[message()]
+pub struct PoolTransferReleaseMessage(pub u64);
+
+/// Defragment part of a dataset once the pool has no scrub, transfer or other defrag running.
+#[message()]
+pub struct PoolDefragMessage(pub DefragJob);
+
+#[derive(Debug)]
+pub struct DefragJob {
+    pub dataset_id: EntityId,
+    pub paths: Vec<PathBuf>,
+    pub compress: Option<CompressionAlgorithm>,
+}
+
+type MaintenanceCompleteMessage = WorkerCompleteMessage<Result<()>>;
+
 impl PoolActor {
+    /// Schedules the scrubs and trims of the model, replacing any scheduled before.
+    fn schedule_maintenance(&mut self, pool: &BtrfsPool, ctx: &BcContext<'_, Self>) -> Result<()> {
+        self.scrub_schedule = None;
+        if pool.model().scrubbing_state() == FeatureState::Enabled && !running_as_root() {
+            warn!(ctx.log(), "scrubbing disabled. scrub requires root");
+        } else if pool.model().scrubbing_state() == FeatureState::Enabled {
+            self.scrub_schedule = pool.model().scrub_schedule.as_ref().map_or(Ok(None), |s| {
+                s.try_into()
+                    .map(|schedule| Some(ScheduledMessage::new(schedule, "scrub", ScrubMessage, ctx)))
+            })?;
+        }
+
+        self.trim_schedule = None;
+        if pool.model().trimming_state() == FeatureState::Enabled && !running_as_root() {
+            warn!(ctx.log(), "trimming disabled. fstrim requires root");
+        } else if pool.model().trimming_state() == FeatureState::Enabled {
+            if let Ok(true) = pool.continuous_discard() {
+                info!(
+                    ctx.log(),
+                    "pool is mounted with discard. scheduled trims will have little to do"
+                );
+            }
+            self.trim_schedule = pool.model().trim_schedule.as_ref().map_or(Ok(None), |s| {
+                s.try_into()
+                    .map(|schedule| Some(ScheduledMessage::new(schedule, "trim", TrimMessage, ctx)))
+            })?;
+        }
+        Ok(())
+    }
+
+    fn purge_trash(&self, log: &Logger) {
+        if let PoolState::Started(pool, _) = &self.pool {
+            match pool.empty_trash(Some(Utc::now())) {
+                Ok(0) => {}
+                Ok(deleted) => info!(log, "deleted expired snapshots from trash"; "count" => deleted),
+                Err(e) => unhandled_error(log, e),
+            }
+        }
+    }
+
+    fn has_active_transfers(&mut self) -> bool {
+        self.transfer_holds.retain(|h| h.upgrade().is_some());
+        !self.transfer_holds.is_empty()
+    }
+
+    fn start_maintenance(&mut self, job: &'static str, task: WorkerTask, observation: StartedObservation) {
+        if let PoolState::Started(pool, _) = self.pool.take() {
+            self.pool = PoolState::Started(pool, State::Maintaining(job, task, observation));
+        }
+    }
+
+    /// Start maintenance that waited for the pool to go idle. A deferred scrub goes first, then trim, then queued
+    /// defrags.
+    fn run_deferred_maintenance(&mut self, ctx: &BcContext<'_, Self>) {
+        if !matches!(self.pool, PoolState::Started(_, State::Idle)) || self.has_active_transfers() {
+            return;
+        }
+        if self.scrub_deferred {
+            debug!(ctx.log(), "pool idle. starting deferred scrub");
+            ctx.address().send(ScrubMessage).expect("send to self is infalliable");
+        } else if self.trim_deferred {
+            debug!(ctx.log(), "pool idle. starting deferred trim");
+            ctx.address().send(TrimMessage).expect("send to self is infalliable");
+        } else if let Some(job) = self.defrag_queue.pop_front() {
+            debug!(ctx.log(), "pool idle. starting queued defrag"; "dataset_id" => %job.dataset_id);
+            ctx.address()
+                .send(PoolDefragMessage(job))
+                .expect("send to self is infalliable");
+        }
+    }
+
+    /// Hold `job` back while on battery. Deferred maintenance is tried again once the power supply was rechecked.
+    fn defer_for_battery(&mut self, ctx: &BcContext<'_, Self>, job: BatteryJob) -> bool {
+        if !battery_defers(job, ctx.log()) {
+            return false;
+        }
+        info!(ctx.log(), "deferring {} while on battery", job);
+        if !mem::replace(&mut self.battery_recheck_pending, true) {
+            ctx.send_later(BatteryRecheckMessage, BATTERY_RECHECK_INTERVAL);
+        }
+        true
+    }
+
     pub fn new(model: BtrfsPoolEntity, log: &Logger) -> BcActor<Self> {
         let id = model.id();
+        let transfer_permits = model.max_concurrent_transfers.map(|max| PoolTransferPermits {
+            pool_id: id,
+            permits: Arc::new(Semaphore::new(max.get())),
+            queued: Default::default(),
+        });
         BcActor::new(
             Self {
                 pool: PoolState::Pending(model),
                 scrub_schedule: None,
+                wake_schedule: None,
+                trash_purge_due: false,
                 datasets: HashMap::<_, _>::default(),
                 containers: HashMap::<_, _>::default(),
+                transfer_holds: Default::default(),
+                transfer_permits,
+                scrub_deferred: false,
+                trim_schedule: None,
+                trim_deferred: false,
+                defrag_queue: Default::default(),
+                battery_recheck_pending: false,
             },
             &log.new(o!("actor" => "pool", "pool_id" => id.to_string())),
         )
+        .observe_lifecycle(id, ObservableEvent::PoolWorker)
     }
 }
 
@@ -84,18 +339,125 @@ impl BcActorCtrl for PoolActor {
         })
         .await;
 
-        if pool.model().scrubbing_state() == FeatureState::Enabled {
-            self.scrub_schedule = pool.model().scrub_schedule.as_ref().map_or(Ok(None), |s| {
-                s.try_into()
-                    .map(|schedule| Some(ScheduledMessage::new(schedule, "scrub", ScrubMessage, &ctx)))
-            })?;
-        }
+        self.schedule_maintenance(&pool, &ctx)?;
+
+        ctx.send_interval(PurgeTrashMessage, TRASH_PURGE_INTERVAL);
+
+        self.wake_schedule = pool.model().wake_schedule.as_ref().map_or(Ok(None), |s| {
+            s.try_into()
+                .map(|schedule| Some(ScheduledMessage::new(schedule, "wake window", WakeWindowMessage, &ctx)))
+        })?;
 
         self.pool = PoolState::Started(pool, State::Idle);
         Ok(())
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<UpdatePoolMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: UpdatePoolMessage) -> Result<Option<Vec<EntityId>>> {
+        let current = match &self.pool {
+            PoolState::Started(pool, _) if updates_in_place(pool.model(), &msg.0) => Arc::clone(pool),
+            _ => return Ok(None),
+        };
+        let pool = BtrfsPool::validate(msg.0).map(Arc::new)?;
+        let log = ctx.log();
+
+        let datasets = diff_entities(&current.model().datasets, &pool.model().datasets);
+        let mut restart_datasets = Vec::new();
+        for model in datasets.changed {
+            let updated = match self.datasets.get(&model.id()) {
+                Some(actor) => actor
+                    .call(UpdateDatasetMessage {
+                        pool: Arc::clone(&pool),
+                        model: model.clone(),
+                    })
+                    .await
+                    .and_then(|r| r),
+                None => Ok(false),
+            };
+            match updated {
+                Ok(true) => {}
+                Ok(false) => restart_datasets.push(model),
+                Err(e) => {
+                    warn!(log, "updating dataset failed, restarting it"; "dataset_id" => %model.id(), "error" => %e);
+                    restart_datasets.push(model);
+                }
+            }
+        }
+        let mut stopping = datasets
+            .removed
+            .iter()
+            .copied()
+            .chain(restart_datasets.iter().map(|m| m.id()))
+            .filter_map(|id| self.datasets.remove(&id))
+            .collect::<Vec<_>>();
+        stop_all_actors(&mut stopping);
+        join_all_actors(stopping).await;
+
+        let containers = diff_entities(&current.model().containers, &pool.model().containers);
+        let mut restart_containers = Vec::new();
+        for model in containers.changed {
+            let updated = match self.containers.get(&model.id()) {
+                Some(actor) => actor
+                    .call(UpdateContainerMessage {
+                        pool: Arc::clone(&pool),
+                        model: model.clone(),
+                    })
+                    .await
+                    .and_then(|r| r),
+                None => Ok(false),
+            };
+            match updated {
+                Ok(true) => {}
+                Ok(false) => restart_containers.push(model),
+                Err(e) => {
+                    warn!(
+                        log,
+                        "updating container failed, restarting it";
+                        "container_id" => %model.id(),
+                        "error" => %e
+                    );
+                    restart_containers.push(model);
+                }
+            }
+        }
+        let mut stopping = containers
+            .removed
+            .iter()
+            .copied()
+            .chain(restart_containers.iter().map(|m| m.id()))
+            .filter_map(|id| self.containers.remove(&id))
+            .collect::<Vec<_>>();
+        stop_all_actors(&mut stopping);
+        join_all_actors(stopping).await;
+
+        let restarted = restart_datasets
+            .iter()
+            .map(|m| m.id())
+            .chain(restart_containers.iter().map(|m| m.id()))
+            .collect();
+        let started_datasets = build_child_actors(&ctx, datasets.added.into_iter().chain(restart_datasets), |m| {
+            future::ready(DatasetActor::new(ctx.address(), &pool, m.clone(), log))
+        })
+        .await;
+        self.datasets.extend(started_datasets);
+        let started_containers =
+            build_child_actors(&ctx, containers.added.into_iter().chain(restart_containers), |m| {
+                future::ready(ContainerActor::new(ctx.address(), &pool, m.clone(), log))
+            })
+            .await;
+        self.containers.extend(started_containers);
+
+        self.schedule_maintenance(&pool, &ctx)?;
+        if let PoolState::Started(running, _) = &mut self.pool {
+            *running = pool;
+        }
+        info!(log, "pool updated in place");
+        Ok(Some(restarted))
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetChildActorMessage<EntityId, BcActor<DatasetActor>>> for PoolActor {
     async fn handle(
@@ -118,10 +480,21 @@ impl BcHandler<GetChildActorMessage<EntityId, BcActor<ContainerActor>>> for Pool
 impl BcHandler<ScrubMessage> for PoolActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: ScrubMessage) {
         self.pool = match self.pool.take() {
+            PoolState::Started(pool, State::Idle) if self.has_active_transfers() => {
+                info!(ctx.log(), "deferring scrub until active transfers finish");
+                self.scrub_deferred = true;
+                PoolState::Started(pool, State::Idle)
+            }
+            PoolState::Started(pool, State::Idle) if self.defer_for_battery(&ctx, BatteryJob::Scrub) => {
+                self.scrub_deferred = true;
+                PoolState::Started(pool, State::Idle)
+            }
             PoolState::Started(pool, State::Idle) => {
+                self.scrub_deferred = false;
                 let observation = start_observation(pool.model().id(), ObservableEvent::PoolScrub).await;
                 let scrub = pool.scrub();
-                let scrub_actor = PoolScrubActor::new(ctx.address().downgrade(), scrub, observation, ctx.log());
+                let scrub_actor = PoolScrubActor::new(ctx.address().downgrade(), scrub, observation, ctx.log())
+                    .for_entity(pool.model().id());
                 let start_result = scrub_actor.start().await.context("failed to start scrub actor");
                 PoolState::Started(
                     pool,
@@ -138,6 +511,11 @@ impl BcHandler<ScrubMessage> for PoolActor {
                 info!(ctx.log(), "skipping scrub. scrub already running");
                 PoolState::Started(pool, State::Scrubbing(actor))
             }
+            PoolState::Started(pool, State::Maintaining(job, task, observation)) => {
+                info!(ctx.log(), "deferring scrub until {} finishes", job);
+                self.scrub_deferred = true;
+                PoolState::Started(pool, State::Maintaining(job, task, observation))
+            }
             PoolState::Pending(_) | PoolState::Faulted => {
                 ctx.stop(None);
                 PoolState::Faulted
@@ -155,14 +533,195 @@ impl BcHandler<ScrubCompleteMessage> for PoolActor {
                 ctx.stop(None);
                 PoolState::Faulted
             }
+        };
+        self.run_deferred_maintenance(&ctx);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PoolDefragMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PoolDefragMessage) {
+        let job = msg.0;
+        let transfers_active = self.has_active_transfers();
+        match &self.pool {
+            PoolState::Started(_, State::Idle) if !transfers_active => {}
+            PoolState::Started(..) => {
+                if !self.defrag_queue.iter().any(|j| j.dataset_id == job.dataset_id) {
+                    info!(ctx.log(), "deferring defrag until the pool is idle"; "dataset_id" => %job.dataset_id);
+                    self.defrag_queue.push_back(job);
+                }
+                return;
+            }
+            PoolState::Pending(_) | PoolState::Faulted => return,
+        }
+
+        if self.defer_for_battery(&ctx, BatteryJob::Defrag) {
+            if !self.defrag_queue.iter().any(|j| j.dataset_id == job.dataset_id) {
+                self.defrag_queue.push_back(job);
+            }
+            return;
         }
+        self.defrag_queue.retain(|j| j.dataset_id != job.dataset_id);
+        info!(ctx.log(), "starting defrag"; "dataset_id" => %job.dataset_id, "paths" => job.paths.len());
+        let observation = start_observation(job.dataset_id, ObservableEvent::DatasetDefrag).await;
+        let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+            defragment(&job.paths, job.compress).await.into()
+        });
+        self.start_maintenance("defrag", task, observation);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<TrimMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: TrimMessage) {
+        let transfers_active = self.has_active_transfers();
+        let pool = match &self.pool {
+            PoolState::Started(pool, State::Idle) if !transfers_active => Arc::clone(pool),
+            PoolState::Started(..) => {
+                info!(ctx.log(), "deferring trim until the pool is idle");
+                self.trim_deferred = true;
+                return;
+            }
+            PoolState::Pending(_) | PoolState::Faulted => return,
+        };
+
+        if self.defer_for_battery(&ctx, BatteryJob::Trim) {
+            self.trim_deferred = true;
+            return;
+        }
+        self.trim_deferred = false;
+        let observation = start_observation(pool.model().id(), ObservableEvent::PoolTrim).await;
+        let log = ctx.log().clone();
+        let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+            let result = pool.trim().await;
+            if let Ok(trimmed) = &result {
+                info!(log, "trim complete"; "trimmed_bytes" => trimmed);
+            }
+            result.map(|_| ()).into()
+        });
+        self.start_maintenance("trim", task, observation);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<BatteryRecheckMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: BatteryRecheckMessage) {
+        self.battery_recheck_pending = false;
+        self.run_deferred_maintenance(&ctx);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<MaintenanceCompleteMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: MaintenanceCompleteMessage) {
+        let result = msg.0;
+        self.pool = match self.pool.take() {
+            PoolState::Started(pool, State::Maintaining(_, _, observation)) => {
+                log_result(ctx.log(), &result);
+                observation.result(&result);
+                PoolState::Started(pool, State::Idle)
+            }
+            PoolState::Pending(_) | PoolState::Started(..) | PoolState::Faulted => {
+                ctx.stop(None);
+                PoolState::Faulted
+            }
+        };
+        self.run_deferred_maintenance(&ctx);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PurgeTrashMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PurgeTrashMessage) {
+        if self.wake_schedule.is_some() {
+            self.trash_purge_due = true;
+            return;
+        }
+        self.purge_trash(ctx.log());
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<WakeWindowMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: WakeWindowMessage) {
+        let pool_id = match &self.pool {
+            PoolState::Started(pool, _) => pool.model().id(),
+            _ => return,
+        };
+
+        info!(ctx.log(), "wake window opened, running deferred jobs");
+        // One child at a time, the point is to keep the disks busy for a short while, not to contend for them.
+        for dataset in self.datasets.values() {
+            log_result(ctx.log(), &dataset.call(RunDeferredJobsMessage).await);
+        }
+        for container in self.containers.values() {
+            log_result(ctx.log(), &container.call(RunDeferredJobsMessage).await);
+        }
+        if mem::take(&mut self.trash_purge_due) {
+            self.purge_trash(ctx.log());
+        }
+
+        match Broker::from_registry().await {
+            Ok(mut broker) => log_result(ctx.log(), &broker.publish(PoolWakeWindowMessage(pool_id))),
+            Err(e) => unhandled_error(ctx.log(), e),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetTransferPermitsMessage> for PoolActor {
+    async fn handle(
+        &mut self, _ctx: BcContext<'_, Self>, _msg: GetTransferPermitsMessage,
+    ) -> Option<PoolTransferPermits> {
+        self.transfer_permits.clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PoolTransferHoldMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PoolTransferHoldMessage) {
+        let holder = msg.0;
+        if !self.transfer_holds.iter().any(|h| h.actor_id() == holder.actor_id()) {
+            debug!(ctx.log(), "transfer hold acquired"; "holder" => holder.actor_type(), "holder_id" => holder.actor_id());
+            self.transfer_holds.push(holder);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PoolTransferReleaseMessage> for PoolActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PoolTransferReleaseMessage) {
+        self.transfer_holds.retain(|h| h.actor_id() != msg.0);
+        self.run_deferred_maintenance(&ctx);
     }
 }
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for PoolActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("idle")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        let queued_transfers = self
+            .transfer_permits
+            .as_ref()
+            .map_or(0, |p| p.queued.load(Ordering::Relaxed));
+        let status = if let PoolState::Faulted = self.pool {
+            return ActorStatus::faulted("pool faulted");
+        } else if let PoolState::Started(_, State::Scrubbing(_)) = self.pool {
+            ActorStatus::active("scrub")
+        } else if let PoolState::Started(_, State::Maintaining(job, ..)) = self.pool {
+            ActorStatus::active(job)
+        } else if self.has_active_transfers() {
+            let running = self.transfer_holds.len().saturating_sub(queued_transfers);
+            ActorStatus::active(format!("{} transfers", running))
+        } else {
+            ActorStatus::idle()
+        };
+        // Deferred scrubs, trims and defrags wait for the pool to go idle, queued transfers for a permit.
+        status.with_queue_depth(
+            usize::from(self.scrub_deferred)
+                + usize::from(self.trim_deferred)
+                + self.defrag_queue.len()
+                + queued_transfers,
+        )
     }
 }
 
@@ -274,8 +833,12 @@ mod scrub {
 
     #[async_trait::async_trait]
     impl BcHandler<GetActorStatusMessage> for PoolScrubActor {
-        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-            self.state.to_string()
+        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+            match &self.state {
+                State::Faulted => ActorStatus::faulted("scrub faulted"),
+                State::Scrubbed(Err(e)) => ActorStatus::active(self.state.to_string()).with_last_error(e.to_string()),
+                state => ActorStatus::active(state.to_string()),
+            }
         }
     }
 }
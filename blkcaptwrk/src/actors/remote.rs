@@ -0,0 +1,317 @@
+use super::{
+    container::{GetSnapshotReceiverMessage, ReceiverReadyMessage},
+    localreceiver::{LocalReceiverActor, LocalReceiverStoppedParentMessage},
+    observation::observable_func,
+};
+use crate::{
+    actorbase::{clock_defers_pruning, log_result, unhandled_result, ScheduledMessage},
+    snapshots::{
+        clear_deleted, failed_snapshot_deletes_as_result, log_evaluation, ContainerSnapshotsResponse,
+        GetContainerSnapshotsMessage, PruneMessage,
+    },
+    xactorext::{
+        join_all_actors, stop_all_actors, ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler,
+        GetActorStatusMessage, TerminalState,
+    },
+};
+use anyhow::{bail, Context as _, Result};
+use libblkcapt::{
+    core::{
+        remote::{RemoteContainer, RemoteContainerSnapshot},
+        retention::evaluate_retention,
+    },
+    model::{
+        entities::{FeatureState, ObservableEvent, RemoteContainerEntity},
+        Entity, EntityId,
+    },
+};
+use slog::{debug, info, o, trace, warn, Logger};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    sync::Arc,
+};
+use xactor::{message, Actor, WeakAddr};
+
+pub struct RemoteContainerActor {
+    container: Arc<RemoteContainer>,
+    snapshots: HashMap<EntityId, Vec<RemoteContainerSnapshot>>,
+    active_receivers: HashMap<u64, ActiveReceiver>,
+    faulted: bool,
+    prune_schedule: Option<ScheduledMessage>,
+}
+
+struct ActiveReceiver {
+    actor: WeakAddr<BcActor<LocalReceiverActor>>,
+    dataset_id: EntityId,
+}
+
+/// Check the remote host accepts the ssh login, so a sync can fail fast instead of waiting on a receive that can't
+/// connect.
+#[message(result = "Result<()>")]
+pub struct CheckRemoteHostMessage;
+
+impl RemoteContainerActor {
+    pub fn new(model: RemoteContainerEntity, log: &Logger) -> Result<BcActor<Self>> {
+        let id = model.id();
+        RemoteContainer::validate(model).map(|container| {
+            BcActor::new(
+                Self {
+                    container: Arc::new(container),
+                    snapshots: Default::default(),
+                    active_receivers: Default::default(),
+                    faulted: false,
+                    prune_schedule: None,
+                },
+                &log.new(o!("container_id" => id.to_string())),
+            )
+            .observe_lifecycle(id, ObservableEvent::ContainerWorker)
+        })
+    }
+
+    /// Delete the snapshots on the remote the retention rules drop, skipping datasets that are receiving.
+    async fn prune(&mut self, log: &Logger) {
+        let newest = self
+            .snapshots
+            .values()
+            .filter_map(|s| s.last())
+            .map(|s| s.datetime)
+            .max();
+        if clock_defers_pruning(newest, log) {
+            return;
+        }
+        let container = Arc::clone(&self.container);
+        let receiving = self
+            .active_receivers
+            .values()
+            .map(|r| r.dataset_id)
+            .collect::<HashSet<_>>();
+        let snapshots = &mut self.snapshots;
+        let result = observable_func(container.model().id(), ObservableEvent::ContainerPrune, || async move {
+            let rules = container
+                .model()
+                .snapshot_retention
+                .as_ref()
+                .expect("retention exist based on message scheduling in started");
+            let mut failed_deletes = 0;
+            for (dataset_id, snapshots) in snapshots.iter_mut() {
+                if receiving.contains(dataset_id) {
+                    debug!(log, "prune skipped while receiving"; "dataset_id" => %dataset_id);
+                    continue;
+                }
+                trace!(log, "prune remote container"; "dataset_id" => %dataset_id);
+                let evaluation = evaluate_retention(snapshots, rules);
+                log_evaluation(&evaluation, log);
+                let mut deleted = HashSet::new();
+                for snapshot in evaluation.drop_snapshots.iter() {
+                    let result = container.delete_snapshot(snapshot).await;
+                    log_result(log, &result);
+                    match result {
+                        Ok(()) => {
+                            deleted.insert(snapshot.datetime);
+                        }
+                        Err(_) => failed_deletes += 1,
+                    }
+                }
+                clear_deleted(snapshots, deleted);
+            }
+            failed_snapshot_deletes_as_result(failed_deletes)
+        })
+        .await;
+
+        unhandled_result(log, result);
+    }
+
+    /// Clean up after receives into a dataset that never got sealed. Must not run while receiving for the dataset.
+    async fn recover_receives(&mut self, dataset_id: EntityId, log: &Logger) {
+        match self.container.recover_receives(dataset_id).await {
+            Ok((sealed, discarded)) => {
+                if !sealed.is_empty() || discarded > 0 {
+                    info!(log, "recovered interrupted receives"; "dataset_id" => %dataset_id, "sealed" => sealed.len(), "discarded" => discarded);
+                }
+                let snapshots = self.snapshots.entry(dataset_id).or_default();
+                snapshots.extend(sealed);
+                snapshots.sort_unstable_by_key(|s| s.datetime);
+            }
+            Err(e) => warn!(log, "failed to recover interrupted receives: {:#}", e; "dataset_id" => %dataset_id),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcActorCtrl for RemoteContainerActor {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        self.container.check_host().await?;
+        self.snapshots = self.container.all_snapshots().await?;
+        let dataset_ids = self.snapshots.keys().copied().collect::<Vec<_>>();
+        for dataset_id in dataset_ids {
+            self.recover_receives(dataset_id, ctx.log()).await;
+        }
+        trace!(
+            ctx.log(),
+            "Starting remote container {} with {} snapshots from {} datasets.",
+            self.container,
+            self.snapshots.values().fold(0, |acc, v| acc + v.len()),
+            self.snapshots.len()
+        );
+
+        if self.container.model().pruning_state() == FeatureState::Enabled {
+            self.prune_schedule = self
+                .container
+                .model()
+                .snapshot_retention
+                .as_ref()
+                .map(|r| &r.evaluation_schedule)
+                .map_or(Ok(None), |s| {
+                    s.try_into()
+                        .map(|schedule| Some(ScheduledMessage::new(schedule, "prune", PruneMessage, &ctx)))
+                })?;
+        }
+        Ok(())
+    }
+
+    async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
+        if self.faulted {
+            return TerminalState::Faulted;
+        }
+
+        let mut active_actors = self
+            .active_receivers
+            .drain()
+            .filter_map(|(_, a)| a.actor.upgrade())
+            .collect::<Vec<_>>();
+        if !active_actors.is_empty() {
+            stop_all_actors(&mut active_actors);
+            join_all_actors(active_actors).await;
+            TerminalState::Cancelled
+        } else {
+            TerminalState::Succeeded
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetContainerSnapshotsMessage> for RemoteContainerActor {
+    async fn handle(
+        &mut self, _ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
+    ) -> ContainerSnapshotsResponse {
+        let (snapshots, total) = msg
+            .query
+            .select(self.snapshots.get(&msg.source_dataset_id).into_iter().flatten());
+        ContainerSnapshotsResponse { snapshots, total }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PruneMessage> for RemoteContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
+        self.prune(ctx.log()).await;
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<CheckRemoteHostMessage> for RemoteContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: CheckRemoteHostMessage) -> Result<()> {
+        self.container.check_host().await
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSnapshotReceiverMessage> for RemoteContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetSnapshotReceiverMessage) -> Result<()> {
+        if self
+            .container
+            .snapshot_by_datetime(msg.source_dataset_id, msg.source_snapshot_handle.datetime)
+            .await?
+            .is_some()
+        {
+            bail!(
+                "receiver requested for existing snapshot dataset_id: {} snapshot_datetime: {}",
+                msg.source_dataset_id,
+                msg.source_snapshot_handle.datetime
+            )
+        }
+
+        let snapshot_receiver = self
+            .container
+            .receive(
+                msg.source_dataset_id,
+                msg.source_snapshot_handle.datetime,
+                msg.compression,
+            )
+            .await?;
+        let started_receiver_actor = LocalReceiverActor::new(
+            ctx.address().sender(),
+            msg.target_finished,
+            snapshot_receiver,
+            &ctx.log().new(o!("message" => ())),
+        )
+        .start()
+        .await;
+
+        if let Ok(addr) = &started_receiver_actor {
+            self.active_receivers.insert(
+                addr.actor_id(),
+                ActiveReceiver {
+                    actor: addr.downgrade(),
+                    dataset_id: msg.source_dataset_id,
+                },
+            );
+        } else {
+            return started_receiver_actor.map(|_| ());
+        }
+
+        msg.target_ready.send(ReceiverReadyMessage(started_receiver_actor))
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<LocalReceiverStoppedParentMessage> for RemoteContainerActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: LocalReceiverStoppedParentMessage) {
+        let LocalReceiverStoppedParentMessage(actor_id, maybe_snapshot_name) = msg;
+        let active_receiver = match self.active_receivers.remove(&actor_id) {
+            Some(active) => active,
+            None => {
+                self.faulted = true;
+                ctx.stop(None);
+                return;
+            }
+        };
+
+        if let Some(new_snapshot_name) = maybe_snapshot_name {
+            let sealed_snapshot = self
+                .container
+                .seal_snapshot(active_receiver.dataset_id, &new_snapshot_name)
+                .await
+                .with_context(|| format!("received snapshot {} but failed to seal it", new_snapshot_name));
+            log_result(ctx.log(), &sealed_snapshot);
+            if let Ok(new_snapshot) = sealed_snapshot {
+                debug!(ctx.log(), "remote container received snapshot {}", new_snapshot.datetime; "received_uuid" => %new_snapshot.received_uuid);
+
+                self.snapshots
+                    .entry(active_receiver.dataset_id)
+                    .or_default()
+                    .push(new_snapshot);
+            }
+        } else if !self
+            .active_receivers
+            .values()
+            .any(|r| r.dataset_id == active_receiver.dataset_id)
+        {
+            self.recover_receives(active_receiver.dataset_id, ctx.log()).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetActorStatusMessage> for RemoteContainerActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        if self.faulted {
+            ActorStatus::faulted("remote container faulted")
+        } else if self.active_receivers.is_empty() {
+            ActorStatus::idle()
+        } else {
+            ActorStatus::active(format!("receiving {} snapshots", self.active_receivers.len()))
+        }
+    }
+}
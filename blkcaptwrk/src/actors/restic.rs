@@ -2,19 +2,20 @@ use super::{
     dataset::{DatasetHolderActor, HolderReadyMessage},
     transfer::TransferComplete,
 };
-use crate::actorbase::log_result;
+use crate::actorbase::{clock_defers_pruning, log_result};
 use crate::xactorext::{BcContext, BoxBcAddr};
 use crate::{
     actorbase::unhandled_result,
     snapshots::{ContainerSnapshotsResponse, GetContainerSnapshotsMessage, PruneMessage},
     tasks::WorkerCompleteMessage,
     tasks::WorkerTask,
-    xactorext::{BcActor, BcActorCtrl, BcHandler, GetActorStatusMessage, TerminalState},
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcHandler, GetActorStatusMessage, TerminalState},
 };
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
 use container::BackupReadyMessage;
-pub use container::{GetBackupMessage, ResticContainerActor};
+pub use container::{CheckHostMessage, GetBackupMessage, ResticContainerActor};
 use derive_more::From;
+use futures_util::future::{BoxFuture, FutureExt};
 use libblkcapt::model::entities::FeatureState;
 use libblkcapt::{
     core::restic::ResticContainerSnapshot,
@@ -44,7 +45,11 @@ mod container {
     use slog::info;
     use xactor::{Actor, WeakAddr};
 
-    use crate::{actorbase::ScheduledMessage, actors::observation::start_observation, snapshots::clear_deleted};
+    use crate::{
+        actorbase::ScheduledMessage,
+        actors::observation::{start_observation, StartedObservation},
+        snapshots::clear_deleted,
+    };
 
     use super::*;
 
@@ -89,8 +94,24 @@ mod container {
             actor: Addr<BcActor<ResticPruneActor>>,
             forgets: Vec<(EntityId, HashSet<DateTime<Utc>>)>,
         },
+        /// Waking the repository host can take minutes, so it runs in a worker.
+        Waking {
+            task: WorkerTask,
+            then: AfterWake,
+            prune_pending: bool,
+        },
     }
 
+    enum AfterWake {
+        Prune {
+            forgets: Vec<(EntityId, HashSet<DateTime<Utc>>)>,
+            observation: StartedObservation,
+        },
+        Backup(GetBackupMessage),
+    }
+
+    type WakeWorkerCompleteMessage = WorkerCompleteMessage<Result<()>>;
+
     impl State {
         fn take(&mut self) -> Self {
             mem::replace(self, State::Faulted)
@@ -107,6 +128,11 @@ mod container {
     #[message]
     pub struct BackupReadyMessage(pub Result<ResticBackup>);
 
+    /// Probe the repository host so a sync can fail fast instead of waiting on a backup that can't connect. Waking
+    /// the host can take minutes, the returned future does it without holding up the container.
+    #[message(result = "BoxFuture<'static, Result<()>>")]
+    pub struct CheckHostMessage;
+
     impl GetBackupMessage {
         pub fn new(
             requestor_addr: &Addr<BcActor<ResticTransferActor>>, source_dataset_id: EntityId,
@@ -133,33 +159,39 @@ mod container {
                 },
                 &log.new(o!("container_id" => id.to_string())),
             )
+            .observe_lifecycle(id, ObservableEvent::ContainerWorker)
         }
 
-        async fn process_waiting(&mut self, ctx: &BcContext<'_, Self>) {
-            let mut state = mem::replace(&mut self.state, State::Idle);
-
-            if let State::Active { active, waiting } = &mut state {
-                let prune_pending = matches!(active, Active::Transfer { prune_pending, .. } if *prune_pending);
-
-                if prune_pending {
-                    if let Some(active_prune) = self.start_prune(ctx).await {
-                        *active = active_prune;
-                        self.state = state;
-                        return;
-                    }
-                }
+        /// Start what was held back while the container was busy, a pending prune first.
+        fn process_waiting(
+            &mut self, ctx: &BcContext<'_, Self>, prune_pending: bool, mut waiting: VecDeque<GetBackupMessage>,
+        ) {
+            let active = match prune_pending {
+                true => self.start_prune(ctx),
+                false => None,
+            };
+            self.state = match active.or_else(|| waiting.pop_front().map(|waiter| self.start_backup(ctx, waiter))) {
+                Some(active) => State::Active { active, waiting },
+                None => State::Idle,
+            };
+        }
 
-                while let Some(waiter) = waiting.pop_front() {
-                    if let Ok(active_transfer) = self.start_backup(waiter).await {
-                        *active = active_transfer;
-                        self.state = state;
-                        return;
-                    }
-                }
+        fn wake_host(&self, ctx: &BcContext<'_, Self>, then: AfterWake) -> Active {
+            let repository = Arc::clone(self.repository.get());
+            let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+                repository.wake_host().await.into()
+            });
+            Active::Waking {
+                task,
+                then,
+                prune_pending: false,
             }
         }
 
-        async fn start_prune(&self, ctx: &BcContext<'_, Self>) -> Option<Active> {
+        fn start_prune(&self, ctx: &BcContext<'_, Self>) -> Option<Active> {
+            if clock_defers_pruning(None, ctx.log()) {
+                return None;
+            }
             let observation = start_observation(self.container_id, ObservableEvent::ContainerPrune).await;
             let repository = self.repository.get();
             let rules = repository
@@ -178,17 +210,36 @@ mod container {
                 })
                 .collect::<Vec<_>>();
 
-            let forgets = evals
-                .iter()
-                .flat_map(|(_, eval)| eval.drop_snapshots.iter().cloned())
-                .collect::<Vec<_>>();
-
-            if forgets.is_empty() {
+            if evals.iter().all(|(_, eval)| eval.drop_snapshots.is_empty()) {
                 observation.succeeded();
                 return None;
             }
 
-            let forget = repository.forget(&forgets);
+            let forgets = evals
+                .into_iter()
+                .map(|(id, eval)| (id, eval.drop_snapshots.into_iter().map(|s| s.datetime).collect()))
+                .collect();
+            Some(self.wake_host(ctx, AfterWake::Prune { forgets, observation }))
+        }
+
+        async fn continue_prune(
+            &self, ctx: &BcContext<'_, Self>, forgets: Vec<(EntityId, HashSet<DateTime<Utc>>)>,
+            observation: StartedObservation,
+        ) -> Option<Active> {
+            let repository = self.repository.get();
+            let snapshots = forgets
+                .iter()
+                .flat_map(|(dataset_id, datetimes)| {
+                    self.snapshots
+                        .get(dataset_id)
+                        .into_iter()
+                        .flatten()
+                        .filter(move |s| datetimes.contains(&s.datetime))
+                })
+                .collect::<Vec<_>>();
+
+            // create forget process
+            let forget = repository.forget(&snapshots);
 
             // create prune process
             let prune = repository.prune();
@@ -199,18 +250,33 @@ mod container {
                 .await
                 .context("failed to start prune actor");
             log_result(ctx.log(), &actor_result);
-            actor_result
-                .map(|actor| Active::Prune {
-                    actor,
-                    forgets: evals
-                        .into_iter()
-                        .map(|(id, eval)| (id, eval.drop_snapshots.into_iter().map(|s| s.datetime).collect()))
-                        .collect(),
-                })
-                .ok()
+            actor_result.map(|actor| Active::Prune { actor, forgets }).ok()
+        }
+
+        fn start_backup(&self, ctx: &BcContext<'_, Self>, msg: GetBackupMessage) -> Active {
+            self.wake_host(ctx, AfterWake::Backup(msg))
+        }
+
+        /// The backup, or why there is none, goes to the transfer. None when the transfer is gone.
+        async fn continue_backup(
+            &self, msg: GetBackupMessage, woken: Result<()>, prune_pending: bool,
+        ) -> Option<Active> {
+            let target = msg.target.clone();
+            let dataset_id = msg.source_dataset_id;
+            let backup = match woken.context("backup target host unavailable") {
+                Ok(()) => self.prepare_backup(msg).await,
+                Err(e) => Err(e),
+            };
+            let addr = target.upgrade()?;
+            let _ = addr.send(BackupReadyMessage(backup));
+            Some(Active::Transfer {
+                dataset_id,
+                prune_pending,
+                actor: addr.downgrade(),
+            })
         }
 
-        async fn start_backup(&self, msg: GetBackupMessage) -> Result<Active> {
+        async fn prepare_backup(&self, msg: GetBackupMessage) -> Result<ResticBackup> {
             let bind_path = {
                 let mut p = runtime_dir();
                 p.push("restic_bind");
@@ -233,14 +299,7 @@ mod container {
                 )
             }
 
-            let snapshot_backup = repository.backup(bind_path, msg.source_dataset_id, msg.source_snapshot_handle);
-            let addr = msg.target.upgrade().context("transfer is no longer alive")?;
-            let _ = addr.send(BackupReadyMessage(Ok(snapshot_backup)));
-            Ok(Active::Transfer {
-                dataset_id: msg.source_dataset_id,
-                prune_pending: false,
-                actor: addr.downgrade(),
-            })
+            Ok(repository.backup(bind_path, msg.source_dataset_id, msg.source_snapshot_handle))
         }
     }
 
@@ -249,6 +308,7 @@ mod container {
         async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
             if let RepositoryState::Pending(model) = &self.repository {
                 let repository = ResticRepository::validate(model.clone()).map(Arc::new)?;
+                repository.wake_host().await?;
                 self.snapshots = group_by(repository.snapshots().await?, |s| &s.dataset_id);
                 trace!(
                     ctx.log(),
@@ -280,10 +340,18 @@ mod container {
 
         async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
             match self.state.take() {
-                State::Active { active, waiting } => {
+                State::Active { active, mut waiting } => {
                     let maybe_actor: Option<BoxBcAddr> = match active {
                         Active::Transfer { actor, .. } => actor.upgrade().map(|a| a.into()),
                         Active::Prune { actor, .. } => Some(actor.into()),
+                        Active::Waking { task, then, .. } => {
+                            task.abort();
+                            match then {
+                                AfterWake::Prune { observation, .. } => observation.cancelled(),
+                                AfterWake::Backup(waiter) => waiting.push_front(waiter),
+                            }
+                            None
+                        }
                     };
                     if let Some(mut actor) = maybe_actor {
                         let _ = actor.stop();
@@ -307,39 +375,43 @@ mod container {
         async fn handle(
             &mut self, _ctx: BcContext<'_, Self>, msg: GetContainerSnapshotsMessage,
         ) -> ContainerSnapshotsResponse {
-            ContainerSnapshotsResponse {
-                snapshots: self
-                    .snapshots
-                    .get(&msg.source_dataset_id)
-                    .map(|v| v.iter().map(|s| s.into()).collect())
-                    .unwrap_or_default(),
-            }
+            let (snapshots, total) = msg
+                .query
+                .select(self.snapshots.get(&msg.source_dataset_id).into_iter().flatten());
+            ContainerSnapshotsResponse { snapshots, total }
         }
     }
 
     #[async_trait::async_trait]
     impl BcHandler<GetBackupMessage> for ResticContainerActor {
-        async fn handle(&mut self, _ctx: BcContext<'_, Self>, msg: GetBackupMessage) -> Result<()> {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: GetBackupMessage) -> Result<()> {
             match &mut self.state {
                 State::Active { waiting, .. } => {
                     waiting.push_back(msg);
                     Ok(())
                 }
-                State::Idle => match self.start_backup(msg).await {
-                    Ok(active) => {
-                        self.state = State::Active {
-                            active,
-                            waiting: Default::default(),
-                        };
-                        Ok(())
-                    }
-                    Err(e) => Err(e),
-                },
+                State::Idle => {
+                    self.state = State::Active {
+                        active: self.start_backup(&ctx, msg),
+                        waiting: Default::default(),
+                    };
+                    Ok(())
+                }
                 State::Faulted => Err(anyhow!("actor faulted")),
             }
         }
     }
 
+    #[async_trait::async_trait]
+    impl BcHandler<CheckHostMessage> for ResticContainerActor {
+        async fn handle(
+            &mut self, _ctx: BcContext<'_, Self>, _msg: CheckHostMessage,
+        ) -> BoxFuture<'static, Result<()>> {
+            let repository = Arc::clone(self.repository.get());
+            async move { repository.check_host().await }.boxed()
+        }
+    }
+
     #[async_trait::async_trait]
     impl BcHandler<PruneMessage> for ResticContainerActor {
         async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: PruneMessage) {
@@ -347,19 +419,35 @@ mod container {
                 State::Active {
                     active: Active::Transfer { prune_pending, .. },
                     ..
+                }
+                | State::Active {
+                    active:
+                        Active::Waking {
+                            then: AfterWake::Backup(_),
+                            prune_pending,
+                            ..
+                        },
+                    ..
                 } => {
                     *prune_pending = true;
                 }
                 State::Active {
                     active: Active::Prune { .. },
                     ..
+                }
+                | State::Active {
+                    active:
+                        Active::Waking {
+                            then: AfterWake::Prune { .. },
+                            ..
+                        },
+                    ..
                 } => {
                     info!(ctx.log(), "prune triggered, but already pruning");
                 }
                 State::Idle => {
                     self.state = self
                         .start_prune(&ctx)
-                        .await
                         .map(|active| State::Active {
                             active,
                             waiting: Default::default(),
@@ -374,27 +462,25 @@ mod container {
     #[async_trait::async_trait]
     impl BcHandler<ParentTransferComplete> for ResticContainerActor {
         async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: ParentTransferComplete) {
-            match &mut self.state {
+            match self.state.take() {
                 State::Active {
-                    active: Active::Transfer { dataset_id, .. },
-                    ..
+                    active:
+                        Active::Transfer {
+                            dataset_id,
+                            prune_pending,
+                            ..
+                        },
+                    waiting,
                 } => {
                     if let Some(snapshot) = msg.0 {
                         info!(ctx.log(), "snapshot received"; "dataset_id" => %dataset_id, "time" => %snapshot.datetime);
-                        self.snapshots.entry(*dataset_id).or_default().push(snapshot);
+                        self.snapshots.entry(dataset_id).or_default().push(snapshot);
                     }
 
-                    self.process_waiting(&ctx).await;
-                }
-                State::Active {
-                    active: Active::Prune { .. },
-                    ..
-                }
-                | State::Idle => {
-                    ctx.stop(None);
-                    self.state = State::Faulted;
+                    self.process_waiting(&ctx, prune_pending, waiting);
                 }
                 State::Faulted => {}
+                _ => ctx.stop(None),
             }
         }
     }
@@ -402,47 +488,76 @@ mod container {
     #[async_trait::async_trait]
     impl BcHandler<PruneCompleteMessage> for ResticContainerActor {
         async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PruneCompleteMessage) {
-            match &mut self.state {
+            match self.state.take() {
                 State::Active {
                     active: Active::Prune { forgets, .. },
-                    ..
+                    waiting,
                 } => {
                     let PruneCompleteMessage(forgot) = msg;
                     if forgot {
                         for (dataset_id, snapshots) in forgets {
-                            if let Some(cache) = self.snapshots.get_mut(dataset_id) {
-                                clear_deleted(cache, mem::take(snapshots));
+                            if let Some(cache) = self.snapshots.get_mut(&dataset_id) {
+                                clear_deleted(cache, snapshots);
                             }
                         }
                     }
 
-                    self.process_waiting(&ctx).await;
+                    self.process_waiting(&ctx, false, waiting);
                 }
+                State::Faulted => {}
+                _ => ctx.stop(None),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BcHandler<WakeWorkerCompleteMessage> for ResticContainerActor {
+        async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: WakeWorkerCompleteMessage) {
+            let (then, prune_pending, waiting) = match self.state.take() {
                 State::Active {
-                    active: Active::Transfer { .. },
-                    ..
-                }
-                | State::Idle => {
+                    active: Active::Waking {
+                        then, prune_pending, ..
+                    },
+                    waiting,
+                } => (then, prune_pending, waiting),
+                State::Faulted => return,
+                _ => {
                     ctx.stop(None);
-                    self.state = State::Faulted;
+                    return;
                 }
-                State::Faulted => {}
+            };
+
+            let active = match (then, msg.0) {
+                (AfterWake::Prune { forgets, observation }, Ok(())) => {
+                    self.continue_prune(&ctx, forgets, observation).await
+                }
+                (AfterWake::Prune { observation, .. }, Err(e)) => {
+                    error!(ctx.log(), "prune target host unavailable: {:#}", e);
+                    observation.failed(format!("{:#}", e));
+                    None
+                }
+                (AfterWake::Backup(waiter), woken) => self.continue_backup(waiter, woken, prune_pending).await,
+            };
+            match active {
+                Some(active) => self.state = State::Active { active, waiting },
+                None => self.process_waiting(&ctx, prune_pending, waiting),
             }
         }
     }
 
     #[async_trait::async_trait]
     impl BcHandler<GetActorStatusMessage> for ResticContainerActor {
-        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
+        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
             match &self.state {
-                State::Active { active, .. } => match active {
-                    Active::Transfer { .. } => "transferring",
-                    Active::Prune { .. } => "pruning",
-                },
-                State::Idle => "idle",
-                State::Faulted => "faulted",
+                State::Active { active, waiting } => match active {
+                    Active::Transfer { .. } => ActorStatus::active("backup"),
+                    Active::Prune { .. } => ActorStatus::active("prune"),
+                    Active::Waking { .. } => ActorStatus::active("waking repository host"),
+                }
+                .with_queue_depth(waiting.len()),
+                State::Idle => ActorStatus::idle(),
+                State::Faulted => ActorStatus::faulted("container faulted"),
             }
-            .into()
         }
     }
 }
@@ -626,8 +741,8 @@ mod transfer {
 
     #[async_trait::async_trait]
     impl BcHandler<GetActorStatusMessage> for ResticTransferActor {
-        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-            String::from("ok")
+        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+            ActorStatus::active("backup")
         }
     }
 }
@@ -771,8 +886,8 @@ mod prune {
 
     #[async_trait::async_trait]
     impl BcHandler<GetActorStatusMessage> for ResticPruneActor {
-        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-            String::from("ok")
+        async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+            ActorStatus::active("prune")
         }
     }
 }
@@ -1,65 +1,141 @@
-use crate::xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState};
-use anyhow::Result;
+use crate::{
+    grpc,
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+};
+use anyhow::{Context, Result};
 use futures_util::{FutureExt, TryFutureExt};
-use libblkcapt::runtime_dir;
-use slog::Logger;
-use tokio::{net::UnixListener, sync::oneshot, task::JoinHandle};
+use hyper::{
+    server::{accept, Server},
+    service::make_service_fn,
+};
+use libblkcapt::{
+    core::{
+        browse::{find_snapshot_by_uuid, list_snapshot_dir},
+        parse_snapshot_timestamp,
+        system::{
+            ConfirmationChallenge, DeletedSnapshotsResponse, NetworkPauseResponse, ReloadQuery,
+            SnapshotCreatedResponse, SnapshotFilesQuery, SnapshotFilesResponse, SnapshotRequestQuery, StatusQuery,
+            CONFIRMATION_HEADER,
+        },
+    },
+    model::{audit::AuditRecord, storage, EntityId},
+    sys::polkit::{check_authorization, ActionClass, Authorization, Subject},
+};
+use slog::{error, info, warn, Logger};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fs,
+    os::unix::fs::PermissionsExt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::oneshot,
+    task::JoinHandle,
+};
 use tokio_stream::wrappers::UnixListenerStream;
-use warp::{Filter, Rejection};
+use uuid::Uuid;
+use warp::{
+    http::{Method, StatusCode},
+    path::FullPath,
+    reject::Reject,
+    Filter, Rejection, Reply,
+};
+use xactor::WeakAddr;
 
-use super::intel::{GetStateMessage, IntelActor};
+use super::{
+    captain::{
+        CaptainActor, DeleteContainerDataMessage, EmergencyMessage, GetDashboardMessage, PreUpgradeSnapshotMessage,
+        PruneArchiveMessage, PruneDatasetMessage, RefreshEntitySnapshotsMessage, ReloadMessage, RollbackDatasetMessage,
+        RunSyncMessage, SnapshotDatasetMessage,
+    },
+    intel::{GetStateMessage, IntelActor},
+    sync::set_network_paused,
+};
+
+/// Destructive requests take effect only when repeated with the issued token within this window.
+const CONFIRMATION_WINDOW: Duration = Duration::from_secs(60);
 
 pub struct ServerActor {
+    captain: WeakAddr<BcActor<CaptainActor>>,
     server: Option<(JoinHandle<()>, oneshot::Sender<()>)>,
+    grpc: Option<(JoinHandle<()>, oneshot::Sender<()>)>,
 }
 
 impl ServerActor {
-    pub fn new(log: &Logger) -> BcActor<Self> {
-        BcActor::new(Self { server: None }, log)
+    pub fn new(captain: WeakAddr<BcActor<CaptainActor>>, log: &Logger) -> BcActor<Self> {
+        BcActor::new(
+            Self {
+                captain,
+                server: None,
+                grpc: None,
+            },
+            log,
+        )
     }
 }
 
 #[async_trait::async_trait]
 impl BcActorCtrl for ServerActor {
-    async fn started(&mut self, _ctx: BcContext<'_, Self>) -> Result<()> {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
         let (sender, receiver) = oneshot::channel::<()>();
         let signal = receiver.map(|_| ());
 
-        let runtime_dir = runtime_dir();
-        std::fs::create_dir_all(&runtime_dir)?;
+        let socket_path = &storage::worker_config().socket_path;
+        if let Some(socket_dir) = socket_path.parent() {
+            std::fs::create_dir_all(socket_dir)?;
+        }
 
-        let socket_path = {
-            let mut path = runtime_dir;
-            path.push("daemon.sock");
-            path
-        };
         if socket_path.exists() {
             std::fs::remove_file(&socket_path)?;
         }
         let listener = UnixListener::bind(socket_path)?;
+        // Anyone may connect, each request is authorized by its API token or through polkit by the identity of the peer.
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(0o666))?;
+
+        let log = ctx.log().clone();
+        let captain = self.captain.clone();
         let handle = tokio::spawn(async move {
             let incoming = UnixListenerStream::new(listener);
-
-            let routes = warp::any().and_then(|| async {
-                let addr = IntelActor::addr();
-                let state = addr
-                    .call(GetStateMessage)
-                    .and_then(|fut| fut.map(Ok))
-                    .await
-                    .map_err(|_| warp::reject())?;
-                Ok::<_, Rejection>(warp::reply::json(&state))
+            let service_log = log.clone();
+            let confirmations = Arc::new(PendingConfirmations::default());
+            let make_service = make_service_fn(move |stream: &UnixStream| {
+                let filter = routes(
+                    peer_subject(stream),
+                    captain.clone(),
+                    confirmations.clone(),
+                    service_log.clone(),
+                );
+                async move { Ok::<_, Infallible>(warp::service(filter)) }
             });
 
-            warp::serve(routes)
-                .serve_incoming_with_graceful_shutdown(incoming, signal)
-                .await;
+            let server = Server::builder(accept::from_stream(incoming))
+                .serve(make_service)
+                .with_graceful_shutdown(signal);
+            if let Err(e) = server.await {
+                error!(log, "control socket server failed"; "error" => %e);
+            }
         });
         self.server = Some((handle, sender));
+
+        if let Some(address) = storage::worker_config().grpc_address {
+            let (sender, receiver) = oneshot::channel::<()>();
+            let log = ctx.log().clone();
+            let captain = self.captain.clone();
+            let handle = tokio::spawn(async move {
+                if let Err(e) = grpc::serve(address, captain, receiver.map(|_| ()), log.clone()).await {
+                    error!(log, "grpc management server failed"; "error" => %e);
+                }
+            });
+            self.grpc = Some((handle, sender));
+        }
         Ok(())
     }
 
     async fn stopped(&mut self, _ctx: BcContext<'_, Self>) -> TerminalState {
-        if let Some((handle, sender)) = self.server.take() {
+        for (handle, sender) in self.server.take().into_iter().chain(self.grpc.take()) {
             if sender.send(()).is_ok() {
                 let _ = handle.await;
             }
@@ -71,7 +147,539 @@ impl BcActorCtrl for ServerActor {
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for ServerActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("listening")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        ActorStatus::idle()
+    }
+}
+
+#[derive(Debug)]
+struct NotAuthorized;
+
+impl Reject for NotAuthorized {}
+
+#[derive(Debug)]
+struct ConfirmationRequired(ConfirmationChallenge);
+
+impl Reject for ConfirmationRequired {}
+
+#[derive(Debug)]
+struct ConfirmationInvalid;
+
+impl Reject for ConfirmationInvalid {}
+
+#[derive(Debug)]
+struct OperationFailed(String);
+
+impl Reject for OperationFailed {}
+
+/// The authorized identity behind a request.
+#[derive(Debug, Clone)]
+struct Caller {
+    subject: Option<Subject>,
+    token: Option<String>,
+}
+
+impl Caller {
+    /// Record a change the caller made. Failing to record it doesn't undo the change, so it's only logged.
+    fn audit(self, log: &Logger, action: &str, detail: String) {
+        let record = AuditRecord::for_peer(self.subject.map(|s| s.uid), self.token, action, detail);
+        if let Err(e) = storage::append_audit(&record) {
+            warn!(log, "failed to record audit entry"; "error" => %e);
+        }
+    }
+}
+
+/// Confirmation tokens issued for destructive requests. Each is good for a single repeat of the request it was
+/// issued for.
+#[derive(Default)]
+struct PendingConfirmations(Mutex<HashMap<String, (String, Instant)>>);
+
+impl PendingConfirmations {
+    fn issue(&self, operation: String) -> ConfirmationChallenge {
+        let token = Uuid::new_v4().to_simple().to_string();
+        let mut pending = self.0.lock().expect("confirmations lock is never poisoned");
+        pending.retain(|_, (_, issued)| issued.elapsed() < CONFIRMATION_WINDOW);
+        pending.insert(token.clone(), (operation.clone(), Instant::now()));
+        ConfirmationChallenge {
+            token,
+            operation,
+            expires_in_secs: CONFIRMATION_WINDOW.as_secs(),
+        }
+    }
+
+    fn redeem(&self, token: &str, operation: &str) -> bool {
+        let mut pending = self.0.lock().expect("confirmations lock is never poisoned");
+        match pending.remove(token) {
+            Some((issued_for, issued)) => issued_for == operation && issued.elapsed() < CONFIRMATION_WINDOW,
+            None => false,
+        }
+    }
+}
+
+fn peer_subject(stream: &UnixStream) -> Option<Subject> {
+    let cred = stream.peer_cred().ok()?;
+    Some(Subject {
+        pid: cred.pid()?,
+        uid: cred.uid(),
+    })
+}
+
+fn routes(
+    subject: Option<Subject>, captain: WeakAddr<BcActor<CaptainActor>>, confirmations: Arc<PendingConfirmations>,
+    log: Logger,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone {
+    let status = warp::path::end()
+        .and(warp::get())
+        .and(authorized(ActionClass::ReadStatus, subject, log.clone()))
+        .and(warp::query::<StatusQuery>())
+        .and_then(|_caller: Caller, query: StatusQuery| async move {
+            let addr = IntelActor::addr();
+            let state = addr
+                .call(GetStateMessage(query))
+                .and_then(|fut| fut.map(Ok))
+                .await
+                .map_err(|_| warp::reject())?;
+            Ok::<_, Rejection>(warp::reply::json(&state))
+        });
+
+    let dashboard_captain = captain.clone();
+    let dashboard = warp::path!("dashboard")
+        .and(warp::get())
+        .and(authorized(ActionClass::ReadStatus, subject, log.clone()))
+        .and_then(move |_caller: Caller| {
+            let captain = dashboard_captain.clone();
+            async move {
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                let dashboard = captain
+                    .call(GetDashboardMessage)
+                    .await
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+                Ok::<_, Rejection>(warp::reply::json(&dashboard))
+            }
+        });
+
+    let refresh_captain = captain.clone();
+    let refresh_snapshots = warp::path!("datasets" / EntityId / "refresh")
+        .or(warp::path!("containers" / EntityId / "refresh"))
+        .unify()
+        .and(warp::post())
+        .and(authorized(ActionClass::ManageJobs, subject, log.clone()))
+        .and_then(move |entity_id: EntityId, _caller: Caller| {
+            let captain = refresh_captain.clone();
+            async move {
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                let changes = captain
+                    .call(RefreshEntitySnapshotsMessage(entity_id))
+                    .await
+                    .and_then(|r| r)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+                Ok::<_, Rejection>(warp::reply::json(&changes))
+            }
+        });
+
+    let snapshot_captain = captain.clone();
+    let snapshot_log = log.clone();
+    let take_snapshot = warp::path!("datasets" / EntityId / "snapshot")
+        .and(warp::post())
+        .and(warp::query::<SnapshotRequestQuery>())
+        .and(authorized(ActionClass::ManageJobs, subject, log.clone()))
+        .and_then(
+            move |dataset_id: EntityId, query: SnapshotRequestQuery, caller: Caller| {
+                let captain = snapshot_captain.clone();
+                let log = snapshot_log.clone();
+                async move {
+                    let captain = captain
+                        .upgrade()
+                        .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                    let datetime = match query.pre_upgrade {
+                        true => captain.call(PreUpgradeSnapshotMessage(dataset_id)).await,
+                        false => captain.call(SnapshotDatasetMessage(dataset_id)).await,
+                    };
+                    let datetime = datetime
+                        .and_then(|r| r)
+                        .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                    caller.audit(
+                        &log,
+                        "dataset snapshot",
+                        format!("dataset: {}, snapshot: {}", dataset_id, datetime),
+                    );
+                    Ok::<_, Rejection>(warp::reply::json(&SnapshotCreatedResponse { datetime }))
+                }
+            },
+        );
+
+    let job_captain = captain.clone();
+    let job_log = log.clone();
+    let start_job = warp::path!("datasets" / EntityId / "prune")
+        .map(|id| (id, JobRequest::DatasetPrune))
+        .or(warp::path!("syncs" / EntityId / "run").map(|id| (id, JobRequest::SyncRun)))
+        .unify()
+        .or(warp::path!("archives" / EntityId / "prune").map(|id| (id, JobRequest::ArchivePrune)))
+        .unify()
+        .and(warp::post())
+        .and(authorized(ActionClass::ManageJobs, subject, log.clone()))
+        .and_then(move |(entity_id, job): (EntityId, JobRequest), caller: Caller| {
+            let captain = job_captain.clone();
+            let log = job_log.clone();
+            async move {
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                let result = match job {
+                    JobRequest::DatasetPrune => captain.call(PruneDatasetMessage(entity_id)).await,
+                    JobRequest::SyncRun => captain.call(RunSyncMessage(entity_id)).await,
+                    JobRequest::ArchivePrune => captain.call(PruneArchiveMessage(entity_id)).await,
+                };
+                result
+                    .and_then(|r| r)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                caller.audit(&log, job.action(), entity_id.to_string());
+                Ok::<_, Rejection>(warp::reply::with_status(warp::reply(), StatusCode::ACCEPTED))
+            }
+        });
+
+    let network_log = log.clone();
+    let network_pause = warp::path!("network" / "pause")
+        .map(|| true)
+        .or(warp::path!("network" / "resume").map(|| false))
+        .unify()
+        .and(warp::post())
+        .and(authorized(ActionClass::ManageJobs, subject, log.clone()))
+        .and_then(move |paused: bool, caller: Caller| {
+            let log = network_log.clone();
+            async move {
+                set_network_paused(paused)
+                    .await
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                let action = if paused { "net pause" } else { "net resume" };
+                caller.audit(&log, action, String::new());
+                Ok::<_, Rejection>(warp::reply::json(&NetworkPauseResponse { paused }))
+            }
+        });
+
+    let emergency_captain = captain.clone();
+    let emergency_log = log.clone();
+    let power_emergency = warp::path!("power" / "emergency")
+        .and(warp::post())
+        .and(authorized(ActionClass::ManageJobs, subject, log.clone()))
+        .and_then(move |caller: Caller| {
+            let captain = emergency_captain.clone();
+            let log = emergency_log.clone();
+            async move {
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                let response = captain
+                    .call(EmergencyMessage)
+                    .await
+                    .and_then(|r| r)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                caller.audit(
+                    &log,
+                    "power emergency",
+                    format!("datasets: {}, syncs: {}", response.datasets, response.syncs),
+                );
+                Ok::<_, Rejection>(warp::reply::json(&response))
+            }
+        });
+
+    let reload_captain = captain.clone();
+    let reload_log = log.clone();
+    let reload = warp::path!("reload")
+        .and(warp::post())
+        .and(warp::query::<ReloadQuery>())
+        .and(authorized(ActionClass::ManageJobs, subject, log.clone()))
+        .and_then(move |query: ReloadQuery, caller: Caller| {
+            let captain = reload_captain.clone();
+            let log = reload_log.clone();
+            async move {
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                captain
+                    .call(ReloadMessage)
+                    .await
+                    .and_then(|r| r)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                if !query.recorded {
+                    caller.audit(&log, "service reload", String::new());
+                }
+                Ok::<_, Rejection>(warp::reply())
+            }
+        });
+
+    let snapshot_files = warp::path!("snapshots" / Uuid / "files")
+        .and(warp::get())
+        .and(authorized(ActionClass::BrowseData, subject, log.clone()))
+        .and(warp::query::<SnapshotFilesQuery>())
+        .and_then(|uuid: Uuid, _caller: Caller, query: SnapshotFilesQuery| async move {
+            let listing = tokio::task::spawn_blocking(move || list_snapshot_files(uuid, query))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r)
+                .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+            Ok::<_, Rejection>(warp::reply::json(&listing))
+        });
+
+    let rollback_captain = captain.clone();
+    let rollback_log = log.clone();
+    let rollback_dataset = warp::path!("datasets" / EntityId / "rollback" / String)
+        .and(warp::post())
+        .and(authorized(ActionClass::Destructive, subject, log.clone()))
+        .and(confirmed(confirmations.clone()))
+        .and_then(move |dataset_id: EntityId, snapshot: String, caller: Caller| {
+            let captain = rollback_captain.clone();
+            let log = rollback_log.clone();
+            async move {
+                let snapshot = parse_snapshot_timestamp(&snapshot)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                let response = captain
+                    .call(RollbackDatasetMessage { dataset_id, snapshot })
+                    .await
+                    .and_then(|r| r)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                caller.audit(
+                    &log,
+                    "dataset rollback",
+                    format!(
+                        "dataset: {}, snapshot: {}, safety snapshot: {}",
+                        dataset_id, snapshot, response.safety_snapshot
+                    ),
+                );
+                Ok::<_, Rejection>(warp::reply::json(&response))
+            }
+        });
+
+    let delete_container_data = warp::path!("containers" / EntityId / "datasets" / EntityId)
+        .and(warp::delete())
+        .and(authorized(ActionClass::Destructive, subject, log.clone()))
+        .and(confirmed(confirmations))
+        .and_then(move |container_id: EntityId, dataset_id: EntityId, caller: Caller| {
+            let captain = captain.clone();
+            let log = log.clone();
+            async move {
+                let captain = captain
+                    .upgrade()
+                    .ok_or_else(|| warp::reject::custom(OperationFailed(String::from("worker is stopping"))))?;
+                let deleted = captain
+                    .call(DeleteContainerDataMessage {
+                        container_id,
+                        dataset_id,
+                    })
+                    .await
+                    .and_then(|r| r)
+                    .map_err(|e| warp::reject::custom(OperationFailed(format!("{:#}", e))))?;
+
+                caller.audit(
+                    &log,
+                    "container delete-data",
+                    format!(
+                        "container: {}, dataset: {}, deleted: {}",
+                        container_id, dataset_id, deleted
+                    ),
+                );
+                Ok::<_, Rejection>(warp::reply::json(&DeletedSnapshotsResponse { deleted }))
+            }
+        });
+
+    status
+        .or(dashboard)
+        .or(refresh_snapshots)
+        .or(take_snapshot)
+        .or(start_job)
+        .or(network_pause)
+        .or(power_emergency)
+        .or(reload)
+        .or(snapshot_files)
+        .or(rollback_dataset)
+        .or(delete_container_data)
+        .recover(handle_rejection)
+}
+
+/// Jobs started on request that run in the background, so there's nothing to return but that they started.
+#[derive(Clone, Copy)]
+enum JobRequest {
+    DatasetPrune,
+    SyncRun,
+    ArchivePrune,
+}
+
+impl JobRequest {
+    fn action(self) -> &'static str {
+        match self {
+            JobRequest::DatasetPrune => "dataset prune",
+            JobRequest::SyncRun => "sync run",
+            JobRequest::ArchivePrune => "archive gc",
+        }
+    }
+}
+
+fn list_snapshot_files(uuid: Uuid, query: SnapshotFilesQuery) -> Result<SnapshotFilesResponse> {
+    let entities = storage::load_entity_config();
+    let (_, snapshot) = find_snapshot_by_uuid(&entities.btrfs_pools, uuid)?
+        .with_context(|| format!("no snapshot found with UUID {}", uuid))?;
+    let path = query.path.unwrap_or_default();
+    let entries = list_snapshot_dir(snapshot.as_ref(), &path)?;
+    Ok(SnapshotFilesResponse {
+        snapshot: snapshot.to_string(),
+        path,
+        entries,
+    })
+}
+
+fn authorized(
+    action: ActionClass, subject: Option<Subject>, log: Logger,
+) -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization").and_then(move |authorization: Option<String>| {
+        let log = log.clone();
+        async move {
+            // A presented token is authoritative, polkit is only consulted for requests without one.
+            let result = match (authorization, subject) {
+                (Some(authorization), _) => authorize_token(action, &authorization),
+                (None, Some(subject)) => tokio::task::spawn_blocking(move || check_authorization(action, subject))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|r| r)
+                    .map(|a| (a, None)),
+                (None, None) => Ok((Authorization::NotAuthorized, None)),
+            };
+            match result {
+                Ok((Authorization::Authorized, token)) => Ok(Caller { subject, token }),
+                Ok((Authorization::NotAuthorized, _)) => {
+                    info!(log, "request denied"; "action" => action.action_id(), "uid" => subject.map(|s| s.uid), "pid" => subject.map(|s| s.pid));
+                    Err(warp::reject::custom(NotAuthorized))
+                }
+                Err(e) => {
+                    error!(log, "authorization check failed"; "action" => action.action_id(), "error" => %e);
+                    Err(warp::reject::custom(NotAuthorized))
+                }
+            }
+        }
+    })
+}
+
+/// Returns the name of the token when it permits the action.
+pub(crate) fn authorize_token(action: ActionClass, authorization: &str) -> Result<(Authorization, Option<String>)> {
+    let secret = authorization
+        .strip_prefix("Bearer ")
+        .context("unsupported authorization scheme")?;
+    let tokens = storage::load_api_tokens()?;
+    Ok(match tokens.find_by_secret(secret) {
+        Some(token) if token.role.permits(action) => (Authorization::Authorized, Some(token.name.clone())),
+        _ => (Authorization::NotAuthorized, None),
+    })
+}
+
+/// Rejects the first attempt at a request with a confirmation challenge. The request passes when repeated with the
+/// challenge token before it expires.
+fn confirmed(confirmations: Arc<PendingConfirmations>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>(CONFIRMATION_HEADER))
+        .and_then(move |method: Method, path: FullPath, token: Option<String>| {
+            let confirmations = confirmations.clone();
+            async move {
+                let operation = format!("{} {}", method, path.as_str());
+                match token {
+                    Some(token) if confirmations.redeem(&token, &operation) => Ok(()),
+                    Some(_) => Err(warp::reject::custom(ConfirmationInvalid)),
+                    None => Err(warp::reject::custom(ConfirmationRequired(
+                        confirmations.issue(operation),
+                    ))),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<Box<dyn Reply>, Infallible> {
+    if rejection.find::<NotAuthorized>().is_some() {
+        Ok(Box::new(warp::reply::with_status(
+            "not authorized",
+            StatusCode::FORBIDDEN,
+        )))
+    } else if let Some(ConfirmationRequired(challenge)) = rejection.find::<ConfirmationRequired>() {
+        Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(challenge),
+            StatusCode::PRECONDITION_REQUIRED,
+        )))
+    } else if rejection.find::<ConfirmationInvalid>().is_some() {
+        Ok(Box::new(warp::reply::with_status(
+            "confirmation token is invalid or expired",
+            StatusCode::PRECONDITION_FAILED,
+        )))
+    } else if let Some(OperationFailed(message)) = rejection.find::<OperationFailed>() {
+        Ok(Box::new(warp::reply::with_status(
+            message.clone(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )))
+    } else if rejection.is_not_found() {
+        Ok(Box::new(warp::reply::with_status("not found", StatusCode::NOT_FOUND)))
+    } else {
+        Ok(Box::new(warp::reply::with_status(
+            "internal error",
+            StatusCode::INTERNAL_SERVER_ERROR,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATION: &str = "POST /datasets/0/rollback/2020-08-23T17-20-10Z";
+
+    #[test]
+    fn confirmation_token_is_good_for_one_repeat() {
+        let confirmations = PendingConfirmations::default();
+        let challenge = confirmations.issue(OPERATION.to_owned());
+        assert_eq!(challenge.operation, OPERATION);
+        assert_eq!(challenge.expires_in_secs, CONFIRMATION_WINDOW.as_secs());
+
+        assert!(confirmations.redeem(&challenge.token, OPERATION));
+        assert!(!confirmations.redeem(&challenge.token, OPERATION));
+    }
+
+    #[test]
+    fn confirmation_token_only_confirms_its_operation() {
+        let confirmations = PendingConfirmations::default();
+        let challenge = confirmations.issue(OPERATION.to_owned());
+
+        assert!(!confirmations.redeem(&challenge.token, "DELETE /containers/0/datasets/0"));
+        // a mismatch spends the token
+        assert!(!confirmations.redeem(&challenge.token, OPERATION));
+        assert!(!confirmations.redeem("unknown", OPERATION));
+    }
+
+    #[test]
+    fn confirmation_token_expires() {
+        let confirmations = PendingConfirmations::default();
+        let challenge = confirmations.issue(OPERATION.to_owned());
+        let issued = Instant::now()
+            .checked_sub(CONFIRMATION_WINDOW)
+            .expect("the clock is past the window");
+        {
+            let mut pending = confirmations.0.lock().unwrap();
+            pending.insert(challenge.token.clone(), (OPERATION.to_owned(), issued));
+            pending.insert("stale".to_owned(), (OPERATION.to_owned(), issued));
+        }
+
+        assert!(!confirmations.redeem(&challenge.token, OPERATION));
+        // expired tokens are dropped when the next one is issued
+        confirmations.issue(OPERATION.to_owned());
+        assert_eq!(confirmations.0.lock().unwrap().len(), 1);
     }
 }
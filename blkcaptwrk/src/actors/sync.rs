@@ -1,55 +1,102 @@
 use super::{
+    archive::{ArchiveContainerActor, ArchiveTransferActor, CheckArchiveMessage, GetArchiveUploadMessage},
     container::ContainerActor,
-    container::GetSnapshotReceiverMessage,
+    container::{GetSnapshotReceiverMessage, RestoreTestMessage},
     dataset::DatasetActor,
     dataset::GetDatasetSnapshotsMessage,
-    dataset::{GetSnapshotHolderMessage, GetSnapshotSenderMessage},
+    dataset::{
+        GetSnapshotHolderMessage, GetSnapshotPathMessage, GetSnapshotSenderMessage, SyncPositionMessage,
+        SyncStoppedMessage,
+    },
     observation::{start_observation, ObservableEventMessage, StartedObservation},
-    restic::GetBackupMessage,
+    pool::{
+        GetTransferPermitsMessage, PoolActor, PoolTransferHoldMessage, PoolTransferPermits, PoolTransferReleaseMessage,
+        PoolWakeWindowMessage,
+    },
+    remote::{CheckRemoteHostMessage, RemoteContainerActor},
+    restic::{CheckHostMessage, GetBackupMessage},
     restic::{ResticContainerActor, ResticTransferActor},
-    transfer::TransferActor,
     transfer::TransferComplete,
+    transfer::{RequestEnds, TransferActor, DEFAULT_PROGRESS_INTERVAL},
 };
 use crate::{
-    actorbase::{unhandled_result, ScheduledMessage},
-    snapshots::{find_parent, find_ready, FindMode, GetContainerSnapshotsMessage},
+    actorbase::{
+        battery_defers, log_result, same_model, unhandled_error, unhandled_result, ScheduledMessage,
+        BATTERY_RECHECK_INTERVAL,
+    },
+    snapshots::{find_parent, find_ready, FindMode, GetContainerSnapshotsMessage, SnapshotQuery},
     xactorext::BoxBcAddr,
-    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, Utc};
 use cron::Schedule;
+use futures_util::FutureExt;
 use libblkcapt::{
-    core::{ObservableEventStage, SnapshotHandle},
+    core::{
+        system::{JobOutcome, SyncActivity},
+        ObservableEventStage, SnapshotHandle,
+    },
     model::{
         entities::{ObservableEvent, SnapshotSyncEntity, SnapshotSyncMode},
-        Entity,
+        worker::BatteryJob,
+        Entity, EntityId, SyncTopology,
     },
+    sys::{btrfs::StreamCompression, privilege::running_as_root},
 };
-use slog::{debug, o, trace, Logger};
-use std::{collections::VecDeque, convert::TryInto, time::Duration};
-use xactor::{message, Actor, Addr, Handler};
+use slog::{debug, info, o, trace, warn, Logger};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use uuid::Uuid;
+use xactor::{message, Actor, Addr, Broker, Handler, Service};
 
 pub struct SyncActor {
     dataset: Addr<BcActor<DatasetActor>>,
     container: SyncToContainer,
+    pools: Vec<Addr<BcActor<PoolActor>>>,
     model: SnapshotSyncEntity,
 
     state_mode: SyncModeState,
     state_active_send: Option<ActiveSend>,
+    /// Non-zero while the active send waits for a pool or worker transfer permit.
+    transfer_queued: Arc<AtomicUsize>,
     last_sent: Option<DateTime<Utc>>,
     sync_cycle_schedule: Option<ScheduledMessage>,
+    restore_test_schedule: Option<ScheduledMessage>,
+    target_rpo: Option<Duration>,
+    /// Whether the RPO was violated when it was last observed. Only changes are observed.
+    rpo_violated: Option<bool>,
+    newest_synced: Option<DateTime<Utc>>,
+    last_transfer: Option<JobOutcome>,
+    wake_pools: Vec<EntityId>,
+    cycle_deferred: bool,
+    unreachable: Option<String>,
+    retry_pending: bool,
+    network_paused: bool,
+    /// Send everything up to now, ignoring the battery rules, until the cycle ends.
+    emergency: bool,
 }
 
 struct ActiveSend {
     actor: BoxBcAddr,
     sending_snapshot: DateTime<Utc>,
     active_limit: Option<DateTime<Utc>>,
+    started: DateTime<Utc>,
 }
 
 pub enum SyncToContainer {
     Btrfs(Addr<BcActor<ContainerActor>>),
     Restic(Addr<BcActor<ResticContainerActor>>),
+    Remote(Addr<BcActor<RemoteContainerActor>>),
+    Archive(Addr<BcActor<ArchiveContainerActor>>),
 }
 
 enum SyncModeState {
@@ -73,23 +120,106 @@ fn get_schedule(mode: &SnapshotSyncMode) -> Option<Result<Schedule>> {
     }
 }
 
+/// Sync the snapshots taken up to now. Sent after each snapshot of the dataset, and on request.
 #[message()]
 #[derive(Clone)]
-struct StartSnapshotSyncCycleMessage;
+pub struct StartSnapshotSyncCycleMessage;
 
 #[message()]
 struct RetrySnapshotSyncCycleMessage;
 
+#[message()]
+#[derive(Clone)]
+struct CheckRpoMessage;
+
+#[message()]
+#[derive(Clone)]
+struct StartRestoreTestMessage;
+
+/// Sync the dataset's snapshots up to now without waiting for the schedule, a wake window or mains power. Sent when
+/// the power is about to go. A network pause still holds remote transfers.
+#[message()]
+pub struct EmergencySyncMessage;
+
+/// How far the sync got and how its latest transfer went, for the dashboard.
+#[message(result = "SyncActivity")]
+pub struct GetSyncActivityMessage;
+
+/// Apply a reloaded model without restarting the actor, so a send in flight carries on. Returns false when the
+/// change needs a restart.
+#[message(result = "Result<bool>")]
+pub struct UpdateSyncMessage {
+    pub model: SnapshotSyncEntity,
+    pub target_rpo: Option<Duration>,
+}
+
+/// Fields read when needed rather than only when the actor starts. The sync mode is checked on its own.
+const LIVE_FIELDS: &[&str] = &[
+    "name",
+    "sync_mode",
+    "progress_interval",
+    "compression",
+    "bandwidth_limit",
+    "restore_test_schedule",
+];
+
+/// Whether the sync mode can change without a restart. Only the schedule of a scheduled mode can.
+fn live_mode_change(current: &SnapshotSyncMode, new: &SnapshotSyncMode) -> bool {
+    match (current, new) {
+        (SnapshotSyncMode::AllScheduled(_), SnapshotSyncMode::AllScheduled(_))
+        | (SnapshotSyncMode::LatestScheduled(_), SnapshotSyncMode::LatestScheduled(_)) => true,
+        _ => same_model(current, new, &[]),
+    }
+}
+
+/// Published when remote transfers are paused or resumed.
+#[message()]
+#[derive(Clone)]
+pub struct NetworkPauseMessage(pub bool);
+
+static NETWORK_PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn network_paused() -> bool {
+    NETWORK_PAUSED.load(Ordering::SeqCst)
+}
+
+/// Hold back remote transfers, cancelling those running, until resumed or the worker restarts.
+pub async fn set_network_paused(paused: bool) -> Result<()> {
+    NETWORK_PAUSED.store(paused, Ordering::SeqCst);
+    Broker::from_registry().await?.publish(NetworkPauseMessage(paused))
+}
+
+const RPO_CHECK_INTERVAL: Duration = Duration::from_secs(900);
+const FAILED_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+const UNREACHABLE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+enum RpoStatus {
+    Compliant(Duration),
+    Violated(Option<Duration>),
+}
+
+impl RpoStatus {
+    /// Record this status as the latest one. Returns whether it's a change from the one recorded before.
+    fn record_change(&self, last_violated: &mut Option<bool>) -> bool {
+        let violated = matches!(self, RpoStatus::Violated(_));
+        last_violated.replace(violated) != Some(violated)
+    }
+}
+
 impl SyncActor {
     pub fn new(
-        dataset: Addr<BcActor<DatasetActor>>, container: SyncToContainer, model: SnapshotSyncEntity, log: &Logger,
+        dataset: Addr<BcActor<DatasetActor>>, container: SyncToContainer, pools: Vec<Addr<BcActor<PoolActor>>>,
+        topology: SyncTopology, target_rpo: Option<Duration>, wake_pools: Vec<EntityId>, model: SnapshotSyncEntity,
+        log: &Logger,
     ) -> BcActor<Self> {
+        let sync_id = model.id();
         let dataset_id = model.dataset_id;
         let container_id = model.container_id;
         BcActor::new(
             Self {
                 dataset,
                 container,
+                pools,
                 state_mode: match model.sync_mode {
                     SnapshotSyncMode::AllScheduled(..) => SyncModeState::AllScheduled(None),
                     SnapshotSyncMode::LatestScheduled(..) => SyncModeState::LatestScheduled(Default::default()),
@@ -99,16 +229,85 @@ impl SyncActor {
                     }
                 },
                 state_active_send: None,
+                transfer_queued: Default::default(),
                 sync_cycle_schedule: None,
+                restore_test_schedule: None,
                 last_sent: None,
+                target_rpo,
+                rpo_violated: None,
+                newest_synced: None,
+                last_transfer: None,
+                wake_pools,
+                cycle_deferred: false,
+                unreachable: None,
+                retry_pending: false,
+                network_paused: false,
+                emergency: false,
                 model,
             },
-            &log.new(o!("dataset_id" => dataset_id.to_string(), "container_id" => container_id.to_string())),
+            &log.new(o!(
+                "dataset_id" => dataset_id.to_string(),
+                "container_id" => container_id.to_string(),
+                "topology" => topology.to_string(),
+            )),
         )
+        .observe_lifecycle(sync_id, ObservableEvent::SnapshotSyncWorker)
+    }
+
+    /// Schedules the sync cycles and restore tests of the model, replacing any scheduled before.
+    fn schedule_jobs(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
+        self.sync_cycle_schedule = get_schedule(&self.model.sync_mode).map_or(Ok(None), |s| {
+            s.map(|schedule| {
+                Some(ScheduledMessage::new(
+                    schedule,
+                    "sync_cycle",
+                    StartSnapshotSyncCycleMessage,
+                    ctx,
+                ))
+            })
+        })?;
+
+        self.restore_test_schedule = None;
+        if let Some(schedule) = &self.model.restore_test_schedule {
+            if matches!(self.container, SyncToContainer::Btrfs(_)) {
+                self.restore_test_schedule = Some(ScheduledMessage::new(
+                    schedule.try_into()?,
+                    "restore_test",
+                    StartRestoreTestMessage,
+                    ctx,
+                ));
+            } else {
+                warn!(
+                    ctx.log(),
+                    "restore tests are only supported for btrfs containers, not scheduling them"
+                );
+            }
+        }
+        Ok(())
     }
 
     async fn run_cycle(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
-        let dataset_snapshots = self.get_dataset_snapshots().await?;
+        let result = self.send_next(ctx).await;
+        // the cycle ended unless a transfer is running, whether it sent everything, was deferred or failed
+        if self.state_active_send.is_none() {
+            self.emergency = false;
+        }
+        result
+    }
+
+    async fn send_next(&mut self, ctx: &BcContext<'_, Self>) -> Result<()> {
+        if self.network_paused {
+            debug!(ctx.log(), "sync cycle deferred while the network is paused");
+            self.cycle_deferred = true;
+            return Ok(());
+        }
+        if !self.emergency && battery_defers(BatteryJob::Sync, ctx.log()) {
+            debug!(ctx.log(), "sync cycle deferred while on battery");
+            self.schedule_retry(ctx, BATTERY_RECHECK_INTERVAL);
+            return Ok(());
+        }
+
+        let (dataset_snapshots, pre_restore) = self.get_dataset_snapshots().await?;
         let container_snapshots = self.get_container_snapshots().await?;
 
         let observation = start_observation(self.model.id(), ObservableEvent::SnapshotSync).await;
@@ -148,21 +347,127 @@ impl SyncActor {
             return Ok(());
         };
 
-        let parent = find_parent(to_send, &dataset_snapshots, &container_snapshots);
-
-        let actor = self.start_transfer_actor(to_send, parent, observation, &ctx).await?;
+        if let Err(e) = self.check_target().await {
+            let message = format!("target unreachable: {:#}", e);
+            warn!(ctx.log(), "{}", message);
+            observation.failed(&message);
+            self.requeue(active_limit);
+            self.unreachable = Some(message);
+            self.schedule_retry(ctx, UNREACHABLE_RETRY_INTERVAL);
+            return Ok(());
+        }
+        self.unreachable = None;
+
+        let parent_candidates = dataset_snapshots
+            .iter()
+            .filter(|s| !pre_restore.contains(&s.uuid))
+            .cloned()
+            .collect::<Vec<_>>();
+        let parent = find_parent(to_send, &parent_candidates, &container_snapshots);
+
+        self.hold_pools(ctx);
+        let actor = match self.start_transfer_actor(to_send, parent, observation, &ctx).await {
+            Ok(actor) => actor,
+            Err(e) => {
+                self.release_pools(ctx);
+                return Err(e);
+            }
+        };
         self.state_active_send = Some(ActiveSend {
             actor,
             sending_snapshot: to_send.datetime,
             active_limit,
+            started: Utc::now(),
         });
         Ok(())
     }
 
+    /// Remote targets are probed before a transfer takes the pool holds and transfer permits.
+    async fn check_target(&self) -> Result<()> {
+        match &self.container {
+            SyncToContainer::Btrfs(_) => Ok(()),
+            SyncToContainer::Restic(container) => container.call(CheckHostMessage).await?.await,
+            SyncToContainer::Remote(container) => container.call(CheckRemoteHostMessage).await?,
+            SyncToContainer::Archive(container) => container.call(CheckArchiveMessage).await?,
+        }
+    }
+
+    fn is_remote(&self) -> bool {
+        matches!(
+            self.container,
+            SyncToContainer::Restic(_) | SyncToContainer::Remote(_) | SyncToContainer::Archive(_)
+        )
+    }
+
+    /// Put a sync time that didn't get sent back at the front of the queue.
+    fn requeue(&mut self, active_limit: Option<DateTime<Utc>>) {
+        if let Some(active_limit) = active_limit {
+            match &mut self.state_mode {
+                SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => {
+                    queue.push_front(active_limit);
+                }
+                SyncModeState::AllScheduled(_) | SyncModeState::AllImmediate => {}
+            };
+        }
+    }
+
+    fn schedule_retry(&mut self, ctx: &BcContext<'_, Self>, after: Duration) {
+        if !mem::replace(&mut self.retry_pending, true) {
+            ctx.send_later(RetrySnapshotSyncCycleMessage, after);
+        }
+    }
+
+    fn rpo_status(&self) -> Option<RpoStatus> {
+        self.target_rpo.map(|target| {
+            let age = self
+                .newest_synced
+                .and_then(|newest| (Utc::now() - newest).to_std().ok());
+            match age {
+                Some(age) if age <= target => RpoStatus::Compliant(age),
+                _ => RpoStatus::Violated(age),
+            }
+        })
+    }
+
+    /// Let the dataset know how far this sync got, so it can track its unsynced backlog.
+    fn report_position(&self, ctx: &BcContext<'_, Self>) {
+        log_result(
+            ctx.log(),
+            &self.dataset.send(SyncPositionMessage {
+                sync_id: self.model.id(),
+                newest_synced: self.newest_synced,
+            }),
+        );
+    }
+
+    fn hold_pools(&self, ctx: &BcContext<'_, Self>) {
+        for pool in self.pools.iter() {
+            log_result(ctx.log(), &pool.send(PoolTransferHoldMessage(ctx.address().into())));
+        }
+    }
+
+    fn release_pools(&self, ctx: &BcContext<'_, Self>) {
+        for pool in self.pools.iter() {
+            let _ = pool.send(PoolTransferReleaseMessage(ctx.actor_id()));
+        }
+    }
+
+    async fn pool_transfer_permits(&self) -> Result<Vec<PoolTransferPermits>> {
+        let mut permits = Vec::new();
+        for pool in self.pools.iter() {
+            permits.extend(pool.call(GetTransferPermitsMessage).await?);
+        }
+        // Acquired in a fixed order so two cross pool transfers can't each hold the permit the other needs.
+        permits.sort_by_key(|p| p.pool_id);
+        Ok(permits)
+    }
+
     async fn get_container_snapshots(&self) -> Result<Vec<SnapshotHandle>> {
         match &self.container {
             SyncToContainer::Btrfs(c) => self._get_container_snapshots(c).await,
             SyncToContainer::Restic(c) => self._get_container_snapshots(c).await,
+            SyncToContainer::Remote(c) => self._get_container_snapshots(c).await,
+            SyncToContainer::Archive(c) => self._get_container_snapshots(c).await,
         }
     }
 
@@ -171,13 +476,17 @@ impl SyncActor {
     ) -> Result<Vec<SnapshotHandle>> {
         addr.call(GetContainerSnapshotsMessage {
             source_dataset_id: self.model.dataset_id,
+            query: SnapshotQuery::all(),
         })
         .await
         .map(|r| r.snapshots)
     }
 
-    async fn get_dataset_snapshots(&self) -> Result<Vec<SnapshotHandle>> {
-        self.dataset.call(GetDatasetSnapshotsMessage).await.map(|r| r.snapshots)
+    async fn get_dataset_snapshots(&self) -> Result<(Vec<SnapshotHandle>, Vec<Uuid>)> {
+        self.dataset
+            .call(GetDatasetSnapshotsMessage(SnapshotQuery::all()))
+            .await
+            .map(|r| (r.snapshots, r.pre_restore))
     }
 
     async fn start_transfer_actor(
@@ -186,8 +495,17 @@ impl SyncActor {
     ) -> Result<BoxBcAddr> {
         match &self.container {
             SyncToContainer::Btrfs(container) => {
-                let transfer_actor = TransferActor::new(
+                self.start_send_receive(container, snapshot, parent, observation, ctx)
+                    .await
+            }
+            SyncToContainer::Remote(container) => {
+                self.start_send_receive(container, snapshot, parent, observation, ctx)
+                    .await
+            }
+            SyncToContainer::Restic(container) => {
+                let transfer_actor = ResticTransferActor::new(
                     ctx.address().sender::<TransferComplete>(),
+                    container.clone(),
                     observation,
                     &ctx.log().new(o!("message" => ())),
                 );
@@ -195,7 +513,7 @@ impl SyncActor {
                 let transfer_actor = transfer_actor.start().await?;
 
                 self.dataset
-                    .call(GetSnapshotSenderMessage::new(
+                    .call(GetSnapshotHolderMessage::new(
                         &transfer_actor,
                         snapshot.clone(),
                         parent.cloned(),
@@ -203,7 +521,7 @@ impl SyncActor {
                     .await??;
 
                 container
-                    .call(GetSnapshotReceiverMessage::new(
+                    .call(GetBackupMessage::new(
                         &transfer_actor,
                         self.model.dataset_id,
                         snapshot.clone(),
@@ -212,10 +530,20 @@ impl SyncActor {
 
                 Ok(transfer_actor.into())
             }
-            SyncToContainer::Restic(container) => {
-                let transfer_actor = ResticTransferActor::new(
+            SyncToContainer::Archive(container) => {
+                let upload = container
+                    .call(GetArchiveUploadMessage {
+                        source_dataset_id: self.model.dataset_id,
+                        snapshot: snapshot.clone(),
+                        parent: parent.cloned(),
+                        compression: self.compression(),
+                    })
+                    .await??;
+                let transfer_actor = ArchiveTransferActor::new(
                     ctx.address().sender::<TransferComplete>(),
                     container.clone(),
+                    upload,
+                    self.model.id(),
                     observation,
                     &ctx.log().new(o!("message" => ())),
                 );
@@ -223,47 +551,115 @@ impl SyncActor {
                 let transfer_actor = transfer_actor.start().await?;
 
                 self.dataset
-                    .call(GetSnapshotHolderMessage::new(
-                        &transfer_actor,
-                        snapshot.clone(),
-                        parent.cloned(),
-                    ))
-                    .await??;
-
-                container
-                    .call(GetBackupMessage::new(
-                        &transfer_actor,
-                        self.model.dataset_id,
-                        snapshot.clone(),
-                    ))
+                    .call(
+                        GetSnapshotSenderMessage::new(&transfer_actor, snapshot.clone(), parent.cloned())
+                            .compressed(self.compression())
+                            .throttled(self.model.bandwidth_limit),
+                    )
                     .await??;
 
                 Ok(transfer_actor.into())
             }
         }
     }
+
+    /// The sync's stream compression, as far as the tools for it are installed. Streams into a local btrfs container
+    /// never leave the machine, compressing them would only cost CPU.
+    fn compression(&self) -> Option<StreamCompression> {
+        match self.container {
+            SyncToContainer::Remote(_) | SyncToContainer::Archive(_) => {
+                self.model.compression.map(StreamCompression::available)
+            }
+            SyncToContainer::Btrfs(_) | SyncToContainer::Restic(_) => None,
+        }
+    }
+
+    /// Pipe btrfs send into a btrfs receive, either on a local pool or over ssh on a remote.
+    async fn start_send_receive<T: Handler<GetSnapshotReceiverMessage>>(
+        &self, container: &Addr<T>, snapshot: &SnapshotHandle, parent: Option<&SnapshotHandle>,
+        observation: StartedObservation, ctx: &BcContext<'_, Self>,
+    ) -> Result<BoxBcAddr> {
+        let pool_permits = self.pool_transfer_permits().await?;
+        let dataset = self.dataset.clone();
+        let container = container.clone();
+        let (snapshot, parent) = (snapshot.clone(), parent.cloned());
+        let (dataset_id, compression, bandwidth_limit) =
+            (self.model.dataset_id, self.compression(), self.model.bandwidth_limit);
+        let request_ends: RequestEnds = Box::new(move |transfer_actor| {
+            async move {
+                dataset
+                    .call(
+                        GetSnapshotSenderMessage::new(&transfer_actor, snapshot.clone(), parent)
+                            .compressed(compression)
+                            .throttled(bandwidth_limit),
+                    )
+                    .await??;
+
+                let source_path = dataset.call(GetSnapshotPathMessage(snapshot.uuid)).await?;
+                container
+                    .call(
+                        GetSnapshotReceiverMessage::new(&transfer_actor, dataset_id, snapshot)
+                            .compressed(compression)
+                            .from_source(source_path),
+                    )
+                    .await?
+            }
+            .boxed()
+        });
+
+        let transfer_actor = TransferActor::new(
+            ctx.address().sender::<TransferComplete>(),
+            self.model.id(),
+            self.model
+                .progress_interval
+                .filter(|i| *i > Duration::from_secs(0))
+                .unwrap_or(DEFAULT_PROGRESS_INTERVAL),
+            pool_permits,
+            self.transfer_queued.clone(),
+            request_ends,
+            observation,
+            &ctx.log().new(o!("message" => ())),
+        );
+
+        Ok(transfer_actor.start().await?.into())
+    }
 }
 
 #[async_trait::async_trait]
 impl BcActorCtrl for SyncActor {
     async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        if !running_as_root()
+            && matches!(
+                self.container,
+                SyncToContainer::Btrfs(_) | SyncToContainer::Remote(_) | SyncToContainer::Archive(_)
+            )
+        {
+            bail!("syncing to a btrfs or archive container requires root for snapshot send/receive");
+        }
+
         if is_immediate(&self.model.sync_mode) {
             ctx.subscribe::<ObservableEventMessage>().await?;
         }
+        if !self.wake_pools.is_empty() {
+            ctx.subscribe::<PoolWakeWindowMessage>().await?;
+        }
+        if self.is_remote() {
+            ctx.subscribe::<NetworkPauseMessage>().await?;
+            self.network_paused = network_paused();
+        }
 
-        self.sync_cycle_schedule = get_schedule(&self.model.sync_mode).map_or(Ok(None), |s| {
-            s.map(|schedule| {
-                Some(ScheduledMessage::new(
-                    schedule,
-                    "sync_cycle",
-                    StartSnapshotSyncCycleMessage,
-                    &ctx,
-                ))
-            })
-        })?;
+        self.schedule_jobs(&ctx)?;
 
+        let newest = self.get_container_snapshots().await?.last().map(|s| s.datetime);
         if matches!(self.model.sync_mode, SnapshotSyncMode::IntervalImmediate(..)) {
-            self.last_sent = self.get_container_snapshots().await?.last().map(|s| s.datetime);
+            self.last_sent = newest;
+        }
+        self.newest_synced = newest;
+        self.report_position(&ctx);
+
+        if self.target_rpo.is_some() {
+            ctx.send_interval(CheckRpoMessage, RPO_CHECK_INTERVAL);
+            ctx.address().send(CheckRpoMessage)?;
         }
 
         Ok(())
@@ -273,10 +669,20 @@ impl BcActorCtrl for SyncActor {
         if is_immediate(&self.model.sync_mode) {
             let _ = ctx.unsubscribe::<ObservableEventMessage>().await;
         }
+        if !self.wake_pools.is_empty() {
+            let _ = ctx.unsubscribe::<PoolWakeWindowMessage>().await;
+        }
+        if self.is_remote() {
+            let _ = ctx.unsubscribe::<NetworkPauseMessage>().await;
+        }
+        let _ = self.dataset.send(SyncStoppedMessage {
+            sync_id: self.model.id(),
+        });
 
         if let Some(ActiveSend { mut actor, .. }) = self.state_active_send.take() {
             let _ = actor.stop();
             actor.wait_for_stop().await;
+            self.release_pools(&ctx);
             TerminalState::Cancelled
         } else {
             TerminalState::Succeeded
@@ -327,6 +733,12 @@ impl BcHandler<StartSnapshotSyncCycleMessage> for SyncActor {
             }
         }
 
+        if !self.wake_pools.is_empty() {
+            debug!(ctx.log(), "sync cycle deferred to the pool's wake window");
+            self.cycle_deferred = true;
+            return;
+        }
+
         if self.state_active_send.is_some() {
             debug!(ctx.log(), "received snapshot cycle message while in active send state");
             return;
@@ -337,10 +749,74 @@ impl BcHandler<StartSnapshotSyncCycleMessage> for SyncActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<EmergencySyncMessage> for SyncActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: EmergencySyncMessage) {
+        let now = Utc::now();
+        match &mut self.state_mode {
+            SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => queue.push_back(now),
+            SyncModeState::AllScheduled(limit) => {
+                limit.replace(now);
+            }
+            SyncModeState::AllImmediate => {}
+        }
+        self.emergency = true;
+
+        if self.state_active_send.is_some() {
+            debug!(ctx.log(), "emergency sync continues the active send");
+            return;
+        }
+        let result = self.run_cycle(&ctx).await;
+        unhandled_result(ctx.log(), result);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<PoolWakeWindowMessage> for SyncActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PoolWakeWindowMessage) {
+        if !self.wake_pools.contains(&msg.0) || !mem::take(&mut self.cycle_deferred) {
+            return;
+        }
+
+        if self.state_active_send.is_some() {
+            debug!(ctx.log(), "pool woke while in active send state");
+            return;
+        }
+
+        let result = self.run_cycle(&ctx).await;
+        unhandled_result(ctx.log(), result);
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<NetworkPauseMessage> for SyncActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: NetworkPauseMessage) {
+        self.network_paused = msg.0;
+        if self.network_paused {
+            if let Some(active) = &mut self.state_active_send {
+                debug!(ctx.log(), "cancelling transfer for network pause");
+                if let Err(e) = active.actor.stop() {
+                    unhandled_error(ctx.log(), e);
+                }
+            }
+            return;
+        }
+
+        // A cycle held for a wake window keeps waiting for it.
+        if !self.wake_pools.is_empty() || !mem::take(&mut self.cycle_deferred) || self.state_active_send.is_some() {
+            return;
+        }
+        let result = self.run_cycle(&ctx).await;
+        unhandled_result(ctx.log(), result);
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<TransferComplete> for SyncActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: TransferComplete) {
         let transfer = msg.0;
+        self.release_pools(&ctx);
+        self.last_transfer = Some(JobOutcome::now(transfer.succeeded()));
         if let Some(ActiveSend {
             sending_snapshot,
             active_limit,
@@ -349,13 +825,10 @@ impl BcHandler<TransferComplete> for SyncActor {
         {
             if transfer.succeeded() {
                 self.last_sent = Some(sending_snapshot);
-            } else if let Some(active_limit) = active_limit {
-                match &mut self.state_mode {
-                    SyncModeState::LatestScheduled(queue) | SyncModeState::LatestImmediate(queue, _) => {
-                        queue.push_front(active_limit);
-                    }
-                    SyncModeState::AllScheduled(_) | SyncModeState::AllImmediate => {}
-                };
+                self.newest_synced = self.newest_synced.max(Some(sending_snapshot));
+                self.report_position(&ctx);
+            } else {
+                self.requeue(active_limit);
             }
         }
 
@@ -363,7 +836,8 @@ impl BcHandler<TransferComplete> for SyncActor {
             let result = self.run_cycle(&ctx).await;
             unhandled_result(ctx.log(), result);
         } else {
-            ctx.send_later(RetrySnapshotSyncCycleMessage, Duration::from_secs(300));
+            self.emergency = false;
+            self.schedule_retry(&ctx, FAILED_RETRY_INTERVAL);
         }
     }
 }
@@ -371,6 +845,7 @@ impl BcHandler<TransferComplete> for SyncActor {
 #[async_trait::async_trait]
 impl BcHandler<RetrySnapshotSyncCycleMessage> for SyncActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: RetrySnapshotSyncCycleMessage) {
+        self.retry_pending = false;
         if self.state_active_send.is_some() {
             debug!(
                 ctx.log(),
@@ -384,9 +859,160 @@ impl BcHandler<RetrySnapshotSyncCycleMessage> for SyncActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<CheckRpoMessage> for SyncActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: CheckRpoMessage) {
+        let status = match self.rpo_status() {
+            Some(status) => status,
+            None => return,
+        };
+        if !status.record_change(&mut self.rpo_violated) {
+            trace!(ctx.log(), "rpo status unchanged");
+            return;
+        }
+        let observation = start_observation(self.model.id(), ObservableEvent::SnapshotSyncRpo).await;
+        match status {
+            RpoStatus::Compliant(age) => {
+                trace!(ctx.log(), "rpo compliant"; "age" => %humantime::Duration::from(age));
+                observation.succeeded();
+            }
+            RpoStatus::Violated(age) => {
+                let message = match age {
+                    Some(age) => format!(
+                        "newest synced snapshot is {} old",
+                        humantime::Duration::from(Duration::from_secs(age.as_secs()))
+                    ),
+                    None => String::from("no snapshot has been synced"),
+                };
+                warn!(ctx.log(), "rpo violated: {}", message);
+                observation.failed(message);
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<StartRestoreTestMessage> for SyncActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: StartRestoreTestMessage) {
+        if let SyncToContainer::Btrfs(container) = &self.container {
+            let result = container.send(RestoreTestMessage {
+                sync_id: self.model.id(),
+                dataset_id: self.model.dataset_id,
+            });
+            log_result(ctx.log(), &result);
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<UpdateSyncMessage> for SyncActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: UpdateSyncMessage) -> Result<bool> {
+        if !same_model(&self.model, &msg.model, LIVE_FIELDS)
+            || !live_mode_change(&self.model.sync_mode, &msg.model.sync_mode)
+            || self.target_rpo.is_some() != msg.target_rpo.is_some()
+        {
+            return Ok(false);
+        }
+        self.model = msg.model;
+        self.target_rpo = msg.target_rpo;
+        self.schedule_jobs(&ctx)?;
+        info!(ctx.log(), "sync updated in place");
+        Ok(true)
+    }
+}
+
+#[async_trait::async_trait]
+impl BcHandler<GetSyncActivityMessage> for SyncActor {
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetSyncActivityMessage) -> SyncActivity {
+        SyncActivity {
+            newest_synced: self.newest_synced,
+            last_transfer: self.last_transfer,
+        }
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for SyncActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("ok")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        let status = match &self.state_active_send {
+            Some(active) if self.transfer_queued.load(Ordering::Relaxed) > 0 => ActorStatus::active(format!(
+                "waiting for a transfer slot to send snapshot {}",
+                active.sending_snapshot
+            ))
+            .since(active.started)
+            .with_queue_depth(1),
+            Some(active) => {
+                ActorStatus::active(format!("sending snapshot {}", active.sending_snapshot)).since(active.started)
+            }
+            None => match &self.unreachable {
+                Some(message) => ActorStatus::unreachable(message.clone()),
+                None if self.network_paused && self.cycle_deferred => ActorStatus::idle()
+                    .with_queue_depth(1)
+                    .with_last_error("network paused"),
+                None => ActorStatus::idle(),
+            },
+        };
+        match self.rpo_status() {
+            Some(RpoStatus::Violated(_)) => status.with_last_error("rpo violated"),
+            _ => status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(cron: &str) -> libblkcapt::model::entities::ScheduleModel {
+        cron.parse().unwrap()
+    }
+
+    #[test]
+    fn scheduled_modes_change_schedule_in_place() {
+        let daily = schedule("0 0 0 * * * *");
+        let hourly = schedule("0 0 * * * * *");
+        assert!(live_mode_change(
+            &SnapshotSyncMode::AllScheduled(daily.clone()),
+            &SnapshotSyncMode::AllScheduled(hourly.clone())
+        ));
+        assert!(live_mode_change(
+            &SnapshotSyncMode::LatestScheduled(daily.clone()),
+            &SnapshotSyncMode::LatestScheduled(hourly)
+        ));
+        assert!(!live_mode_change(
+            &SnapshotSyncMode::AllScheduled(daily.clone()),
+            &SnapshotSyncMode::LatestScheduled(daily)
+        ));
+    }
+
+    #[test]
+    fn rpo_status_changes_are_recorded_once() {
+        let mut last_violated = None;
+        assert!(RpoStatus::Compliant(Duration::from_secs(60)).record_change(&mut last_violated));
+        assert!(!RpoStatus::Compliant(Duration::from_secs(120)).record_change(&mut last_violated));
+        assert!(RpoStatus::Violated(None).record_change(&mut last_violated));
+        assert!(!RpoStatus::Violated(Some(Duration::from_secs(7200))).record_change(&mut last_violated));
+        assert!(RpoStatus::Compliant(Duration::from_secs(60)).record_change(&mut last_violated));
+        assert_eq!(last_violated, Some(false));
+    }
+
+    #[test]
+    fn immediate_modes_only_stay_when_unchanged() {
+        assert!(live_mode_change(
+            &SnapshotSyncMode::AllImmediate,
+            &SnapshotSyncMode::AllImmediate
+        ));
+        assert!(live_mode_change(
+            &SnapshotSyncMode::IntervalImmediate(Duration::from_secs(60)),
+            &SnapshotSyncMode::IntervalImmediate(Duration::from_secs(60))
+        ));
+        assert!(!live_mode_change(
+            &SnapshotSyncMode::IntervalImmediate(Duration::from_secs(60)),
+            &SnapshotSyncMode::IntervalImmediate(Duration::from_secs(120))
+        ));
+        assert!(!live_mode_change(
+            &SnapshotSyncMode::AllImmediate,
+            &SnapshotSyncMode::AllScheduled(schedule("0 0 0 * * * *"))
+        ));
     }
 }
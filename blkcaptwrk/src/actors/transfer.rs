@@ -4,33 +4,98 @@ use super::{
     localreceiver::GetWriterMessage,
     localreceiver::LocalReceiverActor,
     localreceiver::LocalReceiverStoppedMessage,
-    localsender::TakeReaderMessage,
+    localsender::{GetExpectedSizeMessage, TakeReaderMessage},
     localsender::{LocalSenderActor, LocalSenderFinishedMessage},
-    observation::StartedObservation,
+    observation::{start_observation, StartedObservation},
+    pool::PoolTransferPermits,
 };
 use crate::{
     actorbase::unhandled_result,
     tasks::{WorkerCompleteMessage, WorkerTask},
-    xactorext::{BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
+    xactorext::{ActorStatus, BcActor, BcActorCtrl, BcContext, BcHandler, GetActorStatusMessage, TerminalState},
 };
 use anyhow::Result;
 use bytes::BytesMut;
+use chrono::{DateTime, Utc};
 use derive_more::From;
-use slog::{debug, error, warn, Logger};
-use std::mem;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_util::future::BoxFuture;
+use libblkcapt::model::{
+    entities::ObservableEvent,
+    history::{JobKind, JobRecord},
+    storage, EntityId,
+};
+use once_cell::sync::Lazy;
+use slog::{debug, error, info, warn, Logger};
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit},
+};
 use xactor::{message, Addr, Sender};
 
 pub struct TransferActor {
     requestor: Sender<TransferComplete>,
+    sync_id: EntityId,
+    started: Option<DateTime<Utc>>,
+    progress_interval: Duration,
+    progress: Arc<AtomicU64>,
+    expected_size: Option<u64>,
+    last_checkpoint: u64,
+    pool_permits: Vec<PoolTransferPermits>,
+    queued: Arc<AtomicUsize>,
+    _held_permits: Option<HeldPermits>,
     state: State,
 }
 
+/// Asks the dataset and the container for the two ends of a transfer, which report back to the transfer actor.
+pub type RequestEnds = Box<dyn FnOnce(Addr<BcActor<TransferActor>>) -> BoxFuture<'static, Result<()>> + Send>;
+
+/// Held for the whole transfer, released when the transfer actor goes away.
+struct HeldPermits {
+    _pool: Vec<OwnedSemaphorePermit>,
+    _global: Option<SemaphorePermit<'static>>,
+}
+
+static TRANSFER_PERMITS: Lazy<Option<Semaphore>> = Lazy::new(|| {
+    storage::worker_config()
+        .max_concurrent_transfers
+        .map(|max| Semaphore::new(max.get()))
+});
+
+/// Counts a transfer as queued while it waits for permits, also when the wait is cancelled.
+struct QueuedGuard(Arc<AtomicUsize>);
+
+impl QueuedGuard {
+    fn new(queued: &Arc<AtomicUsize>) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(queued))
+    }
+}
+
+impl Drop for QueuedGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(600);
+
+#[message()]
+#[derive(Clone)]
+struct CheckpointMessage;
+
 #[derive(Default)]
 struct ActorCompletions {
     sender: Option<Result<()>>,
     receiver: Option<Result<()>>,
-    transfer: Option<Result<()>>,
+    transfer: Option<Result<u64>>,
 }
 
 struct Actors(
@@ -40,6 +105,8 @@ struct Actors(
 );
 
 enum State {
+    Queued(RequestEnds, StartedObservation),
+    WaitingForPermits(WorkerTask, RequestEnds, StartedObservation),
     WaitingForActors(
         Option<Addr<BcActor<LocalSenderActor>>>,
         Option<Addr<BcActor<LocalReceiverActor>>>,
@@ -56,43 +123,84 @@ impl State {
     }
 }
 
-type TransferWorkerCompleteMessage = WorkerCompleteMessage<Result<()>>;
+type TransferWorkerCompleteMessage = WorkerCompleteMessage<Result<u64>>;
+type PermitsWorkerCompleteMessage = WorkerCompleteMessage<Result<HeldPermits>>;
 
 impl TransferActor {
-    pub fn new(parent: Sender<TransferComplete>, observation: StartedObservation, log: &Logger) -> BcActor<Self> {
+    /// The transfer's ends are only requested once it holds its permits, so a queued transfer starts no processes.
+    pub fn new(
+        parent: Sender<TransferComplete>, sync_id: EntityId, progress_interval: Duration,
+        pool_permits: Vec<PoolTransferPermits>, queued: Arc<AtomicUsize>, request_ends: RequestEnds,
+        observation: StartedObservation, log: &Logger,
+    ) -> BcActor<Self> {
         BcActor::new(
             Self {
-                state: State::WaitingForActors(None, None, observation),
+                state: State::Queued(request_ends, observation),
                 requestor: parent,
+                sync_id,
+                started: None,
+                progress_interval,
+                progress: Default::default(),
+                expected_size: None,
+                last_checkpoint: 0,
+                pool_permits,
+                queued,
+                _held_permits: None,
             },
             log,
         )
+        .for_entity(sync_id)
+    }
+
+    async fn acquire_permits(pool_permits: Vec<PoolTransferPermits>, queued: Arc<AtomicUsize>) -> Result<HeldPermits> {
+        let _queued = QueuedGuard::new(&queued);
+        // Pool permits first, so a transfer waiting on a busy pool doesn't hold one of the global permits.
+        let mut pool = Vec::with_capacity(pool_permits.len());
+        for permits in pool_permits {
+            let _pool_queued = QueuedGuard::new(&permits.queued);
+            pool.push(permits.permits.acquire_owned().await?);
+        }
+        let global = match TRANSFER_PERMITS.as_ref() {
+            Some(permits) => Some(permits.acquire().await?),
+            None => None,
+        };
+        Ok(HeldPermits {
+            _pool: pool,
+            _global: global,
+        })
     }
 
     async fn run_transfer(
         sender_actor: Addr<BcActor<LocalSenderActor>>, receiver_actor: Addr<BcActor<LocalReceiverActor>>,
-    ) -> Result<()> {
+        progress: Arc<AtomicU64>,
+    ) -> Result<u64> {
         let mut reader = sender_actor.call(TakeReaderMessage).await??;
         let mut writer = receiver_actor.call(GetWriterMessage).await??;
 
         let mut buf = BytesMut::with_capacity(1024 * 256);
+        let mut total = 0;
         while let Ok(size) = reader.read_buf(&mut buf).await {
             if size == 0 {
                 break;
             }
             writer.write_all(&buf).await?;
+            total += size as u64;
+            progress.store(total, Ordering::Relaxed);
             buf.clear();
         }
 
-        Ok(())
+        Ok(total)
     }
 
-    fn maybe_start_transfer(incoming: State, ctx: &BcContext<'_, Self>) -> State {
+    fn maybe_start_transfer(&mut self, incoming: State, ctx: &BcContext<'_, Self>) -> State {
         if let State::WaitingForActors(Some(sender), Some(receiver), observation) = incoming {
+            self.started = Some(Utc::now());
+            ctx.send_interval(CheckpointMessage, self.progress_interval);
             let mv_sender = sender.clone();
             let mv_receiver = receiver.clone();
+            let mv_progress = self.progress.clone();
             let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
-                Self::run_transfer(mv_sender, mv_receiver).await.into()
+                Self::run_transfer(mv_sender, mv_receiver, mv_progress).await.into()
             });
             State::Transferring(Default::default(), Actors(task, sender, receiver), observation)
         } else {
@@ -104,11 +212,11 @@ impl TransferActor {
         self.state = match (self.state.take(), input) {
             (State::WaitingForActors(maybe_sender, None, observation), InputReady::Receiver(Ok(receiver))) => {
                 let updated_state = State::WaitingForActors(maybe_sender, Some(receiver), observation);
-                Self::maybe_start_transfer(updated_state, ctx)
+                self.maybe_start_transfer(updated_state, ctx)
             }
             (State::WaitingForActors(None, maybe_receiver, observation), InputReady::Sender(Ok(sender))) => {
                 let updated_state = State::WaitingForActors(Some(sender), maybe_receiver, observation);
-                Self::maybe_start_transfer(updated_state, ctx)
+                self.maybe_start_transfer(updated_state, ctx)
             }
             (State::WaitingForActors(_, None, observation), InputReady::Receiver(Err(e)))
             | (State::WaitingForActors(None, _, observation), InputReady::Sender(Err(e))) => {
@@ -129,19 +237,19 @@ impl TransferActor {
                 if completions.sender.is_none() =>
             {
                 completions.sender = Some(result);
-                Self::maybe_finish_transfer(State::Transferring(completions, actors, observation), ctx)
+                self.maybe_finish_transfer(State::Transferring(completions, actors, observation), ctx)
             }
             (State::Transferring(mut completions, actors, observation), ResultReady::Receiver(result))
                 if completions.receiver.is_none() =>
             {
                 completions.receiver = Some(result);
-                Self::maybe_finish_transfer(State::Transferring(completions, actors, observation), ctx)
+                self.maybe_finish_transfer(State::Transferring(completions, actors, observation), ctx)
             }
             (State::Transferring(mut completions, actors, observation), ResultReady::Transfer(result))
                 if completions.transfer.is_none() =>
             {
                 completions.transfer = Some(result);
-                Self::maybe_finish_transfer(State::Transferring(completions, actors, observation), ctx)
+                self.maybe_finish_transfer(State::Transferring(completions, actors, observation), ctx)
             }
             _ => {
                 ctx.stop(None);
@@ -150,7 +258,7 @@ impl TransferActor {
         };
     }
 
-    fn maybe_finish_transfer(incoming: State, ctx: &BcContext<'_, Self>) -> State {
+    fn maybe_finish_transfer(&self, incoming: State, ctx: &BcContext<'_, Self>) -> State {
         if let State::Transferring(
             ActorCompletions {
                 sender: Some(sender),
@@ -161,7 +269,9 @@ impl TransferActor {
             observation,
         ) = incoming
         {
-            let result = transfer.and(sender).and(receiver);
+            let bytes = transfer.as_ref().ok().copied();
+            let result = transfer.map(|_| ()).and(sender).and(receiver);
+            self.record_history(bytes, &result, ctx);
             ctx.stop(None);
             observation.result(&result);
             State::Transferred(result)
@@ -169,6 +279,73 @@ impl TransferActor {
             incoming
         }
     }
+
+    /// Log how far the transfer got and let observers know whether it's still moving, so a slow transfer can be told
+    /// apart from a hung one.
+    async fn checkpoint(&mut self, ctx: &BcContext<'_, Self>) {
+        let started = match (&self.state, self.started) {
+            (State::Transferring(..), Some(started)) => started,
+            _ => return,
+        };
+
+        let total = self.progress.load(Ordering::Relaxed);
+        let elapsed = (Utc::now() - started).to_std().unwrap_or_default();
+        let rate = (total - self.last_checkpoint) as f64 / self.progress_interval.as_secs_f64();
+        let average_rate = total as f64 / elapsed.as_secs_f64().max(1.0);
+        let stalled = total == self.last_checkpoint;
+        self.last_checkpoint = total;
+
+        match self.expected_size {
+            Some(expected) if expected > 0 => info!(
+                ctx.log(),
+                "transfer progress";
+                "bytes" => total,
+                "expected_bytes" => expected,
+                "percent" => format!("{:.1}", (total as f64 / expected as f64 * 100.0).min(100.0)),
+                "bytes_per_sec" => rate as u64,
+                "average_bytes_per_sec" => average_rate as u64,
+                "elapsed" => %humantime::format_duration(Duration::from_secs(elapsed.as_secs()))
+            ),
+            _ => info!(
+                ctx.log(),
+                "transfer progress";
+                "bytes" => total,
+                "bytes_per_sec" => rate as u64,
+                "average_bytes_per_sec" => average_rate as u64,
+                "elapsed" => %humantime::format_duration(Duration::from_secs(elapsed.as_secs()))
+            ),
+        }
+
+        let observation = start_observation(self.sync_id, ObservableEvent::SnapshotSyncProgress).await;
+        if stalled {
+            warn!(ctx.log(), "no data transferred since last checkpoint"; "bytes" => total);
+            observation.failed(format!(
+                "no data transferred in the last {}, {} bytes so far",
+                humantime::format_duration(self.progress_interval),
+                total
+            ));
+        } else {
+            observation.succeeded();
+        }
+    }
+
+    fn record_history(&self, bytes: Option<u64>, result: &Result<()>, ctx: &BcContext<'_, Self>) {
+        if !storage::worker_config().history_enabled {
+            return;
+        }
+
+        if let Some(started) = self.started {
+            let record = JobRecord {
+                kind: JobKind::Transfer,
+                entity_id: self.sync_id,
+                started,
+                duration: (Utc::now() - started).to_std().unwrap_or_default(),
+                bytes,
+                error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            };
+            unhandled_result(ctx.log(), storage::append_history(&record));
+        }
+    }
 }
 
 #[derive(From)]
@@ -180,7 +357,7 @@ enum InputReady {
 enum ResultReady {
     Sender(Result<()>),
     Receiver(Result<()>),
-    Transfer(Result<()>),
+    Transfer(Result<u64>),
 }
 
 #[message()]
@@ -188,12 +365,31 @@ pub struct TransferComplete(pub TerminalState);
 
 #[async_trait::async_trait]
 impl BcActorCtrl for TransferActor {
-    async fn started(&mut self, _ctx: BcContext<'_, Self>) -> Result<()> {
+    async fn started(&mut self, ctx: BcContext<'_, Self>) -> Result<()> {
+        if let State::Queued(request_ends, observation) = self.state.take() {
+            let pool_permits = mem::take(&mut self.pool_permits);
+            let queued = self.queued.clone();
+            let task = WorkerTask::run(ctx.address(), ctx.log(), |_| async move {
+                Self::acquire_permits(pool_permits, queued).await.into()
+            });
+            self.state = State::WaitingForPermits(task, request_ends, observation);
+        }
         Ok(())
     }
 
     async fn stopped(&mut self, ctx: BcContext<'_, Self>) -> TerminalState {
         let terminal_state = match self.state.take() {
+            State::Queued(_, observation) => {
+                warn!(ctx.log(), "cancelled prior to transfer");
+                observation.cancelled();
+                TerminalState::Cancelled
+            }
+            State::WaitingForPermits(task, _, observation) => {
+                warn!(ctx.log(), "cancelled while waiting for a transfer slot");
+                task.abort();
+                observation.cancelled();
+                TerminalState::Cancelled
+            }
             State::Transferring(_, mut actors, observation) => {
                 warn!(ctx.log(), "cancelled during transfer");
                 actors.0.abort();
@@ -224,6 +420,35 @@ impl BcActorCtrl for TransferActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<PermitsWorkerCompleteMessage> for TransferActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: PermitsWorkerCompleteMessage) {
+        let (request_ends, observation) = match self.state.take() {
+            State::WaitingForPermits(_, request_ends, observation) => (request_ends, observation),
+            _ => {
+                ctx.stop(None);
+                self.state = State::Faulted;
+                return;
+            }
+        };
+        let requested = match msg.0 {
+            Ok(permits) => {
+                self._held_permits = Some(permits);
+                request_ends(ctx.address()).await
+            }
+            Err(e) => Err(e),
+        };
+        self.state = match requested {
+            Ok(()) => State::WaitingForActors(None, None, observation),
+            Err(e) => {
+                ctx.stop(None);
+                observation.error::<anyhow::Error, _>(&e);
+                State::Transferred(Err(e))
+            }
+        };
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<TransferWorkerCompleteMessage> for TransferActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: TransferWorkerCompleteMessage) {
@@ -231,9 +456,19 @@ impl BcHandler<TransferWorkerCompleteMessage> for TransferActor {
     }
 }
 
+#[async_trait::async_trait]
+impl BcHandler<CheckpointMessage> for TransferActor {
+    async fn handle(&mut self, ctx: BcContext<'_, Self>, _msg: CheckpointMessage) {
+        self.checkpoint(&ctx).await;
+    }
+}
+
 #[async_trait::async_trait]
 impl BcHandler<SenderReadyMessage> for TransferActor {
     async fn handle(&mut self, ctx: BcContext<'_, Self>, msg: SenderReadyMessage) {
+        if let Ok(sender) = &msg.0 {
+            self.expected_size = sender.call(GetExpectedSizeMessage).await.ok().flatten();
+        }
         self.input_ready(&ctx, msg.0.into());
     }
 }
@@ -261,7 +496,23 @@ impl BcHandler<LocalReceiverStoppedMessage> for TransferActor {
 
 #[async_trait::async_trait]
 impl BcHandler<GetActorStatusMessage> for TransferActor {
-    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> String {
-        String::from("ok")
+    async fn handle(&mut self, _ctx: BcContext<'_, Self>, _msg: GetActorStatusMessage) -> ActorStatus {
+        match (&self.state, self.expected_size) {
+            (State::Queued(..), _) | (State::WaitingForPermits(..), _) => {
+                ActorStatus::active("waiting for a transfer slot").with_queue_depth(1)
+            }
+            (State::Transferring(..), Some(expected)) if expected > 0 => ActorStatus::active(format!(
+                "transferring {} of {} bytes",
+                self.progress.load(Ordering::Relaxed),
+                expected
+            )),
+            (State::Transferring(..), _) => {
+                ActorStatus::active(format!("transferring {} bytes", self.progress.load(Ordering::Relaxed)))
+            }
+            (State::WaitingForActors(..), _) => ActorStatus::active("waiting for sender and receiver"),
+            (State::Transferred(Err(e)), _) => ActorStatus::idle().with_last_error(format!("{:#}", e)),
+            (State::Faulted, _) => ActorStatus::faulted("transfer faulted"),
+            _ => ActorStatus::idle(),
+        }
     }
 }
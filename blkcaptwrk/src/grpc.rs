@@ -0,0 +1,739 @@
+use crate::{
+    actors::{
+        captain::{
+            CaptainActor, PruneDatasetMessage, RefreshEntitySnapshotsMessage, ReloadMessage, RunSyncMessage,
+            SnapshotDatasetMessage,
+        },
+        intel::{GetStateMessage, IntelActor},
+        server::authorize_token,
+    },
+    xactorext::BcActor,
+};
+use anyhow::{anyhow, bail, Context as _, Result};
+use futures_util::Future;
+use libblkcapt::{
+    core::{
+        archive::ArchiveContainer,
+        remote::RemoteContainer,
+        restic::ResticRepository,
+        system::{ActiveState, ActorState, StatusQuery, SystemActor},
+        BtrfsContainer, BtrfsDataset, BtrfsPool,
+    },
+    model::{
+        audit::AuditRecord,
+        entities::{
+            ArchiveContainerEntity, BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity,
+            HealthchecksObserverEntity, NotificationObserverEntity, RemoteContainerEntity, ResticContainerEntity,
+            SnapshotSyncEntity,
+        },
+        entity_by_id_mut, storage, Entities, Entity, EntityId,
+    },
+    sys::polkit::{ActionClass, Authorization},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use slog::{info, warn, Logger};
+use std::{mem, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+use xactor::WeakAddr;
+
+pub mod proto {
+    tonic::include_proto!("blockcaptain.v1");
+}
+
+use proto::{
+    management_server::{Management, ManagementServer},
+    DeleteEntityRequest, DeleteEntityResponse, EntityKind, GetEntityRequest, JobKind, ListEntitiesRequest,
+    ListEntitiesResponse, PutEntityRequest, StatusUpdate, TriggerJobRequest, TriggerJobResponse, WatchStatusRequest,
+};
+
+const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Serve the management API on `address` until `shutdown` completes.
+pub async fn serve(
+    address: SocketAddr, captain: WeakAddr<BcActor<CaptainActor>>, shutdown: impl Future<Output = ()>, log: Logger,
+) -> Result<()> {
+    info!(log, "serving the grpc management api"; "address" => %address);
+    Server::builder()
+        .add_service(ManagementServer::new(ManagementService { captain, log }))
+        .serve_with_shutdown(address, shutdown)
+        .await
+        .context("grpc server failed")
+}
+
+struct ManagementService {
+    captain: WeakAddr<BcActor<CaptainActor>>,
+    log: Logger,
+}
+
+impl ManagementService {
+    /// Returns the name of the token that permits `action`.
+    fn authorize<T>(&self, request: &Request<T>, action: ActionClass) -> Result<Option<String>, Status> {
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| Status::unauthenticated("an API token is required"))?;
+        match authorize_token(action, authorization) {
+            Ok((Authorization::Authorized, token)) => Ok(token),
+            Ok((Authorization::NotAuthorized, _)) => {
+                info!(self.log, "grpc request denied"; "action" => action.action_id());
+                Err(Status::permission_denied(format!(
+                    "the token does not permit {}",
+                    action.action_id()
+                )))
+            }
+            Err(e) => Err(Status::unauthenticated(format!("{:#}", e))),
+        }
+    }
+
+    /// Whether the request's token also permits `action`, without logging a denial.
+    fn permits<T>(&self, request: &Request<T>, action: ActionClass) -> bool {
+        request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .map_or(false, |authorization| {
+                matches!(
+                    authorize_token(action, authorization),
+                    Ok((Authorization::Authorized, _))
+                )
+            })
+    }
+
+    fn audit(&self, token: Option<String>, action: &str, details: String) {
+        let record = AuditRecord::for_peer(None, token, action, details);
+        if let Err(e) = storage::append_audit(&record) {
+            warn!(self.log, "failed to record audit entry"; "error" => %e);
+        }
+    }
+
+    fn captain(&self) -> Result<xactor::Addr<BcActor<CaptainActor>>, Status> {
+        self.captain
+            .upgrade()
+            .ok_or_else(|| Status::unavailable("worker is stopping"))
+    }
+
+    /// Apply the stored entity config to the running actors.
+    async fn reload(&self) -> Result<(), Status> {
+        self.captain()?
+            .call(ReloadMessage)
+            .await
+            .and_then(|r| r)
+            .map_err(|e| Status::internal(format!("entity config stored, but reloading failed: {:#}", e)))
+    }
+}
+
+#[tonic::async_trait]
+impl Management for ManagementService {
+    async fn list_entities(
+        &self, request: Request<ListEntitiesRequest>,
+    ) -> Result<Response<ListEntitiesResponse>, Status> {
+        self.authorize(&request, ActionClass::ReadStatus)?;
+        let entities = visible_entity_messages(self.permits(&request, ActionClass::Destructive)).await?;
+        Ok(Response::new(ListEntitiesResponse { entities }))
+    }
+
+    async fn get_entity(&self, request: Request<GetEntityRequest>) -> Result<Response<proto::Entity>, Status> {
+        self.authorize(&request, ActionClass::ReadStatus)?;
+        let id = parse_id(&request.get_ref().id)?;
+        visible_entity_messages(self.permits(&request, ActionClass::Destructive))
+            .await?
+            .into_iter()
+            .find(|e| e.id == id.to_string())
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(format!("no entity with id {}", id)))
+    }
+
+    async fn put_entity(&self, request: Request<PutEntityRequest>) -> Result<Response<proto::Entity>, Status> {
+        let token = self.authorize(&request, ActionClass::Destructive)?;
+        let request = request.into_inner();
+        let kind = EntityKind::from_i32(request.kind).unwrap_or(EntityKind::Unspecified);
+        let parent_id = match request.parent_id.as_str() {
+            "" => None,
+            id => Some(parse_id(id)?),
+        };
+
+        let entity = blocking(move || {
+            let mut entities = storage::try_load_entity_config().map_err(internal)?;
+            check_host_only_unchanged(&entities, kind, &request.json)?;
+            let entity = put_entity(&mut entities, kind, parent_id, &request.json)
+                .map_err(|e| Status::invalid_argument(format!("{:#}", e)))?;
+            storage::try_store_entity_config(entities).map_err(internal)?;
+            Ok(entity)
+        })
+        .await?;
+        self.audit(token, "grpc entity put", format!("{:?} {}", kind, entity.id));
+        self.reload().await?;
+        Ok(Response::new(entity))
+    }
+
+    async fn delete_entity(
+        &self, request: Request<DeleteEntityRequest>,
+    ) -> Result<Response<DeleteEntityResponse>, Status> {
+        let token = self.authorize(&request, ActionClass::Destructive)?;
+        let id = parse_id(&request.get_ref().id)?;
+
+        blocking(move || {
+            let mut entities = storage::try_load_entity_config().map_err(internal)?;
+            delete_entity(&mut entities, id).map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+            storage::try_store_entity_config(entities).map_err(internal)
+        })
+        .await?;
+        self.audit(token, "grpc entity delete", id.to_string());
+        self.reload().await?;
+        Ok(Response::new(DeleteEntityResponse {}))
+    }
+
+    async fn trigger_job(&self, request: Request<TriggerJobRequest>) -> Result<Response<TriggerJobResponse>, Status> {
+        let token = self.authorize(&request, ActionClass::ManageJobs)?;
+        let request = request.into_inner();
+        let id = parse_id(&request.entity_id)?;
+        let kind = JobKind::from_i32(request.kind).unwrap_or(JobKind::Unspecified);
+
+        let captain = self.captain()?;
+        let result = match kind {
+            JobKind::Snapshot => captain
+                .call(SnapshotDatasetMessage(id))
+                .await
+                .and_then(|r| r.map(|_| ())),
+            JobKind::Prune => captain.call(PruneDatasetMessage(id)).await.and_then(|r| r),
+            JobKind::Sync => captain.call(RunSyncMessage(id)).await.and_then(|r| r),
+            JobKind::Refresh => captain
+                .call(RefreshEntitySnapshotsMessage(id))
+                .await
+                .and_then(|r| r.map(|_| ())),
+            JobKind::Unspecified => return Err(Status::invalid_argument("job kind is required")),
+        };
+        result.map_err(|e| Status::failed_precondition(format!("{:#}", e)))?;
+        self.audit(token, "grpc job", format!("{:?} {}", kind, id));
+        Ok(Response::new(TriggerJobResponse {}))
+    }
+
+    type WatchStatusStream = ReceiverStream<Result<StatusUpdate, Status>>;
+
+    async fn watch_status(
+        &self, request: Request<WatchStatusRequest>,
+    ) -> Result<Response<Self::WatchStatusStream>, Status> {
+        self.authorize(&request, ActionClass::ReadStatus)?;
+        let interval = match request.get_ref().interval_secs {
+            0 => DEFAULT_WATCH_INTERVAL,
+            secs => Duration::from_secs(secs.into()),
+        };
+
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(async move {
+            loop {
+                let update = status_update().await.map_err(internal);
+                // the client went away
+                if sender.send(update).await.is_err() {
+                    break;
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+fn internal(error: anyhow::Error) -> Status {
+    Status::internal(format!("{:#}", error))
+}
+
+/// Run the file and filesystem work of a request off the async runtime.
+async fn blocking<T: Send + 'static>(func: impl FnOnce() -> Result<T, Status> + Send + 'static) -> Result<T, Status> {
+    tokio::task::spawn_blocking(func)
+        .await
+        .map_err(|e| internal(e.into()))?
+}
+
+/// The stored entities, with credentials redacted unless the caller may change the config anyway.
+async fn visible_entity_messages(admin: bool) -> Result<Vec<proto::Entity>, Status> {
+    blocking(move || {
+        let entities = storage::try_load_entity_config().map_err(internal)?;
+        match admin {
+            true => entity_messages(&entities),
+            false => entity_messages(&entities.redacted()),
+        }
+        .map_err(internal)
+    })
+    .await
+}
+
+fn parse_id(id: &str) -> Result<EntityId, Status> {
+    EntityId::from_str(id).map_err(|_| Status::invalid_argument(format!("invalid entity id: {}", id)))
+}
+
+async fn status_update() -> Result<StatusUpdate> {
+    let state = IntelActor::addr()
+        .call(GetStateMessage(StatusQuery::default()))
+        .await?
+        .await;
+    let mut actors = state.actors;
+    actors.sort_by_key(|a| a.actor_id);
+    Ok(StatusUpdate {
+        actors: actors.into_iter().map(actor_message).collect(),
+    })
+}
+
+fn actor_message(actor: SystemActor) -> proto::ActorState {
+    let mut message = proto::ActorState {
+        actor_id: actor.actor_id,
+        actor_type: actor.actor_type,
+        state: actor.actor_state.to_string(),
+        entity_id: actor.entity_id.map(|id| id.to_string()).unwrap_or_default(),
+        last_activity: actor.last_activity.map_or(0, |t| t.timestamp()),
+        ..Default::default()
+    };
+    match actor.actor_state {
+        ActorState::Started(ActiveState::Running(status)) => {
+            message.substate = status.activity.to_string();
+            message.current_job = status.current_job.unwrap_or_default();
+            message.last_error = status.last_error.unwrap_or_default();
+        }
+        ActorState::Started(active_state) => message.substate = active_state.to_string(),
+        ActorState::Stopped(terminal_state)
+        | ActorState::Dropped(terminal_state)
+        | ActorState::Zombie(terminal_state) => message.substate = terminal_state.to_string(),
+    }
+    message
+}
+
+fn entity_message<T: Entity + Serialize>(
+    entity: &T, kind: EntityKind, parent_id: Option<EntityId>,
+) -> Result<proto::Entity> {
+    Ok(proto::Entity {
+        id: entity.id().to_string(),
+        name: entity.name().to_owned(),
+        kind: kind as i32,
+        parent_id: parent_id.map(|id| id.to_string()).unwrap_or_default(),
+        json: serde_json::to_string(entity)?,
+    })
+}
+
+fn entity_messages(entities: &Entities) -> Result<Vec<proto::Entity>> {
+    let mut messages = Vec::new();
+    for pool in &entities.btrfs_pools {
+        let mut bare_pool = pool.clone();
+        bare_pool.datasets.clear();
+        bare_pool.containers.clear();
+        messages.push(entity_message(&bare_pool, EntityKind::Pool, None)?);
+        for dataset in &pool.datasets {
+            messages.push(entity_message(dataset, EntityKind::Dataset, Some(pool.id()))?);
+        }
+        for container in &pool.containers {
+            messages.push(entity_message(container, EntityKind::BtrfsContainer, Some(pool.id()))?);
+        }
+    }
+    for sync in &entities.snapshot_syncs {
+        messages.push(entity_message(sync, EntityKind::SnapshotSync, None)?);
+    }
+    for container in &entities.restic_containers {
+        messages.push(entity_message(container, EntityKind::ResticContainer, None)?);
+    }
+    for container in &entities.remote_containers {
+        messages.push(entity_message(container, EntityKind::RemoteContainer, None)?);
+    }
+    for container in &entities.archive_containers {
+        messages.push(entity_message(container, EntityKind::ArchiveContainer, None)?);
+    }
+    for observer in &entities.observers {
+        messages.push(entity_message(observer, EntityKind::HealthchecksObserver, None)?);
+    }
+    for notifier in &entities.notifiers {
+        messages.push(entity_message(notifier, EntityKind::NotificationObserver, None)?);
+    }
+    Ok(messages)
+}
+
+/// Replace the entity of `kind` with the id of the one in `json`, or add it. A pool keeps its datasets and
+/// containers when replaced.
+fn put_entity(
+    entities: &mut Entities, kind: EntityKind, parent_id: Option<EntityId>, json: &str,
+) -> Result<proto::Entity> {
+    fn parse<T: DeserializeOwned>(json: &str) -> Result<T> {
+        serde_json::from_str(json).context("invalid entity json")
+    }
+    fn replace<T: Entity>(existing: &mut [T], entity: T) -> Option<T> {
+        match entity_by_id_mut(existing, entity.id()) {
+            Some(slot) => {
+                *slot = entity;
+                None
+            }
+            None => Some(entity),
+        }
+    }
+
+    let pool_id = || parent_id.context("datasets and btrfs containers need the id of their pool");
+    match kind {
+        EntityKind::Pool => {
+            let mut pool = parse::<BtrfsPoolEntity>(json)?;
+            BtrfsPool::validate(pool.clone())?;
+            match entity_by_id_mut(&mut entities.btrfs_pools, pool.id()) {
+                Some(existing) => {
+                    pool.datasets = mem::take(&mut existing.datasets);
+                    pool.containers = mem::take(&mut existing.containers);
+                    *existing = pool;
+                }
+                None => entities.attach_pool(pool)?,
+            }
+        }
+        EntityKind::Dataset => {
+            let pool_id = pool_id()?;
+            let pool = entity_by_id_mut(&mut entities.btrfs_pools, pool_id)
+                .ok_or_else(|| anyhow!("no pool with id {}", pool_id))?;
+            let dataset = parse::<BtrfsDatasetEntity>(json)?;
+            let validated_pool = Arc::new(BtrfsPool::validate(pool.clone())?);
+            BtrfsDataset::validate(&validated_pool, dataset.clone())?;
+            if let Some(dataset) = replace(&mut pool.datasets, dataset) {
+                pool.attach_dataset(dataset)?;
+                if pool.qgroup_hierarchy {
+                    pool.assign_qgroups(&validated_pool.qgroups_in_use()?);
+                }
+            }
+        }
+        EntityKind::BtrfsContainer => {
+            let pool_id = pool_id()?;
+            let pool = entity_by_id_mut(&mut entities.btrfs_pools, pool_id)
+                .ok_or_else(|| anyhow!("no pool with id {}", pool_id))?;
+            let container = parse::<BtrfsContainerEntity>(json)?;
+            let validated_pool = Arc::new(BtrfsPool::validate(pool.clone())?);
+            BtrfsContainer::validate(&validated_pool, container.clone())?;
+            if let Some(container) = replace(&mut pool.containers, container) {
+                pool.attach_container(container)?;
+                if pool.qgroup_hierarchy {
+                    pool.assign_qgroups(&validated_pool.qgroups_in_use()?);
+                }
+            }
+        }
+        EntityKind::SnapshotSync => {
+            let sync = parse::<SnapshotSyncEntity>(json)?;
+            entities
+                .dataset(sync.dataset_id)
+                .with_context(|| format!("no dataset with id {}", sync.dataset_id))?;
+            entities
+                .any_container(sync.container_id)
+                .with_context(|| format!("no container with id {}", sync.container_id))?;
+            entities.sync_topology(&sync)?;
+            if let Some(sync) = replace(&mut entities.snapshot_syncs, sync) {
+                entities.snapshot_syncs.push(sync);
+            }
+        }
+        EntityKind::ResticContainer => {
+            let container = parse::<ResticContainerEntity>(json)?;
+            ResticRepository::validate(container.clone())?;
+            if let Some(container) = replace(&mut entities.restic_containers, container) {
+                entities.restic_containers.push(container);
+            }
+        }
+        EntityKind::RemoteContainer => {
+            let container = parse::<RemoteContainerEntity>(json)?;
+            RemoteContainer::validate(container.clone())?;
+            if let Some(container) = replace(&mut entities.remote_containers, container) {
+                entities.remote_containers.push(container);
+            }
+        }
+        EntityKind::ArchiveContainer => {
+            let container = parse::<ArchiveContainerEntity>(json)?;
+            ArchiveContainer::validate(container.clone())?;
+            if let Some(container) = replace(&mut entities.archive_containers, container) {
+                entities.archive_containers.push(container);
+            }
+        }
+        EntityKind::HealthchecksObserver => {
+            if let Some(observer) = replace(&mut entities.observers, parse::<HealthchecksObserverEntity>(json)?) {
+                entities.attach_observer(observer)?;
+            }
+        }
+        EntityKind::NotificationObserver => {
+            if let Some(notifier) = replace(&mut entities.notifiers, parse::<NotificationObserverEntity>(json)?) {
+                entities.attach_notifier(notifier)?;
+            }
+        }
+        EntityKind::Unspecified => bail!("entity kind is required"),
+    }
+
+    // read back what was stored, the pool without its datasets and containers
+    let json_id = parse::<serde_json::Value>(json)?
+        .get("id")
+        .and_then(|id| id.as_str())
+        .and_then(|id| EntityId::from_str(id).ok())
+        .context("entity json has no id")?;
+    entity_messages(entities)?
+        .into_iter()
+        .find(|e| e.id == json_id.to_string())
+        .context("entity missing after storing it")
+}
+
+/// Fields, as JSON pointers, that make the worker run commands as root or read config that can. Snapshot hooks run
+/// as shell commands, boot menus are written for the bootloader, restic runs a `RESTIC_PASSWORD_COMMAND` from its
+/// environment and an rclone config can define remotes that run commands.
+fn host_only_fields(kind: EntityKind) -> &'static [&'static str] {
+    match kind {
+        EntityKind::Dataset => &["/snapshot_hooks", "/boot_menu"],
+        EntityKind::ResticContainer => &["/custom_environment"],
+        EntityKind::ArchiveContainer => &["/backend/rclone/config"],
+        _ => &[],
+    }
+}
+
+/// The fields of [`host_only_fields`] are only changed through blkcaptctl on the host itself.
+fn check_host_only_unchanged(entities: &Entities, kind: EntityKind, json: &str) -> Result<(), Status> {
+    fn field(entity: &serde_json::Value, pointer: &str) -> serde_json::Value {
+        // unset and empty are the same to the worker
+        match entity.pointer(pointer) {
+            Some(serde_json::Value::Object(map)) if map.is_empty() => serde_json::Value::Null,
+            Some(serde_json::Value::Array(items)) if items.is_empty() => serde_json::Value::Null,
+            Some(value) => value.clone(),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    let fields = host_only_fields(kind);
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let requested = serde_json::from_str::<serde_json::Value>(json)
+        .map_err(|e| Status::invalid_argument(format!("invalid entity json: {}", e)))?;
+    let id = requested.get("id").and_then(|id| id.as_str()).unwrap_or_default();
+    let existing = entity_messages(entities)
+        .map_err(internal)?
+        .into_iter()
+        .find(|e| e.id == id && e.kind == kind as i32)
+        .map(|e| serde_json::from_str(&e.json))
+        .transpose()
+        .map_err(|e| internal(e.into()))?
+        .unwrap_or(serde_json::Value::Null);
+    match fields.iter().find(|f| field(&requested, f) != field(&existing, f)) {
+        None => Ok(()),
+        Some(changed) => Err(Status::permission_denied(format!(
+            "{} can make the worker run commands as root and can't be changed over grpc, use blkcaptctl on the host",
+            changed.trim_start_matches('/').replace('/', ".")
+        ))),
+    }
+}
+
+/// Remove an entity that nothing else depends on.
+fn delete_entity(entities: &mut Entities, id: EntityId) -> Result<()> {
+    fn remove<T: Entity>(existing: &mut Vec<T>, id: EntityId) -> bool {
+        let count = existing.len();
+        existing.retain(|e| e.id() != id);
+        existing.len() != count
+    }
+
+    if let Some(sync) = entities
+        .snapshot_syncs
+        .iter()
+        .find(|s| s.dataset_id == id || s.container_id == id)
+    {
+        bail!("sync {} uses this entity, delete it first", sync.name());
+    }
+    if let Some(pool) = entities.pool(id) {
+        if !pool.datasets.is_empty() || !pool.containers.is_empty() {
+            bail!(
+                "pool {} still has datasets or containers, delete them first",
+                pool.name()
+            );
+        }
+    }
+
+    let removed = remove(&mut entities.btrfs_pools, id)
+        || entities
+            .btrfs_pools
+            .iter_mut()
+            .any(|p| remove(&mut p.datasets, id) || remove(&mut p.containers, id))
+        || remove(&mut entities.snapshot_syncs, id)
+        || remove(&mut entities.restic_containers, id)
+        || remove(&mut entities.remote_containers, id)
+        || remove(&mut entities.archive_containers, id)
+        || remove(&mut entities.observers, id)
+        || remove(&mut entities.notifiers, id);
+    if !removed {
+        bail!("no entity with id {}", id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libblkcapt::{
+        model::{entities::ResticRepository as ResticRepositoryModel, REDACTED},
+        sys::fs::FsPathBuf,
+    };
+    use uuid::Uuid;
+
+    fn restic(name: &str) -> ResticContainerEntity {
+        ResticContainerEntity::new(name.to_owned(), ResticRepositoryModel::Custom("/srv/restic".to_owned()))
+    }
+
+    #[test]
+    fn put_adds_then_replaces_an_entity() {
+        let mut entities = Entities::default();
+        let container = restic("offsite");
+        let json = serde_json::to_string(&container).unwrap();
+
+        let stored = put_entity(&mut entities, EntityKind::ResticContainer, None, &json).unwrap();
+        assert_eq!(stored.id, container.id().to_string());
+        assert_eq!(entities.restic_containers.len(), 1);
+
+        let mut changed = container.clone();
+        changed.pause_pruning = true;
+        put_entity(
+            &mut entities,
+            EntityKind::ResticContainer,
+            None,
+            &serde_json::to_string(&changed).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(entities.restic_containers.len(), 1);
+        assert!(entities.restic_containers[0].pause_pruning);
+    }
+
+    #[test]
+    fn put_rejects_a_sync_without_its_dataset() {
+        let mut entities = Entities::default();
+        let container = restic("offsite");
+        let sync = SnapshotSyncEntity::new("sync".to_owned(), EntityId::new(), container.id());
+        entities.restic_containers.push(container);
+
+        let result = put_entity(
+            &mut entities,
+            EntityKind::SnapshotSync,
+            None,
+            &serde_json::to_string(&sync).unwrap(),
+        );
+        assert!(result.is_err());
+        assert!(entities.snapshot_syncs.is_empty());
+    }
+
+    #[test]
+    fn put_rejects_an_invalid_remote() {
+        let mut entities = Entities::default();
+        let remote = RemoteContainerEntity::new(
+            "remote".to_owned(),
+            libblkcapt::model::entities::SshTarget::new("-oProxyCommand=sh".to_owned()),
+            "/srv/backup".into(),
+        );
+
+        let result = put_entity(
+            &mut entities,
+            EntityKind::RemoteContainer,
+            None,
+            &serde_json::to_string(&remote).unwrap(),
+        );
+        assert!(result.is_err());
+        assert!(entities.remote_containers.is_empty());
+    }
+
+    #[test]
+    fn put_requires_a_kind() {
+        let mut entities = Entities::default();
+        let json = serde_json::to_string(&restic("offsite")).unwrap();
+        assert!(put_entity(&mut entities, EntityKind::Unspecified, None, &json).is_err());
+    }
+
+    #[test]
+    fn delete_refuses_an_entity_in_use() {
+        let mut entities = Entities::default();
+        let container = restic("offsite");
+        let container_id = container.id();
+        entities.restic_containers.push(container);
+        entities.snapshot_syncs.push(SnapshotSyncEntity::new(
+            "sync".to_owned(),
+            EntityId::new(),
+            container_id,
+        ));
+
+        assert!(delete_entity(&mut entities, container_id).is_err());
+        assert_eq!(entities.restic_containers.len(), 1);
+
+        let sync_id = entities.snapshot_syncs[0].id();
+        delete_entity(&mut entities, sync_id).unwrap();
+        delete_entity(&mut entities, container_id).unwrap();
+        assert!(entities.is_empty());
+    }
+
+    #[test]
+    fn delete_fails_for_unknown_id() {
+        let mut entities = Entities::default();
+        assert!(delete_entity(&mut entities, EntityId::new()).is_err());
+    }
+
+    #[test]
+    fn messages_list_pool_children_separately() {
+        let mut pool = BtrfsPoolEntity::new("pool".to_owned(), "/mnt/pool".into(), Uuid::new_v4(), vec![]).unwrap();
+        let dataset = BtrfsDatasetEntity::new("data".to_owned(), FsPathBuf::from("/data"), Uuid::new_v4()).unwrap();
+        let dataset_id = dataset.id();
+        pool.attach_dataset(dataset).unwrap();
+        let pool_id = pool.id();
+        let mut entities = Entities::default();
+        entities.attach_pool(pool).unwrap();
+
+        let messages = entity_messages(&entities).unwrap();
+        assert_eq!(messages.len(), 2);
+        let pool_message = messages.iter().find(|m| m.id == pool_id.to_string()).unwrap();
+        let pool_json: serde_json::Value = serde_json::from_str(&pool_message.json).unwrap();
+        assert_eq!(pool_json["datasets"], serde_json::json!([]));
+        let dataset_message = messages.iter().find(|m| m.id == dataset_id.to_string()).unwrap();
+        assert_eq!(dataset_message.parent_id, pool_id.to_string());
+    }
+
+    #[test]
+    fn hooks_cant_be_changed() {
+        let entities = Entities::default();
+        let hooks = serde_json::json!({ "id": EntityId::new().to_string(), "snapshot_hooks": { "pre": ["sh"] } });
+        assert!(check_host_only_unchanged(&entities, EntityKind::Dataset, &hooks.to_string()).is_err());
+        let no_hooks = serde_json::json!({ "id": EntityId::new().to_string() });
+        assert!(check_host_only_unchanged(&entities, EntityKind::Dataset, &no_hooks.to_string()).is_ok());
+    }
+
+    #[test]
+    fn restic_environment_cant_be_changed() {
+        let mut entities = Entities::default();
+        let mut container = restic("offsite");
+        container
+            .custom_environment
+            .insert("RESTIC_PASSWORD".to_owned(), "hunter2".to_owned());
+        entities.restic_containers.push(container.clone());
+        let check = |container: &ResticContainerEntity| {
+            let json = serde_json::to_string(container).unwrap();
+            check_host_only_unchanged(&entities, EntityKind::ResticContainer, &json)
+        };
+
+        assert!(check(&container).is_ok());
+        let mut paused = container.clone();
+        paused.pause_pruning = true;
+        assert!(check(&paused).is_ok());
+
+        let mut password_command = container.clone();
+        password_command.custom_environment.insert(
+            "RESTIC_PASSWORD_COMMAND".to_owned(),
+            "sh -c 'id > /tmp/owned'".to_owned(),
+        );
+        let status = check(&password_command).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let mut new_container = restic("other");
+        assert!(check(&new_container).is_ok());
+        new_container
+            .custom_environment
+            .insert("RESTIC_PASSWORD_COMMAND".to_owned(), "sh".to_owned());
+        assert!(check(&new_container).is_err());
+    }
+
+    #[test]
+    fn viewers_get_redacted_secrets() {
+        let mut entities = Entities::default();
+        let mut container = restic("offsite");
+        container
+            .custom_environment
+            .insert("RESTIC_PASSWORD".to_owned(), "hunter2".to_owned());
+        entities.restic_containers.push(container);
+
+        let messages = entity_messages(&entities.redacted()).unwrap();
+        assert!(!messages[0].json.contains("hunter2"));
+        assert!(messages[0].json.contains(REDACTED));
+    }
+}
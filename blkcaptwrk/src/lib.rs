@@ -1,19 +1,25 @@
 pub mod actors {
+    pub mod archive;
     pub mod captain;
     pub mod container;
     pub mod dataset;
+    pub mod history;
     pub mod intel;
+    pub mod lifecycle;
     pub mod localreceiver;
     pub mod localsender;
     pub mod observation;
     pub mod pool;
+    pub mod remote;
     pub mod restic;
     pub mod server;
     pub mod sync;
     pub mod transfer;
 }
 mod actorbase;
+pub mod grpc;
 pub mod slogext;
 mod snapshots;
 mod tasks;
+mod watch;
 mod xactorext;
@@ -1,17 +1,41 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use blkcaptapp::{blkcaptapp_run, slogext::CustomFullFormat};
 use blkcaptwrk::{
-    actors::{captain::CaptainActor, intel::IntelActor},
-    slogext::JournalDrain,
+    actors::{
+        captain::{CaptainActor, ReloadMessage},
+        intel::{GetStateMessage, IntelActor},
+    },
+    slogext::{JournalDrain, RecentLinesDrain},
+};
+use libblkcapt::{
+    core::system::{ActiveState, ActorActivity, ActorState, StatusQuery},
+    model::{
+        storage::{load_server_config, load_worker_config},
+        worker::LogSink,
+        BcLogLevel,
+    },
 };
-use libblkcapt::model::{storage::load_server_config, BcLogLevel};
 use libsystemd::daemon::{self, NotifyState};
-use slog::{error, info, Drain, Logger};
+use slog::{error, info, warn, Drain, Logger};
 use std::{env, process::exit, time::Duration};
 use tokio::signal::unix::{signal, SignalKind};
 use xactor::Actor;
 
+/// Time between status updates to systemd, and the longest time between watchdog pets.
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the actor system has to answer a liveness check.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn main() {
+    let worker_config = match load_worker_config() {
+        Ok(c) => c,
+        Err(e) => {
+            println!("reading worker config failed: {:?}", e);
+            exit(1);
+        }
+    };
+
     let log_level = {
         let count = std::env::args().fold(0, |a, e| {
             a + if e.starts_with('-') && e.chars().skip(1).all(|c| c == 'v') {
@@ -34,14 +58,14 @@ fn main() {
         }
     };
 
-    let slog_drain = if use_journal() {
+    let slog_drain = if use_journal(worker_config.log_sink) {
         println!("logging to journald");
-        let drain = JournalDrain.fuse();
+        let drain = RecentLinesDrain(JournalDrain).fuse();
         let drain = slog_async::Async::new(drain).build().fuse();
         slog_atomic::AtomicSwitch::new(drain)
     } else {
         let decorator = slog_term::TermDecorator::new().build();
-        let drain = CustomFullFormat::new(decorator, true).fuse();
+        let drain = RecentLinesDrain(CustomFullFormat::new(decorator, true)).fuse();
         let drain = slog_async::Async::new(drain).build().fuse();
         slog_atomic::AtomicSwitch::new(drain)
     };
@@ -55,13 +79,30 @@ async fn async_main(log: Logger) -> Result<()> {
         let mut captain = CaptainActor::new(&log).start().await?;
         let mut sigint_stream = signal(SignalKind::interrupt())?;
         let mut sigterm_stream = signal(SignalKind::terminate())?;
+        let mut sighup_stream = signal(SignalKind::hangup())?;
         systemd_notify(&log, &[NotifyState::Ready]);
-        let signal = tokio::select! {
-            _ = sigint_stream.recv() => "interrupt",
-            _ = sigterm_stream.recv() => "terminate"
+        let supervisor = tokio::spawn(supervise(log.clone()));
+        let signal = loop {
+            tokio::select! {
+                _ = sigint_stream.recv() => break "interrupt",
+                _ = sigterm_stream.recv() => break "terminate",
+                _ = sighup_stream.recv() => {
+                    info!(log, "process hangup signal received, reloading");
+                    // reload in the background, so a stop signal during a long reload is still handled
+                    let (captain, log) = (captain.clone(), log.clone());
+                    tokio::spawn(async move {
+                        systemd_notify(&log, &[NotifyState::Reloading]);
+                        if let Err(e) = captain.call(ReloadMessage).await.and_then(|r| r) {
+                            error!(log, "reload failed"; "error" => %e);
+                        }
+                        systemd_notify(&log, &[NotifyState::Ready]);
+                    });
+                }
+            }
         };
         info!(log, "process {} signal received", signal);
         systemd_notify(&log, &[NotifyState::Stopping]);
+        supervisor.abort();
         let _ = captain.stop(None);
         captain.wait_for_stop().await;
     }
@@ -77,6 +118,77 @@ fn systemd_notify(log: &Logger, state: &[NotifyState]) {
     }
 }
 
-fn use_journal() -> bool {
-    env::var("JOURNAL_STREAM").is_ok()
+/// Reports the work in progress to systemd and pets its watchdog while the actor system answers liveness checks,
+/// so systemd can restart a hung worker.
+async fn supervise(log: Logger) {
+    let watchdog = daemon::watchdog_enabled(false);
+    // sd_watchdog_enabled(3) recommends petting at half the watchdog period.
+    let interval = watchdog.map_or(SUPERVISE_INTERVAL, |period| (period / 2).min(SUPERVISE_INTERVAL));
+    let mut ticker = tokio::time::interval(interval);
+    let mut last_status = String::new();
+    loop {
+        ticker.tick().await;
+        match liveness(LIVENESS_TIMEOUT.min(interval)).await {
+            Ok(status) => {
+                let mut state = Vec::new();
+                if watchdog.is_some() {
+                    state.push(NotifyState::Watchdog);
+                }
+                if status != last_status {
+                    state.push(NotifyState::Status(status.clone()));
+                    last_status = status;
+                }
+                if !state.is_empty() {
+                    systemd_notify(&log, &state);
+                }
+            }
+            Err(e) => warn!(log, "liveness check failed, not petting the watchdog"; "error" => %e),
+        }
+    }
+}
+
+/// Checks that the intel and captain actors answer and summarizes the jobs running.
+async fn liveness(timeout: Duration) -> Result<String> {
+    let state = tokio::time::timeout(timeout, async {
+        let state = IntelActor::addr().call(GetStateMessage(StatusQuery::default())).await?;
+        Ok::<_, anyhow::Error>(state.await)
+    })
+    .await
+    .context("the intel actor did not answer in time")??;
+
+    let mut jobs = Vec::new();
+    let mut started = 0;
+    let mut unresponsive = 0;
+    for actor in state.actors.iter() {
+        if let ActorState::Started(_) = actor.actor_state {
+            started += 1;
+        }
+        match &actor.actor_state {
+            ActorState::Started(ActiveState::Unresponsive) if actor.actor_type == "captain" => {
+                bail!("the captain actor is unresponsive")
+            }
+            ActorState::Started(ActiveState::Unresponsive) => unresponsive += 1,
+            ActorState::Started(ActiveState::Running(status)) if status.activity == ActorActivity::Active => {
+                jobs.extend(status.current_job.clone())
+            }
+            _ => {}
+        }
+    }
+
+    let mut status = match jobs.len() {
+        0 => format!("idle, {} actors running", started),
+        count => format!("{} job(s) running: {}", count, jobs.join(", ")),
+    };
+    if unresponsive > 0 {
+        status.push_str(&format!(", {} actor(s) unresponsive", unresponsive));
+    }
+    Ok(status)
+}
+
+fn use_journal(sink: LogSink) -> bool {
+    match sink {
+        LogSink::Auto => env::var("JOURNAL_STREAM").is_ok(),
+        LogSink::Journal => true,
+        LogSink::Terminal => false,
+    }
 }
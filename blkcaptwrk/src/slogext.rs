@@ -1,7 +1,72 @@
-use std::fmt;
+use std::{collections::VecDeque, fmt, sync::Mutex};
 
+use chrono::Local;
 use libsystemd::logging::{journal_send, Priority};
+use once_cell::sync::Lazy;
 use slog::{Drain, Key, OwnedKVList, Record, Serializer, KV};
+
+/// Number of recent log lines kept in memory, to report along with failures.
+const RECENT_LINES_CAPACITY: usize = 500;
+
+static RECENT_LINES: Lazy<Mutex<VecDeque<String>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(RECENT_LINES_CAPACITY)));
+
+/// Keeps the most recent log lines, with their key-values, before passing records on to the inner drain.
+pub struct RecentLinesDrain<D>(pub D);
+
+impl<D: Drain> Drain for RecentLinesDrain<D> {
+    type Ok = D::Ok;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let mut serializer = LineSerializer(String::new());
+        let _ = record.kv().serialize(record, &mut serializer);
+        let _ = values.serialize(record, &mut serializer);
+        let line = format!(
+            "{} {} {}{}",
+            Local::now().format("%b %d %H:%M:%S"),
+            record.level().as_short_str(),
+            record.msg(),
+            serializer.0
+        );
+
+        if let Ok(mut lines) = RECENT_LINES.lock() {
+            if lines.len() == RECENT_LINES_CAPACITY {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+
+        self.0.log(record, values)
+    }
+}
+
+/// The newest `limit` of the kept log lines that contain `filter`, oldest first.
+pub fn recent_log_lines(filter: &str, limit: usize) -> Vec<String> {
+    let lines = match RECENT_LINES.lock() {
+        Ok(lines) => lines,
+        Err(_) => return Vec::new(),
+    };
+    let mut matching = lines
+        .iter()
+        .rev()
+        .filter(|l| l.contains(filter))
+        .take(limit)
+        .cloned()
+        .collect::<Vec<_>>();
+    matching.reverse();
+    matching
+}
+
+struct LineSerializer(String);
+
+impl Serializer for LineSerializer {
+    fn emit_arguments(&mut self, key: Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.push_str(&format!(", {}: {}", key, value));
+        Ok(())
+    }
+}
+
 pub struct JournalDrain;
 
 impl Drain for JournalDrain {
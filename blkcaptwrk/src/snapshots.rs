@@ -2,17 +2,21 @@ use anyhow::{anyhow, Result};
 use chrono::{DateTime, Utc};
 use libblkcapt::{
     core::{
-        retention::{evaluate_retention, RetentionEvaluation},
+        retention::{evaluate_pre_upgrade_retention, evaluate_retention, RetentionEvaluation},
+        system::{PageRequest, RefreshedSnapshotsResponse},
         BtrfsSnapshot, Snapshot, SnapshotHandle,
     },
-    model::{entities::RetentionRuleset, EntityId},
+    model::{
+        entities::{ObservableEvent, PreUpgradeRetention, RetentionRuleset},
+        EntityId,
+    },
 };
-use slog::{debug, info, trace, Logger};
-use std::collections::HashSet;
+use slog::{debug, info, trace, warn, Logger};
+use std::{collections::HashSet, time::Duration};
 use uuid::Uuid;
 use xactor::message;
 
-use crate::actorbase::log_result;
+use crate::{actorbase::log_result, actors::observation::start_observation};
 
 pub fn find_ready<'a>(
     dataset_snapshots: &'a [SnapshotHandle], container_snapshots: &[SnapshotHandle], find_mode: FindMode,
@@ -21,16 +25,20 @@ pub fn find_ready<'a>(
         return None;
     }
 
+    // Everything after the newest snapshot the container already holds is ready. Datetimes only decide when the
+    // container's snapshots can't be related to the dataset's, so a skewed clock doesn't resend or skip snapshots.
+    let in_container = related_to(container_snapshots);
     let container_latest = container_snapshots.last().map(|s| s.datetime);
-    let (_parents, to_send) = match container_latest {
-        Some(container_latest) => dataset_snapshots.split_at(
+    let (_parents, to_send) = match (dataset_snapshots.iter().rposition(in_container), container_latest) {
+        (Some(newest_sent), _) => dataset_snapshots.split_at(newest_sent + 1),
+        (None, Some(container_latest)) => dataset_snapshots.split_at(
             dataset_snapshots
                 .iter()
                 .take_while(|s| s.datetime <= container_latest)
                 .count(),
         ),
         // INVARIANT: Len > 0 checked above.
-        None => dataset_snapshots.split_at(dataset_snapshots.len() - 1),
+        (None, None) => dataset_snapshots.split_at(dataset_snapshots.len() - 1),
     };
 
     if to_send.is_empty() {
@@ -63,8 +71,24 @@ pub fn find_parent<'a>(
         return None;
     }
 
-    // logic needs to handle walking source snapshots for restore chains.
+    // The receiving side looks the parent up by uuid, so only snapshots the container holds a copy of will do.
+    let in_container = related_to(container_snapshots);
+    let related = dataset_snapshots
+        .iter()
+        .filter(|s| s.uuid != child_snapshot.uuid)
+        .filter(|&s| in_container(s));
+    // Dataset snapshots share a clock, so the newest older one is the closest. A clock set back can leave the child
+    // dated before all of them, the newest related snapshot is still a valid parent then.
+    let by_uuid = related
+        .clone()
+        .filter(|s| s.datetime < child_snapshot.datetime)
+        .max_by_key(|s| s.datetime)
+        .or_else(|| related.max_by_key(|s| s.datetime));
+    if by_uuid.is_some() || container_snapshots.iter().any(|s| s.received_uuid.is_some()) {
+        return by_uuid;
+    }
 
+    // Containers that don't report received uuids can only be matched by datetime.
     let eligbile_source = dataset_snapshots
         .iter()
         .map(|s| s.datetime)
@@ -75,14 +99,53 @@ pub fn find_parent<'a>(
         .map(|s| s.datetime)
         .filter(|d| d < &child_snapshot.datetime)
         .collect::<HashSet<_>>();
-    let eligbile = eligbile_source.intersection(&eligbile_destination).last();
-    eligbile.and_then(|d| dataset_snapshots.iter().find(|s| &s.datetime == d))
+    eligbile_source
+        .intersection(&eligbile_destination)
+        .max()
+        .and_then(|d| dataset_snapshots.iter().find(|s| &s.datetime == d))
+}
+
+/// Whether a dataset snapshot has a copy among `container_snapshots`. A copy's received_uuid is the uuid of the
+/// snapshot it was sent from. A dataset restored from a container is a writable snapshot of a received snapshot,
+/// which carries the same received_uuid as its origin in the container.
+fn related_to(container_snapshots: &[SnapshotHandle]) -> impl Fn(&SnapshotHandle) -> bool {
+    let received = container_snapshots
+        .iter()
+        .filter_map(|s| s.received_uuid)
+        .collect::<HashSet<_>>();
+    move |s: &SnapshotHandle| received.contains(&s.uuid) || s.received_uuid.map_or(false, |r| received.contains(&r))
 }
 
 #[message()]
 #[derive(Clone)]
 pub struct PruneMessage;
 
+/// Sent by a pool with a wake schedule to its datasets and containers to run the jobs deferred since the last window.
+#[message()]
+pub struct RunDeferredJobsMessage;
+
+/// Jobs that came due while a pool's disks were left to sleep.
+#[derive(Default, Debug)]
+pub struct DeferredJobs {
+    pub snapshot: bool,
+    pub prune: bool,
+    pub refresh: bool,
+    pub defrag: bool,
+}
+
+impl DeferredJobs {
+    pub fn take(&mut self) -> Self {
+        std::mem::take(self)
+    }
+
+    pub fn count(&self) -> usize {
+        [self.snapshot, self.prune, self.refresh, self.defrag]
+            .iter()
+            .filter(|&&due| due)
+            .count()
+    }
+}
+
 pub fn log_evaluation<T: Snapshot>(evaluation: &RetentionEvaluation<T>, log: &Logger) {
     for snapshot in evaluation.keep_interval_buckets.iter().flat_map(|b| b.snapshots.iter()) {
         trace!(log, "Keeping snapshot {} reason: in retention interval.", snapshot);
@@ -100,11 +163,17 @@ pub fn log_evaluation<T: Snapshot>(evaluation: &RetentionEvaluation<T>, log: &Lo
     }
 }
 
-pub fn delete_snapshots<T: BtrfsSnapshot>(snapshots: &[&T], log: &Logger) -> HashSet<DateTime<Utc>> {
+/// Delete the snapshots, or move them to the trash when a trash period is given.
+pub fn delete_snapshots<T: BtrfsSnapshot>(
+    snapshots: &[&T], trash_period: Option<Duration>, log: &Logger,
+) -> HashSet<DateTime<Utc>> {
     snapshots
         .iter()
         .filter_map(|s| {
-            let result = s.delete();
+            let result = match trash_period {
+                Some(keep_for) => s.trash(keep_for),
+                None => s.delete(),
+            };
             log_result(log, &result);
             result.map(|_| s.datetime()).ok()
         })
@@ -130,12 +199,63 @@ pub fn prune_btrfs_snapshots<T: BtrfsSnapshot>(
         eval
     };
     log_evaluation(&evaluation, log);
-    let deleted = delete_snapshots(&evaluation.drop_snapshots, log);
+    let deleted = delete_snapshots(&evaluation.drop_snapshots, rules.trash_period, log);
     let failed_deletes = evaluation.drop_snapshots.len() - deleted.len();
     clear_deleted(snapshots, deleted);
     failed_deletes
 }
 
+/// Prunes pre-upgrade snapshots beyond the newest ones to keep or older than the maximum age.
+pub fn prune_pre_upgrade_snapshots<T: BtrfsSnapshot>(
+    snapshots: &mut Vec<T>, holds: &[Uuid], retention: &PreUpgradeRetention, trash_period: Option<Duration>,
+    log: &Logger,
+) -> usize {
+    let drop_snapshots = evaluate_pre_upgrade_retention(snapshots, retention, Utc::now())
+        .into_iter()
+        .filter(|s| !holds.contains(&s.uuid()))
+        .map(|s| {
+            info!(log, "Pre-upgrade snapshot {} is being pruned.", s);
+            s
+        })
+        .collect::<Vec<_>>();
+    let deleted = delete_snapshots(&drop_snapshots, trash_period, log);
+    let failed_deletes = drop_snapshots.len() - deleted.len();
+    clear_deleted(snapshots, deleted);
+    failed_deletes
+}
+
+/// Prunes everything except the newest snapshots required by the rules and any held snapshots, regardless of the
+/// retention intervals. Used to free space when a pool is too full to take a new snapshot.
+pub fn emergency_prune_btrfs_snapshots<T: BtrfsSnapshot>(
+    snapshots: &mut Vec<T>, holds: &[Uuid], rules: &RetentionRuleset, log: &Logger,
+) -> usize {
+    let keep_count = rules.newest_count.get() as usize;
+    let drop_count = snapshots.len().saturating_sub(keep_count);
+    let drop_snapshots = snapshots[..drop_count]
+        .iter()
+        .filter(|s| {
+            let retain = !holds.contains(&s.uuid());
+            if !retain {
+                debug!(
+                    log,
+                    "Snapshot {} is marked for emergency deletion, but is currently held.", s
+                );
+            }
+            retain
+        })
+        .collect::<Vec<_>>();
+    for snapshot in drop_snapshots.iter() {
+        info!(
+            log,
+            "Snapshot {} is being pruned to free space on a nearly full pool.", snapshot
+        );
+    }
+    let deleted = delete_snapshots(&drop_snapshots, None, log);
+    let failed_deletes = drop_snapshots.len() - deleted.len();
+    clear_deleted(snapshots, deleted);
+    failed_deletes
+}
+
 pub fn failed_snapshot_deletes_as_result(failed_count: usize) -> Result<()> {
     if failed_count == 0 {
         Ok(())
@@ -144,11 +264,175 @@ pub fn failed_snapshot_deletes_as_result(failed_count: usize) -> Result<()> {
     }
 }
 
+/// Re-scan snapshots on disk and reconcile them with the actor's in-memory list, picking up snapshots created or
+/// deleted outside the worker.
+#[message(result = "Result<RefreshedSnapshotsResponse>")]
+pub struct RefreshSnapshotsMessage;
+
+#[message()]
+#[derive(Clone)]
+pub struct ScheduledRefreshMessage;
+
+pub const SNAPSHOT_REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Replace `snapshots` with those found on disk, counting the differences.
+pub fn reconcile_snapshots<T: BtrfsSnapshot>(snapshots: &mut Vec<T>, found: Vec<T>) -> RefreshedSnapshotsResponse {
+    let known = snapshots.iter().map(|s| s.uuid()).collect::<HashSet<_>>();
+    let on_disk = found.iter().map(|s| s.uuid()).collect::<HashSet<_>>();
+    let changes = RefreshedSnapshotsResponse {
+        added: on_disk.difference(&known).count(),
+        removed: known.difference(&on_disk).count(),
+    };
+    *snapshots = found;
+    changes
+}
+
+/// Report snapshots that were created or deleted by something other than the worker as a warning.
+pub async fn report_external_changes(
+    source: EntityId, event: ObservableEvent, changes: RefreshedSnapshotsResponse, log: &Logger,
+) {
+    if changes.is_empty() {
+        return;
+    }
+
+    let message = format!(
+        "{} snapshots appeared and {} disappeared outside of blkcapt",
+        changes.added, changes.removed
+    );
+    warn!(log, "external interference detected: {}", message);
+    start_observation(source, event).await.warned(message);
+}
+
+/// Narrows the snapshots returned by a snapshot listing message.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotQuery {
+    pub after: Option<DateTime<Utc>>,
+    pub before: Option<DateTime<Utc>>,
+    pub page: PageRequest,
+}
+
+impl SnapshotQuery {
+    pub fn all() -> Self {
+        Default::default()
+    }
+
+    pub fn select<'a, T: 'a>(&self, snapshots: impl IntoIterator<Item = &'a T>) -> (Vec<SnapshotHandle>, usize)
+    where
+        &'a T: Into<SnapshotHandle>,
+    {
+        self.page
+            .apply(snapshots.into_iter().map(|s| s.into()).filter(|s: &SnapshotHandle| {
+                self.after.map_or(true, |after| s.datetime > after)
+                    && self.before.map_or(true, |before| s.datetime < before)
+            }))
+    }
+}
+
 #[message(result = "ContainerSnapshotsResponse")]
 pub struct GetContainerSnapshotsMessage {
     pub source_dataset_id: EntityId,
+    pub query: SnapshotQuery,
 }
 
 pub struct ContainerSnapshotsResponse {
     pub snapshots: Vec<SnapshotHandle>,
+    /// Number of snapshots matching the query, before paging.
+    pub total: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use libblkcapt::core::testing::FakeSnapshot;
+    use std::{cell::RefCell, num::NonZeroU32, rc::Rc};
+
+    fn handle(minute: i64, uuid: u128, received_uuid: Option<u128>) -> SnapshotHandle {
+        SnapshotHandle {
+            datetime: Utc.ymd(2021, 3, 1).and_hms(12, 0, 0) + chrono::Duration::minutes(minute),
+            uuid: Uuid::from_u128(uuid),
+            received_uuid: received_uuid.map(Uuid::from_u128),
+        }
+    }
+
+    #[test]
+    fn parent_found_by_uuid_when_container_clock_is_ahead() {
+        let dataset = vec![handle(0, 1, None), handle(10, 2, None)];
+        let container = vec![handle(120, 11, Some(1)), handle(130, 12, Some(2))];
+        let child = handle(20, 3, None);
+
+        let parent = find_parent(&child, &dataset, &container);
+
+        assert_eq!(parent.map(|p| p.uuid), Some(Uuid::from_u128(2)));
+    }
+
+    #[test]
+    fn parent_found_after_clock_set_back() {
+        let dataset = vec![handle(100, 1, None), handle(110, 2, None)];
+        let container = vec![handle(100, 11, Some(1)), handle(110, 12, Some(2))];
+        let child = handle(50, 3, None);
+
+        let parent = find_parent(&child, &dataset, &container);
+
+        assert_eq!(parent.map(|p| p.uuid), Some(Uuid::from_u128(2)));
+    }
+
+    #[test]
+    fn unrelated_snapshot_with_same_datetime_is_no_parent() {
+        let dataset = vec![handle(0, 1, None)];
+        let container = vec![handle(0, 11, Some(99))];
+        let child = handle(10, 3, None);
+
+        assert!(find_parent(&child, &dataset, &container).is_none());
+    }
+
+    #[test]
+    fn parent_found_by_datetime_without_received_uuids() {
+        let dataset = vec![handle(0, 1, None), handle(10, 2, None)];
+        let container = vec![handle(0, 11, None)];
+        let child = handle(20, 3, None);
+
+        let parent = find_parent(&child, &dataset, &container);
+
+        assert_eq!(parent.map(|p| p.uuid), Some(Uuid::from_u128(1)));
+    }
+
+    #[test]
+    fn ready_snapshots_follow_the_newest_copy_despite_skew() {
+        let dataset = vec![handle(0, 1, None), handle(10, 2, None), handle(20, 3, None)];
+        let container = vec![handle(500, 12, Some(2))];
+
+        let ready = find_ready(&dataset, &container, FindMode::Earliest);
+
+        assert_eq!(ready.map(|s| s.uuid), Some(Uuid::from_u128(3)));
+    }
+
+    #[test]
+    fn emergency_prune_keeps_the_newest_and_held_snapshots() {
+        let deleted = Rc::new(RefCell::new(Vec::new()));
+        let mut snapshots = (0..6)
+            .map(|i| {
+                let handle = handle(i * 10, i as u128, None);
+                FakeSnapshot {
+                    // the third snapshot is busy and can't be deleted
+                    deleted: if i == 2 { None } else { Some(deleted.clone()) },
+                    ..FakeSnapshot::new(handle.datetime, handle.uuid)
+                }
+            })
+            .collect::<Vec<_>>();
+        let rules = RetentionRuleset {
+            newest_count: NonZeroU32::new(2).unwrap(),
+            ..Default::default()
+        };
+        let log = Logger::root(slog::Discard, slog::o!());
+
+        let failed = emergency_prune_btrfs_snapshots(&mut snapshots, &[Uuid::from_u128(1)], &rules, &log);
+
+        assert_eq!(failed, 1);
+        assert_eq!(*deleted.borrow(), vec![Uuid::from_u128(0), Uuid::from_u128(3)]);
+        assert_eq!(
+            snapshots.iter().map(|s| s.uuid()).collect::<Vec<_>>(),
+            vec![1, 2, 4, 5].into_iter().map(Uuid::from_u128).collect::<Vec<_>>()
+        );
+    }
 }
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use slog::{debug, warn, Logger};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use xactor::{message, Addr, Handler};
+
+/// Changes are reported once the directories have been quiet this long, so a burst of changes is reconciled once.
+const SETTLE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Sent to the owning actor after subvolumes appeared in or disappeared from a watched directory.
+#[message()]
+#[derive(Clone)]
+pub struct SnapshotsChangedMessage;
+
+/// Watches directories that hold snapshot subvolumes with inotify. Stops watching when dropped.
+pub struct SnapshotWatcher {
+    handle: JoinHandle<()>,
+}
+
+impl SnapshotWatcher {
+    /// Watch `paths`. With `watch_new_dirs`, directories created directly in a watched path are watched as well.
+    pub fn start<A>(owner: &Addr<A>, paths: Vec<PathBuf>, watch_new_dirs: bool, log: &Logger) -> Result<Self>
+    where
+        A: Handler<SnapshotsChangedMessage>,
+    {
+        let mut inotify = Inotify::init().context("failed to initialize inotify")?;
+        let mut watched = HashMap::new();
+        for path in paths {
+            let wd = add_watch(&mut inotify, &path)?;
+            watched.insert(wd, path);
+        }
+        let mut events = inotify.event_stream([0u8; 4096])?;
+
+        let owner = owner.downgrade();
+        let log = log.clone();
+        let handle = tokio::spawn(async move {
+            while let Some(event) = events.next().await {
+                let mut pending = Some(event);
+                // Take in everything that happens until the directories settle.
+                loop {
+                    if let Some(event) = pending.take() {
+                        match event {
+                            Ok(event) => {
+                                if watch_new_dirs && event.mask.contains(EventMask::CREATE | EventMask::ISDIR) {
+                                    let new_path = match (watched.get(&event.wd), &event.name) {
+                                        (Some(parent), Some(name)) => Some(parent.join(name)),
+                                        _ => None,
+                                    };
+                                    if let Some(new_path) = new_path {
+                                        match add_watch(&mut inotify, &new_path) {
+                                            Ok(wd) => {
+                                                watched.insert(wd, new_path);
+                                            }
+                                            Err(e) => warn!(log, "failed to watch new directory"; "error" => %e),
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                warn!(log, "inotify watch failed, external changes won't be detected"; "error" => %e);
+                                return;
+                            }
+                        }
+                    }
+
+                    match tokio::time::timeout(SETTLE_PERIOD, events.next()).await {
+                        Ok(Some(event)) => pending = Some(event),
+                        Ok(None) => return,
+                        Err(_) => break,
+                    }
+                }
+
+                match owner.upgrade() {
+                    Some(owner) => {
+                        if owner.send(SnapshotsChangedMessage).is_err() {
+                            return;
+                        }
+                    }
+                    None => return,
+                }
+            }
+            debug!(log, "inotify event stream ended");
+        });
+
+        Ok(Self { handle })
+    }
+}
+
+impl Drop for SnapshotWatcher {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+fn add_watch(inotify: &mut Inotify, path: &Path) -> Result<WatchDescriptor> {
+    inotify
+        .add_watch(
+            path,
+            WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO,
+        )
+        .with_context(|| format!("failed to watch {:?}", path))
+}
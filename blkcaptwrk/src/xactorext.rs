@@ -3,13 +3,26 @@ use crate::{
     actors::intel::{ActorDropMessage, ActorStartMessage, ActorStopMessage, IntelActor},
 };
 use anyhow::{anyhow, Context as _, Result};
+use chrono::{DateTime, TimeZone, Utc};
 use futures_util::future::{join_all, FutureExt};
 use heck::SnakeCase;
+pub use libblkcapt::core::system::ActorStatus;
+use libblkcapt::model::{entities::ObservableEvent, EntityId};
 use paste::paste;
 use slog::{crit, error, o, trace, Logger};
-use std::{future::Future, marker::PhantomData, panic::AssertUnwindSafe, time::Duration};
+use std::{
+    any::TypeId,
+    future::Future,
+    marker::PhantomData,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use strum_macros::Display;
-use xactor::{message, Actor, Addr, Context, Handler, Message, WeakAddr};
+use xactor::{message, Actor, Addr, Broker, Context, Handler, Message, Service, WeakAddr};
 
 // pub trait ActorAddrExt<T: Actor> {
 //     fn get_child_actor<U, O>(&self, id: Uuid) -> U
@@ -68,7 +81,7 @@ pub trait BcActorCtrl: BcHandler<GetActorStatusMessage> + Sized + Send + 'static
     }
 }
 
-#[derive(Clone, Copy, Display)]
+#[derive(Clone, Copy, Display, Debug)]
 #[strum(serialize_all = "snake_case")]
 pub enum TerminalState {
     Succeeded,
@@ -81,6 +94,28 @@ impl TerminalState {
     pub fn succeeded(self) -> bool {
         matches!(self, Self::Succeeded)
     }
+
+    pub fn faulted(self) -> bool {
+        matches!(self, Self::Failed | Self::Faulted)
+    }
+}
+
+/// Published on the broker whenever an actor starts, fails to start or stops. The lifecycle actor reports them for
+/// actors that observe their lifecycle.
+#[message()]
+#[derive(Clone, Debug)]
+pub struct ActorLifecycleMessage {
+    pub actor_id: u64,
+    pub actor_type: String,
+    pub observed: Option<(EntityId, ObservableEvent)>,
+    pub event: ActorLifecycleEvent,
+}
+
+#[derive(Clone, Debug)]
+pub enum ActorLifecycleEvent {
+    Started,
+    StartFailed(String),
+    Stopped(TerminalState),
 }
 
 // impl TerminalState {
@@ -102,9 +137,30 @@ impl<T, E> From<Result<T, E>> for TerminalState {
     }
 }
 
+/// When an actor last handled a message other than a status query. Shared with the intel actor, which reports it
+/// without having to wait for a busy actor.
+#[derive(Clone, Default)]
+pub struct LastActivity(Arc<AtomicI64>);
+
+impl LastActivity {
+    fn touch(&self) {
+        self.0.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Option<DateTime<Utc>> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Utc.timestamp_millis(millis)),
+        }
+    }
+}
+
 pub struct BcActor<T> {
     inner: T,
     actor_id: u64,
+    observed: Option<(EntityId, ObservableEvent)>,
+    entity_id: Option<EntityId>,
+    last_activity: LastActivity,
     log: Logger,
 }
 
@@ -129,15 +185,49 @@ impl<T> BcActor<T> {
         Self {
             inner,
             actor_id: 0,
+            observed: None,
+            entity_id: None,
+            last_activity: Default::default(),
             log,
         }
     }
 
+    /// Report failed starts and faults of this actor as `event` observations of the entity it works for.
+    pub fn observe_lifecycle(mut self, entity_id: EntityId, event: ObservableEvent) -> Self {
+        self.observed = Some((entity_id, event));
+        self.for_entity(entity_id)
+    }
+
+    /// Show this actor under the entity it works for in the worker status.
+    pub fn for_entity(mut self, entity_id: EntityId) -> Self {
+        self.entity_id = Some(entity_id);
+        self
+    }
+
+    fn lifecycle_message(&self, actor_id: u64, event: ActorLifecycleEvent) -> ActorLifecycleMessage {
+        ActorLifecycleMessage {
+            actor_id,
+            actor_type: snek_type_name::<T>(),
+            observed: self.observed,
+            event,
+        }
+    }
+
     notify_impl!(start, ActorStartMessage);
     notify_impl!(stop, ActorStopMessage);
     notify_impl!(drop, ActorDropMessage);
 }
 
+async fn publish_lifecycle(log: &Logger, message: ActorLifecycleMessage) {
+    match Broker::from_registry().await {
+        Ok(mut broker) => unhandled_result(
+            log,
+            broker.publish(message).context("failed to publish lifecycle event"),
+        ),
+        Err(e) => error!(log, "broker unavailable"; "error" => %e),
+    }
+}
+
 pub async fn halt_and_catch_fire_on_panic<T>(future: impl Future<Output = T>) -> Result<T> {
     let maybe_output = AssertUnwindSafe(future).catch_unwind().await;
     if maybe_output.is_err() {
@@ -159,7 +249,7 @@ pub async fn halt_and_catch_fire_on_panic<T>(future: impl Future<Output = T>) ->
     })
 }
 
-#[message(result = "String")]
+#[message(result = "ActorStatus")]
 pub struct GetActorStatusMessage;
 
 #[async_trait::async_trait]
@@ -171,6 +261,9 @@ where
     async fn handle(&mut self, ctx: &mut Context<Self>, msg: M) -> M::Result {
         let log = self.log.new(o!("message" => snek_type_name::<M>()));
         slog::trace!(log, "message received");
+        if TypeId::of::<M>() != TypeId::of::<GetActorStatusMessage>() {
+            self.last_activity.touch();
+        }
         let fut = self.inner.handle(
             BcContext {
                 log: &self.log,
@@ -200,10 +293,19 @@ where
         let result = halt_and_catch_fire_on_panic(fut).await.and_then(|r| r);
         if let Err(e) = &result {
             error!(self.log, "actor start failed"; "error" => %e);
+            let message = self.lifecycle_message(ctx.actor_id(), ActorLifecycleEvent::StartFailed(e.to_string()));
+            publish_lifecycle(&self.log, message).await;
         } else {
             trace!(self.log, "actor started");
             self.actor_id = ctx.actor_id();
-            self.intel_notify_start(ActorStartMessage::new(ctx.actor_id(), ctx.address()));
+            self.intel_notify_start(ActorStartMessage::new(
+                ctx.actor_id(),
+                ctx.address(),
+                self.entity_id,
+                self.last_activity.clone(),
+            ));
+            let message = self.lifecycle_message(ctx.actor_id(), ActorLifecycleEvent::Started);
+            publish_lifecycle(&self.log, message).await;
         }
         result
     }
@@ -221,6 +323,10 @@ where
             TerminalState::Faulted
         });
         self.intel_notify_stop(ActorStopMessage::new(self.actor_id, terminal_state));
+        if self.actor_id != 0 {
+            let message = self.lifecycle_message(self.actor_id, ActorLifecycleEvent::Stopped(terminal_state));
+            publish_lifecycle(&self.log, message).await;
+        }
         trace!(self.log, "actor stopped"; "terminal_state" => %terminal_state);
     }
 }
@@ -271,7 +377,7 @@ pub trait BcAddr: Sync + Send {
     fn actor_id(&self) -> u64;
     fn actor_type(&self) -> String;
     fn stop(&mut self) -> Result<()>;
-    async fn status(&self) -> Result<String>;
+    async fn status(&self) -> Result<ActorStatus>;
     async fn wait_for_stop(self: Box<Self>);
 }
 
@@ -313,7 +419,7 @@ impl<T: BcActorCtrl> BcAddr for BcAddrImpl<T> {
         snek_type_name::<T>()
     }
 
-    async fn status(&self) -> Result<String> {
+    async fn status(&self) -> Result<ActorStatus> {
         self.0.call(GetActorStatusMessage).await
     }
 
@@ -425,6 +531,14 @@ where
         self.native.send_later(msg, after)
     }
 
+    pub fn send_interval<T>(&self, msg: T, interval: Duration)
+    where
+        A: BcHandler<T>,
+        T: Message<Result = ()> + Clone + Sync,
+    {
+        self.native.send_interval(msg, interval)
+    }
+
     pub async fn subscribe<T: Message<Result = ()>>(&self) -> Result<()>
     where
         A: BcHandler<T>,
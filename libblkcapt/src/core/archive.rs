@@ -0,0 +1,790 @@
+use super::{retention::evaluate_retention, Snapshot, SnapshotHandle};
+use crate::{
+    model::{
+        entities::{ArchiveBackend, ArchiveContainerEntity, RcloneBackend, RetentionRuleset},
+        Entity, EntityId,
+    },
+    sys::{
+        age::AgeStream,
+        btrfs::StreamCompression,
+        rclone::RcloneRemote,
+        s3::{is_cold_storage_class, RestoreState, S3Bucket},
+        tape::TapeDrive,
+    },
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Display,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+const MANIFEST_SUFFIX: &str = ".manifest.json";
+/// How long parts restored from cold storage stay readable, long enough for a restore started a day late.
+const THAW_DAYS: u32 = 7;
+/// Retry delays stop doubling at 2^6 seconds, about a minute.
+const MAX_RETRY_BACKOFF_EXPONENT: u32 = 6;
+
+/// Everything needed to put an archived send stream back together: the parts in order with their checksums, and the
+/// snapshot the stream is incremental to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveManifest {
+    pub dataset_id: EntityId,
+    pub datetime: DateTime<Utc>,
+    /// Uuid of the source snapshot the stream was sent from.
+    pub uuid: Uuid,
+    /// Uuid of the source snapshot a stream is incremental to, which must be received first. `None` for full streams.
+    pub parent_uuid: Option<Uuid>,
+    /// Compression the stream was stored with, undone before it is received.
+    #[serde(default)]
+    pub compression: Option<StreamCompression>,
+    /// Whether the parts are age encrypted. Their sizes and checksums are of the ciphertext.
+    #[serde(default)]
+    pub encrypted: bool,
+    pub parts: Vec<ArchivePart>,
+    pub created: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchivePart {
+    pub key: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl ArchivePart {
+    fn matches(&self, data: &[u8]) -> bool {
+        data.len() as u64 == self.size && hex::encode(Sha256::digest(data)) == self.sha256
+    }
+}
+
+impl ArchiveManifest {
+    pub fn size(&self) -> u64 {
+        self.parts.iter().map(|p| p.size).sum()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.parent_uuid.is_none()
+    }
+}
+
+/// The streams to receive, full stream first, to restore the newest snapshot at or before `point_in_time`. Every
+/// stream after the first is incremental to the one before it, so none can be left out.
+pub fn plan_restore_chain(
+    manifests: &[ArchiveManifest], point_in_time: DateTime<Utc>,
+) -> Result<Vec<&ArchiveManifest>> {
+    let target = manifests
+        .iter()
+        .filter(|m| m.datetime <= point_in_time)
+        .max_by_key(|m| m.datetime)
+        .with_context(|| format!("No archived snapshot at or before {}.", point_in_time))?;
+
+    let mut chain = vec![target];
+    while let Some(parent_uuid) = chain.last().and_then(|m| m.parent_uuid) {
+        if chain.len() > manifests.len() {
+            bail!("The archive chain for {} loops back on itself.", target.datetime);
+        }
+        let parent = manifests.iter().find(|m| m.uuid == parent_uuid).with_context(|| {
+            format!(
+                "The archive chain for {} is broken: the stream it depends on ({}) is missing.",
+                target.datetime, parent_uuid
+            )
+        })?;
+        chain.push(parent);
+    }
+    chain.reverse();
+    Ok(chain)
+}
+
+/// What a tape written from an archive container holds. The index is the tape's first file, padded with zeros to a
+/// whole block, and each stream follows in its own file as it is stored, still compressed and encrypted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TapeIndex {
+    pub archive: String,
+    pub written: DateTime<Utc>,
+    pub block_size: usize,
+    pub files: Vec<TapeIndexEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TapeIndexEntry {
+    /// Position of the stream's file on the tape, counting the index as file 0.
+    pub file_number: usize,
+    pub blocks: u64,
+    /// The stream's manifest. Its size is the stream's length before the padding of the last block.
+    pub manifest: ArchiveManifest,
+}
+
+/// How retention applies to the streams archived for a dataset.
+pub struct ArchiveCollection<'a> {
+    /// Streams retention keeps.
+    pub retained: Vec<&'a ArchiveManifest>,
+    /// Streams retention has expired that a retained stream's restore chain still goes through.
+    pub pinned: Vec<&'a ArchiveManifest>,
+    /// Streams no restore chain of a retained stream needs any more.
+    pub obsolete: Vec<&'a ArchiveManifest>,
+}
+
+/// Apply retention to a dataset's archived streams. An expired stream can only go once nothing retained is
+/// incremental to it, directly or through other streams, so a full stream often outlives its own retention.
+pub fn plan_collection<'a>(manifests: &'a [ArchiveManifest], rules: &RetentionRuleset) -> ArchiveCollection<'a> {
+    let expired = evaluate_retention(manifests, rules)
+        .drop_snapshots
+        .into_iter()
+        .map(|m| m.uuid)
+        .collect::<HashSet<_>>();
+
+    let mut referenced = HashSet::new();
+    for manifest in manifests.iter().filter(|m| !expired.contains(&m.uuid)) {
+        let mut next = Some(manifest);
+        while let Some(current) = next {
+            if !referenced.insert(current.uuid) {
+                break;
+            }
+            next = current
+                .parent_uuid
+                .and_then(|parent_uuid| manifests.iter().find(|m| m.uuid == parent_uuid));
+        }
+    }
+
+    let mut collection = ArchiveCollection {
+        retained: Vec::new(),
+        pinned: Vec::new(),
+        obsolete: Vec::new(),
+    };
+    for manifest in manifests {
+        match (expired.contains(&manifest.uuid), referenced.contains(&manifest.uuid)) {
+            (false, _) => collection.retained.push(manifest),
+            (true, true) => collection.pinned.push(manifest),
+            (true, false) => collection.obsolete.push(manifest),
+        }
+    }
+    collection
+}
+
+impl Snapshot for ArchiveManifest {
+    fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
+}
+
+impl Display for ArchiveManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}",
+            self.dataset_id,
+            self.datetime.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        )
+    }
+}
+
+impl From<&ArchiveManifest> for SnapshotHandle {
+    fn from(manifest: &ArchiveManifest) -> Self {
+        Self {
+            datetime: manifest.datetime,
+            uuid: manifest.uuid,
+            received_uuid: Some(manifest.uuid),
+        }
+    }
+}
+
+enum ArchiveStore {
+    S3(S3Bucket),
+    Rclone(RcloneRemote),
+}
+
+struct StoredObject {
+    key: String,
+    size: u64,
+    /// Has to be restored from cold storage before it can be read.
+    cold: bool,
+}
+
+/// A container keeping send streams in object storage. Nothing is held locally, the manifests in the store are the
+/// only record of what the container holds.
+pub struct ArchiveContainer {
+    model: ArchiveContainerEntity,
+    store: ArchiveStore,
+}
+
+impl ArchiveContainer {
+    pub fn validate(model: ArchiveContainerEntity) -> Result<Self> {
+        if model.part_size() < 1024 * 1024 {
+            bail!("Part size must be at least 1 MiB.");
+        }
+        if model.upload_parallelism() == 0 {
+            bail!("Upload parallelism must be at least 1.");
+        }
+        if model.upload_retries() > ArchiveContainerEntity::MAX_UPLOAD_RETRIES {
+            bail!(
+                "Upload retries must be at most {}.",
+                ArchiveContainerEntity::MAX_UPLOAD_RETRIES
+            );
+        }
+        if matches!(&model.encryption, Some(encryption) if encryption.recipients.is_empty()) {
+            bail!("An encrypted archive container needs at least one recipient.");
+        }
+        let store = match &model.backend {
+            ArchiveBackend::S3(s3) => ArchiveStore::S3(S3Bucket::new(
+                &s3.endpoint,
+                &s3.region,
+                &s3.bucket,
+                &s3.access_key_id,
+                &s3.secret_access_key,
+            )?),
+            ArchiveBackend::Rclone(RcloneBackend { remote, config }) => {
+                if model.storage_class.is_some() {
+                    bail!("Storage classes are set in the rclone remote's config for rclone archive containers.");
+                }
+                ArchiveStore::Rclone(RcloneRemote::new(remote, config.clone())?)
+            }
+        };
+        Ok(Self { model, store })
+    }
+
+    pub fn model(&self) -> &ArchiveContainerEntity {
+        &self.model
+    }
+
+    /// Check the store accepts the credentials by listing the container's prefix.
+    pub async fn check(&self) -> Result<()> {
+        self.list(&self.prefix()).await.map(|_| ())
+    }
+
+    /// Every manifest in the store, grouped by the dataset it was sent from.
+    pub async fn manifests(&self) -> Result<HashMap<EntityId, Vec<ArchiveManifest>>> {
+        let mut manifests = HashMap::<_, Vec<_>>::new();
+        for object in self
+            .list(&self.prefix())
+            .await?
+            .into_iter()
+            .filter(|o| o.key.ends_with(MANIFEST_SUFFIX))
+        {
+            let manifest = self.get_manifest(&object.key).await?;
+            manifests.entry(manifest.dataset_id).or_default().push(manifest);
+        }
+        for dataset_manifests in manifests.values_mut() {
+            dataset_manifests.sort_unstable_by_key(|m| m.datetime);
+        }
+        Ok(manifests)
+    }
+
+    pub fn upload(
+        self: &Arc<Self>, dataset_id: EntityId, snapshot: &SnapshotHandle, parent: Option<&SnapshotHandle>,
+        compression: Option<StreamCompression>,
+    ) -> ArchiveUpload {
+        ArchiveUpload {
+            container: Arc::clone(self),
+            dataset_id,
+            datetime: snapshot.datetime,
+            uuid: snapshot.uuid,
+            parent_uuid: parent.map(|p| p.uuid),
+            compression,
+        }
+    }
+
+    /// Record a fully uploaded stream. Until the manifest is written the parts aren't part of the container.
+    pub async fn put_manifest(&self, manifest: &ArchiveManifest) -> Result<()> {
+        let key = self.manifest_key(manifest.dataset_id, manifest.datetime);
+        let body = serde_json::to_vec_pretty(manifest)?;
+        self.put(&key, body.into()).await
+    }
+
+    /// Remove a stream from the container. The manifest goes first, so a stream is never listed with parts missing.
+    pub async fn delete_stream(&self, manifest: &ArchiveManifest) -> Result<()> {
+        self.delete(&self.manifest_key(manifest.dataset_id, manifest.datetime))
+            .await?;
+        for part in manifest.parts.iter() {
+            self.delete(&part.key).await?;
+        }
+        Ok(())
+    }
+
+    /// Check every part of a restore chain is in the store and matches its checksum, before anything is received.
+    /// Parts in cold storage can't be read until they are restored, so their restore is requested and the check fails
+    /// until all of them are readable.
+    pub async fn verify_chain(&self, chain: &[&ArchiveManifest]) -> Result<()> {
+        let stored = self
+            .list(&self.prefix())
+            .await?
+            .into_iter()
+            .map(|o| (o.key.clone(), o))
+            .collect::<HashMap<_, _>>();
+        let mut cold_parts = Vec::new();
+        for manifest in chain.iter() {
+            for part in manifest.parts.iter() {
+                match stored.get(&part.key) {
+                    Some(object) if object.size == part.size => {
+                        if object.cold {
+                            cold_parts.push(&part.key);
+                        }
+                    }
+                    Some(_) => bail!("Archive part {} has a different size than its manifest.", part.key),
+                    None => bail!("Archive part {} is missing.", part.key),
+                }
+            }
+        }
+
+        let mut thawing = 0;
+        for key in cold_parts {
+            match self.restore_state(key).await? {
+                RestoreState::Available => {}
+                RestoreState::InProgress => thawing += 1,
+                RestoreState::NotRequested => {
+                    self.request_restore(key).await?;
+                    thawing += 1;
+                }
+            }
+        }
+        if thawing > 0 {
+            bail!(
+                "{} archive parts are in cold storage and being restored for reading, which can take hours. Try again \
+                 once the restore finished.",
+                thawing
+            );
+        }
+
+        for manifest in chain.iter() {
+            for part in manifest.parts.iter() {
+                if !part.matches(&self.get(&part.key).await?) {
+                    bail!("Archive part {} does not match its checksum.", part.key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the stream a manifest describes, checking every part against its recorded checksum. Encrypted streams are
+    /// decrypted with `identity` on the way.
+    pub async fn download<W: AsyncWrite + Unpin>(
+        &self, manifest: &ArchiveManifest, identity: Option<&Path>, writer: &mut W,
+    ) -> Result<u64> {
+        if !manifest.encrypted {
+            return self.download_parts(manifest, writer).await;
+        }
+        let identity = identity.with_context(|| {
+            format!(
+                "The archived stream for {} is encrypted and needs an age identity to decrypt it.",
+                manifest.datetime
+            )
+        })?;
+
+        let mut decryptor = AgeStream::decrypt(identity)?;
+        let mut ciphertext = decryptor.input();
+        let mut plaintext = decryptor.output();
+        let (total, _) = tokio::try_join!(
+            async move { self.download_parts(manifest, &mut ciphertext).await },
+            async {
+                tokio::io::copy(&mut plaintext, writer)
+                    .await
+                    .map_err(anyhow::Error::from)
+            },
+        )?;
+        writer.flush().await?;
+        decryptor
+            .wait()
+            .await
+            .context("failed to decrypt the archived stream")?;
+        Ok(total)
+    }
+
+    async fn download_parts<W: AsyncWrite + Unpin>(&self, manifest: &ArchiveManifest, writer: &mut W) -> Result<u64> {
+        let mut total = 0;
+        for part in manifest.parts.iter() {
+            let data = self.read_part(part).await?;
+            writer.write_all(&data).await?;
+            total += part.size;
+        }
+        writer.flush().await?;
+        Ok(total)
+    }
+
+    async fn read_part(&self, part: &ArchivePart) -> Result<Bytes> {
+        let data = self.get(&part.key).await?;
+        if !part.matches(&data) {
+            bail!("archive part {} does not match its manifest", part.key);
+        }
+        Ok(data)
+    }
+
+    /// Write streams to tape from the current position, the index first and then a file per stream in the order
+    /// given. Streams are copied as stored, so a tape of an encrypted container needs the age identity to restore.
+    pub async fn write_to_tape(&self, manifests: &[&ArchiveManifest], drive: &TapeDrive) -> Result<TapeIndex> {
+        let block_size = drive.block_size() as u64;
+        let index = TapeIndex {
+            archive: self.model.name().to_owned(),
+            written: Utc::now(),
+            block_size: drive.block_size(),
+            files: manifests
+                .iter()
+                .enumerate()
+                .map(|(i, m)| TapeIndexEntry {
+                    file_number: i + 1,
+                    blocks: (m.size() + block_size - 1) / block_size,
+                    manifest: (*m).clone(),
+                })
+                .collect(),
+        };
+
+        let mut file = drive.create_file()?;
+        file.write(&serde_json::to_vec_pretty(&index)?)?;
+        file.finish()?;
+        for entry in index.files.iter() {
+            let mut file = drive.create_file()?;
+            for part in entry.manifest.parts.iter() {
+                file.write(&self.read_part(part).await?)?;
+            }
+            file.finish()
+                .with_context(|| format!("failed to write the stream for {} to tape", entry.manifest))?;
+        }
+        Ok(index)
+    }
+
+    fn prefix(&self) -> String {
+        // An rclone remote's path already places the container.
+        let prefix = match &self.model.backend {
+            ArchiveBackend::S3(s3) => s3.prefix.trim_matches('/'),
+            ArchiveBackend::Rclone(_) => "",
+        };
+        if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", prefix)
+        }
+    }
+
+    fn stream_key(&self, dataset_id: EntityId, datetime: DateTime<Utc>) -> String {
+        format!("{}{}/{}", self.prefix(), dataset_id, datetime.format("%FT%H-%M-%SZ"))
+    }
+
+    fn part_key(&self, dataset_id: EntityId, datetime: DateTime<Utc>, index: usize) -> String {
+        format!("{}/part-{:05}", self.stream_key(dataset_id, datetime), index)
+    }
+
+    fn manifest_key(&self, dataset_id: EntityId, datetime: DateTime<Utc>) -> String {
+        self.stream_key(dataset_id, datetime) + MANIFEST_SUFFIX
+    }
+
+    async fn get_manifest(&self, key: &str) -> Result<ArchiveManifest> {
+        let data = self.get(key).await?;
+        serde_json::from_slice(&data).with_context(|| format!("archive manifest {} is not valid", key))
+    }
+
+    async fn put(&self, key: &str, body: Bytes) -> Result<()> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => bucket.put_object(key, body, None).await,
+            ArchiveStore::Rclone(remote) => remote.put(key, body).await,
+        }
+    }
+
+    /// Parts go in the container's storage class. Manifests don't, they are read on every start.
+    async fn put_part(&self, key: &str, body: Bytes) -> Result<()> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => bucket.put_object(key, body, self.model.storage_class.as_deref()).await,
+            ArchiveStore::Rclone(remote) => remote.put(key, body).await,
+        }
+    }
+
+    async fn restore_state(&self, key: &str) -> Result<RestoreState> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => bucket.restore_state(key).await,
+            ArchiveStore::Rclone(_) => Ok(RestoreState::Available),
+        }
+    }
+
+    async fn request_restore(&self, key: &str) -> Result<()> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => bucket.restore_object(key, THAW_DAYS).await,
+            ArchiveStore::Rclone(_) => Ok(()),
+        }
+    }
+
+    /// Upload a part, retrying with a growing delay so a brief outage doesn't fail a long upload.
+    async fn put_part_with_retry(&self, key: &str, body: Bytes) -> Result<()> {
+        let retries = self.model.upload_retries();
+        let mut attempt = 0;
+        loop {
+            match self.put_part(key, body.clone()).await {
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    let delay = retry_delay(attempt);
+                    slog_scope::warn!("archive part upload failed, retrying in {:?}", delay; "key" => key, "error" => %e);
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => bucket.get_object(key).await,
+            ArchiveStore::Rclone(remote) => remote.get(key).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => bucket.delete_object(key).await,
+            ArchiveStore::Rclone(remote) => remote.delete(key).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<StoredObject>> {
+        match &self.store {
+            ArchiveStore::S3(bucket) => Ok(bucket
+                .list_objects(prefix)
+                .await?
+                .into_iter()
+                .map(|o| StoredObject {
+                    cold: o.storage_class.as_deref().map_or(false, is_cold_storage_class),
+                    key: o.key,
+                    size: o.size,
+                })
+                .collect()),
+            ArchiveStore::Rclone(remote) => Ok(remote
+                .list(prefix)
+                .await?
+                .into_iter()
+                .map(|o| StoredObject {
+                    key: o.key,
+                    size: o.size,
+                    cold: false,
+                })
+                .collect()),
+        }
+    }
+}
+
+impl Display for ArchiveContainer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.model.backend {
+            ArchiveBackend::S3(s3) => write!(
+                f,
+                "{} ({}/{}/{})",
+                self.model.name(),
+                s3.endpoint.trim_end_matches('/'),
+                s3.bucket,
+                self.prefix()
+            ),
+            ArchiveBackend::Rclone(rclone) => write!(f, "{} ({})", self.model.name(), rclone.remote),
+        }
+    }
+}
+
+/// A send stream on its way into an archive container.
+pub struct ArchiveUpload {
+    container: Arc<ArchiveContainer>,
+    dataset_id: EntityId,
+    datetime: DateTime<Utc>,
+    uuid: Uuid,
+    parent_uuid: Option<Uuid>,
+    compression: Option<StreamCompression>,
+}
+
+impl ArchiveUpload {
+    pub fn container(&self) -> &Arc<ArchiveContainer> {
+        &self.container
+    }
+
+    /// Split the stream into parts and upload them, several at once, encrypting it first if the container is
+    /// encrypted. The manifest returned still needs to be put once the sending side has confirmed the stream is
+    /// complete.
+    pub async fn run<R: AsyncRead + Unpin>(self, mut reader: R, progress: &AtomicU64) -> Result<ArchiveManifest> {
+        let parts = match &self.container.model.encryption {
+            Some(encryption) => {
+                let mut encryptor = AgeStream::encrypt(&encryption.recipients)?;
+                let mut plaintext = encryptor.input();
+                let (_, parts) = tokio::try_join!(
+                    async move {
+                        tokio::io::copy(&mut reader, &mut plaintext)
+                            .await
+                            .map_err(anyhow::Error::from)
+                    },
+                    self.upload_parts(encryptor.output(), progress),
+                )?;
+                encryptor.wait().await.context("failed to encrypt the send stream")?;
+                parts
+            }
+            None => self.upload_parts(reader, progress).await?,
+        };
+
+        Ok(ArchiveManifest {
+            dataset_id: self.dataset_id,
+            datetime: self.datetime,
+            uuid: self.uuid,
+            parent_uuid: self.parent_uuid,
+            compression: self.compression,
+            encrypted: self.container.model.encryption.is_some(),
+            parts,
+            created: Utc::now(),
+        })
+    }
+
+    async fn upload_parts<R: AsyncRead + Unpin>(
+        &self, mut reader: R, progress: &AtomicU64,
+    ) -> Result<Vec<ArchivePart>> {
+        let part_size = self.container.model.part_size();
+        let parallelism = self.container.model.upload_parallelism();
+        let mut parts = Vec::new();
+        let mut uploads = VecDeque::new();
+        let mut total = 0;
+        let result = async {
+            loop {
+                let mut data = Vec::with_capacity(part_size as usize);
+                (&mut reader).take(part_size).read_to_end(&mut data).await?;
+                if data.is_empty() {
+                    break;
+                }
+
+                let part = ArchivePart {
+                    key: self.container.part_key(self.dataset_id, self.datetime, parts.len()),
+                    size: data.len() as u64,
+                    sha256: hex::encode(Sha256::digest(&data)),
+                };
+                if uploads.len() == parallelism {
+                    total += finish_upload(&mut uploads).await?;
+                    progress.store(total, Ordering::Relaxed);
+                }
+                let container = Arc::clone(&self.container);
+                let (key, size) = (part.key.clone(), part.size);
+                uploads.push_back(tokio::spawn(async move {
+                    container.put_part_with_retry(&key, data.into()).await.map(|_| size)
+                }));
+                parts.push(part);
+            }
+            while !uploads.is_empty() {
+                total += finish_upload(&mut uploads).await?;
+                progress.store(total, Ordering::Relaxed);
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if result.is_err() {
+            uploads.iter().for_each(|u| u.abort());
+        }
+        result?;
+
+        if parts.is_empty() {
+            bail!("send stream for snapshot {} was empty", self.datetime);
+        }
+        Ok(parts)
+    }
+}
+
+/// Wait for the oldest part upload still running, returning its size.
+async fn finish_upload(uploads: &mut VecDeque<JoinHandle<Result<u64>>>) -> Result<u64> {
+    uploads
+        .pop_front()
+        .expect("only called with uploads running")
+        .await
+        .context("part upload task failed")?
+}
+
+/// How long to wait before retry `attempt` of a part upload.
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(2u64.pow(attempt.min(MAX_RETRY_BACKOFF_EXPONENT)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_cap() {
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(3), Duration::from_secs(8));
+        assert_eq!(retry_delay(6), Duration::from_secs(64));
+        assert_eq!(retry_delay(7), Duration::from_secs(64));
+        assert_eq!(retry_delay(u32::MAX), Duration::from_secs(64));
+    }
+
+    fn manifest(datetime: &str, uuid: u128, parent_uuid: Option<u128>) -> ArchiveManifest {
+        ArchiveManifest {
+            dataset_id: "b99a584c-72c0-4cbe-9c6d-0c32274563f7".parse().unwrap(),
+            datetime: datetime.parse().unwrap(),
+            uuid: Uuid::from_u128(uuid),
+            parent_uuid: parent_uuid.map(Uuid::from_u128),
+            compression: None,
+            encrypted: false,
+            parts: Vec::new(),
+            created: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn collection_keeps_expired_streams_a_retained_chain_needs() {
+        let manifests = vec![
+            manifest("2021-01-01T00:00:00Z", 1, None),
+            manifest("2021-01-02T00:00:00Z", 2, Some(1)),
+            manifest("2021-01-03T00:00:00Z", 3, None),
+            manifest("2021-01-04T00:00:00Z", 4, Some(3)),
+            manifest("2021-01-05T00:00:00Z", 5, Some(4)),
+        ];
+        let rules = RetentionRuleset {
+            newest_count: std::num::NonZeroU32::new(1).unwrap(),
+            ..Default::default()
+        };
+
+        let collection = plan_collection(&manifests, &rules);
+        let uuids = |streams: &[&ArchiveManifest]| streams.iter().map(|m| m.uuid.as_u128()).collect::<Vec<_>>();
+        assert_eq!(uuids(&collection.retained), vec![5]);
+        assert_eq!(uuids(&collection.pinned), vec![3, 4]);
+        assert_eq!(uuids(&collection.obsolete), vec![1, 2]);
+    }
+
+    #[test]
+    fn restore_chain_follows_parents_to_full_stream() {
+        let manifests = vec![
+            manifest("2021-01-01T00:00:00Z", 1, None),
+            manifest("2021-01-02T00:00:00Z", 2, Some(1)),
+            manifest("2021-01-03T00:00:00Z", 3, None),
+            manifest("2021-01-04T00:00:00Z", 4, Some(3)),
+            manifest("2021-01-05T00:00:00Z", 5, Some(4)),
+        ];
+
+        let chain = plan_restore_chain(&manifests, "2021-01-05T12:00:00Z".parse().unwrap()).unwrap();
+        assert_eq!(
+            chain.iter().map(|m| m.uuid).collect::<Vec<_>>(),
+            vec![Uuid::from_u128(3), Uuid::from_u128(4), Uuid::from_u128(5)]
+        );
+
+        let chain = plan_restore_chain(&manifests, "2021-01-02T00:00:00Z".parse().unwrap()).unwrap();
+        assert_eq!(
+            chain.iter().map(|m| m.uuid).collect::<Vec<_>>(),
+            vec![Uuid::from_u128(1), Uuid::from_u128(2)]
+        );
+
+        assert!(plan_restore_chain(&manifests, "2020-12-31T00:00:00Z".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn restore_chain_fails_when_a_parent_is_missing() {
+        let manifests = vec![
+            manifest("2021-01-02T00:00:00Z", 2, Some(1)),
+            manifest("2021-01-03T00:00:00Z", 3, Some(2)),
+        ];
+        assert!(plan_restore_chain(&manifests, "2021-01-03T00:00:00Z".parse().unwrap()).is_err());
+
+        let looped = vec![
+            manifest("2021-01-02T00:00:00Z", 2, Some(3)),
+            manifest("2021-01-03T00:00:00Z", 3, Some(2)),
+        ];
+        assert!(plan_restore_chain(&looped, "2021-01-03T00:00:00Z".parse().unwrap()).is_err());
+    }
+}
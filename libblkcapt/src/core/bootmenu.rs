@@ -0,0 +1,190 @@
+use super::{BtrfsDataset, BtrfsDatasetSnapshot, Snapshot};
+use crate::{
+    model::{
+        entities::{BootLoader, BootMenuConfig},
+        Entity, EntityId,
+    },
+    sys::fs::FsPathBuf,
+};
+use anyhow::{Context, Result};
+use std::{collections::HashSet, fs, iter::once, os::unix::fs::PermissionsExt, path::Path};
+
+const GRUB_DIR: &str = "/boot/grub";
+/// Sources the menus at boot, so they are current without running grub-mkconfig after every snapshot.
+const GRUB_SCRIPT_PATH: &str = "/etc/grub.d/41_blockcaptain";
+const GRUB_SCRIPT: &str = r#"#!/bin/sh
+# Written by blkcapt. Sources the snapshot menus it keeps up to date.
+for menu in /boot/grub/blockcaptain-*.cfg; do
+    if [ -f "$menu" ]; then
+        echo "source \${prefix}/$(basename "$menu")"
+    fi
+done
+"#;
+const ENTRY_PREFIX: &str = "blockcaptain-";
+
+struct MenuEntry {
+    label: String,
+    title: String,
+    options: String,
+}
+
+/// Rewrite the dataset's boot menu to list its newest snapshots, if it has one configured.
+pub fn update_boot_menu(dataset: &BtrfsDataset, snapshots: &[BtrfsDatasetSnapshot]) -> Result<()> {
+    let config = match &dataset.model().boot_menu {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    let cmdline = fs::read_to_string("/proc/cmdline").context("Failed to read the kernel command line.")?;
+    let entries = snapshots
+        .iter()
+        .rev()
+        .take(config.entries.get() as usize)
+        .map(|snapshot| MenuEntry {
+            label: snapshot
+                .path()
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            title: format!(
+                "{} snapshot {}",
+                dataset.model().name(),
+                snapshot.datetime().format("%F %H:%M UTC")
+            ),
+            options: snapshot_cmdline(&cmdline, snapshot.path()),
+        })
+        .collect::<Vec<_>>();
+
+    match config.loader {
+        BootLoader::Grub => write_grub_menu(config, dataset.model().id(), dataset.model().name(), &entries),
+        BootLoader::SystemdBoot => write_systemd_boot_entries(config, dataset.model().id(), &entries),
+    }
+}
+
+/// The running kernel's command line with the root mounted from the snapshot instead. The image and initrd added
+/// by the bootloader are left out, the menu entry names its own.
+pub fn snapshot_cmdline(cmdline: &str, subvolume: &FsPathBuf) -> String {
+    let subvol = format!("subvol={}", subvolume.as_pathbuf(Path::new("/")).display());
+    let mut has_rootflags = false;
+    let mut args = cmdline
+        .split_whitespace()
+        .filter(|arg| !arg.starts_with("BOOT_IMAGE=") && !arg.starts_with("initrd="))
+        .map(|arg| match arg.strip_prefix("rootflags=") {
+            Some(flags) => {
+                has_rootflags = true;
+                let flags = flags
+                    .split(',')
+                    .filter(|f| !f.starts_with("subvol=") && !f.starts_with("subvolid="))
+                    .chain(once(subvol.as_str()))
+                    .collect::<Vec<_>>();
+                format!("rootflags={}", flags.join(","))
+            }
+            None => arg.to_owned(),
+        })
+        .collect::<Vec<_>>();
+    if !has_rootflags {
+        args.push(format!("rootflags={}", subvol));
+    }
+    args.join(" ")
+}
+
+fn write_grub_menu(config: &BootMenuConfig, dataset_id: EntityId, name: &str, entries: &[MenuEntry]) -> Result<()> {
+    let mut menu = String::new();
+    if !entries.is_empty() {
+        menu.push_str(&format!(
+            "submenu '{}' {{\n",
+            grub_quote(&format!("{} snapshots", name))
+        ));
+    }
+    for entry in entries {
+        menu.push_str(&format!("    menuentry '{}' {{\n", grub_quote(&entry.title)));
+        menu.push_str(&format!("        linux {} {}\n", config.kernel, entry.options));
+        if !config.initrds.is_empty() {
+            menu.push_str(&format!("        initrd {}\n", config.initrds.join(" ")));
+        }
+        menu.push_str("    }\n");
+    }
+    if !entries.is_empty() {
+        menu.push_str("}\n");
+    }
+    write_replacing(
+        &Path::new(GRUB_DIR).join(format!("{}{}.cfg", ENTRY_PREFIX, dataset_id)),
+        &menu,
+    )?;
+
+    let script = Path::new(GRUB_SCRIPT_PATH);
+    if !script.exists() {
+        write_replacing(script, GRUB_SCRIPT)?;
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make {:?} executable.", script))?;
+        slog_scope::warn!(
+            "Installed {}, run grub-mkconfig once so grub loads the snapshot menus.",
+            GRUB_SCRIPT_PATH
+        );
+    }
+    Ok(())
+}
+
+/// One entry file per snapshot, entries of snapshots no longer listed are removed.
+fn write_systemd_boot_entries(config: &BootMenuConfig, dataset_id: EntityId, entries: &[MenuEntry]) -> Result<()> {
+    let dir = config.boot_path.join("loader").join("entries");
+    fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}.", dir))?;
+    let prefix = format!("{}{}-", ENTRY_PREFIX, dataset_id);
+
+    let mut current = HashSet::new();
+    for entry in entries {
+        let file_name = format!("{}{}.conf", prefix, entry.label);
+        let mut contents = format!("title {}\nlinux {}\n", entry.title, config.kernel);
+        for initrd in &config.initrds {
+            contents.push_str(&format!("initrd {}\n", initrd));
+        }
+        contents.push_str(&format!("options {}\n", entry.options));
+        write_replacing(&dir.join(&file_name), &contents)?;
+        current.insert(file_name);
+    }
+
+    for dir_entry in fs::read_dir(&dir).with_context(|| format!("Failed to list {:?}.", dir))? {
+        let file_name = dir_entry?.file_name().to_string_lossy().into_owned();
+        if file_name.starts_with(&prefix) && !current.contains(&file_name) {
+            let path = dir.join(&file_name);
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}.", path))?;
+        }
+    }
+    Ok(())
+}
+
+fn grub_quote(value: &str) -> String {
+    value.replace('\'', r"'\''")
+}
+
+/// Written to a temporary file first, the bootloader never reads a half written menu.
+fn write_replacing(path: &Path, contents: &str) -> Result<()> {
+    let partial = path.with_extension("partial");
+    fs::write(&partial, contents).with_context(|| format!("Failed to write {:?}.", partial))?;
+    fs::rename(&partial, path).with_context(|| format!("Failed to move {:?} into place.", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmdline_replaces_root_subvolume() {
+        let snapshot = FsPathBuf::from(".blkcapt/snapshots/abc/2020-08-23T17-20-10Z");
+        assert_eq!(
+            snapshot_cmdline(
+                "BOOT_IMAGE=/@/boot/vmlinuz-linux root=UUID=1234 rw rootflags=subvol=@,compress=zstd quiet\n",
+                &snapshot
+            ),
+            "root=UUID=1234 rw rootflags=compress=zstd,subvol=/.blkcapt/snapshots/abc/2020-08-23T17-20-10Z quiet"
+        );
+    }
+
+    #[test]
+    fn cmdline_adds_rootflags() {
+        let snapshot = FsPathBuf::from(".blkcapt/snapshots/abc/2020-08-23T17-20-10Z");
+        assert_eq!(
+            snapshot_cmdline("initrd=\\initramfs-linux.img root=UUID=1234 rw", &snapshot),
+            "root=UUID=1234 rw rootflags=subvol=/.blkcapt/snapshots/abc/2020-08-23T17-20-10Z"
+        );
+    }
+}
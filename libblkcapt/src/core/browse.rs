@@ -0,0 +1,302 @@
+use super::{BtrfsContainer, BtrfsDataset, BtrfsPool, BtrfsSnapshot};
+use crate::{
+    core::system::{FileKind, SnapshotFileEntry},
+    model::{entities::BtrfsPoolEntity, Entity},
+    sys::process::output_to_result,
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use std::{
+    fs::{self, Metadata},
+    path::{Component, Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::Arc,
+};
+use uuid::Uuid;
+
+/// Find a local dataset snapshot or a container copy by its UUID. Pools that fail validation are skipped. Returns
+/// the dataset or container holding the snapshot along with it.
+pub fn find_snapshot_by_uuid(
+    pools: &[BtrfsPoolEntity], uuid: Uuid,
+) -> Result<Option<(String, Box<dyn BtrfsSnapshot>)>> {
+    for pool_model in pools.iter() {
+        let pool = match BtrfsPool::validate(pool_model.clone()) {
+            Ok(pool) => Arc::new(pool),
+            Err(e) => {
+                slog_scope::warn!("Skipping pool {}: {:#}", pool_model.name(), e);
+                continue;
+            }
+        };
+        if let Some(found) = find_snapshot_in_pool(&pool, uuid)? {
+            return Ok(Some(found));
+        }
+    }
+    Ok(None)
+}
+
+fn find_snapshot_in_pool(pool: &Arc<BtrfsPool>, uuid: Uuid) -> Result<Option<(String, Box<dyn BtrfsSnapshot>)>> {
+    for dataset_model in pool.model().datasets.iter() {
+        let dataset = Arc::new(BtrfsDataset::validate(pool, dataset_model.clone())?);
+        if let Some(snapshot) = dataset.snapshots()?.into_iter().find(|s| s.uuid() == uuid) {
+            return Ok(Some((dataset.to_string(), Box::new(snapshot))));
+        }
+    }
+
+    for container_model in pool.model().containers.iter() {
+        let container = Arc::new(BtrfsContainer::validate(pool, container_model.clone())?);
+        for dataset_id in container.source_dataset_ids()? {
+            if let Some(snapshot) = container.snapshots(dataset_id)?.into_iter().find(|s| s.uuid() == uuid) {
+                return Ok(Some((container.to_string(), Box::new(snapshot))));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Resolve a path relative to a snapshot root, refusing anything that leads outside the snapshot, including
+/// through symlinks stored in it.
+pub fn resolve_snapshot_path(root: &Path, relative: &Path) -> Result<PathBuf> {
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Path {:?} must be relative to the snapshot root.", relative);
+    }
+
+    let root = root.canonicalize().context("Snapshot root not found.")?;
+    let resolved = root
+        .join(relative)
+        .canonicalize()
+        .with_context(|| format!("Path {:?} not found in snapshot.", relative))?;
+    if !resolved.starts_with(&root) {
+        bail!("Path {:?} leads outside the snapshot.", relative);
+    }
+    Ok(resolved)
+}
+
+/// List a directory inside a snapshot, sorted by name. Symlinks are reported, not followed.
+pub fn list_snapshot_dir(snapshot: &dyn BtrfsSnapshot, relative: &Path) -> Result<Vec<SnapshotFileEntry>> {
+    let dir = resolve_snapshot_path(&snapshot.local_path(), relative)?;
+    let mut entries = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to list {:?} in snapshot.", relative))?
+        .map(|entry| -> Result<SnapshotFileEntry> {
+            let entry = entry?;
+            Ok(file_entry(
+                entry.file_name().to_string_lossy().into_owned(),
+                &entry.metadata()?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Copy a file or directory out of a snapshot, keeping ownership, modes, times and extended attributes. On the same
+/// filesystem the copy shares its data with the snapshot. An existing directory at `dest` receives the copy under its
+/// original name. Returns the path of the copy.
+pub fn extract_from_snapshot(snapshot: &dyn BtrfsSnapshot, relative: &Path, dest: &Path) -> Result<PathBuf> {
+    let source = resolve_snapshot_path(&snapshot.local_path(), relative)?;
+    let target = match dest.is_dir() {
+        true => dest.join(source.file_name().context("Extracted path has no name.")?),
+        false => dest.to_path_buf(),
+    };
+    if target.symlink_metadata().is_ok() {
+        bail!("Destination {:?} already exists.", target);
+    }
+
+    let mut command = Command::new("cp");
+    command
+        .args(&["--archive", "--reflink=auto", "--no-target-directory"])
+        .arg(&source)
+        .arg(&target);
+    output_to_result(command.output())
+        .with_context(|| format!("Failed to copy {:?} out of the snapshot.", relative))?;
+    Ok(target)
+}
+
+fn file_entry(name: String, metadata: &Metadata) -> SnapshotFileEntry {
+    let file_type = metadata.file_type();
+    SnapshotFileEntry {
+        name,
+        kind: if file_type.is_dir() {
+            FileKind::Directory
+        } else if file_type.is_file() {
+            FileKind::File
+        } else if file_type.is_symlink() {
+            FileKind::Symlink
+        } else {
+            FileKind::Other
+        },
+        size: metadata.len(),
+        modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+    }
+}
+
+/// A shell style pattern for paths relative to a snapshot root. `*` and `?` match within one path component, `**`
+/// matches any number of components.
+#[derive(Debug)]
+pub struct PathGlob {
+    regex: Regex,
+    base: PathBuf,
+    max_depth: Option<usize>,
+}
+
+impl PathGlob {
+    pub fn is_match(&self, relative: &Path) -> bool {
+        self.regex.is_match(&relative.to_string_lossy())
+    }
+}
+
+impl FromStr for PathGlob {
+    type Err = anyhow::Error;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let pattern = pattern.trim_start_matches('/');
+        let components = pattern.split('/').filter(|c| !c.is_empty()).collect::<Vec<_>>();
+        if components.is_empty() {
+            bail!("The path pattern is empty.");
+        }
+        if components.iter().any(|&c| c == "..") {
+            bail!("The path pattern can't lead outside the snapshot.");
+        }
+
+        // The directories before the first wildcard are fixed, so a search can start below them.
+        let base = components
+            .iter()
+            .take(components.len() - 1)
+            .take_while(|c| !c.contains(|ch| ch == '*' || ch == '?'))
+            .collect::<PathBuf>();
+
+        let mut expression = String::from("^");
+        for (index, component) in components.iter().enumerate() {
+            let last = index == components.len() - 1;
+            if *component == "**" {
+                expression.push_str(if last { ".*" } else { "(?:[^/]+/)*" });
+                continue;
+            }
+            for ch in component.chars() {
+                match ch {
+                    '*' => expression.push_str("[^/]*"),
+                    '?' => expression.push_str("[^/]"),
+                    ch => expression.push_str(&regex::escape(&ch.to_string())),
+                }
+            }
+            if !last {
+                expression.push('/');
+            }
+        }
+        expression.push('$');
+
+        Ok(Self {
+            regex: Regex::new(&expression)?,
+            base,
+            max_depth: if components.contains(&"**") {
+                None
+            } else {
+                Some(components.len())
+            },
+        })
+    }
+}
+
+/// Every path in a snapshot matching the glob, named by its path relative to the snapshot root. Symlinks are
+/// reported, not followed.
+pub fn find_in_snapshot(snapshot: &dyn BtrfsSnapshot, glob: &PathGlob) -> Result<Vec<SnapshotFileEntry>> {
+    let root = snapshot.local_path();
+    let base = match resolve_snapshot_path(&root, &glob.base) {
+        Ok(base) => base,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let root = root.canonicalize()?;
+
+    let mut found = Vec::new();
+    let mut pending = vec![base];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                slog_scope::warn!("Skipping {:?} in snapshot: {}", dir, e);
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+            let relative = path.strip_prefix(&root).expect("walk stays below the snapshot root");
+            let depth = relative.components().count();
+
+            if glob.is_match(relative) {
+                found.push(file_entry(relative.to_string_lossy().into_owned(), &metadata));
+            }
+            if metadata.is_dir() && glob.max_depth.map_or(true, |max| depth < max) {
+                pending.push(path);
+            }
+        }
+    }
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn path_glob_matches_within_components() {
+        let glob = "home/*/notes-?.txt".parse::<PathGlob>().unwrap();
+        assert_eq!(glob.base, Path::new("home"));
+        assert_eq!(glob.max_depth, Some(3));
+        assert!(glob.is_match(Path::new("home/alex/notes-1.txt")));
+        assert!(!glob.is_match(Path::new("home/alex/old/notes-1.txt")));
+        assert!(!glob.is_match(Path::new("home/alex/notes-10.txt")));
+    }
+
+    #[test]
+    fn path_glob_double_star_spans_components() {
+        let glob = "/srv/**/*.db".parse::<PathGlob>().unwrap();
+        assert_eq!(glob.base, Path::new("srv"));
+        assert_eq!(glob.max_depth, None);
+        assert!(glob.is_match(Path::new("srv/app.db")));
+        assert!(glob.is_match(Path::new("srv/data/2020/app.db")));
+        assert!(!glob.is_match(Path::new("var/app.db")));
+    }
+
+    #[test]
+    fn path_glob_rejects_parent_components() {
+        assert!("../etc/passwd".parse::<PathGlob>().is_err());
+        assert!("/".parse::<PathGlob>().is_err());
+    }
+
+    #[test]
+    fn snapshot_paths_stay_inside_the_snapshot() {
+        let scratch = tempdir().unwrap();
+        let dir = scratch.path().canonicalize().unwrap();
+        let root = dir.join("snapshot");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::create_dir_all(dir.join("snapshot-other")).unwrap();
+        fs::write(root.join("docs/a.txt"), "inside").unwrap();
+        fs::write(dir.join("snapshot-other/secret.txt"), "outside").unwrap();
+        symlink("docs", root.join("inner")).unwrap();
+        symlink("../snapshot-other", root.join("escape")).unwrap();
+        symlink(dir.join("snapshot-other"), root.join("absolute")).unwrap();
+
+        let resolved = |relative: &str| resolve_snapshot_path(&root, Path::new(relative));
+        assert_eq!(resolved("").unwrap(), root);
+        assert_eq!(resolved(".").unwrap(), root);
+        assert_eq!(resolved("./docs/a.txt").unwrap(), root.join("docs/a.txt"));
+        assert_eq!(resolved("inner/a.txt").unwrap(), root.join("docs/a.txt"));
+        assert!(resolved("../snapshot-other/secret.txt").is_err());
+        assert!(resolved("docs/../../snapshot-other").is_err());
+        assert!(resolved("docs/..").is_err());
+        assert!(resolved(&dir.join("snapshot-other").to_string_lossy()).is_err());
+        assert!(resolved("escape/secret.txt").is_err());
+        assert!(resolved("absolute").is_err());
+        assert!(resolved("docs/missing.txt").is_err());
+    }
+}
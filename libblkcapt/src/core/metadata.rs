@@ -0,0 +1,363 @@
+use super::{parse_snapshot_label, BtrfsDataset, BtrfsPool, BLKCAPT_FS_META_DIR};
+use crate::{
+    model::{Entity, EntityId},
+    sys::{btrfs::Subvolume, fs::FsPathBuf},
+};
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, path::Path, str::FromStr};
+use uuid::Uuid;
+
+const XATTR_PREFIX: &str = "user.blkcapt.";
+const DATASET_ID_ATTR: &str = "dataset_id";
+const DATASET_NAME_ATTR: &str = "dataset_name";
+const DATASET_UUID_ATTR: &str = "dataset_uuid";
+const POOL_ID_ATTR: &str = "pool_id";
+const POOL_NAME_ATTR: &str = "pool_name";
+const VERSION_ATTR: &str = "version";
+
+/// Where a snapshot came from, kept in extended attributes on the snapshot root so it outlives the entity store.
+/// btrfs send carries the attributes along, so copies received into containers have them too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotMetadata {
+    pub dataset_id: EntityId,
+    pub dataset_name: String,
+    /// The dataset's subvolume at the time, to find it again after the config is lost.
+    pub dataset_uuid: Uuid,
+    pub pool_id: EntityId,
+    pub pool_name: String,
+    /// Version of blkcapt that took the snapshot.
+    pub version: String,
+}
+
+impl SnapshotMetadata {
+    pub fn for_dataset(dataset: &BtrfsDataset) -> Self {
+        let pool = dataset.pool().model();
+        Self {
+            dataset_id: dataset.model().id(),
+            dataset_name: dataset.model().name().to_owned(),
+            dataset_uuid: dataset.uuid(),
+            pool_id: pool.id(),
+            pool_name: pool.name().to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        }
+    }
+
+    /// Write the metadata to a snapshot that is still writable. Snapshots are only made read-only after, a read-only
+    /// snapshot is never flipped back.
+    pub fn write(&self, snapshot_path: &Path) -> Result<()> {
+        self.attributes().iter().try_for_each(|(name, value)| {
+            xattr::set(snapshot_path, XATTR_PREFIX.to_owned() + name, value.as_bytes())
+                .with_context(|| format!("Failed to set {}{} on {:?}.", XATTR_PREFIX, name, snapshot_path))
+        })
+    }
+
+    /// Metadata of a snapshot, or None for snapshots taken before it was recorded.
+    pub fn read(snapshot_path: &Path) -> Result<Option<Self>> {
+        let mut attributes = BTreeMap::new();
+        for name in xattr::list(snapshot_path)
+            .with_context(|| format!("Failed to list extended attributes of {:?}.", snapshot_path))?
+        {
+            let full_name = name.to_string_lossy();
+            if let Some(short_name) = full_name.strip_prefix(XATTR_PREFIX) {
+                if let Some(value) = xattr::get(snapshot_path, &name)? {
+                    attributes.insert(short_name.to_owned(), String::from_utf8_lossy(&value).into_owned());
+                }
+            }
+        }
+        Self::from_attributes(&attributes)
+    }
+
+    fn attributes(&self) -> BTreeMap<String, String> {
+        vec![
+            (DATASET_ID_ATTR, self.dataset_id.to_string()),
+            (DATASET_NAME_ATTR, self.dataset_name.clone()),
+            (DATASET_UUID_ATTR, self.dataset_uuid.to_string()),
+            (POOL_ID_ATTR, self.pool_id.to_string()),
+            (POOL_NAME_ATTR, self.pool_name.clone()),
+            (VERSION_ATTR, self.version.clone()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect()
+    }
+
+    fn from_attributes(attributes: &BTreeMap<String, String>) -> Result<Option<Self>> {
+        let dataset_id = match attributes.get(DATASET_ID_ATTR) {
+            Some(value) => EntityId::from_str(value)?,
+            None => return Ok(None),
+        };
+        let text = |name: &str| attributes.get(name).cloned().unwrap_or_default();
+        let uuid = |name: &str| -> Result<Uuid> {
+            attributes
+                .get(name)
+                .map_or(Ok(Uuid::nil()), |value| Uuid::parse_str(value))
+                .with_context(|| format!("Invalid snapshot metadata {}.", name))
+        };
+        Ok(Some(Self {
+            dataset_id,
+            dataset_name: text(DATASET_NAME_ATTR),
+            dataset_uuid: uuid(DATASET_UUID_ATTR)?,
+            pool_id: attributes
+                .get(POOL_ID_ATTR)
+                .map_or(Ok(EntityId::default()), |value| EntityId::from_str(value))?,
+            pool_name: text(POOL_NAME_ATTR),
+            version: text(VERSION_ATTR),
+        }))
+    }
+}
+
+/// A dataset found through the snapshots it left on a pool.
+#[derive(Debug)]
+pub struct RecoveredDataset {
+    pub id: EntityId,
+    /// Metadata of the newest snapshot that has any.
+    pub metadata: Option<SnapshotMetadata>,
+    /// The dataset's subvolume, when it still exists on the pool.
+    pub subvolume: Option<Subvolume>,
+    pub snapshots: usize,
+    /// Source subvolume of the newest snapshot, which finds the dataset when its snapshots have no metadata.
+    parent_uuid: Option<Uuid>,
+}
+
+/// A container found through the snapshots received into it.
+#[derive(Debug)]
+pub struct RecoveredContainer {
+    pub subvolume: Subvolume,
+    pub sources: Vec<RecoveredSource>,
+}
+
+/// A dataset whose snapshots were received into a container.
+#[derive(Debug)]
+pub struct RecoveredSource {
+    pub dataset_id: EntityId,
+    /// Metadata of the newest received snapshot that has any.
+    pub metadata: Option<SnapshotMetadata>,
+    pub snapshots: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveredMappings {
+    pub datasets: Vec<RecoveredDataset>,
+    pub containers: Vec<RecoveredContainer>,
+}
+
+/// Whether blkcapt ever used the filesystem mounted here, so there is anything to recover.
+pub fn has_recoverable_state(mountpoint: &Path) -> bool {
+    mountpoint.join(BLKCAPT_FS_META_DIR).is_dir()
+}
+
+/// Rebuild which datasets and containers live on a pool from the snapshots stored there. Dataset snapshots live
+/// below `.blkcapt/snapshots/<dataset id>`, received snapshots below `<container>/<dataset id>` with a `.bcrcv`
+/// extension. Snapshot metadata fills in names, the dataset subvolumes are found through it or the snapshots' parent.
+pub fn recover_mappings(pool: &BtrfsPool) -> Result<RecoveredMappings> {
+    let mountpoint = &pool.filesystem.fstree_mountpoint;
+    let subvolumes = pool.filesystem.list_all_subvolumes()?;
+    Ok(classify_subvolumes(&subvolumes, |snapshot| {
+        SnapshotMetadata::read(&snapshot.path.as_pathbuf(mountpoint)).unwrap_or_else(|e| {
+            slog_scope::warn!("Failed to read snapshot metadata of {:?}: {:#}", snapshot.path, e);
+            None
+        })
+    }))
+}
+
+fn classify_subvolumes(
+    subvolumes: &[Subvolume], read_metadata: impl Fn(&Subvolume) -> Option<SnapshotMetadata>,
+) -> RecoveredMappings {
+    let snapshots_dir = FsPathBuf::from(BLKCAPT_FS_META_DIR).join("snapshots");
+    let is_snapshot = |s: &Subvolume| {
+        s.path
+            .file_stem()
+            .map_or(false, |n| parse_snapshot_label(&n.to_string_lossy()).is_ok())
+    };
+    let dataset_dir_id = |s: &Subvolume| {
+        s.path.parent().and_then(|p| {
+            p.file_name()
+                .and_then(|n| EntityId::from_str(&n.to_string_lossy()).ok())
+        })
+    };
+
+    // newest snapshots last, so the newest metadata wins
+    let mut snapshots = subvolumes.iter().filter(|s| is_snapshot(s)).collect::<Vec<_>>();
+    snapshots.sort_unstable_by_key(|s| s.path.file_name().map(|n| n.to_owned()));
+
+    let mut datasets = BTreeMap::<EntityId, RecoveredDataset>::new();
+    let mut containers = BTreeMap::<FsPathBuf, BTreeMap<EntityId, RecoveredSource>>::new();
+    for snapshot in snapshots {
+        let dataset_id = match dataset_dir_id(snapshot) {
+            Some(id) => id,
+            None => continue,
+        };
+        let metadata = read_metadata(snapshot);
+
+        if snapshot.path.starts_with(&snapshots_dir) && snapshot.path.extension().is_none() {
+            let dataset = datasets.entry(dataset_id).or_insert_with(|| RecoveredDataset {
+                id: dataset_id,
+                metadata: None,
+                subvolume: None,
+                snapshots: 0,
+                parent_uuid: None,
+            });
+            dataset.snapshots += 1;
+            if snapshot.parent_uuid.is_some() {
+                dataset.parent_uuid = snapshot.parent_uuid;
+            }
+            if metadata.is_some() {
+                dataset.metadata = metadata;
+            }
+        } else if snapshot.path.extension() == Some("bcrcv".as_ref()) && snapshot.received_uuid.is_some() {
+            let container_path = match snapshot.path.parent().and_then(|p| p.parent()) {
+                Some(path) => path,
+                None => continue,
+            };
+            let source = containers
+                .entry(container_path)
+                .or_default()
+                .entry(dataset_id)
+                .or_insert_with(|| RecoveredSource {
+                    dataset_id,
+                    metadata: None,
+                    snapshots: 0,
+                });
+            source.snapshots += 1;
+            if metadata.is_some() {
+                source.metadata = metadata;
+            }
+        }
+    }
+
+    for dataset in datasets.values_mut() {
+        let find = |uuid: Uuid| subvolumes.iter().find(|s| s.uuid == uuid).cloned();
+        dataset.subvolume = dataset
+            .metadata
+            .as_ref()
+            .and_then(|m| find(m.dataset_uuid))
+            .or_else(|| dataset.parent_uuid.and_then(find));
+    }
+
+    let containers = containers
+        .into_iter()
+        .filter_map(|(path, sources)| {
+            let subvolume = subvolumes.iter().find(|s| s.path == path)?.clone();
+            Some(RecoveredContainer {
+                subvolume,
+                sources: sources.into_iter().map(|(_, s)| s).collect(),
+            })
+        })
+        .collect();
+
+    RecoveredMappings {
+        datasets: datasets.into_iter().map(|(_, d)| d).collect(),
+        containers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_attributes_roundtrip() {
+        let metadata = SnapshotMetadata {
+            dataset_id: EntityId::from_str("f0fd6e0e-3e61-4b3c-a3ea-8fdbeb4b1c6c").unwrap(),
+            dataset_name: "root".to_owned(),
+            dataset_uuid: Uuid::new_v4(),
+            pool_id: EntityId::from_str("1b7a0ba7-55a0-4b4b-9c54-5d2a9a1b2c3d").unwrap(),
+            pool_name: "system".to_owned(),
+            version: "0.1.0".to_owned(),
+        };
+
+        let parsed = SnapshotMetadata::from_attributes(&metadata.attributes()).unwrap();
+
+        assert_eq!(parsed, Some(metadata));
+    }
+
+    fn subvolume(path: &str, parent_uuid: Option<Uuid>, received_uuid: Option<Uuid>) -> Subvolume {
+        Subvolume {
+            uuid: Uuid::new_v4(),
+            path: FsPathBuf::from(path),
+            parent_uuid,
+            received_uuid,
+            generation: 10,
+            creation_generation: 10,
+        }
+    }
+
+    #[test]
+    fn subvolumes_are_classified_into_datasets_and_containers() {
+        let home_id = EntityId::from_str("f0fd6e0e-3e61-4b3c-a3ea-8fdbeb4b1c6c").unwrap();
+        let gone_id = EntityId::from_str("1b7a0ba7-55a0-4b4b-9c54-5d2a9a1b2c3d").unwrap();
+        let home = subvolume("home", None, None);
+        let older = subvolume(
+            &format!(".blkcapt/snapshots/{}/2020-11-29T21-26-00Z", home_id),
+            Some(home.uuid),
+            None,
+        );
+        let newer = subvolume(
+            &format!(".blkcapt/snapshots/{}/2020-11-30T21-26-00Z", home_id),
+            Some(home.uuid),
+            None,
+        );
+        let orphan = subvolume(
+            &format!(".blkcapt/snapshots/{}/2020-11-29T21-26-00Z", gone_id),
+            Some(Uuid::new_v4()),
+            None,
+        );
+        let backups = subvolume("backups", None, None);
+        let received_dir = subvolume(&format!("backups/{}", home_id), None, None);
+        let received = subvolume(
+            &format!("backups/{}/2020-11-29T21-26-00Z.bcrcv", home_id),
+            None,
+            Some(older.uuid),
+        );
+        let partial = subvolume(&format!("backups/{}/2020-11-30T21-26-00Z.bcrcv", home_id), None, None);
+        let unrelated = subvolume("srv/2020-11-29T21-26-00Z", None, None);
+        let newer_path = newer.path.clone();
+        let metadata = SnapshotMetadata {
+            dataset_id: home_id,
+            dataset_name: "home".to_owned(),
+            dataset_uuid: home.uuid,
+            pool_id: EntityId::default(),
+            pool_name: "tank".to_owned(),
+            version: "0.1.0".to_owned(),
+        };
+        let subvolumes = vec![
+            newer,
+            orphan,
+            home.clone(),
+            received,
+            older,
+            backups.clone(),
+            received_dir,
+            partial,
+            unrelated,
+        ];
+
+        let mappings = classify_subvolumes(&subvolumes, |s| match s.path == newer_path {
+            true => Some(metadata.clone()),
+            false => None,
+        });
+
+        assert_eq!(mappings.datasets.len(), 2);
+        let recovered_home = mappings.datasets.iter().find(|d| d.id == home_id).unwrap();
+        assert_eq!(recovered_home.snapshots, 2);
+        assert_eq!(recovered_home.metadata, Some(metadata));
+        assert_eq!(recovered_home.subvolume.as_ref().map(|s| s.uuid), Some(home.uuid));
+        let recovered_gone = mappings.datasets.iter().find(|d| d.id == gone_id).unwrap();
+        assert_eq!(recovered_gone.snapshots, 1);
+        assert!(recovered_gone.subvolume.is_none());
+
+        assert_eq!(mappings.containers.len(), 1);
+        assert_eq!(mappings.containers[0].subvolume.uuid, backups.uuid);
+        assert_eq!(mappings.containers[0].sources.len(), 1);
+        assert_eq!(mappings.containers[0].sources[0].dataset_id, home_id);
+        assert_eq!(mappings.containers[0].sources[0].snapshots, 1);
+    }
+
+    #[test]
+    fn metadata_without_dataset_id_is_none() {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(VERSION_ATTR.to_owned(), "0.1.0".to_owned());
+
+        assert_eq!(SnapshotMetadata::from_attributes(&attributes).unwrap(), None);
+    }
+}
@@ -1,13 +1,27 @@
+pub mod archive;
+pub mod bootmenu;
+pub mod browse;
+pub mod metadata;
+pub mod notify;
+pub mod remote;
 pub mod restic;
+pub mod restore;
 pub mod retention;
 pub mod system;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
+pub mod verify;
+use crate::core::metadata::SnapshotMetadata;
 use crate::sys::fs::{lookup_mountentry, BlockDeviceIds, BtrfsMountEntry, FsPathBuf};
 use crate::{
     model::entities::{
         BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity, HealthchecksObservation, ObservableEvent,
-        SubvolumeEntity,
+        RestoreDivergence, SubvolumeEntity,
+    },
+    sys::{
+        net::HttpsClient,
+        privilege::{running_as_root, UnprivilegedAccess},
     },
-    sys::net::HttpsClient,
 };
 use crate::{
     model::Entity,
@@ -15,18 +29,21 @@ use crate::{
 };
 use crate::{
     model::EntityId,
-    sys::btrfs::{Filesystem, MountedFilesystem, Subvolume},
+    sys::btrfs::{
+        is_read_only, set_read_only, Filesystem, MountedFilesystem, QGroupId, Subvolume, SubvolumeProperties,
+    },
 };
 use anyhow::{anyhow, bail, Context, Result};
 use chrono::{DateTime, NaiveDateTime, Timelike, Utc};
 use derivative::Derivative;
 use hyper::Uri;
-use std::path::PathBuf;
-use std::{convert::TryFrom, str::FromStr, sync::Arc};
+use std::path::{Path, PathBuf};
+use std::{convert::TryFrom, str::FromStr, sync::Arc, time::Duration};
 use std::{fmt::Debug, fmt::Display, fs};
 use uuid::Uuid;
 
 const BLKCAPT_FS_META_DIR: &str = ".blkcapt";
+const TRASH_TIMESTAMP_FORMAT: &str = "%FT%H-%M-%SZ";
 
 #[derive(Debug)]
 pub struct BtrfsPool {
@@ -103,6 +120,182 @@ impl BtrfsPool {
         self.filesystem.scrub()
     }
 
+    pub async fn trim(&self) -> Result<u64> {
+        self.filesystem.trim().await
+    }
+
+    /// Whether the pool is mounted with a discard option, making scheduled trims mostly redundant.
+    pub fn continuous_discard(&self) -> Result<bool> {
+        lookup_mountentry(&self.filesystem.fstree_mountpoint)
+            .context("Pool mountpoint not found in the mount table.")
+            .and_then(BtrfsMountEntry::try_from)
+            .map(|mount| mount.continuous_discard())
+    }
+
+    /// Fails with a [`PoolNearlyFullError`] when the pool is too close to full to safely take a snapshot. Without
+    /// root only the free space reported by statvfs is checked.
+    pub fn check_free_space(&self) -> Result<()> {
+        let (free, metadata_headroom) = if running_as_root() {
+            let usage = self.filesystem.usage()?;
+            (usage.free_estimated, Some(usage.metadata_headroom()))
+        } else {
+            (self.filesystem.available_bytes()?, None)
+        };
+        PoolNearlyFullError::check(&self.model, free, metadata_headroom)?;
+        Ok(())
+    }
+
+    /// Every quota group on the pool, empty when quotas aren't enabled.
+    pub fn qgroups_in_use(&self) -> Result<Vec<QGroupId>> {
+        match self.filesystem.qgroups() {
+            Ok(qgroups) => Ok(qgroups.into_iter().map(|q| q.id).collect()),
+            Err(e) if MountedFilesystem::quotas_disabled(&e) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a quota group if it is missing and assign it every subvolume at or below `paths` that isn't already a
+    /// member. Quotas are enabled first when they aren't yet and the pool is configured with a quota group
+    /// hierarchy. Returns the number of subvolumes assigned.
+    pub fn sync_qgroup(&self, qgroup: QGroupId, paths: &[FsPathBuf]) -> Result<usize> {
+        let qgroups = match self.filesystem.qgroups() {
+            Ok(qgroups) => qgroups,
+            Err(e) if self.model.qgroup_hierarchy && MountedFilesystem::quotas_disabled(&e) => {
+                slog_scope::info!("Enabling quotas on pool {}.", self.model.name());
+                self.filesystem.enable_quota()?;
+                self.filesystem.qgroups()?
+            }
+        };
+        if !qgroups.iter().any(|q| q.id == qgroup) {
+            self.filesystem.create_qgroup(qgroup)?;
+        }
+
+        let mut assigned = 0;
+        for (path, id) in self.filesystem.subvolume_ids()? {
+            if !paths.iter().any(|p| path.starts_with(p)) {
+                continue;
+            }
+            let member = QGroupId::subvolume(id);
+            let is_assigned = qgroups
+                .iter()
+                .find(|q| q.id == member)
+                .map_or(false, |q| q.parents.contains(&qgroup));
+            if !is_assigned {
+                self.filesystem.assign_qgroup(member, qgroup)?;
+                assigned += 1;
+            }
+        }
+        Ok(assigned)
+    }
+
+    /// Where the pool's top-level subvolume is mounted, which dataset and container paths are relative to.
+    pub fn fstree_mountpoint(&self) -> &Path {
+        &self.filesystem.fstree_mountpoint
+    }
+
+    /// Create an empty subvolume for a container whose subvolume is missing, like on a recreated pool. Returns
+    /// whether it had to be created.
+    pub fn recreate_container(&self, container: &mut BtrfsContainerEntity) -> Result<bool> {
+        let container_path = container.path.as_pathbuf(&self.filesystem.fstree_mountpoint);
+        if container_path.exists() {
+            return Ok(false);
+        }
+        if let Some(parent) = container_path.parent() {
+            fs::create_dir_all(parent).context("Failed to create parent directories for the container.")?;
+        }
+        self.filesystem.create_subvolume(&container.path)?;
+        container.uuid = self.filesystem.subvolume_by_path(&container.path)?.uuid;
+        Ok(true)
+    }
+
+    /// All subvolumes on the pool except blkcapt's own snapshot storage.
+    pub fn user_subvolumes(&self) -> Result<Vec<Subvolume>> {
+        let meta_dir = FsPathBuf::from(BLKCAPT_FS_META_DIR);
+        Ok(self
+            .filesystem
+            .list_all_subvolumes()?
+            .into_iter()
+            .filter(|s| !s.path.starts_with(&meta_dir))
+            .collect())
+    }
+
+    /// Move a snapshot into the pool's trash, from where it is deleted once `keep_for` has passed.
+    pub fn trash_subvolume(&self, path: &FsPathBuf, keep_for: Duration) -> Result<()> {
+        let trash_path = trash_container_path();
+        if !trash_path.as_pathbuf(&self.filesystem.fstree_mountpoint).exists() {
+            self.filesystem.create_subvolume(&trash_path)?;
+        }
+
+        let expires = Utc::now() + chrono::Duration::from_std(keep_for)?;
+        let target_path = trash_path
+            .join(expires.format(TRASH_TIMESTAMP_FORMAT).to_string())
+            .join(path);
+        self.filesystem.move_subvolume(path, &target_path)
+    }
+
+    pub fn trashed_snapshots(&self) -> Result<Vec<TrashedSnapshot>> {
+        let trash_path = trash_container_path();
+        if !trash_path.as_pathbuf(&self.filesystem.fstree_mountpoint).exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut trashed = self
+            .filesystem
+            .list_subvolumes(&trash_path)?
+            .into_iter()
+            .filter_map(|subvolume| {
+                let relative_path = subvolume.path.strip_prefix(&trash_path)?;
+                let mut components = Path::new(relative_path.as_ref()).components();
+                let expires = NaiveDateTime::parse_from_str(
+                    &components.next()?.as_os_str().to_string_lossy(),
+                    TRASH_TIMESTAMP_FORMAT,
+                )
+                .ok()?;
+                Some(TrashedSnapshot {
+                    original_path: FsPathBuf::from(components.as_path()),
+                    expires: DateTime::<Utc>::from_utc(expires, Utc),
+                    subvolume,
+                })
+            })
+            .collect::<Vec<_>>();
+        trashed.sort_unstable_by_key(|t| t.expires);
+        Ok(trashed)
+    }
+
+    /// Delete trashed snapshots that expired before `now`, or all of them when space is needed. Returns the
+    /// number deleted.
+    pub fn empty_trash(&self, now: Option<DateTime<Utc>>) -> Result<usize> {
+        let mut deleted = 0;
+        for trashed in self.trashed_snapshots()? {
+            if now.map_or(true, |now| trashed.expires <= now) {
+                self.filesystem.delete_subvolume(&trashed.subvolume.path)?;
+                self.remove_empty_trash_dir(&trashed);
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Move a trashed snapshot back to where it was pruned from.
+    pub fn restore_trashed(&self, uuid: Uuid) -> Result<FsPathBuf> {
+        let trashed = self
+            .trashed_snapshots()?
+            .into_iter()
+            .find(|t| t.subvolume.uuid == uuid)
+            .context("no trashed snapshot with that uuid")?;
+        self.filesystem
+            .move_subvolume(&trashed.subvolume.path, &trashed.original_path)?;
+        self.remove_empty_trash_dir(&trashed);
+        Ok(trashed.original_path)
+    }
+
+    fn remove_empty_trash_dir(&self, trashed: &TrashedSnapshot) {
+        // Best effort, the directories only group snapshots by expiry. remove_dir fails while others remain.
+        let mut dir = trashed.subvolume.path.as_pathbuf(&self.filesystem.fstree_mountpoint);
+        let trash_dir = trash_container_path().as_pathbuf(&self.filesystem.fstree_mountpoint);
+        while dir.pop() && dir != trash_dir && fs::remove_dir(&dir).is_ok() {}
+    }
+
     pub fn create_dataset(self: &Arc<Self>, name: String) -> Result<BtrfsDataset> {
         let fs_path = FsPathBuf::from(&name);
         self.filesystem.create_subvolume(&fs_path)?;
@@ -122,6 +315,35 @@ impl Display for BtrfsPool {
     }
 }
 
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error(
+    "pool {pool} nearly full: {free} bytes free{}, refusing to create a snapshot",
+    .metadata_headroom.map(|h| format!(" and {} bytes available for metadata", h)).unwrap_or_default()
+)]
+pub struct PoolNearlyFullError {
+    pub pool: String,
+    pub free: u64,
+    /// Unknown when not running as root.
+    pub metadata_headroom: Option<u64>,
+}
+
+impl PoolNearlyFullError {
+    fn check(pool: &BtrfsPoolEntity, free: u64, metadata_headroom: Option<u64>) -> Result<(), Self> {
+        let min_free = pool.min_free_bytes.unwrap_or(BtrfsPoolEntity::DEFAULT_MIN_FREE_BYTES);
+        let min_metadata_headroom = pool
+            .min_metadata_headroom_bytes
+            .unwrap_or(BtrfsPoolEntity::DEFAULT_MIN_METADATA_HEADROOM_BYTES);
+        if free < min_free || metadata_headroom.map_or(false, |h| h < min_metadata_headroom) {
+            return Err(Self {
+                pool: pool.name().to_owned(),
+                free,
+                metadata_headroom,
+            });
+        }
+        Ok(())
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct BtrfsDataset {
@@ -135,12 +357,17 @@ impl BtrfsDataset {
     pub fn new(pool: &Arc<BtrfsPool>, name: String, path: PathBuf) -> Result<Self> {
         let subvolume = Subvolume::from_path(&path).context("Path does not resolve to a subvolume.")?;
 
-        let dataset = Self {
+        let mut dataset = Self {
             model: BtrfsDatasetEntity::new(name, subvolume.path.clone(), subvolume.uuid)?,
             subvolume,
             pool: Arc::clone(pool),
         };
 
+        dataset.model.properties = dataset.properties()?;
+        if dataset.model.properties.checksums_disabled() {
+            slog_scope::warn!("{}", SubvolumeProperties::CHECKSUMS_DISABLED_WARNING);
+        }
+
         let snapshot_path = dataset.snapshot_container_path();
         if !snapshot_path
             .as_pathbuf(&dataset.pool.filesystem.fstree_mountpoint)
@@ -154,11 +381,36 @@ impl BtrfsDataset {
     }
 
     pub fn create_local_snapshot(self: &Arc<Self>) -> Result<BtrfsDatasetSnapshot> {
+        self.pool.check_free_space()?;
+
         let now = Utc::now();
         let snapshot_path = self
             .snapshot_container_path()
             .join(now.format("%FT%H-%M-%SZ").to_string());
-        self.pool.filesystem.create_snapshot(&self.subvolume, &snapshot_path)?;
+        // taken writable so the metadata can go on before it is made read-only, nothing else knows of it yet
+        self.pool
+            .filesystem
+            .create_snapshot(&self.subvolume, &snapshot_path, self.model.qgroup, false)?;
+        // the snapshot itself is fine without its metadata, it's only needed to recover a lost config
+        let local_path = snapshot_path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        if let Err(e) = SnapshotMetadata::for_dataset(self).write(&local_path) {
+            slog_scope::warn!("Failed to write metadata to snapshot {:?}: {:#}", snapshot_path, e);
+        }
+        let sealed = set_read_only(&local_path, true).and_then(|_| match is_read_only(&local_path)? {
+            true => Ok(()),
+            false => Err(anyhow!("Snapshot {:?} is still writable.", snapshot_path)),
+        });
+        if let Err(e) = sealed {
+            // a writable snapshot must never be kept, sent or taken as a parent
+            if let Err(delete_error) = self.pool.filesystem.delete_subvolume(&snapshot_path) {
+                slog_scope::error!(
+                    "Failed to delete writable snapshot {:?}: {:#}",
+                    snapshot_path,
+                    delete_error
+                );
+            }
+            return Err(e.context(format!("Failed to make snapshot {:?} read-only.", snapshot_path)));
+        }
 
         self.pool
             .filesystem
@@ -170,6 +422,19 @@ impl BtrfsDataset {
             })
     }
 
+    /// Whether anything was written to the dataset after the snapshot was taken.
+    pub fn changed_since(&self, snapshot: &BtrfsDatasetSnapshot) -> Result<bool> {
+        let current = self.pool.filesystem.subvolume_by_path(&self.subvolume.path)?;
+        Ok(current.generation > snapshot.subvolume.creation_generation)
+    }
+
+    /// Bytes of file data written to the dataset after the snapshot was taken.
+    pub fn changed_bytes_since(&self, snapshot: &BtrfsDatasetSnapshot) -> Result<u64> {
+        self.pool
+            .filesystem
+            .subvolume_changed_bytes(&self.subvolume.path, snapshot.subvolume.creation_generation + 1)
+    }
+
     pub fn snapshots(self: &Arc<Self>) -> Result<Vec<BtrfsDatasetSnapshot>> {
         let mut snapshots = self
             .pool
@@ -209,11 +474,70 @@ impl BtrfsDataset {
         Ok(snapshots.pop())
     }
 
+    /// What this process could do with the dataset without root.
+    pub fn unprivileged_access(&self) -> Result<UnprivilegedAccess> {
+        let mountpoint = &self.pool.filesystem.fstree_mountpoint;
+        let mount = lookup_mountentry(mountpoint)
+            .context("Pool mountpoint not found in the mount table.")
+            .and_then(BtrfsMountEntry::try_from)?;
+        Ok(UnprivilegedAccess::probe(
+            &self.subvolume.path.as_pathbuf(mountpoint),
+            &self.snapshot_container_path().as_pathbuf(mountpoint),
+            &mount,
+        ))
+    }
+
+    pub fn pool(&self) -> &BtrfsPool {
+        &self.pool
+    }
+
+    /// Bring the dataset's quota group up to date with its subvolume and snapshots, when it has one.
+    pub fn sync_qgroup(&self) -> Result<usize> {
+        match self.model.qgroup {
+            Some(qgroup) => self
+                .pool
+                .sync_qgroup(qgroup, &[self.subvolume.path.clone(), self.snapshot_container_path()]),
+            None => Ok(0),
+        }
+    }
+
+    /// Bytes referenced by the dataset's quota group. None when the dataset has no quota group.
+    pub fn qgroup_referenced(&self) -> Result<Option<u64>> {
+        let qgroup = match self.model.qgroup {
+            Some(qgroup) => qgroup,
+            None => return Ok(None),
+        };
+        self.pool
+            .filesystem
+            .qgroups()?
+            .into_iter()
+            .find(|q| q.id == qgroup)
+            .map(|q| Some(q.referenced))
+            .ok_or_else(|| anyhow!("quota group {} doesn't exist", qgroup))
+    }
+
+    /// Blocks until space from deleted snapshots has been reclaimed by the filesystem.
+    pub fn sync_snapshot_deletes(&self) -> Result<()> {
+        self.pool.filesystem.sync_deleted_subvolumes()
+    }
+
+    /// Where the dataset's defrag job runs in the local filesystem. The whole dataset unless paths are configured.
+    pub fn defrag_paths(&self) -> Vec<PathBuf> {
+        let root = self.subvolume.path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        match self.model.defrag.as_ref().map(|d| &d.paths) {
+            Some(paths) if !paths.is_empty() => paths.iter().map(|p| root.join(p)).collect(),
+            _ => vec![root],
+        }
+    }
+
     pub fn snapshot_container_path(&self) -> FsPathBuf {
-        let mut builder = FsPathBuf::from(BLKCAPT_FS_META_DIR);
-        builder.push("snapshots");
-        builder.push(self.model.id().to_string());
-        builder
+        dataset_snapshot_container_path(self.model.id())
+    }
+
+    /// Where the snapshot container is reachable in the local filesystem.
+    pub fn snapshot_container_local_path(&self) -> PathBuf {
+        self.snapshot_container_path()
+            .as_pathbuf(&self.pool.filesystem.fstree_mountpoint)
     }
 
     pub fn uuid(&self) -> Uuid {
@@ -224,12 +548,29 @@ impl BtrfsDataset {
         self.subvolume.parent_uuid
     }
 
-    pub fn validate(pool: &Arc<BtrfsPool>, model: BtrfsDatasetEntity) -> Result<Self> {
+    pub fn properties(&self) -> Result<SubvolumeProperties> {
+        self.pool.filesystem.subvolume_properties(&self.subvolume.path)
+    }
+
+    pub fn validate(pool: &Arc<BtrfsPool>, mut model: BtrfsDatasetEntity) -> Result<Self> {
         let subvolume = pool
             .filesystem
             .subvolume_by_uuid(model.uuid())
             .context("Can't locate subvolume for existing dataset.")?;
 
+        if let Some(divergence) = new_divergence(
+            model.restore_divergence.as_ref(),
+            subvolume.parent_uuid,
+            |source_snapshot| Self::snapshot_datetime(pool, source_snapshot),
+        ) {
+            slog_scope::info!(
+                "Dataset {} was restored from snapshot {}. Recording the divergence point.",
+                model.name(),
+                divergence.source_snapshot
+            );
+            model.restore_divergence = Some(divergence);
+        }
+
         Ok(Self {
             model,
             subvolume,
@@ -237,6 +578,11 @@ impl BtrfsDataset {
         })
     }
 
+    fn snapshot_datetime(pool: &BtrfsPool, uuid: &Uuid) -> Option<DateTime<Utc>> {
+        let subvolume = pool.filesystem.subvolume_by_uuid(uuid).ok()?;
+        parse_snapshot_label(&subvolume.path.file_stem()?.to_string_lossy()).ok()
+    }
+
     pub fn state(&self) -> BtrfsDatasetState {
         match self.parent_uuid() {
             Some(parent_snapshot) => BtrfsDatasetState::Restored { parent_snapshot },
@@ -251,6 +597,36 @@ impl BtrfsDataset {
     pub fn take_model(self) -> BtrfsDatasetEntity {
         self.model
     }
+
+    /// The dataset's subvolume is the one mounted when neither subvol nor subvolid is given.
+    pub fn is_default_subvolume(&self) -> Result<bool> {
+        Ok(self.pool.filesystem.default_subvolume()?.as_ref() == Some(&self.model.path))
+    }
+
+    pub fn make_default_subvolume(&self) -> Result<()> {
+        self.pool.filesystem.set_default_subvolume(&self.model.path)
+    }
+}
+
+fn trash_container_path() -> FsPathBuf {
+    let mut builder = FsPathBuf::from(BLKCAPT_FS_META_DIR);
+    builder.push("trash");
+    builder
+}
+
+/// A snapshot moved aside by pruning instead of being deleted.
+#[derive(Debug, Clone)]
+pub struct TrashedSnapshot {
+    pub subvolume: Subvolume,
+    pub original_path: FsPathBuf,
+    pub expires: DateTime<Utc>,
+}
+
+fn dataset_snapshot_container_path(dataset_id: EntityId) -> FsPathBuf {
+    let mut builder = FsPathBuf::from(BLKCAPT_FS_META_DIR);
+    builder.push("snapshots");
+    builder.push(dataset_id.to_string());
+    builder
 }
 
 impl Display for BtrfsDataset {
@@ -271,7 +647,14 @@ pub trait Snapshot: Display {
 
 pub trait BtrfsSnapshot: Snapshot {
     fn uuid(&self) -> Uuid;
+    fn received_uuid(&self) -> Option<Uuid>;
+    fn size(&self) -> Result<u64>;
     fn delete(&self) -> Result<()>;
+    fn trash(&self, keep_for: Duration) -> Result<()>;
+    /// Create a writable copy of the snapshot that blkcapt does not manage.
+    fn clone_writable(&self, path: &Path) -> Result<()>;
+    /// Where the snapshot is reachable in the local filesystem.
+    fn local_path(&self) -> PathBuf;
 }
 
 #[derive(Clone, Derivative)]
@@ -300,6 +683,17 @@ impl BtrfsDatasetSnapshot {
         self.subvolume.received_uuid
     }
 
+    /// Snapshots taken of a subvolume that was later replaced by a restore don't share history with the current
+    /// subvolume past the divergence point. The snapshot the dataset was restored from is the exception.
+    pub fn is_pre_restore(&self) -> bool {
+        match self.state() {
+            BtrfsDatasetSnapshotState::Original { parent_dataset } => {
+                parent_dataset != self.dataset.uuid() && self.dataset.parent_uuid() != Some(self.uuid())
+            }
+            BtrfsDatasetSnapshotState::Restored { .. } => false,
+        }
+    }
+
     pub fn send(&self, parent: Option<&BtrfsDatasetSnapshot>) -> SnapshotSender {
         self.dataset
             .pool
@@ -327,6 +721,14 @@ impl BtrfsSnapshot for BtrfsDatasetSnapshot {
         self.subvolume.uuid
     }
 
+    fn received_uuid(&self) -> Option<Uuid> {
+        self.subvolume.received_uuid
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.dataset.pool.filesystem.subvolume_size(self.path())
+    }
+
     fn delete(&self) -> Result<()> {
         self.dataset.pool.filesystem.delete_subvolume(self.path())
         // .map_err(|e| SnapshotDeleteError {
@@ -334,6 +736,18 @@ impl BtrfsSnapshot for BtrfsDatasetSnapshot {
         //     snapshot: self,
         // })
     }
+
+    fn trash(&self, keep_for: Duration) -> Result<()> {
+        self.dataset.pool.trash_subvolume(self.path(), keep_for)
+    }
+
+    fn clone_writable(&self, path: &Path) -> Result<()> {
+        self.dataset.pool.filesystem.clone_subvolume(self.path(), path)
+    }
+
+    fn local_path(&self) -> PathBuf {
+        self.canonical_path()
+    }
 }
 
 impl Snapshot for BtrfsDatasetSnapshot {
@@ -386,6 +800,7 @@ pub enum BtrfsDatasetSnapshotState {
 pub struct SnapshotHandle {
     pub datetime: DateTime<Utc>,
     pub uuid: Uuid,
+    pub received_uuid: Option<Uuid>,
 }
 
 impl<T> From<&T> for SnapshotHandle
@@ -396,6 +811,7 @@ where
         Self {
             datetime: snapshot.datetime(),
             uuid: snapshot.uuid(),
+            received_uuid: snapshot.received_uuid(),
         }
     }
 }
@@ -456,7 +872,22 @@ impl BtrfsContainer {
         self.subvolume.path.join(dataset_id.to_string())
     }
 
-    pub fn receive(self: &Arc<Self>, dataset_id: EntityId) -> Result<SnapshotReceiver> {
+    /// Bring the container's quota group up to date with everything received into it, when it has one.
+    pub fn sync_qgroup(&self) -> Result<usize> {
+        match self.model.qgroup {
+            Some(qgroup) => self.pool.sync_qgroup(qgroup, &[self.subvolume.path.clone()]),
+            None => Ok(0),
+        }
+    }
+
+    /// Where the container subvolume is reachable in the local filesystem.
+    pub fn local_path(&self) -> PathBuf {
+        self.subvolume.path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint)
+    }
+
+    /// Receive the snapshot taken at `datetime`. A marker is left next to the receive until it is sealed, so an
+    /// interrupted receive can be told apart from other subvolumes in the container.
+    pub fn receive(self: &Arc<Self>, dataset_id: EntityId, datetime: DateTime<Utc>) -> Result<SnapshotReceiver> {
         let dataset_container_path = self.snapshot_container_path(dataset_id);
         let dataset_container_exists = self.pool.filesystem.subvolume_by_path(&dataset_container_path).is_ok();
 
@@ -464,16 +895,35 @@ impl BtrfsContainer {
             self.pool.filesystem.create_subvolume(&dataset_container_path)?;
         }
 
+        let marker = self
+            .local_snapshot_container_path(dataset_id)
+            .join(receive_marker_name(&snapshot_label(datetime)));
+        fs::write(&marker, b"").with_context(|| format!("Failed to mark the receive in progress at {:?}.", marker))?;
         Ok(self.pool.filesystem.receive_subvolume(&dataset_container_path))
     }
 
+    fn local_snapshot_container_path(&self, dataset_id: EntityId) -> PathBuf {
+        self.snapshot_container_path(dataset_id)
+            .as_pathbuf(&self.pool.filesystem.fstree_mountpoint)
+    }
+
+    fn clear_receive_marker(&self, dataset_id: EntityId, name: &str) -> Result<()> {
+        let marker = self
+            .local_snapshot_container_path(dataset_id)
+            .join(receive_marker_name(name));
+        match fs::remove_file(&marker) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove the receive marker {:?}.", marker))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub fn seal_snapshot(
         self: &Arc<Self>, dataset_id: EntityId, incoming_name: &str,
     ) -> Result<BtrfsContainerSnapshot> {
         let final_name = incoming_name.to_owned() + ".bcrcv";
-        let container_path = self
-            .snapshot_container_path(dataset_id)
-            .as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+        let container_path = self.local_snapshot_container_path(dataset_id);
 
         let source_path = container_path.join(incoming_name);
         let destination_path = container_path.join(&final_name);
@@ -483,10 +933,67 @@ impl BtrfsContainer {
                 source_path, destination_path
             )
         })?;
+        if let Err(e) = self.clear_receive_marker(dataset_id, incoming_name) {
+            slog_scope::warn!("{:#}", e);
+        }
 
+        if let Err(e) = self.sync_qgroup() {
+            slog_scope::warn!(
+                "Failed to assign received snapshot to the container's quota group: {:#}",
+                e
+            );
+        }
         self.snapshot_by_name(dataset_id, &final_name)
     }
 
+    /// The names of the receives whose marker is still there: they were cut off, or finished just before the worker
+    /// stopped without being sealed.
+    pub fn interrupted_receives(&self, dataset_id: EntityId) -> Result<Vec<String>> {
+        let dir = self.local_snapshot_container_path(dataset_id);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to list {:?}.", dir)),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            if let Some(name) = marked_receive(&entry?.file_name().to_string_lossy()) {
+                names.push(name.to_owned());
+            }
+        }
+        Ok(names)
+    }
+
+    /// Seal the receives that completed without being sealed, so they don't have to be sent again, and delete the
+    /// partial ones so their snapshot can be received again.
+    pub fn recover_receives(self: &Arc<Self>, dataset_id: EntityId) -> Result<RecoveredReceives> {
+        let mut recovered = RecoveredReceives::default();
+        for name in self.interrupted_receives(dataset_id)? {
+            let path = self.snapshot_container_path(dataset_id).join(&name);
+            let subvolume = self.pool.filesystem.subvolume_by_path(&path).ok();
+            match InterruptedReceive::of(subvolume.as_ref().map(|s| s.received_uuid.is_some())) {
+                InterruptedReceive::Completed => {
+                    // btrfs receive sets the received uuid once the whole stream is applied, then makes it read only
+                    let local_path = path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint);
+                    if !is_read_only(&local_path)? {
+                        set_read_only(&local_path, true)?;
+                    }
+                    recovered.sealed.push(self.seal_snapshot(dataset_id, &name)?);
+                }
+                InterruptedReceive::Partial => {
+                    self.pool
+                        .filesystem
+                        .delete_subvolume(&path)
+                        .with_context(|| format!("Failed to delete the partially received snapshot '{}'.", name))?;
+                    self.clear_receive_marker(dataset_id, &name)?;
+                    recovered.discarded += 1;
+                }
+                InterruptedReceive::NotStarted => self.clear_receive_marker(dataset_id, &name)?,
+            }
+        }
+        Ok(recovered)
+    }
+
     pub fn validate(pool: &Arc<BtrfsPool>, model: BtrfsContainerEntity) -> Result<Self> {
         let subvolume = pool
             .filesystem
@@ -540,12 +1047,84 @@ impl BtrfsContainer {
     }
 }
 
+#[derive(Debug, Default)]
+pub struct RecoveredReceives {
+    /// Receives that had completed, now sealed as container snapshots.
+    pub sealed: Vec<BtrfsContainerSnapshot>,
+    /// Partial receives that were deleted.
+    pub discarded: usize,
+}
+
+/// The divergence to record for a dataset whose subvolume is a snapshot of `parent_uuid`, unless the recorded one
+/// already names that snapshot.
+fn new_divergence(
+    recorded: Option<&RestoreDivergence>, parent_uuid: Option<Uuid>,
+    diverged_at: impl FnOnce(&Uuid) -> Option<DateTime<Utc>>,
+) -> Option<RestoreDivergence> {
+    let source_snapshot = parent_uuid?;
+    match recorded {
+        Some(recorded) if recorded.source_snapshot == source_snapshot => None,
+        _ => Some(RestoreDivergence {
+            source_snapshot,
+            diverged_at: diverged_at(&source_snapshot),
+        }),
+    }
+}
+
+/// What is left of a receive whose marker is still there.
+#[derive(Debug, PartialEq)]
+enum InterruptedReceive {
+    /// The whole stream was applied, the snapshot only has to be sealed.
+    Completed,
+    /// The subvolume exists but the stream was cut off.
+    Partial,
+    /// No subvolume was created, or it was sealed but the marker not yet removed.
+    NotStarted,
+}
+
+impl InterruptedReceive {
+    /// From whether the receive's subvolume exists and, if so, has a received uuid.
+    fn of(received: Option<bool>) -> Self {
+        match received {
+            Some(true) => Self::Completed,
+            Some(false) => Self::Partial,
+            None => Self::NotStarted,
+        }
+    }
+}
+
+const RECEIVE_MARKER_EXTENSION: &str = ".receiving";
+
+fn receive_marker_name(snapshot_name: &str) -> String {
+    format!("{}{}", snapshot_name, RECEIVE_MARKER_EXTENSION)
+}
+
+/// The snapshot name a receive marker stands for, None when the file is no marker.
+fn marked_receive(file_name: &str) -> Option<&str> {
+    file_name
+        .strip_suffix(RECEIVE_MARKER_EXTENSION)
+        .filter(|name| parse_snapshot_label(name).is_ok())
+}
+
+pub fn snapshot_label(datetime: DateTime<Utc>) -> String {
+    datetime.format("%FT%H-%M-%SZ").to_string()
+}
+
 fn parse_snapshot_label(value: &str) -> Result<DateTime<Utc>> {
     NaiveDateTime::parse_from_str(value, "%FT%H-%M-%SZ")
         .map(|naive_datetime| DateTime::<Utc>::from_utc(naive_datetime, Utc))
         .context("unable to parse snapshot label")
 }
 
+/// Parse a user supplied snapshot timestamp, either a snapshot label or an RFC 3339 datetime.
+pub fn parse_snapshot_timestamp(value: &str) -> Result<DateTime<Utc>> {
+    parse_snapshot_label(value.trim_end_matches(".bcrcv")).or_else(|_| {
+        DateTime::parse_from_rfc3339(value)
+            .map(|datetime| datetime.with_timezone(&Utc))
+            .context("Snapshot timestamp must be a snapshot label (2020-08-23T17-20-10Z) or an RFC 3339 datetime.")
+    })
+}
+
 impl Display for BtrfsContainer {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!("{}/{}", self.pool, self.model().name(),))
@@ -575,6 +1154,10 @@ impl BtrfsContainerSnapshot {
             .received_uuid
             .expect("container snapshots are always received")
     }
+
+    pub fn send(&self) -> SnapshotSender {
+        self.container.pool.filesystem.send_subvolume(self.path(), None)
+    }
 }
 
 impl BtrfsSnapshot for BtrfsContainerSnapshot {
@@ -582,9 +1165,30 @@ impl BtrfsSnapshot for BtrfsContainerSnapshot {
         self.subvolume.uuid
     }
 
+    fn received_uuid(&self) -> Option<Uuid> {
+        self.subvolume.received_uuid
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.container.pool.filesystem.subvolume_size(self.path())
+    }
+
     fn delete(&self) -> Result<()> {
         self.container.pool.filesystem.delete_subvolume(self.path())
     }
+
+    fn trash(&self, keep_for: Duration) -> Result<()> {
+        self.container.pool.trash_subvolume(self.path(), keep_for)
+    }
+
+    fn clone_writable(&self, path: &Path) -> Result<()> {
+        self.container.pool.filesystem.clone_subvolume(self.path(), path)
+    }
+
+    fn local_path(&self) -> PathBuf {
+        self.path()
+            .as_pathbuf(&self.container.pool.filesystem.fstree_mountpoint)
+    }
 }
 
 impl Snapshot for BtrfsContainerSnapshot {
@@ -616,6 +1220,8 @@ pub enum ObservableEventStage {
     Starting,
     Succeeded,
     Failed(String),
+    /// Nothing failed, but the message is something to look into.
+    Warning(String),
 }
 
 pub struct ObservationRouter {
@@ -651,18 +1257,31 @@ impl ObservationEmitter {
     }
 
     pub async fn emit(&self, healthcheck_id: Uuid, stage: ObservableEventStage) -> Result<()> {
+        let body = match &stage {
+            ObservableEventStage::Failed(error) | ObservableEventStage::Warning(error) => Some(error.clone()),
+            _ => None,
+        };
+        self.emit_with_body(healthcheck_id, stage, body).await
+    }
+
+    /// Ping with a body, which the Healthchecks dashboard shows with the ping. Like failure details or run duration.
+    pub async fn emit_with_body(
+        &self, healthcheck_id: Uuid, stage: ObservableEventStage, body: Option<String>,
+    ) -> Result<()> {
         let suffix = match stage {
             ObservableEventStage::Starting => "/start",
             ObservableEventStage::Succeeded => "",
             ObservableEventStage::Failed(_) => "/fail",
+            // logged with the check, without changing its status
+            ObservableEventStage::Warning(_) => "/log",
         };
         let uri_string = format!("{}{}", &self.url, healthcheck_id.to_hyphenated());
         let uri = Uri::from_str((uri_string + suffix).as_str()).context("parsing healtcheck uri failed")?;
 
         slog_scope::trace!("Emitting health check to url: {}", uri);
-        let result = match stage {
-            ObservableEventStage::Starting | ObservableEventStage::Succeeded => self.http_client.get(uri).await,
-            ObservableEventStage::Failed(error) => self.http_client.post(uri, error).await,
+        let result = match body {
+            Some(body) => self.http_client.post(uri, body).await,
+            None => self.http_client.get(uri).await,
         };
 
         result
@@ -682,3 +1301,81 @@ impl Default for ObservationEmitter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn receive_markers_name_the_snapshot_received() {
+        let datetime = "2020-11-29T21:26:00Z".parse::<DateTime<Utc>>().unwrap();
+        let marker = receive_marker_name(&snapshot_label(datetime));
+        assert_eq!(marker, "2020-11-29T21-26-00Z.receiving");
+        assert_eq!(marked_receive(&marker), Some("2020-11-29T21-26-00Z"));
+        assert_eq!(
+            parse_snapshot_label(marked_receive(&marker).unwrap()).unwrap(),
+            datetime
+        );
+    }
+
+    #[test]
+    fn only_markers_of_snapshots_are_receives() {
+        assert_eq!(marked_receive("2020-11-29T21-26-00Z"), None);
+        assert_eq!(marked_receive("2020-11-29T21-26-00Z.bcrcv"), None);
+        assert_eq!(marked_receive("home.receiving"), None);
+        assert_eq!(marked_receive(".manifests"), None);
+    }
+
+    #[test]
+    fn free_space_is_checked_against_the_pool_thresholds() {
+        let gib = 1024 * 1024 * 1024;
+        let mut pool =
+            BtrfsPoolEntity::new("tank".to_owned(), PathBuf::from("/mnt/tank"), Uuid::new_v4(), vec![]).unwrap();
+
+        assert!(PoolNearlyFullError::check(&pool, 2 * gib, Some(gib)).is_ok());
+        assert!(PoolNearlyFullError::check(&pool, 2 * gib, None).is_ok());
+        assert_eq!(
+            PoolNearlyFullError::check(&pool, gib / 2, None),
+            Err(PoolNearlyFullError {
+                pool: "tank".to_owned(),
+                free: gib / 2,
+                metadata_headroom: None,
+            })
+        );
+        assert!(PoolNearlyFullError::check(&pool, 2 * gib, Some(1024)).is_err());
+
+        pool.min_free_bytes = Some(4 * gib);
+        pool.min_metadata_headroom_bytes = Some(0);
+        assert!(PoolNearlyFullError::check(&pool, 2 * gib, Some(0)).is_err());
+        assert!(PoolNearlyFullError::check(&pool, 8 * gib, Some(0)).is_ok());
+    }
+
+    #[test]
+    fn divergence_is_recorded_once_per_restore() {
+        let first = Uuid::new_v4();
+        let diverged_at = "2020-11-29T21:26:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        assert_eq!(new_divergence(None, None, |_| unreachable!()), None);
+        let recorded = new_divergence(None, Some(first), |_| Some(diverged_at)).unwrap();
+        assert_eq!(
+            recorded,
+            RestoreDivergence {
+                source_snapshot: first,
+                diverged_at: Some(diverged_at),
+            }
+        );
+        assert_eq!(new_divergence(Some(&recorded), Some(first), |_| unreachable!()), None);
+
+        let second = Uuid::new_v4();
+        let restored_again = new_divergence(Some(&recorded), Some(second), |_| None).unwrap();
+        assert_eq!(restored_again.source_snapshot, second);
+        assert_eq!(restored_again.diverged_at, None);
+    }
+
+    #[test]
+    fn interrupted_receives_are_classified_by_their_subvolume() {
+        assert_eq!(InterruptedReceive::of(Some(true)), InterruptedReceive::Completed);
+        assert_eq!(InterruptedReceive::of(Some(false)), InterruptedReceive::Partial);
+        assert_eq!(InterruptedReceive::of(None), InterruptedReceive::NotStarted);
+    }
+}
@@ -0,0 +1,399 @@
+use super::ObservableEventStage;
+use crate::{
+    model::entities::{NotificationService, NotifyOn, ObservableEvent},
+    model::EntityId,
+    sys::{
+        net::HttpsClient,
+        s3::uri_encode,
+        snmp::{self, SnmpValue},
+        zabbix::{self, ZabbixValue},
+    },
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Request,
+};
+use hyper::Body;
+use serde_json::json;
+
+pub const NTFY_DEFAULT_SERVER: &str = "https://ntfy.sh";
+/// blockCaptainMIB, as published in debian/BLOCKCAPTAIN-MIB.txt.
+pub const SNMP_MIB_OID: &[u32] = &[1, 3, 6, 1, 3, 7310];
+
+/// The values a notification template can refer to as `{entity}`, `{event}`, `{result}`, `{error}` and `{host}`.
+pub struct NotificationContext<'a> {
+    pub entity_id: EntityId,
+    pub entity: &'a str,
+    pub event: ObservableEvent,
+    pub stage: &'a ObservableEventStage,
+    pub host: &'a str,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl NotificationContext<'_> {
+    pub fn stage_name(&self) -> &'static str {
+        match self.stage {
+            ObservableEventStage::Starting => "starting",
+            ObservableEventStage::Succeeded => "succeeded",
+            ObservableEventStage::Failed(_) => "failed",
+            ObservableEventStage::Warning(_) => "warning",
+        }
+    }
+
+    /// Nagios plugin exit status: OK, WARNING or CRITICAL.
+    fn check_state(&self) -> u8 {
+        match self.stage {
+            ObservableEventStage::Failed(_) => 2,
+            ObservableEventStage::Warning(_) => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn result(&self) -> &'static str {
+        match self.stage {
+            ObservableEventStage::Starting => "started",
+            ObservableEventStage::Succeeded => "succeeded",
+            ObservableEventStage::Failed(_) => "failed",
+            ObservableEventStage::Warning(_) => "needs attention",
+        }
+    }
+
+    fn error(&self) -> &str {
+        match self.stage {
+            ObservableEventStage::Failed(error) | ObservableEventStage::Warning(error) => error.as_str(),
+            _ => "",
+        }
+    }
+
+    pub fn render(&self, template: &str) -> String {
+        let error = self.error();
+        // The error goes in last, so placeholders in its text are left alone.
+        template
+            .replace("\\n", "\n")
+            .replace("{entity}", self.entity)
+            .replace("{event}", &self.event.to_string())
+            .replace("{result}", self.result())
+            .replace("{host}", self.host)
+            .replace("{error}", error)
+            .trim_end()
+            .to_owned()
+    }
+}
+
+/// The value of an event in the BcEventType enumeration of the MIB. Numbers are never reused.
+fn snmp_event_number(event: ObservableEvent) -> i64 {
+    match event {
+        ObservableEvent::DatasetSnapshot => 1,
+        ObservableEvent::DatasetPrune => 2,
+        ObservableEvent::ContainerPrune => 3,
+        ObservableEvent::SnapshotSync => 4,
+        ObservableEvent::SnapshotSyncRpo => 5,
+        ObservableEvent::PoolScrub => 6,
+        ObservableEvent::PoolTrim => 7,
+        ObservableEvent::DatasetExternalChange => 8,
+        ObservableEvent::DatasetSyncBacklog => 9,
+        ObservableEvent::DatasetQuota => 10,
+        ObservableEvent::DatasetDefrag => 11,
+        ObservableEvent::DatasetSnapshotHook => 12,
+        ObservableEvent::ContainerExternalChange => 13,
+        ObservableEvent::PoolWorker => 14,
+        ObservableEvent::DatasetWorker => 15,
+        ObservableEvent::ContainerWorker => 16,
+        ObservableEvent::SnapshotSyncWorker => 17,
+        ObservableEvent::ContainerVerify => 18,
+        ObservableEvent::SnapshotSyncRestoreTest => 19,
+        ObservableEvent::SnapshotSyncProgress => 20,
+    }
+}
+
+pub fn should_notify(notify_on: NotifyOn, stage: &ObservableEventStage) -> bool {
+    match (notify_on, stage) {
+        (_, ObservableEventStage::Failed(_)) | (_, ObservableEventStage::Warning(_)) => true,
+        (NotifyOn::Completion, ObservableEventStage::Succeeded) => true,
+        (NotifyOn::All, _) => true,
+        _ => false,
+    }
+}
+
+pub struct NotificationEmitter {
+    http_client: HttpsClient,
+    service: NotificationService,
+}
+
+impl NotificationEmitter {
+    pub fn new(service: NotificationService) -> Self {
+        Self {
+            http_client: HttpsClient::default(),
+            service,
+        }
+    }
+
+    /// Passive checks get every result, other services the stages configured.
+    pub fn should_send(&self, notify_on: NotifyOn, stage: &ObservableEventStage) -> bool {
+        match self.service.is_passive_check() {
+            true => *stage != ObservableEventStage::Starting,
+            false => should_notify(notify_on, stage),
+        }
+    }
+
+    /// Send the rendered title and message. Webhooks also get the event itself, along with them.
+    pub async fn send(&self, context: &NotificationContext<'_>, title: &str, message: &str) -> Result<()> {
+        let urgent = matches!(context.stage, ObservableEventStage::Failed(_));
+        let request = match &self.service {
+            NotificationService::Zabbix { server, hostname } => {
+                let value = ZabbixValue {
+                    host: hostname.as_deref().unwrap_or(context.host).to_owned(),
+                    key: format!("blkcapt.{}[\"{}\"]", context.event, context.entity),
+                    value: context.check_state().to_string(),
+                };
+                return zabbix::send_values(server, &[value]).await;
+            }
+            NotificationService::SnmpTrap { target, community } => {
+                let notification = match context.stage {
+                    ObservableEventStage::Succeeded => 1,
+                    ObservableEventStage::Failed(_) => 2,
+                    ObservableEventStage::Starting => 3,
+                    ObservableEventStage::Warning(_) => 4,
+                };
+                let object = |id: u32| [SNMP_MIB_OID, &[1, id, 0]].concat();
+                let varbinds = [
+                    (object(1), SnmpValue::String(context.entity_id.to_string())),
+                    (object(2), SnmpValue::String(context.entity.to_owned())),
+                    (object(3), SnmpValue::Integer(snmp_event_number(context.event))),
+                    (object(4), SnmpValue::String(message.to_owned())),
+                    (object(5), SnmpValue::String(context.host.to_owned())),
+                ];
+                let trap_oid = [SNMP_MIB_OID, &[0, notification]].concat();
+                return snmp::send_trap(target, community, &trap_oid, &varbinds).await;
+            }
+            NotificationService::Webhook { url, token } => {
+                let text = format!("{}\n{}", title, message);
+                webhook_request(
+                    url,
+                    token.as_deref(),
+                    json!({
+                        "entity_id": context.entity_id,
+                        "entity": context.entity,
+                        "event": context.event,
+                        "stage": context.stage_name(),
+                        "error": Some(context.error()).filter(|e| !e.is_empty()),
+                        "timestamp": context.timestamp.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        "host": context.host,
+                        "title": title,
+                        "message": message,
+                        "text": text,
+                        "content": text,
+                    }),
+                )
+            }
+            NotificationService::Nrdp { url, token, hostname } => {
+                let results = json!({ "checkresults": [{
+                    "checkresult": { "type": "service", "checktype": "1" },
+                    "hostname": hostname.as_deref().unwrap_or(context.host),
+                    "servicename": format!("blkcapt {} {}", context.event, context.entity),
+                    "state": context.check_state().to_string(),
+                    "output": message,
+                }]});
+                let form = format!(
+                    "token={}&cmd=submitcheck&json={}",
+                    uri_encode(token, true),
+                    uri_encode(&results.to_string(), true)
+                );
+                Request::post(url.as_str())
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(Body::from(form))
+                    .context("building notification request failed")
+            }
+            NotificationService::UptimeKuma { push_url } => {
+                let base = push_url.split('?').next().unwrap_or_default();
+                let status = match context.stage {
+                    ObservableEventStage::Failed(_) => "down",
+                    // a warning doesn't take the monitor down, the message carries it
+                    _ => "up",
+                };
+                Request::get(format!("{}?status={}&msg={}", base, status, uri_encode(message, true)))
+                    .body(Body::empty())
+                    .context("building notification request failed")
+            }
+            _ => self.message_request(title, message, urgent),
+        }?;
+
+        self.request(request, title).await
+    }
+
+    /// Send a digest of several events. Only services that show free-form messages take them.
+    pub async fn send_digest(&self, host: &str, title: &str, message: &str) -> Result<()> {
+        let request = match &self.service {
+            NotificationService::Webhook { url, token } => {
+                let text = format!("{}\n{}", title, message);
+                webhook_request(
+                    url,
+                    token.as_deref(),
+                    json!({
+                        "digest": true,
+                        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                        "host": host,
+                        "title": title,
+                        "message": message,
+                        "text": text,
+                        "content": text,
+                    }),
+                )
+            }
+            _ => self.message_request(title, message, false),
+        }?;
+
+        self.request(request, title).await
+    }
+
+    fn message_request(&self, title: &str, message: &str, urgent: bool) -> Result<Request<Body>> {
+        match &self.service {
+            NotificationService::Telegram { bot_token, chat_id } => {
+                let body = json!({ "chat_id": chat_id, "text": format!("{}\n{}", title, message) });
+                Request::post(format!("https://api.telegram.org/bot{}/sendMessage", bot_token))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+            }
+            NotificationService::Ntfy {
+                server,
+                topic,
+                access_token,
+            } => {
+                let server = server.as_deref().unwrap_or(NTFY_DEFAULT_SERVER);
+                let mut request = Request::post(format!("{}/{}", server.trim_end_matches('/'), topic))
+                    .header("Title", title)
+                    .header("Priority", if urgent { "high" } else { "default" });
+                if let Some(token) = access_token {
+                    request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+                }
+                request.body(Body::from(message.to_owned()))
+            }
+            NotificationService::Gotify { server, app_token } => {
+                let priority = if urgent { 8 } else { 4 };
+                let body = json!({ "title": title, "message": message, "priority": priority });
+                Request::post(format!("{}/message", server.trim_end_matches('/')))
+                    .header("X-Gotify-Key", app_token.as_str())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body.to_string()))
+            }
+            service => bail!("{} observers do not send digests", service.kind()),
+        }
+        .context("building notification request failed")
+    }
+
+    async fn request(&self, request: Request<Body>, title: &str) -> Result<()> {
+        slog_scope::trace!("Sending {} notification: {}", self.service.kind(), title);
+        let response = self
+            .http_client
+            .request(request)
+            .await
+            .context("notification network request failed")?;
+        if !response.status().is_success() {
+            bail!(
+                "{} server responded with unsuccessful status {}",
+                self.service.kind(),
+                response.status()
+            );
+        }
+        if let NotificationService::Nrdp { .. } = self.service {
+            // NRDP answers errors, like a bad token, with a negative status in a successful response.
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .context("failed to read NRDP response")?;
+            let body = String::from_utf8_lossy(&body);
+            if body.contains("<status>-") {
+                bail!("NRDP server rejected the check result: {}", body.trim());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The text and content fields make the payload a valid Slack or Discord message as it is.
+fn webhook_request(url: &str, token: Option<&str>, body: serde_json::Value) -> Result<Request<Body>> {
+    let mut request = Request::post(url).header(CONTENT_TYPE, "application/json");
+    if let Some(token) = token {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+    }
+    request
+        .body(Body::from(body.to_string()))
+        .context("building notification request failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::entities::NotificationObserverEntity;
+
+    #[test]
+    fn render_default_templates() {
+        let stage = ObservableEventStage::Failed(String::from("disk full"));
+        let context = NotificationContext {
+            entity_id: EntityId::default(),
+            entity: "tank/home",
+            event: ObservableEvent::DatasetSnapshot,
+            stage: &stage,
+            host: "nas",
+            timestamp: Utc::now(),
+        };
+
+        assert_eq!(
+            context.render(NotificationObserverEntity::DEFAULT_TITLE_TEMPLATE),
+            "dataset_snapshot failed on nas"
+        );
+        assert_eq!(
+            context.render(NotificationObserverEntity::DEFAULT_MESSAGE_TEMPLATE),
+            "dataset_snapshot for tank/home failed.\ndisk full"
+        );
+    }
+
+    #[test]
+    fn render_leaves_placeholders_in_error_alone() {
+        let stage = ObservableEventStage::Failed(String::from("bad {host}"));
+        let context = NotificationContext {
+            entity_id: EntityId::default(),
+            entity: "tank/home",
+            event: ObservableEvent::DatasetPrune,
+            stage: &stage,
+            host: "nas",
+            timestamp: Utc::now(),
+        };
+
+        assert_eq!(context.render("{host}: {error}\\n"), "nas: bad {host}");
+    }
+
+    #[test]
+    fn notify_on_stages() {
+        let failed = ObservableEventStage::Failed(String::new());
+        assert!(should_notify(NotifyOn::Failure, &failed));
+        assert!(!should_notify(NotifyOn::Failure, &ObservableEventStage::Succeeded));
+        assert!(should_notify(NotifyOn::Completion, &ObservableEventStage::Succeeded));
+        assert!(!should_notify(NotifyOn::Completion, &ObservableEventStage::Starting));
+        assert!(should_notify(NotifyOn::All, &ObservableEventStage::Starting));
+        assert!(should_notify(
+            NotifyOn::Failure,
+            &ObservableEventStage::Warning(String::new())
+        ));
+    }
+
+    #[test]
+    fn render_warnings() {
+        let stage = ObservableEventStage::Warning(String::from("1 snapshots appeared"));
+        let context = NotificationContext {
+            entity_id: EntityId::default(),
+            entity: "tank/home",
+            event: ObservableEvent::DatasetExternalChange,
+            stage: &stage,
+            host: "nas",
+            timestamp: Utc::now(),
+        };
+
+        assert_eq!(context.check_state(), 1);
+        assert_eq!(
+            context.render(NotificationObserverEntity::DEFAULT_MESSAGE_TEMPLATE),
+            "dataset_external_change for tank/home needs attention.\n1 snapshots appeared"
+        );
+    }
+}
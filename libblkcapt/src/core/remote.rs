@@ -0,0 +1,396 @@
+use super::{
+    marked_receive, parse_snapshot_label, receive_marker_name, snapshot_label, InterruptedReceive, Snapshot,
+    SnapshotHandle,
+};
+use crate::{
+    model::{entities::RemoteContainerEntity, Entity, EntityId},
+    sys::{
+        btrfs::{SnapshotReceiver, StreamCompression, Subvolume},
+        process::{output_stdout_to_result, output_to_result},
+    },
+};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use std::{collections::HashMap, fmt::Display, path::Path, path::PathBuf};
+use tokio::process::Command;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteContainerSnapshot {
+    pub datetime: DateTime<Utc>,
+    pub dataset_id: EntityId,
+    pub uuid: Uuid,
+    pub received_uuid: Uuid,
+}
+
+impl From<&RemoteContainerSnapshot> for SnapshotHandle {
+    fn from(snapshot: &RemoteContainerSnapshot) -> Self {
+        Self {
+            datetime: snapshot.datetime,
+            uuid: snapshot.uuid,
+            received_uuid: Some(snapshot.received_uuid),
+        }
+    }
+}
+
+impl Display for RemoteContainerSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.datetime)
+    }
+}
+
+impl Snapshot for RemoteContainerSnapshot {
+    fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
+}
+
+impl RemoteContainerSnapshot {
+    fn from_subvolume(dataset_id: EntityId, subvolume: Subvolume) -> Option<Self> {
+        if subvolume.path.extension() != Some("bcrcv".as_ref()) {
+            return None;
+        }
+        let datetime = parse_snapshot_label(&subvolume.path.file_stem()?.to_string_lossy()).ok()?;
+        Some(Self {
+            datetime,
+            dataset_id,
+            uuid: subvolume.uuid,
+            received_uuid: subvolume.received_uuid?,
+        })
+    }
+}
+
+/// A btrfs filesystem on another machine. Every operation runs the btrfs tools there over ssh.
+pub struct RemoteContainer {
+    model: RemoteContainerEntity,
+}
+
+impl RemoteContainer {
+    pub fn validate(model: RemoteContainerEntity) -> Result<Self> {
+        // ssh parses its destination for options and tokens, so only plain names and addresses get through
+        let valid_host = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':');
+        if model.ssh.host.is_empty() || model.ssh.host.starts_with('-') || !model.ssh.host.chars().all(valid_host) {
+            bail!("'{}' is not a valid ssh host.", model.ssh.host);
+        }
+        let valid_user = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_');
+        if let Some(user) = &model.ssh.user {
+            if user.is_empty() || user.starts_with('-') || !user.chars().all(valid_user) {
+                bail!("'{}' is not a valid ssh user.", user);
+            }
+        }
+        if !model.path.is_absolute() {
+            bail!("Remote path {:?} must be absolute.", model.path);
+        }
+        Ok(Self { model })
+    }
+
+    pub fn model(&self) -> &RemoteContainerEntity {
+        &self.model
+    }
+
+    /// Check that the host accepts the login and the remote path is on a btrfs filesystem.
+    pub async fn check_host(&self) -> Result<()> {
+        let command = self.remote_command(format!("stat -f -c %T {}", shell_quote(&self.model.path)));
+        let filesystem = output_stdout_to_result(command.output().await)
+            .with_context(|| format!("failed to reach {}", self.model.ssh.destination()))?;
+        match filesystem.trim() {
+            "btrfs" => Ok(()),
+            other => Err(anyhow!(
+                "remote path {:?} is on a {} filesystem, not btrfs",
+                self.model.path,
+                other
+            )),
+        }
+    }
+
+    /// Every snapshot on the remote, grouped by the dataset it was received from.
+    pub async fn all_snapshots(&self) -> Result<HashMap<EntityId, Vec<RemoteContainerSnapshot>>> {
+        let command = self.remote_command(format!("ls -1 {}", shell_quote(&self.model.path)));
+        let listing = output_stdout_to_result(command.output().await).context("failed to list remote datasets")?;
+        let mut snapshots = HashMap::new();
+        for dataset_id in listing.lines().filter_map(|l| l.trim().parse::<EntityId>().ok()) {
+            snapshots.insert(dataset_id, self.snapshots(dataset_id).await?);
+        }
+        Ok(snapshots)
+    }
+
+    pub async fn snapshots(&self, dataset_id: EntityId) -> Result<Vec<RemoteContainerSnapshot>> {
+        let command = self.remote_command(format!(
+            "btrfs subvolume list -cuqRo {}",
+            shell_quote(&self.snapshot_container_path(dataset_id))
+        ));
+        let output_data = output_stdout_to_result(command.output().await).context("failed to list remote snapshots")?;
+        let mut snapshots = Subvolume::_parse_list(&output_data)
+            .into_iter()
+            .filter_map(|s| RemoteContainerSnapshot::from_subvolume(dataset_id, s))
+            .collect::<Vec<_>>();
+        snapshots.sort_unstable_by_key(|s| s.datetime);
+        Ok(snapshots)
+    }
+
+    pub fn snapshot_container_path(&self, dataset_id: EntityId) -> PathBuf {
+        self.model.path.join(dataset_id.to_string())
+    }
+
+    /// Receive the snapshot taken at `datetime` into the dataset's subvolume on the remote, creating the subvolume if
+    /// needed. A compressed stream is decompressed on the remote, so it crosses the network compressed. A marker is
+    /// left next to the receive until it is sealed, like in a local container.
+    pub async fn receive(
+        &self, dataset_id: EntityId, datetime: DateTime<Utc>, compression: Option<StreamCompression>,
+    ) -> Result<SnapshotReceiver> {
+        let container_path = self.snapshot_container_path(dataset_id);
+        let path = shell_quote(&container_path);
+        let command = self.remote_command(format!(
+            "btrfs subvolume show {0} >/dev/null 2>&1 || btrfs subvolume create {0}",
+            path
+        ));
+        output_to_result(command.output().await).context("failed to create the remote dataset subvolume")?;
+
+        let marker = shell_quote(&container_path.join(receive_marker_name(&snapshot_label(datetime))));
+        let script = match compression {
+            Some(compression) => format!(
+                "touch {} && {} | btrfs receive {}",
+                marker,
+                compression.decompress_script(),
+                path
+            ),
+            None => format!("touch {} && btrfs receive {}", marker, path),
+        };
+        Ok(SnapshotReceiver::new(self.remote_command(script)))
+    }
+
+    pub async fn seal_snapshot(&self, dataset_id: EntityId, incoming_name: &str) -> Result<RemoteContainerSnapshot> {
+        let container_path = self.snapshot_container_path(dataset_id);
+        let final_name = incoming_name.to_owned() + ".bcrcv";
+        let command = self.remote_command(format!(
+            "mv -T {} {} && rm -f {}",
+            shell_quote(&container_path.join(incoming_name)),
+            shell_quote(&container_path.join(&final_name)),
+            shell_quote(&container_path.join(receive_marker_name(incoming_name)))
+        ));
+        output_to_result(command.output().await).with_context(|| {
+            format!(
+                "Failed to rename the snapshot '{}' on the remote after successfully receiving it.",
+                incoming_name
+            )
+        })?;
+
+        let datetime = parse_snapshot_label(incoming_name)?;
+        self.snapshot_by_datetime(dataset_id, datetime)
+            .await?
+            .with_context(|| format!("received snapshot {} not found on the remote", final_name))
+    }
+
+    /// Seal the receives on the remote that completed without being sealed and delete the partial ones, like a local
+    /// container does. Returns the snapshots sealed and the number of partial receives deleted.
+    pub async fn recover_receives(&self, dataset_id: EntityId) -> Result<(Vec<RemoteContainerSnapshot>, usize)> {
+        let container_path = self.snapshot_container_path(dataset_id);
+        let command = self.remote_command(format!("ls -1 {}", shell_quote(&container_path)));
+        let listing = match output_stdout_to_result(command.output().await) {
+            Ok(listing) => listing,
+            // nothing was ever received for the dataset
+            Err(_) => return Ok((Vec::new(), 0)),
+        };
+        let interrupted = listing
+            .lines()
+            .filter_map(|l| marked_receive(l.trim()))
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if interrupted.is_empty() {
+            return Ok((Vec::new(), 0));
+        }
+
+        let command = self.remote_command(format!("btrfs subvolume list -cuqRo {}", shell_quote(&container_path)));
+        let output_data = output_stdout_to_result(command.output().await).context("failed to list remote snapshots")?;
+        let subvolumes = Subvolume::_parse_list(&output_data)
+            .into_iter()
+            .filter_map(|s| {
+                Some((
+                    s.path.file_name()?.to_string_lossy().into_owned(),
+                    s.received_uuid.is_some(),
+                ))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut sealed = Vec::new();
+        let mut discarded = 0;
+        for name in interrupted {
+            let path = shell_quote(&container_path.join(&name));
+            let marker = shell_quote(&container_path.join(receive_marker_name(&name)));
+            match InterruptedReceive::of(subvolumes.get(&name).copied()) {
+                InterruptedReceive::Completed => {
+                    let command = self.remote_command(format!("btrfs property set -ts {} ro true", path));
+                    output_to_result(command.output().await)
+                        .with_context(|| format!("failed to make the received snapshot '{}' read only", name))?;
+                    sealed.push(self.seal_snapshot(dataset_id, &name).await?);
+                }
+                InterruptedReceive::Partial => {
+                    let command = self.remote_command(format!("btrfs subvolume delete {} && rm -f {}", path, marker));
+                    output_to_result(command.output().await)
+                        .with_context(|| format!("Failed to delete the partially received snapshot '{}'.", name))?;
+                    discarded += 1;
+                }
+                InterruptedReceive::NotStarted => {
+                    let command = self.remote_command(format!("rm -f {}", marker));
+                    output_to_result(command.output().await)
+                        .with_context(|| format!("failed to remove the receive marker of '{}'", name))?;
+                }
+            }
+        }
+        Ok((sealed, discarded))
+    }
+
+    pub async fn delete_snapshot(&self, snapshot: &RemoteContainerSnapshot) -> Result<()> {
+        let path = self
+            .snapshot_container_path(snapshot.dataset_id)
+            .join(snapshot.datetime.format("%FT%H-%M-%SZ.bcrcv").to_string());
+        let command = self.remote_command(format!("btrfs subvolume delete {}", shell_quote(&path)));
+        output_to_result(command.output().await)
+            .with_context(|| format!("Failed to delete the remote snapshot '{}'.", path.display()))
+    }
+
+    pub async fn snapshot_by_datetime(
+        &self, dataset_id: EntityId, datetime: DateTime<Utc>,
+    ) -> Result<Option<RemoteContainerSnapshot>> {
+        Ok(self
+            .snapshots(dataset_id)
+            .await?
+            .into_iter()
+            .find(|s| s.datetime == datetime))
+    }
+
+    /// An ssh command running a shell script on the remote. Arguments in the script must be quoted.
+    fn remote_command(&self, script: String) -> Command {
+        let ssh = &self.model.ssh;
+        let mut command = Command::new("ssh");
+        command.args(&["-o", "BatchMode=yes", "-o", "ConnectTimeout=30"]);
+        if let Some(port) = ssh.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity_file) = &ssh.identity_file {
+            command.arg("-i").arg(identity_file).args(&["-o", "IdentitiesOnly=yes"]);
+        }
+        command.arg("--").arg(ssh.destination()).arg(script);
+        command
+    }
+}
+
+impl Display for RemoteContainer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}:{})",
+            self.model.name(),
+            self.model.ssh.destination(),
+            self.model.path.display()
+        )
+    }
+}
+
+/// Quote a path for the remote shell, which ssh runs every command through.
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{model::entities::SshTarget, sys::fs::FsPathBuf};
+
+    #[test]
+    fn shell_quote_escapes_single_quotes() {
+        assert_eq!(shell_quote(Path::new("/mnt/backup")), "'/mnt/backup'");
+        assert_eq!(shell_quote(Path::new("/mnt/it's here")), r"'/mnt/it'\''s here'");
+    }
+
+    #[test]
+    fn remote_snapshot_requires_sealed_received_subvolume() {
+        let dataset_id = "b99a584c-72c0-4cbe-9c6d-0c32274563f7".parse().unwrap();
+        let subvolume = |path: &str, received_uuid: Option<Uuid>| Subvolume {
+            uuid: "7f56a00a-2139-4048-96e2-c4946b731914".parse().unwrap(),
+            path: FsPathBuf::from(path),
+            parent_uuid: None,
+            received_uuid,
+            generation: 10,
+            creation_generation: 9,
+        };
+        let received = Some("57c929a8-61ad-6747-957d-5daa101de0ff".parse().unwrap());
+
+        let sealed = RemoteContainerSnapshot::from_subvolume(
+            dataset_id,
+            subvolume(
+                "backup/b99a584c-72c0-4cbe-9c6d-0c32274563f7/2020-11-29T21-26-00Z.bcrcv",
+                received,
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            sealed.datetime,
+            "2020-11-29T21:26:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+        assert_eq!(sealed.received_uuid, received.unwrap());
+
+        assert!(RemoteContainerSnapshot::from_subvolume(
+            dataset_id,
+            subvolume(
+                "backup/b99a584c-72c0-4cbe-9c6d-0c32274563f7/2020-11-29T21-26-00Z",
+                received
+            ),
+        )
+        .is_none());
+        assert!(RemoteContainerSnapshot::from_subvolume(
+            dataset_id,
+            subvolume(
+                "backup/b99a584c-72c0-4cbe-9c6d-0c32274563f7/2020-11-29T21-26-00Z.bcrcv",
+                None
+            ),
+        )
+        .is_none());
+    }
+
+    fn remote(host: &str, user: Option<&str>) -> RemoteContainerEntity {
+        let mut ssh = SshTarget::new(host.to_owned());
+        ssh.user = user.map(str::to_owned);
+        RemoteContainerEntity::new("remote".to_owned(), ssh, PathBuf::from("/srv/backup"))
+    }
+
+    #[test]
+    fn validate_accepts_plain_hosts_and_users() {
+        assert!(RemoteContainer::validate(remote("backup.example.net", Some("blkcapt_recv"))).is_ok());
+        assert!(RemoteContainer::validate(remote("192.168.1.20", None)).is_ok());
+        assert!(RemoteContainer::validate(remote("fe80::1", None)).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_option_like_or_odd_destinations() {
+        for host in &[
+            "",
+            "-oProxyCommand=sh",
+            "host name",
+            "host;reboot",
+            "user@host",
+            "host\nother",
+        ] {
+            assert!(
+                RemoteContainer::validate(remote(host, None)).is_err(),
+                "host {:?}",
+                host
+            );
+        }
+        for user in &["", "-l", "root@other", "a b", "$(id)"] {
+            assert!(
+                RemoteContainer::validate(remote("backup", Some(user))).is_err(),
+                "user {:?}",
+                user
+            );
+        }
+    }
+
+    #[test]
+    fn remote_command_ends_options_before_destination() {
+        let container = RemoteContainer::validate(remote("backup", Some("recv"))).unwrap();
+        let command = container.remote_command("true".to_owned());
+        let args = command.as_std().get_args().collect::<Vec<_>>();
+        assert_eq!(args[args.len() - 3..], ["--", "recv@backup", "true"]);
+    }
+}
@@ -1,13 +1,14 @@
 use super::{parse_snapshot_label, Snapshot, SnapshotHandle};
 use crate::{
-    model::{entities::ResticContainerEntity, Entity, EntityId},
+    model::{entities::ResticContainerEntity, storage, Entity, EntityId},
     sys::{
         fs::{bind_mount, unmount},
+        net::{probe_reachable, send_magic_packet, wait_reachable},
         process::exit_status_as_result,
     },
 };
 use anyhow::{anyhow, bail, Context, Error, Result};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, Utc};
 use serde::{Deserialize, Deserializer};
 use std::{borrow::Borrow, fmt::Display, fs, path::Path, path::PathBuf, process::Stdio, str::FromStr, sync::Arc};
 use tokio::{
@@ -31,6 +32,7 @@ impl From<&ResticContainerSnapshot> for SnapshotHandle {
         Self {
             datetime: snapshot.datetime,
             uuid: snapshot.uuid.low,
+            received_uuid: Some(snapshot.received_uuid),
         }
     }
 }
@@ -129,6 +131,33 @@ impl ResticRepository {
         ResticForget::new(command, snapshots)
     }
 
+    /// Wake the repository host with Wake-on-LAN if it is configured and not already answering.
+    pub async fn wake_host(&self) -> Result<()> {
+        let host = match &self.model.host {
+            Some(host) => host,
+            None => return Ok(()),
+        };
+        let wake_on_lan = match &host.wake_on_lan {
+            Some(wake_on_lan) => wake_on_lan,
+            None => return Ok(()),
+        };
+        if probe_reachable(&host.address).await.is_ok() {
+            return Ok(());
+        }
+        send_magic_packet(wake_on_lan.mac_address, wake_on_lan.broadcast())?;
+        wait_reachable(&host.address, wake_on_lan.timeout())
+            .await
+            .with_context(|| format!("host {} did not wake", host.address))
+    }
+
+    /// Check that the repository host answers, waking it first if wake-on-lan is configured.
+    pub async fn check_host(&self) -> Result<()> {
+        match &self.model.host {
+            Some(host) if host.wake_on_lan.is_none() => probe_reachable(&host.address).await,
+            _ => self.wake_host().await,
+        }
+    }
+
     pub fn model(&self) -> &ResticContainerEntity {
         &self.model
     }
@@ -226,6 +255,12 @@ impl ResticBackup {
         bind_mount(path, &self.source.bind_path)?;
 
         // spawn as restic user?
+        if let Some(limit) = storage::worker_config().bandwidth_limit(Local::now().time()) {
+            // restic takes the limit in KiB/s
+            self.command
+                .arg("--limit-upload")
+                .arg((limit / 1024).max(1).to_string());
+        }
         self.command.arg(&self.source.bind_path);
         self.command.stdout(Stdio::piped());
         self.command
@@ -0,0 +1,412 @@
+use super::{
+    archive::{ArchiveContainer, ArchiveManifest},
+    dataset_snapshot_container_path,
+    verify::{read_files, ChecksumManifest, SampleVerification},
+    BtrfsContainerSnapshot, BtrfsDataset, BtrfsDatasetSnapshot, BtrfsPool, BtrfsSnapshot, BLKCAPT_FS_META_DIR,
+};
+use crate::{
+    model::{entities::BtrfsDatasetEntity, Entity, EntityId},
+    sys::{
+        btrfs::{is_read_only, MountedFilesystem, Subvolume},
+        fs::FsPathBuf,
+    },
+};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use uuid::Uuid;
+
+/// Recreates a dataset on a pool from a container snapshot. The dataset keeps its identity, so the received
+/// snapshot becomes its first local snapshot and existing syncs can continue incrementally from it.
+pub struct DatasetRestore {
+    pool: Arc<BtrfsPool>,
+    model: BtrfsDatasetEntity,
+    /// Where the dataset's current subvolume is moved to when restoring over it.
+    replaced_path: Option<FsPathBuf>,
+}
+
+impl DatasetRestore {
+    pub fn new(pool: &Arc<BtrfsPool>, model: BtrfsDatasetEntity) -> Result<Self> {
+        let target_path = model.path.as_pathbuf(&pool.filesystem.fstree_mountpoint);
+        if target_path.exists() {
+            bail!("Restore path {:?} already exists.", target_path);
+        }
+
+        Ok(Self {
+            pool: Arc::clone(pool),
+            model,
+            replaced_path: None,
+        })
+    }
+
+    /// Restore over the dataset in place. Its current subvolume is only moved aside, next to it, once the snapshot
+    /// is ready, so a failed or mistaken restore loses nothing.
+    pub fn replacing(pool: &Arc<BtrfsPool>, model: BtrfsDatasetEntity) -> Result<Self> {
+        let target_path = model.path.as_pathbuf(&pool.filesystem.fstree_mountpoint);
+        if !target_path.exists() {
+            return Self::new(pool, model);
+        }
+
+        let mut name = model.path.file_name().context("Dataset path has no name.")?.to_owned();
+        name.push(Utc::now().format(".pre-restore-%FT%H-%M-%SZ").to_string());
+        let replaced_path = model.path.with_file_name(name);
+        if replaced_path.as_pathbuf(&pool.filesystem.fstree_mountpoint).exists() {
+            bail!("Path {:?} for the replaced subvolume already exists.", replaced_path);
+        }
+
+        Ok(Self {
+            pool: Arc::clone(pool),
+            model,
+            replaced_path: Some(replaced_path),
+        })
+    }
+
+    pub fn replaced_path(&self) -> Option<&FsPathBuf> {
+        self.replaced_path.as_ref()
+    }
+
+    pub fn model(&self) -> &BtrfsDatasetEntity {
+        &self.model
+    }
+
+    pub async fn run(self, snapshot: &BtrfsContainerSnapshot) -> Result<BtrfsDataset> {
+        let filesystem = &self.pool.filesystem;
+        let snapshot_container_path = self.create_snapshot_container()?;
+
+        let mut sender = snapshot.send().start()?;
+        let mut receiver = filesystem.receive_subvolume(&snapshot_container_path).start()?;
+        {
+            let reader = sender.reader();
+            let writer = receiver.writer();
+            tokio::pin!(reader, writer);
+            tokio::io::copy(&mut reader, &mut writer)
+                .await
+                .context("Failed to transfer snapshot stream.")?;
+        }
+        sender.wait().await.context("Sending the container snapshot failed.")?;
+        let incoming_name = receiver.wait().await.context("Receiving the snapshot failed.")?;
+
+        // container snapshots carry a .bcrcv extension that isn't part of the dataset snapshot label
+        let label = Path::new(&incoming_name)
+            .file_stem()
+            .context("Received snapshot has no name.")?
+            .to_owned();
+        let container_path = snapshot_container_path.as_pathbuf(&filesystem.fstree_mountpoint);
+        fs::rename(container_path.join(&incoming_name), container_path.join(&label)).with_context(|| {
+            format!(
+                "Failed to rename the snapshot from '{}' to '{}' after receiving it.",
+                incoming_name,
+                label.to_string_lossy()
+            )
+        })?;
+
+        self.finish(&snapshot_container_path.join(&label))
+    }
+
+    /// Create the dataset from one of its own local snapshots, which needs no transfer.
+    pub fn run_local(self, snapshot: &BtrfsDatasetSnapshot) -> Result<BtrfsDataset> {
+        self.finish(snapshot.path())
+    }
+
+    /// Receive a chain of archived streams, full stream first. The snapshots in between are kept as the dataset's
+    /// snapshots, the last becomes the restored dataset. Encrypted streams are decrypted with `identity`.
+    pub async fn run_archive(
+        self, archive: &ArchiveContainer, chain: &[&ArchiveManifest], identity: Option<&Path>,
+    ) -> Result<BtrfsDataset> {
+        let filesystem = &self.pool.filesystem;
+        let snapshot_container_path = self.create_snapshot_container()?;
+
+        let mut last_name = None;
+        for manifest in chain {
+            let mut receiver = filesystem
+                .receive_subvolume(&snapshot_container_path)
+                .compressed(manifest.compression)
+                .start()?;
+            {
+                let writer = receiver.writer();
+                tokio::pin!(writer);
+                archive
+                    .download(manifest, identity, &mut writer)
+                    .await
+                    .with_context(|| format!("Failed to download the archived stream for {}.", manifest.datetime))?;
+            }
+            last_name = Some(
+                receiver
+                    .wait()
+                    .await
+                    .with_context(|| format!("Receiving the archived stream for {} failed.", manifest.datetime))?,
+            );
+        }
+
+        let name = last_name.context("The restore chain is empty.")?;
+        self.finish(&snapshot_container_path.join(name))
+    }
+
+    fn create_snapshot_container(&self) -> Result<FsPathBuf> {
+        let filesystem = &self.pool.filesystem;
+        let snapshot_container_path = dataset_snapshot_container_path(self.model.id());
+        if !snapshot_container_path
+            .as_pathbuf(&filesystem.fstree_mountpoint)
+            .exists()
+        {
+            filesystem.create_subvolume(&snapshot_container_path)?;
+        }
+        Ok(snapshot_container_path)
+    }
+
+    /// Create the writable dataset from the snapshot received last.
+    fn finish(self, snapshot_path: &FsPathBuf) -> Result<BtrfsDataset> {
+        let filesystem = &self.pool.filesystem;
+        let local_snapshot = filesystem.subvolume_by_path(snapshot_path)?;
+        if let Some(parent) = self.model.path.as_pathbuf(&filesystem.fstree_mountpoint).parent() {
+            fs::create_dir_all(parent).context("Failed to create parent directories for the restored dataset.")?;
+        }
+        if let Some(replaced_path) = &self.replaced_path {
+            filesystem
+                .move_subvolume(&self.model.path, replaced_path)
+                .context("Failed to move the dataset's current subvolume aside.")?;
+        }
+        if let Err(e) = filesystem.create_writable_snapshot(&local_snapshot, &self.model.path) {
+            if let Some(replaced_path) = &self.replaced_path {
+                if let Err(e) = filesystem.move_subvolume(replaced_path, &self.model.path) {
+                    slog_scope::error!("Failed to move the replaced subvolume back into place: {:?}", e);
+                }
+            }
+            return Err(e);
+        }
+
+        let mut model = self.model;
+        model.uuid = filesystem.subvolume_by_path(&model.path)?.uuid;
+        model.restore_divergence = None;
+        BtrfsDataset::validate(&self.pool, model)
+    }
+}
+
+/// Rolls a dataset back to one of its own snapshots: its subvolume is replaced by a writable copy of the snapshot,
+/// and the copy becomes the default subvolume when the replaced one was.
+pub struct DatasetRollback {
+    restore: DatasetRestore,
+    snapshot: BtrfsDatasetSnapshot,
+    replaced_path: PathBuf,
+    was_default: bool,
+}
+
+impl DatasetRollback {
+    pub fn new(dataset: &Arc<BtrfsDataset>, datetime: DateTime<Utc>) -> Result<Self> {
+        let snapshot = dataset
+            .snapshots()?
+            .into_iter()
+            .find(|s| s.datetime() == datetime)
+            .context("Snapshot not found in dataset.")?;
+        let restore = DatasetRestore::replacing(&dataset.pool, dataset.model().clone())?;
+        let replaced_path = restore
+            .replaced_path()
+            .map(|p| p.as_pathbuf(&dataset.pool.model().mountpoint_path))
+            .context("The dataset's subvolume is missing, use 'dataset restore' to recreate it.")?;
+        Ok(Self {
+            restore,
+            snapshot,
+            replaced_path,
+            was_default: dataset.is_default_subvolume()?,
+        })
+    }
+
+    pub fn snapshot(&self) -> &BtrfsDatasetSnapshot {
+        &self.snapshot
+    }
+
+    /// Where the replaced subvolume is kept, under the pool's mountpoint.
+    pub fn replaced_path(&self) -> &Path {
+        &self.replaced_path
+    }
+
+    pub fn was_default(&self) -> bool {
+        self.was_default
+    }
+
+    /// `safety` is the snapshot of the current state taken before rolling back, it stays held on the rolled back
+    /// dataset.
+    pub fn run(mut self, safety: DateTime<Utc>) -> Result<BtrfsDataset> {
+        let held_snapshots = &mut self.restore.model.held_snapshots;
+        if !held_snapshots.contains(&safety) {
+            held_snapshots.push(safety);
+            held_snapshots.sort_unstable();
+        }
+        let restored = self
+            .restore
+            .run_local(&self.snapshot)
+            .context("Failed to roll back the dataset.")?;
+        if self.was_default {
+            restored
+                .make_default_subvolume()
+                .context("Rolled back, but failed to make the new subvolume the default.")?;
+        }
+        Ok(restored)
+    }
+}
+
+/// A container snapshot received back into scratch space on the container's pool, to prove it can be restored
+/// without touching any dataset.
+pub struct RestoreTest {
+    pool: Arc<BtrfsPool>,
+    subvolume: Subvolume,
+}
+
+impl RestoreTest {
+    /// Each container has its own scratch directory on the pool, holding at most one test copy. Leftovers of an
+    /// interrupted test are removed first.
+    pub async fn receive(snapshot: &BtrfsContainerSnapshot) -> Result<Self> {
+        let pool = Arc::clone(&snapshot.container.pool);
+        let scratch_path = restore_test_path(snapshot.container.model().id());
+        {
+            let pool = Arc::clone(&pool);
+            let scratch_path = scratch_path.clone();
+            blocking(move || {
+                clear_scratch(&pool.filesystem, &scratch_path)?;
+                fs::create_dir_all(scratch_path.as_pathbuf(&pool.filesystem.fstree_mountpoint))
+                    .context("Failed to create the restore test directory.")
+            })
+            .await?;
+        }
+
+        let received = receive_into(snapshot, &pool.filesystem, &scratch_path).await;
+        let expected_uuid = snapshot.received_uuid();
+        let snapshot_uuid = snapshot.uuid();
+        blocking(move || {
+            let subvolume = match received.and_then(|name| pool.filesystem.subvolume_by_path(&scratch_path.join(name)))
+            {
+                Ok(subvolume) => subvolume,
+                Err(e) => {
+                    if let Err(e) = clear_scratch(&pool.filesystem, &scratch_path) {
+                        slog_scope::warn!("Failed to clean up after the restore test: {:?}", e);
+                    }
+                    return Err(e);
+                }
+            };
+
+            let test = Self { pool, subvolume };
+            let read_only = is_read_only(&test.local_path())?;
+            if let Some(problem) = restored_copy_problem(test.subvolume.received_uuid, expected_uuid, read_only) {
+                test.discard()?;
+                bail!("The restored copy of snapshot {} {}.", snapshot_uuid, problem);
+            }
+            Ok(test)
+        })
+        .await
+    }
+
+    /// Where the restored copy is reachable in the local filesystem.
+    pub fn local_path(&self) -> PathBuf {
+        self.subvolume.path.as_pathbuf(&self.pool.filesystem.fstree_mountpoint)
+    }
+
+    /// Compare the restored files with the manifest written when the snapshot was received into the container, or
+    /// just read all of them when there is none.
+    pub fn verify(&self, manifest: Option<&ChecksumManifest>) -> Result<SampleVerification> {
+        let root = self.local_path();
+        match manifest {
+            Some(manifest) => manifest.verify_restored(&self.subvolume, &root),
+            None => read_files(&root),
+        }
+    }
+
+    pub fn discard(self) -> Result<()> {
+        self.pool
+            .filesystem
+            .delete_subvolume(&self.subvolume.path)
+            .context("Failed to delete the restore test copy.")
+    }
+}
+
+/// Returns the name the snapshot was received as.
+async fn receive_into(
+    snapshot: &BtrfsContainerSnapshot, filesystem: &MountedFilesystem, path: &FsPathBuf,
+) -> Result<String> {
+    let mut sender = snapshot.send().start()?;
+    let mut receiver = filesystem.receive_subvolume(path).start()?;
+    {
+        let reader = sender.reader();
+        let writer = receiver.writer();
+        tokio::pin!(reader, writer);
+        tokio::io::copy(&mut reader, &mut writer)
+            .await
+            .context("Failed to transfer snapshot stream.")?;
+    }
+    sender.wait().await.context("Sending the container snapshot failed.")?;
+    receiver.wait().await.context("Receiving the snapshot failed.")
+}
+
+/// Why a received test copy doesn't stand for the container snapshot, if it doesn't.
+fn restored_copy_problem(received_uuid: Option<Uuid>, expected_uuid: Uuid, read_only: bool) -> Option<&'static str> {
+    if received_uuid != Some(expected_uuid) {
+        Some("was not received from the same source")
+    } else if !read_only {
+        Some("is not a read-only snapshot")
+    } else {
+        None
+    }
+}
+
+/// The btrfs tools block, keep them off the async runtime.
+async fn blocking<T: Send + 'static>(func: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(func)
+        .await
+        .context("restore test task panicked")?
+}
+
+fn restore_test_path(container_id: EntityId) -> FsPathBuf {
+    let mut builder = FsPathBuf::from(BLKCAPT_FS_META_DIR);
+    builder.push("restore-test");
+    builder.push(container_id.to_string());
+    builder
+}
+
+fn clear_scratch(filesystem: &MountedFilesystem, scratch_path: &FsPathBuf) -> Result<()> {
+    if !scratch_path.as_pathbuf(&filesystem.fstree_mountpoint).exists() {
+        return Ok(());
+    }
+    for subvolume in filesystem
+        .list_all_subvolumes()?
+        .into_iter()
+        .filter(|s| s.path.starts_with(scratch_path))
+    {
+        filesystem.delete_subvolume(&subvolume.path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restore_test_scratch_is_per_container() {
+        let first = EntityId::new();
+        let second = EntityId::new();
+        assert_ne!(restore_test_path(first), restore_test_path(second));
+        assert!(restore_test_path(first).starts_with(&FsPathBuf::from(".blkcapt/restore-test")));
+        assert!(!restore_test_path(first).starts_with(&restore_test_path(second)));
+    }
+
+    #[test]
+    fn restored_copy_must_match_and_be_read_only() {
+        let expected = Uuid::new_v4();
+        assert_eq!(restored_copy_problem(Some(expected), expected, true), None);
+        assert_eq!(
+            restored_copy_problem(Some(expected), expected, false),
+            Some("is not a read-only snapshot")
+        );
+        assert_eq!(
+            restored_copy_problem(Some(Uuid::new_v4()), expected, true),
+            Some("was not received from the same source")
+        );
+        assert_eq!(
+            restored_copy_problem(None, expected, true),
+            Some("was not received from the same source")
+        );
+    }
+}
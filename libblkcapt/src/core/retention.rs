@@ -1,11 +1,31 @@
 use super::Snapshot;
 use crate::model::entities::KeepSpec;
-use crate::model::entities::RetentionRuleset;
+use crate::model::entities::{PreUpgradeRetention, RetentionRuleset};
 
 use chrono::{DateTime, Utc};
 use std::{cmp::Reverse, collections::HashSet, iter::repeat};
 use std::{convert::TryFrom, num::NonZeroUsize};
 
+/// Pre-upgrade snapshots to drop: all but the newest ones to keep, and any older than the maximum age.
+pub fn evaluate_pre_upgrade_retention<'a, T: Snapshot>(
+    snapshots: &'a [T], retention: &PreUpgradeRetention, now: DateTime<Utc>,
+) -> Vec<&'a T> {
+    let mut snapshots: Vec<_> = snapshots.iter().collect();
+    snapshots.sort_unstable_by_key(|b| Reverse(b.datetime()));
+    let oldest_kept = chrono::Duration::from_std(retention.max_age)
+        .ok()
+        .and_then(|max_age| now.checked_sub_signed(max_age));
+    let keep_count = usize::try_from(retention.newest_count.get()).expect("u32 always fits in usize");
+    snapshots
+        .into_iter()
+        .enumerate()
+        .filter(|(index, snapshot)| {
+            *index >= keep_count || oldest_kept.map_or(false, |oldest| snapshot.datetime() < oldest)
+        })
+        .map(|(_, snapshot)| snapshot)
+        .collect()
+}
+
 pub fn evaluate_retention<'a, T: Snapshot>(snapshots: &'a [T], rules: &RetentionRuleset) -> RetentionEvaluation<'a, T> {
     if snapshots.is_empty() {
         return RetentionEvaluation {
@@ -23,11 +43,12 @@ pub fn evaluate_retention<'a, T: Snapshot>(snapshots: &'a [T], rules: &Retention
     let mut keep_interval_buckets = rules
         .interval
         .iter()
-        .flat_map(|m| repeat(m).take(usize::try_from(m.repeat.get()).expect("u32 always fits in usize")))
-        .scan(begin_time, |end_time_state, sm| {
+        .enumerate()
+        .flat_map(|(i, m)| repeat((i, m)).take(usize::try_from(m.repeat.get()).expect("u32 always fits in usize")))
+        .scan(begin_time, |end_time_state, (i, sm)| {
             *end_time_state = *end_time_state
                 - chrono::Duration::from_std(sm.duration).expect("interval duration always fits in chrono duration");
-            Some(RetainBucket::new(sm.keep, *end_time_state))
+            Some(RetainBucket::new(sm.keep, *end_time_state, i))
         })
         .collect::<Vec<_>>();
 
@@ -76,10 +97,12 @@ pub struct RetainBucket<'a, T> {
     pub snapshots: Vec<&'a T>,
     pub max_fill: NonZeroUsize,
     pub end_time: chrono::DateTime<Utc>,
+    /// Index of the interval in the ruleset the bucket repeats.
+    pub interval: usize,
 }
 
 impl<'a, T> RetainBucket<'a, T> {
-    fn new(keep: KeepSpec, end_time: DateTime<Utc>) -> Self {
+    fn new(keep: KeepSpec, end_time: DateTime<Utc>, interval: usize) -> Self {
         Self {
             snapshots: Default::default(),
             max_fill: match keep {
@@ -88,6 +111,85 @@ impl<'a, T> RetainBucket<'a, T> {
                 KeepSpec::All => NonZeroUsize::new(usize::MAX).expect("usize always fits in usize"),
             },
             end_time,
+            interval,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fmt::Display, num::NonZeroU32, time::Duration};
+
+    struct TestSnapshot(DateTime<Utc>);
+
+    impl Display for TestSnapshot {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl Snapshot for TestSnapshot {
+        fn datetime(&self) -> DateTime<Utc> {
+            self.0
+        }
+    }
+
+    fn days_ago(now: DateTime<Utc>, days: i64) -> TestSnapshot {
+        TestSnapshot(now - chrono::Duration::days(days))
+    }
+
+    fn retention(newest_count: u32, max_age_days: u64) -> PreUpgradeRetention {
+        PreUpgradeRetention {
+            newest_count: NonZeroU32::new(newest_count).unwrap(),
+            max_age: Duration::from_secs(max_age_days * 24 * 3600),
+        }
+    }
+
+    fn dropped_days(now: DateTime<Utc>, dropped: Vec<&TestSnapshot>) -> Vec<i64> {
+        let mut days = dropped.into_iter().map(|s| (now - s.0).num_days()).collect::<Vec<_>>();
+        days.sort_unstable();
+        days
+    }
+
+    #[test]
+    fn pre_upgrade_keeps_the_newest_count() {
+        let now = "2021-03-01T12:00:00Z".parse().unwrap();
+        // unordered, the newest are picked by datetime
+        let snapshots = vec![days_ago(now, 3), days_ago(now, 1), days_ago(now, 4), days_ago(now, 2)];
+
+        let dropped = evaluate_pre_upgrade_retention(&snapshots, &retention(2, 30), now);
+        assert_eq!(dropped_days(now, dropped), vec![3, 4]);
+    }
+
+    #[test]
+    fn pre_upgrade_drops_older_than_max_age_even_if_newest() {
+        let now = "2021-03-01T12:00:00Z".parse().unwrap();
+        let snapshots = vec![days_ago(now, 1), days_ago(now, 10), days_ago(now, 40)];
+
+        let dropped = evaluate_pre_upgrade_retention(&snapshots, &retention(3, 30), now);
+        assert_eq!(dropped_days(now, dropped), vec![40]);
+
+        let dropped = evaluate_pre_upgrade_retention(&snapshots, &retention(3, 5), now);
+        assert_eq!(dropped_days(now, dropped), vec![10, 40]);
+    }
+
+    #[test]
+    fn pre_upgrade_keeps_all_within_limits() {
+        let now = "2021-03-01T12:00:00Z".parse().unwrap();
+        let snapshots = vec![days_ago(now, 1), days_ago(now, 2)];
+        assert!(evaluate_pre_upgrade_retention(&snapshots, &retention(3, 30), now).is_empty());
+        assert!(evaluate_pre_upgrade_retention::<TestSnapshot>(&[], &retention(1, 30), now).is_empty());
+    }
+
+    #[test]
+    fn pre_upgrade_max_age_beyond_chrono_range_only_counts() {
+        let now = "2021-03-01T12:00:00Z".parse().unwrap();
+        let snapshots = vec![days_ago(now, 1), days_ago(now, 4000)];
+        let retention = PreUpgradeRetention {
+            newest_count: NonZeroU32::new(5).unwrap(),
+            max_age: Duration::from_secs(u64::MAX),
+        };
+        assert!(evaluate_pre_upgrade_retention(&snapshots, &retention, now).is_empty());
+    }
+}
@@ -1,9 +1,86 @@
+use crate::model::EntityId;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use strum_macros::Display;
 
 #[derive(Serialize, Deserialize)]
 pub struct SystemState {
     pub actors: Vec<SystemActor>,
+    /// Number of actors matching the query, before paging.
+    #[serde(default)]
+    pub total: usize,
+}
+
+/// Query string accepted by the snapshot file listing endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SnapshotFilesQuery {
+    /// Directory relative to the snapshot root. Default: the root.
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotFilesResponse {
+    /// The snapshot, with the dataset or container holding it.
+    pub snapshot: String,
+    pub path: PathBuf,
+    pub entries: Vec<SnapshotFileEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotFileEntry {
+    pub name: String,
+    pub kind: FileKind,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// Query string accepted by the worker status endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StatusQuery {
+    pub actor_type: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+impl StatusQuery {
+    pub fn page(&self) -> PageRequest {
+        PageRequest {
+            offset: self.offset.unwrap_or_default(),
+            limit: self.limit,
+        }
+    }
+}
+
+/// Selects a window of a listing, so large inventories aren't copied or serialized in full for every query.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct PageRequest {
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl PageRequest {
+    /// Returns the items in the window along with the total number of items.
+    pub fn apply<T>(&self, items: impl IntoIterator<Item = T>) -> (Vec<T>, usize) {
+        let mut total = 0;
+        let page = items
+            .into_iter()
+            .inspect(|_| total += 1)
+            .enumerate()
+            .filter(|(i, _)| *i >= self.offset && self.limit.map_or(true, |l| *i - self.offset < l))
+            .map(|(_, item)| item)
+            .collect();
+        (page, total)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -11,6 +88,14 @@ pub struct SystemActor {
     pub actor_id: u64,
     pub actor_state: ActorState,
     pub actor_type: String,
+    #[serde(default)]
+    pub started: Option<DateTime<Utc>>,
+    /// The pool, dataset, container, sync or observer the actor works for.
+    #[serde(default)]
+    pub entity_id: Option<EntityId>,
+    /// When the actor last handled a message.
+    #[serde(default)]
+    pub last_activity: Option<DateTime<Utc>>,
 }
 
 #[derive(Serialize, Deserialize, Display, Clone)]
@@ -27,11 +112,87 @@ pub enum ActorState {
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum ActiveState {
-    Custom(String),
+    Running(ActorStatus),
     Unresponsive,
     Stopping,
 }
 
+#[derive(Serialize, Deserialize, Display, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ActorActivity {
+    Idle,
+    Active,
+    Faulted,
+    Unreachable,
+}
+
+/// What a running actor reports about itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActorStatus {
+    pub activity: ActorActivity,
+    /// The work in progress.
+    pub current_job: Option<String>,
+    /// Work waiting behind the current job.
+    #[serde(default)]
+    pub queue_depth: usize,
+    pub last_error: Option<String>,
+    /// When the current job started.
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl ActorStatus {
+    pub fn idle() -> Self {
+        Self {
+            activity: ActorActivity::Idle,
+            current_job: None,
+            queue_depth: 0,
+            last_error: None,
+            since: None,
+        }
+    }
+
+    pub fn active<S: Into<String>>(job: S) -> Self {
+        Self {
+            activity: ActorActivity::Active,
+            current_job: Some(job.into()),
+            ..Self::idle()
+        }
+    }
+
+    pub fn faulted<S: Into<String>>(error: S) -> Self {
+        Self {
+            activity: ActorActivity::Faulted,
+            last_error: Some(error.into()),
+            ..Self::idle()
+        }
+    }
+
+    /// Waiting for a remote target to answer before work can continue.
+    pub fn unreachable<S: Into<String>>(error: S) -> Self {
+        Self {
+            activity: ActorActivity::Unreachable,
+            last_error: Some(error.into()),
+            ..Self::idle()
+        }
+    }
+
+    pub fn with_queue_depth(mut self, queue_depth: usize) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    pub fn with_last_error<S: Into<String>>(mut self, error: S) -> Self {
+        self.last_error = Some(error.into());
+        self
+    }
+
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+}
+
 #[derive(Serialize, Deserialize, Display, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
@@ -48,3 +209,168 @@ impl Default for TerminalState {
         TerminalState::Indeterminate
     }
 }
+
+/// Request header carrying the confirmation token for a destructive API request.
+pub const CONFIRMATION_HEADER: &str = "x-blkcapt-confirm";
+
+/// Returned instead of performing a destructive request. The request takes effect when repeated with the token in
+/// `CONFIRMATION_HEADER` before it expires.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfirmationChallenge {
+    pub token: String,
+    pub operation: String,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeletedSnapshotsResponse {
+    pub deleted: usize,
+}
+
+/// Whether remote transfers are held back by a network pause.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct NetworkPauseResponse {
+    pub paused: bool,
+}
+
+/// Critical datasets snapshotted and their syncs started after a UPS low battery signal.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct EmergencyResponse {
+    pub datasets: usize,
+    pub syncs: usize,
+}
+
+/// Query string accepted by the dataset snapshot endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct SnapshotRequestQuery {
+    /// Tag the snapshot for the dataset's pre-upgrade retention.
+    #[serde(default)]
+    pub pre_upgrade: bool,
+}
+
+/// Query string accepted by the reload endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct ReloadQuery {
+    /// The caller already recorded the change being applied in the audit log, so the reload isn't recorded again.
+    #[serde(default)]
+    pub recorded: bool,
+}
+
+/// A snapshot the worker took on request.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SnapshotCreatedResponse {
+    pub datetime: DateTime<Utc>,
+}
+
+/// A dataset the worker rolled back to one of its snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RollbackResponse {
+    /// The held snapshot of the state before the rollback.
+    pub safety_snapshot: DateTime<Utc>,
+    /// Where the replaced subvolume is kept.
+    pub replaced_path: PathBuf,
+    /// The rolled back subvolume became the default subvolume.
+    pub made_default: bool,
+}
+
+/// Snapshots found on or missing from disk when a worker re-scanned a dataset or container.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct RefreshedSnapshotsResponse {
+    pub added: usize,
+    pub removed: usize,
+}
+
+impl RefreshedSnapshotsResponse {
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0
+    }
+}
+
+/// The configured pools, datasets and syncs with what their running actors know, for the dashboard.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DashboardState {
+    pub pools: Vec<PoolOverview>,
+    pub datasets: Vec<DatasetOverview>,
+    pub syncs: Vec<SyncOverview>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PoolOverview {
+    pub id: EntityId,
+    pub name: String,
+    pub mountpoint: PathBuf,
+    pub datasets: usize,
+    pub containers: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DatasetOverview {
+    pub id: EntityId,
+    pub path: String,
+    /// None when the dataset's actor isn't running.
+    pub activity: Option<DatasetActivity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DatasetActivity {
+    pub latest_snapshot: Option<DateTime<Utc>>,
+    /// The latest prune since the worker started.
+    pub last_prune: Option<JobOutcome>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncOverview {
+    pub id: EntityId,
+    pub name: String,
+    pub dataset_id: EntityId,
+    /// The dataset and container, by path and name.
+    pub route: String,
+    /// None when the sync's actor isn't running.
+    pub activity: Option<SyncActivity>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SyncActivity {
+    /// The newest snapshot held by the container.
+    pub newest_synced: Option<DateTime<Utc>>,
+    /// The latest transfer since the worker started.
+    pub last_transfer: Option<JobOutcome>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobOutcome {
+    pub finished: DateTime<Utc>,
+    pub succeeded: bool,
+}
+
+impl JobOutcome {
+    pub fn now(succeeded: bool) -> Self {
+        Self {
+            finished: Utc::now(),
+            succeeded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_window_and_total() {
+        let page = PageRequest {
+            offset: 2,
+            limit: Some(3),
+        };
+        assert_eq!(page.apply(0..10), (vec![2, 3, 4], 10));
+    }
+
+    #[test]
+    fn page_past_end() {
+        let page = PageRequest {
+            offset: 12,
+            limit: None,
+        };
+        assert_eq!(page.apply(0..10), (vec![], 10));
+    }
+}
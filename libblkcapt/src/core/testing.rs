@@ -0,0 +1,82 @@
+use super::{BtrfsSnapshot, Snapshot};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use std::{
+    cell::RefCell,
+    fmt,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+use uuid::Uuid;
+
+/// A snapshot that only exists in memory, for testing code that works with any kind of snapshot.
+pub struct FakeSnapshot {
+    pub datetime: DateTime<Utc>,
+    pub uuid: Uuid,
+    pub received_uuid: Option<Uuid>,
+    /// Where the files of the snapshot are, empty for tests that read none.
+    pub root: PathBuf,
+    /// Deleting the snapshot records its UUID here. Without it, deleting fails like it does for a busy subvolume.
+    pub deleted: Option<Rc<RefCell<Vec<Uuid>>>>,
+}
+
+impl FakeSnapshot {
+    pub fn new(datetime: DateTime<Utc>, uuid: Uuid) -> Self {
+        Self {
+            datetime,
+            uuid,
+            received_uuid: None,
+            root: PathBuf::new(),
+            deleted: None,
+        }
+    }
+}
+
+impl fmt::Display for FakeSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.datetime, self.uuid)
+    }
+}
+
+impl Snapshot for FakeSnapshot {
+    fn datetime(&self) -> DateTime<Utc> {
+        self.datetime
+    }
+}
+
+impl BtrfsSnapshot for FakeSnapshot {
+    fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    fn received_uuid(&self) -> Option<Uuid> {
+        self.received_uuid
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    fn delete(&self) -> Result<()> {
+        match &self.deleted {
+            Some(deleted) => {
+                deleted.borrow_mut().push(self.uuid);
+                Ok(())
+            }
+            None => bail!("subvolume busy"),
+        }
+    }
+
+    fn trash(&self, _keep_for: Duration) -> Result<()> {
+        self.delete()
+    }
+
+    fn clone_writable(&self, _path: &Path) -> Result<()> {
+        bail!("a fake snapshot can't be cloned")
+    }
+
+    fn local_path(&self) -> PathBuf {
+        self.root.clone()
+    }
+}
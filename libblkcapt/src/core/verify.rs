@@ -0,0 +1,522 @@
+use super::{BtrfsContainer, BtrfsContainerSnapshot, BtrfsSnapshot, SnapshotHandle};
+use crate::{model::EntityId, sys::btrfs::Subvolume};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+const MANIFEST_DIR: &str = ".manifests";
+
+/// Outcome of comparing files between a snapshot and its copy in a container.
+#[derive(Debug, Default)]
+pub struct SampleVerification {
+    pub checked: Vec<PathBuf>,
+    pub mismatched: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+}
+
+impl SampleVerification {
+    pub fn is_intact(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
+}
+
+/// Compare the contents of up to `count` randomly chosen regular files in the source snapshot with the same paths in
+/// its received copy. This catches corruption that a matching received_uuid can't, such as bad blocks on the
+/// container's disks or a faulty receive.
+pub fn verify_sample(source: &dyn BtrfsSnapshot, copy: &dyn BtrfsSnapshot, count: usize) -> Result<SampleVerification> {
+    verify_lineage(source.uuid(), source.received_uuid(), copy.uuid(), copy.received_uuid())?;
+
+    let source_root = source.local_path();
+    let copy_root = copy.local_path();
+    let mut verification = SampleVerification::default();
+    for relative in sample_files(&source_root, count, &mut thread_rng())? {
+        let copy_path = copy_root.join(&relative);
+        if !copy_path.is_file() {
+            verification.missing.push(relative);
+            continue;
+        }
+        if file_checksum(&source_root.join(&relative))? != file_checksum(&copy_path)? {
+            verification.mismatched.push(relative.clone());
+        }
+        verification.checked.push(relative);
+    }
+    Ok(verification)
+}
+
+/// A copy is linked to its source when its received_uuid is the source's uuid, or the uuid the source itself was
+/// received from.
+fn verify_lineage(
+    source_uuid: Uuid, source_received_uuid: Option<Uuid>, copy_uuid: Uuid, link: Option<Uuid>,
+) -> Result<()> {
+    if link != Some(source_uuid) && (link.is_none() || link != source_received_uuid) {
+        bail!(
+            "Snapshot {} in the container was not received from source snapshot {}.",
+            copy_uuid,
+            source_uuid
+        );
+    }
+    Ok(())
+}
+
+/// Pick up to `count` regular files below root uniformly at random, as sorted paths relative to root. Symlinks
+/// aren't followed. Reservoir sampling keeps only `count` paths in memory, however many files there are.
+pub fn sample_files(root: &Path, count: usize, rng: &mut impl Rng) -> Result<Vec<PathBuf>> {
+    let mut chosen = Vec::with_capacity(count);
+    let mut seen = 0;
+    visit_regular_files(root, |relative| {
+        seen += 1;
+        if chosen.len() < count {
+            chosen.push(relative);
+        } else {
+            let slot = rng.gen_range(0..seen);
+            if slot < count {
+                chosen[slot] = relative;
+            }
+        }
+        Ok(())
+    })?;
+    chosen.sort_unstable();
+    Ok(chosen)
+}
+
+/// Every regular file below root, as paths relative to root. Symlinks aren't followed.
+fn regular_files(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    visit_regular_files(root, |relative| {
+        files.push(relative);
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+fn visit_regular_files(root: &Path, mut visit: impl FnMut(PathBuf) -> Result<()>) -> Result<()> {
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative_dir) = pending.pop() {
+        let entries =
+            fs::read_dir(root.join(&relative_dir)).with_context(|| format!("Failed to list {:?}.", relative_dir))?;
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let relative = relative_dir.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push(relative);
+            } else if file_type.is_file() {
+                visit(relative)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read every regular file below root through to the end, for copies that have no manifest to compare with.
+pub fn read_files(root: &Path) -> Result<SampleVerification> {
+    let mut verification = SampleVerification::default();
+    visit_regular_files(root, |relative| {
+        file_checksum(&root.join(&relative))?;
+        verification.checked.push(relative);
+        Ok(())
+    })?;
+    Ok(verification)
+}
+
+/// SHA-256 of a file's contents, hex encoded.
+pub fn file_checksum(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Failed to open {:?}.", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {:?}.", path))?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Checksums of every regular file in the source snapshot of a copy received into a container. Copies can be checked
+/// against it long after the source snapshot was pruned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChecksumManifest {
+    pub source_uuid: Uuid,
+    pub source_received_uuid: Option<Uuid>,
+    pub created: DateTime<Utc>,
+    pub files: BTreeMap<PathBuf, String>,
+}
+
+impl ChecksumManifest {
+    /// Hashes the source snapshot reachable at `source_root`, never the received copy, which is what gets checked.
+    pub fn generate(source: &SnapshotHandle, source_root: &Path) -> Result<Self> {
+        let files = regular_files(source_root)?
+            .into_iter()
+            .map(|relative| file_checksum(&source_root.join(&relative)).map(|checksum| (relative, checksum)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            source_uuid: source.uuid,
+            source_received_uuid: source.received_uuid,
+            created: Utc::now(),
+            files,
+        })
+    }
+
+    /// Manifests live in a plain directory of the container subvolume, next to the per dataset subvolumes.
+    pub fn path(container: &BtrfsContainer, dataset_id: EntityId, copy_uuid: Uuid) -> PathBuf {
+        Self::path_in(&Self::dataset_dir(container, dataset_id), copy_uuid)
+    }
+
+    fn path_in(dir: &Path, copy_uuid: Uuid) -> PathBuf {
+        dir.join(format!("{}.json", copy_uuid))
+    }
+
+    fn dataset_dir(container: &BtrfsContainer, dataset_id: EntityId) -> PathBuf {
+        container.local_path().join(MANIFEST_DIR).join(dataset_id.to_string())
+    }
+
+    /// Every manifest kept in the container, with the dataset it belongs to and the uuid of the copy it describes.
+    pub fn stored(container: &BtrfsContainer) -> Result<Vec<(EntityId, Uuid, Self)>> {
+        Self::stored_in(&container.local_path().join(MANIFEST_DIR))
+    }
+
+    fn stored_in(root: &Path) -> Result<Vec<(EntityId, Uuid, Self)>> {
+        let mut manifests = Vec::new();
+        if !root.is_dir() {
+            return Ok(manifests);
+        }
+        for dataset_entry in fs::read_dir(root).with_context(|| format!("Failed to list {:?}.", root))? {
+            let dataset_entry = dataset_entry?;
+            let dataset_id = match dataset_entry.file_name().to_string_lossy().parse::<EntityId>() {
+                Ok(dataset_id) if dataset_entry.path().is_dir() => dataset_id,
+                _ => continue,
+            };
+            let dir = dataset_entry.path();
+            for entry in fs::read_dir(&dir).with_context(|| format!("Failed to list {:?}.", dir))? {
+                let path = entry?.path();
+                if path.extension().map_or(true, |e| e != "json") {
+                    continue;
+                }
+                let copy_uuid = match path.file_stem().and_then(|s| s.to_str()).map(Uuid::parse_str) {
+                    Some(Ok(copy_uuid)) => copy_uuid,
+                    _ => continue,
+                };
+                if let Some(manifest) = Self::load(&path)? {
+                    manifests.push((dataset_id, copy_uuid, manifest));
+                }
+            }
+        }
+        manifests.sort_by_key(|(dataset_id, copy_uuid, _)| (*dataset_id, *copy_uuid));
+        Ok(manifests)
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .with_context(|| format!("Failed to parse manifest {:?}.", path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read manifest {:?}.", path)),
+        }
+    }
+
+    /// Written to a temporary file first, a manifest is never seen half written.
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().expect("manifest paths have a parent");
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create manifest directory {:?}.", dir))?;
+        let partial = path.with_extension("json.partial");
+        fs::write(&partial, serde_json::to_vec(self)?).with_context(|| format!("Failed to write {:?}.", partial))?;
+        fs::rename(&partial, path).with_context(|| format!("Failed to move manifest into place at {:?}.", path))
+    }
+
+    /// Check that the copy was received from the recorded source and that every file still has its recorded
+    /// contents.
+    pub fn verify(&self, copy: &dyn BtrfsSnapshot) -> Result<SampleVerification> {
+        verify_lineage(
+            self.source_uuid,
+            self.source_received_uuid,
+            copy.uuid(),
+            copy.received_uuid(),
+        )?;
+        self.verify_files(&copy.local_path())
+    }
+
+    /// The same checks for a copy received again from the container, reachable at root.
+    pub fn verify_restored(&self, restored: &Subvolume, root: &Path) -> Result<SampleVerification> {
+        verify_lineage(
+            self.source_uuid,
+            self.source_received_uuid,
+            restored.uuid,
+            restored.received_uuid,
+        )?;
+        self.verify_files(root)
+    }
+
+    fn verify_files(&self, root: &Path) -> Result<SampleVerification> {
+        let mut verification = SampleVerification::default();
+        for (relative, checksum) in &self.files {
+            let path = root.join(relative);
+            if !path.is_file() {
+                verification.missing.push(relative.clone());
+                continue;
+            }
+            if &file_checksum(&path)? != checksum {
+                verification.mismatched.push(relative.clone());
+            }
+            verification.checked.push(relative.clone());
+        }
+        Ok(verification)
+    }
+}
+
+/// Outcome of checking the snapshots in a container against their manifests.
+#[derive(Debug, Default)]
+pub struct ContainerVerification {
+    pub verified: usize,
+    /// Snapshots received before manifests were kept, or whose manifest was never written.
+    pub unverified: usize,
+    pub failures: Vec<(Uuid, String)>,
+    pub removed_manifests: usize,
+}
+
+impl ContainerVerification {
+    pub fn is_intact(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+/// Verify every snapshot received from the dataset that has a manifest, and drop manifests of snapshots that are no
+/// longer in the container. Copies of one source must also have distinct received_uuids, two copies of the same
+/// send mean the chain got mixed up. Snapshots pruned while being checked aren't failures.
+pub fn verify_container_snapshots(
+    container: &BtrfsContainer, dataset_id: EntityId, snapshots: &[BtrfsContainerSnapshot],
+    verification: &mut ContainerVerification,
+) -> Result<()> {
+    verify_snapshots(
+        &ChecksumManifest::dataset_dir(container, dataset_id),
+        snapshots,
+        verification,
+    )
+}
+
+fn verify_snapshots<S: BtrfsSnapshot>(
+    dir: &Path, snapshots: &[S], verification: &mut ContainerVerification,
+) -> Result<()> {
+    let mut received = HashSet::new();
+    for snapshot in snapshots {
+        if let Some(received_uuid) = snapshot.received_uuid() {
+            if !received.insert(received_uuid) {
+                verification.failures.push((
+                    snapshot.uuid(),
+                    format!("another snapshot was also received from {}", received_uuid),
+                ));
+            }
+        }
+
+        let failure = match ChecksumManifest::load(&ChecksumManifest::path_in(dir, snapshot.uuid())) {
+            Ok(Some(manifest)) => match manifest.verify(snapshot) {
+                Ok(result) if result.is_intact() => {
+                    verification.verified += 1;
+                    None
+                }
+                Ok(result) => Some(format!(
+                    "{} files differ and {} are missing",
+                    result.mismatched.len(),
+                    result.missing.len()
+                )),
+                Err(e) => Some(format!("{:#}", e)),
+            },
+            Ok(None) => {
+                verification.unverified += 1;
+                None
+            }
+            Err(e) => Some(format!("{:#}", e)),
+        };
+        if let Some(reason) = failure {
+            if snapshot.local_path().is_dir() {
+                verification.failures.push((snapshot.uuid(), reason));
+            }
+        }
+    }
+
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let current = snapshots
+        .iter()
+        .map(|s| format!("{}.json", s.uuid()))
+        .collect::<HashSet<_>>();
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to list {:?}.", dir))? {
+        let entry = entry?;
+        if !current.contains(entry.file_name().to_string_lossy().as_ref()) {
+            fs::remove_file(entry.path()).with_context(|| format!("Failed to remove {:?}.", entry.path()))?;
+            verification.removed_manifests += 1;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::testing::FakeSnapshot;
+    use rand::{rngs::StdRng, SeedableRng};
+    use tempfile::{tempdir, TempDir};
+
+    /// Holding a.txt and nested/b.txt.
+    fn tree() -> TempDir {
+        let tree = tempdir().unwrap();
+        fs::create_dir_all(tree.path().join("nested")).unwrap();
+        fs::write(tree.path().join("a.txt"), "first").unwrap();
+        fs::write(tree.path().join("nested/b.txt"), "second").unwrap();
+        tree
+    }
+
+    fn source_handle(uuid: Uuid) -> SnapshotHandle {
+        SnapshotHandle {
+            datetime: Utc::now(),
+            uuid,
+            received_uuid: None,
+        }
+    }
+
+    fn copy_of(source: Uuid, root: &Path) -> FakeSnapshot {
+        FakeSnapshot {
+            received_uuid: Some(source),
+            root: root.to_owned(),
+            ..FakeSnapshot::new(Utc::now(), Uuid::new_v4())
+        }
+    }
+
+    /// Holding `count` files spread over two directories.
+    fn tree_of(count: usize) -> TempDir {
+        let tree = tempdir().unwrap();
+        fs::create_dir_all(tree.path().join("nested")).unwrap();
+        for i in 0..count {
+            let dir = if i % 2 == 0 { "" } else { "nested" };
+            fs::write(tree.path().join(dir).join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn sample_takes_every_file_of_a_small_tree() {
+        let tree = tree();
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(
+            sample_files(tree.path(), 5, &mut rng).unwrap(),
+            vec![PathBuf::from("a.txt"), PathBuf::from("nested/b.txt")]
+        );
+        assert!(sample_files(tree.path(), 0, &mut rng).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sample_picks_distinct_existing_files() {
+        let tree = tree_of(50);
+        let mut rng = StdRng::seed_from_u64(2);
+        let sampled = sample_files(tree.path(), 10, &mut rng).unwrap();
+
+        assert_eq!(sampled.len(), 10);
+        assert!(sampled.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sampled.iter().all(|relative| tree.path().join(relative).is_file()));
+    }
+
+    #[test]
+    fn sample_is_uniform() {
+        let tree = tree_of(4);
+        let mut picks = BTreeMap::<PathBuf, usize>::new();
+        for seed in 0..4000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for relative in sample_files(tree.path(), 1, &mut rng).unwrap() {
+                *picks.entry(relative).or_default() += 1;
+            }
+        }
+
+        assert_eq!(picks.len(), 4);
+        assert!(picks.values().all(|&count| (800..1200).contains(&count)), "{:?}", picks);
+    }
+
+    #[test]
+    fn stored_manifests_are_found_per_dataset() {
+        let source = tree();
+        let root = tempdir().unwrap();
+        let dataset_id = EntityId::default();
+        let copy_uuid = Uuid::new_v4();
+        let manifest = ChecksumManifest::generate(&source_handle(Uuid::new_v4()), source.path()).unwrap();
+        let dir = root.path().join(dataset_id.to_string());
+        manifest.store(&ChecksumManifest::path_in(&dir, copy_uuid)).unwrap();
+        fs::write(dir.join("notes.txt"), "not a manifest").unwrap();
+        fs::write(dir.join(format!("{}.json.partial", Uuid::new_v4())), "{").unwrap();
+        fs::create_dir_all(root.path().join("not-a-dataset")).unwrap();
+
+        let stored = ChecksumManifest::stored_in(root.path()).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!((stored[0].0, stored[0].1), (dataset_id, copy_uuid));
+        assert_eq!(stored[0].2.files, manifest.files);
+        assert!(ChecksumManifest::stored_in(&root.path().join("missing"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn manifest_is_taken_from_the_source() {
+        let source = tree();
+        let copy = tree();
+        fs::write(copy.path().join("a.txt"), "corrupted on receive").unwrap();
+        let source_uuid = Uuid::new_v4();
+
+        let manifest = ChecksumManifest::generate(&source_handle(source_uuid), source.path()).unwrap();
+        assert_eq!(manifest.files.len(), 2);
+
+        let result = manifest.verify(&copy_of(source_uuid, copy.path())).unwrap();
+        assert_eq!(result.mismatched, vec![PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn manifest_verify_finds_changed_and_missing_files() {
+        let tree = tree();
+        let source_uuid = Uuid::new_v4();
+        let manifest = ChecksumManifest::generate(&source_handle(source_uuid), tree.path()).unwrap();
+        let copy = copy_of(source_uuid, tree.path());
+        assert!(manifest.verify(&copy).unwrap().is_intact());
+
+        fs::write(tree.path().join("a.txt"), "changed").unwrap();
+        fs::remove_file(tree.path().join("nested/b.txt")).unwrap();
+        let result = manifest.verify(&copy).unwrap();
+        assert_eq!(result.mismatched, vec![PathBuf::from("a.txt")]);
+        assert_eq!(result.missing, vec![PathBuf::from("nested/b.txt")]);
+        assert_eq!(result.checked, vec![PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn manifest_verify_rejects_a_copy_of_another_source() {
+        let tree = tree();
+        let manifest = ChecksumManifest::generate(&source_handle(Uuid::new_v4()), tree.path()).unwrap();
+        assert!(manifest.verify(&copy_of(Uuid::new_v4(), tree.path())).is_err());
+    }
+
+    #[test]
+    fn container_snapshots_are_verified_against_their_manifests() {
+        let tree = tree();
+        let manifests = tempdir().unwrap();
+        let source_uuid = Uuid::new_v4();
+        let manifest = ChecksumManifest::generate(&source_handle(source_uuid), tree.path()).unwrap();
+
+        let verified = copy_of(source_uuid, tree.path());
+        manifest
+            .store(&ChecksumManifest::path_in(manifests.path(), verified.uuid))
+            .unwrap();
+        let duplicate = copy_of(source_uuid, tree.path());
+        let unverified = copy_of(Uuid::new_v4(), tree.path());
+        let stale = ChecksumManifest::path_in(manifests.path(), Uuid::new_v4());
+        manifest.store(&stale).unwrap();
+
+        let mut verification = ContainerVerification::default();
+        verify_snapshots(manifests.path(), &[verified, duplicate, unverified], &mut verification).unwrap();
+        assert_eq!(verification.verified, 1);
+        assert_eq!(verification.unverified, 2);
+        assert_eq!(verification.failures.len(), 1);
+        assert!(verification.failures[0].1.contains("also received from"));
+        assert!(!stale.exists());
+        assert_eq!(verification.removed_manifests, 1);
+    }
+}
@@ -1,12 +1,51 @@
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use once_cell::sync::Lazy;
+use std::{env, ffi::OsString, path::PathBuf};
 pub mod core;
 pub mod model;
 pub mod parsing;
 pub mod sys;
 
+/// Names a directory to use as the entity store instead of the default.
+pub const STORE_ENV: &str = "BLKCAPT_STORE";
+/// Selects a per-user store under the XDG data directory. The profile `system` is the worker's state dir.
+pub const PROFILE_ENV: &str = "BLKCAPT_PROFILE";
+pub const SYSTEM_PROFILE: &str = "system";
+
+static DATA_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    resolve_data_dir(
+        env::var_os(STORE_ENV),
+        env::var(PROFILE_ENV).ok(),
+        xdg_data_home(),
+        || model::storage::worker_config().state_dir.clone(),
+    )
+});
+
 pub fn data_dir() -> PathBuf {
-    PathBuf::from("/var/lib/blockcaptain")
+    DATA_DIR.clone()
+}
+
+fn resolve_data_dir(
+    store: Option<OsString>, profile: Option<String>, xdg_data_home: Option<PathBuf>, system: impl FnOnce() -> PathBuf,
+) -> PathBuf {
+    if let Some(store) = store.filter(|s| !s.is_empty()) {
+        return PathBuf::from(store);
+    }
+    match (profile.filter(|p| !p.is_empty() && p != SYSTEM_PROFILE), xdg_data_home) {
+        (Some(profile), Some(xdg_data_home)) => xdg_data_home.join("blockcaptain").join(profile),
+        _ => system(),
+    }
+}
+
+fn xdg_data_home() -> Option<PathBuf> {
+    env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/share")))
+}
+
+pub fn config_dir() -> PathBuf {
+    PathBuf::from("/etc/blockcaptain")
 }
 
 pub fn runtime_dir() -> PathBuf {
@@ -31,8 +70,48 @@ pub fn error_cause(error: &anyhow::Error) -> String {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     pub mod prelude {
         pub use indoc::indoc;
         pub use serial_test::serial;
     }
+
+    fn system() -> PathBuf {
+        PathBuf::from("/var/lib/blockcaptain")
+    }
+
+    #[test]
+    fn data_dir_explicit_store_wins() {
+        let dir = resolve_data_dir(
+            Some(OsString::from("/tmp/staging")),
+            Some(String::from("test")),
+            Some(PathBuf::from("/home/user/.local/share")),
+            system,
+        );
+        assert_eq!(dir, PathBuf::from("/tmp/staging"));
+    }
+
+    #[test]
+    fn data_dir_user_profile() {
+        let dir = resolve_data_dir(
+            None,
+            Some(String::from("test")),
+            Some(PathBuf::from("/home/user/.local/share")),
+            system,
+        );
+        assert_eq!(dir, PathBuf::from("/home/user/.local/share/blockcaptain/test"));
+    }
+
+    #[test]
+    fn data_dir_system_profile() {
+        let dir = resolve_data_dir(
+            None,
+            Some(String::from(SYSTEM_PROFILE)),
+            Some(PathBuf::from("/home/user/.local/share")),
+            system,
+        );
+        assert_eq!(dir, system());
+        assert_eq!(resolve_data_dir(None, None, None, system), system());
+    }
 }
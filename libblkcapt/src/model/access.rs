@@ -0,0 +1,114 @@
+use crate::sys::polkit::ActionClass;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use strum_macros::{Display, EnumString};
+use uuid::Uuid;
+
+/// Roles attached to API tokens. Each role includes everything the roles before it can do.
+#[derive(Serialize, Deserialize, Clone, Copy, Display, EnumString, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ApiRole {
+    /// Read status only.
+    Viewer,
+    /// Also start and stop jobs.
+    Operator,
+    /// Also restore and delete data.
+    Admin,
+}
+
+impl ApiRole {
+    pub fn permits(&self, action: ActionClass) -> bool {
+        match action {
+            ActionClass::ReadStatus => true,
+            ActionClass::ManageJobs => matches!(self, ApiRole::Operator | ApiRole::Admin),
+            ActionClass::BrowseData | ActionClass::Destructive => matches!(self, ApiRole::Admin),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiToken {
+    pub name: String,
+    pub role: ApiRole,
+    pub created: DateTime<Utc>,
+    /// Hex SHA-256 of the token secret. The secret itself is only shown once when the token is created.
+    secret_hash: String,
+}
+
+impl ApiToken {
+    /// Create a token and return it with its secret.
+    pub fn generate(name: String, role: ApiRole) -> (Self, String) {
+        let secret = format!("{}{}", Uuid::new_v4().to_simple(), Uuid::new_v4().to_simple());
+        let token = Self {
+            name,
+            role,
+            created: Utc::now(),
+            secret_hash: hash_secret(&secret),
+        };
+        (token, secret)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ApiTokens {
+    pub tokens: Vec<ApiToken>,
+}
+
+impl ApiTokens {
+    pub fn add(&mut self, token: ApiToken) -> Result<()> {
+        if self.tokens.iter().any(|t| t.name == token.name) {
+            bail!("a token named '{}' already exists", token.name);
+        }
+        self.tokens.push(token);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<ApiToken> {
+        match self.tokens.iter().position(|t| t.name == name) {
+            Some(index) => Ok(self.tokens.remove(index)),
+            None => bail!("no token named '{}'", name),
+        }
+    }
+
+    pub fn find_by_secret(&self, secret: &str) -> Option<&ApiToken> {
+        let secret_hash = hash_secret(secret);
+        self.tokens.iter().find(|t| t.secret_hash == secret_hash)
+    }
+}
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_cumulative() {
+        assert!(ApiRole::Viewer.permits(ActionClass::ReadStatus));
+        assert!(!ApiRole::Viewer.permits(ActionClass::ManageJobs));
+        assert!(!ApiRole::Viewer.permits(ActionClass::Destructive));
+        assert!(ApiRole::Operator.permits(ActionClass::ManageJobs));
+        assert!(!ApiRole::Operator.permits(ActionClass::Destructive));
+        assert!(ApiRole::Admin.permits(ActionClass::Destructive));
+        assert!(!ApiRole::Operator.permits(ActionClass::BrowseData));
+        assert!(ApiRole::Admin.permits(ActionClass::BrowseData));
+    }
+
+    #[test]
+    fn token_found_only_by_its_secret() {
+        let mut tokens = ApiTokens::default();
+        let (token, secret) = ApiToken::generate(String::from("monitoring"), ApiRole::Viewer);
+        tokens.add(token).unwrap();
+
+        assert_eq!(tokens.find_by_secret(&secret).map(|t| t.role), Some(ApiRole::Viewer));
+        assert!(tokens.find_by_secret("not-the-secret").is_none());
+        assert!(tokens
+            .add(ApiToken::generate(String::from("monitoring"), ApiRole::Admin).0)
+            .is_err());
+    }
+}
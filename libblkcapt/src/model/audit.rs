@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use nix::unistd::{getuid, Uid, User};
+use serde::{Deserialize, Serialize};
+use strum_macros::Display;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum AuditInterface {
+    Cli,
+    Api,
+}
+
+/// An administrative change, recorded separately from job history.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditRecord {
+    pub timestamp: DateTime<Utc>,
+    pub interface: AuditInterface,
+    pub uid: Option<u32>,
+    pub user: Option<String>,
+    /// Name of the API token the change was authorized with.
+    #[serde(default)]
+    pub token: Option<String>,
+    /// The user that invoked sudo, when the change was made through it.
+    #[serde(default)]
+    pub sudo_user: Option<String>,
+    pub action: String,
+    pub detail: String,
+    /// Why the action failed, possibly after it had changed something. `None` when it succeeded.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// A record attributed to the user running this process.
+    pub fn for_current_user(interface: AuditInterface, action: &str, detail: String) -> Self {
+        let uid = getuid();
+        Self {
+            timestamp: Utc::now(),
+            interface,
+            uid: Some(uid.as_raw()),
+            user: user_name(uid),
+            token: None,
+            sudo_user: std::env::var("SUDO_USER").ok(),
+            action: action.to_owned(),
+            detail,
+            error: None,
+        }
+    }
+
+    /// A record attributed to a client of the worker API.
+    pub fn for_peer(uid: Option<u32>, token: Option<String>, action: &str, detail: String) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            interface: AuditInterface::Api,
+            uid,
+            user: uid.and_then(|uid| user_name(Uid::from_raw(uid))),
+            token,
+            sudo_user: None,
+            action: action.to_owned(),
+            detail,
+            error: None,
+        }
+    }
+
+    /// The user to show for the record, crediting the sudo caller and naming the token when there are any.
+    pub fn actor(&self) -> String {
+        let mut actor = self
+            .user
+            .clone()
+            .or_else(|| self.uid.map(|uid| uid.to_string()))
+            .unwrap_or_else(|| String::from("unknown"));
+        if let Some(sudo_user) = &self.sudo_user {
+            actor = format!("{} (as {})", sudo_user, actor);
+        }
+        if let Some(token) = &self.token {
+            actor = format!("{} with token '{}'", actor, token);
+        }
+        actor
+    }
+}
+
+fn user_name(uid: Uid) -> Option<String> {
+    User::from_uid(uid).ok().flatten().map(|u| u.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user: Option<&str>, uid: Option<u32>) -> AuditRecord {
+        AuditRecord {
+            timestamp: Utc::now(),
+            interface: AuditInterface::Cli,
+            uid,
+            user: user.map(String::from),
+            token: None,
+            sudo_user: None,
+            action: String::from("pool create"),
+            detail: String::new(),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn actor_falls_back_from_name_to_uid() {
+        assert_eq!(record(Some("alice"), Some(1000)).actor(), "alice");
+        assert_eq!(record(None, Some(1000)).actor(), "1000");
+        assert_eq!(record(None, None).actor(), "unknown");
+    }
+
+    #[test]
+    fn actor_credits_sudo_caller_and_token() {
+        let mut sudo = record(Some("root"), Some(0));
+        sudo.sudo_user = Some(String::from("alice"));
+        assert_eq!(sudo.actor(), "alice (as root)");
+
+        let mut token = record(None, None);
+        token.token = Some(String::from("monitoring"));
+        assert_eq!(token.actor(), "unknown with token 'monitoring'");
+    }
+
+    #[test]
+    fn peer_record_is_an_api_record() {
+        let record = AuditRecord::for_peer(None, Some(String::from("ci")), "sync run", String::from("7"));
+        assert_eq!(record.interface, AuditInterface::Api);
+        assert_eq!(record.user, None);
+        assert_eq!(record.sudo_user, None);
+        assert_eq!(record.action, "sync run");
+    }
+
+    #[test]
+    fn records_without_token_or_sudo_user_still_parse() {
+        let record: AuditRecord = serde_json::from_str(
+            r#"{"timestamp":"2021-03-01T10:00:00Z","interface":"cli","uid":0,"user":"root",
+            "action":"dataset update","detail":""}"#,
+        )
+        .unwrap();
+        assert_eq!(record.token, None);
+        assert_eq!(record.sudo_user, None);
+        assert_eq!(record.error, None);
+        assert_eq!(record.actor(), "root");
+    }
+}
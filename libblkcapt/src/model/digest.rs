@@ -0,0 +1,83 @@
+use super::entities::ObservableEvent;
+use serde::{Deserialize, Serialize};
+
+/// Successes a notification observer collected since it last sent a digest, stored so a restart doesn't lose them.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct NotificationDigest {
+    entries: Vec<DigestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DigestEntry {
+    pub entity: String,
+    pub event: ObservableEvent,
+    pub count: usize,
+}
+
+impl NotificationDigest {
+    pub fn record(&mut self, entity: &str, event: ObservableEvent) {
+        match self.entries.iter_mut().find(|e| e.entity == entity && e.event == event) {
+            Some(entry) => entry.count += 1,
+            None => self.entries.push(DigestEntry {
+                entity: entity.to_owned(),
+                event,
+                count: 1,
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn total(&self) -> usize {
+        self.entries.iter().map(|e| e.count).sum()
+    }
+
+    /// One line per entity and event, in the order they first happened.
+    pub fn summary(&self) -> String {
+        self.entries
+            .iter()
+            .map(|e| format!("{} for {}: {}x", e.event, e.entity, e.count))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_counts_repeats_per_entity_and_event() {
+        let mut digest = NotificationDigest::default();
+        digest.record("tank/home", ObservableEvent::DatasetSnapshot);
+        digest.record("tank/home", ObservableEvent::DatasetPrune);
+        digest.record("tank/home", ObservableEvent::DatasetSnapshot);
+        digest.record("tank/vm", ObservableEvent::DatasetSnapshot);
+
+        assert_eq!(digest.total(), 4);
+        assert_eq!(
+            digest.summary(),
+            "dataset_snapshot for tank/home: 2x\ndataset_prune for tank/home: 1x\ndataset_snapshot for tank/vm: 1x"
+        );
+
+        digest.clear();
+        assert!(digest.is_empty());
+        assert_eq!(digest.total(), 0);
+    }
+
+    #[test]
+    fn digest_survives_a_round_trip() {
+        let mut digest = NotificationDigest::default();
+        digest.record("tank/home", ObservableEvent::DatasetSnapshot);
+        digest.record("tank/home", ObservableEvent::DatasetSnapshot);
+
+        let stored = serde_json::to_string(&digest).unwrap();
+        assert_eq!(serde_json::from_str::<NotificationDigest>(&stored).unwrap(), digest);
+    }
+}
@@ -1,10 +1,20 @@
 use super::{Entity, EntityId, EntityStatic, EntityType};
-use crate::sys::fs::FsPathBuf;
+use crate::sys::{
+    btrfs::{CompressionAlgorithm, QGroupId, StreamCompression, SubvolumeProperties},
+    fs::FsPathBuf,
+    net::MacAddress,
+};
 use anyhow::{anyhow, bail, Context as AnyhowContext, Result};
+use chrono::{DateTime, Utc};
 use cron::Schedule;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, convert::TryFrom, convert::TryInto, path::PathBuf, str::FromStr};
-use std::{default::Default, num::NonZeroU32, time::Duration};
+use std::{
+    default::Default,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    time::Duration,
+};
 use strum_macros::Display;
 use strum_macros::EnumString;
 use uuid::Uuid;
@@ -18,12 +28,39 @@ pub struct BtrfsPoolEntity {
     pub uuid_subs: Vec<Uuid>,
     pub scrub_schedule: Option<ScheduleModel>,
     pub pause_scrubbing: bool,
+    #[serde(default)]
+    pub dataset_defaults: DatasetDefaults,
+    /// Maximum number of snapshot transfers reading from or writing to this pool at once, within the worker's
+    /// global limit. Default: unlimited.
+    #[serde(default)]
+    pub max_concurrent_transfers: Option<NonZeroUsize>,
+    /// Batch the snapshots, prunes and syncs that come due on this pool into windows on this schedule, so its
+    /// disks can stay spun down in between.
+    #[serde(default)]
+    pub wake_schedule: Option<ScheduleModel>,
+    /// Keep a level 1 quota group for each dataset and container, holding its subvolume and snapshots.
+    #[serde(default)]
+    pub qgroup_hierarchy: bool,
+    /// Schedule for discarding unused blocks on SSDs with fstrim.
+    #[serde(default)]
+    pub trim_schedule: Option<ScheduleModel>,
+    #[serde(default)]
+    pub pause_trimming: bool,
+    /// Refuse to snapshot while the pool has less free space than this. Default: 1 GiB.
+    #[serde(default)]
+    pub min_free_bytes: Option<u64>,
+    /// Refuse to snapshot while less than this is left for metadata. Needs root to check. Default: 256 MiB.
+    #[serde(default)]
+    pub min_metadata_headroom_bytes: Option<u64>,
 
     pub datasets: Vec<BtrfsDatasetEntity>,
     pub containers: Vec<BtrfsContainerEntity>,
 }
 
 impl BtrfsPoolEntity {
+    pub const DEFAULT_MIN_FREE_BYTES: u64 = 1024 * 1024 * 1024;
+    pub const DEFAULT_MIN_METADATA_HEADROOM_BYTES: u64 = 256 * 1024 * 1024;
+
     pub fn new(name: String, mountpoint: PathBuf, uuid: Uuid, uuid_subs: Vec<Uuid>) -> Result<Self> {
         Ok(Self {
             id: EntityId::new(),
@@ -33,11 +70,25 @@ impl BtrfsPoolEntity {
             uuid_subs,
             scrub_schedule: None,
             pause_scrubbing: false,
+            dataset_defaults: Default::default(),
+            max_concurrent_transfers: None,
+            wake_schedule: None,
+            qgroup_hierarchy: false,
+            trim_schedule: None,
+            pause_trimming: false,
+            min_free_bytes: None,
+            min_metadata_headroom_bytes: None,
             datasets: Vec::<BtrfsDatasetEntity>::default(),
             containers: Vec::<BtrfsContainerEntity>::default(),
         })
     }
 
+    /// Keep the id the pool had before its config was lost, as recorded in its snapshots.
+    pub fn with_id(mut self, id: EntityId) -> Self {
+        self.id = id;
+        self
+    }
+
     pub fn attach_dataset(&mut self, dataset: BtrfsDatasetEntity) -> Result<()> {
         self.subvolume_by_uuid(dataset.uuid()).map_or(Ok(()), |d| {
             Err(anyhow!("uuid already used by {} {}.", d.entity_type(), d.name()))
@@ -62,6 +113,32 @@ impl BtrfsPoolEntity {
         Ok(())
     }
 
+    /// Give each dataset and container that doesn't have one the next free level 1 quota group. `in_use` holds the
+    /// quota groups that already exist on the filesystem, so groups created outside blkcapt are never taken over.
+    pub fn assign_qgroups(&mut self, in_use: &[QGroupId]) {
+        let mut next = self
+            .subvolumes()
+            .filter_map(|s| s.qgroup())
+            .chain(in_use.iter().copied())
+            .filter(|q| q.level == 1)
+            .map(|q| q.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let mut allocate = |qgroup: &mut Option<QGroupId>| {
+            if qgroup.is_none() {
+                *qgroup = Some(QGroupId { level: 1, id: next });
+                next += 1;
+            }
+        };
+        for dataset in self.datasets.iter_mut() {
+            allocate(&mut dataset.qgroup);
+        }
+        for container in self.containers.iter_mut() {
+            allocate(&mut container.qgroup);
+        }
+    }
+
     fn subvolume_by_uuid(&self, uuid: &Uuid) -> Option<&dyn SubvolumeEntity> {
         self.subvolumes().find(|d| d.uuid() == uuid)
     }
@@ -88,6 +165,18 @@ impl BtrfsPoolEntity {
         }
     }
 
+    pub fn trimming_state(&self) -> FeatureState {
+        if self.trim_schedule.is_some() {
+            if self.pause_trimming {
+                FeatureState::Paused
+            } else {
+                FeatureState::Enabled
+            }
+        } else {
+            FeatureState::Unconfigured
+        }
+    }
+
     pub(super) fn post_deserialize(&mut self) {
         let id = self.id();
         for container in self.containers.iter_mut() {
@@ -125,6 +214,7 @@ impl<'a> AsRef<dyn Entity + 'a> for BtrfsPoolEntity {
 pub trait SubvolumeEntity: Entity {
     fn path(&self) -> &FsPathBuf;
     fn uuid(&self) -> &Uuid;
+    fn qgroup(&self) -> Option<QGroupId>;
 }
 
 #[derive(Display, Copy, Clone, Eq, PartialEq)]
@@ -144,6 +234,269 @@ pub struct BtrfsDatasetEntity {
     pub pause_snapshotting: bool,
     pub snapshot_retention: Option<RetentionRuleset>,
     pub pause_pruning: bool,
+    #[serde(default)]
+    pub properties: SubvolumeProperties,
+    #[serde(default)]
+    pub restore_divergence: Option<RestoreDivergence>,
+    /// Maximum acceptable age of the newest snapshot held by each sync target.
+    #[serde(default, with = "humantime_serde")]
+    pub target_rpo: Option<Duration>,
+    /// Don't take a scheduled snapshot when nothing was written since the latest one.
+    #[serde(default)]
+    pub skip_unchanged: bool,
+    /// Also take a snapshot this long after the system boots or resumes from suspend, for machines that are rarely
+    /// on at the scheduled times.
+    #[serde(default, with = "humantime_serde")]
+    pub snapshot_after_wake: Option<Duration>,
+    /// When the pool is too full to snapshot, prune down to the minimum retained snapshots and retry once.
+    #[serde(default)]
+    pub emergency_prune: bool,
+    /// Snapshot and sync right away when the UPS reports it is about to run out.
+    #[serde(default)]
+    pub critical: bool,
+    /// What to do when snapshots pile up because a sync target isn't keeping up.
+    #[serde(default)]
+    pub sync_backlog: Option<SyncBacklogLimit>,
+    #[serde(default)]
+    pub qgroup: Option<QGroupId>,
+    /// Limit on the space referenced by the dataset and its snapshots. Requires the pool's quota group hierarchy.
+    #[serde(default)]
+    pub snapshot_quota: Option<SnapshotQuota>,
+    /// Scheduled defragmentation, for datasets with heavy random-write workloads like VM images.
+    #[serde(default)]
+    pub defrag: Option<DefragConfig>,
+    /// Snapshots pinned by the user, never pruned until released.
+    #[serde(default)]
+    pub held_snapshots: Vec<DateTime<Utc>>,
+    #[serde(default)]
+    pub snapshot_hooks: Option<SnapshotHooks>,
+    /// Snapshots taken by `blkcaptctl upgrade-hook run` before system package upgrades. Regular retention leaves
+    /// them alone, they are pruned by `pre_upgrade_retention` instead.
+    #[serde(default)]
+    pub pre_upgrade_snapshots: Vec<DateTime<Utc>>,
+    #[serde(default)]
+    pub pre_upgrade_retention: PreUpgradeRetention,
+    /// Boot menu entries for the newest snapshots, for datasets holding the root filesystem.
+    #[serde(default)]
+    pub boot_menu: Option<BootMenuConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum BootLoader {
+    Grub,
+    SystemdBoot,
+}
+
+/// Menu entries that boot the dataset's newest snapshots with the running system's kernel and command line, like
+/// grub-btrfs. Snapshots are read-only, booting them is meant for inspection or recovery unless the initramfs
+/// overlays a writable layer.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BootMenuConfig {
+    pub loader: BootLoader,
+    /// How many of the newest snapshots get an entry.
+    pub entries: NonZeroU32,
+    /// Kernel image as the bootloader finds it, e.g. /vmlinuz-linux.
+    pub kernel: String,
+    /// Initramfs images as the bootloader finds them, microcode first.
+    #[serde(default)]
+    pub initrds: Vec<String>,
+    /// Where systemd-boot's loader directory lives, the ESP or XBOOTLDR mount. Grub menus go below /boot/grub.
+    #[serde(default = "default_boot_path")]
+    pub boot_path: PathBuf,
+}
+
+impl BootMenuConfig {
+    pub fn new(loader: BootLoader, kernel: String) -> Self {
+        Self {
+            loader,
+            entries: NonZeroU32::new(5).expect("nonzero valid constant"),
+            kernel,
+            initrds: Vec::new(),
+            boot_path: default_boot_path(),
+        }
+    }
+}
+
+fn default_boot_path() -> PathBuf {
+    PathBuf::from("/boot")
+}
+
+/// Pruning for pre-upgrade snapshots. They're only there to roll back a bad upgrade, so they go much sooner than
+/// regular snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PreUpgradeRetention {
+    pub newest_count: NonZeroU32,
+    /// Pre-upgrade snapshots older than this are pruned, even the newest.
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+}
+
+impl Default for PreUpgradeRetention {
+    fn default() -> Self {
+        Self {
+            newest_count: NonZeroU32::new(3).expect("nonzero valid constant"),
+            max_age: Duration::from_secs(30 * 24 * 3600),
+        }
+    }
+}
+
+/// Commands run around each snapshot of a dataset, e.g. to flush a database to disk before and release it after.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotHooks {
+    /// Shell command run before the snapshot is taken.
+    #[serde(default)]
+    pub pre: Option<String>,
+    /// Shell command run after the snapshot, whether or not it was taken, so whatever the pre command did is undone.
+    #[serde(default)]
+    pub post: Option<String>,
+    /// How long each command may run before it is killed and counts as failed. Default: 60s.
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub on_failure: HookFailurePolicy,
+}
+
+impl SnapshotHooks {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT)
+    }
+}
+
+/// What happens to the snapshot when the pre-snapshot command fails. A failed post-snapshot command never affects
+/// the snapshot, it has already been taken.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum HookFailurePolicy {
+    /// Skip the snapshot and report it as failed.
+    Abort,
+    /// Take the snapshot anyway and report the failed command.
+    Continue,
+}
+
+impl Default for HookFailurePolicy {
+    fn default() -> Self {
+        Self::Abort
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DefragConfig {
+    pub schedule: ScheduleModel,
+    /// Files or directories relative to the dataset root. Empty defragments the whole dataset.
+    #[serde(default)]
+    pub paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub compress: Option<CompressionAlgorithm>,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// Cap on the bytes referenced by a dataset's quota group, which holds the dataset and all its local snapshots.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotQuota {
+    pub max_referenced: u64,
+    pub action: SnapshotQuotaAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum SnapshotQuotaAction {
+    /// Report the overage as a failed observation.
+    Alert,
+    /// Evaluate the retention rules right away, then report if the dataset is still over quota.
+    Prune,
+}
+
+impl SnapshotQuota {
+    /// Describe how the referenced bytes exceed the quota, if they do.
+    pub fn exceeded(&self, referenced: u64) -> Option<String> {
+        if referenced > self.max_referenced {
+            Some(format!(
+                "{} bytes referenced (quota {})",
+                referenced, self.max_referenced
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Limits on the snapshots of a dataset that haven't reached every sync target yet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SyncBacklogLimit {
+    pub max_snapshots: Option<usize>,
+    /// Bytes written to the dataset since the newest snapshot every sync target has.
+    pub max_bytes: Option<u64>,
+    pub action: SyncBacklogAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncBacklogAction {
+    /// Keep snapshotting, but report the backlog as a failed observation.
+    Alert,
+    /// Only take a scheduled snapshot once this long has passed since the latest one.
+    Slow(#[serde(with = "humantime_serde")] Duration),
+    /// Skip scheduled snapshots until the backlog drains.
+    Pause,
+}
+
+impl FromStr for SyncBacklogAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.splitn(2, ':').collect::<Vec<_>>().as_slice() {
+            ["alert"] => Ok(Self::Alert),
+            ["pause"] => Ok(Self::Pause),
+            ["slow", interval] => Ok(Self::Slow(*interval.parse::<humantime::Duration>()?)),
+            _ => bail!("expected alert, pause or slow:<interval>"),
+        }
+    }
+}
+
+impl std::fmt::Display for SyncBacklogAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Alert => write!(f, "alert"),
+            Self::Pause => write!(f, "pause"),
+            Self::Slow(interval) => write!(f, "slow:{}", humantime::format_duration(*interval)),
+        }
+    }
+}
+
+impl SyncBacklogLimit {
+    /// Describe how the backlog exceeds the limit, if it does. An unknown size never exceeds `max_bytes`.
+    pub fn exceeded(&self, snapshots: usize, bytes: Option<u64>) -> Option<String> {
+        let mut reasons = Vec::new();
+        if let Some(max_snapshots) = self.max_snapshots {
+            if snapshots > max_snapshots {
+                reasons.push(format!("{} unsynced snapshots (limit {})", snapshots, max_snapshots));
+            }
+        }
+        if let (Some(max_bytes), Some(bytes)) = (self.max_bytes, bytes) {
+            if bytes > max_bytes {
+                reasons.push(format!("{} unsynced bytes (limit {})", bytes, max_bytes));
+            }
+        }
+        if reasons.is_empty() {
+            None
+        } else {
+            Some(reasons.join(", "))
+        }
+    }
+}
+
+/// The point where a restored dataset's history split from the snapshots taken before the restore.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RestoreDivergence {
+    pub source_snapshot: Uuid,
+    pub diverged_at: Option<DateTime<Utc>>,
 }
 
 impl SubvolumeEntity for BtrfsDatasetEntity {
@@ -153,6 +506,9 @@ impl SubvolumeEntity for BtrfsDatasetEntity {
     fn uuid(&self) -> &Uuid {
         &self.uuid
     }
+    fn qgroup(&self) -> Option<QGroupId> {
+        self.qgroup
+    }
 }
 
 impl Entity for BtrfsDatasetEntity {
@@ -263,9 +619,31 @@ impl BtrfsDatasetEntity {
             snapshot_retention: None,
             pause_pruning: false,
             pause_snapshotting: false,
+            properties: Default::default(),
+            restore_divergence: None,
+            target_rpo: None,
+            skip_unchanged: false,
+            snapshot_after_wake: None,
+            emergency_prune: false,
+            critical: false,
+            sync_backlog: None,
+            qgroup: None,
+            snapshot_quota: None,
+            defrag: None,
+            held_snapshots: Vec::new(),
+            snapshot_hooks: None,
+            pre_upgrade_snapshots: Vec::new(),
+            pre_upgrade_retention: Default::default(),
+            boot_menu: None,
         })
     }
 
+    /// Keep the id the dataset had before its config was lost. Its snapshots are stored under it.
+    pub fn with_id(mut self, id: EntityId) -> Self {
+        self.id = id;
+        self
+    }
+
     pub fn snapshotting_state(&self) -> FeatureState {
         if self.snapshot_schedule.is_some() {
             if self.pause_snapshotting {
@@ -289,6 +667,41 @@ impl BtrfsDatasetEntity {
             FeatureState::Unconfigured
         }
     }
+
+    pub fn defrag_state(&self) -> FeatureState {
+        match &self.defrag {
+            Some(defrag) if defrag.paused => FeatureState::Paused,
+            Some(_) => FeatureState::Enabled,
+            None => FeatureState::Unconfigured,
+        }
+    }
+}
+
+/// Snapshot policies a pool hands to datasets when they are attached or created, before any explicit options.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DatasetDefaults {
+    pub snapshot_schedule: Option<ScheduleModel>,
+    pub snapshot_retention: Option<RetentionRuleset>,
+    #[serde(default, with = "humantime_serde")]
+    pub target_rpo: Option<Duration>,
+}
+
+impl DatasetDefaults {
+    pub fn is_empty(&self) -> bool {
+        self.snapshot_schedule.is_none() && self.snapshot_retention.is_none() && self.target_rpo.is_none()
+    }
+
+    pub fn apply(&self, dataset: &mut BtrfsDatasetEntity) {
+        if self.snapshot_schedule.is_some() {
+            dataset.snapshot_schedule = self.snapshot_schedule.clone();
+        }
+        if self.snapshot_retention.is_some() {
+            dataset.snapshot_retention = self.snapshot_retention.clone();
+        }
+        if self.target_rpo.is_some() {
+            dataset.target_rpo = self.target_rpo;
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -301,6 +714,11 @@ pub struct BtrfsContainerEntity {
     pub uuid: Uuid,
     pub snapshot_retention: Option<RetentionRuleset>,
     pub pause_pruning: bool,
+    #[serde(default)]
+    pub qgroup: Option<QGroupId>,
+    /// Keep a checksum manifest of every received snapshot and check the snapshots against them on this schedule.
+    #[serde(default)]
+    pub verify_schedule: Option<ScheduleModel>,
 }
 
 impl BtrfsContainerEntity {
@@ -313,6 +731,8 @@ impl BtrfsContainerEntity {
             uuid: subvolume_uuid,
             snapshot_retention: None,
             pause_pruning: false,
+            qgroup: None,
+            verify_schedule: None,
         })
     }
 
@@ -340,6 +760,9 @@ impl SubvolumeEntity for BtrfsContainerEntity {
     fn uuid(&self) -> &Uuid {
         &self.uuid
     }
+    fn qgroup(&self) -> Option<QGroupId> {
+        self.qgroup
+    }
 }
 
 impl Entity for BtrfsContainerEntity {
@@ -367,6 +790,18 @@ pub struct SnapshotSyncEntity {
     pub dataset_id: EntityId,
     pub container_id: EntityId,
     pub sync_mode: SnapshotSyncMode,
+    #[serde(default, with = "humantime_serde")]
+    pub progress_interval: Option<Duration>,
+    /// Compress the send stream on its way to a remote or archive container, worth it when the link is slower than the
+    /// compressor. Streams to local btrfs containers are never compressed.
+    #[serde(default)]
+    pub compression: Option<StreamCompression>,
+    /// Most bytes per second the send stream may use, so a sync doesn't saturate a slow bus or link.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+    /// When to check that the latest snapshot in the container can be restored. Only btrfs containers.
+    #[serde(default)]
+    pub restore_test_schedule: Option<ScheduleModel>,
 }
 
 impl<'a> AsRef<dyn Entity + 'a> for SnapshotSyncEntity {
@@ -406,6 +841,10 @@ impl SnapshotSyncEntity {
             dataset_id,
             container_id,
             sync_mode: SnapshotSyncMode::AllImmediate,
+            progress_interval: None,
+            compression: None,
+            bandwidth_limit: None,
+            restore_test_schedule: None,
         }
     }
 }
@@ -433,6 +872,9 @@ pub struct RetentionRuleset {
     pub interval: Vec<IntervalSpec>,
     pub newest_count: NonZeroU32,
     pub evaluation_schedule: ScheduleModel,
+    /// Move pruned snapshots to the pool's trash for this long before deleting them.
+    #[serde(default, with = "humantime_serde")]
+    pub trash_period: Option<Duration>,
 }
 
 impl Default for RetentionRuleset {
@@ -442,6 +884,7 @@ impl Default for RetentionRuleset {
             newest_count: NonZeroU32::new(1).expect("nonzero valid constant"),
             evaluation_schedule: ScheduleModel::try_from(Duration::from_secs(3600 * 24))
                 .expect("schedulemodel valid constant"),
+            trash_period: None,
         }
     }
 }
@@ -548,13 +991,206 @@ impl<'a> AsRef<dyn Entity + 'a> for HealthchecksObserverEntity {
     }
 }
 
+/// An observer that sends a message to a push notification service, webhook or monitoring system, instead of pinging
+/// healthchecks.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationObserverEntity {
+    id: EntityId,
+    name: String,
+    pub service: NotificationService,
+    pub observations: Vec<Observation>,
+    #[serde(default)]
+    pub notify_on: NotifyOn,
+    /// Template for the message title. Default: [`NotificationObserverEntity::DEFAULT_TITLE_TEMPLATE`].
+    #[serde(default)]
+    pub title_template: Option<String>,
+    /// Template for the message body. Default: [`NotificationObserverEntity::DEFAULT_MESSAGE_TEMPLATE`].
+    #[serde(default)]
+    pub message_template: Option<String>,
+    /// Collect successes and send them as one summary on this schedule. Failures are still sent right away.
+    #[serde(default)]
+    pub digest_schedule: Option<ScheduleModel>,
+}
+
+impl NotificationObserverEntity {
+    pub const DEFAULT_TITLE_TEMPLATE: &'static str = "{event} {result} on {host}";
+    pub const DEFAULT_MESSAGE_TEMPLATE: &'static str = "{event} for {entity} {result}.\n{error}";
+
+    pub fn new(name: String, service: NotificationService, observations: Vec<Observation>) -> Self {
+        Self {
+            id: EntityId::new(),
+            name,
+            service,
+            observations,
+            notify_on: Default::default(),
+            title_template: None,
+            message_template: None,
+            digest_schedule: None,
+        }
+    }
+
+    pub fn title_template(&self) -> &str {
+        self.title_template.as_deref().unwrap_or(Self::DEFAULT_TITLE_TEMPLATE)
+    }
+
+    pub fn message_template(&self) -> &str {
+        self.message_template
+            .as_deref()
+            .unwrap_or(Self::DEFAULT_MESSAGE_TEMPLATE)
+    }
+}
+
+impl Entity for NotificationObserverEntity {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn id(&self) -> EntityId {
+        self.id
+    }
+    fn entity_type(&self) -> EntityType {
+        EntityType::Observer
+    }
+}
+
+impl EntityStatic for NotificationObserverEntity {
+    fn entity_type_static() -> EntityType {
+        EntityType::Observer
+    }
+}
+
+impl<'a> AsRef<dyn Entity + 'a> for NotificationObserverEntity {
+    fn as_ref(&self) -> &(dyn Entity + 'a) {
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationService {
+    Telegram {
+        bot_token: String,
+        chat_id: String,
+    },
+    Ntfy {
+        /// Self-hosted ntfy server. Default: https://ntfy.sh
+        server: Option<String>,
+        topic: String,
+        access_token: Option<String>,
+    },
+    Gotify {
+        server: String,
+        app_token: String,
+    },
+    /// POSTs a JSON payload describing the event, for Slack, Discord and other tooling.
+    Webhook {
+        url: String,
+        /// Sent as a bearer token.
+        token: Option<String>,
+    },
+    /// Submits passive check results through NRDP, to a service named "blkcapt <event> <entity>" of the host.
+    Nrdp {
+        url: String,
+        token: String,
+        /// Default: this machine's hostname.
+        hostname: Option<String>,
+    },
+    /// Sends SNMPv2c traps, described by debian/BLOCKCAPTAIN-MIB.txt.
+    SnmpTrap {
+        /// Manager as host or host:port.
+        target: String,
+        community: String,
+    },
+    /// Pushes to an Uptime Kuma push monitor, up on success and down on failure.
+    UptimeKuma {
+        /// The monitor's push URL. Query parameters shown with it by Uptime Kuma are dropped.
+        push_url: String,
+    },
+    /// Sends zabbix_sender values to the trapper item `blkcapt.<event>["<entity>"]` of the host, 0 on success and 2 on
+    /// failure.
+    Zabbix {
+        /// Server or proxy as host or host:port.
+        server: String,
+        /// Default: this machine's hostname.
+        hostname: Option<String>,
+    },
+}
+
+impl NotificationService {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NotificationService::Telegram { .. } => "telegram",
+            NotificationService::Ntfy { .. } => "ntfy",
+            NotificationService::Gotify { .. } => "gotify",
+            NotificationService::Webhook { .. } => "webhook",
+            NotificationService::Nrdp { .. } => "nrdp",
+            NotificationService::SnmpTrap { .. } => "snmp_trap",
+            NotificationService::UptimeKuma { .. } => "uptime_kuma",
+            NotificationService::Zabbix { .. } => "zabbix",
+        }
+    }
+
+    /// The credential the service is reached with, if it takes one.
+    pub fn secret_mut(&mut self) -> Option<&mut String> {
+        match self {
+            NotificationService::Telegram { bot_token, .. } => Some(bot_token),
+            NotificationService::Ntfy { access_token, .. } => access_token.as_mut(),
+            NotificationService::Gotify { app_token, .. } => Some(app_token),
+            NotificationService::Webhook { token, .. } => token.as_mut(),
+            NotificationService::Nrdp { token, .. } => Some(token),
+            NotificationService::SnmpTrap { community, .. } => Some(community),
+            // the push URL carries the monitor's token
+            NotificationService::UptimeKuma { push_url } => Some(push_url),
+            NotificationService::Zabbix { .. } => None,
+        }
+    }
+
+    /// Monitoring systems that expect a result for every run, so they can clear a failure again.
+    pub fn is_passive_check(&self) -> bool {
+        matches!(
+            self,
+            NotificationService::Nrdp { .. }
+                | NotificationService::UptimeKuma { .. }
+                | NotificationService::Zabbix { .. }
+        )
+    }
+
+    /// Services that show free-form messages, so they can take a digest of several events.
+    pub fn takes_digest(&self) -> bool {
+        matches!(
+            self,
+            NotificationService::Telegram { .. }
+                | NotificationService::Ntfy { .. }
+                | NotificationService::Gotify { .. }
+                | NotificationService::Webhook { .. }
+        )
+    }
+}
+
+/// Which stages of an observed event send a notification.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum NotifyOn {
+    Failure,
+    /// Success and failure.
+    Completion,
+    /// Starting, success and failure.
+    All,
+}
+
+impl Default for NotifyOn {
+    fn default() -> Self {
+        Self::Failure
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Observation {
     pub entity_id: EntityId,
     pub event: ObservableEvent,
 }
 
-#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, EnumString, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, EnumString, PartialEq, Eq, Hash)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
 pub enum ObservableEvent {
@@ -562,7 +1198,30 @@ pub enum ObservableEvent {
     DatasetPrune,
     ContainerPrune,
     SnapshotSync,
+    SnapshotSyncRpo,
     PoolScrub,
+    PoolTrim,
+    /// Snapshots were created or deleted by something other than blkcapt.
+    DatasetExternalChange,
+    /// The dataset's unsynced snapshots were checked against its backlog limit.
+    DatasetSyncBacklog,
+    /// The space referenced by the dataset and its snapshots was checked against its quota.
+    DatasetQuota,
+    DatasetDefrag,
+    /// The dataset's pre- and post-snapshot hook commands ran around a snapshot.
+    DatasetSnapshotHook,
+    ContainerExternalChange,
+    /// Received snapshots were checked against their source lineage and checksum manifests.
+    ContainerVerify,
+    /// The container's latest snapshot from the dataset was received into scratch space and read back.
+    SnapshotSyncRestoreTest,
+    /// A running transfer reached a progress checkpoint. Fails when nothing was sent since the one before.
+    SnapshotSyncProgress,
+    /// The worker for the entity started, or failed to start or stopped on a fault.
+    PoolWorker,
+    DatasetWorker,
+    ContainerWorker,
+    SnapshotSyncWorker,
 }
 
 impl ObservableEvent {
@@ -572,7 +1231,22 @@ impl ObservableEvent {
             ObservableEvent::DatasetPrune => EntityType::Dataset,
             ObservableEvent::ContainerPrune => EntityType::Container,
             ObservableEvent::SnapshotSync => EntityType::SnapshotSync,
+            ObservableEvent::SnapshotSyncRpo => EntityType::SnapshotSync,
             ObservableEvent::PoolScrub => EntityType::Pool,
+            ObservableEvent::PoolTrim => EntityType::Pool,
+            ObservableEvent::DatasetExternalChange => EntityType::Dataset,
+            ObservableEvent::DatasetSyncBacklog => EntityType::Dataset,
+            ObservableEvent::DatasetQuota => EntityType::Dataset,
+            ObservableEvent::DatasetDefrag => EntityType::Dataset,
+            ObservableEvent::DatasetSnapshotHook => EntityType::Dataset,
+            ObservableEvent::ContainerExternalChange => EntityType::Container,
+            ObservableEvent::ContainerVerify => EntityType::Container,
+            ObservableEvent::SnapshotSyncRestoreTest => EntityType::SnapshotSync,
+            ObservableEvent::SnapshotSyncProgress => EntityType::SnapshotSync,
+            ObservableEvent::PoolWorker => EntityType::Pool,
+            ObservableEvent::DatasetWorker => EntityType::Dataset,
+            ObservableEvent::ContainerWorker => EntityType::Container,
+            ObservableEvent::SnapshotSyncWorker => EntityType::SnapshotSync,
         }
     }
 }
@@ -587,6 +1261,8 @@ pub struct ResticContainerEntity {
     pub custom_environment: HashMap<String, String>,
     pub snapshot_retention: Option<RetentionRuleset>,
     pub pause_pruning: bool,
+    #[serde(default)]
+    pub host: Option<RemoteHost>,
 }
 
 impl ResticContainerEntity {
@@ -612,8 +1288,56 @@ impl ResticContainerEntity {
             custom_environment: Default::default(),
             snapshot_retention: None,
             pause_pruning: false,
+            host: None,
+        }
+    }
+}
+
+/// The machine serving a remote repository, probed at `address` (a `host:port`) before it is used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemoteHost {
+    pub address: String,
+    #[serde(default)]
+    pub wake_on_lan: Option<WakeOnLan>,
+}
+
+impl RemoteHost {
+    pub fn new(address: String) -> Self {
+        Self {
+            address,
+            wake_on_lan: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WakeOnLan {
+    pub mac_address: MacAddress,
+    #[serde(default)]
+    pub broadcast: Option<SocketAddr>,
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+}
+
+impl WakeOnLan {
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+    pub fn new(mac_address: MacAddress) -> Self {
+        Self {
+            mac_address,
+            broadcast: None,
+            timeout: None,
         }
     }
+
+    pub fn broadcast(&self) -> SocketAddr {
+        self.broadcast
+            .unwrap_or_else(|| SocketAddr::from(([255, 255, 255, 255], 9)))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(Self::DEFAULT_TIMEOUT)
+    }
 }
 
 impl Entity for ResticContainerEntity {
@@ -645,3 +1369,270 @@ impl<'a> AsRef<dyn Entity + 'a> for ResticContainerEntity {
 pub enum ResticRepository {
     Custom(String),
 }
+
+// ## Remote #######################################################################################################
+
+/// A btrfs filesystem on another machine, receiving snapshots into `path` over ssh.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemoteContainerEntity {
+    id: EntityId,
+    name: String,
+    pub ssh: SshTarget,
+    /// Directory on the remote btrfs filesystem that holds a subvolume of received snapshots per source dataset.
+    pub path: PathBuf,
+    /// Pruned snapshots are deleted on the remote right away, the trash period doesn't apply.
+    #[serde(default)]
+    pub snapshot_retention: Option<RetentionRuleset>,
+    #[serde(default)]
+    pub pause_pruning: bool,
+}
+
+impl RemoteContainerEntity {
+    pub fn new(name: String, ssh: SshTarget, path: PathBuf) -> Self {
+        Self {
+            id: EntityId::new(),
+            name,
+            ssh,
+            path,
+            snapshot_retention: None,
+            pause_pruning: false,
+        }
+    }
+
+    pub fn pruning_state(&self) -> FeatureState {
+        if self.snapshot_retention.is_some() {
+            if self.pause_pruning {
+                FeatureState::Paused
+            } else {
+                FeatureState::Enabled
+            }
+        } else {
+            FeatureState::Unconfigured
+        }
+    }
+}
+
+/// How to log in to a remote machine. The host key must already be known, ssh runs without prompting.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SshTarget {
+    pub host: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+}
+
+impl SshTarget {
+    pub fn new(host: String) -> Self {
+        Self {
+            host,
+            user: None,
+            port: None,
+            identity_file: None,
+        }
+    }
+
+    /// The destination as ssh takes it, `user@host` or `host`.
+    pub fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+}
+
+impl Entity for RemoteContainerEntity {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn id(&self) -> EntityId {
+        self.id
+    }
+    fn entity_type(&self) -> EntityType {
+        EntityType::Container
+    }
+}
+
+impl EntityStatic for RemoteContainerEntity {
+    fn entity_type_static() -> EntityType {
+        EntityType::Container
+    }
+}
+
+impl<'a> AsRef<dyn Entity + 'a> for RemoteContainerEntity {
+    fn as_ref(&self) -> &(dyn Entity + 'a) {
+        self
+    }
+}
+
+// ## Archive ######################################################################################################
+
+/// A container keeping snapshots as btrfs send streams in object storage, split into parts. A manifest stored next to
+/// the parts records how to put the stream back together so it can be received again on restore.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveContainerEntity {
+    id: EntityId,
+    name: String,
+    pub backend: ArchiveBackend,
+    /// Size of the parts a send stream is split into. Default: 64 MiB.
+    #[serde(default)]
+    pub part_size: Option<u64>,
+    /// Storage class stream parts are uploaded in, e.g. STANDARD_IA or GLACIER. Parts in GLACIER or DEEP_ARCHIVE have
+    /// to be restored before a dataset can be restored from them. Default: the bucket's default.
+    #[serde(default)]
+    pub storage_class: Option<String>,
+    /// Parts uploaded at once. Each holds a part in memory, so this times the part size is the memory an upload
+    /// uses. Default: 4.
+    #[serde(default)]
+    pub upload_parallelism: Option<usize>,
+    /// Times a failed part upload is retried before the upload fails. Default: 3, at most 10.
+    #[serde(default)]
+    pub upload_retries: Option<u32>,
+    #[serde(default)]
+    pub encryption: Option<ArchiveEncryption>,
+    /// Streams retention expires are deleted once no retained stream depends on them. The trash period does not
+    /// apply, deleted streams are gone from the store.
+    #[serde(default)]
+    pub snapshot_retention: Option<RetentionRuleset>,
+    #[serde(default)]
+    pub pause_pruning: bool,
+    #[serde(default)]
+    pub pricing: Option<ArchivePricing>,
+}
+
+/// What the storage service charges, for rough cost estimates. Nothing is limited by them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArchivePricing {
+    /// Price of storing a GB (10^9 bytes) for a month.
+    pub storage_per_gb_month: f64,
+    /// Price of downloading a GB out of the service.
+    #[serde(default)]
+    pub egress_per_gb: f64,
+}
+
+impl ArchivePricing {
+    const GB: f64 = 1e9;
+
+    pub fn storage_cost(&self, bytes: u64) -> f64 {
+        bytes as f64 / Self::GB * self.storage_per_gb_month
+    }
+
+    pub fn egress_cost(&self, bytes: u64) -> f64 {
+        bytes as f64 / Self::GB * self.egress_per_gb
+    }
+}
+
+impl ArchiveContainerEntity {
+    pub const DEFAULT_PART_SIZE: u64 = 64 * 1024 * 1024;
+    pub const DEFAULT_UPLOAD_PARALLELISM: usize = 4;
+    pub const DEFAULT_UPLOAD_RETRIES: u32 = 3;
+    pub const MAX_UPLOAD_RETRIES: u32 = 10;
+
+    pub fn new(name: String, backend: ArchiveBackend) -> Self {
+        Self {
+            id: EntityId::new(),
+            name,
+            backend,
+            part_size: None,
+            storage_class: None,
+            upload_parallelism: None,
+            upload_retries: None,
+            encryption: None,
+            snapshot_retention: None,
+            pause_pruning: false,
+            pricing: None,
+        }
+    }
+
+    pub fn part_size(&self) -> u64 {
+        self.part_size.unwrap_or(Self::DEFAULT_PART_SIZE)
+    }
+
+    pub fn upload_parallelism(&self) -> usize {
+        self.upload_parallelism.unwrap_or(Self::DEFAULT_UPLOAD_PARALLELISM)
+    }
+
+    pub fn upload_retries(&self) -> u32 {
+        self.upload_retries.unwrap_or(Self::DEFAULT_UPLOAD_RETRIES)
+    }
+
+    pub fn pruning_state(&self) -> FeatureState {
+        if self.snapshot_retention.is_some() {
+            if self.pause_pruning {
+                FeatureState::Paused
+            } else {
+                FeatureState::Enabled
+            }
+        } else {
+            FeatureState::Unconfigured
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveBackend {
+    S3(S3Backend),
+    Rclone(RcloneBackend),
+}
+
+/// A bucket in S3 compatible object storage, such as AWS S3, Backblaze B2 or MinIO.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct S3Backend {
+    /// Base URL of the service, e.g. https://s3.us-west-002.backblazeb2.com. Buckets are addressed path style.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Key prefix for everything the container stores, so a bucket can be shared.
+    #[serde(default)]
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+/// A path on a remote configured in rclone, for storage blkcapt has no native backend for.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RcloneBackend {
+    /// Remote and path everything the container stores goes under, e.g. gdrive:backups/nas.
+    pub remote: String,
+    /// rclone config file the remote is defined in, rclone's default is used when unset.
+    #[serde(default)]
+    pub config: Option<PathBuf>,
+}
+
+/// Streams are encrypted with age before they are uploaded, so the store only ever holds ciphertext.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveEncryption {
+    /// Age recipients (public keys or ssh public keys) streams are encrypted for. Never empty.
+    pub recipients: Vec<String>,
+    /// Age identity file to decrypt streams with on restore. The private key is often kept off the machine, so it
+    /// can also be given when restoring.
+    #[serde(default)]
+    pub identity_file: Option<PathBuf>,
+}
+
+impl Entity for ArchiveContainerEntity {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn id(&self) -> EntityId {
+        self.id
+    }
+    fn entity_type(&self) -> EntityType {
+        EntityType::Container
+    }
+}
+
+impl EntityStatic for ArchiveContainerEntity {
+    fn entity_type_static() -> EntityType {
+        EntityType::Container
+    }
+}
+
+impl<'a> AsRef<dyn Entity + 'a> for ArchiveContainerEntity {
+    fn as_ref(&self) -> &(dyn Entity + 'a) {
+        self
+    }
+}
@@ -0,0 +1,215 @@
+use super::{entities::ObservableEvent, EntityId};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+use strum_macros::Display;
+
+/// Number of recent transfers considered when estimating throughput.
+const THROUGHPUT_SAMPLE_COUNT: usize = 10;
+/// Transfers smaller than this are dominated by setup time and skew throughput estimates.
+const THROUGHPUT_MIN_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, PartialEq, Eq, Hash)]
+#[strum(serialize_all = "snake_case")]
+pub enum JobKind {
+    Transfer,
+    DatasetSnapshot,
+    DatasetPrune,
+    ContainerPrune,
+    SnapshotSync,
+    PoolScrub,
+    DatasetDefrag,
+    PoolTrim,
+    ContainerVerify,
+    RestoreTest,
+}
+
+impl JobKind {
+    /// The job kind recorded for an observable event. Events that aren't jobs, like compliance checks, have none.
+    pub fn from_event(event: ObservableEvent) -> Option<Self> {
+        match event {
+            ObservableEvent::DatasetSnapshot => Some(JobKind::DatasetSnapshot),
+            ObservableEvent::DatasetPrune => Some(JobKind::DatasetPrune),
+            ObservableEvent::ContainerPrune => Some(JobKind::ContainerPrune),
+            ObservableEvent::SnapshotSync => Some(JobKind::SnapshotSync),
+            ObservableEvent::PoolScrub => Some(JobKind::PoolScrub),
+            ObservableEvent::PoolTrim => Some(JobKind::PoolTrim),
+            ObservableEvent::SnapshotSyncRpo => None,
+            ObservableEvent::DatasetExternalChange => None,
+            ObservableEvent::DatasetSyncBacklog => None,
+            ObservableEvent::DatasetQuota => None,
+            ObservableEvent::DatasetDefrag => Some(JobKind::DatasetDefrag),
+            ObservableEvent::DatasetSnapshotHook => None,
+            ObservableEvent::ContainerExternalChange => None,
+            ObservableEvent::ContainerVerify => Some(JobKind::ContainerVerify),
+            ObservableEvent::SnapshotSyncRestoreTest => Some(JobKind::RestoreTest),
+            ObservableEvent::SnapshotSyncProgress => None,
+            ObservableEvent::PoolWorker => None,
+            ObservableEvent::DatasetWorker => None,
+            ObservableEvent::ContainerWorker => None,
+            ObservableEvent::SnapshotSyncWorker => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct JobRecord {
+    pub kind: JobKind,
+    pub entity_id: EntityId,
+    pub started: DateTime<Utc>,
+    pub duration: Duration,
+    pub bytes: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl JobRecord {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct JobSummary {
+    pub kind: JobKind,
+    pub runs: usize,
+    pub failures: usize,
+    pub average_duration: Duration,
+    /// Distinct failure messages with their counts, most frequent first.
+    pub failure_causes: Vec<(String, usize)>,
+}
+
+impl JobSummary {
+    pub fn success_rate(&self) -> f64 {
+        (self.runs - self.failures) as f64 / self.runs as f64
+    }
+}
+
+/// Summarize jobs started at or after since, grouped by job kind.
+pub fn summarize(history: &[JobRecord], since: DateTime<Utc>) -> Vec<JobSummary> {
+    let mut groups = HashMap::<JobKind, Vec<&JobRecord>>::new();
+    for record in history.iter().filter(|r| r.started >= since) {
+        groups.entry(record.kind).or_default().push(record);
+    }
+
+    let mut summaries = groups
+        .into_iter()
+        .map(|(kind, records)| {
+            let mut causes = HashMap::<String, usize>::new();
+            for error in records.iter().filter_map(|r| r.error.as_ref()) {
+                let cause = error.lines().next().unwrap_or_default().to_owned();
+                *causes.entry(cause).or_default() += 1;
+            }
+            let mut failure_causes = causes.into_iter().collect::<Vec<_>>();
+            failure_causes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+            let total_duration = records.iter().map(|r| r.duration).sum::<Duration>();
+            JobSummary {
+                kind,
+                runs: records.len(),
+                failures: records.iter().filter(|r| !r.succeeded()).count(),
+                average_duration: total_duration / records.len() as u32,
+                failure_causes,
+            }
+        })
+        .collect::<Vec<_>>();
+    summaries.sort_by_key(|s| s.kind.to_string());
+    summaries
+}
+
+/// Average bytes per second over the most recent successful transfers.
+pub fn transfer_throughput(history: &[JobRecord]) -> Option<f64> {
+    let (bytes, seconds) = history
+        .iter()
+        .rev()
+        .filter(|r| r.kind == JobKind::Transfer && r.succeeded())
+        .filter_map(|r| r.bytes.filter(|b| *b >= THROUGHPUT_MIN_BYTES).map(|b| (b, r.duration)))
+        .take(THROUGHPUT_SAMPLE_COUNT)
+        .fold((0u64, 0f64), |(bytes, seconds), (b, d)| {
+            (bytes + b, seconds + d.as_secs_f64())
+        });
+
+    if seconds > 0.0 {
+        Some(bytes as f64 / seconds)
+    } else {
+        None
+    }
+}
+
+pub fn estimate_transfer_duration(size: u64, history: &[JobRecord]) -> Option<Duration> {
+    transfer_throughput(history).map(|rate| Duration::from_secs_f64(size as f64 / rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(bytes: u64, seconds: u64, error: Option<&str>) -> JobRecord {
+        JobRecord {
+            kind: JobKind::Transfer,
+            entity_id: EntityId::default(),
+            started: Utc::now(),
+            duration: Duration::from_secs(seconds),
+            bytes: Some(bytes),
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn estimate_uses_successful_large_transfers() {
+        let gib = 1024 * 1024 * 1024;
+        let history = vec![
+            transfer(gib, 8, None),
+            transfer(gib, 1000, Some("failed")),
+            transfer(1024, 100, None),
+            transfer(3 * gib, 24, None),
+        ];
+
+        assert_eq!(
+            estimate_transfer_duration(10 * gib, &history),
+            Some(Duration::from_secs(80))
+        );
+    }
+
+    #[test]
+    fn summarize_groups_by_kind_and_cause() {
+        let now = Utc::now();
+        let mut old = transfer(1024, 5, Some("ignored"));
+        old.started = now - chrono::Duration::days(2);
+        let mut snapshot = transfer(0, 4, None);
+        snapshot.kind = JobKind::DatasetSnapshot;
+        let history = vec![
+            old,
+            transfer(1024, 10, None),
+            transfer(1024, 20, Some("receive failed\ncause 1: disk full")),
+            transfer(1024, 30, Some("receive failed")),
+            transfer(1024, 40, Some("cancelled")),
+            snapshot,
+        ];
+
+        let summaries = summarize(&history, now - chrono::Duration::days(1));
+        assert_eq!(
+            summaries,
+            vec![
+                JobSummary {
+                    kind: JobKind::DatasetSnapshot,
+                    runs: 1,
+                    failures: 0,
+                    average_duration: Duration::from_secs(4),
+                    failure_causes: vec![],
+                },
+                JobSummary {
+                    kind: JobKind::Transfer,
+                    runs: 4,
+                    failures: 3,
+                    average_duration: Duration::from_secs(25),
+                    failure_causes: vec![(String::from("receive failed"), 2), (String::from("cancelled"), 1)],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn estimate_without_history() {
+        assert_eq!(estimate_transfer_duration(1024, &[]), None);
+    }
+}
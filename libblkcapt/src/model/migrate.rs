@@ -0,0 +1,105 @@
+use super::Entities;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+
+/// Layout of the entity config written by this build. Bump it and append a migration to MIGRATIONS whenever a
+/// change to the entity structs can't read what the previous version wrote.
+pub const ENTITY_SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades version n to n + 1. Configs written before versioning are version 0.
+const MIGRATIONS: [Migration; ENTITY_SCHEMA_VERSION as usize] = [unversioned_to_v1];
+
+/// Every field added before versioning has a serde default, so these configs read as they are.
+fn unversioned_to_v1(_entities: &mut Map<String, Value>) -> Result<()> {
+    Ok(())
+}
+
+/// Upgrade a stored entity config to the current layout in place. Returns the version it was stored with. Configs
+/// from a newer build are refused rather than read with their new fields dropped.
+pub fn migrate_entities(state: &mut Value) -> Result<u32> {
+    let entities = state.as_object_mut().context("entity config is not a json object")?;
+    let stored_version = match entities.remove(SCHEMA_VERSION_KEY) {
+        Some(version) => version
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .context("entity config has an invalid schema version")?,
+        None => 0,
+    };
+    if stored_version > ENTITY_SCHEMA_VERSION {
+        bail!(
+            "entity config schema version {} is newer than supported version {}, it was written by a newer blkcapt",
+            stored_version,
+            ENTITY_SCHEMA_VERSION
+        );
+    }
+
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(stored_version as usize) {
+        migration(entities).with_context(|| format!("failed to migrate entity config from version {}", version))?;
+    }
+    Ok(stored_version)
+}
+
+/// The entity config as it is stored, with its schema version. Exports in other formats have the same shape.
+#[derive(Serialize, Deserialize)]
+pub struct EntityConfig(#[serde(with = "versioned")] pub Entities);
+
+/// Serialize the entity config with the current schema version, and migrate it on the way in. For use with
+/// `#[serde(with)]`.
+pub mod versioned {
+    use super::*;
+    use serde::de::Error;
+
+    #[derive(Serialize)]
+    struct VersionedEntities<'a> {
+        schema_version: u32,
+        #[serde(flatten)]
+        entities: &'a Entities,
+    }
+
+    pub fn serialize<S: Serializer>(entities: &Entities, serializer: S) -> Result<S::Ok, S::Error> {
+        VersionedEntities {
+            schema_version: ENTITY_SCHEMA_VERSION,
+            entities,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Entities, D::Error> {
+        let mut state = Value::deserialize(deserializer)?;
+        migrate_entities(&mut state).map_err(|e| D::Error::custom(format!("{:#}", e)))?;
+        serde_json::from_value(state).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn unversioned_config_is_migrated() {
+        let mut state = json!({ "btrfs_pools": [], "snapshot_syncs": [], "observers": [], "restic_containers": [] });
+        assert_eq!(migrate_entities(&mut state).unwrap(), 0);
+        assert!(state.get(SCHEMA_VERSION_KEY).is_none());
+        assert!(serde_json::from_value::<Entities>(state).is_ok());
+    }
+
+    #[test]
+    fn current_config_is_unchanged() {
+        let mut state = serde_json::to_value(EntityConfig(Entities::default())).unwrap();
+        assert_eq!(state[SCHEMA_VERSION_KEY], json!(ENTITY_SCHEMA_VERSION));
+        assert_eq!(migrate_entities(&mut state).unwrap(), ENTITY_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn newer_config_is_refused() {
+        let mut state = json!({ "schema_version": ENTITY_SCHEMA_VERSION + 1, "btrfs_pools": [] });
+        assert!(migrate_entities(&mut state).is_err());
+    }
+}
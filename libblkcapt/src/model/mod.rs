@@ -1,20 +1,32 @@
+pub mod access;
+pub mod audit;
+pub mod digest;
 pub mod entities;
+pub mod history;
+pub mod migrate;
+pub mod recovery;
 pub mod storage;
+pub mod worker;
 
 use crate::parsing::parse_uuid;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use entities::{
-    BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity, HealthchecksObserverEntity, ResticContainerEntity,
+    ArchiveBackend, ArchiveContainerEntity, BtrfsContainerEntity, BtrfsDatasetEntity, BtrfsPoolEntity,
+    HealthchecksObserverEntity, NotificationObserverEntity, RemoteContainerEntity, ResticContainerEntity,
     SnapshotSyncEntity,
 };
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, iter::repeat};
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    iter::{once, repeat},
+};
 use std::{path::Path, str::FromStr};
 use strum_macros::Display;
 use strum_macros::EnumString;
 use uuid::Uuid;
 
-#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct EntityId(Uuid);
 
 impl EntityId {
@@ -43,15 +55,74 @@ impl From<EntityId> for Uuid {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// Stands in for a credential in a copy of the config that leaves out secrets.
+pub const REDACTED: &str = "<redacted>";
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Entities {
     pub btrfs_pools: Vec<BtrfsPoolEntity>,
     pub snapshot_syncs: Vec<SnapshotSyncEntity>,
     pub observers: Vec<HealthchecksObserverEntity>,
     pub restic_containers: Vec<ResticContainerEntity>,
+    #[serde(default)]
+    pub remote_containers: Vec<RemoteContainerEntity>,
+    #[serde(default)]
+    pub archive_containers: Vec<ArchiveContainerEntity>,
+    #[serde(default)]
+    pub notifiers: Vec<NotificationObserverEntity>,
 }
 
 impl Entities {
+    /// A copy with the credentials replaced by [`REDACTED`]: restic environment values, object storage secret keys
+    /// and the tokens of notification services.
+    pub fn redacted(&self) -> Self {
+        let mut entities = self.clone();
+        for restic in entities.restic_containers.iter_mut() {
+            for value in restic.custom_environment.values_mut() {
+                *value = REDACTED.to_owned();
+            }
+        }
+        for archive in entities.archive_containers.iter_mut() {
+            if let ArchiveBackend::S3(s3) = &mut archive.backend {
+                s3.secret_access_key = REDACTED.to_owned();
+            }
+        }
+        for notifier in entities.notifiers.iter_mut() {
+            if let Some(secret) = notifier.service.secret_mut() {
+                *secret = REDACTED.to_owned();
+            }
+        }
+        entities
+    }
+
+    /// Whether any credential is still the [`REDACTED`] placeholder, as in a config imported from a redacted copy.
+    pub fn has_redacted_secrets(&self) -> bool {
+        let notifier_secrets = self
+            .notifiers
+            .iter()
+            .filter_map(|n| n.service.clone().secret_mut().cloned())
+            .collect::<Vec<_>>();
+        self.restic_containers
+            .iter()
+            .flat_map(|r| r.custom_environment.values())
+            .chain(self.archive_containers.iter().filter_map(|a| match &a.backend {
+                ArchiveBackend::S3(s3) => Some(&s3.secret_access_key),
+                _ => None,
+            }))
+            .chain(notifier_secrets.iter())
+            .any(|secret| secret == REDACTED)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.btrfs_pools.is_empty()
+            && self.snapshot_syncs.is_empty()
+            && self.observers.is_empty()
+            && self.restic_containers.is_empty()
+            && self.remote_containers.is_empty()
+            && self.archive_containers.is_empty()
+            && self.notifiers.is_empty()
+    }
+
     pub(super) fn post_deserialize(&mut self) {
         for pool in self.btrfs_pools.iter_mut() {
             pool.post_deserialize()
@@ -71,9 +142,91 @@ impl Entities {
         Ok(())
     }
 
+    /// Check a configuration that wasn't built through the attach functions, like an imported one: the rules they
+    /// enforce, that ids are unique, and that syncs and observations refer to entities that exist.
+    pub fn validate(&self) -> Result<()> {
+        let mut rebuilt = Entities::default();
+        for pool in self.btrfs_pools.iter() {
+            let mut empty_pool = pool.clone();
+            empty_pool.datasets.clear();
+            empty_pool.containers.clear();
+            for dataset in pool.datasets.iter().cloned() {
+                let name = dataset.name().to_owned();
+                empty_pool
+                    .attach_dataset(dataset)
+                    .with_context(|| format!("Invalid dataset {}/{}.", pool.name(), name))?;
+            }
+            for container in pool.containers.iter().cloned() {
+                let name = container.name().to_owned();
+                empty_pool
+                    .attach_container(container)
+                    .with_context(|| format!("Invalid container {}/{}.", pool.name(), name))?;
+            }
+            rebuilt
+                .attach_pool(empty_pool)
+                .with_context(|| format!("Invalid pool {}.", pool.name()))?;
+        }
+        let mut observer_names = HashSet::new();
+        for name in self
+            .observers
+            .iter()
+            .map(|o| o.name())
+            .chain(self.notifiers.iter().map(|n| n.name()))
+        {
+            if !observer_names.insert(name) {
+                bail!("Observer name '{}' already exists.", name);
+            }
+        }
+
+        let mut ids = HashSet::new();
+        let all_ids = self
+            .btrfs_pools
+            .iter()
+            .flat_map(|p| {
+                once(p.id())
+                    .chain(p.datasets.iter().map(|d| d.id()))
+                    .chain(p.containers.iter().map(|c| c.id()))
+            })
+            .chain(self.snapshot_syncs.iter().map(|s| s.id()))
+            .chain(self.observers.iter().map(|o| o.id()))
+            .chain(self.restic_containers.iter().map(|r| r.id()))
+            .chain(self.remote_containers.iter().map(|r| r.id()))
+            .chain(self.archive_containers.iter().map(|a| a.id()))
+            .chain(self.notifiers.iter().map(|n| n.id()));
+        for id in all_ids {
+            if !ids.insert(id) {
+                bail!("Id {} is used by more than one entity.", id);
+            }
+        }
+
+        for sync in self.snapshot_syncs.iter() {
+            self.sync_topology(sync)
+                .with_context(|| format!("Invalid sync {}.", sync.name()))?;
+        }
+        let observations = self
+            .observers
+            .iter()
+            .flat_map(|o| o.observations.iter().map(move |h| (o.name(), &h.observation)))
+            .chain(
+                self.notifiers
+                    .iter()
+                    .flat_map(|n| n.observations.iter().map(move |o| (n.name(), o))),
+            );
+        for (observer, observation) in observations {
+            if !ids.contains(&observation.entity_id) {
+                bail!(
+                    "Observer {} observes {} of entity {}, which doesn't exist.",
+                    observer,
+                    observation.event,
+                    observation.entity_id
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn attach_observer(&mut self, observer: HealthchecksObserverEntity) -> Result<()> {
-        entity_by_name(&self.observers, observer.name())
-            .map_or(Ok(()), |o| Err(anyhow!("Observer name '{}' already exists.", o.name())))?;
+        self.observer_name_available(observer.name())?;
 
         if let Some(other) = self.observers.iter().find(|o| o.custom_url == observer.custom_url) {
             let other_type = match observer.custom_url {
@@ -92,6 +245,20 @@ impl Entities {
         Ok(())
     }
 
+    pub fn attach_notifier(&mut self, notifier: NotificationObserverEntity) -> Result<()> {
+        self.observer_name_available(notifier.name())?;
+
+        self.notifiers.push(notifier);
+        Ok(())
+    }
+
+    fn observer_name_available(&self, name: &str) -> Result<()> {
+        entity_by_name(&self.observers, name)
+            .map(|o| o.name())
+            .or_else(|| entity_by_name(&self.notifiers, name).map(|n| n.name()))
+            .map_or(Ok(()), |name| Err(anyhow!("Observer name '{}' already exists.", name)))
+    }
+
     pub fn pool_by_uuid(&self, uuid: Uuid) -> Option<&BtrfsPoolEntity> {
         self.btrfs_pools.iter().find(|p| p.uuid == uuid)
     }
@@ -112,6 +279,24 @@ impl Entities {
         entity_by_id(self.observers.iter(), id)
     }
 
+    pub fn notifier(&self, id: EntityId) -> Option<&NotificationObserverEntity> {
+        entity_by_id(self.notifiers.iter(), id)
+    }
+
+    /// The path of an entity as shown to users, like pool/dataset, if it exists.
+    pub fn entity_path(&self, etype: EntityType, id: EntityId) -> Option<String> {
+        match etype {
+            EntityType::Pool => self.pool(id).map(|p| p.name().to_owned()),
+            EntityType::Dataset => self.dataset(id).map(|d| d.path()),
+            EntityType::Container => self.container(id).map(|c| c.path()),
+            EntityType::SnapshotSync => self.snapshot_sync(id).map(|s| s.name().to_owned()),
+            EntityType::Observer => self
+                .observer(id)
+                .map(|o| o.name().to_owned())
+                .or_else(|| self.notifier(id).map(|n| n.name().to_owned())),
+        }
+    }
+
     pub fn snapshot_sync(&self, id: EntityId) -> Option<&SnapshotSyncEntity> {
         entity_by_id(self.snapshot_syncs.iter(), id)
     }
@@ -148,15 +333,63 @@ impl Entities {
         entity_by_id(self.containers(), id)
             .map(|r| AnyContainer::Btrfs(r.entity))
             .or_else(|| entity_by_id(self.restic_containers.iter(), id).map(|r| AnyContainer::Restic(r)))
+            .or_else(|| entity_by_id(self.remote_containers.iter(), id).map(|r| AnyContainer::Remote(r)))
+            .or_else(|| entity_by_id(self.archive_containers.iter(), id).map(|a| AnyContainer::Archive(a)))
     }
 
     pub fn restic_container(&self, id: EntityId) -> Option<&ResticContainerEntity> {
         entity_by_id(self.restic_containers.iter(), id)
     }
 
+    pub fn remote_container(&self, id: EntityId) -> Option<&RemoteContainerEntity> {
+        entity_by_id(self.remote_containers.iter(), id)
+    }
+
+    pub fn archive_container(&self, id: EntityId) -> Option<&ArchiveContainerEntity> {
+        entity_by_id(self.archive_containers.iter(), id)
+    }
+
+    pub fn sync_topology(&self, sync: &SnapshotSyncEntity) -> Result<SyncTopology> {
+        let source_pool = self
+            .dataset(sync.dataset_id)
+            .map(|d| d.parent.id())
+            .context("source dataset does not exist")?;
+
+        Ok(
+            match self
+                .any_container(sync.container_id)
+                .context("destination container does not exist")?
+            {
+                AnyContainer::Btrfs(c) if c.parent() == source_pool => SyncTopology::SamePool(source_pool),
+                AnyContainer::Btrfs(c) => SyncTopology::CrossPool {
+                    source_pool,
+                    destination_pool: c.parent(),
+                },
+                AnyContainer::Restic(_) => SyncTopology::Restic(source_pool),
+                AnyContainer::Remote(_) => SyncTopology::Remote(source_pool),
+                AnyContainer::Archive(_) => SyncTopology::Archive(source_pool),
+            },
+        )
+    }
+
     pub fn pool_by_mountpoint_mut(&mut self, path: &Path) -> Option<&mut BtrfsPoolEntity> {
         self.btrfs_pools.iter_mut().find(|p| p.mountpoint_path == path)
     }
+
+    /// Replaces a dataset with an updated model, moving it to another pool if needed.
+    pub fn relocate_dataset(&mut self, dataset: BtrfsDatasetEntity, pool_id: EntityId) -> Result<()> {
+        if self.pool(pool_id).is_none() {
+            bail!("pool {} does not exist", pool_id);
+        }
+        for pool in self.btrfs_pools.iter_mut() {
+            pool.datasets.retain(|d| d.id() != dataset.id());
+        }
+        entity_by_id_mut(&mut self.btrfs_pools, pool_id)
+            .expect("pool existence checked above")
+            .datasets
+            .push(dataset);
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -274,11 +507,54 @@ pub enum EntityType {
     Observer,
 }
 
+/// Where the data of a snapshot sync travels. Both pool variants are local transfers between pool actors, remote
+/// transfers leave the machine over ssh and archive transfers as send streams uploaded to object storage.
+#[derive(Display, Clone, Copy, Debug, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum SyncTopology {
+    SamePool(EntityId),
+    CrossPool {
+        source_pool: EntityId,
+        destination_pool: EntityId,
+    },
+    Restic(EntityId),
+    Remote(EntityId),
+    Archive(EntityId),
+}
+
+impl SyncTopology {
+    pub fn pools(&self) -> Vec<EntityId> {
+        match *self {
+            SyncTopology::SamePool(pool)
+            | SyncTopology::Restic(pool)
+            | SyncTopology::Remote(pool)
+            | SyncTopology::Archive(pool) => vec![pool],
+            SyncTopology::CrossPool {
+                source_pool,
+                destination_pool,
+            } => vec![source_pool, destination_pool],
+        }
+    }
+}
+
 #[derive(Display)]
 #[strum(serialize_all = "snake_case")]
 pub enum AnyContainer<'a> {
     Btrfs(&'a BtrfsContainerEntity),
     Restic(&'a ResticContainerEntity),
+    Remote(&'a RemoteContainerEntity),
+    Archive(&'a ArchiveContainerEntity),
+}
+
+impl<'a> AnyContainer<'a> {
+    pub fn name(&self) -> &'a str {
+        match *self {
+            AnyContainer::Btrfs(c) => c.name(),
+            AnyContainer::Restic(c) => c.name(),
+            AnyContainer::Remote(c) => c.name(),
+            AnyContainer::Archive(c) => c.name(),
+        }
+    }
 }
 
 pub trait Entity: Debug {
@@ -0,0 +1,194 @@
+use super::{
+    history::{JobKind, JobRecord},
+    Entities, EntityId, ServerConfig,
+};
+use crate::sys::net::hostname;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const BUNDLE_VERSION: u32 = 2;
+
+/// Everything needed to bootstrap recovery on a fresh machine, packaged as a single document.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecoveryBundle {
+    pub version: u32,
+    pub created: DateTime<Utc>,
+    pub hostname: String,
+    #[serde(with = "super::migrate::versioned")]
+    pub entities: Entities,
+    pub server_config: ServerConfig,
+    /// Age identities archive streams are encrypted with. Only carried in encrypted bundles.
+    #[serde(default)]
+    pub keys: Vec<BundledKey>,
+    /// Checksum manifests of the copies in containers, to verify copies against once the containers are back.
+    #[serde(default)]
+    pub manifests: Vec<BundledManifest>,
+    /// The latest verification and restore test of every entity that had one.
+    #[serde(default)]
+    pub verifications: Vec<JobRecord>,
+}
+
+/// The contents of an age identity file, and where the configuration expects to find it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundledKey {
+    pub container_id: EntityId,
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// A checksum manifest as stored in its container, for the copy of a dataset snapshot with uuid `copy_uuid`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BundledManifest {
+    pub container_id: EntityId,
+    pub dataset_id: EntityId,
+    pub copy_uuid: Uuid,
+    pub manifest: serde_json::Value,
+}
+
+impl RecoveryBundle {
+    pub fn new(entities: Entities, server_config: ServerConfig) -> Self {
+        Self {
+            version: BUNDLE_VERSION,
+            created: Utc::now(),
+            hostname: hostname(),
+            entities,
+            server_config,
+            keys: Vec::new(),
+            manifests: Vec::new(),
+            verifications: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        serde_json::to_vec_pretty(self).context("failed to serialize recovery bundle")
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut bundle: Self = serde_json::from_slice(data).context("failed to parse recovery bundle")?;
+        if bundle.version > BUNDLE_VERSION {
+            bail!(
+                "recovery bundle version {} is newer than supported version {}",
+                bundle.version,
+                BUNDLE_VERSION
+            );
+        }
+        bundle.entities.post_deserialize();
+        Ok(bundle)
+    }
+}
+
+/// The most recent container verification and restore test of each entity, oldest first.
+pub fn latest_verifications(history: &[JobRecord]) -> Vec<JobRecord> {
+    let mut latest = Vec::<JobRecord>::new();
+    for record in history
+        .iter()
+        .filter(|r| matches!(r.kind, JobKind::ContainerVerify | JobKind::RestoreTest))
+    {
+        match latest
+            .iter_mut()
+            .find(|l| l.kind == record.kind && l.entity_id == record.entity_id)
+        {
+            Some(existing) if existing.started < record.started => *existing = record.clone(),
+            Some(_) => {}
+            None => latest.push(record.clone()),
+        }
+    }
+    latest.sort_by_key(|r| r.started);
+    latest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::entities::{ResticContainerEntity, ResticRepository};
+    use std::time::Duration;
+
+    fn record(kind: JobKind, entity_id: EntityId, hours_ago: i64, error: Option<&str>) -> JobRecord {
+        JobRecord {
+            kind,
+            entity_id,
+            started: Utc::now() - chrono::Duration::hours(hours_ago),
+            duration: Duration::from_secs(60),
+            bytes: None,
+            error: error.map(String::from),
+        }
+    }
+
+    #[test]
+    fn latest_verifications_keep_the_newest_check_per_entity() {
+        let container = EntityId::new();
+        let sync = EntityId::new();
+        let history = vec![
+            record(JobKind::ContainerVerify, container, 48, None),
+            record(JobKind::Transfer, container, 30, None),
+            record(
+                JobKind::ContainerVerify,
+                container,
+                24,
+                Some("1 files differ and 0 are missing"),
+            ),
+            record(JobKind::RestoreTest, sync, 36, None),
+        ];
+
+        let latest = latest_verifications(&history);
+        assert_eq!(latest.len(), 2);
+        assert_eq!((latest[0].kind, latest[0].entity_id), (JobKind::RestoreTest, sync));
+        assert_eq!(
+            (latest[1].kind, latest[1].entity_id),
+            (JobKind::ContainerVerify, container)
+        );
+        assert!(!latest[1].succeeded());
+    }
+
+    #[test]
+    fn redacted_credentials_are_recognized() {
+        let mut entities = Entities::default();
+        let mut container =
+            ResticContainerEntity::new("offsite".to_owned(), ResticRepository::Custom("/srv".to_owned()));
+        container
+            .custom_environment
+            .insert("RESTIC_PASSWORD".to_owned(), "hunter2".to_owned());
+        entities.restic_containers.push(container);
+
+        assert!(!entities.has_redacted_secrets());
+        assert!(entities.redacted().has_redacted_secrets());
+    }
+
+    #[test]
+    fn bundles_without_keys_or_manifests_still_load() {
+        let mut bundle = RecoveryBundle::new(Entities::default(), ServerConfig::default());
+        bundle.version = 1;
+        let mut document = serde_json::to_value(&bundle).unwrap();
+        for field in ["keys", "manifests", "verifications"].iter() {
+            document.as_object_mut().unwrap().remove(*field);
+        }
+
+        let loaded = RecoveryBundle::from_bytes(&serde_json::to_vec(&document).unwrap()).unwrap();
+        assert_eq!(loaded.version, 1);
+        assert!(loaded.keys.is_empty() && loaded.manifests.is_empty() && loaded.verifications.is_empty());
+    }
+
+    #[test]
+    fn bundle_contents_survive_a_round_trip() {
+        let mut bundle = RecoveryBundle::new(Entities::default(), ServerConfig::default());
+        bundle.keys.push(BundledKey {
+            container_id: EntityId::new(),
+            path: PathBuf::from("/etc/blkcapt/archive.key"),
+            contents: "AGE-SECRET-KEY-1EXAMPLE\n".to_owned(),
+        });
+        bundle.manifests.push(BundledManifest {
+            container_id: EntityId::new(),
+            dataset_id: EntityId::new(),
+            copy_uuid: Uuid::new_v4(),
+            manifest: serde_json::json!({ "files": {} }),
+        });
+
+        let loaded = RecoveryBundle::from_bytes(&bundle.to_bytes().unwrap()).unwrap();
+        assert_eq!(loaded.version, BUNDLE_VERSION);
+        assert_eq!(loaded.keys, bundle.keys);
+        assert_eq!(loaded.manifests, bundle.manifests);
+    }
+}
@@ -1,16 +1,44 @@
-use crate::{data_dir, model};
+use crate::{
+    config_dir, data_dir, model,
+    model::access::ApiTokens,
+    model::audit::AuditRecord,
+    model::digest::NotificationDigest,
+    model::history::JobRecord,
+    model::migrate::{migrate_entities, EntityConfig, ENTITY_SCHEMA_VERSION},
+    model::worker::WorkerConfig,
+};
 use anyhow::{Context, Result};
 use once_cell::sync::Lazy;
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 use std::{
-    fs::{self, File},
+    fs::{self, File, OpenOptions},
     path::PathBuf,
 };
 use std::{
-    io::{BufReader, BufWriter},
+    io::{BufRead, BufReader, BufWriter, Write},
     path::Path,
 };
 
+static WORKER_CONFIG_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = config_dir();
+    path.push("worker.json");
+    path
+});
+
+static WORKER_CONFIG: Lazy<WorkerConfig> = Lazy::new(|| {
+    load_worker_config().unwrap_or_else(|e| {
+        slog_scope::warn!("Ignoring worker config: {:#}", e);
+        WorkerConfig::default()
+    })
+});
+
+static API_TOKENS_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = config_dir();
+    path.push("tokens.json");
+    path
+});
+
 static SERVER_PATH: Lazy<PathBuf> = Lazy::new(|| {
     let mut path = data_dir();
     path.push("config");
@@ -25,14 +53,94 @@ static ENTITY_PATH: Lazy<PathBuf> = Lazy::new(|| {
     path
 });
 
+static AUDIT_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = data_dir();
+    path.push("audit");
+    path.push("audit.jsonl");
+    path
+});
+
+static DIGEST_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = data_dir();
+    path.push("digests");
+    path
+});
+
+static BOOT_SNAPSHOT_DIR: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = data_dir();
+    path.push("boot-snapshots");
+    path
+});
+
+static HISTORY_PATH: Lazy<PathBuf> = Lazy::new(|| {
+    let mut path = data_dir();
+    path.push("history");
+    path.push("jobs.jsonl");
+    path
+});
+
+pub fn load_worker_config() -> Result<WorkerConfig> {
+    let config: WorkerConfig = read_state(&WORKER_CONFIG_PATH)?;
+    config
+        .validate()
+        .context(format!("invalid worker config {:?}", *WORKER_CONFIG_PATH))?;
+    Ok(config)
+}
+
+/// The worker config loaded once for the life of the process. Falls back to defaults if the file is invalid.
+pub fn worker_config() -> &'static WorkerConfig {
+    &WORKER_CONFIG
+}
+
+/// Read on every request by the worker so that deleted tokens stop working immediately.
+pub fn load_api_tokens() -> Result<ApiTokens> {
+    read_state(&API_TOKENS_PATH)
+}
+
+pub fn store_api_tokens(tokens: &ApiTokens) -> Result<()> {
+    write_state(&API_TOKENS_PATH, tokens)
+}
+
 pub fn load_entity_config() -> model::Entities {
-    let mut entities: model::Entities = read_state(&ENTITY_PATH).expect("FIXME");
+    try_load_entity_config().expect("FIXME")
+}
+
+/// For the worker, which keeps running on the config it has when the stored one can't be read.
+pub fn try_load_entity_config() -> Result<model::Entities> {
+    let mut entities = read_entity_config(&ENTITY_PATH)?;
     entities.post_deserialize();
-    entities
+    Ok(entities)
 }
 
 pub fn store_entity_config(entities: model::Entities) {
-    write_state(&ENTITY_PATH, &entities).expect("FIXME")
+    try_store_entity_config(entities).expect("FIXME")
+}
+
+pub fn try_store_entity_config(entities: model::Entities) -> Result<()> {
+    write_state(&ENTITY_PATH, &EntityConfig(entities))
+}
+
+/// A config stored by an older version is copied aside before migrating, the migrated config replaces it on the
+/// next store.
+fn read_entity_config(path: &Path) -> Result<model::Entities> {
+    let mut state: Value = read_state(path)?;
+    if state.is_null() {
+        return Ok(model::Entities::default());
+    }
+    let stored_version = migrate_entities(&mut state).context(format!("invalid entity config {:?}", path))?;
+    if stored_version < ENTITY_SCHEMA_VERSION {
+        let backup = path.with_extension(format!("v{}.json", stored_version));
+        if !backup.exists() {
+            fs::copy(path, &backup).context(format!("failed to back up entity config to {:?}", backup))?;
+            slog_scope::info!(
+                "Migrating entity config from schema version {} to {}, the old config is kept at {:?}.",
+                stored_version,
+                ENTITY_SCHEMA_VERSION,
+                backup
+            );
+        }
+    }
+    serde_json::from_value(state).context("failed to read json state data")
 }
 
 pub fn load_server_config() -> Result<model::ServerConfig> {
@@ -43,17 +151,90 @@ pub fn store_server_config(entities: model::ServerConfig) -> Result<()> {
     write_state(&SERVER_PATH, &entities)
 }
 
+pub fn load_notification_digest(observer_id: model::EntityId) -> Result<NotificationDigest> {
+    read_state(&DIGEST_DIR.join(format!("{}.json", observer_id)))
+}
+
+pub fn store_notification_digest(observer_id: model::EntityId, digest: &NotificationDigest) -> Result<()> {
+    write_state(&DIGEST_DIR.join(format!("{}.json", observer_id)), digest)
+}
+
+/// The boot a dataset was last snapshot after, empty if it never was.
+pub fn load_boot_snapshot(dataset_id: model::EntityId) -> Result<String> {
+    read_state(&BOOT_SNAPSHOT_DIR.join(format!("{}.json", dataset_id)))
+}
+
+pub fn store_boot_snapshot(dataset_id: model::EntityId, boot_id: &str) -> Result<()> {
+    write_state(&BOOT_SNAPSHOT_DIR.join(format!("{}.json", dataset_id)), &boot_id)
+}
+
+pub fn append_history(record: &JobRecord) -> Result<()> {
+    append_json_line(&HISTORY_PATH, record, "history")
+}
+
+pub fn load_history() -> Result<Vec<JobRecord>> {
+    read_json_lines(&HISTORY_PATH, "history")
+}
+
+pub fn append_audit(record: &AuditRecord) -> Result<()> {
+    append_json_line(&AUDIT_PATH, record, "audit")
+}
+
+pub fn load_audit() -> Result<Vec<AuditRecord>> {
+    read_json_lines(&AUDIT_PATH, "audit")
+}
+
+fn append_json_line(path: &Path, record: &impl Serialize, kind: &str) -> Result<()> {
+    if !path.exists() {
+        fs::create_dir_all(path.parent().expect("log file always has a parent directory"))
+            .with_context(|| format!("failed to create directory structure for {}", kind))?;
+    }
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("failed to open {} file", kind))?;
+    let mut line = serde_json::to_vec(record).with_context(|| format!("failed to serialize {} record", kind))?;
+    line.push(b'\n');
+    file.write_all(&line)
+        .with_context(|| format!("failed to write {} record", kind))
+}
+
+fn read_json_lines<T: DeserializeOwned>(path: &Path, kind: &str) -> Result<Vec<T>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let file = File::open(path).with_context(|| format!("failed to open {} file", kind))?;
+    BufReader::new(file)
+        .lines()
+        .filter(|l| !matches!(l, Ok(l) if l.trim().is_empty()))
+        .map(|l| {
+            l.with_context(|| format!("failed to read {} file", kind))
+                .and_then(|l| serde_json::from_str(&l).with_context(|| format!("failed to parse {} record", kind)))
+        })
+        .collect()
+}
+
 fn write_state(path: &Path, state: &impl Serialize) -> Result<()> {
-    // need the libc renameat2 PR merged to make this transactional.
-    // write new file then swap in to place.
+    // write a new file then rename it over the old one, so a reader never sees a partly written state
     if !path.exists() {
         fs::create_dir_all(path.parent().expect("config file always has a parent directory"))
             .context("failed to create directory structure for state")?;
     }
-    let file = File::create(path).context("failed to create updated json state file")?;
-    let writer = BufWriter::new(file);
+    let mut staged = path.as_os_str().to_owned();
+    staged.push(".tmp");
+    let staged = PathBuf::from(staged);
+    let file = File::create(&staged).context("failed to create updated json state file")?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, state).context("failed to write json state data")?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .context("failed to flush json state file")?;
 
-    serde_json::to_writer_pretty(writer, state).context("failed to write json state data")
+    fs::rename(&staged, path).context("failed to replace json state file")
 }
 
 fn read_state<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
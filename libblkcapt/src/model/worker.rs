@@ -0,0 +1,354 @@
+use crate::{
+    parsing::parse_byte_size,
+    runtime_dir,
+    sys::{clock::ClockProblem, power::PowerSupply},
+};
+use anyhow::{bail, Result};
+use chrono::NaiveTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::NonZeroUsize,
+    path::PathBuf,
+};
+use strum_macros::{Display, EnumString};
+
+/// Daemon-level settings for the worker. Kept apart from the entity store so they can be managed as system
+/// configuration and so the store itself can be relocated.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Unix socket the worker serves its API on. Default: `/run/blockcaptain/daemon.sock`.
+    pub socket_path: PathBuf,
+    /// Address to serve the gRPC management API on, e.g. `127.0.0.1:7733`. Calls are authorized by API token alone
+    /// and are not encrypted, so only expose it on a trusted network. Default: off.
+    pub grpc_address: Option<SocketAddr>,
+    /// Where the worker sends its log output. Default: `auto`, the journal when started by systemd and the
+    /// terminal otherwise.
+    pub log_sink: LogSink,
+    /// Maximum number of snapshot transfers running at once. Default: unlimited.
+    pub max_concurrent_transfers: Option<NonZeroUsize>,
+    /// Bandwidth limits for each transfer by time of day, the first matching window applies. Default: unlimited.
+    pub bandwidth_schedule: Vec<BandwidthWindow>,
+    /// Directory holding the entity store and job history. Default: `/var/lib/blockcaptain`.
+    pub state_dir: PathBuf,
+    /// Report job results to the configured observers. Default: `true`.
+    pub observers_enabled: bool,
+    /// Record job results in the history store. Default: `true`.
+    pub history_enabled: bool,
+    /// Source address selection for outbound connections. The API itself is only served on `socket_path`.
+    pub network: NetworkConfig,
+    /// Jobs held back while the machine runs on battery. Default: none.
+    pub battery: Vec<BatteryRule>,
+    /// Hold pruning back while the kernel reports the clock as unsynchronized. Clock jumps and snapshots dated in the
+    /// future always hold it back. Default: `false`, only a warning.
+    pub require_synchronized_clock: bool,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            socket_path: runtime_dir().join("daemon.sock"),
+            grpc_address: None,
+            log_sink: LogSink::Auto,
+            max_concurrent_transfers: None,
+            bandwidth_schedule: Vec::new(),
+            state_dir: PathBuf::from("/var/lib/blockcaptain"),
+            observers_enabled: true,
+            history_enabled: true,
+            network: Default::default(),
+            battery: Vec::new(),
+            require_synchronized_clock: false,
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.socket_path.is_absolute() {
+            bail!("socket_path must be an absolute path, found {:?}", self.socket_path);
+        }
+        if self.socket_path.file_name().is_none() {
+            bail!("socket_path must name a file, found {:?}", self.socket_path);
+        }
+        if !self.state_dir.is_absolute() {
+            bail!("state_dir must be an absolute path, found {:?}", self.state_dir);
+        }
+        self.network.validate()?;
+        for window in &self.bandwidth_schedule {
+            window.validate()?;
+        }
+        for rule in &self.battery {
+            if matches!(rule.min_charge, Some(charge) if charge > 100) {
+                bail!("battery min_charge for {} jobs must be a percentage", rule.job);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a `job` has to wait while the machine is powered by `supply`.
+    pub fn battery_defers(&self, job: BatteryJob, supply: &PowerSupply) -> bool {
+        supply.on_battery
+            && self.battery.iter().any(|rule| {
+                rule.job == job
+                    && rule
+                        .min_charge
+                        .map_or(true, |min| supply.charge.map_or(true, |c| c < min))
+            })
+    }
+
+    /// Whether pruning has to wait because of `problem` with the clock.
+    pub fn clock_defers_pruning(&self, problem: ClockProblem) -> bool {
+        match problem {
+            ClockProblem::Unsynchronized => self.require_synchronized_clock,
+            ClockProblem::Jumped(_) | ClockProblem::BehindSnapshot(_) => true,
+        }
+    }
+
+    /// The bytes per second a transfer may use at `time`, `None` when unlimited.
+    pub fn bandwidth_limit(&self, time: NaiveTime) -> Option<u64> {
+        self.bandwidth_schedule
+            .iter()
+            .find(|w| w.contains(time))
+            .and_then(|w| w.limit)
+    }
+
+    /// The tighter of a stream's own limit and the schedule's limit at `time`, `None` when neither limits it.
+    pub fn stream_bandwidth_limit(&self, stream_limit: Option<u64>, time: NaiveTime) -> Option<u64> {
+        match (stream_limit, self.bandwidth_limit(time)) {
+            (Some(stream), Some(scheduled)) => Some(stream.min(scheduled)),
+            (stream, scheduled) => stream.or(scheduled),
+        }
+    }
+}
+
+/// A local time of day window, e.g. `{ "from": "08:00", "until": "23:00", "limit": "5M" }`. A window whose `until` is
+/// earlier than its `from` runs past midnight.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthWindow {
+    #[serde(with = "time_of_day")]
+    pub from: NaiveTime,
+    #[serde(with = "time_of_day")]
+    pub until: NaiveTime,
+    /// Bytes per second, as a number or a size like `5M`. Absent means unlimited during the window.
+    #[serde(default, with = "byte_rate")]
+    pub limit: Option<u64>,
+}
+
+impl BandwidthWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.until {
+            self.from <= time && time < self.until
+        } else {
+            time >= self.from || time < self.until
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.from == self.until {
+            bail!("bandwidth window from {} is empty", self.from.format("%H:%M"));
+        }
+        if self.limit == Some(0) {
+            bail!(
+                "bandwidth window from {} limits transfers to nothing",
+                self.from.format("%H:%M")
+            );
+        }
+        Ok(())
+    }
+}
+
+mod time_of_day {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&time.format("%H:%M"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&string, "%H:%M")
+            .or_else(|_| NaiveTime::parse_from_str(&string, "%H:%M:%S"))
+            .map_err(|_| serde::de::Error::custom(format!("'{}' is not a time of day like 08:00", string)))
+    }
+}
+
+mod byte_rate {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ByteRate {
+        Bytes(u64),
+        Size(String),
+    }
+
+    pub fn serialize<S: Serializer>(limit: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        limit.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+        match Option::<ByteRate>::deserialize(deserializer)? {
+            Some(ByteRate::Bytes(bytes)) => Ok(Some(bytes)),
+            Some(ByteRate::Size(size)) => parse_byte_size(size).map(Some).map_err(serde::de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Outbound connection settings for multi-homed hosts. With neither address nor interface set, the routing table
+/// picks the source address.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Local IPv4 or IPv6 address outbound connections are made from.
+    pub bind_address: Option<IpAddr>,
+    /// Interface whose addresses outbound connections are made from.
+    pub interface: Option<String>,
+    /// Connect over IPv6 first when a host has addresses of both families. Default: `false`.
+    pub prefer_ipv6: bool,
+}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.bind_address.is_some() && self.interface.is_some() {
+            bail!("network.bind_address and network.interface can't both be set");
+        }
+        if matches!(&self.interface, Some(interface) if interface.is_empty()) {
+            bail!("network.interface must name an interface");
+        }
+        Ok(())
+    }
+}
+
+/// Holds a job type back on battery, e.g. `{ "job": "sync", "min_charge": 50 }`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryRule {
+    pub job: BatteryJob,
+    /// Let the job run on battery while the charge is at least this percentage. Absent means never on battery.
+    #[serde(default)]
+    pub min_charge: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Display, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum BatteryJob {
+    Sync,
+    Scrub,
+    Trim,
+    Defrag,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Display, EnumString, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum LogSink {
+    Auto,
+    Journal,
+    Terminal,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_config_uses_defaults() {
+        let config: WorkerConfig =
+            serde_json::from_str(r#"{ "max_concurrent_transfers": 2, "log_sink": "journal" }"#).unwrap();
+        assert_eq!(config.max_concurrent_transfers, NonZeroUsize::new(2));
+        assert_eq!(config.log_sink, LogSink::Journal);
+        assert_eq!(config.state_dir, WorkerConfig::default().state_dir);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn relative_paths_rejected() {
+        let config = WorkerConfig {
+            state_dir: PathBuf::from("blockcaptain"),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn network_source_is_address_or_interface() {
+        let config: WorkerConfig = serde_json::from_str(r#"{ "network": { "bind_address": "fd00::2" } }"#).unwrap();
+        assert!(config.validate().is_ok());
+
+        let config: WorkerConfig =
+            serde_json::from_str(r#"{ "network": { "bind_address": "10.0.0.2", "interface": "eth1" } }"#).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn bandwidth_schedule_windows() {
+        let config: WorkerConfig = serde_json::from_str(
+            r#"{ "bandwidth_schedule": [
+                { "from": "08:00", "until": "23:00", "limit": "5M" },
+                { "from": "23:00", "until": "01:30", "limit": 1048576 }
+            ] }"#,
+        )
+        .unwrap();
+        assert!(config.validate().is_ok());
+
+        let at = |hour, minute| NaiveTime::from_hms(hour, minute, 0);
+        assert_eq!(config.bandwidth_limit(at(8, 0)), Some(5 * 1024 * 1024));
+        assert_eq!(config.bandwidth_limit(at(23, 30)), Some(1024 * 1024));
+        assert_eq!(config.bandwidth_limit(at(0, 45)), Some(1024 * 1024));
+        assert_eq!(config.bandwidth_limit(at(3, 0)), None);
+
+        assert_eq!(
+            config.stream_bandwidth_limit(Some(2 * 1024 * 1024), at(8, 0)),
+            Some(2 * 1024 * 1024)
+        );
+        assert_eq!(
+            config.stream_bandwidth_limit(Some(2 * 1024 * 1024), at(23, 30)),
+            Some(1024 * 1024)
+        );
+        assert_eq!(
+            config.stream_bandwidth_limit(Some(2 * 1024 * 1024), at(3, 0)),
+            Some(2 * 1024 * 1024)
+        );
+        assert_eq!(config.stream_bandwidth_limit(None, at(3, 0)), None);
+    }
+
+    #[test]
+    fn battery_rules_defer_on_battery() {
+        let config: WorkerConfig =
+            serde_json::from_str(r#"{ "battery": [{ "job": "scrub" }, { "job": "sync", "min_charge": 50 }] }"#)
+                .unwrap();
+        assert!(config.validate().is_ok());
+
+        let supply = |on_battery, charge| PowerSupply { on_battery, charge };
+        assert!(!config.battery_defers(BatteryJob::Scrub, &supply(false, Some(20))));
+        assert!(config.battery_defers(BatteryJob::Scrub, &supply(true, Some(90))));
+        assert!(!config.battery_defers(BatteryJob::Sync, &supply(true, Some(80))));
+        assert!(config.battery_defers(BatteryJob::Sync, &supply(true, Some(30))));
+        assert!(!config.battery_defers(BatteryJob::Trim, &supply(true, Some(30))));
+
+        let config: WorkerConfig =
+            serde_json::from_str(r#"{ "battery": [{ "job": "sync", "min_charge": 150 }] }"#).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn unsynchronized_clock_defers_only_when_required() {
+        let jumped = ClockProblem::Jumped(chrono::Duration::hours(1));
+        let config = WorkerConfig::default();
+        assert!(!config.clock_defers_pruning(ClockProblem::Unsynchronized));
+        assert!(config.clock_defers_pruning(jumped));
+
+        let config: WorkerConfig = serde_json::from_str(r#"{ "require_synchronized_clock": true }"#).unwrap();
+        assert!(config.clock_defers_pruning(ClockProblem::Unsynchronized));
+        assert!(config.clock_defers_pruning(jumped));
+    }
+
+    #[test]
+    fn unknown_fields_rejected() {
+        assert!(serde_json::from_str::<WorkerConfig>(r#"{ "max_transfers": 2 }"#).is_err());
+    }
+}
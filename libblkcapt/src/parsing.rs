@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use std::{error::Error, iter::FromIterator};
 use uuid::Uuid;
 
@@ -31,3 +31,48 @@ pub fn parse_uuid<S: AsRef<str>>(value: S) -> Result<Uuid> {
         .map_err(|e| e.source().map(|e| anyhow!(e.to_string())).unwrap_or(anyhow!(e)))
         .context(format!("'{}' is not a valid GUID", value.as_ref()))
 }
+
+/// Parse a byte count with an optional binary unit suffix, e.g. `512`, `100M`, `1.5GiB` or `2T`.
+pub fn parse_byte_size<S: AsRef<str>>(value: S) -> Result<u64> {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+
+    let value = value.as_ref().trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or_else(|| value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .with_context(|| format!("'{}' is not a valid size", value))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let unit = unit.trim_end_matches("IB").trim_end_matches('B');
+    let exponent = match unit {
+        "" => 0,
+        _ => match UNITS.iter().position(|u| *u == unit) {
+            Some(index) => index as i32 + 1,
+            None => bail!("'{}' has an unknown size unit", value),
+        },
+    };
+    Ok((number * 1024f64.powi(exponent)).round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_size_units() {
+        assert_eq!(parse_byte_size("512").unwrap(), 512);
+        assert_eq!(parse_byte_size("512B").unwrap(), 512);
+        assert_eq!(parse_byte_size("100M").unwrap(), 100 * 1024 * 1024);
+        assert_eq!(parse_byte_size("1.5GiB").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(parse_byte_size("2 tb").unwrap(), 2 * 1024u64.pow(4));
+    }
+
+    #[test]
+    fn byte_size_invalid() {
+        assert!(parse_byte_size("").is_err());
+        assert!(parse_byte_size("12X").is_err());
+        assert!(parse_byte_size("G").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+#[mockall_double::double]
+use crate::sys::process::double as process_double;
+use crate::sys::process::{output_as_result, output_to_result, run_command_with_input};
+use anyhow::{Context, Result};
+use process_double::run_command;
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+use tokio::process::{Child, ChildStdin, ChildStdout};
+
+fn age_command() -> Command {
+    Command::new("age")
+}
+
+/// Encrypt data to a file for one or more age recipients (public keys or ssh keys).
+pub fn encrypt_to_file(plaintext: &[u8], recipients: &[String], output: &Path) -> Result<()> {
+    let mut command = age_command();
+    command.arg("--encrypt");
+    for recipient in recipients {
+        command.arg("--recipient").arg(recipient);
+    }
+    command.arg("--output").arg(output);
+    run_command_with_input(command, plaintext)
+        .map(|_| ())
+        .context("age encryption failed")
+}
+
+/// Decrypt a file with an age identity file.
+pub fn decrypt_file(input: &Path, identity: &Path) -> Result<Vec<u8>> {
+    let mut command = age_command();
+    command.arg("--decrypt").arg("--identity").arg(identity).arg(input);
+    run_command(command)
+        .context("failed to run age")
+        .and_then(output_as_result)
+        .map(|o| o.stdout)
+        .context("age decryption failed")
+}
+
+/// An age process encrypting or decrypting whatever is written to its input, for streams too large to hold in memory.
+pub struct AgeStream {
+    process: Child,
+}
+
+impl AgeStream {
+    pub fn encrypt(recipients: &[String]) -> Result<Self> {
+        let mut command = age_command();
+        command.arg("--encrypt");
+        for recipient in recipients {
+            command.arg("--recipient").arg(recipient);
+        }
+        Self::start(command)
+    }
+
+    pub fn decrypt(identity: &Path) -> Result<Self> {
+        let mut command = age_command();
+        command.arg("--decrypt").arg("--identity").arg(identity);
+        Self::start(command)
+    }
+
+    fn start(command: Command) -> Result<Self> {
+        let mut command = tokio::process::Command::from(command);
+        command.stdin(Stdio::piped());
+        command.stdout(Stdio::piped());
+        command.stderr(Stdio::piped());
+        command.kill_on_drop(true);
+        let process = command.spawn().context("failed to start age")?;
+        Ok(Self { process })
+    }
+
+    /// The end to write to. Dropping it ends the stream.
+    pub fn input(&mut self) -> ChildStdin {
+        self.process.stdin.take().expect("stdin is piped and only taken once")
+    }
+
+    pub fn output(&mut self) -> ChildStdout {
+        self.process.stdout.take().expect("stdout is piped and only taken once")
+    }
+
+    pub async fn wait(self) -> Result<()> {
+        output_to_result(self.process.wait_with_output().await)
+    }
+}
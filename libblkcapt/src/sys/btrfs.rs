@@ -1,14 +1,16 @@
 use super::fs::{BtrfsMountEntry, DevicePathBuf, FsPathBuf};
-use crate::parsing::{parse_key_value_pair_lines, parse_uuid, StringPair};
+use crate::parsing::{parse_key_value_data, parse_key_value_pair_lines, parse_uuid, StringPair};
+use crate::sys::process::output_stdout_to_result;
 #[mockall_double::double]
 use crate::sys::{fs::double as fs_double, process::double as process_double};
 use anyhow::{anyhow, bail, Context, Result};
 use fs_double::lookup_mountentries_by_devices;
+use nix::sys::statvfs::statvfs;
 pub use operations::*;
 use process_double::run_command_as_result;
-use serde::Deserialize;
-use std::{convert::TryFrom, fs::OpenOptions, process::Command, writeln};
-use std::{convert::TryInto, num::NonZeroUsize, string::String};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom, fs, fs::OpenOptions, process::Command, writeln};
+use std::{convert::TryInto, fmt, num::NonZeroUsize, str::FromStr, string::String};
 use std::{
     ffi::OsStr,
     io::Write,
@@ -200,6 +202,15 @@ pub fn fstab_line(mounted: &MountedFilesystem) -> String {
     )
 }
 
+pub fn subvolume_fstab_line(uuid: &Uuid, subvolume_path: &FsPathBuf, mountpoint: &Path) -> String {
+    format!(
+        "UUID={}\t{}\tbtrfs\tsubvol={},noatime\t0\t0",
+        uuid.to_hyphenated(),
+        mountpoint.to_string_lossy(),
+        subvolume_path.as_pathbuf(Path::new("/")).to_string_lossy()
+    )
+}
+
 #[derive(Clone, Copy, Display, Debug, EnumString, PartialEq, Eq)]
 #[strum(serialize_all = "snake_case")]
 pub enum AllocationMode {
@@ -228,15 +239,74 @@ impl MountedFilesystem {
         Subvolume::from_path(&path.as_pathbuf(&self.fstree_mountpoint))
     }
 
-    pub fn create_snapshot(&self, subvolume: &Subvolume, path: &FsPathBuf) -> Result<()> {
+    pub fn subvolume_properties(&self, path: &FsPathBuf) -> Result<SubvolumeProperties> {
+        SubvolumeProperties::query(&path.as_pathbuf(&self.fstree_mountpoint))
+    }
+
+    /// Needs root, use [`available_bytes`](Self::available_bytes) otherwise.
+    pub fn usage(&self) -> Result<FilesystemUsage> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["filesystem", "usage", "--raw"])
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to query btrfs filesystem usage.")?;
+        _parse_usage(&output_data)
+    }
+
+    /// Bytes available for new data according to statvfs. Works without root, but knows nothing about metadata.
+    pub fn available_bytes(&self) -> Result<u64> {
+        let stat = statvfs(&self.fstree_mountpoint)
+            .with_context(|| format!("Failed to query free space of {:?}.", self.fstree_mountpoint))?;
+        Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+    }
+
+    /// Bytes of file extents written to a subvolume in or after the given generation.
+    pub fn subvolume_changed_bytes(&self, path: &FsPathBuf, since_generation: u64) -> Result<u64> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "find-new"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint))
+                .arg(since_generation.to_string());
+            command
+        })
+        .context(format!("Failed to find changes in subvolume {:?}.", path))?;
+        Ok(_parse_find_new_bytes(&output_data))
+    }
+
+    /// Total bytes referenced by a subvolume, roughly what a full send of it would transfer.
+    pub fn subvolume_size(&self, path: &FsPathBuf) -> Result<u64> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["filesystem", "du", "-s", "--raw"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint));
+            command
+        })
+        .context(format!("Failed to query size of subvolume {:?}.", path))?;
+        _parse_du_total(&output_data)
+    }
+
+    pub fn create_snapshot(
+        &self, subvolume: &Subvolume, path: &FsPathBuf, qgroup: Option<QGroupId>, read_only: bool,
+    ) -> Result<()> {
         let target_path = path.as_pathbuf(&self.fstree_mountpoint);
         if target_path.exists() {
             bail!("Path to new snapshot, {:?}, already exists!", &target_path)
         }
         run_command_as_result({
             let mut command = btrfs_command();
+            command.args(&["subvolume", "snapshot"]);
+            if read_only {
+                command.arg("-r");
+            }
+            if let Some(qgroup) = qgroup {
+                command.arg("-i").arg(qgroup.to_string());
+            }
             command
-                .args(&["subvolume", "snapshot", "-r"])
                 .arg(subvolume.path.as_pathbuf(&self.fstree_mountpoint))
                 .arg(target_path);
             command
@@ -245,6 +315,91 @@ impl MountedFilesystem {
         .map(|_| ())
     }
 
+    pub fn enable_quota(&self) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["quota", "enable"]).arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to enable btrfs quotas.")
+        .map(|_| ())
+    }
+
+    /// Every quota group with its usage, limits and parents. Fails when quotas aren't enabled.
+    pub fn qgroups(&self) -> Result<Vec<QGroup>> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "show", "--raw", "-p", "-r", "-e"])
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to list btrfs quota groups.")?;
+        _parse_qgroups(&output_data)
+    }
+
+    /// Whether a `qgroups` failure only means quotas aren't enabled on the filesystem.
+    pub fn quotas_disabled(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            let message = cause.to_string();
+            message.contains("quotas not enabled") || message.contains("No such file or directory")
+        })
+    }
+
+    pub fn create_qgroup(&self, qgroup: QGroupId) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "create"])
+                .arg(qgroup.to_string())
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to create quota group {}.", qgroup))
+        .map(|_| ())
+    }
+
+    pub fn assign_qgroup(&self, child: QGroupId, parent: QGroupId) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["qgroup", "assign"])
+                .arg(child.to_string())
+                .arg(parent.to_string())
+                .arg(&self.fstree_mountpoint);
+            command
+        })
+        .context(format!("Failed to assign quota group {} to {}.", child, parent))
+        .map(|_| ())
+    }
+
+    /// The id of every subvolume in the filesystem by path, which is also the id of its level 0 quota group.
+    pub fn subvolume_ids(&self) -> Result<HashMap<FsPathBuf, u64>> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["subvolume", "list"]).arg(&self.fstree_mountpoint);
+            command
+        })?;
+        Ok(_parse_subvolume_ids(&output_data))
+    }
+
+    pub fn create_writable_snapshot(&self, subvolume: &Subvolume, path: &FsPathBuf) -> Result<()> {
+        let target_path = path.as_pathbuf(&self.fstree_mountpoint);
+        if target_path.exists() {
+            bail!("Path to new subvolume, {:?}, already exists!", &target_path)
+        }
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "snapshot"])
+                .arg(subvolume.path.as_pathbuf(&self.fstree_mountpoint))
+                .arg(target_path);
+            command
+        })
+        .context(format!("Failed to create writable btrfs snapshot at {:?}.", path))
+        .map(|_| ())
+    }
+
     pub fn create_subvolume(&self, path: &FsPathBuf) -> Result<()> {
         let target_path = path.as_pathbuf(&self.fstree_mountpoint);
         if target_path.exists() {
@@ -259,6 +414,41 @@ impl MountedFilesystem {
         .map(|_| ())
     }
 
+    /// Writable snapshot of a subvolume at a path outside blkcapt management. The target must be on this
+    /// filesystem.
+    pub fn clone_subvolume(&self, path: &FsPathBuf, target_path: &Path) -> Result<()> {
+        if target_path.exists() {
+            bail!("Path to clone, {:?}, already exists!", target_path)
+        }
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "snapshot"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint))
+                .arg(target_path);
+            command
+        })
+        .context(format!(
+            "Failed to clone {:?} to {:?}. The clone must be on the same filesystem.",
+            path, target_path
+        ))
+        .map(|_| ())
+    }
+
+    /// Move a subvolume elsewhere on the filesystem, creating parent directories as needed.
+    pub fn move_subvolume(&self, path: &FsPathBuf, new_path: &FsPathBuf) -> Result<()> {
+        let source_path = path.as_pathbuf(&self.fstree_mountpoint);
+        let target_path = new_path.as_pathbuf(&self.fstree_mountpoint);
+        if target_path.exists() {
+            bail!("Path to moved subvolume, {:?}, already exists!", &target_path)
+        }
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).context(format!("Failed to create directory {:?}.", parent))?;
+        }
+        fs::rename(&source_path, &target_path)
+            .context(format!("Failed to move btrfs subvolume {:?} to {:?}.", path, new_path))
+    }
+
     pub fn delete_subvolume(&self, path: &FsPathBuf) -> Result<()> {
         let target_path = path.as_pathbuf(&self.fstree_mountpoint);
         if !target_path.exists() {
@@ -273,6 +463,40 @@ impl MountedFilesystem {
         .map(|_| ())
     }
 
+    /// The subvolume mounted when neither subvol nor subvolid is given, none when that's the top level.
+    pub fn default_subvolume(&self) -> Result<Option<FsPathBuf>> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["subvolume", "get-default"]).arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed to query the default btrfs subvolume.")?;
+        _parse_default_subvolume(&output_data)
+    }
+
+    pub fn set_default_subvolume(&self, path: &FsPathBuf) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command
+                .args(&["subvolume", "set-default"])
+                .arg(path.as_pathbuf(&self.fstree_mountpoint));
+            command
+        })
+        .context(format!("Failed to make {:?} the default btrfs subvolume.", path))
+        .map(|_| ())
+    }
+
+    /// Waits for the cleaner to finish removing deleted subvolumes so their space shows up as free.
+    pub fn sync_deleted_subvolumes(&self) -> Result<()> {
+        run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["subvolume", "sync"]).arg(&self.fstree_mountpoint);
+            command
+        })
+        .context("Failed waiting for deleted subvolumes to be cleaned up.")?;
+        Ok(())
+    }
+
     pub fn send_subvolume(&self, path: &FsPathBuf, parent: Option<&FsPathBuf>) -> SnapshotSender {
         let mut command = tokio::process::Command::new("btrfs");
         let source_snap_path = path.as_pathbuf(&self.fstree_mountpoint);
@@ -302,6 +526,18 @@ impl MountedFilesystem {
         Subvolume::list_subvolumes(&target_path)
     }
 
+    pub fn list_all_subvolumes(&self) -> Result<Vec<Subvolume>> {
+        Subvolume::list_all_subvolumes(&self.fstree_mountpoint)
+    }
+
+    /// Discard unused blocks on the filesystem's devices. Returns the number of bytes trimmed.
+    pub async fn trim(&self) -> Result<u64> {
+        let mut command = tokio::process::Command::new("fstrim");
+        command.arg("-v").arg(&self.fstree_mountpoint);
+        let output_data = output_stdout_to_result(command.output().await).context("fstrim failed")?;
+        _parse_fstrim_output(&output_data)
+    }
+
     pub fn scrub(&self) -> PoolScrub {
         let mut command = tokio::process::Command::new("btrfs");
         command.args(&["scrub", "start", "-BRd"]).arg(&self.fstree_mountpoint);
@@ -309,6 +545,23 @@ impl MountedFilesystem {
     }
 }
 
+fn _parse_fstrim_output(output_data: &str) -> Result<u64> {
+    once_regex!(r"\((\d+) bytes\) trimmed")
+        .captures(output_data)
+        .and_then(|c| c[1].parse().ok())
+        .ok_or_else(|| anyhow!("Failed to parse output of fstrim: {:?}", output_data.trim()))
+}
+
+fn _parse_default_subvolume(output_data: &str) -> Result<Option<FsPathBuf>> {
+    if output_data.trim().ends_with("(FS_TREE)") {
+        return Ok(None);
+    }
+    once_regex!(r"^ID \d+ gen \d+ top level \d+ path (.+)$")
+        .captures(output_data.trim())
+        .map(|c| Some(FsPathBuf::from(&c[1])))
+        .ok_or_else(|| anyhow!("Failed to parse output of get-default: {:?}", output_data.trim()))
+}
+
 #[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct Subvolume {
     pub uuid: Uuid,
@@ -317,6 +570,12 @@ pub struct Subvolume {
     pub parent_uuid: Option<Uuid>,
     #[serde(rename = "received uuid")]
     pub received_uuid: Option<Uuid>,
+    /// Generation of the last transaction that modified the subvolume.
+    pub generation: u64,
+    /// Generation of the transaction that created the subvolume. For a snapshot this matches the generation of its
+    /// source at the time it was taken.
+    #[serde(rename = "gen at creation")]
+    pub creation_generation: u64,
 }
 
 impl Subvolume {
@@ -330,34 +589,55 @@ impl Subvolume {
     }
 
     pub fn list_subvolumes(path: &Path) -> Result<Vec<Subvolume>> {
-        let paths_regex =
-            once_regex!(r"(?m)\bparent_uuid\s+(.*?)\s+received_uuid\s+(.*?)\s+uuid\s+(.*?)\s+path\s+(.*?)\s*$");
         let output_data = run_command_as_result({
             let mut command = btrfs_command();
-            command.args(&["subvolume", "list", "-uqRo"]).arg(path);
+            command.args(&["subvolume", "list", "-cuqRo"]).arg(path);
+            command
+        })?;
+        Ok(Self::_parse_list(&output_data))
+    }
+
+    /// Every subvolume in the filesystem containing path, not only those below it.
+    pub fn list_all_subvolumes(path: &Path) -> Result<Vec<Subvolume>> {
+        let output_data = run_command_as_result({
+            let mut command = btrfs_command();
+            command.args(&["subvolume", "list", "-cuqR"]).arg(path);
             command
         })?;
-        let path_matches = paths_regex.captures_iter(&output_data);
+        Ok(Self::_parse_list(&output_data))
+    }
+
+    pub(crate) fn _parse_list(output_data: &str) -> Vec<Subvolume> {
+        let paths_regex = once_regex!(
+            r"(?m)\bgen\s+(\d+)\s+cgen\s+(\d+)\b.*?\bparent_uuid\s+(.*?)\s+received_uuid\s+(.*?)\s+uuid\s+(.*?)\s+path\s+(.*?)\s*$"
+        );
+        let path_matches = paths_regex.captures_iter(output_data);
         let parse_uuid = |m| parse_uuid(m).expect("Should always have parsable UUID in btrfs list.");
-        Ok(path_matches
+        let parse_gen = |m: &str| m.parse().expect("Should always have numeric generation in btrfs list.");
+        path_matches
             .map(|m| Self {
-                uuid: parse_uuid(m.get(3).unwrap().as_str()),
-                path: FsPathBuf::from(m.get(4).unwrap().as_str()),
-                parent_uuid: match m.get(1).unwrap().as_str() {
+                uuid: parse_uuid(m.get(5).unwrap().as_str()),
+                path: FsPathBuf::from(m.get(6).unwrap().as_str()),
+                parent_uuid: match m.get(3).unwrap().as_str() {
                     "-" => None,
                     s => Some(parse_uuid(s)),
                 },
-                received_uuid: match m.get(2).unwrap().as_str() {
+                received_uuid: match m.get(4).unwrap().as_str() {
                     "-" => None,
                     s => Some(parse_uuid(s)),
                 },
+                generation: parse_gen(m.get(1).unwrap().as_str()),
+                creation_generation: parse_gen(m.get(2).unwrap().as_str()),
             })
-            .collect::<Vec<_>>())
+            .collect::<Vec<_>>()
     }
 
     fn _parse(data: String) -> Result<Self> {
-        let kvps = parse_key_value_pair_lines::<_, Vec<StringPair>>(data.lines().take(6), ":")
-            .context("Failed to parse output of btrfs subvolume.")?;
+        let kvps = parse_key_value_pair_lines::<_, Vec<StringPair>>(
+            data.lines().take_while(|l| !l.trim_start().starts_with("Snapshot(s):")),
+            ":",
+        )
+        .context("Failed to parse output of btrfs subvolume.")?;
 
         let subvolume = envy::from_iter::<_, Self>(kvps.into_iter().filter_map(|x| {
             if x.1 != "-" {
@@ -371,74 +651,560 @@ impl Subvolume {
     }
 }
 
+/// Space figures from btrfs filesystem usage, in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemUsage {
+    pub device_size: u64,
+    pub device_unallocated: u64,
+    pub free_estimated: u64,
+    pub metadata_size: u64,
+    pub metadata_used: u64,
+}
+
+impl FilesystemUsage {
+    /// Room left for metadata, either in already allocated metadata chunks or in space that can still be allocated.
+    pub fn metadata_headroom(&self) -> u64 {
+        self.metadata_size.saturating_sub(self.metadata_used) + self.device_unallocated
+    }
+}
+
+fn _parse_usage(data: &str) -> Result<FilesystemUsage> {
+    let field = |regex: &regex::Regex, name: &str| -> Result<u64> {
+        regex
+            .captures(data)
+            .and_then(|m| m.get(1))
+            .context(format!("btrfs filesystem usage output is missing {}.", name))?
+            .as_str()
+            .parse()
+            .context(format!("Failed to parse {} from btrfs filesystem usage.", name))
+    };
+    let metadata_regex = once_regex!(r"(?m)^Metadata,[^:]*:\s+Size:(\d+),\s+Used:(\d+)");
+    let metadata = metadata_regex
+        .captures(data)
+        .context("btrfs filesystem usage output is missing metadata allocation.")?;
+    Ok(FilesystemUsage {
+        device_size: field(once_regex!(r"(?m)^\s*Device size:\s+(\d+)"), "device size")?,
+        device_unallocated: field(once_regex!(r"(?m)^\s*Device unallocated:\s+(\d+)"), "unallocated space")?,
+        free_estimated: field(
+            once_regex!(r"(?m)^\s*Free \(estimated\):\s+(\d+)"),
+            "estimated free space",
+        )?,
+        metadata_size: metadata[1].parse().context("Failed to parse metadata size.")?,
+        metadata_used: metadata[2].parse().context("Failed to parse metadata used.")?,
+    })
+}
+
+fn _parse_find_new_bytes(data: &str) -> u64 {
+    let extent_regex = once_regex!(r"(?m)^inode\s+\d+\s+file offset\s+\d+\s+len\s+(\d+)\b");
+    extent_regex
+        .captures_iter(data)
+        .map(|m| m.get(1).unwrap().as_str().parse::<u64>().unwrap_or_default())
+        .sum()
+}
+
+fn _parse_du_total(data: &str) -> Result<u64> {
+    data.lines()
+        .nth(1)
+        .and_then(|l| l.split_whitespace().next())
+        .context("Unexpected output from btrfs filesystem du.")?
+        .parse()
+        .context("Failed to parse total size from btrfs filesystem du.")
+}
+
+/// A quota group id, written `level/id`. Level 0 groups belong to subvolumes and share their ids, higher levels
+/// collect other groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QGroupId {
+    pub level: u16,
+    pub id: u64,
+}
+
+impl QGroupId {
+    pub fn subvolume(id: u64) -> Self {
+        Self { level: 0, id }
+    }
+}
+
+impl fmt::Display for QGroupId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.level, self.id)
+    }
+}
+
+impl FromStr for QGroupId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.splitn(2, '/').collect::<Vec<_>>();
+        match parts.as_slice() {
+            [level, id] => Ok(Self {
+                level: level.parse().context("Invalid quota group level.")?,
+                id: id.parse().context("Invalid quota group id.")?,
+            }),
+            _ => bail!("'{}' is not a quota group id like 1/100", s),
+        }
+    }
+}
+
+impl Serialize for QGroupId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for QGroupId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        QGroupId::from_str(&string).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Usage and limits of a quota group, in bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QGroup {
+    pub id: QGroupId,
+    pub referenced: u64,
+    pub exclusive: u64,
+    pub max_referenced: Option<u64>,
+    pub max_exclusive: Option<u64>,
+    pub parents: Vec<QGroupId>,
+}
+
+fn _parse_qgroups(data: &str) -> Result<Vec<QGroup>> {
+    let limit = |value: &str| -> Result<Option<u64>> {
+        match value {
+            "none" => Ok(None),
+            v => Ok(Some(v.parse()?)),
+        }
+    };
+    data.lines()
+        .skip_while(|l| !l.starts_with("--"))
+        .skip(1)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| {
+            let fields = l.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 6 {
+                bail!("Unexpected line in btrfs qgroup show output: {}", l);
+            }
+            Ok(QGroup {
+                id: fields[0].parse()?,
+                referenced: fields[1].parse()?,
+                exclusive: fields[2].parse()?,
+                max_referenced: limit(fields[3])?,
+                max_exclusive: limit(fields[4])?,
+                parents: match fields[5] {
+                    "-" | "---" => Vec::new(),
+                    parents => parents.split(',').map(QGroupId::from_str).collect::<Result<_>>()?,
+                },
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("Failed to parse btrfs qgroup show output.")
+}
+
+fn _parse_subvolume_ids(data: &str) -> HashMap<FsPathBuf, u64> {
+    let id_regex = once_regex!(r"(?m)^ID\s+(\d+)\b.*?\bpath\s+(.*?)\s*$");
+    id_regex
+        .captures_iter(data)
+        .filter_map(|m| {
+            let id = m.get(1).unwrap().as_str().parse().ok()?;
+            Some((FsPathBuf::from(m.get(2).unwrap().as_str()), id))
+        })
+        .collect()
+}
+
+/// Properties of a subvolume root that change how its data is stored. New files in the subvolume inherit these.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubvolumeProperties {
+    pub compression: Option<String>,
+    pub nodatacow: bool,
+}
+
+impl SubvolumeProperties {
+    pub fn query(path: &Path) -> Result<Self> {
+        let compression_data = run_command_as_result(property_get_command(path, PropertyName::Compression))
+            .context("Failed to query btrfs compression property.")?;
+        let attribute_data = run_command_as_result({
+            let mut command = Command::new("lsattr");
+            command.arg("-d").arg(path);
+            command
+        })
+        .context("Failed to query file attributes.")?;
+        Self::_parse(&compression_data, &attribute_data)
+    }
+
+    /// What to tell the user about a subvolume whose checksums are disabled.
+    pub const CHECKSUMS_DISABLED_WARNING: &'static str =
+        "nodatacow is set. Btrfs keeps no data checksums, so scrub and send can't detect corruption of file contents.";
+
+    /// With nodatacow set btrfs stores no data checksums, so scrub and send can't detect corruption of file contents.
+    pub fn checksums_disabled(&self) -> bool {
+        self.nodatacow
+    }
+
+    fn _parse(compression_data: &str, attribute_data: &str) -> Result<Self> {
+        let mut properties = parse_key_value_data::<HashMap<_, _>>(compression_data.trim())
+            .context("Failed to parse output of btrfs property get.")?;
+        let flags = attribute_data
+            .split_whitespace()
+            .next()
+            .context("Failed to parse output of lsattr.")?;
+
+        Ok(Self {
+            compression: properties.remove("compression").filter(|c| !c.is_empty()),
+            nodatacow: flags.contains('C'),
+        })
+    }
+}
+
+/// Btrfs properties blkcapt reads and changes. `ro` is set on the subvolume, `compression` on its root inode and
+/// inherited by new files.
+#[derive(Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum PropertyName {
+    Ro,
+    Compression,
+}
+
+impl PropertyName {
+    fn object_type(self) -> &'static str {
+        match self {
+            PropertyName::Ro => "subvol",
+            PropertyName::Compression => "inode",
+        }
+    }
+}
+
+fn property_get_command(path: &Path, name: PropertyName) -> Command {
+    let mut command = btrfs_command();
+    command
+        .args(&["property", "get", "-t", name.object_type()])
+        .arg(path)
+        .arg(name.to_string());
+    command
+}
+
+/// Raw value of a property. None when it isn't set.
+pub fn get_property(path: &Path, name: PropertyName) -> Result<Option<String>> {
+    let output_data = run_command_as_result(property_get_command(path, name))
+        .context(format!("Failed to query btrfs {} property of {:?}.", name, path))?;
+    let mut properties = parse_key_value_data::<HashMap<_, _>>(output_data.trim())
+        .context("Failed to parse output of btrfs property get.")?;
+    Ok(properties.remove(&name.to_string()).filter(|v| !v.is_empty()))
+}
+
+/// Set a property from its raw value. An empty value resets it.
+pub fn set_property(path: &Path, name: PropertyName, value: &str) -> Result<()> {
+    run_command_as_result({
+        let mut command = btrfs_command();
+        command
+            .args(&["property", "set", "-t", name.object_type()])
+            .arg(path)
+            .arg(name.to_string())
+            .arg(value);
+        command
+    })
+    .context(format!("Failed to set btrfs {} property of {:?}.", name, path))
+    .map(|_| ())
+}
+
+pub fn is_read_only(path: &Path) -> Result<bool> {
+    match get_property(path, PropertyName::Ro)?.as_deref() {
+        Some("true") => Ok(true),
+        Some("false") | None => Ok(false),
+        Some(value) => bail!("Unexpected btrfs ro property value {:?}.", value),
+    }
+}
+
+/// Flipping a received subvolume to writable makes btrfs drop its received UUID, so it can no longer be the parent
+/// of an incremental receive.
+pub fn set_read_only(path: &Path, read_only: bool) -> Result<()> {
+    set_property(path, PropertyName::Ro, if read_only { "true" } else { "false" })
+}
+
+pub fn compression(path: &Path) -> Result<Option<Compression>> {
+    get_property(path, PropertyName::Compression)?
+        .map(|value| value.parse())
+        .transpose()
+}
+
+pub fn set_compression(path: &Path, compression: Option<Compression>) -> Result<()> {
+    set_property(
+        path,
+        PropertyName::Compression,
+        &compression.map_or_else(String::new, |c| c.to_string()),
+    )
+}
+
+/// Compression algorithms btrfs can apply to file data.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum CompressionAlgorithm {
+    Zlib,
+    Lzo,
+    Zstd,
+}
+
+/// A compression property value, an algorithm with an optional level like `zstd:3`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Compression {
+    pub algorithm: CompressionAlgorithm,
+    pub level: Option<u8>,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let algorithm = parts
+            .next()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| anyhow!("Unknown compression {:?}, expected zlib, lzo or zstd.", s))?;
+        let level = parts
+            .next()
+            .map(|l| l.parse().context(format!("Invalid compression level in {:?}.", s)))
+            .transpose()?;
+        Ok(Self { algorithm, level })
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.level {
+            Some(level) => write!(f, "{}:{}", self.algorithm, level),
+            None => write!(f, "{}", self.algorithm),
+        }
+    }
+}
+
+/// Compression applied to a send stream between the sending and receiving side. The receiving side must have the
+/// same tool installed to decompress it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Display, EnumString, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum StreamCompression {
+    Zstd,
+    Gzip,
+}
+
+impl StreamCompression {
+    /// Fall back to gzip, which every system has, when zstd isn't installed.
+    pub fn available(self) -> Self {
+        match self {
+            StreamCompression::Zstd if !program_exists("zstd") => StreamCompression::Gzip,
+            other => other,
+        }
+    }
+
+    /// The command decompressing stdin to stdout, for running in a remote shell.
+    pub fn decompress_script(&self) -> &'static str {
+        match self {
+            StreamCompression::Zstd => "zstd -d -c -q",
+            StreamCompression::Gzip => "gzip -d -c",
+        }
+    }
+
+    fn compress_command(&self) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(self.to_string());
+        match self {
+            StreamCompression::Zstd => command.args(&["-c", "-q", "-T0"]),
+            StreamCompression::Gzip => command.args(&["-c"]),
+        };
+        command
+    }
+
+    fn decompress_command(&self) -> tokio::process::Command {
+        let mut command = tokio::process::Command::new(self.to_string());
+        match self {
+            StreamCompression::Zstd => command.args(&["-d", "-c", "-q"]),
+            StreamCompression::Gzip => command.args(&["-d", "-c"]),
+        };
+        command
+    }
+}
+
+fn program_exists(name: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|paths| std::env::split_paths(&paths).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false)
+}
+
 mod operations {
-    use crate::sys::process::{exit_status_as_result, output_to_result};
+    use super::{CompressionAlgorithm, StreamCompression};
+    use crate::sys::{
+        process::{exit_status_as_result, output_to_result},
+        throttle::{BandwidthLimit, ThrottledReader},
+    };
     use anyhow::{anyhow, Context as AnyhowContext, Result};
-    use std::process::Stdio;
+    use std::{path::PathBuf, process::Stdio};
     use tokio::{
         io::{AsyncBufReadExt, AsyncRead, AsyncWrite, BufReader},
         process::{Child, Command},
         task::JoinHandle,
     };
 
+    /// A compression or decompression process in the middle of a stream, fed by a task copying into its stdin.
+    struct StreamFilter {
+        process: Child,
+        feeder: JoinHandle<std::io::Result<u64>>,
+    }
+
+    impl StreamFilter {
+        fn start(
+            mut command: Command, mut input: impl AsyncRead + Unpin + Send + 'static,
+        ) -> Result<(Self, Option<tokio::process::ChildStdout>)> {
+            command.stdin(Stdio::piped());
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+            let mut process = command.spawn().context("failed to start stream compression")?;
+            let mut stdin = process.stdin.take().expect("only taken once");
+            let stdout = process.stdout.take();
+            let feeder = tokio::spawn(async move { tokio::io::copy(&mut input, &mut stdin).await });
+            Ok((Self { process, feeder }, stdout))
+        }
+
+        fn start_feeding(
+            mut command: Command, mut output: impl AsyncWrite + Unpin + Send + 'static,
+        ) -> Result<(Self, Option<tokio::process::ChildStdin>)> {
+            command.stdin(Stdio::piped());
+            command.stdout(Stdio::piped());
+            command.stderr(Stdio::piped());
+            let mut process = command.spawn().context("failed to start stream decompression")?;
+            let stdin = process.stdin.take();
+            let mut stdout = process.stdout.take().expect("only taken once");
+            let feeder = tokio::spawn(async move { tokio::io::copy(&mut stdout, &mut output).await });
+            Ok((Self { process, feeder }, stdin))
+        }
+
+        async fn wait(self) -> Result<()> {
+            self.feeder
+                .await
+                .expect("task doesn't panic")
+                .context("failed to pipe the stream through compression")?;
+            output_to_result(self.process.wait_with_output().await).context("stream compression failed")
+        }
+    }
+
     pub struct SnapshotSender {
         command: Command,
+        compression: Option<StreamCompression>,
+        bandwidth_limit: BandwidthLimit,
     }
 
     impl SnapshotSender {
         pub(super) fn new(mut command: Command) -> Self {
             command.stdout(Stdio::piped());
             command.stderr(Stdio::piped());
-            Self { command }
+            Self {
+                command,
+                compression: None,
+                bandwidth_limit: Box::new(|| None),
+            }
+        }
+
+        /// Compress the stream before it reaches the reader.
+        pub fn compressed(mut self, compression: Option<StreamCompression>) -> Self {
+            self.compression = compression;
+            self
+        }
+
+        /// Let the stream be read no faster than the limit allows, measured after compression.
+        pub fn throttled(mut self, bandwidth_limit: BandwidthLimit) -> Self {
+            self.bandwidth_limit = bandwidth_limit;
+            self
         }
 
         pub fn start(mut self) -> Result<StartedSnapshotSender> {
-            self.command
-                .spawn()
-                .map(|process| StartedSnapshotSender { process })
-                .map_err(|e| anyhow!(e))
+            let mut process = self.command.spawn().map_err(|e| anyhow!(e))?;
+            let (compressor, reader) = match self.compression {
+                Some(compression) => {
+                    let stdout = process.stdout.take().expect("only taken once");
+                    match StreamFilter::start(compression.compress_command(), stdout) {
+                        Ok((compressor, reader)) => (Some(compressor), reader),
+                        Err(e) => {
+                            let _ = process.start_kill();
+                            return Err(e);
+                        }
+                    }
+                }
+                None => (None, process.stdout.take()),
+            };
+            Ok(StartedSnapshotSender {
+                process,
+                compressor,
+                reader: reader.map(|r| ThrottledReader::new(r, self.bandwidth_limit)),
+            })
         }
     }
 
     pub struct StartedSnapshotSender {
         process: Child,
+        compressor: Option<StreamFilter>,
+        reader: Option<ThrottledReader<tokio::process::ChildStdout>>,
     }
 
     impl StartedSnapshotSender {
         pub fn reader(&mut self) -> impl AsyncRead {
-            self.process
-                .stdout
-                .take()
-                .expect("child did not have a handle to stdout")
+            self.reader.take().expect("child did not have a handle to stdout")
         }
 
         pub async fn wait(self) -> Result<()> {
-            output_to_result(self.process.wait_with_output().await)
+            output_to_result(self.process.wait_with_output().await)?;
+            match self.compressor {
+                Some(compressor) => compressor.wait().await,
+                None => Ok(()),
+            }
         }
     }
 
     pub struct SnapshotReceiver {
         command: Command,
+        compression: Option<StreamCompression>,
     }
 
     impl SnapshotReceiver {
-        pub(super) fn new(mut command: Command) -> Self {
+        pub(crate) fn new(mut command: Command) -> Self {
             command.stdin(Stdio::piped());
             command.stdout(Stdio::piped());
             command.stderr(Stdio::piped());
-            Self { command }
+            Self {
+                command,
+                compression: None,
+            }
+        }
+
+        /// Decompress the stream written to the writer before it reaches btrfs receive.
+        pub fn compressed(mut self, compression: Option<StreamCompression>) -> Self {
+            self.compression = compression;
+            self
         }
 
         pub fn start(mut self) -> Result<StartedSnapshotReceiver> {
-            self.command.spawn().map_err(|e| anyhow!(e)).map(|mut process| {
-                let name_reader_stdout =
-                    Self::spawn_name_reader(process.stdout.take().expect("only taken once"), false);
-                let name_reader_stderr = Self::spawn_name_reader(process.stderr.take().expect("only taken once"), true);
-                StartedSnapshotReceiver {
-                    process,
-                    name_reader_stdout,
-                    name_reader_stderr,
+            let mut process = self.command.spawn().map_err(|e| anyhow!(e))?;
+            let name_reader_stdout = Self::spawn_name_reader(process.stdout.take().expect("only taken once"), false);
+            let name_reader_stderr = Self::spawn_name_reader(process.stderr.take().expect("only taken once"), true);
+            let (decompressor, writer) = match self.compression {
+                Some(compression) => {
+                    let stdin = process.stdin.take().expect("only taken once");
+                    match StreamFilter::start_feeding(compression.decompress_command(), stdin) {
+                        Ok((decompressor, writer)) => (Some(decompressor), writer),
+                        Err(e) => {
+                            let _ = process.start_kill();
+                            return Err(e);
+                        }
+                    }
                 }
+                None => (None, process.stdin.take()),
+            };
+            Ok(StartedSnapshotReceiver {
+                process,
+                decompressor,
+                writer,
+                name_reader_stdout,
+                name_reader_stderr,
             })
         }
 
@@ -474,23 +1240,27 @@ mod operations {
 
     pub struct StartedSnapshotReceiver {
         process: Child,
+        decompressor: Option<StreamFilter>,
+        writer: Option<tokio::process::ChildStdin>,
         name_reader_stdout: JoinHandle<Result<(Option<String>, String)>>,
         name_reader_stderr: JoinHandle<Result<(Option<String>, String)>>,
     }
 
     impl StartedSnapshotReceiver {
         pub fn writer(&mut self) -> impl AsyncWrite {
-            self.process
-                .stdin
-                .take()
-                .expect("child did not have a handle to stdout")
+            self.writer.take().expect("child did not have a handle to stdin")
         }
 
         pub async fn wait(mut self) -> Result<String> {
+            let decompressed = match self.decompressor.take() {
+                Some(decompressor) => decompressor.wait().await,
+                None => Ok(()),
+            };
             let stdout_result = self.name_reader_stdout.await.expect("task doesn't panic")?;
             let stderr_result = self.name_reader_stderr.await.expect("task doesn't panic")?;
             match exit_status_as_result(self.process.wait().await?) {
                 Ok(_) => {
+                    decompressed?;
                     let incoming_snapshot_name = stdout_result
                         .0
                         .or(stderr_result.0)
@@ -509,6 +1279,20 @@ mod operations {
         }
     }
 
+    /// Recursively defragment files under the paths, optionally recompressing the rewritten extents. Defragmenting
+    /// unshares extents with snapshots, so space usage grows until the snapshots holding the old extents are pruned.
+    pub async fn defragment(paths: &[PathBuf], compress: Option<CompressionAlgorithm>) -> Result<()> {
+        let mut command = Command::new("btrfs");
+        command.args(&["filesystem", "defragment", "-r"]);
+        if let Some(algorithm) = compress {
+            command.arg(format!("-c{}", algorithm));
+        }
+        command.args(paths);
+        command.stdout(Stdio::null());
+        command.stderr(Stdio::piped());
+        output_to_result(command.output().await).context("btrfs defragment failed")
+    }
+
     pub struct PoolScrub {
         command: Command,
     }
@@ -553,6 +1337,51 @@ mod operations {
         #[error("uncorrectable errors were found during scrub")]
         UncorrectableErrors,
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        fn gzip(decompress: bool) -> Command {
+            let mut command = Command::new("gzip");
+            if decompress {
+                command.arg("-d");
+            }
+            command
+        }
+
+        #[tokio::test]
+        async fn stream_filters_compress_and_decompress() {
+            let data = b"blkcapt send stream ".repeat(4096);
+
+            let (compressor, output) = StreamFilter::start(gzip(false), Cursor::new(data.clone())).unwrap();
+            let mut compressed = Vec::new();
+            output.unwrap().read_to_end(&mut compressed).await.unwrap();
+            compressor.wait().await.unwrap();
+            assert!(compressed.len() < data.len());
+
+            let (mut restored, sink) = tokio::io::duplex(64 * 1024);
+            let (decompressor, input) = StreamFilter::start_feeding(gzip(true), sink).unwrap();
+            let mut input = input.unwrap();
+            let feed = async move {
+                input.write_all(&compressed).await.unwrap();
+            };
+            let mut decompressed = Vec::new();
+            let (_, read) = tokio::join!(feed, restored.read_to_end(&mut decompressed));
+            read.unwrap();
+            decompressor.wait().await.unwrap();
+            assert_eq!(decompressed, data);
+        }
+
+        #[tokio::test]
+        async fn stream_filter_reports_a_failed_filter() {
+            let (filter, output) = StreamFilter::start(Command::new("false"), Cursor::new(Vec::new())).unwrap();
+            drop(output);
+            assert!(filter.wait().await.is_err());
+        }
+    }
 }
 
 #[cfg(test)]
@@ -613,6 +1442,25 @@ mod filesystem_tests {
         );
     }
 
+    #[test]
+    fn fstrim_output() {
+        assert_eq!(
+            _parse_fstrim_output("/mnt/data_pool: 12.3 GiB (13207024435 bytes) trimmed\n").unwrap(),
+            13207024435
+        );
+        assert!(_parse_fstrim_output("fstrim: /mnt/data_pool: the discard operation is not supported").is_err());
+    }
+
+    #[test]
+    fn default_subvolume_output() {
+        assert_eq!(_parse_default_subvolume("ID 5 (FS_TREE)\n").unwrap(), None);
+        assert_eq!(
+            _parse_default_subvolume("ID 256 gen 1024 top level 5 path @\n").unwrap(),
+            Some(FsPathBuf::from("@"))
+        );
+        assert!(_parse_default_subvolume("ERROR: can't access '/mnt'").is_err());
+    }
+
     fn process_context() -> process_double::__run_command_as_result::Context {
         const BTRFS_DATA: &str = indoc!(
             r#"
@@ -674,6 +1522,8 @@ mod subvolume_tests {
                 uuid: Uuid::parse_str("0c61d287-c754-2944-a71e-ee6f0cbfb40e").unwrap(),
                 parent_uuid: None,
                 received_uuid: None,
+                generation: 587,
+                creation_generation: 6,
             }
         );
     }
@@ -700,24 +1550,32 @@ mod subvolume_tests {
                     uuid: Uuid::parse_str("8a7ae0b5-b28c-b240-8c07-0015431d58d8").unwrap(),
                     parent_uuid: None,
                     received_uuid: None,
+                    generation: 48,
+                    creation_generation: 8,
                 },
                 Subvolume {
                     path: FsPathBuf::from("test4/test5"),
                     uuid: Uuid::parse_str("ed4c840e-934f-9c49-bcac-fa8a1be864ff").unwrap(),
                     parent_uuid: None,
                     received_uuid: None,
+                    generation: 9,
+                    creation_generation: 9,
                 },
                 Subvolume {
                     path: FsPathBuf::from(".blkcapt/snapshots"),
                     uuid: Uuid::parse_str("45700e9d-9cba-f840-bf2b-b165b87623b7").unwrap(),
                     parent_uuid: None,
                     received_uuid: None,
+                    generation: 47,
+                    creation_generation: 33,
                 },
                 Subvolume {
                     path: FsPathBuf::from(".blkcapt/snapshots/b99a584c-72c0-4cbe-9c6d-0c32274563f7"),
                     uuid: Uuid::parse_str("0cdd2cd3-8e63-4749-adb5-e63a1050b3ea").unwrap(),
                     parent_uuid: None,
                     received_uuid: None,
+                    generation: 50,
+                    creation_generation: 47,
                 },
                 Subvolume {
                     path: FsPathBuf::from(
@@ -726,8 +1584,190 @@ mod subvolume_tests {
                     uuid: Uuid::parse_str("269b40d7-e072-954e-9138-04cbef62a13f").unwrap(),
                     parent_uuid: Some(Uuid::parse_str("8a7ae0b5-b28c-b240-8c07-0015431d58d8").unwrap()),
                     received_uuid: None,
+                    generation: 48,
+                    creation_generation: 48,
                 }
             ]
         );
     }
+
+    #[test]
+    fn subvolume_properties_nodatacow() {
+        let properties = SubvolumeProperties::_parse("", "---------------C------ /mnt/data_pool/vm_images\n").unwrap();
+        assert_eq!(
+            properties,
+            SubvolumeProperties {
+                compression: None,
+                nodatacow: true,
+            }
+        );
+        assert!(properties.checksums_disabled());
+    }
+
+    #[test]
+    fn subvolume_properties_compressed() {
+        let properties =
+            SubvolumeProperties::_parse("compression=zstd:3\n", "---------------------- /mnt/data_pool/docs\n")
+                .unwrap();
+        assert_eq!(
+            properties,
+            SubvolumeProperties {
+                compression: Some(String::from("zstd:3")),
+                nodatacow: false,
+            }
+        );
+    }
+
+    #[test]
+    fn subvolume_size_from_du() {
+        const DU_DATA: &str = indoc!(
+            r#"
+                 Total   Exclusive  Set shared  Filename
+            5368709120      16384  5368692736  /mnt/data_pool/.blkcapt/snapshots/2020-08-23T17-20-10Z
+            "#
+        );
+        assert_eq!(_parse_du_total(DU_DATA).unwrap(), 5368709120);
+    }
+
+    #[test]
+    fn filesystem_usage_from_raw() {
+        const USAGE_DATA: &str = indoc!(
+            r#"
+            Overall:
+                Device size:		        107374182400
+                Device allocated:		         10766778368
+                Device unallocated:		         96607404032
+                Device missing:		                   0
+                Used:			          3388489728
+                Free (estimated):		        101918949376	(min: 53615247360)
+                Data ratio:			                1.00
+                Metadata ratio:		                2.00
+                Global reserve:		             5586944	(used: 0)
+
+            Data,single: Size:8388608000, Used:3078074368 (36.69%)
+               /dev/sdb	        8388608000
+
+            Metadata,DUP: Size:1073741824, Used:155181056 (14.45%)
+               /dev/sdb	        2147483648
+
+            System,DUP: Size:8388608, Used:16384 (0.20%)
+               /dev/sdb	          16777216
+
+            Unallocated:
+               /dev/sdb	         96607404032"#
+        );
+        let usage = _parse_usage(USAGE_DATA).unwrap();
+        assert_eq!(
+            usage,
+            FilesystemUsage {
+                device_size: 107374182400,
+                device_unallocated: 96607404032,
+                free_estimated: 101918949376,
+                metadata_size: 1073741824,
+                metadata_used: 155181056,
+            }
+        );
+        assert_eq!(usage.metadata_headroom(), 97525964800);
+    }
+
+    #[test]
+    fn subvolume_changed_bytes_from_find_new() {
+        const FIND_NEW_DATA: &str = indoc!(
+            r#"
+            inode 257 file offset 0 len 4096 disk start 13631488 offset 0 gen 590 flags NONE notes.txt
+            inode 258 file offset 0 len 131072 disk start 13635584 offset 0 gen 591 flags COMPRESS photos/a.jpg
+            inode 258 file offset 131072 len 65536 disk start 13766656 offset 0 gen 591 flags NONE photos/a.jpg
+            transid marker was 592"#
+        );
+        assert_eq!(_parse_find_new_bytes(FIND_NEW_DATA), 200704);
+    }
+
+    #[test]
+    fn qgroups_from_show() {
+        const QGROUP_DATA: &str = indoc!(
+            r#"
+            qgroupid         rfer         excl     max_rfer     max_excl parent
+            --------         ----         ----     --------     -------- ------
+            0/5             16384        16384         none         none ---
+            0/256       104857600     52428800         none         none 1/1
+            0/261        52445184        16384         none         none 1/1,1/7
+            1/1         157286400    157286400  10737418240         none ---"#
+        );
+        let qgroups = _parse_qgroups(QGROUP_DATA).unwrap();
+        assert_eq!(qgroups.len(), 4);
+        assert_eq!(qgroups[0].parents, vec![]);
+        assert_eq!(
+            qgroups[2].parents,
+            vec![QGroupId { level: 1, id: 1 }, QGroupId { level: 1, id: 7 }]
+        );
+        assert_eq!(
+            qgroups[3],
+            QGroup {
+                id: QGroupId { level: 1, id: 1 },
+                referenced: 157286400,
+                exclusive: 157286400,
+                max_referenced: Some(10737418240),
+                max_exclusive: None,
+                parents: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn quotas_disabled_from_errors() {
+        let not_enabled = anyhow!("ERROR: can't list qgroups: quotas not enabled\n")
+            .context("exit code: 1")
+            .context("Failed to list btrfs quota groups.");
+        assert!(MountedFilesystem::quotas_disabled(&not_enabled));
+        let older = anyhow!("ERROR: can't perform the search: No such file or directory\n");
+        assert!(MountedFilesystem::quotas_disabled(&older));
+        let denied = anyhow!("ERROR: can't perform the search: Operation not permitted\n")
+            .context("Failed to list btrfs quota groups.");
+        assert!(!MountedFilesystem::quotas_disabled(&denied));
+    }
+
+    #[test]
+    fn subvolume_ids_from_list() {
+        const LIST_DATA: &str = indoc!(
+            r#"
+            ID 256 gen 587 top level 5 path @
+            ID 259 gen 590 top level 5 path .blkcapt/snapshots/8a7ae0b5-b28c-b240-8c07-0015431d58d8/2020-08-23T17-20-10Z"#
+        );
+        let ids = _parse_subvolume_ids(LIST_DATA);
+        assert_eq!(ids.get(&FsPathBuf::from("@")), Some(&256));
+        assert_eq!(
+            ids.get(&FsPathBuf::from(
+                ".blkcapt/snapshots/8a7ae0b5-b28c-b240-8c07-0015431d58d8/2020-08-23T17-20-10Z"
+            )),
+            Some(&259)
+        );
+    }
+
+    #[test]
+    #[serial(fakecmd)]
+    fn read_only_property() {
+        let ctx = process_double::run_command_as_result_context();
+        ctx.expect().returning(|_| Ok(String::from("ro=true\n")));
+
+        assert!(is_read_only(&PathBuf::from(
+            "/mnt/data_pool/.blkcapt/snapshots/a/2020-08-23T17-20-10Z"
+        ))
+        .unwrap());
+    }
+
+    #[test]
+    fn compression_property_values() {
+        let compression = "zstd:3".parse::<Compression>().unwrap();
+        assert_eq!(
+            compression,
+            Compression {
+                algorithm: CompressionAlgorithm::Zstd,
+                level: Some(3),
+            }
+        );
+        assert_eq!(compression.to_string(), "zstd:3");
+        assert_eq!("lzo".parse::<Compression>().unwrap().level, None);
+        assert!("zstd:high".parse::<Compression>().is_err());
+        assert!("brotli".parse::<Compression>().is_err());
+    }
 }
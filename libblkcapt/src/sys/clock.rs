@@ -0,0 +1,103 @@
+use super::{power::uptime, process::double::run_command_as_result};
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use std::{fmt, process::Command, time::Duration};
+
+/// Disagreement between the wall clock and the uptime below this is NTP slewing, not a jump.
+const JUMP_THRESHOLD: Duration = Duration::from_secs(60);
+
+/// How long after a jump the wall clock stays suspect, in case it is still settling.
+const JUMP_SETTLE_PERIOD: Duration = Duration::from_secs(900);
+
+/// Whether the kernel considers the wall clock synchronized, as reported by timedated. This covers any NTP daemon
+/// that disciplines the kernel clock, not just timesyncd.
+pub fn synchronized() -> Result<bool> {
+    let mut command = Command::new("timedatectl");
+    command.args(&["show", "--property=NTPSynchronized", "--value"]);
+    match run_command_as_result(command)?.trim() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        other => bail!("unexpected NTPSynchronized value: {}", other),
+    }
+}
+
+/// Why the wall clock can't be trusted to name or prune snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockProblem {
+    /// The wall clock was stepped by this much recently.
+    Jumped(ChronoDuration),
+    /// The kernel reports the clock as not synchronized.
+    Unsynchronized,
+    /// The newest snapshot is dated in the future.
+    BehindSnapshot(DateTime<Utc>),
+}
+
+impl fmt::Display for ClockProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClockProblem::Jumped(offset) => write!(f, "the clock jumped by {}s", offset.num_seconds()),
+            ClockProblem::Unsynchronized => write!(f, "the clock is not synchronized"),
+            ClockProblem::BehindSnapshot(newest) => write!(f, "the newest snapshot is from the future, {}", newest),
+        }
+    }
+}
+
+/// Notices the wall clock being stepped. The uptime advances steadily while the wall clock follows `settimeofday`
+/// and NTP steps, so the two drift apart by the size of the step.
+pub struct ClockMonitor {
+    uptime: Duration,
+    wall: DateTime<Utc>,
+    jump: Option<(Duration, ChronoDuration)>,
+}
+
+impl ClockMonitor {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            uptime: uptime()?,
+            wall: Utc::now(),
+            jump: None,
+        })
+    }
+
+    /// The most recent jump, while it is within the settle period.
+    pub fn check(&mut self) -> Result<Option<ChronoDuration>> {
+        let (uptime, wall) = (uptime()?, Utc::now());
+        if let Some(offset) = jump_between(self.uptime, uptime, wall - self.wall) {
+            self.jump = Some((uptime, offset));
+        }
+        self.uptime = uptime;
+        self.wall = wall;
+        Ok(self
+            .jump
+            .and_then(|(at, offset)| match uptime - at < JUMP_SETTLE_PERIOD {
+                true => Some(offset),
+                false => None,
+            }))
+    }
+}
+
+fn jump_between(previous_uptime: Duration, uptime: Duration, wall_elapsed: ChronoDuration) -> Option<ChronoDuration> {
+    let elapsed = ChronoDuration::from_std(uptime.checked_sub(previous_uptime)?).ok()?;
+    let threshold = ChronoDuration::from_std(JUMP_THRESHOLD).expect("threshold fits");
+    Some(wall_elapsed - elapsed).filter(|offset| *offset >= threshold || *offset <= -threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drift_beyond_threshold_is_a_jump() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(jump_between(minute, minute * 2, ChronoDuration::minutes(1)), None);
+        assert_eq!(jump_between(minute, minute * 2, ChronoDuration::seconds(65)), None);
+        assert_eq!(
+            jump_between(minute, minute * 2, ChronoDuration::hours(1)),
+            Some(ChronoDuration::minutes(59))
+        );
+        assert_eq!(
+            jump_between(minute, minute * 2, ChronoDuration::minutes(-59)),
+            Some(ChronoDuration::minutes(-60))
+        );
+    }
+}
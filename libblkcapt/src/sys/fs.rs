@@ -37,6 +37,10 @@ impl FsPathBuf {
         self.0.extension()
     }
 
+    pub fn parent(&self) -> Option<FsPathBuf> {
+        self.0.parent().map(|p| Self(p.to_path_buf()))
+    }
+
     pub fn join<P: AsRef<Path>>(&self, path: P) -> Self {
         Self(self.0.join(path))
     }
@@ -44,6 +48,24 @@ impl FsPathBuf {
     pub fn push<P: AsRef<Path>>(&mut self, path: P) {
         self.0.push(path);
     }
+
+    pub fn with_file_name<S: AsRef<OsStr>>(&self, file_name: S) -> Self {
+        Self(self.0.with_file_name(file_name))
+    }
+
+    pub fn starts_with(&self, base: &FsPathBuf) -> bool {
+        self.0.starts_with(&base.0)
+    }
+
+    pub fn strip_prefix(&self, base: &FsPathBuf) -> Option<FsPathBuf> {
+        self.0.strip_prefix(&base.0).ok().map(|p| Self(p.to_path_buf()))
+    }
+}
+
+impl AsRef<Path> for FsPathBuf {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
 }
 
 impl<T: ?Sized + AsRef<OsStr>> From<&T> for FsPathBuf {
@@ -176,6 +198,22 @@ impl BtrfsMountEntry {
             || subvol_path.unwrap_or_default() == "/"
     }
 
+    /// Unprivileged users may delete subvolumes they own on this mount.
+    pub fn user_subvol_rm_allowed(&self) -> bool {
+        self.0
+            .mntops
+            .iter()
+            .any(|x| matches!(x, mnt::MntOps::Extra(extra) if extra == "user_subvol_rm_allowed"))
+    }
+
+    /// The filesystem discards freed blocks as it goes, so scheduled trims have little left to do.
+    pub fn continuous_discard(&self) -> bool {
+        self.0
+            .mntops
+            .iter()
+            .any(|x| matches!(x, mnt::MntOps::Extra(extra) if extra == "discard" || extra.starts_with("discard=")))
+    }
+
     pub fn keyed_option<T>(&self, key: &str) -> Option<T>
     where
         T: FromStr,
@@ -336,6 +374,15 @@ mod tests {
         assert_eq!(mount.subvolume_path().unwrap(), "/testsub");
     }
 
+    #[test]
+    fn user_subvol_rm_allowed_parsed() {
+        let mount: MountEntry = "/dev/vda / btrfs rw,noatime,user_subvol_rm_allowed 0 0"
+            .parse()
+            .unwrap();
+        assert!(BtrfsMountEntry::try_from(mount).unwrap().user_subvol_rm_allowed());
+        assert!(!btrfs_without_subvol_opts().user_subvol_rm_allowed());
+    }
+
     fn btrfs_with_top_subvol_opts() -> BtrfsMountEntry {
         let mount: MountEntry = "/dev/vda / btrfs rw,noatime,subvolid=5,subvol=/ 0 0".parse().unwrap();
         BtrfsMountEntry::try_from(mount).unwrap()
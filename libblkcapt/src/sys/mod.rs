@@ -1,4 +1,15 @@
+pub mod age;
 pub mod btrfs;
+pub mod clock;
 pub mod fs;
 pub mod net;
+pub mod polkit;
+pub mod power;
+pub mod privilege;
 pub mod process;
+pub mod rclone;
+pub mod s3;
+pub mod snmp;
+pub mod tape;
+pub mod throttle;
+pub mod zabbix;
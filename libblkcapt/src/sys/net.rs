@@ -1,11 +1,24 @@
-use crate::runtime_dir;
-use http::Request;
+use crate::{
+    core::system::CONFIRMATION_HEADER,
+    model::{storage, worker::NetworkConfig},
+};
+use anyhow::{bail, Context as _, Result as AnyResult};
+use http::{header::AUTHORIZATION, Request};
 use hyper::{client::connect::dns::GaiResolver, client::HttpConnector, Client, Uri};
 use hyper::{Body, Response};
 use hyper_timeout::TimeoutConnector;
 use hyper_tls::HttpsConnector;
 use hyperlocal::UnixConnector;
-use std::time::Duration;
+use nix::{ifaddrs::getifaddrs, sys::socket::SockAddr};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use slog_scope::warn;
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
 
 type HyperClient = Client<TimeoutConnector<HttpsConnector<HttpConnector<GaiResolver>>>>;
 
@@ -15,13 +28,30 @@ pub struct HttpsClient {
 
 impl HttpsClient {
     pub fn default() -> Self {
+        Self::with_io_timeout(Duration::from_secs(5))
+    }
+
+    /// A client for sending large request bodies, which can stall on a slow uplink or while the server digests
+    /// what it received.
+    pub fn for_uploads() -> Self {
+        Self::with_io_timeout(Duration::from_secs(120))
+    }
+
+    fn with_io_timeout(timeout: Duration) -> Self {
         let mut http = HttpConnector::new();
+        match Outbound::current() {
+            Ok(outbound) => match outbound.local_addresses() {
+                (Some(v4), Some(v6)) => http.set_local_addresses(v4, v6),
+                (v4, v6) => http.set_local_address(v4.map(IpAddr::V4).or_else(|| v6.map(IpAddr::V6))),
+            },
+            Err(e) => warn!("ignoring outbound network configuration: {:#}", e),
+        }
         http.set_connect_timeout(Some(Duration::from_secs(3)));
         http.enforce_http(false);
         let https = HttpsConnector::new_with_connector(http);
         let mut connector = TimeoutConnector::new(https);
-        connector.set_read_timeout(Some(Duration::from_secs(5)));
-        connector.set_write_timeout(Some(Duration::from_secs(5)));
+        connector.set_read_timeout(Some(timeout));
+        connector.set_write_timeout(Some(timeout));
 
         Self {
             client: Client::builder().build::<_, hyper::Body>(connector),
@@ -36,10 +66,24 @@ impl HttpsClient {
         let request = Request::post(url).body(Body::from(body)).expect("valid request setup");
         self.client.request(request).await
     }
+
+    pub async fn request(&self, request: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        self.client.request(request).await
+    }
+}
+
+pub fn hostname() -> String {
+    std::fs::read_to_string("/etc/hostname")
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_default()
 }
 
+/// API token presented to the worker instead of relying on polkit.
+pub const API_TOKEN_ENV: &str = "BLKCAPT_TOKEN";
+
 pub struct ServiceClient {
     client: Client<TimeoutConnector<UnixConnector>>,
+    token: Option<String>,
 }
 
 impl ServiceClient {
@@ -51,16 +95,311 @@ impl ServiceClient {
 
         Self {
             client: Client::builder().build::<_, hyper::Body>(connector),
+            token: std::env::var(API_TOKEN_ENV).ok().filter(|t| !t.is_empty()),
         }
     }
 
     pub async fn get(&self, path: &str) -> Result<Response<Body>, hyper::Error> {
-        let socket_path = {
-            let mut path = runtime_dir();
-            path.push("daemon.sock");
-            path
+        self.send(Request::get(self.uri(path))).await
+    }
+
+    pub async fn post(&self, path: &str) -> Result<Response<Body>, hyper::Error> {
+        self.send(Request::post(self.uri(path))).await
+    }
+
+    /// Send a destructive request. Without a confirmation token the worker answers with a challenge instead of
+    /// acting on it.
+    pub async fn delete(&self, path: &str, confirmation_token: Option<&str>) -> Result<Response<Body>, hyper::Error> {
+        let mut request = Request::delete(self.uri(path));
+        if let Some(token) = confirmation_token {
+            request = request.header(CONFIRMATION_HEADER, token);
+        }
+        self.send(request).await
+    }
+
+    /// Like [`delete`](Self::delete), for destructive requests that aren't deletions.
+    pub async fn post_confirmed(
+        &self, path: &str, confirmation_token: Option<&str>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let mut request = Request::post(self.uri(path));
+        if let Some(token) = confirmation_token {
+            request = request.header(CONFIRMATION_HEADER, token);
+        }
+        self.send(request).await
+    }
+
+    fn uri(&self, path: &str) -> Uri {
+        let socket_path = &storage::worker_config().socket_path;
+        hyperlocal::Uri::new(socket_path, path).into()
+    }
+
+    async fn send(&self, mut request: http::request::Builder) -> Result<Response<Body>, hyper::Error> {
+        if let Some(token) = &self.token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        self.client
+            .request(request.body(Body::empty()).expect("valid request setup"))
+            .await
+    }
+}
+
+/// The local addresses outbound connections are made from, per the worker's network configuration.
+#[derive(Debug, Default)]
+pub struct Outbound {
+    sources: Vec<IpAddr>,
+    prefer_ipv6: bool,
+}
+
+impl Outbound {
+    pub fn current() -> AnyResult<Self> {
+        Self::from_config(&storage::worker_config().network)
+    }
+
+    pub fn from_config(config: &NetworkConfig) -> AnyResult<Self> {
+        let sources = match (config.bind_address, &config.interface) {
+            (Some(address), _) => vec![address],
+            (None, Some(interface)) => {
+                let sources = interface_addresses(interface)?;
+                if sources.is_empty() {
+                    bail!("interface {} has no usable IP addresses", interface);
+                }
+                sources
+            }
+            (None, None) => Vec::new(),
         };
-        let url: Uri = hyperlocal::Uri::new(socket_path, path).into();
-        self.client.get(url).await
+        Ok(Self {
+            sources,
+            prefer_ipv6: config.prefer_ipv6,
+        })
+    }
+
+    /// The first source of each address family, for clients that bind one local address per family.
+    pub fn local_addresses(&self) -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+        let v4 = self.sources.iter().find_map(|a| match a {
+            IpAddr::V4(v4) => Some(*v4),
+            IpAddr::V6(_) => None,
+        });
+        let v6 = self.sources.iter().find_map(|a| match a {
+            IpAddr::V6(v6) => Some(*v6),
+            IpAddr::V4(_) => None,
+        });
+        (v4, v6)
+    }
+
+    /// The address to bind before connecting to `remote`, `None` when the OS should choose.
+    fn source_for(&self, remote: &SocketAddr) -> AnyResult<Option<SocketAddr>> {
+        if self.sources.is_empty() {
+            return Ok(None);
+        }
+        match self.sources.iter().find(|a| a.is_ipv6() == remote.is_ipv6()) {
+            Some(source) => Ok(Some(SocketAddr::new(*source, 0))),
+            None => bail!("no outbound source address can reach {}", remote),
+        }
+    }
+
+    pub async fn connect(&self, address: &str) -> AnyResult<TcpStream> {
+        let mut remotes = lookup_host(address)
+            .await
+            .with_context(|| format!("failed to resolve {}", address))?
+            .collect::<Vec<_>>();
+        remotes.sort_by_key(|r| r.is_ipv6() != self.prefer_ipv6);
+
+        let mut last_error = None;
+        for remote in remotes {
+            match self.connect_to(remote).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("{} has no addresses", address)))
+    }
+
+    async fn connect_to(&self, remote: SocketAddr) -> AnyResult<TcpStream> {
+        let socket = if remote.is_ipv6() {
+            TcpSocket::new_v6()?
+        } else {
+            TcpSocket::new_v4()?
+        };
+        if let Some(source) = self.source_for(&remote)? {
+            socket.bind(source)?;
+        }
+        Ok(socket.connect(remote).await?)
+    }
+
+    pub fn udp_socket(&self, remote: &SocketAddr) -> AnyResult<UdpSocket> {
+        let source = self.source_for(remote)?.unwrap_or_else(|| match remote {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        });
+        Ok(UdpSocket::bind(source)?)
+    }
+}
+
+/// Addresses on `interface` that can be bound without a scope, so IPv6 link-local addresses are left out.
+fn interface_addresses(interface: &str) -> AnyResult<Vec<IpAddr>> {
+    let addresses = getifaddrs()
+        .context("failed to list network interfaces")?
+        .filter(|a| a.interface_name == interface)
+        .filter_map(|a| match a.address {
+            Some(SockAddr::Inet(inet)) => Some(inet.to_std().ip()),
+            _ => None,
+        })
+        .filter(|ip| match ip {
+            IpAddr::V6(v6) => v6.segments()[0] & 0xffc0 != 0xfe80,
+            IpAddr::V4(_) => true,
+        })
+        .collect();
+    Ok(addresses)
+}
+
+/// How long a single connection attempt to a remote host may take.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A hardware address, written as six colon or dash separated hex octets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    /// The Wake-on-LAN magic packet: six 0xff bytes followed by the address sixteen times.
+    pub fn magic_packet(&self) -> Vec<u8> {
+        let mut packet = vec![0xff; 6];
+        for _ in 0..16 {
+            packet.extend_from_slice(&self.0);
+        }
+        packet
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AnyResult<Self> {
+        let octets = s
+            .split(|c| c == ':' || c == '-')
+            .map(|octet| u8::from_str_radix(octet, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("'{}' is not a valid MAC address", s))?;
+        if octets.len() != 6 {
+            bail!("'{}' is not a valid MAC address", s);
+        }
+        let mut address = [0; 6];
+        address.copy_from_slice(&octets);
+        Ok(Self(address))
+    }
+}
+
+impl Display for MacAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let octets = self.0.iter().map(|o| format!("{:02x}", o)).collect::<Vec<_>>();
+        write!(f, "{}", octets.join(":"))
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let string = String::deserialize(deserializer)?;
+        MacAddress::from_str(&string).map_err(serde::de::Error::custom)
+    }
+}
+
+pub fn send_magic_packet(mac_address: MacAddress, target: SocketAddr) -> AnyResult<()> {
+    let socket = Outbound::current()
+        .and_then(|outbound| outbound.udp_socket(&target))
+        .context("failed to open socket for wake-on-lan")?;
+    socket.set_broadcast(true)?;
+    socket
+        .send_to(&mac_address.magic_packet(), target)
+        .with_context(|| format!("failed to send wake-on-lan packet to {}", target))?;
+    Ok(())
+}
+
+/// Open and drop a TCP connection to `address`, a `host:port`.
+pub async fn probe_reachable(address: &str) -> AnyResult<()> {
+    let outbound = Outbound::current()?;
+    match tokio::time::timeout(PROBE_TIMEOUT, outbound.connect(address)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(e.context(format!("{} is not reachable", address))),
+        Err(_) => bail!(
+            "{} did not answer within {}",
+            address,
+            humantime::format_duration(PROBE_TIMEOUT)
+        ),
+    }
+}
+
+/// Probe `address` until it answers or `timeout` passes.
+pub async fn wait_reachable(address: &str, timeout: Duration) -> AnyResult<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match probe_reachable(address).await {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(e.context(format!("gave up after {}", humantime::format_duration(timeout))))
+            }
+            Err(_) => tokio::time::sleep(PROBE_INTERVAL).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_address_round_trip() {
+        let mac = MacAddress::from_str("00:1A-2b:3c:4d:5e").unwrap();
+        assert_eq!(mac.to_string(), "00:1a:2b:3c:4d:5e");
+        assert!(MacAddress::from_str("00:1a:2b:3c:4d").is_err());
+        assert!(MacAddress::from_str("00:1a:2b:3c:4d:zz").is_err());
+    }
+
+    #[test]
+    fn source_matches_remote_family() {
+        let outbound = Outbound::from_config(&NetworkConfig {
+            bind_address: Some("fd00::2".parse().unwrap()),
+            ..Default::default()
+        })
+        .unwrap();
+        let remote_v6 = "[fd00::1]:22".parse().unwrap();
+        let remote_v4 = "10.0.0.1:22".parse().unwrap();
+        assert_eq!(
+            outbound.source_for(&remote_v6).unwrap(),
+            Some("[fd00::2]:0".parse().unwrap())
+        );
+        assert!(outbound.source_for(&remote_v4).is_err());
+        assert_eq!(Outbound::default().source_for(&remote_v4).unwrap(), None);
+    }
+
+    #[test]
+    fn local_addresses_cover_both_families() {
+        let outbound = Outbound {
+            sources: vec![
+                "fd00::2".parse().unwrap(),
+                "10.0.0.2".parse().unwrap(),
+                "10.0.0.3".parse().unwrap(),
+            ],
+            prefer_ipv6: true,
+        };
+        assert_eq!(
+            outbound.local_addresses(),
+            (Some("10.0.0.2".parse().unwrap()), Some("fd00::2".parse().unwrap()))
+        );
+        assert_eq!(Outbound::default().local_addresses(), (None, None));
+    }
+
+    #[test]
+    fn magic_packet_layout() {
+        let mac = MacAddress::from_str("01:02:03:04:05:06").unwrap();
+        let packet = mac.magic_packet();
+        assert_eq!(packet.len(), 102);
+        assert_eq!(&packet[..6], &[0xff; 6]);
+        assert!(packet[6..].chunks(6).all(|chunk| chunk == [1, 2, 3, 4, 5, 6]));
     }
 }
@@ -0,0 +1,109 @@
+use super::process::double::run_command;
+use anyhow::{anyhow, Context, Result};
+use std::{fs, process::Command};
+use strum_macros::Display;
+
+/// Classes of worker operations that are authorized separately, so read-only access can be delegated without
+/// handing out control of jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Display)]
+pub enum ActionClass {
+    /// Read the worker and job status.
+    ReadStatus,
+    /// Start, stop, or reconfigure jobs.
+    ManageJobs,
+    /// List files inside snapshots.
+    BrowseData,
+    /// Restore data or delete snapshots and container data.
+    Destructive,
+}
+
+impl ActionClass {
+    pub fn action_id(&self) -> &'static str {
+        match self {
+            ActionClass::ReadStatus => "org.blockcaptain.read-status",
+            ActionClass::ManageJobs => "org.blockcaptain.manage-jobs",
+            ActionClass::BrowseData => "org.blockcaptain.browse-data",
+            ActionClass::Destructive => "org.blockcaptain.destructive",
+        }
+    }
+}
+
+/// Identity of a process on the other end of the control socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subject {
+    pub pid: i32,
+    pub uid: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authorization {
+    Authorized,
+    NotAuthorized,
+}
+
+/// Ask polkit whether `subject` may perform `action`. Root is always authorized. Polkit prompts are never
+/// allowed, the worker has no way to relay them.
+pub fn check_authorization(action: ActionClass, subject: Subject) -> Result<Authorization> {
+    if subject.uid == 0 {
+        return Ok(Authorization::Authorized);
+    }
+
+    let stat = fs::read_to_string(format!("/proc/{}/stat", subject.pid))
+        .with_context(|| format!("failed to read process stat for pid {}", subject.pid))?;
+    let start_time = _parse_start_time(&stat)?;
+
+    let mut command = Command::new("pkcheck");
+    command
+        .arg("--action-id")
+        .arg(action.action_id())
+        .arg("--process")
+        .arg(format!("{},{},{}", subject.pid, start_time, subject.uid));
+    let output = run_command(command).context("failed to run pkcheck")?;
+
+    match output.status.code() {
+        Some(0) => Ok(Authorization::Authorized),
+        // 1: not authorized, 2: authentication required, 3: authentication dismissed
+        Some(1) | Some(2) | Some(3) => Ok(Authorization::NotAuthorized),
+        Some(c) => Err(anyhow!(
+            "pkcheck failed with exit code {}: {}",
+            c,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        None => Err(anyhow!("pkcheck terminated by signal")),
+    }
+}
+
+fn _parse_start_time(stat: &str) -> Result<u64> {
+    // The command name can contain spaces and parentheses, fields are counted from the last ')'.
+    let fields = stat
+        .rfind(')')
+        .map(|i| &stat[i + 1..])
+        .context("malformed process stat")?;
+    fields
+        .split_whitespace()
+        .nth(19)
+        .context("process stat is missing start time")?
+        .parse()
+        .context("process start time is not a number")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_time_from_stat() {
+        let stat = "4242 (blk capt (ctl)) S 4100 4242 4100 34816 4242 4194304 1043 0 0 0 2 1 0 0 20 0 4 0 \
+                    8839413 1131216896 3214 18446744073709551615 1 1 0 0 0 0 0 4096 1088 0 0 0 17 3 0 0 0 0 0";
+        assert_eq!(_parse_start_time(stat).unwrap(), 8839413);
+    }
+
+    #[test]
+    fn root_is_always_authorized() {
+        let subject = Subject { pid: 1, uid: 0 };
+        assert_eq!(
+            check_authorization(ActionClass::ManageJobs, subject).unwrap(),
+            Authorization::Authorized
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// Clock drift below this is measurement noise, not a suspend.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(10);
+
+/// Time since the system booted, including time spent suspended.
+pub fn uptime() -> Result<Duration> {
+    let uptime = fs::read_to_string("/proc/uptime").context("failed to read /proc/uptime")?;
+    uptime
+        .split_whitespace()
+        .next()
+        .and_then(|v| v.parse::<f64>().ok())
+        .map(Duration::from_secs_f64)
+        .context("failed to parse /proc/uptime")
+}
+
+/// Identifies the current boot. The kernel picks a new one every time the system boots.
+pub fn boot_id() -> Result<String> {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .map(|id| id.trim().to_owned())
+        .context("failed to read the boot id")
+}
+
+/// How the machine is powered right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerSupply {
+    /// A battery is present and no AC adapter is online.
+    pub on_battery: bool,
+    /// Charge of the system battery in percent, if it has one.
+    pub charge: Option<u8>,
+}
+
+impl PowerSupply {
+    /// Read the power supplies the kernel exposes in sysfs. Batteries of peripherals, like a wireless mouse, don't
+    /// count.
+    pub fn read() -> Result<Self> {
+        let mut mains_online = false;
+        let mut charge = None;
+        let entries = fs::read_dir("/sys/class/power_supply").context("failed to list power supplies")?;
+        for entry in entries {
+            let path = entry?.path();
+            let attribute = |name| read_attribute(&path, name);
+            match attribute("type").as_deref() {
+                Some("Mains") | Some("USB") => mains_online |= attribute("online").as_deref() == Some("1"),
+                Some("Battery") if attribute("scope").as_deref() != Some("Device") => {
+                    charge = attribute("capacity").and_then(|c| c.parse::<u8>().ok()).or(charge);
+                }
+                _ => {}
+            }
+        }
+        Ok(Self {
+            on_battery: charge.is_some() && !mains_online,
+            charge,
+        })
+    }
+}
+
+fn read_attribute(supply: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(supply.join(name)).ok().map(|v| v.trim().to_owned())
+}
+
+/// Notices the system resumed from suspend. The uptime keeps counting while suspended but the monotonic clock behind
+/// `Instant` stops, so the two drift apart by the time spent suspended.
+pub struct ResumeDetector {
+    uptime: Duration,
+    instant: Instant,
+}
+
+impl ResumeDetector {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            uptime: uptime()?,
+            instant: Instant::now(),
+        })
+    }
+
+    /// How long the system was suspended since the previous check, if it was.
+    pub fn check(&mut self) -> Result<Option<Duration>> {
+        let (uptime, instant) = (uptime()?, Instant::now());
+        let suspended = suspended_between(self.uptime, uptime, instant - self.instant);
+        self.uptime = uptime;
+        self.instant = instant;
+        Ok(suspended)
+    }
+}
+
+fn suspended_between(previous_uptime: Duration, uptime: Duration, elapsed: Duration) -> Option<Duration> {
+    uptime
+        .checked_sub(previous_uptime)
+        .and_then(|u| u.checked_sub(elapsed))
+        .filter(|drift| *drift >= SUSPEND_THRESHOLD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drift_beyond_threshold_is_a_suspend() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(suspended_between(minute, minute * 2, minute), None);
+        assert_eq!(
+            suspended_between(minute, minute * 2 + Duration::from_millis(50), minute),
+            None
+        );
+        assert_eq!(suspended_between(minute, minute * 32, minute), Some(minute * 30));
+    }
+}
@@ -0,0 +1,45 @@
+use super::fs::BtrfsMountEntry;
+use nix::unistd::{access, geteuid, AccessFlags};
+use std::{fs, os::unix::fs::MetadataExt, path::Path};
+
+/// Features that need root no matter how the pool is mounted or who owns the subvolumes.
+pub const ROOT_ONLY_FEATURES: &[&str] = &["snapshot send/receive", "pool scrub", "device queries"];
+
+pub fn running_as_root() -> bool {
+    geteuid().is_root()
+}
+
+/// Snapshot operations an unprivileged process can still perform on a dataset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnprivilegedAccess {
+    /// The process owns the dataset subvolume and can write to its snapshot directory.
+    pub create_snapshots: bool,
+    /// Snapshot creation is possible and the pool is mounted with user_subvol_rm_allowed.
+    pub delete_snapshots: bool,
+}
+
+impl UnprivilegedAccess {
+    pub fn probe(subvolume: &Path, snapshot_dir: &Path, mount: &BtrfsMountEntry) -> Self {
+        let owns_subvolume = fs::metadata(subvolume)
+            .map(|m| m.uid() == geteuid().as_raw())
+            .unwrap_or(false);
+        let snapshot_dir_writable = access(snapshot_dir, AccessFlags::W_OK | AccessFlags::X_OK).is_ok();
+        let create_snapshots = owns_subvolume && snapshot_dir_writable;
+        Self {
+            create_snapshots,
+            delete_snapshots: create_snapshots && mount.user_subvol_rm_allowed(),
+        }
+    }
+
+    pub fn unavailable_features(&self) -> Vec<&'static str> {
+        let mut features = Vec::new();
+        if !self.create_snapshots {
+            features.push("snapshot creation");
+        }
+        if !self.delete_snapshots {
+            features.push("snapshot pruning");
+        }
+        features.extend(ROOT_ONLY_FEATURES);
+        features
+    }
+}
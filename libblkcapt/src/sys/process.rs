@@ -1,5 +1,13 @@
 use anyhow::{anyhow, Context as _, Result};
-use std::process::{Command, ExitStatus, Output, Stdio};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::{setpgid, Pid},
+};
+use std::{
+    io::{self, Write},
+    process::{Command, ExitStatus, Output, Stdio},
+    time::Duration,
+};
 
 pub fn exit_status_as_result(status: ExitStatus) -> Result<()> {
     match status {
@@ -34,6 +42,52 @@ pub fn output_self_to_result(result: std::io::Result<Output>) -> Result<Output>
     convert_result(result)
 }
 
+/// Run a user supplied command with `sh -c`, killing it and everything it started if it runs longer than `timeout`.
+pub async fn run_shell_hook(command: &str, env: &[(&str, &str)], timeout: Duration) -> Result<()> {
+    let mut shell = tokio::process::Command::new("sh");
+    shell
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().copied())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    // The shell leads a process group of its own, so the commands it starts can be killed along with it. setpgid is
+    // async-signal-safe, so it may run between fork and exec.
+    unsafe {
+        shell.pre_exec(|| setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(|_| io::Error::last_os_error()));
+    }
+    let child = shell.spawn().context("failed to spawn subprocess")?;
+    let group = child.id().map(|id| Pid::from_raw(id as i32));
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(output) => output_to_result(output),
+        Err(_) => {
+            if let Some(group) = group {
+                let _ = killpg(group, Signal::SIGKILL);
+            }
+            Err(anyhow!(
+                "command timed out after {}",
+                humantime::format_duration(timeout)
+            ))
+        }
+    }
+}
+
+pub fn run_command_with_input(mut command: Command, input: &[u8]) -> Result<Output> {
+    command.stdin(Stdio::piped());
+    command.stderr(Stdio::piped());
+    command.stdout(Stdio::piped());
+    let mut child = command.spawn().context("failed to spawn subprocess")?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(input)
+        .context("failed to write subprocess input")?;
+    convert_result(child.wait_with_output()).and_then(output_as_result)
+}
+
 fn convert_result(result: std::io::Result<Output>) -> Result<Output> {
     result.context("waiting for subprocess result failed")
 }
@@ -59,3 +113,61 @@ pub mod double {
         output_stdout_to_result(command.output())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+    use tempfile::tempdir;
+
+    /// Whether a process is still running. Killed processes nobody reaped yet are zombies, which don't count.
+    fn running(pid: &str) -> bool {
+        match fs::read_to_string(Path::new("/proc").join(pid).join("stat")) {
+            Ok(stat) => !stat
+                .rsplit(')')
+                .next()
+                .unwrap_or_default()
+                .trim_start()
+                .starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_get_their_environment() {
+        run_shell_hook(
+            "test \"$BLKCAPT_HOOK\" = pre",
+            &[("BLKCAPT_HOOK", "pre")],
+            Duration::from_secs(10),
+        )
+        .await
+        .unwrap();
+
+        let error = run_shell_hook("echo 'database busy' >&2; exit 1", &[], Duration::from_secs(10))
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("database busy"));
+    }
+
+    #[tokio::test]
+    async fn timed_out_hooks_are_killed_with_their_children() {
+        let scratch = tempdir().unwrap();
+        let pid_file = scratch.path().join("hook.pid");
+        let command = format!("sleep 60 & echo $! > {}; wait", pid_file.display());
+
+        let error = run_shell_hook(&command, &[], Duration::from_millis(500))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let pid = pid.trim();
+        for _ in 0..50 {
+            if !running(pid) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("background command {} of the hook is still running", pid);
+    }
+}
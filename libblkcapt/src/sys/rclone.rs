@@ -0,0 +1,173 @@
+use super::process::{output_as_result, output_stdout_to_result, output_to_result};
+use anyhow::{bail, Context, Result};
+use hyper::body::Bytes;
+use serde::Deserialize;
+use std::{path::PathBuf, process::Stdio};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// An object in an rclone listing, with its key relative to the remote.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RcloneObject {
+    pub key: String,
+    pub size: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListEntry {
+    path: String,
+    size: i64,
+}
+
+/// A location on a configured rclone remote, e.g. `b2:my-bucket/blkcapt`. Every operation runs the rclone tool, so
+/// any storage rclone can reach works without blkcapt knowing its API.
+pub struct RcloneRemote {
+    remote: String,
+    config: Option<PathBuf>,
+}
+
+impl RcloneRemote {
+    pub fn new(remote: &str, config: Option<PathBuf>) -> Result<Self> {
+        if !remote.contains(':') || remote.starts_with('-') {
+            bail!("'{}' is not an rclone remote path, e.g. remote:bucket/path.", remote);
+        }
+        Ok(Self {
+            remote: remote.trim_end_matches('/').to_owned(),
+            config,
+        })
+    }
+
+    pub async fn put(&self, key: &str, body: Bytes) -> Result<()> {
+        let mut command = self.command("rcat");
+        command.arg(self.path(key));
+        run_with_stdin(command, &body)
+            .await
+            .with_context(|| format!("failed to upload {}", key))
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Bytes> {
+        let mut command = self.command("cat");
+        command.arg(self.path(key));
+        command
+            .output()
+            .await
+            .context("failed to start rclone")
+            .and_then(output_as_result)
+            .map(|o| o.stdout.into())
+            .with_context(|| format!("failed to download {}", key))
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<()> {
+        let mut command = self.command("deletefile");
+        command.arg(self.path(key));
+        output_to_result(command.output().await).with_context(|| format!("failed to delete {}", key))
+    }
+
+    /// Every object under a directory prefix ("" or ending in '/').
+    pub async fn list(&self, prefix: &str) -> Result<Vec<RcloneObject>> {
+        let mut command = self.command("lsjson");
+        command
+            .args(&["--recursive", "--files-only", "--no-modtime", "--no-mimetype"])
+            .arg(self.path(prefix));
+        let listing = output_stdout_to_result(command.output().await).context("failed to list the rclone remote")?;
+        parse_list(prefix, &listing)
+    }
+
+    fn path(&self, key: &str) -> String {
+        match self.remote.ends_with(':') {
+            true => format!("{}{}", self.remote, key),
+            false => format!("{}/{}", self.remote, key),
+        }
+    }
+
+    fn command(&self, subcommand: &str) -> Command {
+        let mut command = Command::new("rclone");
+        if let Some(config) = &self.config {
+            command.arg("--config").arg(config);
+        }
+        command.arg(subcommand);
+        command
+    }
+}
+
+/// Run command with body as its stdin. Output is read while the body is written, so a child that logs more than a
+/// pipe holds before it has read all of its input can't stall both sides.
+async fn run_with_stdin(mut command: Command, body: &[u8]) -> Result<()> {
+    command.stdin(Stdio::piped());
+    command.stdout(Stdio::piped());
+    command.stderr(Stdio::piped());
+    let mut child = command.spawn().context("failed to start rclone")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let write = async move {
+        let written = stdin.write_all(body).await;
+        drop(stdin);
+        written
+    };
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    // a failed command explains itself better than the broken pipe it left behind
+    output_to_result(output)?;
+    written.context("failed to write the command's input")
+}
+
+fn parse_list(prefix: &str, listing: &str) -> Result<Vec<RcloneObject>> {
+    let entries: Vec<ListEntry> = serde_json::from_str(listing).context("unexpected rclone listing")?;
+    Ok(entries
+        .into_iter()
+        .map(|e| RcloneObject {
+            key: format!("{}{}", prefix, e.path),
+            size: e.size.max(0) as u64,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_keys_include_prefix() {
+        let listing = r#"[
+{"Path":"b99a584c-72c0-4cbe-9c6d-0c32274563f7/2021-01-20T04-12-06Z/part-00000","Name":"part-00000","Size":67108864,"IsDir":false},
+{"Path":"b99a584c-72c0-4cbe-9c6d-0c32274563f7/2021-01-20T04-12-06Z.manifest.json","Name":"2021-01-20T04-12-06Z.manifest.json","Size":512,"IsDir":false}
+]"#;
+        assert_eq!(
+            parse_list("nas/", listing).unwrap(),
+            vec![
+                RcloneObject {
+                    key: "nas/b99a584c-72c0-4cbe-9c6d-0c32274563f7/2021-01-20T04-12-06Z/part-00000".to_owned(),
+                    size: 67108864,
+                },
+                RcloneObject {
+                    key: "nas/b99a584c-72c0-4cbe-9c6d-0c32274563f7/2021-01-20T04-12-06Z.manifest.json".to_owned(),
+                    size: 512,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stdin_is_written_while_stderr_is_drained() {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("head -c 1048576 /dev/zero >&2; test \"$(wc -c)\" -eq 1048576");
+        run_with_stdin(command, &vec![0; 1 << 20]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_commands_report_their_stderr() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("echo 'remote not found' >&2; exit 3");
+        let error = run_with_stdin(command, &vec![0; 1 << 20]).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("remote not found"));
+    }
+
+    #[test]
+    fn remote_paths_join_keys() {
+        let remote = RcloneRemote::new("b2:backup/", None).unwrap();
+        assert_eq!(remote.path("nas/part-00000"), "b2:backup/nas/part-00000");
+        let remote = RcloneRemote::new("local:", None).unwrap();
+        assert_eq!(remote.path("part-00000"), "local:part-00000");
+        assert!(RcloneRemote::new("/mnt/backup", None).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+use super::{net::Outbound, power};
+use anyhow::{bail, Context, Result};
+use rand::random;
+use tokio::net::lookup_host;
+
+pub const DEFAULT_TRAP_PORT: u16 = 162;
+const SYS_UP_TIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_TIME_TICKS: u8 = 0x43;
+const TAG_TRAP_V2: u8 = 0xa7;
+const VERSION_2C: i64 = 1;
+
+pub enum SnmpValue {
+    Integer(i64),
+    String(String),
+}
+
+/// Send an SNMPv2c trap to a manager, given as host or host:port. sysUpTime and snmpTrapOID come first, as required,
+/// followed by `varbinds`.
+pub async fn send_trap(
+    target: &str, community: &str, trap_oid: &[u32], varbinds: &[(Vec<u32>, SnmpValue)],
+) -> Result<()> {
+    let address = match target.contains(':') {
+        true => target.to_owned(),
+        false => format!("{}:{}", target, DEFAULT_TRAP_PORT),
+    };
+    let remote = lookup_host(&address)
+        .await
+        .with_context(|| format!("failed to resolve {}", address))?
+        .next()
+        .with_context(|| format!("{} has no addresses", address))?;
+
+    let message = encode_trap(community, random::<u32>() >> 1, uptime_ticks(), trap_oid, varbinds);
+    let socket = Outbound::current()?.udp_socket(&remote)?;
+    let sent = socket
+        .send_to(&message, remote)
+        .with_context(|| format!("failed to send trap to {}", address))?;
+    if sent != message.len() {
+        bail!("trap to {} was truncated", address);
+    }
+    Ok(())
+}
+
+fn encode_trap(
+    community: &str, request_id: u32, uptime: u32, trap_oid: &[u32], varbinds: &[(Vec<u32>, SnmpValue)],
+) -> Vec<u8> {
+    let mut bindings = Vec::new();
+    bindings.extend(encode_varbind(SYS_UP_TIME, tlv(TAG_TIME_TICKS, &unsigned(uptime))));
+    bindings.extend(encode_varbind(SNMP_TRAP_OID, tlv(TAG_OID, &oid(trap_oid))));
+    for (name, value) in varbinds {
+        let value = match value {
+            SnmpValue::Integer(i) => tlv(TAG_INTEGER, &integer(*i)),
+            SnmpValue::String(s) => tlv(TAG_OCTET_STRING, s.as_bytes()),
+        };
+        bindings.extend(encode_varbind(name, value));
+    }
+
+    let mut pdu = tlv(TAG_INTEGER, &integer(request_id as i64));
+    pdu.extend(tlv(TAG_INTEGER, &integer(0)));
+    pdu.extend(tlv(TAG_INTEGER, &integer(0)));
+    pdu.extend(tlv(TAG_SEQUENCE, &bindings));
+
+    let mut message = tlv(TAG_INTEGER, &integer(VERSION_2C));
+    message.extend(tlv(TAG_OCTET_STRING, community.as_bytes()));
+    message.extend(tlv(TAG_TRAP_V2, &pdu));
+    tlv(TAG_SEQUENCE, &message)
+}
+
+fn encode_varbind(name: &[u32], value: Vec<u8>) -> Vec<u8> {
+    let mut binding = tlv(TAG_OID, &oid(name));
+    binding.extend(value);
+    tlv(TAG_SEQUENCE, &binding)
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let length = content.len();
+    if length < 0x80 {
+        encoded.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let significant = &bytes[bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len() - 1)..];
+        encoded.push(0x80 | significant.len() as u8);
+        encoded.extend_from_slice(significant);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+/// Two's complement in as few bytes as keep the sign.
+fn integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < bytes.len() - 1
+        && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+    {
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+fn unsigned(value: u32) -> Vec<u8> {
+    integer(value as i64)
+}
+
+fn oid(ids: &[u32]) -> Vec<u8> {
+    let mut encoded = vec![(ids[0] * 40 + ids[1]) as u8];
+    for id in &ids[2..] {
+        let mut chunk = vec![(*id & 0x7f) as u8];
+        let mut rest = *id >> 7;
+        while rest > 0 {
+            chunk.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        encoded.extend(chunk.iter().rev());
+    }
+    encoded
+}
+
+/// Hundredths of a second since the system booted.
+fn uptime_ticks() -> u32 {
+    power::uptime()
+        .map(|uptime| (uptime.as_millis() / 10) as u32)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_primitives() {
+        assert_eq!(oid(SYS_UP_TIME), vec![0x2b, 6, 1, 2, 1, 1, 3, 0]);
+        assert_eq!(oid(&[1, 3, 6, 1, 4, 1, 311]), vec![0x2b, 6, 1, 4, 1, 0x82, 0x37]);
+        assert_eq!(integer(0), vec![0]);
+        assert_eq!(integer(127), vec![0x7f]);
+        assert_eq!(integer(128), vec![0, 0x80]);
+        assert_eq!(integer(-1), vec![0xff]);
+        assert_eq!(tlv(TAG_OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+    }
+
+    #[test]
+    fn encode_trap_message() {
+        let message = encode_trap("public", 1, 0, &[1, 3, 6, 1, 3, 1], &[]);
+        assert_eq!(
+            message,
+            vec![
+                0x30, 0x3c, // message
+                0x02, 0x01, 0x01, // version 2c
+                0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', // community
+                0xa7, 0x2f, // trap pdu
+                0x02, 0x01, 0x01, // request id
+                0x02, 0x01, 0x00, // error status
+                0x02, 0x01, 0x00, // error index
+                0x30, 0x24, // varbinds
+                0x30, 0x0d, 0x06, 0x08, 0x2b, 6, 1, 2, 1, 1, 3, 0, 0x43, 0x01, 0x00, // sysUpTime.0
+                0x30, 0x13, 0x06, 0x0a, 0x2b, 6, 1, 6, 3, 1, 1, 4, 1, 0, 0x06, 0x05, 0x2b, 6, 1, 3,
+                1, // snmpTrapOID.0
+            ]
+        );
+    }
+}
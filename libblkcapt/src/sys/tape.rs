@@ -0,0 +1,142 @@
+use super::process::output_to_result;
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A tape drive written through its non-rewinding device, one tape file at a time. Every write to the device is one
+/// block of `block_size` bytes, and closing the device after a file writes the filemark that ends it.
+pub struct TapeDrive {
+    device: PathBuf,
+    block_size: usize,
+}
+
+impl TapeDrive {
+    pub fn new(device: &Path, block_size: usize) -> Result<Self> {
+        let device = non_rewinding_device(device)?;
+        let metadata = fs::metadata(&device).with_context(|| format!("failed to inspect {}", device.display()))?;
+        if !metadata.file_type().is_char_device() {
+            bail!("{} is not a tape device.", device.display());
+        }
+        if block_size == 0 || block_size % 512 != 0 {
+            bail!("The tape block size must be a multiple of 512 bytes.");
+        }
+        Ok(Self { device, block_size })
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn rewind(&self) -> Result<()> {
+        let mut command = Command::new("mt");
+        command.arg("-f").arg(&self.device).arg("rewind");
+        output_to_result(command.output()).with_context(|| format!("failed to rewind {}", self.device.display()))
+    }
+
+    /// Start the next file on the tape, at the current position.
+    pub fn create_file(&self) -> Result<TapeFile> {
+        let device = OpenOptions::new()
+            .write(true)
+            .open(&self.device)
+            .with_context(|| format!("failed to open {}", self.device.display()))?;
+        Ok(TapeFile {
+            device,
+            block: Vec::with_capacity(self.block_size),
+            block_size: self.block_size,
+            blocks: 0,
+        })
+    }
+}
+
+/// The device node behind a path, such as a /dev/tape/by-id link, when it's a non-rewinding SCSI tape device: nst0,
+/// or one of its mode variants nst0l, nst0m and nst0a. Closing a rewinding device rewinds the tape, so the next file
+/// would overwrite the one before it.
+fn non_rewinding_device(device: &Path) -> Result<PathBuf> {
+    let resolved = device
+        .canonicalize()
+        .with_context(|| format!("failed to resolve {}", device.display()))?;
+    let name = resolved.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let unit = name.strip_prefix("nst").unwrap_or_default();
+    let unit = unit.strip_suffix(&['l', 'm', 'a'][..]).unwrap_or(unit);
+    if unit.is_empty() || !unit.bytes().all(|b| b.is_ascii_digit()) {
+        bail!(
+            "{} is not a non-rewinding tape device, such as /dev/nst0.",
+            device.display()
+        );
+    }
+    Ok(resolved)
+}
+
+/// A file being written to tape. Data is collected into whole blocks, the last one padded with zeros.
+pub struct TapeFile {
+    device: File,
+    block: Vec<u8>,
+    block_size: usize,
+    blocks: u64,
+}
+
+impl TapeFile {
+    pub fn write(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let take = (self.block_size - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() == self.block_size {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pad and write the last block and end the file with a filemark. Returns the number of blocks in the file.
+    pub fn finish(mut self) -> Result<u64> {
+        if !self.block.is_empty() {
+            self.block.resize(self.block_size, 0);
+            self.write_block()?;
+        }
+        self.device.sync_all().context("failed to finish the tape file")?;
+        Ok(self.blocks)
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        self.device
+            .write_all(&self.block)
+            .context("failed to write to the tape")?;
+        self.block.clear();
+        self.blocks += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn devices_are_checked_after_resolving_links() {
+        let scratch = tempdir().unwrap();
+        let dir = scratch.path().canonicalize().unwrap();
+        fs::create_dir_all(dir.join("by-id")).unwrap();
+        for name in ["nst0", "nst12m", "st0", "nvme0", "nst"].iter() {
+            File::create(dir.join(name)).unwrap();
+        }
+        symlink(dir.join("nst0"), dir.join("by-id/scsi-tape")).unwrap();
+        symlink(dir.join("st0"), dir.join("by-id/nst-alias")).unwrap();
+
+        let resolved = |name: &str| non_rewinding_device(&dir.join(name));
+        assert_eq!(resolved("by-id/scsi-tape").unwrap(), dir.join("nst0"));
+        assert_eq!(resolved("nst12m").unwrap(), dir.join("nst12m"));
+        assert!(resolved("by-id/nst-alias").is_err());
+        assert!(resolved("st0").is_err());
+        assert!(resolved("nvme0").is_err());
+        assert!(resolved("nst").is_err());
+        assert!(resolved("nst1").is_err());
+    }
+}
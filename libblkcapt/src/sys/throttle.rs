@@ -0,0 +1,174 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+/// Reads are let through in chunks of at least this size, so a slow limit doesn't turn into a flood of tiny reads.
+const MIN_CHUNK: u64 = 64 * 1024;
+
+/// A token bucket filled at `rate` bytes per second. It holds at most a second's worth, so the rate can be exceeded
+/// in a short burst after an idle period but not on average.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            tokens: rate as f64,
+            refilled: now,
+        }
+    }
+
+    /// Switch to another rate, keeping what built up at the old one up to a second's worth of the new one.
+    fn set_rate(&mut self, rate: u64, now: Instant) {
+        let rate = rate.max(1);
+        if rate != self.rate {
+            self.refill(now);
+            self.rate = rate;
+            self.tokens = self.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.refilled = now;
+    }
+
+    /// How many bytes may pass now, or how long until a chunk's worth has built up.
+    fn available(&mut self, now: Instant) -> Result<usize, Duration> {
+        self.refill(now);
+        let chunk = MIN_CHUNK.min(self.rate) as f64;
+        if self.tokens >= chunk {
+            Ok(self.tokens as usize)
+        } else {
+            Err(Duration::from_secs_f64((chunk - self.tokens) / self.rate as f64))
+        }
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Bytes per second a stream may move right now, `None` when unlimited. Asked on every read, so a limit that
+/// follows a schedule takes effect while a long stream is running.
+pub type BandwidthLimit = Box<dyn FnMut() -> Option<u64> + Send>;
+
+/// A reader that passes bytes through no faster than a limit. While unlimited it only forwards reads.
+pub struct ThrottledReader<R> {
+    inner: R,
+    limit: BandwidthLimit,
+    bucket: Option<TokenBucket>,
+    delay: Option<Pin<Box<Sleep>>>,
+    scratch: Vec<u8>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, limit: BandwidthLimit) -> Self {
+        Self {
+            inner,
+            limit,
+            bucket: None,
+            delay: None,
+            scratch: Vec::new(),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let bucket = match (this.limit)() {
+            Some(rate) => {
+                let now = Instant::now();
+                let bucket = this.bucket.get_or_insert_with(|| TokenBucket::new(rate, now));
+                bucket.set_rate(rate, now);
+                bucket
+            }
+            None => {
+                this.bucket = None;
+                this.delay = None;
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+        };
+
+        loop {
+            if let Some(delay) = &mut this.delay {
+                if delay.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.delay = None;
+            }
+            match bucket.available(Instant::now()) {
+                Ok(allowed) => {
+                    this.scratch.resize(allowed.min(buf.remaining()), 0);
+                    let mut limited = ReadBuf::new(&mut this.scratch);
+                    if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, &mut limited) {
+                        result?;
+                        bucket.consume(limited.filled().len());
+                        buf.put_slice(limited.filled());
+                        return Poll::Ready(Ok(()));
+                    }
+                    return Poll::Pending;
+                }
+                Err(wait) => this.delay = Some(Box::pin(sleep(wait))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_limits_rate_after_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1024 * 1024, start);
+
+        assert_eq!(bucket.available(start), Ok(1024 * 1024));
+        bucket.consume(1024 * 1024);
+        assert_eq!(bucket.available(start), Err(Duration::from_secs_f64(1.0 / 16.0)));
+
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.available(later), Ok(512 * 1024));
+
+        let idle = start + Duration::from_secs(10);
+        assert_eq!(bucket.available(idle), Ok(1024 * 1024));
+    }
+
+    #[test]
+    fn token_bucket_follows_a_rate_change() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1024 * 1024, start);
+        bucket.set_rate(256 * 1024, start);
+        assert_eq!(bucket.available(start), Ok(256 * 1024));
+        bucket.consume(256 * 1024);
+
+        bucket.set_rate(1024 * 1024, start + Duration::from_millis(500));
+        assert_eq!(bucket.available(start + Duration::from_millis(500)), Ok(128 * 1024));
+        assert_eq!(bucket.available(start + Duration::from_secs(1)), Ok(640 * 1024));
+    }
+
+    #[test]
+    fn token_bucket_waits_for_whole_chunk_below_chunk_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        bucket.consume(1000);
+        assert_eq!(bucket.available(start), Err(Duration::from_secs(1)));
+        assert_eq!(bucket.available(start + Duration::from_secs(1)), Ok(1000));
+    }
+}
@@ -0,0 +1,119 @@
+use super::net::Outbound;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::timeout,
+};
+
+pub const DEFAULT_PORT: u16 = 10051;
+const HEADER: &[u8] = b"ZBXD\x01";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A value for a trapper item, as the zabbix_sender tool would send it.
+#[derive(Serialize, Debug)]
+pub struct ZabbixValue {
+    pub host: String,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Serialize)]
+struct SenderRequest<'a> {
+    request: &'static str,
+    data: &'a [ZabbixValue],
+}
+
+#[derive(Deserialize)]
+struct SenderResponse {
+    response: String,
+    #[serde(default)]
+    info: String,
+}
+
+/// Send trapper item values to a Zabbix server or proxy, given as host or host:port.
+pub async fn send_values(server: &str, values: &[ZabbixValue]) -> Result<()> {
+    let address = match server.contains(':') {
+        true => server.to_owned(),
+        false => format!("{}:{}", server, DEFAULT_PORT),
+    };
+    let request = frame(&serde_json::to_vec(&SenderRequest {
+        request: "sender data",
+        data: values,
+    })?);
+
+    let exchange = async {
+        let mut stream = Outbound::current()?.connect(&address).await?;
+        stream.write_all(&request).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, anyhow::Error>(response)
+    };
+    let response = timeout(TIMEOUT, exchange)
+        .await
+        .context("zabbix server did not respond in time")?
+        .with_context(|| format!("failed to send values to zabbix server {}", address))?;
+
+    check_response(&response, values.len())
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(HEADER.len() + 8 + payload.len());
+    framed.extend_from_slice(HEADER);
+    framed.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// The server accepts the request even when it drops values, for items it does not know, so the processed count is
+/// checked as well.
+fn check_response(response: &[u8], sent: usize) -> Result<()> {
+    if response.len() < HEADER.len() + 8 || &response[..HEADER.len()] != HEADER {
+        bail!("zabbix server sent an invalid response");
+    }
+    let length = u64::from_le_bytes(response[HEADER.len()..HEADER.len() + 8].try_into().expect("8 bytes")) as usize;
+    let payload = response
+        .get(HEADER.len() + 8..HEADER.len() + 8 + length)
+        .context("zabbix server sent a truncated response")?;
+    let response: SenderResponse = serde_json::from_slice(payload).context("failed to parse zabbix response")?;
+    if response.response != "success" {
+        bail!("zabbix server rejected the values: {}", response.info);
+    }
+
+    let processed = response
+        .info
+        .split(';')
+        .find_map(|part| part.trim().strip_prefix("processed:"))
+        .and_then(|count| count.trim().parse::<usize>().ok());
+    if processed != Some(sent) {
+        bail!(
+            "zabbix server did not process all values, check the host and item keys: {}",
+            response.info
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_prefixes_header_and_length() {
+        let framed = frame(b"{}");
+        assert_eq!(framed, b"ZBXD\x01\x02\0\0\0\0\0\0\0{}");
+    }
+
+    #[test]
+    fn response_counts_processed_values() {
+        let ok =
+            frame(br#"{"response":"success","info":"processed: 1; failed: 0; total: 1; seconds spent: 0.000055"}"#);
+        assert!(check_response(&ok, 1).is_ok());
+
+        let unknown_item =
+            frame(br#"{"response":"success","info":"processed: 0; failed: 1; total: 1; seconds spent: 0.000055"}"#);
+        assert!(check_response(&unknown_item, 1).is_err());
+        assert!(check_response(b"garbage", 1).is_err());
+    }
+}